@@ -0,0 +1,45 @@
+//! Hops per second a long request can be decoded and framed again, with copied payloads (as
+//! before Bytes) and shared ones. Run with `cargo bench --bench hop_throughput`.
+
+use std::time::Instant;
+use bytes::Bytes;
+use pathfinder::domain::{ClientQuery, HopMessage, NodeInfo};
+use pathfinder::node_connector::{self, WireConfig};
+
+const HOPS: usize = 2000;
+
+/// Hop of a query which crossed 2000 nodes.
+fn long_request(wire: &WireConfig) -> HopMessage {
+    let query = serde_json::to_vec(&ClientQuery::new(1, NodeInfo::new(0, 1), NodeInfo::new(1999, 2))).unwrap();
+    let hops = node_connector::decode_requests(wire, &query).unwrap();
+    let mut hop = serde_json::to_value(&hops[0]).unwrap();
+    hop["path"] = (0..2000u64).map(|i| serde_json::json!({"id": i, "region_id": 1, "cord_x": i * 10, "cord_y": i * 3})).collect();
+    serde_json::from_value(hop).unwrap()
+}
+
+/// Receives `frame` and frames the request again for the next server. The transport keeps
+/// a copy of the frame for retries and the spool, `share` makes that copy.
+fn hop(wire: &WireConfig, frame: &Bytes, share: &dyn Fn(&Bytes) -> Bytes) -> Bytes {
+    let requests = node_connector::decode_requests(wire, frame).unwrap();
+    let raw = Bytes::from(node_connector::encode_request(wire, &requests[0]).unwrap());
+    let retained = share(&raw);
+    let sent = zeromq::ZmqMessage::from(share(&raw));
+    assert_eq!(sent.get(0).unwrap().len(), retained.len());
+    raw
+}
+
+fn main() {
+    let wire = WireConfig::default();
+    let start = Bytes::from(node_connector::encode_request(&wire, &long_request(&wire)).unwrap());
+    let measure = |name: &str, share: &dyn Fn(&Bytes) -> Bytes| {
+        let mut frame = start.clone();
+        let started = Instant::now();
+        for _ in 0..HOPS {
+            frame = hop(&wire, &frame, share);
+        }
+        let elapsed = started.elapsed();
+        println!("{}: {} hops in {:?}, {:.0} hops/s", name, HOPS, elapsed, HOPS as f64 / elapsed.as_secs_f64());
+    };
+    measure("copied payloads", &|raw| Bytes::from(raw.to_vec()));
+    measure("shared payloads", &|raw| raw.clone());
+}
@@ -0,0 +1,7 @@
+fn main() {
+    // Both write the messages to the same module, the gRPC service is only added with `grpc`.
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/pathfinder.proto").unwrap();
+    #[cfg(all(feature = "protobuf", not(feature = "grpc")))]
+    prost_build::compile_protos(&["proto/messages.proto"], &["proto"]).unwrap();
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use pathfinder::audit::audit_weights;
+use pathfinder::data_quality::DataPolicy;
+use pathfinder::graph::WeightScale;
+use pathfinder::graph_provider::{GraphProvider, StorageConfig};
+use pathfinder::graph_provider::mock::MockGraphProvider;
+
+const USAGE: &str = "Usage: audit_weights [--dir <data dir>] [--tolerance <relative>] <region>...";
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let mut dir = None;
+    let mut tolerance = 0.1;
+    let mut regions = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dir" => { dir = Some(PathBuf::from(args.next().expect(USAGE))) }
+            "--tolerance" => { tolerance = args.next().expect(USAGE).parse().expect(USAGE) }
+            region => { regions.push(region.parse().expect(USAGE)) }
+        }
+    }
+    if regions.is_empty() {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+
+    let weight_scale = WeightScale::from_env().unwrap();
+    let data_policy = DataPolicy::from_env().unwrap();
+    // Without a local directory the regions are read from the bucket configured in the environment.
+    let provider: Box<dyn GraphProvider> = match dir {
+        Some(dir) => { Box::new(MockGraphProvider::new(dir).with_weight_scale(weight_scale).with_data_policy(data_policy)) }
+        None => { StorageConfig::from_env().unwrap().graph_provider(weight_scale, data_policy).unwrap() }
+    };
+    let mut graphs = HashMap::new();
+    for region in regions {
+        graphs.insert(region, provider.get_region(region).await.unwrap());
+    }
+
+    let report = audit_weights(&graphs, tolerance);
+    for (region, scale) in report.scales.iter() {
+        println!("Region {}: median cost per coordinate unit {:.3}", region, scale);
+    }
+    println!("Checked {} borders with {} border vertices", report.borders.len(), report.border_vertices);
+    for anomaly in report.anomalies.iter() {
+        println!("{}", anomaly);
+    }
+    if !report.anomalies.is_empty() {
+        std::process::exit(1);
+    }
+}
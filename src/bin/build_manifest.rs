@@ -0,0 +1,26 @@
+use std::env;
+use std::path::PathBuf;
+use pathfinder::manifest::{self, RegionManifest};
+
+const USAGE: &str = "Usage: build_manifest <version> <group id> <output dir> <region file>...";
+
+fn main() {
+    env_logger::init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 4 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let group_id: usize = args[1].parse().expect(USAGE);
+    let out_dir = PathBuf::from(&args[2]);
+
+    let mut manifest = RegionManifest::new(&args[0]);
+    for file in args[3..].iter().map(PathBuf::from) {
+        // Listed under the name the file is stored with, next to the group file.
+        let name = file.file_name().expect(USAGE).to_string_lossy().to_string();
+        manifest.add(&name, &std::fs::read(&file).unwrap());
+    }
+    let path = out_dir.join(manifest::file_name(group_id));
+    std::fs::write(&path, serde_json::to_vec_pretty(&manifest).unwrap()).unwrap();
+    println!("Version {} of group {}: {} files listed in {}", manifest.version, group_id, manifest.files.len(), path.display());
+}
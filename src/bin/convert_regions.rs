@@ -0,0 +1,67 @@
+use std::env;
+use std::path::PathBuf;
+use pathfinder::data_quality::DataPolicy;
+use pathfinder::graph::WeightScale;
+use pathfinder::graph_provider::{GraphProvider, StorageConfig};
+use pathfinder::graph_provider::mapped::{convert, record, region_path};
+use pathfinder::graph_provider::mock::MockGraphProvider;
+use pathfinder::graph_provider::packed;
+
+const USAGE: &str = "Usage: convert_regions [--dir <data dir>] [--packed | --version <version>] <output dir> <region>...";
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let mut dir = None;
+    let mut to_packed = false;
+    let mut version = None;
+    let mut positional = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dir" => { dir = Some(PathBuf::from(args.next().expect(USAGE))) }
+            "--packed" => { to_packed = true }
+            "--version" => { version = Some(args.next().expect(USAGE)) }
+            other => { positional.push(other.to_string()) }
+        }
+    }
+    if positional.len() < 2 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    if to_packed && version.is_some() {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    // Mapped regions of a version go to its own subdirectory, listed in its manifest.
+    let out_dir = match &version {
+        Some(version) => { PathBuf::from(&positional[0]).join(version) }
+        None => { PathBuf::from(&positional[0]) }
+    };
+    let regions: Vec<u32> = positional[1..].iter().map(|region| region.parse().expect(USAGE)).collect();
+
+    let weight_scale = WeightScale::from_env().unwrap();
+    let data_policy = DataPolicy::from_env().unwrap();
+    // Without a local directory the regions are read from the bucket configured in the environment.
+    let provider: Box<dyn GraphProvider> = match dir {
+        Some(dir) => { Box::new(MockGraphProvider::new(dir).with_weight_scale(weight_scale).with_data_policy(data_policy)) }
+        None => { StorageConfig::from_env().unwrap().graph_provider(weight_scale, data_policy).unwrap() }
+    };
+    std::fs::create_dir_all(&out_dir).unwrap();
+    for region in regions {
+        let graph = provider.get_region(region).await.unwrap();
+        let path = if to_packed {
+            let path = packed::region_path(&out_dir, region);
+            packed::convert(&graph, weight_scale, &path).unwrap();
+            path
+        } else {
+            let path = region_path(&out_dir, region);
+            convert(&graph, &path).unwrap();
+            if let Some(version) = &version {
+                record(&out_dir, version, region).unwrap();
+            }
+            path
+        };
+        println!("Region {}: {} nodes written to {}", region, graph.node_count(), path.display());
+    }
+}
@@ -0,0 +1,53 @@
+use std::env;
+use pathfinder::client::ResultsClient;
+
+const USAGE: &str = "Usage: dead_letters <server id> [list [--count <n>] | replay [--count <n>] | clear]";
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let mut args = env::args().skip(1);
+    let server_id: usize = match args.next().map(|arg| arg.parse()) {
+        Some(Ok(server_id)) => { server_id }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    let command = args.next().unwrap_or_else(|| "list".to_string());
+    let mut count = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--count" => { count = Some(args.next().expect(USAGE).parse::<usize>().expect(USAGE)) }
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let client = ResultsClient::new(&redis_url).unwrap();
+    match command.as_str() {
+        "list" => {
+            for letter in client.dead_letters(server_id, count.unwrap_or(20)).await.unwrap() {
+                let target = letter.target_server.map_or("-".to_string(), |server| server.to_string());
+                println!("{:>12} request {:>10} region {:>6} {:?} to server {}: {}",
+                         letter.failed_at, letter.request_id(), letter.region(), letter.stage, target, letter.error);
+            }
+        }
+        "replay" => {
+            if !client.replay_dead_letters(server_id, count.unwrap_or(usize::MAX)).await.unwrap() {
+                eprintln!("Server {} isn't running", server_id);
+                std::process::exit(1);
+            }
+        }
+        "clear" => {
+            println!("Dropped {} dead letters", client.clear_dead_letters(server_id).await.unwrap());
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
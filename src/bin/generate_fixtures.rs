@@ -0,0 +1,16 @@
+use std::env;
+use std::path::PathBuf;
+use pathfinder::fixtures::FixtureDefinition;
+
+fn main() {
+    env_logger::init();
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <definition.toml> <output dir>", args[0]);
+        std::process::exit(2);
+    }
+    let content = std::fs::read_to_string(&args[1]).unwrap();
+    let definition = FixtureDefinition::from_toml(&content).unwrap();
+    let manifest = definition.generate(&PathBuf::from(&args[2])).unwrap();
+    println!("{}", serde_json::to_string_pretty(&manifest).unwrap());
+}
@@ -0,0 +1,20 @@
+use std::env;
+use std::path::PathBuf;
+use pathfinder::graph_provider::sqlite;
+
+const USAGE: &str = "Usage: import_sqlite <data dir> <sqlite file>";
+
+fn main() {
+    env_logger::init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let (dir, path) = (PathBuf::from(&args[0]), PathBuf::from(&args[1]));
+    if let Err(err) = sqlite::import(&dir, &path) {
+        eprintln!("Unable to import {}, details: {}", dir.display(), err);
+        std::process::exit(1);
+    }
+    println!("Imported {} into {}", dir.display(), path.display());
+}
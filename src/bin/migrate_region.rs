@@ -0,0 +1,30 @@
+use std::env;
+use pathfinder::client::ResultsClient;
+use pathfinder::graph::RegionIdx;
+
+const USAGE: &str = "Usage: migrate_region <region> <from server id> <to server id>";
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let parsed: Option<((RegionIdx, usize), usize)> = match args.as_slice() {
+        [region, from, to] => { region.parse().ok().zip(from.parse().ok()).zip(to.parse().ok()) }
+        _ => { None }
+    };
+    let ((region, from), to) = match parsed {
+        Some(parsed) => { parsed }
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let client = ResultsClient::new(&redis_url).unwrap();
+    if !client.migrate_region(region, from, to).await.unwrap() {
+        eprintln!("Server {} isn't running", from);
+        std::process::exit(1);
+    }
+    println!("Asked server {} to hand region {} over to server {}", from, region, to);
+}
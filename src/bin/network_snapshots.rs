@@ -0,0 +1,36 @@
+use std::env;
+use std::path::PathBuf;
+use pathfinder::graph_provider::StorageConfig;
+use pathfinder::snapshot::{DirSnapshotStore, SnapshotStore};
+
+const USAGE: &str = "Usage: network_snapshots [--dir <snapshot dir>] <earlier snapshot> <later snapshot>";
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let mut dir = None;
+    let mut names = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dir" => { dir = Some(PathBuf::from(args.next().expect(USAGE))) }
+            name => { names.push(name.to_string()) }
+        }
+    }
+    if names.len() != 2 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+
+    // Without a local directory the snapshots are read from the bucket configured in the environment.
+    let store: Box<dyn SnapshotStore> = match dir {
+        Some(dir) => { Box::new(DirSnapshotStore::new(dir)) }
+        None => { StorageConfig::from_env().unwrap().snapshot_store().unwrap() }
+    };
+    let earlier = store.load(&names[0]).await.unwrap();
+    let later = store.load(&names[1]).await.unwrap();
+    println!("{} servers at {}, {} servers at {}", earlier.servers.len(), earlier.taken_at, later.servers.len(), later.taken_at);
+    for change in earlier.diff(&later) {
+        println!("{}", change);
+    }
+}
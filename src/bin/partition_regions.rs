@@ -0,0 +1,57 @@
+use std::env;
+use std::path::PathBuf;
+use pathfinder::data_quality::DataPolicy;
+use pathfinder::graph::WeightScale;
+use pathfinder::graph_provider::{GraphProvider, GroupInfoProvider, StorageConfig};
+use pathfinder::graph_provider::mock::MockGraphProvider;
+use pathfinder::partition::{generate, read_csv, PartitionConfig};
+
+const USAGE: &str = "Usage: partition_regions [--regions <count>] [--groups <count>] [--imbalance <share>] [--upload] <nodes.csv> <edges.csv> <output dir>";
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let mut config = PartitionConfig::default();
+    let mut groups = None;
+    let mut upload = false;
+    let mut positional = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--regions" => { config.regions = args.next().and_then(|count| count.parse().ok()).expect(USAGE) }
+            "--groups" => { groups = Some(args.next().and_then(|count| count.parse().ok()).expect(USAGE)) }
+            "--imbalance" => { config.imbalance = args.next().and_then(|share| share.parse().ok()).expect(USAGE) }
+            "--upload" => { upload = true }
+            other => { positional.push(other.to_string()) }
+        }
+    }
+    if positional.len() != 3 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let (nodes, edges) = read_csv(&PathBuf::from(&positional[0]), &PathBuf::from(&positional[1])).unwrap();
+    // One region per group unless told otherwise.
+    let out_dir = PathBuf::from(&positional[2]);
+    let manifest = generate(&nodes, &edges, &config, groups.unwrap_or(config.regions), &out_dir).unwrap();
+    println!("{}", serde_json::to_string_pretty(&manifest).unwrap());
+    if !upload {
+        return;
+    }
+
+    // Regions are uploaded packed, with weights scaled like the servers reading them scale them.
+    let weight_scale = WeightScale::from_env().unwrap();
+    let data_policy = DataPolicy::from_env().unwrap();
+    let written = MockGraphProvider::new(out_dir).with_weight_scale(weight_scale).with_data_policy(data_policy);
+    let storage_config = StorageConfig::from_env().unwrap();
+    let storage = storage_config.provider(weight_scale, data_policy).unwrap();
+    // A group is written before its regions, the storage refuses them until it checked the group
+    // has no manifest.
+    for group_id in manifest.groups.iter() {
+        let group_info = written.get_info(*group_id).await.unwrap();
+        storage.put_info(&group_info).await.unwrap();
+        for region in group_info.regions.iter() {
+            storage.put_region(&written.get_region(*region).await.unwrap()).await.unwrap();
+        }
+    }
+    eprintln!("Uploaded {} groups and {} regions to {}", manifest.groups.len(), manifest.regions.len(), storage_config.name());
+}
@@ -0,0 +1,53 @@
+use std::env;
+use std::time::Duration;
+use pathfinder::client::ResultsClient;
+
+const USAGE: &str = "Usage: region_stats [--timeout <ms>] [--usage] <region>";
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let mut timeout = Duration::from_millis(5000);
+    let mut region = None;
+    let mut usage_only = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--usage" => { usage_only = true }
+            "--timeout" => { timeout = Duration::from_millis(args.next().expect(USAGE).parse().expect(USAGE)) }
+            region_id => { region = Some(region_id.parse().expect(USAGE)) }
+        }
+    }
+    let region = match region {
+        Some(region) => { region }
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let client = ResultsClient::new(&redis_url).unwrap();
+    if usage_only {
+        let usage = client.region_usage(region).await.unwrap();
+        println!("Region {}: {} hops served, {:.3} ms per search on average", region, usage.served, usage.average_search_ms());
+        println!("{} boundary crossings, {} forwarded to other servers", usage.boundary_crossings, usage.forwarded);
+        return;
+    }
+    let report = match client.region_report(region, timeout).await.unwrap() {
+        Some(report) => { report }
+        None => {
+            eprintln!("No server answered for region {} within {:?}", region, timeout);
+            std::process::exit(1);
+        }
+    };
+    println!("Region {}: {} nodes, {} vertices", report.region, report.nodes, report.vertices);
+    println!("Weights from {} to {}, {} vertices of weight zero", report.min_weight, report.max_weight, report.zero_weights);
+    for bucket in report.weights.iter() {
+        println!("  < {:>12.3}: {}", bucket.upper, bucket.count);
+    }
+    println!("Degrees:");
+    for (degree, count) in report.degrees.iter() {
+        println!("  {:>4}: {}", degree, count);
+    }
+}
@@ -0,0 +1,157 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, RwLock};
+use redis::RedisResult;
+use serde::{Serialize, Deserialize};
+use tokio::task::JoinHandle;
+use crate::graph::{Graph, RegionIdx};
+use crate::redis_connector::{Delivery, RedisConnector, Subscriber};
+
+/// Regions bordering a region, with the cheapest edge crossing into each of them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct RegionBorders {
+    pub(crate) region: RegionIdx,
+    pub(crate) neighbours: HashMap<RegionIdx, u64>,
+}
+
+impl RegionBorders {
+    /// Borders of a loaded region, found through the nodes across its boundary held by its file.
+    pub(crate) fn new(graph: &Graph) -> Self {
+        let mut neighbours = HashMap::new();
+        for vertex in graph.vertices() {
+            let regions = (graph.get_node(vertex.a).map(|node| node.region), graph.get_node(vertex.b).map(|node| node.region));
+            let neighbour = match regions {
+                (Some(a), Some(b)) if a == graph.region_idx && b != a => { b }
+                (Some(a), Some(b)) if b == graph.region_idx && a != b => { a }
+                _ => { continue }
+            };
+            let weight = graph.current_weight(vertex.id).unwrap_or(vertex.weight);
+            neighbours.entry(neighbour)
+                .and_modify(|cheapest: &mut u64| *cheapest = (*cheapest).min(weight))
+                .or_insert(weight);
+        }
+        Self {
+            region: graph.region_idx,
+            neighbours,
+        }
+    }
+}
+
+/// Meta-graph of the regions of the cluster, joined where they border each other.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RegionAdjacency {
+    borders: HashMap<RegionIdx, HashMap<RegionIdx, u64>>,
+}
+
+impl RegionAdjacency {
+    pub(crate) fn insert(&mut self, borders: RegionBorders) {
+        self.borders.insert(borders.region, borders.neighbours);
+    }
+
+    /// Least cost of the boundary crossings from every region to `target`, a lower bound of the
+    /// cost of getting there. Regions which don't reach it, or which no server reported, are missing.
+    pub(crate) fn distances_to(&self, target: RegionIdx) -> HashMap<RegionIdx, u64> {
+        // Either side of a border may have reported it, crossings cost the same both ways.
+        let mut edges: HashMap<RegionIdx, HashMap<RegionIdx, u64>> = HashMap::new();
+        for (region, neighbours) in self.borders.iter() {
+            for (neighbour, cost) in neighbours.iter() {
+                for (from, to) in [(*region, *neighbour), (*neighbour, *region)] {
+                    let known = edges.entry(from).or_default().entry(to).or_insert(*cost);
+                    *known = (*known).min(*cost);
+                }
+            }
+        }
+        let mut distances = HashMap::from([(target, 0)]);
+        let mut queue = BinaryHeap::from([Reverse((0, target))]);
+        while let Some(Reverse((distance, region))) = queue.pop() {
+            if distances.get(&region).is_some_and(|known| *known < distance) {
+                continue;
+            }
+            for (neighbour, cost) in edges.get(&region).into_iter().flatten() {
+                if distances.get(neighbour).is_none_or(|known| distance + cost < *known) {
+                    distances.insert(*neighbour, distance + cost);
+                    queue.push(Reverse((distance + cost, *neighbour)));
+                }
+            }
+        }
+        distances
+    }
+}
+
+/// Redis channel on which servers announce the [`RegionBorders`] of the regions they load.
+pub(crate) const UPDATES_CHANNEL: &str = "region_borders_updates";
+
+/// Reads the borders reported so far and keeps following the reports of servers loading later.
+/// Once subscribed again after the connection dropped, the borders are read again, so reports
+/// published meanwhile aren't missed.
+pub(crate) async fn spawn_tracker(redis_connector: &RedisConnector) -> RedisResult<(Arc<RwLock<RegionAdjacency>>, JoinHandle<()>)> {
+    let mut subscriber = Subscriber::new(redis_connector, vec![UPDATES_CHANNEL.to_string()]).await?;
+    let mut adjacency = RegionAdjacency::default();
+    for borders in redis_connector.get_region_borders().await? {
+        adjacency.insert(borders);
+    }
+    let adjacency = Arc::new(RwLock::new(adjacency));
+    let tracked = adjacency.clone();
+    let redis_connector = redis_connector.clone();
+    let update_task = tokio::task::spawn(async move {
+        loop {
+            let message = match subscriber.next().await {
+                Delivery::Message(message) => { message }
+                Delivery::Resubscribed => {
+                    match redis_connector.get_region_borders().await {
+                        Ok(reported) => {
+                            let mut tracked = tracked.write().unwrap();
+                            for borders in reported {
+                                tracked.insert(borders);
+                            }
+                        }
+                        Err(err) => { log::warn!("Unable to read the region borders again, reports published meanwhile are missing. Details: {}", err) }
+                    }
+                    continue
+                }
+            };
+            let borders = message.get_payload::<String>().map_err(|err| err.to_string())
+                .and_then(|payload| serde_json::from_str(&payload).map_err(|err| err.to_string()));
+            match borders {
+                Ok(borders) => { tracked.write().unwrap().insert(borders) }
+                Err(err) => { log::warn!("Ignoring malformed region borders, details: {}", err) }
+            }
+        }
+    });
+    Ok((adjacency, update_task))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use bitvec::vec::BitVec;
+    use crate::adjacency::{RegionAdjacency, RegionBorders};
+    use crate::graph::{Access, Graph, Node, Vertex};
+
+    #[test]
+    fn borders_of_loaded_regions() {
+        // Nodes 1 and 2 in region 1, 3 across the border in region 2 and 4 in region 3.
+        let regions = [(1, 1), (2, 1), (3, 2), (4, 3)];
+        let mut nodes: HashMap<_, _> = regions.iter().map(|(id, region)| (*id, Node::new(vec![], *id, *region, 0, 0))).collect();
+        let mut vertices = HashMap::new();
+        for (id, a, b, weight) in [(1, 1, 2, 1), (2, 1, 3, 7), (3, 2, 3, 4), (4, 4, 2, 9), (5, 3, 4, 1)] {
+            nodes.get_mut(&a).unwrap().connections.push(id);
+            nodes.get_mut(&b).unwrap().connections.push(id);
+            vertices.insert(id, Vertex { a, b, weight, id, region_bits: BitVec::repeat(true, 4), access: Access::ALL });
+        }
+        let borders = RegionBorders::new(&Graph::new(nodes, vertices, 1));
+        assert_eq!(borders.neighbours, HashMap::from([(2, 4), (3, 9)]));
+    }
+
+    #[test]
+    fn distances_between_regions() {
+        // 1 - 2 - 3 - 4 in a line, with a costly shortcut 1 - 4. Region 5 has no borders.
+        let mut adjacency = RegionAdjacency::default();
+        adjacency.insert(RegionBorders { region: 1, neighbours: HashMap::from([(2, 3), (4, 20)]) });
+        adjacency.insert(RegionBorders { region: 2, neighbours: HashMap::from([(1, 5), (3, 2)]) });
+        adjacency.insert(RegionBorders { region: 3, neighbours: HashMap::from([(4, 1)]) });
+        let distances = adjacency.distances_to(4);
+        assert_eq!(distances, HashMap::from([(4, 0), (3, 1), (2, 3), (1, 6)]));
+        assert_eq!(adjacency.distances_to(5), HashMap::from([(5, 0)]));
+    }
+}
@@ -0,0 +1,465 @@
+use std::env;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use redis::RedisResult;
+use crate::domain::{FailureKind, HopMessage, RouteFailure, RouteResult};
+use crate::graph::{GraphError, WeightScale};
+use crate::liveness;
+use crate::node_connector::{ConnectionError, ResultReplier};
+use crate::redis_connector::RedisConnector;
+use crate::retention::RetentionConfig;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Longest a result is held back for when ARBITRATION_TIMEOUT_MS is not set.
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// Reads ARBITRATION_TIMEOUT_MS, results are sent as they are found if it is 0.
+pub(crate) fn timeout_from_env() -> Result<Option<Duration>> {
+    parse_timeout(env::var("ARBITRATION_TIMEOUT_MS").ok().as_deref())
+}
+
+fn parse_timeout(millis: Option<&str>) -> Result<Option<Duration>> {
+    match millis {
+        Some(millis) => {
+            let timeout = Duration::from_millis(millis.parse()?);
+            Ok(Some(timeout).filter(|timeout| !timeout.is_zero()))
+        }
+        None => { Ok(Some(DEFAULT_TIMEOUT)) }
+    }
+}
+
+/// Error of a hop given up on, whose kind can't be told from the error it stems from.
+#[derive(Debug)]
+pub(crate) struct HopFailure {
+    pub(crate) kind: FailureKind,
+    pub(crate) message: String,
+}
+
+impl HopFailure {
+    pub(crate) fn new(kind: FailureKind, message: String) -> Self {
+        Self { kind, message }
+    }
+}
+
+impl Display for HopFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for HopFailure {}
+
+/// Kind of failure `err` stands for, none if it only tells there is no route, such as a target
+/// unreachable within a region.
+pub(crate) fn failure_kind(err: &(dyn Error + 'static)) -> Option<FailureKind> {
+    if let Some(failure) = err.downcast_ref::<HopFailure>() {
+        return Some(failure.kind);
+    }
+    if liveness::is_server_down(err) {
+        return Some(FailureKind::ServerDown);
+    }
+    match err.downcast_ref::<GraphError>() {
+        Some(GraphError::Unreachable(_, _)) => { None }
+        Some(_) => { Some(FailureKind::Graph) }
+        None if err.is::<ConnectionError>() => { Some(FailureKind::Send) }
+        None => { Some(FailureKind::Internal) }
+    }
+}
+
+/// Where the servers keep track of the queries in flight: the hops left, the cheapest cost, the
+/// result held back and whether the query was answered.
+#[async_trait::async_trait]
+pub(crate) trait QueryStore: Send + Sync {
+    async fn spawn_hops(&self, request_id: usize, count: usize) -> RedisResult<()>;
+
+    async fn offer_cost(&self, request_id: usize, cost: u64) -> RedisResult<u64>;
+
+    async fn get_best_cost(&self, request_id: usize) -> RedisResult<Option<u64>>;
+
+    async fn hold_result(&self, result: &RouteResult) -> Result<bool>;
+
+    async fn get_held_result(&self, request_id: usize) -> RedisResult<Option<RouteResult>>;
+
+    async fn claim_answer(&self, request_id: usize) -> RedisResult<bool>;
+
+    async fn mark_answered(&self, request_id: usize) -> RedisResult<()>;
+
+    async fn store_result(&self, result: &RouteResult, ttl: Duration) -> Result<()>;
+
+    async fn finish_hop(&self, request_id: usize) -> RedisResult<bool>;
+
+    async fn record_hop_failure(&self, request_id: usize, failure: &RouteFailure, reason: &str) -> RedisResult<()>;
+
+    async fn get_hop_failure(&self, request_id: usize) -> RedisResult<Option<(RouteFailure, String)>>;
+}
+
+#[async_trait::async_trait]
+impl QueryStore for RedisConnector {
+    async fn spawn_hops(&self, request_id: usize, count: usize) -> RedisResult<()> {
+        RedisConnector::spawn_hops(self, request_id, count).await
+    }
+
+    async fn offer_cost(&self, request_id: usize, cost: u64) -> RedisResult<u64> {
+        RedisConnector::offer_cost(self, request_id, cost).await
+    }
+
+    async fn get_best_cost(&self, request_id: usize) -> RedisResult<Option<u64>> {
+        RedisConnector::get_best_cost(self, request_id).await
+    }
+
+    async fn hold_result(&self, result: &RouteResult) -> Result<bool> {
+        RedisConnector::hold_result(self, result).await
+    }
+
+    async fn get_held_result(&self, request_id: usize) -> RedisResult<Option<RouteResult>> {
+        RedisConnector::get_held_result(self, request_id).await
+    }
+
+    async fn claim_answer(&self, request_id: usize) -> RedisResult<bool> {
+        RedisConnector::claim_answer(self, request_id).await
+    }
+
+    async fn mark_answered(&self, request_id: usize) -> RedisResult<()> {
+        RedisConnector::mark_answered(self, request_id).await
+    }
+
+    async fn store_result(&self, result: &RouteResult, ttl: Duration) -> Result<()> {
+        RedisConnector::store_result(self, result, ttl).await
+    }
+
+    async fn finish_hop(&self, request_id: usize) -> RedisResult<bool> {
+        RedisConnector::finish_hop(self, request_id).await
+    }
+
+    async fn record_hop_failure(&self, request_id: usize, failure: &RouteFailure, reason: &str) -> RedisResult<()> {
+        RedisConnector::record_hop_failure(self, request_id, failure, reason).await
+    }
+
+    async fn get_hop_failure(&self, request_id: usize) -> RedisResult<Option<(RouteFailure, String)>> {
+        RedisConnector::get_hop_failure(self, request_id).await
+    }
+}
+
+/// Sends results to clients. Arbitrating, the cheapest result of a query is held back until its
+/// last hop ends, or for at most `timeout` after it was found, so clients receive exactly one.
+#[derive(Clone)]
+pub(crate) struct ResultArbiter {
+    queries: Arc<dyn QueryStore>,
+    result_reply: Box<dyn ResultReplier>,
+    retention: Arc<RetentionConfig>,
+    timeout: Option<Duration>,
+    /// Reported as the server which gave up on the hops it fails.
+    server_id: usize,
+}
+
+impl ResultArbiter {
+    pub(crate) fn new(queries: Arc<dyn QueryStore>,
+                      result_reply: Box<dyn ResultReplier>,
+                      retention: Arc<RetentionConfig>,
+                      timeout: Option<Duration>,
+                      server_id: usize) -> Self {
+        Self {
+            queries,
+            result_reply,
+            retention,
+            timeout,
+            server_id,
+        }
+    }
+
+    /// Sends `result` right away, or holds it if arbitrating and it beats the one held so far.
+    pub(crate) async fn offer(&self, priority_class: Option<&str>, result: RouteResult) -> Result<()> {
+        let timeout = match self.timeout {
+            Some(timeout) => { timeout }
+            None => { return self.send(priority_class, &result).await }
+        };
+        if !self.queries.hold_result(&result).await? {
+            return Ok(());
+        }
+        // Hops lost on the way, e.g. to a crashed server, would keep the query from ever ending.
+        let arbiter = self.clone();
+        let priority_class = priority_class.map(str::to_string);
+        tokio::task::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            let request_id = result.request_id;
+            match arbiter.queries.claim_answer(request_id).await {
+                Ok(true) => {
+                    log::debug!("Request {} is still searched after {:?}, sending the cheapest result so far", request_id, timeout);
+                    if let Err(err) = arbiter.settle(priority_class.as_deref(), result).await {
+                        log::warn!("Unable to send the result of request {}, details: {}", request_id, err);
+                    }
+                }
+                Ok(false) => {}
+                Err(err) => { log::warn!("Unable to claim the answer to request {}, details: {}", request_id, err) }
+            }
+        });
+        Ok(())
+    }
+
+    /// Counts `request` as finished. The last hop of a query in flight sends the result held back for
+    /// it, or tells the client the target wasn't found if no result was sent, or which failure a hop
+    /// of the query was lost to if one was. Failures of hops before the last are recorded for it.
+    pub(crate) async fn finish_hop(&self, request: &HopMessage, weight_scale: WeightScale, served: &Result<()>) {
+        let failure = match served {
+            Err(err) => { failure_kind(&**err).map(|kind| (RouteFailure { kind, server: self.server_id }, err.to_string())) }
+            Ok(()) => { None }
+        };
+        // Recorded before the hop is counted, so the last hop can't finish without seeing it.
+        if let Some((failure, reason)) = &failure {
+            if let Err(err) = self.queries.record_hop_failure(request.request_id, failure, reason).await {
+                log::warn!("Unable to record the failure of request {}, details: {}", request.request_id, err);
+            }
+        }
+        match self.queries.finish_hop(request.request_id).await {
+            Ok(true) => {
+                let failure = match failure {
+                    Some(failure) => { Some(failure) }
+                    None => { self.recorded_failure(request.request_id).await }
+                };
+                let mut unanswered = match (failure, served) {
+                    (Some((failure, reason)), _) => { request.failed(failure, reason) }
+                    (None, Err(err)) => { request.not_found(err.to_string()) }
+                    (None, Ok(())) => { request.not_found("No route leads from the source to the target".to_string()) }
+                };
+                unanswered.weight_scale = weight_scale;
+                if let Err(err) = self.settle(request.priority_class.as_deref(), unanswered).await {
+                    log::warn!("Unable to send the result of request {}, details: {}", request.request_id, err);
+                }
+            }
+            Ok(false) => {}
+            Err(err) => { log::warn!("Unable to count the hops of request {}, details: {}", request.request_id, err) }
+        }
+    }
+
+    /// First failure recorded for a hop of the query, a failure to read it is taken for none.
+    async fn recorded_failure(&self, request_id: usize) -> Option<(RouteFailure, String)> {
+        match self.queries.get_hop_failure(request_id).await {
+            Ok(failure) => { failure }
+            Err(err) => {
+                log::warn!("Unable to read the failures of request {}, details: {}", request_id, err);
+                None
+            }
+        }
+    }
+
+    /// Sends the result held for the query of `fallback`, or `fallback` if none is. Only for whoever
+    /// claimed the answer to the query.
+    pub(crate) async fn settle(&self, priority_class: Option<&str>, fallback: RouteResult) -> Result<()> {
+        let result = match self.queries.get_held_result(fallback.request_id).await? {
+            Some(held) => { held }
+            None => { fallback }
+        };
+        self.send(priority_class, &result).await
+    }
+
+    /// Sends `result`, and keeps it for the retention of its class.
+    async fn send(&self, priority_class: Option<&str>, result: &RouteResult) -> Result<()> {
+        self.result_reply.send(result).await?;
+        if let Err(err) = self.queries.mark_answered(result.request_id).await {
+            log::warn!("Unable to mark request {} answered, details: {}", result.request_id, err);
+        }
+        if let Some(ttl) = self.retention.ttl(priority_class) {
+            if let Err(err) = self.queries.store_result(result, ttl).await {
+                log::warn!("Unable to store the result of request {}, details: {}", result.request_id, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Queries and results kept in memory, for tests of whatever arbitrates them.
+#[cfg(test)]
+pub(crate) mod memory {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use redis::RedisResult;
+    use crate::arbiter::{QueryStore, Result};
+    use crate::domain::{RouteFailure, RouteResult, RouteStatus};
+    use crate::node_connector::{BasicResult, ResultReplier};
+
+    #[derive(Default)]
+    struct State {
+        pending_hops: HashMap<usize, i64>,
+        best_costs: HashMap<usize, u64>,
+        held: HashMap<usize, RouteResult>,
+        answered: HashSet<usize>,
+        failures: HashMap<usize, (RouteFailure, String)>,
+    }
+
+    /// Keeps queries like the scripts of [`crate::redis_connector::RedisConnector`] do in redis.
+    #[derive(Clone, Default)]
+    pub(crate) struct MemoryQueries(Arc<Mutex<State>>);
+
+    #[async_trait::async_trait]
+    impl QueryStore for MemoryQueries {
+        async fn spawn_hops(&self, request_id: usize, count: usize) -> RedisResult<()> {
+            *self.0.lock().unwrap().pending_hops.entry(request_id).or_default() += count as i64;
+            Ok(())
+        }
+
+        async fn offer_cost(&self, request_id: usize, cost: u64) -> RedisResult<u64> {
+            let mut state = self.0.lock().unwrap();
+            let best = state.best_costs.entry(request_id).or_insert(cost);
+            *best = cost.min(*best);
+            Ok(*best)
+        }
+
+        async fn get_best_cost(&self, request_id: usize) -> RedisResult<Option<u64>> {
+            Ok(self.0.lock().unwrap().best_costs.get(&request_id).copied())
+        }
+
+        async fn hold_result(&self, result: &RouteResult) -> Result<bool> {
+            let mut state = self.0.lock().unwrap();
+            if let Some(held) = state.held.get(&result.request_id) {
+                if result.status != RouteStatus::Found || (held.status == RouteStatus::Found && result.cost >= held.cost) {
+                    return Ok(false);
+                }
+            }
+            state.held.insert(result.request_id, result.clone());
+            Ok(true)
+        }
+
+        async fn get_held_result(&self, request_id: usize) -> RedisResult<Option<RouteResult>> {
+            Ok(self.0.lock().unwrap().held.get(&request_id).cloned())
+        }
+
+        async fn claim_answer(&self, request_id: usize) -> RedisResult<bool> {
+            Ok(self.0.lock().unwrap().answered.insert(request_id))
+        }
+
+        async fn mark_answered(&self, request_id: usize) -> RedisResult<()> {
+            self.0.lock().unwrap().answered.insert(request_id);
+            Ok(())
+        }
+
+        async fn store_result(&self, _result: &RouteResult, _ttl: Duration) -> Result<()> {
+            Ok(())
+        }
+
+        async fn finish_hop(&self, request_id: usize) -> RedisResult<bool> {
+            let mut state = self.0.lock().unwrap();
+            let left = state.pending_hops.entry(request_id).or_default();
+            *left -= 1;
+            Ok(*left < 0 && state.answered.insert(request_id))
+        }
+
+        async fn record_hop_failure(&self, request_id: usize, failure: &RouteFailure, reason: &str) -> RedisResult<()> {
+            self.0.lock().unwrap().failures.entry(request_id).or_insert_with(|| (*failure, reason.to_string()));
+            Ok(())
+        }
+
+        async fn get_hop_failure(&self, request_id: usize) -> RedisResult<Option<(RouteFailure, String)>> {
+            Ok(self.0.lock().unwrap().failures.get(&request_id).cloned())
+        }
+    }
+
+    /// Results sent to clients, in the order they were sent.
+    #[derive(Clone, Default)]
+    pub(crate) struct SentResults(pub(crate) Arc<Mutex<Vec<RouteResult>>>);
+
+    impl SentResults {
+        pub(crate) fn of(&self, request_id: usize) -> Vec<RouteResult> {
+            self.0.lock().unwrap().iter().filter(|result| result.request_id == request_id).cloned().collect()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ResultReplier for SentResults {
+        async fn send(&self, reply: &RouteResult) -> BasicResult<()> {
+            self.0.lock().unwrap().push(reply.clone());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use redis::RedisError;
+    use crate::arbiter::{failure_kind, parse_timeout, HopFailure, QueryStore, ResultArbiter};
+    use crate::arbiter::memory::{MemoryQueries, SentResults};
+    use crate::domain::{FailureKind, HopMessage, NodeInfo, RouteStatus};
+    use crate::graph::{GraphError, WeightScale};
+    use crate::node_connector::ConnectionError;
+    use crate::retention::RetentionConfig;
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+    #[test]
+    fn failures_are_told_from_missing_routes() {
+        let unreachable: BoxedError = GraphError::Unreachable(9, 3).into();
+        assert_eq!(failure_kind(&*unreachable), None);
+        let missing_node: BoxedError = GraphError::StartNodeNotFound(9, 3).into();
+        assert_eq!(failure_kind(&*missing_node), Some(FailureKind::Graph));
+        let down: BoxedError = ConnectionError::ServerDown(2).into();
+        assert_eq!(failure_kind(&*down), Some(FailureKind::ServerDown));
+        let unknown_server: BoxedError = ConnectionError::TargetDoesNotExist(2).into();
+        assert_eq!(failure_kind(&*unknown_server), Some(FailureKind::Send));
+        let looping: BoxedError = HopFailure::new(FailureKind::HopLimit, "Crossed 129 region boundaries".to_string()).into();
+        assert_eq!(failure_kind(&*looping), Some(FailureKind::HopLimit));
+        let redis: BoxedError = RedisError::from((redis::ErrorKind::TypeError, "Response was of incompatible type")).into();
+        assert_eq!(failure_kind(&*redis), Some(FailureKind::Internal));
+    }
+
+    fn arbiter(queries: &MemoryQueries, sent: &SentResults) -> ResultArbiter {
+        let retention = Arc::new(RetentionConfig::default());
+        ResultArbiter::new(Arc::new(queries.clone()), Box::new(sent.clone()), retention, Some(Duration::from_secs(60)), 4)
+    }
+
+    fn hop(request_id: usize) -> HopMessage {
+        HopMessage::new(request_id, NodeInfo(1, 1), NodeInfo(9, 2), 1, vec![], 0, vec![1])
+    }
+
+    #[tokio::test]
+    async fn only_the_cheapest_route_is_sent() {
+        let (queries, sent) = (MemoryQueries::default(), SentResults::default());
+        let arbiter = arbiter(&queries, &sent);
+        let request = hop(7);
+        queries.spawn_hops(7, 1).await.unwrap();
+        arbiter.offer(None, request.finish(vec![], 30)).await.unwrap();
+        arbiter.offer(None, request.finish(vec![], 20)).await.unwrap();
+        arbiter.offer(None, request.finish(vec![], 25)).await.unwrap();
+        arbiter.finish_hop(&request, WeightScale::default(), &Ok(())).await;
+        assert!(sent.of(7).is_empty());
+
+        // A hop failing after a route was found doesn't turn the answer into a failure.
+        let failed = Err(HopFailure::new(FailureKind::Send, "Server 3 is unreachable".to_string()).into());
+        arbiter.finish_hop(&request, WeightScale::default(), &failed).await;
+        let answers = sent.of(7);
+        assert_eq!(answers.len(), 1);
+        assert_eq!((answers[0].status, answers[0].cost), (RouteStatus::Found, 20));
+    }
+
+    #[tokio::test]
+    async fn failures_are_sent_only_without_a_route() {
+        let (queries, sent) = (MemoryQueries::default(), SentResults::default());
+        let arbiter = arbiter(&queries, &sent);
+        let request = hop(8);
+        queries.spawn_hops(8, 1).await.unwrap();
+        let failed = Err(HopFailure::new(FailureKind::Send, "Server 3 is unreachable".to_string()).into());
+        arbiter.finish_hop(&request, WeightScale::default(), &failed).await;
+        assert!(sent.of(8).is_empty());
+
+        arbiter.finish_hop(&request, WeightScale::default(), &Ok(())).await;
+        let answers = sent.of(8);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].status, RouteStatus::Failed);
+        assert_eq!(answers[0].failure.map(|failure| (failure.kind, failure.server)), Some((FailureKind::Send, 4)));
+
+        arbiter.finish_hop(&hop(9), WeightScale::default(), &Ok(())).await;
+        assert_eq!(sent.of(9).iter().map(|result| result.status).collect::<Vec<_>>(), vec![RouteStatus::NotFound]);
+    }
+
+    #[test]
+    fn arbitration_is_on_unless_turned_off() {
+        assert_eq!(parse_timeout(None).unwrap(), Some(Duration::from_millis(5000)));
+        assert_eq!(parse_timeout(Some("200")).unwrap(), Some(Duration::from_millis(200)));
+        assert_eq!(parse_timeout(Some("0")).unwrap(), None);
+        assert!(parse_timeout(Some("soon")).is_err());
+    }
+}
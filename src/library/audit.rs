@@ -0,0 +1,147 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Formatter;
+use crate::graph::{Graph, Node, RegionIdx, VertexIdx};
+
+/// Inconsistency between the weights of neighbouring regions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Anomaly {
+    /// A border edge is stored with different weights in the files of the two regions.
+    BorderWeightMismatch {
+        vertex: VertexIdx,
+        weights: [(RegionIdx, u64); 2],
+    },
+    /// Typical cost of a coordinate unit differs between two neighbouring regions.
+    ScaleMismatch {
+        regions: (RegionIdx, RegionIdx),
+        scales: (f64, f64),
+    },
+}
+
+impl std::fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Anomaly::BorderWeightMismatch { vertex, weights: [(r1, w1), (r2, w2)] } => {
+                write!(f, "Border vertex {} weighs {} in region {} but {} in region {}", vertex, w1, r1, w2, r2)
+            }
+            Anomaly::ScaleMismatch { regions: (r1, r2), scales: (s1, s2) } => {
+                write!(f, "Regions {} and {} differ in scale: median cost per coordinate unit {:.3} vs {:.3}", r1, r2, s1, s2)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuditReport {
+    /// Median cost per coordinate unit of the edges inside each region.
+    pub scales: BTreeMap<RegionIdx, f64>,
+    pub borders: BTreeSet<(RegionIdx, RegionIdx)>,
+    pub border_vertices: usize,
+    pub anomalies: Vec<Anomaly>,
+}
+
+fn length(a: &Node, b: &Node) -> f64 {
+    let (ax, ay) = a.coordinates();
+    let (bx, by) = b.coordinates();
+    let (dx, dy) = (ax.abs_diff(bx) as f64, ay.abs_diff(by) as f64);
+    (dx * dx + dy * dy).sqrt()
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    Some(values[values.len() / 2])
+}
+
+fn deviates(a: f64, b: f64, tolerance: f64) -> bool {
+    a.max(b) > a.min(b) * (1. + tolerance)
+}
+
+/// Compares border edge weights on both sides of every border between the given regions, and the
+/// scale of each region with its neighbours. Values further apart than `tolerance` (relative) are reported.
+pub fn audit_weights(graphs: &HashMap<RegionIdx, Graph>, tolerance: f64) -> AuditReport {
+    let mut report = AuditReport::default();
+    let mut border_weights: BTreeMap<VertexIdx, Vec<(RegionIdx, u64)>> = BTreeMap::new();
+    for (region, graph) in graphs.iter() {
+        let mut ratios = vec![];
+        for vertex in graph.vertices() {
+            let (a, b) = match (graph.get_node(vertex.a), graph.get_node(vertex.b)) {
+                (Some(a), Some(b)) => { (a, b) }
+                _ => { continue }
+            };
+            if a.region() != b.region() {
+                report.borders.insert((a.region().min(b.region()), a.region().max(b.region())));
+                border_weights.entry(vertex.id).or_default().push((*region, vertex.weight));
+            } else if length(&a, &b) > 0. {
+                ratios.push(vertex.weight as f64 / length(&a, &b));
+            }
+        }
+        if let Some(scale) = median(ratios) {
+            report.scales.insert(*region, scale);
+        }
+    }
+
+    report.border_vertices = border_weights.len();
+    for (vertex, weights) in border_weights {
+        if let [(r1, w1), (r2, w2)] = weights[..] {
+            if deviates(w1 as f64, w2 as f64, tolerance) {
+                let mut weights = [(r1, w1), (r2, w2)];
+                weights.sort();
+                report.anomalies.push(Anomaly::BorderWeightMismatch { vertex, weights });
+            }
+        }
+    }
+    for (r1, r2) in report.borders.iter() {
+        if let (Some(s1), Some(s2)) = (report.scales.get(r1), report.scales.get(r2)) {
+            if deviates(*s1, *s2, tolerance) {
+                report.anomalies.push(Anomaly::ScaleMismatch { regions: (*r1, *r2), scales: (*s1, *s2) });
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use bitvec::vec::BitVec;
+    use crate::audit::{audit_weights, Anomaly};
+    use crate::graph::{Access, Graph, Node, RegionIdx, Vertex};
+
+    /// Nodes 1-2 in region 1, 3-4 in region 2, on a line one unit apart. Every region also
+    /// stores the border edge 2-3 and its foreign end.
+    fn region(region: RegionIdx, scale: u64, border_weight: u64) -> Graph {
+        let ids = if region == 1 { [1, 2, 3] } else { [2, 3, 4] };
+        let mut nodes: HashMap<_, _> = ids.iter()
+            .map(|id| (*id, Node::new(vec![], *id, if *id <= 2 { 1 } else { 2 }, *id as u64, 0)))
+            .collect();
+        let edges = if region == 1 { [(1, 1, 2, scale), (2, 2, 3, border_weight)] } else { [(2, 2, 3, border_weight), (3, 3, 4, scale)] };
+        let mut vertices = HashMap::new();
+        for (id, a, b, weight) in edges {
+            nodes.get_mut(&a).unwrap().connections.push(id);
+            nodes.get_mut(&b).unwrap().connections.push(id);
+            vertices.insert(id, Vertex { a, b, weight, id, region_bits: BitVec::repeat(true, 3), access: Access::ALL });
+        }
+        Graph::new(nodes, vertices, region)
+    }
+
+    #[test]
+    fn consistent_regions() {
+        let graphs = HashMap::from([(1, region(1, 10, 10)), (2, region(2, 11, 10))]);
+        let report = audit_weights(&graphs, 0.2);
+        assert_eq!(report.border_vertices, 1);
+        assert_eq!(report.borders.len(), 1);
+        assert!(report.anomalies.is_empty());
+    }
+
+    #[test]
+    fn mismatched_regions() {
+        let graphs = HashMap::from([(1, region(1, 10, 10)), (2, region(2, 1000, 1000))]);
+        let report = audit_weights(&graphs, 0.2);
+        assert_eq!(report.anomalies, vec![
+            Anomaly::BorderWeightMismatch { vertex: 2, weights: [(1, 10), (2, 1000)] },
+            Anomaly::ScaleMismatch { regions: (1, 2), scales: (10., 1000.) },
+        ]);
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use crate::graph::NodeIdx;
+
+/// Monotone bucket queue (Dial's algorithm) for small integer priorities.
+///
+/// Nodes are kept in a circular array of buckets indexed by priority, so both push and pop
+/// are O(1) amortized as long as the priorities of queued nodes stay within a window of
+/// `buckets.len()`, which holds for Dijkstra and for A* with a consistent heuristic when the
+/// window covers twice the largest edge weight. The window starts at the lowest queued priority
+/// rather than at zero, since A* estimates begin far from it. It grows if the queued priorities
+/// spread beyond it.
+pub(crate) struct BucketQueue {
+    buckets: Vec<Vec<NodeIdx>>,
+    /// Current priority of every queued node. Bucket entries which don't match it are stale.
+    priorities: HashMap<NodeIdx, u64>,
+    /// No queued node has a lower priority than this.
+    current: u64,
+    /// No queued node has a higher priority than this.
+    highest: u64,
+    /// Priority of the last popped node. Lower priorities are queued at it.
+    popped: u64,
+}
+
+impl BucketQueue {
+    pub(crate) fn new(max_weight: u64) -> Self {
+        Self {
+            buckets: vec![vec![]; 2 * max_weight as usize + 2],
+            priorities: HashMap::new(),
+            current: 0,
+            highest: 0,
+            popped: 0,
+        }
+    }
+
+    /// Queues `node` or lowers its priority. Priorities are never raised.
+    pub(crate) fn push_decrease(&mut self, node: NodeIdx, priority: u64) {
+        let priority = priority.max(self.popped);
+        if self.priorities.get(&node).is_some_and(|queued| *queued <= priority) {
+            return;
+        }
+        if self.priorities.is_empty() {
+            (self.current, self.highest) = (priority, priority);
+        }
+        let (lowest, highest) = (self.current.min(priority), self.highest.max(priority));
+        if highest - lowest >= self.buckets.len() as u64 {
+            self.grow(highest - lowest + 1);
+        }
+        (self.current, self.highest) = (lowest, highest);
+        self.priorities.insert(node, priority);
+        let bucket = (priority % self.buckets.len() as u64) as usize;
+        self.buckets[bucket].push(node);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.priorities.len()
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<(NodeIdx, u64)> {
+        while !self.priorities.is_empty() {
+            let bucket = (self.current % self.buckets.len() as u64) as usize;
+            while let Some(node) = self.buckets[bucket].pop() {
+                if self.priorities.get(&node) == Some(&self.current) {
+                    self.priorities.remove(&node);
+                    self.popped = self.current;
+                    return Some((node, self.current));
+                }
+            }
+            self.current += 1;
+        }
+        None
+    }
+
+    fn grow(&mut self, min_window: u64) {
+        let window = (min_window as usize).max(2 * self.buckets.len());
+        self.buckets = vec![vec![]; window];
+        for (node, priority) in self.priorities.iter() {
+            self.buckets[(*priority % window as u64) as usize].push(*node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bucket_queue::BucketQueue;
+
+    #[test]
+    fn pops_in_priority_order() {
+        let mut queue = BucketQueue::new(3);
+        queue.push_decrease(1, 5);
+        queue.push_decrease(2, 2);
+        queue.push_decrease(3, 7);
+        queue.push_decrease(1, 4);
+        queue.push_decrease(3, 9);
+        assert_eq!(queue.priorities.len(), 3);
+        assert_eq!(queue.pop(), Some((2, 2)));
+        queue.push_decrease(4, 3);
+        assert_eq!(queue.pop(), Some((4, 3)));
+        assert_eq!(queue.pop(), Some((1, 4)));
+        assert_eq!(queue.pop(), Some((3, 7)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn grows_for_distant_priorities() {
+        let mut queue = BucketQueue::new(1);
+        queue.push_decrease(1, 100);
+        queue.push_decrease(2, 3);
+        assert_eq!(queue.pop(), Some((2, 3)));
+        assert_eq!(queue.pop(), Some((1, 100)));
+    }
+
+    #[test]
+    fn starts_the_window_at_the_first_priority() {
+        let mut queue = BucketQueue::new(3);
+        queue.push_decrease(1, 0);
+        assert_eq!(queue.pop(), Some((1, 0)));
+        // The first estimate of an A* search lies far from the start at 0.
+        queue.push_decrease(2, 1_000_005);
+        queue.push_decrease(3, 1_000_001);
+        queue.push_decrease(4, 1_000_003);
+        assert_eq!(queue.buckets.len(), 8);
+        assert_eq!(queue.pop(), Some((3, 1_000_001)));
+        assert_eq!(queue.pop(), Some((4, 1_000_003)));
+        assert_eq!(queue.pop(), Some((2, 1_000_005)));
+        queue.push_decrease(5, 2);
+        assert_eq!(queue.pop(), Some((5, 1_000_005)));
+        assert_eq!(queue.buckets.len(), 8);
+    }
+}
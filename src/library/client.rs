@@ -0,0 +1,208 @@
+use std::time::Duration;
+use futures_util::{Stream, StreamExt as _};
+use redis::{AsyncCommands, RedisError, RedisResult};
+use crate::codec;
+use crate::dead_letter::{self, DeadLetter};
+use crate::domain::RouteResult;
+use crate::events::QueryEvent;
+use crate::graph::RegionIdx;
+use crate::inspect::{self, RegionReport};
+use crate::migration::{self, Migration};
+use crate::queues::{self, QueueReport};
+use crate::usage::{self, RegionUsage};
+
+/// Request ids handed out by the cluster, e.g. to queries submitted over HTTP, have this bit set.
+/// Clients choosing their own ids keep them below it, so the two never collide. Ids stay below
+/// 2^53 either way, where JSON numbers read as doubles are still exact.
+pub const ISSUED_REQUEST_ID_BIT: usize = 1 << 52;
+
+/// Redis channel on which the results of a query are published.
+pub fn results_channel(request_id: usize) -> String {
+    format!("results_{}", request_id)
+}
+
+/// Pattern matching the [`results_channel`] of every query.
+pub(crate) fn results_channel_pattern() -> &'static str {
+    "results_*"
+}
+
+/// Redis channel on which the progress and the results of a query are published as
+/// [`QueryEvent`](crate::events::QueryEvent)s, see [`ClientQuery::stream_events`](crate::domain::ClientQuery::stream_events).
+pub fn events_channel(request_id: usize) -> String {
+    format!("events_{}", request_id)
+}
+
+/// Pattern matching the [`events_channel`] of every query.
+pub(crate) fn events_channel_pattern() -> &'static str {
+    "events_*"
+}
+
+/// Redis key under which the result of a query is kept, if results are retained.
+pub fn result_key(request_id: usize) -> String {
+    format!("result_{}", request_id)
+}
+
+/// Receives query results from a cluster connected through redis. In ZMQ mode results are sent to
+/// the REPLY_ADDR collector instead.
+pub struct ResultsClient {
+    client: redis::Client,
+}
+
+impl ResultsClient {
+    pub fn new(redis_url: &str) -> RedisResult<Self> {
+        Ok(Self {
+            client: redis::Client::open(redis_url)?,
+        })
+    }
+
+    /// Result of a query kept by the servers, the cheapest sent so far. Only kept with
+    /// RESULT_RETENTION set, and until it expires.
+    pub async fn get_result(&self, request_id: usize) -> RedisResult<Option<RouteResult>> {
+        let mut conn = self.client.get_async_connection().await?;
+        conn.get(result_key(request_id)).await
+    }
+
+    /// Weight and degree statistics of a region from the server loading it, `None` if no server
+    /// answers within `timeout`.
+    pub async fn region_report(&self, region: RegionIdx, timeout: Duration) -> RedisResult<Option<RegionReport>> {
+        let reply_channel = format!("region_stats_reply_{}", uuid::Uuid::new_v4());
+        let mut pubsub = self.client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(&reply_channel).await?;
+        let mut conn = self.client.get_async_connection().await?;
+        let _: () = conn.publish(inspect::channel(region), &reply_channel).await?;
+        let mut messages = pubsub.on_message();
+        match tokio::time::timeout(timeout, messages.next()).await {
+            Ok(Some(message)) => {
+                let payload: String = message.get_payload()?;
+                let report = serde_json::from_str(&payload).map_err(|err| {
+                    RedisError::from((redis::ErrorKind::TypeError, "Undecodable region report", err.to_string()))
+                })?;
+                Ok(Some(report))
+            }
+            _ => { Ok(None) }
+        }
+    }
+
+    /// Depth of the queues of server `server_id` as it last reported it, `None` if it stopped.
+    pub async fn queue_report(&self, server_id: usize) -> RedisResult<Option<QueueReport>> {
+        let mut conn = self.client.get_async_connection().await?;
+        let raw: Option<String> = conn.get(queues::report_key(server_id)).await?;
+        raw.map(|raw| serde_json::from_str(&raw).map_err(|err| {
+            RedisError::from((redis::ErrorKind::TypeError, "Undecodable queue report", err.to_string()))
+        })).transpose()
+    }
+
+    /// Work done in `region` by all servers hosting it since its usage was last reset, see
+    /// [`usage`](crate::usage).
+    pub async fn region_usage(&self, region: RegionIdx) -> RedisResult<RegionUsage> {
+        let mut conn = self.client.get_async_connection().await?;
+        let fields = conn.hgetall(usage::usage_key(region)).await?;
+        Ok(RegionUsage::from_fields(&fields))
+    }
+
+    /// The `count` most recent dead letters of server `server_id`, newest first.
+    pub async fn dead_letters(&self, server_id: usize, count: usize) -> RedisResult<Vec<DeadLetter>> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        let mut conn = self.client.get_async_connection().await?;
+        let raw: Vec<String> = conn.lrange(dead_letter::list_key(server_id), 0, count as isize - 1).await?;
+        raw.iter()
+            .map(|raw| serde_json::from_str(raw).map_err(|err| {
+                RedisError::from((redis::ErrorKind::TypeError, "Undecodable dead letter", err.to_string()))
+            }))
+            .collect()
+    }
+
+    /// Asks server `server_id` to send its `count` oldest dead letters again, returns whether a
+    /// server got the request. Letters failing again are kept anew.
+    pub async fn replay_dead_letters(&self, server_id: usize, count: usize) -> RedisResult<bool> {
+        let mut conn = self.client.get_async_connection().await?;
+        let receivers: usize = conn.publish(dead_letter::replay_channel(server_id), count).await?;
+        Ok(receivers > 0)
+    }
+
+    /// Asks server `from` to hand `region` over to server `to`, returns whether it got the request.
+    /// The handover is carried out by the servers, see the logs of both for its outcome.
+    pub async fn migrate_region(&self, region: RegionIdx, from: usize, to: usize) -> RedisResult<bool> {
+        let mut conn = self.client.get_async_connection().await?;
+        let command = serde_json::to_string(&Migration::Shed { region, to }).expect("Migrations serialize");
+        let receivers: usize = conn.publish(migration::channel(from), command).await?;
+        Ok(receivers > 0)
+    }
+
+    /// Drops the dead letters of server `server_id`, returns how many there were.
+    pub async fn clear_dead_letters(&self, server_id: usize) -> RedisResult<usize> {
+        let mut conn = self.client.get_async_connection().await?;
+        let key = dead_letter::list_key(server_id);
+        let (count,): (usize,) = redis::pipe().atomic()
+            .llen(&key)
+            .del(&key).ignore()
+            .query_async(&mut conn).await?;
+        Ok(count)
+    }
+
+    /// Events of every query as they are published on `channel`, the PROGRESS_CHANNEL of the
+    /// servers. Undecodable ones are logged and skipped, the stream never ends on its own.
+    pub async fn progress_stream(&self, channel: &str) -> RedisResult<impl Stream<Item=QueryEvent> + Send> {
+        let mut pubsub = self.client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(channel).await?;
+        Ok(pubsub.into_on_message().filter_map(|message| async move {
+            match codec::decode::<QueryEvent>(message.get_payload_bytes()) {
+                Ok(event) => { Some(event) }
+                Err(err) => {
+                    log::warn!("Skipping undecodable event on {}, details: {}", message.get_channel_name(), err);
+                    None
+                }
+            }
+        }))
+    }
+
+    /// Results of `request_ids` as they are published, undecodable ones are logged and skipped.
+    ///
+    /// Redis doesn't keep published messages, so subscribe before sending the queries. A query may
+    /// be answered more than once when cheaper routes turn up later, hence the stream never ends on
+    /// its own: bound it with combinators such as `take` or `take_until`.
+    pub async fn results_stream(&self, request_ids: impl IntoIterator<Item=usize>) -> RedisResult<impl Stream<Item=RouteResult> + Send> {
+        let mut pubsub = self.client.get_async_connection().await?.into_pubsub();
+        for request_id in request_ids {
+            pubsub.subscribe(results_channel(request_id)).await?;
+        }
+        Ok(pubsub.into_on_message().filter_map(|message| async move {
+            match message.get_payload::<RouteResult>() {
+                Ok(result) => { Some(result) }
+                Err(err) => {
+                    log::warn!("Skipping undecodable result on {}, details: {}", message.get_channel_name(), err);
+                    None
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use redis::{FromRedisValue, Value};
+    use crate::codec;
+    use crate::domain::{NodeInfo, RouteResult, RouteStatus};
+    use crate::graph::WeightScale;
+
+    #[test]
+    fn published_results_decode() {
+        let result = RouteResult {
+            request_id: 7,
+            source: NodeInfo(1, 1),
+            target: NodeInfo(2, 2),
+            path: vec![],
+            cost: 12,
+            status: RouteStatus::Found,
+            weight_scale: WeightScale(4),
+            reason: None,
+            failure: None,
+        };
+        let published = Value::Data(codec::encode(&result).unwrap().to_vec());
+        let decoded = RouteResult::from_redis_value(&published).unwrap();
+        assert_eq!((decoded.request_id, decoded.cost, decoded.real_cost()), (7, 12, 3.));
+        assert!(RouteResult::from_redis_value(&Value::Data(b"{".to_vec())).is_err());
+    }
+}
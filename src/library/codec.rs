@@ -0,0 +1,524 @@
+//! Wire formats of the payloads exchanged by servers and clients. Hops between servers are
+//! encoded in the format chosen with WIRE_FORMAT, everything read by clients (results, events,
+//! stored routes) stays JSON. Binary payloads start with a format byte, which no JSON document
+//! starts with, so payloads of any format are decoded and clusters mixing formats interoperate,
+//! e.g. while switching them one server at a time.
+//!
+//! There is no bincode format: messages rely on self-describing formats, fresh queries and hops
+//! arrive on the same listeners told apart by their fields, and optional fields are left out.
+//!
+//! Hops and results sent by transports are compressed with zstd above the size set with
+//! PAYLOAD_COMPRESSION_THRESHOLD, which needs the `compressed-payloads` feature. Their format byte
+//! has the [`COMPRESSED`] bit set then, and is followed by the compressed payload.
+//!
+//! With a cluster secret set, see [`crate::signing`], hops are sent in a signed envelope: the
+//! [`SIGNED`] byte, the tag of the payload and the payload, compressed or not. Listeners decode
+//! messages with [`WireConfig::decode_inbound`], which refuses messages without a valid tag then.
+//!
+//! The format, the threshold and the secret of a server are kept in the [`WireConfig`] its
+//! transports are built with.
+
+use std::cell::RefCell;
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use bytes::Bytes;
+use redis::{ErrorKind, RedisError, RedisResult, Value};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::signing::{ClusterSecret, TAG_LEN};
+
+/// Number of spare buffers kept per thread.
+const MAX_POOLED_BUFFERS: usize = 4;
+/// Buffers which grew past this size are freed instead of pooled, so a single huge path
+/// doesn't pin its memory for the lifetime of the thread.
+const MAX_POOLED_CAPACITY: usize = 1 << 20;
+/// Format byte of MessagePack payloads.
+const MESSAGE_PACK: u8 = 0x01;
+/// Flag of the format byte of compressed payloads, the other bits tell the format of the payload
+/// once decompressed, 0 for JSON. No JSON document starts with a byte having it.
+const COMPRESSED: u8 = 0x80;
+#[cfg(feature = "compressed-payloads")]
+const COMPRESSION_LEVEL: i32 = 3;
+/// Format byte of signed envelopes, followed by the tag and the payload it authenticates. No JSON
+/// document starts with it.
+const SIGNED: u8 = 0x40;
+/// Compressed payloads inflating past this size are refused, a few bytes of zstd must not
+/// allocate gigabytes.
+#[cfg(feature = "compressed-payloads")]
+const MAX_DECOMPRESSED_LEN: usize = 64 << 20;
+
+thread_local! {
+    static BUFFERS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    /// MessagePack with field names, about a third smaller than JSON for long paths.
+    MessagePack,
+}
+
+impl WireFormat {
+    /// Reads WIRE_FORMAT, `json` (default) or `msgpack`.
+    pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        match env::var("WIRE_FORMAT") {
+            Ok(format) => { Ok(format.parse()?) }
+            Err(_) => { Ok(WireFormat::Json) }
+        }
+    }
+}
+
+impl FromStr for WireFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => { Ok(WireFormat::Json) }
+            "msgpack" | "messagepack" => { Ok(WireFormat::MessagePack) }
+            _ => { Err(format!("Unknown wire format {}, expected json or msgpack", s)) }
+        }
+    }
+}
+
+/// Reads PAYLOAD_COMPRESSION_THRESHOLD, the size in bytes above which hops and results are sent
+/// compressed. Payloads aren't compressed if it isn't set.
+pub(crate) fn compression_threshold_from_env() -> std::result::Result<Option<usize>, Box<dyn std::error::Error + Send + Sync>> {
+    match env::var("PAYLOAD_COMPRESSION_THRESHOLD") {
+        Ok(threshold) if cfg!(feature = "compressed-payloads") => { Ok(Some(threshold.parse()?)) }
+        Ok(_) => { Err("PAYLOAD_COMPRESSION_THRESHOLD needs the compressed-payloads feature".into()) }
+        Err(_) => { Ok(None) }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum CodecError {
+    Json(serde_json::Error),
+    MessagePackEncode(rmp_serde::encode::Error),
+    MessagePackDecode(rmp_serde::decode::Error),
+    Compression(std::io::Error),
+    Signing(openssl::error::ErrorStack),
+    Unauthenticated(&'static str),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Json(err) => { err.fmt(f) }
+            CodecError::MessagePackEncode(err) => { err.fmt(f) }
+            CodecError::MessagePackDecode(err) => { err.fmt(f) }
+            CodecError::Compression(err) => { write!(f, "Cannot (de)compress payload, details: {}", err) }
+            CodecError::Signing(err) => { write!(f, "Cannot sign payload, details: {}", err) }
+            CodecError::Unauthenticated(reason) => { write!(f, "Refusing unauthenticated message: {}", reason) }
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(err: serde_json::Error) -> Self {
+        CodecError::Json(err)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for CodecError {
+    fn from(err: rmp_serde::encode::Error) -> Self {
+        CodecError::MessagePackEncode(err)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for CodecError {
+    fn from(err: rmp_serde::decode::Error) -> Self {
+        CodecError::MessagePackDecode(err)
+    }
+}
+
+impl From<std::io::Error> for CodecError {
+    fn from(err: std::io::Error) -> Self {
+        CodecError::Compression(err)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for CodecError {
+    fn from(err: openssl::error::ErrorStack) -> Self {
+        CodecError::Signing(err)
+    }
+}
+
+pub(crate) type Result<T> = std::result::Result<T, CodecError>;
+
+/// A wire format, writing and reading payloads after the format byte.
+pub(crate) trait Codec {
+    /// Byte the payloads of the format start with, none for JSON.
+    const FORMAT_BYTE: Option<u8>;
+
+    fn write<T: Serialize + ?Sized>(value: &T, buffer: &mut Vec<u8>) -> Result<()>;
+
+    fn read<T: DeserializeOwned>(raw: &[u8]) -> Result<T>;
+}
+
+pub(crate) struct Json;
+
+impl Codec for Json {
+    const FORMAT_BYTE: Option<u8> = None;
+
+    fn write<T: Serialize + ?Sized>(value: &T, buffer: &mut Vec<u8>) -> Result<()> {
+        Ok(serde_json::to_writer(buffer, value)?)
+    }
+
+    fn read<T: DeserializeOwned>(raw: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(raw)?)
+    }
+}
+
+pub(crate) struct MessagePack;
+
+impl Codec for MessagePack {
+    const FORMAT_BYTE: Option<u8> = Some(MESSAGE_PACK);
+
+    fn write<T: Serialize + ?Sized>(value: &T, buffer: &mut Vec<u8>) -> Result<()> {
+        // Named fields, optional ones may be left out and messages are told apart by their fields.
+        Ok(rmp_serde::encode::write_named(buffer, value)?)
+    }
+
+    fn read<T: DeserializeOwned>(raw: &[u8]) -> Result<T> {
+        Ok(rmp_serde::from_slice(raw)?)
+    }
+}
+
+/// Serializes `value` in format `C` in a pooled buffer and hands the bytes to `f`.
+///
+/// Every hop is serialized at least once, so reusing the buffer saves the repeated
+/// growth reallocations `serde_json::to_vec` does for long paths.
+pub(crate) fn with_encoded_as<C, T, R, F>(value: &T, f: F) -> Result<R>
+    where C: Codec,
+          T: Serialize + ?Sized,
+          F: FnOnce(&[u8]) -> R {
+    let mut buffer = BUFFERS.with(|buffers| buffers.borrow_mut().pop()).unwrap_or_default();
+    buffer.clear();
+    buffer.extend(C::FORMAT_BYTE);
+    let res = C::write(value, &mut buffer).map(|_| f(&buffer));
+    if buffer.capacity() <= MAX_POOLED_CAPACITY {
+        BUFFERS.with(|buffers| {
+            let mut buffers = buffers.borrow_mut();
+            if buffers.len() < MAX_POOLED_BUFFERS {
+                buffers.push(buffer);
+            }
+        });
+    }
+    res
+}
+
+/// Serializes `value` to JSON, see [`with_encoded_as`].
+pub(crate) fn with_encoded<T, R, F>(value: &T, f: F) -> Result<R>
+    where T: Serialize + ?Sized,
+          F: FnOnce(&[u8]) -> R {
+    with_encoded_as::<Json, _, _, _>(value, f)
+}
+
+/// How a server encodes what its transports send and checks what they receive: the format of
+/// hops, the size above which hops and results are compressed and the cluster secret. Every
+/// transport of the server is built with a copy of it.
+#[derive(Debug, Clone, Default)]
+pub struct WireConfig {
+    format: WireFormat,
+    /// No payload is compressed without one.
+    compression_threshold: Option<usize>,
+    secret: Option<ClusterSecret>,
+}
+
+impl WireConfig {
+    pub(crate) fn new(format: WireFormat, compression_threshold: Option<usize>, secret: Option<ClusterSecret>) -> Self {
+        Self {
+            format,
+            compression_threshold,
+            secret,
+        }
+    }
+
+    pub(crate) fn secret(&self) -> Option<&ClusterSecret> {
+        self.secret.as_ref()
+    }
+
+    fn threshold(&self) -> usize {
+        self.compression_threshold.unwrap_or(usize::MAX)
+    }
+
+    /// Serializes a hop in the format of the server, compressed above its threshold and signed
+    /// with its secret if it has one, see [`with_encoded_as`].
+    pub(crate) fn with_encoded_hop<T, R, F>(&self, value: &T, f: F) -> Result<R>
+        where T: Serialize + ?Sized,
+              F: FnOnce(&[u8]) -> R {
+        let threshold = self.threshold();
+        let sign = |payload: &[u8]| with_signed(payload, self.secret(), f);
+        match self.format {
+            WireFormat::Json => { with_encoded_as::<Json, _, _, _>(value, |raw| with_compressed(raw, threshold, sign))?? }
+            WireFormat::MessagePack => { with_encoded_as::<MessagePack, _, _, _>(value, |raw| with_compressed(raw, threshold, sign))?? }
+        }
+    }
+
+    /// Serializes a result sent to a collector to JSON, compressed above the threshold of the
+    /// server, see [`with_encoded_as`].
+    pub(crate) fn with_encoded_reply<T, R, F>(&self, value: &T, f: F) -> Result<R>
+        where T: Serialize + ?Sized,
+              F: FnOnce(&[u8]) -> R {
+        let threshold = self.threshold();
+        with_encoded(value, |raw| with_compressed(raw, threshold, f))?
+    }
+
+    /// Serializes a hop like [`encode`], see [`WireConfig::with_encoded_hop`].
+    pub(crate) fn encode_hop<T: Serialize + ?Sized>(&self, value: &T) -> Result<Bytes> {
+        self.with_encoded_hop(value, Bytes::copy_from_slice)
+    }
+
+    /// Serializes a result like [`encode`], see [`WireConfig::with_encoded_reply`].
+    pub(crate) fn encode_reply<T: Serialize + ?Sized>(&self, value: &T) -> Result<Bytes> {
+        self.with_encoded_reply(value, Bytes::copy_from_slice)
+    }
+
+    /// Deserializes a message received by a listener, see [`authenticate`].
+    pub(crate) fn decode_inbound<T: DeserializeOwned>(&self, raw: &[u8]) -> Result<T> {
+        decode(authenticate(raw, self.secret())?)
+    }
+
+    /// Deserializes a message received by a listener straight from the redis value, see
+    /// [`WireConfig::decode_inbound`].
+    pub(crate) fn decode_inbound_redis<T: DeserializeOwned>(&self, v: &Value) -> RedisResult<T> {
+        decode_redis_with(v, |raw| self.decode_inbound(raw))
+    }
+}
+
+/// Hands the encoded payload `raw` to `f`, compressed if it is longer than `threshold`.
+fn with_compressed<R, F: FnOnce(&[u8]) -> R>(raw: &[u8], threshold: usize, f: F) -> Result<R> {
+    if raw.len() <= threshold {
+        return Ok(f(raw));
+    }
+    Ok(f(&compress(raw)?))
+}
+
+/// Hands `payload` to `f`, in a signed envelope if there is a `secret`.
+fn with_signed<R, F: FnOnce(&[u8]) -> R>(payload: &[u8], secret: Option<&ClusterSecret>, f: F) -> Result<R> {
+    let secret = match secret {
+        Some(secret) => { secret }
+        None => { return Ok(f(payload)) }
+    };
+    let mut signed = Vec::with_capacity(1 + TAG_LEN + payload.len());
+    signed.push(SIGNED);
+    signed.extend(secret.tag(payload)?);
+    signed.extend_from_slice(payload);
+    Ok(f(&signed))
+}
+
+/// Payload of a message received by a listener. With a `secret` it has to be in a signed envelope
+/// with a valid tag, without one an envelope is taken off unverified.
+fn authenticate<'a>(raw: &'a [u8], secret: Option<&ClusterSecret>) -> Result<&'a [u8]> {
+    if raw.first() != Some(&SIGNED) {
+        return match secret {
+            Some(_) => { Err(CodecError::Unauthenticated("message isn't signed")) }
+            None => { Ok(raw) }
+        };
+    }
+    if raw.len() < 1 + TAG_LEN {
+        return Err(CodecError::Unauthenticated("signed envelope is truncated"));
+    }
+    let (tag, payload) = raw[1..].split_at(TAG_LEN);
+    match secret {
+        Some(secret) if !secret.verify(payload, tag)? => { Err(CodecError::Unauthenticated("tag doesn't match")) }
+        _ => { Ok(payload) }
+    }
+}
+
+#[cfg(feature = "compressed-payloads")]
+fn compress(raw: &[u8]) -> Result<Vec<u8>> {
+    let (format, payload) = match raw.first() {
+        Some(&MESSAGE_PACK) => { (MESSAGE_PACK, &raw[1..]) }
+        _ => { (0, raw) }
+    };
+    let mut compressed = vec![COMPRESSED | format];
+    zstd::stream::copy_encode(payload, &mut compressed, COMPRESSION_LEVEL)?;
+    Ok(compressed)
+}
+
+#[cfg(feature = "compressed-payloads")]
+fn decompress(compressed: &[u8]) -> Result<Vec<u8>> {
+    use std::io::Read;
+    let mut payload = Vec::new();
+    zstd::stream::read::Decoder::new(compressed)?.take(MAX_DECOMPRESSED_LEN as u64 + 1).read_to_end(&mut payload)?;
+    if payload.len() > MAX_DECOMPRESSED_LEN {
+        return Err(CodecError::Compression(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("payload inflates past {} bytes", MAX_DECOMPRESSED_LEN))));
+    }
+    Ok(payload)
+}
+
+#[cfg(not(feature = "compressed-payloads"))]
+fn compress(_raw: &[u8]) -> Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "compressed-payloads"))]
+fn decompress(_compressed: &[u8]) -> Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "compressed-payloads"))]
+fn unsupported() -> CodecError {
+    CodecError::Compression(std::io::Error::new(std::io::ErrorKind::Unsupported, "build with the compressed-payloads feature to compress payloads"))
+}
+
+/// Serializes `value` to JSON into an exactly sized shared buffer, for transports which take
+/// ownership of the payload. Retries, spooling and framing only clone the reference, never the payload.
+pub(crate) fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Bytes> {
+    with_encoded(value, Bytes::copy_from_slice)
+}
+
+/// Deserializes a payload of any format, compressed or not.
+pub(crate) fn decode<T: DeserializeOwned>(raw: &[u8]) -> Result<T> {
+    match raw.first() {
+        Some(&format) if format & COMPRESSED != 0 => {
+            let payload = decompress(&raw[1..])?;
+            match format & !COMPRESSED {
+                MESSAGE_PACK => { MessagePack::read(&payload) }
+                _ => { Json::read(&payload) }
+            }
+        }
+        Some(&MESSAGE_PACK) => { MessagePack::read(&raw[1..]) }
+        _ => { Json::read(raw) }
+    }
+}
+
+/// Deserializes a payload straight from the redis value, without copying it into a `String` first.
+pub(crate) fn decode_redis<T: DeserializeOwned>(v: &Value) -> RedisResult<T> {
+    decode_redis_with(v, decode)
+}
+
+fn decode_redis_with<T, F: FnOnce(&[u8]) -> Result<T>>(v: &Value, decode: F) -> RedisResult<T> {
+    match v {
+        Value::Data(raw) => { decode(raw).map_err(redis_error) }
+        _ => {
+            Err(RedisError::from((ErrorKind::TypeError, "Response was of incompatible type", format!("{:?}", v))))
+        }
+    }
+}
+
+/// Error of a payload which can't be decoded, as the redis client reports it.
+pub(crate) fn redis_error(err: CodecError) -> RedisError {
+    RedisError::from((ErrorKind::TypeError, "Failed to deserialize payload: ", err.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::codec::{self, MessagePack, WireConfig, WireFormat};
+    use crate::domain::{ClientQuery, HopMessage, InboundMessage, InboundPayload, NodeInfo, PathPoint};
+    use crate::signing::{ClusterSecret, TAG_LEN};
+
+    fn long_request() -> HopMessage {
+        let path = (0..2000).map(|i| PathPoint::new(i, 1, i as u64 * 10, i as u64 * 3)).collect();
+        HopMessage::new(1, NodeInfo(0, 1), NodeInfo(1999, 2), 1999, path, 12345, vec![1])
+    }
+
+    #[test]
+    fn roundtrip() {
+        let request = long_request();
+        let raw = codec::encode(&request).unwrap();
+        let decoded: HopMessage = codec::decode(&raw).unwrap();
+        assert_eq!(raw, codec::encode(&decoded).unwrap());
+        let frame = zeromq::ZmqMessage::from(raw.clone());
+        assert_eq!(frame.get(0).unwrap().as_ptr(), raw.as_ptr());
+    }
+
+    #[test]
+    fn hops_decode_whatever_their_format() {
+        let request = long_request();
+        let json = codec::encode(&request).unwrap();
+        let packed = codec::with_encoded_as::<MessagePack, _, _, _>(&request, |raw| raw.to_vec()).unwrap();
+        assert_eq!(packed[0], 0x01);
+        assert!(packed.len() * 4 < json.len() * 3, "{} bytes packed, {} as JSON", packed.len(), json.len());
+        let decoded: HopMessage = codec::decode(&packed).unwrap();
+        assert_eq!(codec::encode(&decoded).unwrap(), json);
+
+        // Queries and hops are told apart by their fields in either format.
+        let query = ClientQuery::new(7, NodeInfo(1, 1), NodeInfo(2, 2));
+        let packed = codec::with_encoded_as::<MessagePack, _, _, _>(&query, |raw| raw.to_vec()).unwrap();
+        assert!(matches!(codec::decode(&packed).unwrap(), InboundMessage::Query(query) if query.request_id == 7));
+        let packed = codec::with_encoded_as::<MessagePack, _, _, _>(&request, |raw| raw.to_vec()).unwrap();
+        assert!(matches!(codec::decode(&packed).unwrap(), InboundMessage::Hop(hop) if hop.request_id == 1));
+        assert_eq!("MsgPack".parse::<WireFormat>().unwrap(), WireFormat::MessagePack);
+        assert!("bincode".parse::<WireFormat>().is_err());
+    }
+
+    #[test]
+    fn batches_are_told_apart_from_single_messages() {
+        let batch = vec![long_request(), long_request()];
+        let json = codec::encode(&batch).unwrap();
+        let packed = codec::with_encoded_as::<MessagePack, _, _, _>(&batch, |raw| raw.to_vec()).unwrap();
+        for raw in [&json[..], &packed[..]] {
+            assert!(matches!(codec::decode(raw).unwrap(), InboundPayload::Batch(hops) if hops.len() == 2));
+        }
+        let packed = codec::with_encoded_as::<MessagePack, _, _, _>(&long_request(), |raw| raw.to_vec()).unwrap();
+        assert_eq!(codec::decode::<InboundPayload>(&packed).unwrap().into_hops().len(), 1);
+        let query = codec::encode(&ClientQuery::new(7, NodeInfo(1, 1), NodeInfo(2, 2))).unwrap();
+        assert!(matches!(codec::decode(&query).unwrap(), InboundPayload::Single(message) if matches!(*message, InboundMessage::Query(_))));
+    }
+
+    #[test]
+    fn signed_envelopes_are_verified() {
+        let secret = ClusterSecret::new(b"cluster secret");
+        let raw = codec::encode(&long_request()).unwrap();
+        let signed = codec::with_signed(&raw, Some(&secret), |signed| signed.to_vec()).unwrap();
+        assert_eq!(signed[0], 0x40);
+        assert_eq!(codec::authenticate(&signed, Some(&secret)).unwrap(), &raw[..]);
+        assert!(codec::authenticate(&raw, Some(&secret)).is_err());
+        assert!(codec::authenticate(&signed, Some(&ClusterSecret::new(b"other secret"))).is_err());
+        let mut tampered = signed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(codec::authenticate(&tampered, Some(&secret)).is_err());
+
+        // Without a secret envelopes are taken off, and unsigned messages accepted.
+        assert_eq!(codec::authenticate(&signed, None).unwrap(), &raw[..]);
+        assert_eq!(codec::authenticate(&raw, None).unwrap(), &raw[..]);
+        assert!(codec::authenticate(&signed[..TAG_LEN], None).is_err());
+    }
+
+    #[test]
+    fn hops_are_sent_as_configured() {
+        let request = long_request();
+        let wire = WireConfig::new(WireFormat::MessagePack, None, Some(ClusterSecret::new(b"cluster secret")));
+        let raw = wire.encode_hop(&request).unwrap();
+        assert_eq!(raw[0], codec::SIGNED);
+        assert_eq!(raw[1 + TAG_LEN], codec::MESSAGE_PACK);
+        assert_eq!(wire.decode_inbound::<HopMessage>(&raw).unwrap().request_id, 1);
+
+        // Configurations of the same process don't affect each other.
+        let plain = WireConfig::default().encode_hop(&request).unwrap();
+        assert_eq!(plain, codec::encode(&request).unwrap());
+        assert!(wire.decode_inbound::<HopMessage>(&plain).is_err());
+        assert_eq!(WireConfig::default().decode_inbound::<HopMessage>(&raw).unwrap().request_id, 1);
+    }
+
+    #[cfg(feature = "compressed-payloads")]
+    #[test]
+    fn decompressed_payloads_are_bounded() {
+        let inflating = vec![b' '; codec::MAX_DECOMPRESSED_LEN + 1];
+        let mut compressed = vec![codec::COMPRESSED];
+        zstd::stream::copy_encode(&inflating[..], &mut compressed, 1).unwrap();
+        assert!(compressed.len() < 1 << 16);
+        assert!(matches!(codec::decode::<HopMessage>(&compressed), Err(codec::CodecError::Compression(_))));
+    }
+
+    #[cfg(feature = "compressed-payloads")]
+    #[test]
+    fn long_payloads_are_compressed() {
+        let request = long_request();
+        for raw in [codec::encode(&request).unwrap().to_vec(), codec::with_encoded_as::<MessagePack, _, _, _>(&request, |raw| raw.to_vec()).unwrap()] {
+            let compressed = codec::with_compressed(&raw, 1024, |compressed| compressed.to_vec()).unwrap();
+            assert_eq!(compressed[0] & codec::COMPRESSED, codec::COMPRESSED);
+            assert!(compressed.len() * 2 < raw.len(), "{} bytes compressed, {} before", compressed.len(), raw.len());
+            let decoded: HopMessage = codec::decode(&compressed).unwrap();
+            assert_eq!(codec::encode(&decoded).unwrap(), codec::encode(&request).unwrap());
+        }
+        let short = codec::encode(&ClientQuery::new(7, NodeInfo(1, 1), NodeInfo(2, 2))).unwrap();
+        assert_eq!(codec::with_compressed(&short, 1024, |raw| raw.to_vec()).unwrap(), short);
+    }
+}
@@ -0,0 +1,84 @@
+//! Region objects compressed with gzip or zstd, e.g. `nodes_<region>.csv.zst`, recognized by the
+//! header of their content. Decompressing them needs the `compressed-regions` feature.
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub(crate) fn detect(raw: &[u8]) -> Option<Compression> {
+        if raw.starts_with(&ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else if raw.starts_with(&GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Extensions of compressed objects tried before the plain object, smallest format first. None
+/// without the `compressed-regions` feature, which couldn't read them anyway.
+pub(crate) fn extensions() -> &'static [&'static str] {
+    if cfg!(feature = "compressed-regions") {
+        &["zst", "gz"]
+    } else {
+        &[]
+    }
+}
+
+/// Content of `raw`, decompressed if it is compressed.
+pub(crate) fn decompress(raw: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    match Compression::detect(&raw) {
+        Some(compression) => { decode(compression, &raw) }
+        None => { Ok(raw) }
+    }
+}
+
+#[cfg(feature = "compressed-regions")]
+fn decode(compression: Compression, raw: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+    match compression {
+        Compression::Gzip => {
+            let mut content = vec![];
+            flate2::read::MultiGzDecoder::new(raw).read_to_end(&mut content)?;
+            Ok(content)
+        }
+        Compression::Zstd => { zstd::stream::decode_all(raw) }
+    }
+}
+
+#[cfg(not(feature = "compressed-regions"))]
+fn decode(compression: Compression, _raw: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::{Error, ErrorKind};
+    Err(Error::new(ErrorKind::Unsupported, format!("Region object is compressed with {:?}, build with the compressed-regions feature to read it", compression)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compression::{decompress, Compression};
+
+    #[test]
+    fn detects_compressed_content() {
+        assert_eq!(Compression::detect(&[0x1f, 0x8b, 8, 0]), Some(Compression::Gzip));
+        assert_eq!(Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0]), Some(Compression::Zstd));
+        assert_eq!(Compression::detect(b"1,10,20,1\n"), None);
+        assert_eq!(decompress(b"1,10,20,1\n".to_vec()).unwrap(), b"1,10,20,1\n");
+    }
+
+    #[cfg(feature = "compressed-regions")]
+    #[test]
+    fn decompresses_gzip() {
+        // `printf '1,10,20,1\n' | gzip -n`
+        let gzipped = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x33, 0xd4, 0x31, 0x34, 0xd0, 0x31,
+            0x32, 0xd0, 0x31, 0xe4, 0x02, 0x00, 0x54, 0x81, 0x01, 0xad, 0x0a, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(decompress(gzipped.to_vec()).unwrap(), b"1,10,20,1\n");
+    }
+}
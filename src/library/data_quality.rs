@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use bitvec::vec::BitVec;
+use crate::graph::{Access, BoundingBox, Graph, Node, NodeIdx, Profile, RegionIdx, Vertex, VertexIdx, WeightScale};
+use crate::graph_provider::{RawNode, RawVertex};
+
+/// What to do with invalid records in region data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DataPolicy {
+    /// Refuse to load the region.
+    #[default]
+    Strict,
+    /// Log and skip the offending records.
+    Permissive,
+    /// Apply safe fixes where one exists (e.g. widening malformed region bits), skip the rest.
+    Repair,
+}
+
+impl DataPolicy {
+    /// Reads DATA_QUALITY_POLICY (strict, permissive or repair), strict by default.
+    pub fn from_env() -> Result<Self, String> {
+        match env::var("DATA_QUALITY_POLICY").as_deref() {
+            Err(_) | Ok("strict") => { Ok(DataPolicy::Strict) }
+            Ok("permissive") => { Ok(DataPolicy::Permissive) }
+            Ok("repair") => { Ok(DataPolicy::Repair) }
+            Ok(other) => { Err(format!("Unknown data quality policy {}", other)) }
+        }
+    }
+}
+
+/// Records skipped or repaired while loading regions.
+#[derive(Debug, Default)]
+pub struct QualityStats {
+    pub skipped_nodes: AtomicU64,
+    pub skipped_vertices: AtomicU64,
+    pub repaired_vertices: AtomicU64,
+}
+
+/// Assembles a region from raw records, validating them according to the data policy.
+pub(crate) struct GraphBuilder<'a> {
+    region: RegionIdx,
+    scale: WeightScale,
+    policy: DataPolicy,
+    stats: &'a QualityStats,
+    bounds: Option<BoundingBox>,
+    nodes: HashMap<NodeIdx, Node>,
+    vertices: HashMap<VertexIdx, Vertex>,
+}
+
+impl<'a> GraphBuilder<'a> {
+    pub(crate) fn new(region: RegionIdx, scale: WeightScale, policy: DataPolicy, stats: &'a QualityStats) -> Self {
+        Self {
+            region,
+            scale,
+            policy,
+            stats,
+            bounds: None,
+            nodes: HashMap::new(),
+            vertices: HashMap::new(),
+        }
+    }
+
+    /// Keeps only the nodes within `bounds` and the edges between them, see [`crate::graph::Graph::within`].
+    pub(crate) fn with_bounds(mut self, bounds: Option<&BoundingBox>) -> Self {
+        self.bounds = bounds.copied();
+        self
+    }
+
+    /// Decides about a record with a `problem`, `repaired` being the fixed record if a safe fix exists.
+    fn resolve<T>(&self, problem: String, repaired: Option<T>, skipped: &AtomicU64) -> Result<Option<T>, String> {
+        let problem = format!("Region {}: {}", self.region, problem);
+        match (self.policy, repaired) {
+            (DataPolicy::Strict, _) => { Err(problem) }
+            (DataPolicy::Repair, Some(repaired)) => {
+                log::warn!("{}, repaired", problem);
+                self.stats.repaired_vertices.fetch_add(1, Ordering::Relaxed);
+                Ok(Some(repaired))
+            }
+            _ => {
+                log::warn!("{}, skipped", problem);
+                skipped.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
+            }
+        }
+    }
+
+    pub(crate) fn add_node<E: Display>(&mut self, record: Result<RawNode, E>) -> Result<(), String> {
+        let raw_node = match record {
+            Ok(raw_node) => { raw_node }
+            Err(err) => {
+                self.resolve::<()>(format!("malformed node record ({})", err), None, &self.stats.skipped_nodes)?;
+                return Ok(());
+            }
+        };
+        if matches!(self.bounds, Some(bounds) if !bounds.contains(raw_node.cord_x, raw_node.cord_y)) {
+            return Ok(());
+        }
+        if self.nodes.contains_key(&raw_node.id) {
+            self.resolve::<()>(format!("node {} is listed twice", raw_node.id), None, &self.stats.skipped_nodes)?;
+            return Ok(());
+        }
+        self.nodes.insert(raw_node.id, Node::from(raw_node));
+        Ok(())
+    }
+
+    pub(crate) fn add_vertex<E: Display>(&mut self, record: Result<RawVertex, E>) -> Result<(), String> {
+        let raw_vertex = match record {
+            Ok(raw_vertex) => { raw_vertex }
+            Err(err) => {
+                self.resolve::<()>(format!("malformed vertex record ({})", err), None, &self.stats.skipped_vertices)?;
+                return Ok(());
+            }
+        };
+        if self.vertices.contains_key(&raw_vertex.id) {
+            self.resolve::<()>(format!("vertex {} is listed twice", raw_vertex.id), None, &self.stats.skipped_vertices)?;
+            return Ok(());
+        }
+        let weight = match self.scale.to_fixed(raw_vertex.weight) {
+            Some(weight) => { weight }
+            None => {
+                self.resolve::<()>(format!("vertex {} has invalid weight {}", raw_vertex.id, raw_vertex.weight), None, &self.stats.skipped_vertices)?;
+                return Ok(());
+            }
+        };
+        let region_bits = match parse_region_bits(&raw_vertex.region_bits) {
+            Some(region_bits) => { region_bits }
+            None => {
+                // Leading towards every region is safe, it only widens the search.
+                let widened = BitVec::repeat(true, raw_vertex.region_bits.len().max(self.region as usize + 1));
+                match self.resolve(format!("vertex {} has malformed region bits {}", raw_vertex.id, raw_vertex.region_bits), Some(widened), &self.stats.skipped_vertices)? {
+                    Some(region_bits) => { region_bits }
+                    None => { return Ok(()) }
+                }
+            }
+        };
+        let access = match parse_access(raw_vertex.access.as_deref()) {
+            Ok(access) => { access }
+            Err(known) => {
+                match self.resolve(format!("vertex {} has unknown transport modes {:?}", raw_vertex.id, raw_vertex.access), Some(known), &self.stats.skipped_vertices)? {
+                    Some(access) => { access }
+                    None => { return Ok(()) }
+                }
+            }
+        };
+        self.vertices.insert(raw_vertex.id, Vertex {
+            a: raw_vertex.a,
+            b: raw_vertex.b,
+            weight,
+            id: raw_vertex.id,
+            region_bits,
+            access,
+        });
+        Ok(())
+    }
+
+    /// Drops vertices which connect none of the region's nodes and links the rest to their nodes.
+    pub(crate) fn build(mut self) -> Result<Graph, String> {
+        if self.bounds.is_some() {
+            // Edges leaving the bounds aren't broken, they are cut off on purpose.
+            let nodes = &self.nodes;
+            self.vertices.retain(|_, vertex| nodes.contains_key(&vertex.a) && nodes.contains_key(&vertex.b));
+        }
+        let mut dangling: Vec<VertexIdx> = self.vertices.values()
+            .filter(|vertex| !self.nodes.contains_key(&vertex.a) && !self.nodes.contains_key(&vertex.b))
+            .map(|vertex| vertex.id)
+            .collect();
+        dangling.sort();
+        for vertex_id in dangling {
+            self.resolve::<()>(format!("vertex {} connects no known node", vertex_id), None, &self.stats.skipped_vertices)?;
+            self.vertices.remove(&vertex_id);
+        }
+        for vertex in self.vertices.values() {
+            if let Some(node) = self.nodes.get_mut(&vertex.a) {
+                node.connections.push(vertex.id);
+            }
+            if let Some(node) = self.nodes.get_mut(&vertex.b) {
+                node.connections.push(vertex.id);
+            }
+        }
+        Ok(Graph::new(self.nodes, self.vertices, self.region))
+    }
+}
+
+pub(crate) fn parse_region_bits(raw: &str) -> Option<BitVec> {
+    raw.chars()
+        .map(|c| match c {
+            '0' => { Some(false) }
+            '1' => { Some(true) }
+            _ => { None }
+        })
+        .collect()
+}
+
+/// Transport modes separated by `|`, all of them if absent. On unknown names,
+/// fails with the modes which could be recognized (all of them if none could).
+pub(crate) fn parse_access(raw: Option<&str>) -> Result<Access, Access> {
+    let modes = match raw {
+        None | Some("") => { return Ok(Access::ALL) }
+        Some(modes) => { modes }
+    };
+    let parsed: Vec<Option<Profile>> = modes.split('|').map(|mode| mode.parse().ok()).collect();
+    let known: Vec<Profile> = parsed.iter().flatten().copied().collect();
+    if known.len() == parsed.len() {
+        Ok(Access::new(known))
+    } else if known.is_empty() {
+        Err(Access::ALL)
+    } else {
+        Err(Access::new(known))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+    use crate::data_quality::{DataPolicy, GraphBuilder, QualityStats};
+    use crate::graph::{Access, Profile, WeightScale};
+    use crate::graph_provider::{RawNode, RawVertex};
+
+    fn build(policy: DataPolicy, stats: &QualityStats) -> Result<crate::graph::Graph, String> {
+        let mut builder = GraphBuilder::new(1, WeightScale::default(), policy, stats);
+        for id in [1, 2, 1] {
+            builder.add_node(Ok::<_, String>(RawNode { id, cord_x: 0, cord_y: 0, region: 1 }))?;
+        }
+        let vertex = |id, a, b, weight, region_bits: &str, access: Option<&str>| Ok::<_, String>(RawVertex {
+            id, a, b, weight, region_bits: region_bits.to_string(), access: access.map(str::to_string),
+        });
+        builder.add_vertex(vertex(1, 1, 2, 1., "011", None))?;
+        builder.add_vertex(vertex(2, 1, 2, -1., "011", None))?;
+        builder.add_vertex(vertex(3, 1, 2, 1., "0x1", None))?;
+        builder.add_vertex(vertex(4, 1, 2, 1., "011", Some("car|hovercraft")))?;
+        builder.add_vertex(vertex(5, 8, 9, 1., "011", None))?;
+        builder.add_vertex(Err("missing field"))?;
+        builder.build()
+    }
+
+    #[test]
+    fn strict_fails() {
+        let stats = QualityStats::default();
+        assert!(build(DataPolicy::Strict, &stats).unwrap_err().contains("node 1 is listed twice"));
+    }
+
+    #[test]
+    fn permissive_skips() {
+        let stats = QualityStats::default();
+        let graph = build(DataPolicy::Permissive, &stats).unwrap();
+        assert_eq!(graph.vertices().count(), 1);
+        assert_eq!(graph.node_count(), 2);
+        assert_eq!(stats.skipped_nodes.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.skipped_vertices.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn repair_fixes() {
+        let stats = QualityStats::default();
+        let graph = build(DataPolicy::Repair, &stats).unwrap();
+        let mut vertices: Vec<_> = graph.vertices().map(|vertex| vertex.id).collect();
+        vertices.sort();
+        assert_eq!(vertices, vec![1, 3, 4]);
+        let vertex = |id| graph.vertices().find(|vertex| vertex.id == id).unwrap();
+        assert!((0..3).all(|region| vertex(3).region_bits.get(region) == Some(true)));
+        assert_eq!(vertex(4).access, Access::new([Profile::Car]));
+        assert_eq!(stats.repaired_vertices.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.skipped_vertices.load(Ordering::Relaxed), 3);
+        assert_eq!(graph.connections(1).count(), 3);
+    }
+}
@@ -0,0 +1,159 @@
+//! Hops a server gave up on, kept in redis so they can be inspected and sent again instead of being
+//! lost with a warning in the log. Each server keeps the hops it failed to forward, or failed to
+//! serve, in a list of its own.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use futures_util::StreamExt as _;
+use redis::RedisResult;
+use serde::{Serialize, Deserialize};
+use tokio::task::JoinHandle;
+use crate::domain::HopMessage;
+use crate::graph::RegionIdx;
+use crate::node_connector::NodeSender;
+use crate::redis_connector::RedisConnector;
+use crate::replicas::ReplicaSelector;
+
+/// Most dead letters kept per server, the oldest ones are dropped past it.
+pub const MAX_LEN: usize = 10_000;
+
+/// Where handling a hop failed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Sending the hop to the server of its region failed.
+    Forward,
+    /// Searching the region of the hop failed.
+    Serve,
+    /// The hop crossed more region boundaries than HOP_LIMIT allows, see the error for the regions.
+    HopLimit,
+}
+
+/// A hop that failed, with the context it failed in.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    request: HopMessage,
+    pub stage: Stage,
+    /// Server the hop was sent to, if it failed on the way.
+    pub target_server: Option<usize>,
+    pub error: String,
+    /// Seconds since the epoch.
+    pub failed_at: u64,
+}
+
+impl DeadLetter {
+    pub(crate) fn new(request: HopMessage, stage: Stage, target_server: Option<usize>, error: String) -> Self {
+        Self {
+            request,
+            stage,
+            target_server,
+            error,
+            failed_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        }
+    }
+
+    pub fn request_id(&self) -> usize {
+        self.request.request_id
+    }
+
+    /// Region the hop was on its way to search.
+    pub fn region(&self) -> RegionIdx {
+        self.request.region()
+    }
+}
+
+/// Redis list of the dead letters of a server, newest first, as JSON.
+pub fn list_key(server_id: usize) -> String {
+    format!("dead_letters_{}", server_id)
+}
+
+/// Redis channel on which a server is asked to replay its dead letters. The payload is the number
+/// of letters to replay, oldest first.
+pub fn replay_channel(server_id: usize) -> String {
+    format!("dead_letter_replay_{}", server_id)
+}
+
+/// Keeps `letter`, a failure to do so is only logged as the hop is lost either way.
+pub(crate) async fn push(redis_connector: &RedisConnector, server_id: usize, letter: &DeadLetter) {
+    if let Err(err) = redis_connector.push_dead_letter(server_id, letter).await {
+        log::warn!("Unable to keep dead letter of request {}, details: {}", letter.request_id(), err);
+    }
+}
+
+/// Subscribes to the replay channel of `server_id` and sends replayed hops to the servers of their
+/// regions. Hops failing again go back to the list.
+pub(crate) async fn spawn_replays(redis_connector: &RedisConnector,
+                                  node_sender_mgr: Box<dyn NodeSender>,
+                                  replicas: ReplicaSelector,
+                                  server_id: usize) -> RedisResult<JoinHandle<()>> {
+    let mut pubsub = redis_connector.spawn_connection().await?.into_pubsub();
+    pubsub.subscribe(replay_channel(server_id)).await?;
+    let redis_connector = redis_connector.clone();
+    Ok(tokio::task::spawn(async move {
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let count = match message.get_payload::<usize>() {
+                Ok(count) => { count }
+                Err(err) => {
+                    log::warn!("Ignoring malformed dead letter replay request, details: {}", err);
+                    continue;
+                }
+            };
+            let mut replayed = 0;
+            for _ in 0..count {
+                let letter = match redis_connector.take_dead_letter(server_id).await {
+                    Ok(Some(letter)) => { letter }
+                    Ok(None) => { break }
+                    Err(err) => {
+                        log::warn!("Unable to take dead letters, details: {}", err);
+                        break
+                    }
+                };
+                match replay(&redis_connector, &*node_sender_mgr, &replicas, &letter).await {
+                    Ok(()) => { replayed += 1 }
+                    Err((target_server, err)) => {
+                        log::warn!("Unable to replay request {} into region {}, details: {}", letter.request_id(), letter.region(), err);
+                        push(&redis_connector, server_id, &DeadLetter::new(letter.request, Stage::Forward, target_server, err)).await;
+                    }
+                }
+            }
+            log::info!("Replayed {} dead letters", replayed);
+        }
+        log::warn!("Dead letter replay subscription closed");
+    }))
+}
+
+/// Sends the hop of `letter` to the server of its region, on failure returns that server if known.
+async fn replay(redis_connector: &RedisConnector, node_sender_mgr: &dyn NodeSender, replicas: &ReplicaSelector, letter: &DeadLetter) -> Result<(), (Option<usize>, String)> {
+    let request_id = letter.request_id();
+    redis_connector.spawn_hops(request_id, 1).await.map_err(|err| (None, err.to_string()))?;
+    let sent = match replicas.pick(letter.region()).await {
+        Ok(target_server) => {
+            node_sender_mgr.send_request(target_server, letter.request.clone()).await.map_err(|err| (Some(target_server), err.to_string()))
+        }
+        Err(err) => { Err((None, err.to_string())) }
+    };
+    if sent.is_err() {
+        if let Err(err) = redis_connector.finish_hop(request_id).await {
+            log::warn!("Unable to count the hops of request {}, details: {}", request_id, err);
+        }
+    }
+    sent
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dead_letter::{DeadLetter, Stage};
+    use crate::domain::{HopMessage, NodeInfo};
+
+    #[test]
+    fn letters_keep_the_hop_and_its_context() {
+        let request = HopMessage::new(7, NodeInfo(1, 2), NodeInfo(3, 4), 1, vec![], 0, vec![2]);
+        let letter = DeadLetter::new(request, Stage::Forward, Some(5), "Connection refused".to_string());
+        let raw = serde_json::to_string(&letter).unwrap();
+        assert!(raw.contains("\"stage\":\"forward\""));
+        let decoded: DeadLetter = serde_json::from_str(&raw).unwrap();
+        assert_eq!((decoded.request_id(), decoded.region(), decoded.stage), (7, 2, Stage::Forward));
+        assert_eq!((decoded.target_server, decoded.error.as_str()), (Some(5), "Connection refused"));
+        assert!(decoded.failed_at > 0);
+    }
+}
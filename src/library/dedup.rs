@@ -0,0 +1,190 @@
+//! Drops copies of forwarded hops a server already took, e.g. continuations reaching the same
+//! boundary node over the same regions in another order, or requests sent again by a transport
+//! whose acknowledgement got lost. Fresh queries are never dropped. Runs as a layer of the
+//! middleware chain, so copies are skipped by the workers.
+
+use std::cmp::Reverse;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+use priority_queue::PriorityQueue;
+use sha2::{Digest, Sha256};
+use crate::domain::HopMessage;
+use crate::middleware::{HopMiddleware, Skip};
+use crate::policy::ExecutionParams;
+use crate::redis_connector::RedisConnector;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Default number of fingerprints a server remembers.
+const DEFAULT_CACHE_SIZE: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub(crate) struct DedupConfig {
+    /// Fingerprints remembered in memory, the oldest are forgotten first. 0 remembers none.
+    cache_size: usize,
+    /// How long fingerprints are also claimed in redis, so copies taken by another process of the
+    /// group, e.g. a standby which took over, are dropped as well.
+    shared_ttl: Option<Duration>,
+}
+
+impl DedupConfig {
+    /// Reads DEDUP_CACHE_SIZE and DEDUP_REDIS_TTL_SECS.
+    pub(crate) fn from_env() -> Result<Self> {
+        Ok(Self {
+            cache_size: match env::var("DEDUP_CACHE_SIZE") {
+                Ok(size) => { size.parse()? }
+                Err(_) => { DEFAULT_CACHE_SIZE }
+            },
+            shared_ttl: match env::var("DEDUP_REDIS_TTL_SECS") {
+                Ok(secs) => { Some(Duration::from_secs(secs.parse()?)) }
+                Err(_) => { None }
+            },
+        })
+    }
+}
+
+/// Identifies the work a hop stands for: the query and end it searches from, where it stands, the
+/// regions it visited whatever their order, and its cost.
+pub(crate) fn fingerprint(request: &HopMessage) -> String {
+    let mut visited = request.visited_regions.clone();
+    visited.sort_unstable();
+    visited.dedup();
+    let mut hasher = Sha256::new();
+    hasher.update((request.request_id as u64).to_be_bytes());
+    hasher.update(request.direction.name());
+    hasher.update((request.last as u64).to_be_bytes());
+    for region in visited {
+        hasher.update(region.to_be_bytes());
+    }
+    hasher.update(request.cost().to_be_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Fingerprints seen most recently, at most `capacity` of them.
+#[derive(Debug, Default)]
+struct RecentFingerprints {
+    /// Fingerprints by when they were first seen, the oldest first.
+    members: PriorityQueue<String, Reverse<u64>>,
+    /// Fingerprints seen so far.
+    seen: u64,
+    capacity: usize,
+}
+
+impl RecentFingerprints {
+    /// Remembers `fingerprint`, returns whether it is new.
+    fn insert(&mut self, fingerprint: &str) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        if self.members.get(fingerprint).is_some() {
+            return false;
+        }
+        self.members.push(fingerprint.to_string(), Reverse(self.seen));
+        self.seen += 1;
+        if self.members.len() > self.capacity {
+            self.members.pop();
+        }
+        true
+    }
+
+    fn remove(&mut self, fingerprint: &str) {
+        self.members.remove(fingerprint);
+    }
+}
+
+pub(crate) struct Deduplicator {
+    seen: Mutex<RecentFingerprints>,
+    shared_ttl: Option<Duration>,
+    redis_connector: RedisConnector,
+}
+
+impl Deduplicator {
+    pub(crate) fn new(config: &DedupConfig, redis_connector: RedisConnector) -> Self {
+        Self {
+            seen: Mutex::new(RecentFingerprints { capacity: config.cache_size, ..RecentFingerprints::default() }),
+            shared_ttl: config.shared_ttl,
+            redis_connector,
+        }
+    }
+
+    /// Whether `request` is a copy of a hop taken before, remembers it otherwise. Copies are let
+    /// through if redis can't tell.
+    pub(crate) async fn is_duplicate(&self, request: &HopMessage) -> bool {
+        if request.is_fresh() {
+            return false;
+        }
+        let fingerprint = fingerprint(request);
+        if !self.seen.lock().unwrap().insert(&fingerprint) {
+            return true;
+        }
+        let ttl = match self.shared_ttl {
+            Some(ttl) => { ttl }
+            None => { return false }
+        };
+        match self.redis_connector.claim_hop(&fingerprint, ttl).await {
+            Ok(claimed) => { !claimed }
+            Err(err) => {
+                log::warn!("Unable to claim hop of request {}, details: {}", request.request_id, err);
+                false
+            }
+        }
+    }
+
+    /// Forgets `request`, so a replay of it is served again.
+    pub(crate) async fn forget(&self, request: &HopMessage) {
+        let fingerprint = fingerprint(request);
+        self.seen.lock().unwrap().remove(&fingerprint);
+        if self.shared_ttl.is_some() {
+            if let Err(err) = self.redis_connector.release_hop(&fingerprint).await {
+                log::warn!("Unable to release hop of request {}, details: {}", request.request_id, err);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl HopMiddleware for Deduplicator {
+    async fn before(&self, request: &HopMessage, _params: &ExecutionParams) -> Result<()> {
+        if self.is_duplicate(request).await {
+            Err(Skip(format!("Copy of a hop of request {} at node {} taken before", request.request_id, request.last)))?
+        }
+        Ok(())
+    }
+
+    /// Hops which failed are forgotten, a replay of the dead letter isn't a copy to drop.
+    async fn after(&self, request: &HopMessage, outcome: &Result<()>) {
+        if outcome.is_err() {
+            self.forget(request).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dedup::{fingerprint, RecentFingerprints};
+    use crate::domain::{ClientQuery, HopMessage, NodeInfo};
+
+    #[test]
+    fn copies_share_a_fingerprint() {
+        let query = HopMessage::from(ClientQuery::new(3, NodeInfo::new(1, 1), NodeInfo::new(9, 4)));
+        let through_2 = query.update(vec![], 5, 10, 2).update(vec![], 7, 5, 3);
+        let through_3 = query.update(vec![], 6, 8, 3).update(vec![], 7, 7, 2);
+        assert_eq!(fingerprint(&through_2), fingerprint(&through_3));
+        assert_ne!(fingerprint(&through_2), fingerprint(&query.update(vec![], 6, 8, 3).update(vec![], 7, 8, 2)));
+        assert_ne!(fingerprint(&through_2), fingerprint(&through_2.reversed().update(vec![], 7, 15, 2)));
+    }
+
+    #[test]
+    fn the_oldest_fingerprints_are_forgotten() {
+        let mut seen = RecentFingerprints { capacity: 2, ..RecentFingerprints::default() };
+        assert!(seen.insert("a"));
+        assert!(seen.insert("b"));
+        assert!(!seen.insert("a"));
+        assert!(seen.insert("c"));
+        assert!(seen.insert("a"));
+        seen.remove("c");
+        assert!(seen.insert("c"));
+        assert!(!seen.insert("a"));
+    }
+}
@@ -0,0 +1,324 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicU64;
+use std::time::{Duration, Instant};
+use async_channel::{Receiver, RecvError, Sender, unbounded};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::domain::Priority;
+use crate::graph::RegionIdx;
+
+/// Assumed cost of a task which never leaves its region, until real samples are collected.
+const DEFAULT_LOCAL_COST: Duration = Duration::from_millis(5);
+/// Assumed cost of a task crossing region boundaries, until real samples are collected.
+const DEFAULT_REMOTE_COST: Duration = Duration::from_millis(20);
+/// Weight of the newest sample in the moving average.
+const SMOOTHING: f64 = 0.2;
+
+/// Region a hop is served in and the region of its target.
+pub(crate) type RegionPair = (RegionIdx, RegionIdx);
+
+/// Predicts how long a worker will be busy with a task, based on the time previous tasks between
+/// the same pair of regions took.
+#[derive(Debug, Default)]
+pub(crate) struct LoadEstimator {
+    averages: HashMap<RegionPair, f64>,
+}
+
+impl LoadEstimator {
+    pub(crate) fn estimate(&self, pair: RegionPair) -> Duration {
+        if let Some(micros) = self.averages.get(&pair) {
+            return Duration::from_micros(*micros as u64);
+        }
+        let local = pair.0 == pair.1;
+        let similar: Vec<f64> = self.averages.iter()
+            .filter(|((from, to), _)| (from == to) == local)
+            .map(|(_, micros)| *micros)
+            .collect();
+        if !similar.is_empty() {
+            Duration::from_micros((similar.iter().sum::<f64>() / similar.len() as f64) as u64)
+        } else if local {
+            DEFAULT_LOCAL_COST
+        } else {
+            DEFAULT_REMOTE_COST
+        }
+    }
+
+    pub(crate) fn record(&mut self, pair: RegionPair, elapsed: Duration) {
+        let sample = elapsed.as_micros() as f64;
+        self.averages.entry(pair)
+            .and_modify(|avg| *avg = SMOOTHING * sample + (1. - SMOOTHING) * *avg)
+            .or_insert(sample);
+    }
+}
+
+/// Room for one task in a [`WorkQueue`], held until a worker finished serving the task. Times the
+/// task it was taken with for the estimator of the queue when dropped.
+pub(crate) struct Slot {
+    _permit: OwnedSemaphorePermit,
+    estimator: Arc<Mutex<LoadEstimator>>,
+    pair: Option<RegionPair>,
+    taken: Option<Instant>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let (Some(pair), Some(taken)) = (self.pair, self.taken) {
+            self.estimator.lock().unwrap().record(pair, taken.elapsed());
+        }
+    }
+}
+
+/// Task waiting in a [`Lane`], due its estimated cost after it was queued.
+struct Queued<T> {
+    due: Instant,
+    /// Tells apart tasks due at once, the first queued comes first.
+    seq: u64,
+    task: T,
+    slot: Slot,
+}
+
+impl<T> PartialEq for Queued<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl<T> Eq for Queued<T> {}
+
+impl<T> PartialOrd for Queued<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Queued<T> {
+    // The heap pops its greatest entry, the one due first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+/// Tasks of one priority, with a token in `tokens` for every task in `tasks`. Workers wait for a
+/// token, then take the task due first, which isn't necessarily the one queued with the token.
+struct Lane<T> {
+    tokens: Sender<()>,
+    tasks: Arc<Mutex<BinaryHeap<Queued<T>>>>,
+}
+
+impl<T> Lane<T> {
+    fn new() -> (Self, LaneReceiver<T>) {
+        let (tokens, receiver) = unbounded();
+        let tasks = Arc::new(Mutex::new(BinaryHeap::new()));
+        (Self { tokens, tasks: tasks.clone() }, LaneReceiver { tokens: receiver, tasks })
+    }
+
+    fn push(&self, queued: Queued<T>) -> Result<(), T> {
+        // The token is sent while the task can't be taken yet, so it is queued before anyone takes it.
+        let mut tasks = self.tasks.lock().unwrap();
+        if self.tokens.try_send(()).is_err() {
+            return Err(queued.task);
+        }
+        tasks.push(queued);
+        Ok(())
+    }
+}
+
+struct LaneReceiver<T> {
+    tokens: Receiver<()>,
+    tasks: Arc<Mutex<BinaryHeap<Queued<T>>>>,
+}
+
+impl<T> Clone for LaneReceiver<T> {
+    fn clone(&self) -> Self {
+        Self { tokens: self.tokens.clone(), tasks: self.tasks.clone() }
+    }
+}
+
+impl<T> LaneReceiver<T> {
+    /// Takes the task due first, for a token just received.
+    fn take(&self) -> (T, Slot) {
+        let queued = self.tasks.lock().unwrap().pop().expect("A task is queued for every token");
+        let mut slot = queued.slot;
+        slot.taken = Some(Instant::now());
+        (queued.task, slot)
+    }
+}
+
+/// Queue shared by all workers of a server, whichever worker is idle takes the next task, so a
+/// slow request holds up no other. Holds at most `capacity` tasks, including those being served.
+///
+/// Tasks of the same priority are taken in the order they are due, the time they were queued plus
+/// their cost, estimated from how long tasks between the same regions took before. Cheap tasks
+/// overtake expensive ones queued shortly before them rather than waiting for them, and a task is
+/// only overtaken by tasks queued less than its estimate after it.
+pub(crate) struct WorkQueue<T> {
+    high: Lane<T>,
+    low: Lane<T>,
+    slots: Arc<Semaphore>,
+    capacity: usize,
+    estimator: Arc<Mutex<LoadEstimator>>,
+    seq: AtomicU64,
+}
+
+/// Takes tasks from a [`WorkQueue`], the high priority ones first. Cloned for every worker.
+pub(crate) struct WorkReceiver<T> {
+    high: LaneReceiver<T>,
+    low: LaneReceiver<T>,
+}
+
+impl<T> Clone for WorkReceiver<T> {
+    fn clone(&self) -> Self {
+        Self { high: self.high.clone(), low: self.low.clone() }
+    }
+}
+
+impl<T> WorkQueue<T> {
+    pub(crate) fn new(capacity: usize) -> (Self, WorkReceiver<T>) {
+        // The slots bound both lanes together.
+        let (high, high_receiver) = Lane::new();
+        let (low, low_receiver) = Lane::new();
+        let queue = Self {
+            high,
+            low,
+            slots: Arc::new(Semaphore::new(capacity)),
+            capacity,
+            estimator: Arc::default(),
+            seq: AtomicU64::new(0),
+        };
+        (queue, WorkReceiver { high: high_receiver, low: low_receiver })
+    }
+
+    fn slot(&self, permit: OwnedSemaphorePermit) -> Slot {
+        Slot { _permit: permit, estimator: self.estimator.clone(), pair: None, taken: None }
+    }
+
+    /// Room for another task, `None` if the queue is full.
+    pub(crate) fn try_reserve(&self) -> Option<Slot> {
+        self.slots.clone().try_acquire_owned().ok().map(|permit| self.slot(permit))
+    }
+
+    /// Waits until a worker finished a task if the queue is full.
+    pub(crate) async fn reserve(&self) -> Slot {
+        let permit = self.slots.clone().acquire_owned().await.expect("Work queue slots are never closed");
+        self.slot(permit)
+    }
+
+    /// Queues `task`, served in region `pair.0` towards region `pair.1`. Gives it back if the queue
+    /// is closed or no worker is left to take it.
+    pub(crate) fn push(&self, mut slot: Slot, priority: Priority, pair: RegionPair, task: T) -> Result<(), T> {
+        let estimate = self.estimator.lock().unwrap().estimate(pair);
+        slot.pair = Some(pair);
+        let queued = Queued {
+            due: Instant::now() + estimate,
+            seq: self.seq.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+            task,
+            slot,
+        };
+        match priority {
+            Priority::High => { self.high.push(queued) }
+            Priority::Low => { self.low.push(queued) }
+        }
+    }
+
+    /// Tasks queued or being served.
+    pub(crate) fn queued(&self) -> usize {
+        self.capacity - self.slots.available_permits()
+    }
+
+    /// Workers take the tasks still queued, then stop.
+    pub(crate) fn close(&self) {
+        self.high.tokens.close();
+        self.low.tokens.close();
+    }
+}
+
+impl<T> WorkReceiver<T> {
+    /// Next task, with the slot to drop once it is served. Fails once the queue is closed and empty.
+    pub(crate) async fn recv(&self) -> Result<(T, Slot), RecvError> {
+        tokio::select! {
+            biased;
+            Ok(()) = self.high.tokens.recv() => { Ok(self.high.take()) }
+            token = self.low.tokens.recv() => { token.map(|()| self.low.take()) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::dispatcher::{LoadEstimator, WorkQueue};
+    use crate::domain::Priority;
+
+    #[tokio::test]
+    async fn idle_workers_take_the_next_task() {
+        let (queue, first_worker) = WorkQueue::new(3);
+        let second_worker = first_worker.clone();
+        for task in 0..3 {
+            queue.push(queue.try_reserve().unwrap(), Priority::High, (1, 1), task).unwrap();
+        }
+        assert!(queue.try_reserve().is_none());
+        let (slow, _slow_slot) = first_worker.recv().await.unwrap();
+        assert_eq!(slow, 0);
+        // The slow task holds up no other worker.
+        let (next, slot) = second_worker.recv().await.unwrap();
+        assert_eq!(next, 1);
+        assert_eq!(queue.queued(), 3);
+        drop(slot);
+        assert_eq!(queue.queued(), 2);
+        assert!(queue.try_reserve().is_some());
+    }
+
+    #[tokio::test]
+    async fn high_priority_tasks_are_taken_first_until_closed() {
+        let (queue, worker) = WorkQueue::new(4);
+        queue.push(queue.reserve().await, Priority::Low, (1, 1), 1).unwrap();
+        queue.push(queue.reserve().await, Priority::High, (1, 1), 2).unwrap();
+        queue.push(queue.reserve().await, Priority::Low, (1, 1), 3).unwrap();
+        queue.close();
+        assert_eq!(queue.push(queue.reserve().await, Priority::High, (1, 1), 4), Err(4));
+        let mut served = vec![];
+        while let Ok((task, _)) = worker.recv().await {
+            served.push(task);
+        }
+        assert_eq!(served, vec![2, 1, 3]);
+    }
+
+    #[test]
+    fn estimator_learns_per_pair() {
+        let mut estimator = LoadEstimator::default();
+        assert!(estimator.estimate((1, 1)) < estimator.estimate((1, 2)));
+        estimator.record((1, 2), Duration::from_millis(100));
+        assert_eq!(estimator.estimate((1, 2)), Duration::from_millis(100));
+        assert_eq!(estimator.estimate((3, 4)), Duration::from_millis(100));
+        estimator.record((1, 2), Duration::from_millis(200));
+        assert_eq!(estimator.estimate((1, 2)), Duration::from_millis(120));
+    }
+
+    #[tokio::test]
+    async fn cheap_tasks_overtake_expensive_ones() {
+        let (queue, worker) = WorkQueue::new(4);
+        queue.estimator.lock().unwrap().record((1, 9), Duration::from_secs(1));
+        queue.push(queue.reserve().await, Priority::High, (1, 9), "expensive").unwrap();
+        queue.push(queue.reserve().await, Priority::High, (2, 2), "cheap").unwrap();
+        queue.push(queue.reserve().await, Priority::High, (1, 9), "expensive again").unwrap();
+        let mut served = vec![];
+        for _ in 0..3 {
+            served.push(worker.recv().await.unwrap().0);
+        }
+        assert_eq!(served, vec!["cheap", "expensive", "expensive again"]);
+    }
+
+    #[tokio::test]
+    async fn served_tasks_are_timed() {
+        let (queue, worker) = WorkQueue::new(2);
+        queue.push(queue.reserve().await, Priority::High, (1, 2), ()).unwrap();
+        let (_, slot) = worker.recv().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(slot);
+        assert!(queue.estimator.lock().unwrap().estimate((1, 2)) >= Duration::from_millis(50));
+        // Slots given back unused time nothing.
+        drop(queue.reserve().await);
+        assert_eq!(queue.estimator.lock().unwrap().averages.len(), 1);
+    }
+}
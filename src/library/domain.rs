@@ -1,12 +1,21 @@
-use crate::graph::{Node, NodeIdx};
+use std::collections::{BTreeSet, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::graph::{Avoid, Node, NodeIdx, Profile, VertexIdx, WeightScale};
 use crate::RegionIdx;
+use crate::mirror::Probe;
 use serde::{Serialize, Deserialize};
 
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct NodeInfo(pub(crate) NodeIdx, pub(crate) RegionIdx);
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash)]
+impl NodeInfo {
+    pub fn new(node: NodeIdx, region: RegionIdx) -> Self {
+        Self(node, region)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct PathPoint {
     id: NodeIdx,
     region_id: RegionIdx,
@@ -27,10 +36,20 @@ impl PathPoint {
             cord_y,
         }
     }
+
+    pub(crate) fn id(&self) -> NodeIdx {
+        self.id
+    }
 }
 
 impl From<Node> for PathPoint {
     fn from(node: Node) -> Self {
+        Self::from(&node)
+    }
+}
+
+impl From<&Node> for PathPoint {
+    fn from(node: &Node) -> Self {
         Self::new(node.id,
                   node.region,
                   node.cord_x,
@@ -38,16 +57,259 @@ impl From<Node> for PathPoint {
     }
 }
 
-impl PartialEq<Self> for PathPoint {
-    fn eq(&self, other: &Self) -> bool {
-        return self.id == other.id && self.region_id == other.region_id && self.cord_x == other.cord_x && self.cord_y == other.cord_y;
+/// How urgently a query is served. Servers read and serve the high priority hops waiting for
+/// them before the low priority ones, whatever the order they arrived in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    High,
+    Low,
+}
+
+/// Path query as submitted by a client.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientQuery {
+    pub request_id: usize,
+    pub source: NodeInfo,
+    pub target: NodeInfo,
+    /// Name of the submitting client, routing policies may be defined per client.
+    #[serde(default)]
+    pub client: Option<String>,
+    /// Selects the latency objective the query is measured against.
+    #[serde(default)]
+    pub priority_class: Option<String>,
+    /// Interactive queries keep the default, batch jobs such as distance matrices set `low` so
+    /// they don't hold interactive ones up.
+    #[serde(default)]
+    pub priority: Priority,
+    /// Nodes, edges and regions the route must not pass through.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub avoid_nodes: Vec<NodeIdx>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub avoid_vertices: Vec<VertexIdx>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub avoid_regions: Vec<RegionIdx>,
+    /// Routes costing more are not searched for.
+    #[serde(default)]
+    pub max_cost: Option<u64>,
+    /// Limit of region boundaries the route may cross.
+    #[serde(default)]
+    pub max_region_hops: Option<usize>,
+    /// Transport mode, only edges open to it are used. Any edge if absent.
+    #[serde(default)]
+    pub profile: Option<Profile>,
+    /// Drops path points deviating less than this from the simplified route, in coordinate units.
+    /// For clients that only draw the route, the full path is returned if absent.
+    #[serde(default)]
+    pub simplify_tolerance: Option<f64>,
+    /// Earlier query whose route is reused between the first and the last boundary it crossed, so
+    /// dragging an endpoint within its region only searches the legs to the moved endpoints. Only
+    /// routes kept with RESULT_RETENTION are reused, the route is searched in full otherwise.
+    #[serde(default)]
+    pub reuse_route_of: Option<usize>,
+    /// Publishes the progress of the search, the regions it enters with the cost so far, on
+    /// `events_<request id>` besides the results, see `GET /paths/<request id>/events`.
+    #[serde(default)]
+    pub stream_events: bool,
+    /// Milliseconds after entering the cluster at which the query is given up and answered
+    /// `TIMED_OUT`, unless a route was found by then. REQUEST_TIMEOUT_MS if absent.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// W3C `traceparent` of the client's span, the spans serving the query join its trace.
+    #[serde(default)]
+    pub trace_context: Option<String>,
+}
+
+impl ClientQuery {
+    pub fn new(request_id: usize,
+               source: NodeInfo,
+               target: NodeInfo) -> Self {
+        Self {
+            request_id,
+            source,
+            target,
+            client: None,
+            priority_class: None,
+            priority: Priority::High,
+            avoid_nodes: vec![],
+            avoid_vertices: vec![],
+            avoid_regions: vec![],
+            max_cost: None,
+            max_region_hops: None,
+            profile: None,
+            simplify_tolerance: None,
+            reuse_route_of: None,
+            stream_events: false,
+            timeout_ms: None,
+            trace_context: None,
+        }
+    }
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RouteStatus {
+    /// `path` leads from the source to the target.
+    #[default]
+    Found,
+    /// The search gave up at the end of `path`, as going on would have exceeded the query's limits.
+    BudgetExceeded,
+    /// Every search of the query ended without reaching the target, `reason` tells the last one's cause.
+    NotFound,
+    /// The query's deadline passed before a route was found, its remaining hops are dropped.
+    TimedOut,
+    /// The query was rejected unsearched, as its client or the cluster sent queries faster than allowed.
+    RateLimited,
+    /// A hop was lost to a server which went down and no route was found, see `failure`.
+    Unavailable,
+    /// A hop was given up on for a failure of the cluster rather than for want of a route, and no
+    /// route was found, see `failure`.
+    Failed,
+}
+
+/// Final answer to a [`ClientQuery`], published once the target has been reached or the search has been given up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RouteResult {
+    pub request_id: usize,
+    pub source: NodeInfo,
+    pub target: NodeInfo,
+    pub path: Vec<PathPoint>,
+    /// Fixed-point cost, `weight_scale` units per cost unit of the data set.
+    pub cost: u64,
+    #[serde(default)]
+    pub status: RouteStatus,
+    #[serde(default)]
+    pub weight_scale: WeightScale,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// What failed, for results `UNAVAILABLE` or `FAILED`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<RouteFailure>,
+}
+
+/// What kind of failure made a server give up on a hop.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// The hop reached a server which doesn't serve the region of its node.
+    NotServedRegion,
+    /// The region's graph doesn't hold what the hop refers to, e.g. its start node.
+    Graph,
+    /// Sending the hop on to the server of the next region failed.
+    Send,
+    /// The server of the next region went down and no standby took over.
+    ServerDown,
+    /// The hop crossed more region boundaries than HOP_LIMIT allows.
+    HopLimit,
+    /// Redis or another part of the server failed.
+    Internal,
+}
+
+/// The failure a query was lost to, reported by the server which gave up on its hop.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteFailure {
+    pub kind: FailureKind,
+    /// GROUP_ID of the server which gave up.
+    pub server: usize,
+}
+
+impl RouteResult {
+    /// Cost in units of the data set.
+    pub fn real_cost(&self) -> f64 {
+        self.weight_scale.to_float(self.cost)
+    }
+}
+
+fn segment_distance(point: &PathPoint, start: &PathPoint, end: &PathPoint) -> f64 {
+    let (px, py) = (point.cord_x as f64, point.cord_y as f64);
+    let (sx, sy) = (start.cord_x as f64, start.cord_y as f64);
+    let (dx, dy) = (end.cord_x as f64 - sx, end.cord_y as f64 - sy);
+    let length = dx * dx + dy * dy;
+    let along = if length == 0. { 0. } else { (((px - sx) * dx + (py - sy) * dy) / length).clamp(0., 1.) };
+    let (x, y) = (sx + along * dx, sy + along * dy);
+    ((px - x) * (px - x) + (py - y) * (py - y)).sqrt()
+}
+
+/// Douglas-Peucker simplification: keeps the ends of `path` and, recursively, the point furthest
+/// from the segment between the points kept around it while it lies further than `tolerance`.
+pub fn simplify_path(path: &[PathPoint], tolerance: f64) -> Vec<PathPoint> {
+    if path.len() < 3 || tolerance.is_nan() || tolerance < 0. {
+        return path.to_vec();
+    }
+    let mut kept = vec![false; path.len()];
+    kept[0] = true;
+    kept[path.len() - 1] = true;
+    let mut spans = vec![(0, path.len() - 1)];
+    while let Some((start, end)) = spans.pop() {
+        let furthest = (start + 1..end)
+            .map(|idx| (idx, segment_distance(&path[idx], &path[start], &path[end])))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((idx, distance)) = furthest {
+            if distance > tolerance {
+                kept[idx] = true;
+                spans.push((start, idx));
+                spans.push((idx, end));
+            }
+        }
+    }
+    path.iter().zip(kept).filter(|(_, kept)| *kept).map(|(point, _)| *point).collect()
+}
+
+/// Which end of the query a hop started from. Backward hops search from the target towards the
+/// source, so their `source` and `target` are swapped.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SearchDirection {
+    #[default]
+    Forward,
+    Backward,
+}
+
+impl SearchDirection {
+    pub(crate) fn opposite(self) -> Self {
+        match self {
+            SearchDirection::Forward => { SearchDirection::Backward }
+            SearchDirection::Backward => { SearchDirection::Forward }
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            SearchDirection::Forward => { "forward" }
+            SearchDirection::Backward => { "backward" }
+        }
     }
 }
 
-impl Eq for PathPoint {}
+/// Part of a route reaching a boundary node, recorded so a hop coming from the other end can be
+/// joined with it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct HalfRoute {
+    /// From the end the hop started at, up to the boundary node but without it.
+    pub(crate) path: Vec<PathPoint>,
+    pub(crate) cost: u64,
+    pub(crate) region_hops: usize,
+}
 
+impl HalfRoute {
+    /// Complete route through `meeting` and its cost, from the forward half to the backward one.
+    pub(crate) fn join(forward: &HalfRoute, meeting: PathPoint, backward: &HalfRoute) -> (Vec<PathPoint>, u64) {
+        let mut path = forward.path.clone();
+        path.push(meeting);
+        path.extend(backward.path.iter().rev().copied());
+        (path, forward.cost + backward.cost)
+    }
+}
+
+/// Internal record of a query travelling between servers. Carries the path assembled so far, or
+/// only its boundaries if compacted, so its layout is free to change without affecting clients.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub(crate) struct PathRequest {
+pub struct HopMessage {
     pub(crate) request_id: usize,
     pub(crate) source: NodeInfo,
     pub(crate) target: NodeInfo,
@@ -55,17 +317,67 @@ pub(crate) struct PathRequest {
     path: Vec<PathPoint>,
     cost: u64,
     pub(crate) visited_regions: Vec<RegionIdx>,
+    /// Cheapest complete route to the target found so far by any server, if known when this hop was sent.
+    #[serde(default)]
+    pub(crate) best_known_cost: Option<u64>,
+    #[serde(default)]
+    pub(crate) client: Option<String>,
+    #[serde(default)]
+    pub(crate) priority_class: Option<String>,
+    #[serde(default)]
+    pub(crate) priority: Priority,
+    /// Milliseconds since the unix epoch at which the query entered the cluster, 0 if unknown.
+    #[serde(default)]
+    pub(crate) issued_at: u64,
+    /// Milliseconds since the unix epoch after which the hop is dropped instead of served.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) deadline: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) avoid_nodes: Vec<NodeIdx>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) avoid_vertices: Vec<VertexIdx>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) avoid_regions: Vec<RegionIdx>,
+    #[serde(default)]
+    pub(crate) max_cost: Option<u64>,
+    #[serde(default)]
+    pub(crate) max_region_hops: Option<usize>,
+    #[serde(default)]
+    pub(crate) profile: Option<Profile>,
+    #[serde(default)]
+    pub(crate) simplify_tolerance: Option<f64>,
+    /// Whether the query is searched from both ends, whose hops meet at region boundaries.
+    #[serde(default)]
+    pub(crate) bidirectional: bool,
+    #[serde(default)]
+    pub(crate) direction: SearchDirection,
+    #[serde(default)]
+    pub(crate) reuse_route_of: Option<usize>,
+    #[serde(default)]
+    pub(crate) stream_events: bool,
+    /// Where the path entered every region after the first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    entries: Vec<RouteEntry>,
+    /// Whether the path is kept in redis instead, see [`crate::segments`]. `path` stays empty then.
+    #[serde(default)]
+    pub(crate) compact_path: bool,
+    /// Set on hops sent over both transports, see [`crate::mirror`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) probe: Option<Probe>,
+    /// W3C `traceparent` of the span that sent the hop on, see [`crate::telemetry`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) trace_context: Option<String>,
 }
 
-impl PathRequest {
+impl HopMessage {
     pub(crate) fn new(request_id: usize,
                       source: NodeInfo,
                       target: NodeInfo,
                       last: NodeIdx,
                       path: Vec<PathPoint>,
                       cost: u64,
-                      visited_regions: Vec<RegionIdx>) -> PathRequest {
-        PathRequest {
+                      visited_regions: Vec<RegionIdx>) -> HopMessage {
+        HopMessage {
             request_id,
             source,
             target,
@@ -73,56 +385,781 @@ impl PathRequest {
             path,
             cost,
             visited_regions,
+            best_known_cost: None,
+            client: None,
+            priority_class: None,
+            priority: Priority::High,
+            issued_at: 0,
+            deadline: None,
+            avoid_nodes: vec![],
+            avoid_vertices: vec![],
+            avoid_regions: vec![],
+            max_cost: None,
+            max_region_hops: None,
+            profile: None,
+            simplify_tolerance: None,
+            bidirectional: false,
+            direction: SearchDirection::Forward,
+            reuse_route_of: None,
+            stream_events: false,
+            entries: vec![],
+            compact_path: false,
+            probe: None,
+            trace_context: None,
+        }
+    }
+
+    /// Whether the hop is where its end of the query starts, before crossing any boundary.
+    pub(crate) fn is_fresh(&self) -> bool {
+        self.path.is_empty() && self.visited_regions.len() <= 1
+    }
+
+    /// Region the hop was sent to search, the one its last node belongs to.
+    pub(crate) fn region(&self) -> RegionIdx {
+        self.visited_regions.last().copied().unwrap_or(self.source.1)
+    }
+
+    /// The same query searched from the target towards the source.
+    pub(crate) fn reversed(&self) -> HopMessage {
+        let mut reversed = self.update(vec![], self.target.0, 0, self.target.1);
+        reversed.source = self.target;
+        reversed.target = self.source;
+        reversed.path = vec![];
+        reversed.cost = 0;
+        reversed.visited_regions = vec![self.target.1];
+        reversed.entries = vec![];
+        reversed.direction = self.direction.opposite();
+        reversed
+    }
+
+    /// What this hop records for hops from the other end to meet it, by node: the half route up to
+    /// `entered`, the node `last` it entered the region at, and if the `crossing` weight of the
+    /// boundary edge is known, the one up to the node it left the previous region from. Hops from
+    /// the other end cross the same edge the other way round, so they meet at one of its ends.
+    pub(crate) fn half_routes(&self, entered: PathPoint, crossing: Option<u64>) -> Vec<(PathPoint, HalfRoute)> {
+        let mut halves = vec![(entered, HalfRoute { path: self.path.clone(), cost: self.cost, region_hops: self.region_hops() })];
+        if let (Some(weight), Some((exit, path))) = (crossing, self.path.split_last()) {
+            let half = HalfRoute { path: path.to_vec(), cost: self.cost.saturating_sub(weight), region_hops: self.region_hops().saturating_sub(1) };
+            halves.push((*exit, half));
+        }
+        halves
+    }
+
+    /// Node the hop left the previous region from, across the boundary from `last`.
+    pub(crate) fn exit(&self) -> Option<NodeIdx> {
+        self.path.last().map(|point| point.id)
+    }
+
+    /// Result of a route joined from this hop and one from the other end of the query.
+    pub(crate) fn joined(&self, path: Vec<PathPoint>, cost: u64) -> RouteResult {
+        let (source, target) = self.query_endpoints();
+        RouteResult {
+            request_id: self.request_id,
+            source,
+            target,
+            path: self.client_path(path),
+            cost,
+            status: RouteStatus::Found,
+            weight_scale: WeightScale::default(),
+            reason: None,
+            failure: None,
+        }
+    }
+
+    /// Result of a query whose deadline passed before a route was found.
+    pub(crate) fn timed_out(&self) -> RouteResult {
+        let (source, target) = self.query_endpoints();
+        RouteResult {
+            request_id: self.request_id,
+            source,
+            target,
+            path: vec![],
+            cost: 0,
+            status: RouteStatus::TimedOut,
+            weight_scale: WeightScale::default(),
+            reason: Some(format!("No route found within {}ms", self.deadline.unwrap_or_default().saturating_sub(self.issued_at))),
+            failure: None,
+        }
+    }
+
+    /// Result of a query rejected before it was searched.
+    pub(crate) fn rate_limited(&self, reason: String) -> RouteResult {
+        self.without_route(RouteStatus::RateLimited, reason)
+    }
+
+    /// Result of a query none of whose searches reached the target, this hop being the last one.
+    pub(crate) fn not_found(&self, reason: String) -> RouteResult {
+        self.without_route(RouteStatus::NotFound, reason)
+    }
+
+    /// Result of a query without a route, one of whose hops was lost to `failure`. `UNAVAILABLE`
+    /// if it was lost to a server which went down.
+    pub(crate) fn failed(&self, failure: RouteFailure, reason: String) -> RouteResult {
+        let status = match failure.kind {
+            FailureKind::ServerDown => { RouteStatus::Unavailable }
+            _ => { RouteStatus::Failed }
+        };
+        RouteResult { failure: Some(failure), ..self.without_route(status, reason) }
+    }
+
+    fn without_route(&self, status: RouteStatus, reason: String) -> RouteResult {
+        let (source, target) = self.query_endpoints();
+        RouteResult {
+            request_id: self.request_id,
+            source,
+            target,
+            path: vec![],
+            cost: 0,
+            status,
+            weight_scale: WeightScale::default(),
+            reason: Some(reason),
+            failure: None,
+        }
+    }
+
+    /// Source and target as given by the client, whichever end this hop searches from.
+    fn query_endpoints(&self) -> (NodeInfo, NodeInfo) {
+        match self.direction {
+            SearchDirection::Forward => { (self.source, self.target) }
+            SearchDirection::Backward => { (self.target, self.source) }
+        }
+    }
+
+    /// Path as returned to the client, simplified if the query asks for it.
+    fn client_path(&self, path: Vec<PathPoint>) -> Vec<PathPoint> {
+        match self.simplify_tolerance {
+            Some(tolerance) => { simplify_path(&path, tolerance) }
+            None => { path }
+        }
+    }
+
+    pub(crate) fn avoid(&self) -> Avoid {
+        Avoid {
+            nodes: self.avoid_nodes.iter().copied().collect(),
+            vertices: self.avoid_vertices.iter().copied().collect(),
+            regions: self.avoid_regions.iter().copied().collect(),
+            profile: self.profile,
+        }
+    }
+
+    /// Time since the query entered the cluster.
+    pub(crate) fn elapsed(&self) -> Option<Duration> {
+        if self.issued_at == 0 {
+            return None;
+        }
+        Some(Duration::from_millis(now_millis().saturating_sub(self.issued_at)))
+    }
+
+    /// Gives the query `timeout` from entering the cluster, unless it set its own deadline.
+    pub(crate) fn default_deadline(&mut self, timeout: Duration) {
+        if self.deadline.is_none() && self.issued_at != 0 {
+            self.deadline = Some(self.issued_at.saturating_add(u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX)));
         }
     }
 
-    pub(crate) fn update_without_region(&self,
-                                        mut path: Vec<PathPoint>,
-                                        last: NodeIdx,
-                                        cost: u64) -> Self {
+    /// Whether the deadline of the query passed.
+    pub(crate) fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| now_millis() >= deadline)
+    }
+
+    pub(crate) fn cost(&self) -> u64 {
+        self.cost
+    }
+
+    /// Whether extending this hop by `extra_cost` cannot beat an already found route.
+    pub(crate) fn is_pruned(&self, extra_cost: u64, best_known_cost: Option<u64>) -> bool {
+        best_known_cost.is_some_and(|best| self.cost.saturating_add(extra_cost) >= best)
+    }
+
+    /// Number of region boundaries crossed so far.
+    pub(crate) fn region_hops(&self) -> usize {
+        self.visited_regions.len().saturating_sub(1)
+    }
+
+    /// The last regions the hop went through and those it entered more than once, for diagnostics.
+    pub(crate) fn region_trail(&self) -> String {
+        const SHOWN: usize = 16;
+        let skipped = self.visited_regions.len().saturating_sub(SHOWN);
+        let mut trail: Vec<String> = self.visited_regions[skipped..].iter().map(RegionIdx::to_string).collect();
+        if skipped > 0 {
+            trail.insert(0, format!("{} more", skipped));
+        }
+        let mut seen = HashSet::new();
+        let revisited: BTreeSet<RegionIdx> = self.visited_regions.iter().copied().filter(|region| !seen.insert(*region)).collect();
+        if revisited.is_empty() {
+            format!("regions {}", trail.join(" > "))
+        } else {
+            format!("regions {}, revisiting {:?}", trail.join(" > "), revisited)
+        }
+    }
+
+    /// Whether extending this hop by `extra_cost` breaks the limits set by the query.
+    pub(crate) fn exceeds_budget(&self, extra_cost: u64) -> bool {
+        self.max_cost.is_some_and(|max| self.cost.saturating_add(extra_cost) > max)
+            || self.max_region_hops.is_some_and(|max| self.region_hops() > max)
+    }
+
+    /// Completes the route with the last segment, which has to end at the target. Routes found by
+    /// backward hops are turned around.
+    pub(crate) fn finish(&self,
+                         mut path: Vec<PathPoint>,
+                         cost: u64) -> RouteResult {
         let mut new_path = self.path.clone();
         new_path.append(&mut path);
+        if self.direction == SearchDirection::Backward {
+            new_path.reverse();
+        }
+        self.joined(new_path, self.cost.saturating_add(cost))
+    }
 
-        PathRequest::new(
-            self.request_id,
-            self.source.clone(),
-            self.target.clone(),
-            last,
-            new_path,
-            self.cost.clone() + cost,
-            self.visited_regions.clone(),
-        )
+    /// Gives up the search, reporting the path assembled so far.
+    pub(crate) fn budget_exceeded(&self) -> RouteResult {
+        RouteResult {
+            request_id: self.request_id,
+            source: self.source,
+            target: self.target,
+            path: self.client_path(self.path.clone()),
+            cost: self.cost,
+            status: RouteStatus::BudgetExceeded,
+            weight_scale: WeightScale::default(),
+            reason: None,
+            failure: None,
+        }
     }
+
     pub(crate) fn update(&self,
                          mut path: Vec<PathPoint>,
                          last: NodeIdx,
                          cost: u64,
                          new_region_idx: RegionIdx) -> Self {
-        let mut new_path = self.path.clone();
-        new_path.append(&mut path);
-        let mut visited_regions = self.visited_regions.clone();
-        visited_regions.push(new_region_idx);
-
-        PathRequest::new(
-            self.request_id,
-            self.source.clone(),
-            self.target.clone(),
+        let mut new_request = HopMessage {
             last,
-            new_path,
-            self.cost.clone() + cost,
-            visited_regions,
-        )
+            cost: self.cost.saturating_add(cost),
+            // Only the hop a client sent reuses a route, and only mirrored hops carry a probe.
+            reuse_route_of: None,
+            probe: None,
+            ..self.clone()
+        };
+        if !new_request.compact_path {
+            new_request.path.append(&mut path);
+        }
+        new_request.visited_regions.push(new_region_idx);
+        new_request.entries.push(RouteEntry { node: last, region: new_region_idx, index: new_request.path.len(), cost: new_request.cost });
+        new_request
+    }
+
+    /// Keeps the path of a query entering the cluster in redis from now on. Bidirectional queries
+    /// and those reusing routes keep carrying theirs, as they are joined from full paths.
+    pub(crate) fn compact(&mut self) {
+        if self.is_fresh() && !self.bidirectional && self.reuse_route_of.is_none() {
+            self.compact_path = true;
+        }
+    }
+
+    /// Nodes each region crossed so far was searched from and left to, in order.
+    pub(crate) fn crossed_segments(&self) -> Vec<(NodeIdx, NodeIdx)> {
+        let starts = std::iter::once(self.source.0).chain(self.entries.iter().map(|entry| entry.node));
+        starts.zip(self.entries.iter().map(|entry| entry.node)).collect()
+    }
+
+    /// The hop carrying the path of the regions crossed, one segment per entry.
+    pub(crate) fn with_segments(&self, segments: Vec<Vec<PathPoint>>) -> HopMessage {
+        let mut expanded = self.clone();
+        expanded.path = vec![];
+        for (entry, mut segment) in expanded.entries.iter_mut().zip(segments) {
+            expanded.path.append(&mut segment);
+            entry.index = expanded.path.len();
+        }
+        expanded.compact_path = false;
+        expanded
+    }
+
+    /// Route completed by the final `path` of this hop, for later queries to reuse.
+    pub(crate) fn stored_route(&self, path: &[PathPoint]) -> StoredRoute {
+        StoredRoute {
+            source: self.source,
+            target: self.target,
+            path: self.path.iter().chain(path).cloned().collect(),
+            entries: self.entries.clone(),
+        }
+    }
+
+    /// Hop entering the target region of `route` along it, after `first_leg` (of `cost`) from the
+    /// source of this query to where the route entered its second region.
+    pub(crate) fn reusing(&self, route: &StoredRoute, mut first_leg: Vec<PathPoint>, cost: u64) -> HopMessage {
+        let (first, last) = (&route.entries[0], &route.entries[route.entries.len() - 1]);
+        first_leg.pop();
+        let offset = first_leg.len();
+        first_leg.extend_from_slice(&route.path[first.index..last.index]);
+        let mut reusing = self.update(first_leg, last.node, cost + last.cost - first.cost, last.region);
+        reusing.visited_regions = std::iter::once(self.source.1).chain(route.entries.iter().map(|entry| entry.region)).collect();
+        reusing.entries = route.entries.iter()
+            .map(|entry| RouteEntry { index: entry.index - first.index + offset, cost: entry.cost - first.cost + cost, ..*entry })
+            .collect();
+        reusing
+    }
+}
+
+/// Boundary node through which a route entered a region, with its position on the path and the
+/// cost of the route up to it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RouteEntry {
+    pub(crate) node: NodeIdx,
+    pub(crate) region: RegionIdx,
+    index: usize,
+    cost: u64,
+}
+
+/// Complete route of a query kept for later queries to reuse, with the full path unlike the
+/// simplified one the client may have received.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct StoredRoute {
+    source: NodeInfo,
+    target: NodeInfo,
+    path: Vec<PathPoint>,
+    pub(crate) entries: Vec<RouteEntry>,
+}
+
+impl StoredRoute {
+    /// Whether `request` starts and ends in the regions of this route, which crossed a boundary.
+    pub(crate) fn reusable_for(&self, request: &HopMessage) -> bool {
+        !self.entries.is_empty() && self.source.1 == request.source.1 && self.target.1 == request.target.1
+    }
+}
+
+impl From<ClientQuery> for HopMessage {
+    fn from(query: ClientQuery) -> Self {
+        let mut hop = HopMessage::new(
+            query.request_id,
+            query.source,
+            query.target,
+            query.source.0,
+            vec![],
+            0,
+            vec![query.source.1],
+        );
+        hop.client = query.client;
+        hop.priority_class = query.priority_class;
+        hop.priority = query.priority;
+        hop.issued_at = now_millis();
+        hop.deadline = query.timeout_ms.map(|timeout| hop.issued_at.saturating_add(timeout));
+        hop.avoid_nodes = query.avoid_nodes;
+        hop.avoid_vertices = query.avoid_vertices;
+        hop.avoid_regions = query.avoid_regions;
+        hop.max_cost = query.max_cost;
+        hop.max_region_hops = query.max_region_hops;
+        hop.profile = query.profile;
+        hop.simplify_tolerance = query.simplify_tolerance;
+        hop.reuse_route_of = query.reuse_route_of;
+        hop.stream_events = query.stream_events;
+        hop.trace_context = query.trace_context;
+        hop
+    }
+}
+
+/// Anything a server accepts on its listener: either a fresh query from a client
+/// or a hop forwarded by another server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub(crate) enum InboundMessage {
+    Hop(HopMessage),
+    Query(ClientQuery),
+}
+
+impl From<InboundMessage> for HopMessage {
+    fn from(message: InboundMessage) -> Self {
+        match message {
+            InboundMessage::Hop(hop) => { hop }
+            InboundMessage::Query(query) => { HopMessage::from(query) }
+        }
+    }
+}
+
+/// Payload read by a listener: a single message, or the hops a server forwarded to the same
+/// server at once, written as a sequence of hops.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub(crate) enum InboundPayload {
+    Batch(Vec<HopMessage>),
+    Single(Box<InboundMessage>),
+}
+
+impl InboundPayload {
+    pub(crate) fn into_hops(self) -> Vec<HopMessage> {
+        match self {
+            InboundPayload::Batch(hops) => { hops }
+            InboundPayload::Single(message) => { vec![HopMessage::from(*message)] }
+        }
+    }
+}
+
+/// Conversions from and to the messages of the protobuf schema, see [`crate::protocol`]. Servers
+/// only exchange them over gRPC so far.
+#[cfg(feature = "protobuf")]
+#[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+mod protobuf {
+    use std::convert::TryFrom;
+    use crate::domain::{ClientQuery, FailureKind, HopMessage, InboundMessage, NodeInfo, PathPoint, Priority, RouteEntry, RouteFailure, RouteResult, RouteStatus, SearchDirection};
+    use crate::graph::{Profile, WeightScale};
+    use crate::mirror::Probe;
+    use crate::protocol::{self, check_version, path_request, ProtocolError};
+
+    impl From<NodeInfo> for protocol::NodeInfo {
+        fn from(info: NodeInfo) -> Self {
+            Self { node: info.0 as u64, region: info.1 }
+        }
+    }
+
+    fn node_info(info: Option<protocol::NodeInfo>, field: &'static str) -> Result<NodeInfo, ProtocolError> {
+        let info = info.ok_or(ProtocolError::MissingField(field))?;
+        Ok(NodeInfo(info.node as usize, info.region))
+    }
+
+    impl From<&PathPoint> for protocol::PathPoint {
+        fn from(point: &PathPoint) -> Self {
+            Self { id: point.id as u64, region: point.region_id, x: point.cord_x, y: point.cord_y }
+        }
+    }
+
+    impl From<protocol::PathPoint> for PathPoint {
+        fn from(point: protocol::PathPoint) -> Self {
+            PathPoint::new(point.id as usize, point.region, point.x, point.y)
+        }
+    }
+
+    fn to_profile(profile: Option<Profile>) -> protocol::Profile {
+        match profile {
+            None => { protocol::Profile::Any }
+            Some(Profile::Car) => { protocol::Profile::Car }
+            Some(Profile::Bike) => { protocol::Profile::Bike }
+            Some(Profile::Foot) => { protocol::Profile::Foot }
+            Some(Profile::Truck) => { protocol::Profile::Truck }
+        }
+    }
+
+    fn from_profile(value: i32) -> Result<Option<Profile>, ProtocolError> {
+        match protocol::Profile::from_i32(value).ok_or(ProtocolError::UnknownValue("profile", value))? {
+            protocol::Profile::Any => { Ok(None) }
+            protocol::Profile::Car => { Ok(Some(Profile::Car)) }
+            protocol::Profile::Bike => { Ok(Some(Profile::Bike)) }
+            protocol::Profile::Foot => { Ok(Some(Profile::Foot)) }
+            protocol::Profile::Truck => { Ok(Some(Profile::Truck)) }
+        }
+    }
+
+    fn to_priority(priority: Priority) -> protocol::Priority {
+        match priority {
+            Priority::High => { protocol::Priority::High }
+            Priority::Low => { protocol::Priority::Low }
+        }
+    }
+
+    fn from_priority(value: i32) -> Result<Priority, ProtocolError> {
+        match protocol::Priority::from_i32(value).ok_or(ProtocolError::UnknownValue("priority", value))? {
+            protocol::Priority::High => { Ok(Priority::High) }
+            protocol::Priority::Low => { Ok(Priority::Low) }
+        }
+    }
+
+    fn to_indices(indices: &[usize]) -> Vec<u64> {
+        indices.iter().map(|&idx| idx as u64).collect()
+    }
+
+    fn from_indices(indices: Vec<u64>) -> Vec<usize> {
+        indices.into_iter().map(|idx| idx as usize).collect()
+    }
+
+    impl From<ClientQuery> for protocol::PathRequest {
+        fn from(query: ClientQuery) -> Self {
+            protocol::PathRequest {
+                version: protocol::VERSION,
+                body: Some(path_request::Body::Query(protocol::Query {
+                    request_id: query.request_id as u64,
+                    source: Some(query.source.into()),
+                    target: Some(query.target.into()),
+                    avoid_nodes: to_indices(&query.avoid_nodes),
+                    avoid_vertices: to_indices(&query.avoid_vertices),
+                    avoid_regions: query.avoid_regions,
+                    max_cost: query.max_cost,
+                    max_region_hops: query.max_region_hops.map(|hops| hops as u64),
+                    profile: to_profile(query.profile) as i32,
+                    simplify_tolerance: query.simplify_tolerance,
+                    reuse_route_of: query.reuse_route_of.map(|id| id as u64),
+                    stream_events: query.stream_events,
+                    timeout_ms: query.timeout_ms,
+                    trace_context: query.trace_context,
+                    client: query.client,
+                    priority_class: query.priority_class,
+                    priority: to_priority(query.priority) as i32,
+                })),
+                signature: vec![],
+            }
+        }
+    }
+
+    fn from_query(query: protocol::Query) -> Result<ClientQuery, ProtocolError> {
+        Ok(ClientQuery {
+            request_id: query.request_id as usize,
+            source: node_info(query.source, "source")?,
+            target: node_info(query.target, "target")?,
+            client: query.client,
+            priority_class: query.priority_class,
+            priority: from_priority(query.priority)?,
+            avoid_nodes: from_indices(query.avoid_nodes),
+            avoid_vertices: from_indices(query.avoid_vertices),
+            avoid_regions: query.avoid_regions,
+            max_cost: query.max_cost,
+            max_region_hops: query.max_region_hops.map(|hops| hops as usize),
+            profile: from_profile(query.profile)?,
+            simplify_tolerance: query.simplify_tolerance,
+            reuse_route_of: query.reuse_route_of.map(|id| id as usize),
+            stream_events: query.stream_events,
+            timeout_ms: query.timeout_ms,
+            trace_context: query.trace_context,
+        })
+    }
+
+    impl HopMessage {
+        pub(crate) fn to_protobuf(&self) -> protocol::PathRequest {
+            let direction = match self.direction {
+                SearchDirection::Forward => { protocol::SearchDirection::Forward }
+                SearchDirection::Backward => { protocol::SearchDirection::Backward }
+            };
+            protocol::PathRequest {
+                version: protocol::VERSION,
+                body: Some(path_request::Body::Hop(protocol::Hop {
+                    request_id: self.request_id as u64,
+                    source: Some(self.source.into()),
+                    target: Some(self.target.into()),
+                    last: self.last as u64,
+                    path: self.path.iter().map(protocol::PathPoint::from).collect(),
+                    cost: self.cost,
+                    visited_regions: self.visited_regions.clone(),
+                    best_known_cost: self.best_known_cost,
+                    client: self.client.clone(),
+                    priority_class: self.priority_class.clone(),
+                    priority: to_priority(self.priority) as i32,
+                    issued_at: self.issued_at,
+                    deadline: self.deadline,
+                    avoid_nodes: to_indices(&self.avoid_nodes),
+                    avoid_vertices: to_indices(&self.avoid_vertices),
+                    avoid_regions: self.avoid_regions.clone(),
+                    max_cost: self.max_cost,
+                    max_region_hops: self.max_region_hops.map(|hops| hops as u64),
+                    profile: to_profile(self.profile) as i32,
+                    simplify_tolerance: self.simplify_tolerance,
+                    bidirectional: self.bidirectional,
+                    direction: direction as i32,
+                    reuse_route_of: self.reuse_route_of.map(|id| id as u64),
+                    stream_events: self.stream_events,
+                    entries: self.entries.iter().map(|entry| protocol::RouteEntry {
+                        node: entry.node as u64,
+                        region: entry.region,
+                        index: entry.index as u64,
+                        cost: entry.cost,
+                    }).collect(),
+                    probe_sent_at: self.probe.map(|probe| probe.sent_at),
+                    trace_context: self.trace_context.clone(),
+                    compact_path: self.compact_path,
+                })),
+                signature: vec![],
+            }
+        }
+
+        fn from_protobuf(hop: protocol::Hop) -> Result<Self, ProtocolError> {
+            let direction = match protocol::SearchDirection::from_i32(hop.direction).ok_or(ProtocolError::UnknownValue("direction", hop.direction))? {
+                protocol::SearchDirection::Forward => { SearchDirection::Forward }
+                protocol::SearchDirection::Backward => { SearchDirection::Backward }
+            };
+            let mut message = HopMessage::new(
+                hop.request_id as usize,
+                node_info(hop.source, "source")?,
+                node_info(hop.target, "target")?,
+                hop.last as usize,
+                hop.path.into_iter().map(PathPoint::from).collect(),
+                hop.cost,
+                hop.visited_regions,
+            );
+            message.best_known_cost = hop.best_known_cost;
+            message.client = hop.client;
+            message.priority_class = hop.priority_class;
+            message.priority = from_priority(hop.priority)?;
+            message.issued_at = hop.issued_at;
+            message.deadline = hop.deadline;
+            message.avoid_nodes = from_indices(hop.avoid_nodes);
+            message.avoid_vertices = from_indices(hop.avoid_vertices);
+            message.avoid_regions = hop.avoid_regions;
+            message.max_cost = hop.max_cost;
+            message.max_region_hops = hop.max_region_hops.map(|hops| hops as usize);
+            message.profile = from_profile(hop.profile)?;
+            message.simplify_tolerance = hop.simplify_tolerance;
+            message.bidirectional = hop.bidirectional;
+            message.direction = direction;
+            message.reuse_route_of = hop.reuse_route_of.map(|id| id as usize);
+            message.stream_events = hop.stream_events;
+            message.entries = hop.entries.into_iter().map(|entry| RouteEntry {
+                node: entry.node as usize,
+                region: entry.region,
+                index: entry.index as usize,
+                cost: entry.cost,
+            }).collect();
+            message.probe = hop.probe_sent_at.map(|sent_at| Probe { sent_at });
+            message.trace_context = hop.trace_context;
+            message.compact_path = hop.compact_path;
+            Ok(message)
+        }
+    }
+
+    impl InboundMessage {
+        pub(crate) fn from_protobuf(request: protocol::PathRequest) -> Result<Self, ProtocolError> {
+            check_version(request.version)?;
+            match request.body.ok_or(ProtocolError::MissingField("body"))? {
+                path_request::Body::Query(query) => { Ok(InboundMessage::Query(from_query(query)?)) }
+                path_request::Body::Hop(hop) => { Ok(InboundMessage::Hop(HopMessage::from_protobuf(hop)?)) }
+            }
+        }
+    }
+
+    impl From<&RouteResult> for protocol::PathReply {
+        fn from(result: &RouteResult) -> Self {
+            let status = match result.status {
+                RouteStatus::Found => { protocol::RouteStatus::Found }
+                RouteStatus::BudgetExceeded => { protocol::RouteStatus::BudgetExceeded }
+                RouteStatus::NotFound => { protocol::RouteStatus::NotFound }
+                RouteStatus::TimedOut => { protocol::RouteStatus::TimedOut }
+                RouteStatus::RateLimited => { protocol::RouteStatus::RateLimited }
+                RouteStatus::Unavailable => { protocol::RouteStatus::Unavailable }
+                RouteStatus::Failed => { protocol::RouteStatus::Failed }
+            };
+            protocol::PathReply {
+                version: protocol::VERSION,
+                request_id: result.request_id as u64,
+                source: Some(result.source.into()),
+                target: Some(result.target.into()),
+                path: result.path.iter().map(protocol::PathPoint::from).collect(),
+                cost: result.cost,
+                status: status as i32,
+                weight_scale: result.weight_scale.0,
+                reason: result.reason.clone(),
+                failure: result.failure.map(|failure| protocol::RouteFailure {
+                    kind: match failure.kind {
+                        FailureKind::NotServedRegion => { protocol::FailureKind::NotServedRegion }
+                        FailureKind::Graph => { protocol::FailureKind::Graph }
+                        FailureKind::Send => { protocol::FailureKind::Send }
+                        FailureKind::ServerDown => { protocol::FailureKind::ServerDown }
+                        FailureKind::HopLimit => { protocol::FailureKind::HopLimit }
+                        FailureKind::Internal => { protocol::FailureKind::Internal }
+                    } as i32,
+                    server: failure.server as u64,
+                }),
+            }
+        }
+    }
+
+    impl TryFrom<protocol::PathReply> for RouteResult {
+        type Error = ProtocolError;
+
+        fn try_from(reply: protocol::PathReply) -> Result<Self, Self::Error> {
+            check_version(reply.version)?;
+            let status = match protocol::RouteStatus::from_i32(reply.status).ok_or(ProtocolError::UnknownValue("status", reply.status))? {
+                protocol::RouteStatus::Found => { RouteStatus::Found }
+                protocol::RouteStatus::BudgetExceeded => { RouteStatus::BudgetExceeded }
+                protocol::RouteStatus::NotFound => { RouteStatus::NotFound }
+                protocol::RouteStatus::TimedOut => { RouteStatus::TimedOut }
+                protocol::RouteStatus::RateLimited => { RouteStatus::RateLimited }
+                protocol::RouteStatus::Unavailable => { RouteStatus::Unavailable }
+                protocol::RouteStatus::Failed => { RouteStatus::Failed }
+            };
+            let failure = match reply.failure {
+                Some(failure) => {
+                    let kind = match protocol::FailureKind::from_i32(failure.kind).ok_or(ProtocolError::UnknownValue("failure.kind", failure.kind))? {
+                        protocol::FailureKind::NotServedRegion => { FailureKind::NotServedRegion }
+                        protocol::FailureKind::Graph => { FailureKind::Graph }
+                        protocol::FailureKind::Send => { FailureKind::Send }
+                        protocol::FailureKind::ServerDown => { FailureKind::ServerDown }
+                        protocol::FailureKind::HopLimit => { FailureKind::HopLimit }
+                        protocol::FailureKind::Internal => { FailureKind::Internal }
+                    };
+                    Some(RouteFailure { kind, server: failure.server as usize })
+                }
+                None => { None }
+            };
+            Ok(RouteResult {
+                request_id: reply.request_id as usize,
+                source: node_info(reply.source, "source")?,
+                target: node_info(reply.target, "target")?,
+                path: reply.path.into_iter().map(PathPoint::from).collect(),
+                cost: reply.cost,
+                status,
+                // Unset by writers unaware of fixed-point costs.
+                weight_scale: if reply.weight_scale == 0 { WeightScale::default() } else { WeightScale(reply.weight_scale) },
+                reason: reply.reason,
+                failure,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::convert::TryFrom;
+        use prost::Message;
+        use crate::domain::{ClientQuery, FailureKind, HopMessage, InboundMessage, NodeInfo, PathPoint, Priority, RouteEntry, RouteFailure, RouteResult, RouteStatus, SearchDirection};
+        use crate::graph::{Profile, WeightScale};
+        use crate::protocol::{self, ProtocolError};
+
+        #[test]
+        fn messages_survive_the_wire() {
+            let mut hop = HopMessage::new(7, NodeInfo(1, 1), NodeInfo(9, 3), 4, vec![PathPoint::new(1, 1, 0, 0), PathPoint::new(4, 2, 10, 5)], 42, vec![1, 2]);
+            hop.profile = Some(Profile::Bike);
+            hop.direction = SearchDirection::Backward;
+            hop.max_region_hops = Some(3);
+            hop.priority = Priority::Low;
+            hop.entries = vec![RouteEntry { node: 4, region: 2, index: 1, cost: 42 }];
+            let raw = hop.to_protobuf().encode_to_vec();
+            let decoded = match InboundMessage::from_protobuf(protocol::PathRequest::decode(raw.as_slice()).unwrap()).unwrap() {
+                InboundMessage::Hop(decoded) => { decoded }
+                InboundMessage::Query(_) => { panic!("Hop decoded as a query") }
+            };
+            assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&hop).unwrap());
+
+            let mut query = ClientQuery::new(8, NodeInfo(1, 1), NodeInfo(9, 3));
+            query.avoid_regions = vec![2];
+            query.priority = Priority::Low;
+            match InboundMessage::from_protobuf(protocol::PathRequest::from(query)).unwrap() {
+                InboundMessage::Query(query) => { assert_eq!((query.avoid_regions, query.priority), (vec![2], Priority::Low)) }
+                InboundMessage::Hop(_) => { panic!("Query decoded as a hop") }
+            }
+
+            let result = RouteResult { request_id: 7, source: NodeInfo(1, 1), target: NodeInfo(9, 3), path: vec![PathPoint::new(1, 1, 0, 0)], cost: 1500, status: RouteStatus::BudgetExceeded, weight_scale: WeightScale(1000), reason: None, failure: None };
+            let reply = RouteResult::try_from(protocol::PathReply::from(&result)).unwrap();
+            assert_eq!((reply.request_id, reply.status, reply.real_cost()), (7, RouteStatus::BudgetExceeded, 1.5));
+
+            let failure = RouteFailure { kind: FailureKind::Send, server: 4 };
+            let failed = HopMessage::from(ClientQuery::new(7, NodeInfo(1, 1), NodeInfo(9, 3))).failed(failure, "Connection refused".to_string());
+            let reply = RouteResult::try_from(protocol::PathReply::from(&failed)).unwrap();
+            assert_eq!((reply.status, reply.failure), (RouteStatus::Failed, Some(failure)));
+        }
+
+        #[test]
+        fn newer_versions_are_refused() {
+            let mut request = protocol::PathRequest::from(ClientQuery::new(8, NodeInfo(1, 1), NodeInfo(9, 3)));
+            request.version = protocol::VERSION + 1;
+            assert_eq!(InboundMessage::from_protobuf(request).unwrap_err(), ProtocolError::UnsupportedVersion(protocol::VERSION + 1));
+            let request = protocol::PathRequest { version: protocol::VERSION, body: None, signature: vec![] };
+            assert_eq!(InboundMessage::from_protobuf(request).unwrap_err(), ProtocolError::MissingField("body"));
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{PathPoint, PathRequest};
-    use crate::domain::NodeInfo;
+    use std::time::Duration;
+    use crate::domain::{simplify_path, ClientQuery, HalfRoute, HopMessage, InboundMessage, NodeInfo, PathPoint, Priority, RouteResult, RouteStatus, SearchDirection};
+    use crate::graph::{Profile, WeightScale};
 
     #[tokio::test]
     async fn sample_request() {
-        let mut request = PathRequest {
+        let mut request = HopMessage {
             request_id: 12,
             source: NodeInfo(1, 1),
             target: NodeInfo(100, 10),
@@ -130,6 +1167,27 @@ mod test {
             path: vec![],
             cost: 0,
             visited_regions: vec![],
+            best_known_cost: None,
+            client: None,
+            priority_class: None,
+            priority: Priority::High,
+            issued_at: 0,
+            deadline: None,
+            avoid_nodes: vec![],
+            avoid_vertices: vec![],
+            avoid_regions: vec![],
+            max_cost: None,
+            max_region_hops: None,
+            profile: None,
+            simplify_tolerance: None,
+            bidirectional: false,
+            direction: SearchDirection::Forward,
+            reuse_route_of: None,
+            stream_events: false,
+            entries: vec![],
+            compact_path: false,
+            probe: None,
+            trace_context: None,
         };
         let serialized_empty = serde_json::to_string(&request).unwrap();
         println!("{}", serialized_empty);
@@ -154,4 +1212,294 @@ mod test {
         let serialized_empty = serde_json::to_string(&request).unwrap();
         println!("{}", serialized_empty);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn query_roundtrip() {
+        let query = ClientQuery::new(7, NodeInfo::new(1, 1), NodeInfo::new(5, 2));
+        let hop = HopMessage::from(query);
+        assert_eq!(hop.last, 1);
+        assert_eq!(hop.visited_regions, vec![1]);
+        assert_eq!(hop.reversed().region(), 2);
+
+        let p1 = PathPoint::new(1, 1, 0, 0);
+        let p2 = PathPoint::new(3, 2, 4, 0);
+        let p3 = PathPoint::new(5, 2, 4, 4);
+        let hop = hop.update(vec![p1], 3, 10, 2);
+        assert_eq!(hop.region(), 2);
+        let result = hop.finish(vec![p2, p3], 5);
+        assert_eq!(result.request_id, 7);
+        assert_eq!(result.cost, 15);
+        let result = RouteResult { weight_scale: WeightScale(10), ..result };
+        assert_eq!(result.real_cost(), 1.5);
+        assert_eq!(result.path, vec![p1, p2, p3]);
+    }
+
+    #[test]
+    fn inbound_accepts_query_and_hop() {
+        let query = r#"{"request_id":3,"source":[1,1],"target":[9,4]}"#;
+        let hop = HopMessage::from(serde_json::from_str::<InboundMessage>(query).unwrap());
+        assert_eq!(hop.request_id, 3);
+        assert_eq!(hop.last, 1);
+        assert!(hop.elapsed().is_some());
+        let issued_at = hop.issued_at;
+
+        let forwarded = serde_json::to_string(&hop.update(vec![], 6, 2, 3)).unwrap();
+        let hop = HopMessage::from(serde_json::from_str::<InboundMessage>(&forwarded).unwrap());
+        assert_eq!(hop.last, 6);
+        assert_eq!(hop.visited_regions, vec![1, 3]);
+        assert_eq!(hop.best_known_cost, None);
+        assert_eq!(hop.issued_at, issued_at);
+        assert!(!forwarded.contains("avoid"));
+
+        let query = r#"{"request_id":4,"source":[1,1],"target":[9,4],"avoid_nodes":[5],"avoid_regions":[2],"profile":"bike"}"#;
+        let hop = HopMessage::from(serde_json::from_str::<InboundMessage>(query).unwrap()).update(vec![], 6, 2, 3);
+        let avoid = hop.avoid();
+        assert!(avoid.nodes.contains(&5) && avoid.regions.contains(&2) && avoid.vertices.is_empty());
+        assert_eq!(avoid.profile, Some(Profile::Bike));
+    }
+
+    #[test]
+    fn deadlines_travel_with_the_hops() {
+        let mut query = ClientQuery::new(5, NodeInfo::new(1, 1), NodeInfo::new(9, 4));
+        query.timeout_ms = Some(60_000);
+        let hop = HopMessage::from(query);
+        assert_eq!(hop.deadline, Some(hop.issued_at + 60_000));
+        let forwarded = hop.update(vec![], 6, 2, 3);
+        assert_eq!(forwarded.deadline, hop.deadline);
+        assert!(!forwarded.is_expired());
+
+        let mut hop = HopMessage::from(ClientQuery::new(6, NodeInfo::new(1, 1), NodeInfo::new(9, 4)));
+        assert!(!serde_json::to_string(&hop).unwrap().contains("deadline"));
+        hop.issued_at -= 2_000;
+        hop.default_deadline(Duration::from_secs(1));
+        assert!(hop.is_expired());
+        let result = hop.reversed().timed_out();
+        assert_eq!((result.status, result.source.0, result.target.0), (RouteStatus::TimedOut, 1, 9));
+        assert_eq!(result.reason.as_deref(), Some("No route found within 1000ms"));
+
+        let mut query = ClientQuery::new(7, NodeInfo::new(1, 1), NodeInfo::new(9, 4));
+        query.timeout_ms = Some(u64::MAX);
+        let mut hop = HopMessage::from(query);
+        assert_eq!(hop.deadline, Some(u64::MAX));
+        assert!(!hop.is_expired());
+        hop.deadline = None;
+        hop.default_deadline(Duration::MAX);
+        assert_eq!(hop.deadline, Some(u64::MAX));
+    }
+
+    #[test]
+    fn region_trails_show_the_last_regions_and_the_revisited_ones() {
+        let mut hop = HopMessage::from(ClientQuery::new(5, NodeInfo::new(1, 1), NodeInfo::new(9, 4)));
+        hop = hop.update(vec![], 2, 1, 2).update(vec![], 3, 1, 3);
+        assert_eq!(hop.region_trail(), "regions 1 > 2 > 3");
+        for _ in 0..10 {
+            hop = hop.update(vec![], 2, 1, 2).update(vec![], 3, 1, 3);
+        }
+        assert_eq!(hop.region_hops(), 22);
+        assert_eq!(hop.region_trail(), "regions 7 more > 2 > 3 > 2 > 3 > 2 > 3 > 2 > 3 > 2 > 3 > 2 > 3 > 2 > 3 > 2 > 3, revisiting {2, 3}");
+    }
+
+    #[test]
+    fn trace_contexts_travel_with_the_hops() {
+        let parent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let query = r#"{"request_id":5,"source":[1,1],"target":[9,4],"trace_context":"00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"}"#;
+        let hop = HopMessage::from(serde_json::from_str::<InboundMessage>(query).unwrap());
+        assert_eq!(hop.trace_context.as_deref(), Some(parent));
+        let forwarded = hop.update(vec![], 6, 2, 3);
+        assert_eq!(forwarded.trace_context.as_deref(), Some(parent));
+        let hop = HopMessage::from(ClientQuery::new(6, NodeInfo::new(1, 1), NodeInfo::new(9, 4)));
+        assert!(!serde_json::to_string(&hop).unwrap().contains("trace_context"));
+    }
+
+    #[test]
+    fn budget() {
+        let query = r#"{"request_id":5,"source":[1,1],"target":[9,4],"max_cost":20,"max_region_hops":1}"#;
+        let hop = HopMessage::from(serde_json::from_str::<InboundMessage>(query).unwrap());
+        assert!(!hop.exceeds_budget(20));
+        assert!(hop.exceeds_budget(21));
+
+        let hop = hop.update(vec![PathPoint::new(1, 1, 0, 0)], 3, 10, 2);
+        assert_eq!(hop.region_hops(), 1);
+        assert!(!hop.exceeds_budget(0));
+        assert!(hop.update(vec![], 4, 1, 3).exceeds_budget(0));
+
+        let result = serde_json::to_string(&hop.budget_exceeded()).unwrap();
+        assert!(result.contains("BUDGET_EXCEEDED"));
+        assert_eq!(hop.budget_exceeded().cost, 10);
+        assert_eq!(hop.finish(vec![], 1).status, RouteStatus::Found);
+
+        let mut hop = hop;
+        hop.cost = u64::MAX;
+        assert!(hop.exceeds_budget(1));
+        assert_eq!(hop.update(vec![], 4, 1, 3).cost(), u64::MAX);
+        assert_eq!(hop.finish(vec![], 1).cost, u64::MAX);
+    }
+
+    #[test]
+    fn pruning() {
+        let mut hop = HopMessage::from(ClientQuery::new(1, NodeInfo::new(1, 1), NodeInfo::new(5, 2)));
+        hop = hop.update(vec![], 3, 10, 2);
+        assert!(!hop.is_pruned(5, None));
+        assert!(!hop.is_pruned(5, Some(16)));
+        assert!(hop.is_pruned(6, Some(16)));
+
+        hop.best_known_cost = Some(16);
+        let forwarded = serde_json::to_string(&hop.update(vec![], 4, 1, 3)).unwrap();
+        let mut hop = HopMessage::from(serde_json::from_str::<InboundMessage>(&forwarded).unwrap());
+        assert_eq!(hop.best_known_cost, Some(16));
+        assert_eq!(hop.cost(), 11);
+
+        hop.cost = u64::MAX;
+        assert!(hop.is_pruned(1, Some(16)));
+    }
+
+    #[test]
+    fn both_ends_meet() {
+        let point = |id| PathPoint::new(id, 1, id as u64, 0);
+        let mut forward = HopMessage::from(ClientQuery::new(1, NodeInfo::new(1, 1), NodeInfo::new(5, 3)));
+        forward.bidirectional = true;
+        let backward = forward.reversed();
+        assert!(forward.is_fresh() && backward.is_fresh());
+        assert_eq!((backward.source.0, backward.target.0, backward.last), (5, 1, 5));
+        assert_eq!(backward.direction, SearchDirection::Backward);
+
+        let forward = forward.update(vec![point(1), point(2)], 3, 6, 2);
+        let backward = backward.update(vec![point(5), point(4)], 3, 4, 2);
+        assert!(!forward.is_fresh() && backward.bidirectional);
+        let half = |hop: &HopMessage| hop.half_routes(point(3), None).remove(0).1;
+        let (path, cost) = HalfRoute::join(&half(&forward), point(3), &half(&backward));
+        assert_eq!(path, (1..=5).map(point).collect::<Vec<_>>());
+        assert_eq!(cost, 10);
+        let joined = backward.joined(path, cost);
+        assert_eq!((joined.source.0, joined.target.0), (1, 5));
+
+        let finished = backward.finish(vec![point(3), point(2), point(1)], 2);
+        assert_eq!(finished.path, vec![point(1), point(2), point(3), point(4), point(5)]);
+        assert_eq!((finished.source.0, finished.target.0, finished.cost), (1, 5, 6));
+    }
+
+    #[tokio::test]
+    async fn both_ends_meet_across_the_boundary_edge() {
+        use crate::fixtures::generate_sample;
+        use crate::graph::{Graph, PathResult, SearchLimits};
+        use crate::graph_provider::mock::MockGraphProvider;
+        use crate::GraphProvider;
+
+        let dir = generate_sample("two_regions");
+        let provider = MockGraphProvider::new(dir.path().to_path_buf()).with_weight_scale(WeightScale(10));
+        let regions = [provider.get_region(1).await.unwrap(), provider.get_region(2).await.unwrap()];
+        async fn cross(graph: &Graph, hop: &HopMessage) -> HopMessage {
+            let results = graph.find_way(NodeInfo(hop.last, hop.region()), hop.target, &hop.avoid(), &SearchLimits::default()).await.unwrap();
+            match results.into_iter().next().unwrap() {
+                PathResult::Continue(path, cost, continuation) => { hop.update(path, continuation.get_node_idx(), cost, 3 - hop.region()) }
+                PathResult::TargetReached(..) => { panic!("The target is in the other region") }
+            }
+        }
+        let mut query = HopMessage::from(ClientQuery::new(1, NodeInfo(1, 1), NodeInfo(4, 2)));
+        query.bidirectional = true;
+        let forward = cross(&regions[0], &query).await;
+        let backward = cross(&regions[1], &query.reversed()).await;
+        // Each end entered the other region, neither at the node the other one entered at.
+        assert_eq!((forward.last, backward.last), (3, 2));
+
+        let halves = |hop: &HopMessage| {
+            let graph = &regions[hop.region() as usize - 1];
+            let entered = PathPoint::from(&*graph.get_node(hop.last).unwrap());
+            let crossing = hop.exit().and_then(|exit| graph.crossing_weight(hop.last, exit, &hop.avoid()));
+            hop.half_routes(entered, crossing)
+        };
+        let (forward_halves, backward_halves) = (halves(&forward), halves(&backward));
+        for (point, forward_half) in forward_halves.iter() {
+            let (_, backward_half) = backward_halves.iter().find(|(other, _)| other == point).unwrap();
+            let (path, cost) = HalfRoute::join(forward_half, *point, backward_half);
+            assert_eq!(path.iter().map(PathPoint::id).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+            assert_eq!((cost, forward_half.region_hops + backward_half.region_hops), (65, 1));
+        }
+        // The node the forward end left from is avoided, so the ends must not meet there.
+        let mut avoiding = forward.clone();
+        avoiding.avoid_nodes = vec![forward.exit().unwrap()];
+        assert_eq!(halves(&avoiding).iter().map(|(point, _)| point.id).collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn simplification() {
+        let point = |id, x, y| PathPoint::new(id, 1, x, y);
+        // Along x with a small bump at 3 and a sharp turn at 5.
+        let path = vec![point(1, 0, 0), point(2, 1, 0), point(3, 2, 1), point(4, 3, 0), point(5, 4, 0), point(6, 4, 5)];
+        let ids = |path: Vec<PathPoint>| path.iter().map(|point| point.id).collect::<Vec<_>>();
+        assert_eq!(ids(simplify_path(&path, 0.)), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(ids(simplify_path(&path, 2.)), vec![1, 5, 6]);
+        assert_eq!(ids(simplify_path(&path, 10.)), vec![1, 6]);
+        assert_eq!(ids(simplify_path(&path[..2], 10.)), vec![1, 2]);
+
+        let mut query = ClientQuery::new(1, NodeInfo::new(1, 1), NodeInfo::new(6, 1));
+        query.simplify_tolerance = Some(2.);
+        let hop = HopMessage::from(query).update(vec![], 4, 0, 2);
+        assert_eq!(ids(hop.finish(path, 9).path), vec![1, 5, 6]);
+    }
+
+    #[test]
+    fn reused_routes() {
+        let point = |id| PathPoint::new(id, 0, id as u64, 0);
+        let ids = |path: &[PathPoint]| path.iter().map(|point| point.id).collect::<Vec<_>>();
+        // 1 and 2 in region 1, 3 and 4 in region 2, 5 and 9 in region 3.
+        let hop = HopMessage::from(ClientQuery::new(12, NodeInfo(1, 1), NodeInfo(9, 3)))
+            .update(vec![point(1), point(2)], 3, 5, 2)
+            .update(vec![point(3), point(4)], 5, 4, 3);
+        let route = hop.stored_route(&[point(5), point(9)]);
+        assert_eq!(ids(&route.path), vec![1, 2, 3, 4, 5, 9]);
+
+        let mut query = ClientQuery::new(13, NodeInfo(10, 1), NodeInfo(11, 3));
+        query.reuse_route_of = Some(12);
+        let request = HopMessage::from(query);
+        assert!(route.reusable_for(&request));
+        let reusing = request.reusing(&route, vec![point(10), point(3)], 3);
+        assert_eq!((reusing.last, reusing.cost, reusing.visited_regions.clone()), (5, 3 + 4, vec![1, 2, 3]));
+        let result = reusing.finish(vec![point(5), point(11)], 2);
+        assert_eq!((ids(&result.path), result.cost), (vec![10, 3, 4, 5, 11], 9));
+        // Routes reused again keep pointing at their boundaries.
+        let reused = reusing.stored_route(&[point(5), point(11)]);
+        assert_eq!(reused.entries.iter().map(|entry| (ids(&reused.path)[entry.index], entry.cost)).collect::<Vec<_>>(), vec![(3, 3), (5, 7)]);
+
+        assert!(!route.reusable_for(&HopMessage::from(ClientQuery::new(14, NodeInfo(10, 2), NodeInfo(11, 3)))));
+        let local = HopMessage::from(ClientQuery::new(15, NodeInfo(1, 1), NodeInfo(2, 1))).stored_route(&[point(1), point(2)]);
+        assert!(!local.reusable_for(&HopMessage::from(ClientQuery::new(16, NodeInfo(1, 1), NodeInfo(2, 1)))));
+    }
+
+    #[test]
+    fn compacted_paths_are_assembled_from_segments() {
+        let point = |id| PathPoint::new(id, 0, id as u64, 0);
+        let ids = |path: &[PathPoint]| path.iter().map(|point| point.id).collect::<Vec<_>>();
+        let mut hop = HopMessage::from(ClientQuery::new(12, NodeInfo(1, 1), NodeInfo(9, 3)));
+        hop.compact();
+        let hop = hop
+            .update(vec![point(1), point(2)], 3, 5, 2)
+            .update(vec![point(3), point(4)], 5, 4, 3);
+        assert!(hop.path.is_empty() && hop.compact_path);
+        assert_eq!((hop.last, hop.cost), (5, 9));
+        assert_eq!(hop.crossed_segments(), vec![(1, 3), (3, 5)]);
+
+        let expanded = hop.with_segments(vec![vec![point(1), point(2)], vec![point(3), point(4)]]);
+        assert!(!expanded.compact_path);
+        let result = expanded.finish(vec![point(5), point(9)], 2);
+        assert_eq!((ids(&result.path), result.cost), (vec![1, 2, 3, 4, 5, 9], 11));
+        // Stored routes point at their boundaries as if never compacted.
+        let route = expanded.stored_route(&[point(5), point(9)]);
+        assert_eq!(route.entries.iter().map(|entry| ids(&route.path)[entry.index]).collect::<Vec<_>>(), vec![3, 5]);
+
+        let mut bidirectional = HopMessage::from(ClientQuery::new(13, NodeInfo(1, 1), NodeInfo(9, 3)));
+        bidirectional.bidirectional = true;
+        bidirectional.compact();
+        assert!(!bidirectional.compact_path);
+    }
+
+    #[test]
+    fn unreachable_targets() {
+        let request = HopMessage::from(ClientQuery::new(3, NodeInfo(1, 1), NodeInfo(9, 2)));
+        let result = request.reversed().not_found("Node 9 is unreachable".to_string());
+        assert_eq!((result.source.0, result.target.0, result.status), (1, 9, RouteStatus::NotFound));
+        let published = serde_json::to_string(&result).unwrap();
+        assert!(published.contains(r#""status":"NOT_FOUND","weight_scale":1,"reason":"Node 9 is unreachable""#));
+        assert!(!serde_json::to_string(&request.budget_exceeded()).unwrap().contains("reason"));
+    }
+}
@@ -0,0 +1,130 @@
+//! Progress of queries for clients watching them, e.g. over `GET /paths/<request id>/events` of
+//! the HTTP ingress. Every server publishes the events of the queries it serves on
+//! `events_<request id>`: the regions entered by queries setting `stream_events`, and the results
+//! of all queries sent by servers running the HTTP ingress or having a progress channel. With PROGRESS_CHANNEL set, the events of every
+//! query are published on that channel too, for dashboards following the whole cluster.
+
+use std::env;
+use serde::{Deserialize, Serialize};
+use crate::client;
+use crate::domain::{HopMessage, RouteResult};
+use crate::graph::RegionIdx;
+use crate::middleware::HopMiddleware;
+use crate::node_connector::{BasicResult, ResultReplier};
+use crate::policy::ExecutionParams;
+use crate::redis_connector::RedisConnector;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum QueryEvent {
+    /// A hop of the query is searched in `region` by server `server`, having cost `cost` up to
+    /// there. A query searching several continuations enters many regions at once.
+    RegionEntered {
+        request_id: usize,
+        region: RegionIdx,
+        cost: u64,
+        server: usize,
+    },
+    /// A result of the query, with the final path if it was found.
+    Result(RouteResult),
+}
+
+/// Reads PROGRESS_CHANNEL, the redis channel the events of all queries are published on.
+pub(crate) fn progress_channel_from_env() -> Option<String> {
+    env::var("PROGRESS_CHANNEL").ok().filter(|channel| !channel.is_empty())
+}
+
+/// Publishes `event` of query `request_id` on its own channel if the query streams its events,
+/// and on the progress channel if there is one.
+async fn publish(redis_connector: &RedisConnector, progress_channel: Option<&str>, request_id: usize, stream_events: bool, event: &QueryEvent) {
+    let channels = stream_events.then(|| client::events_channel(request_id)).into_iter()
+        .chain(progress_channel.map(str::to_string));
+    for channel in channels {
+        if let Err(err) = redis_connector.publish_event(&channel, event).await {
+            log::debug!("Unable to publish an event of request {} on {}, details: {}", request_id, channel, err);
+        }
+    }
+}
+
+/// Publishes where the hops of queries setting `stream_events`, or of every query with a progress
+/// channel, are searched.
+pub(crate) struct ProgressEvents {
+    redis_connector: RedisConnector,
+    server_id: usize,
+    progress_channel: Option<String>,
+}
+
+impl ProgressEvents {
+    pub(crate) fn new(redis_connector: RedisConnector, server_id: usize, progress_channel: Option<String>) -> Self {
+        Self {
+            redis_connector,
+            server_id,
+            progress_channel,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl HopMiddleware for ProgressEvents {
+    async fn before(&self, request: &HopMessage, _params: &ExecutionParams) -> BasicResult<()> {
+        if request.stream_events || self.progress_channel.is_some() {
+            let event = QueryEvent::RegionEntered {
+                request_id: request.request_id,
+                region: request.region(),
+                cost: request.cost(),
+                server: self.server_id,
+            };
+            publish(&self.redis_connector, self.progress_channel.as_deref(), request.request_id, request.stream_events, &event).await;
+        }
+        Ok(())
+    }
+}
+
+/// Publishes results as events before handing them to the replier of the transport.
+#[derive(Clone)]
+pub(crate) struct EventReplier {
+    primary: Box<dyn ResultReplier>,
+    redis_connector: RedisConnector,
+    progress_channel: Option<String>,
+}
+
+impl EventReplier {
+    pub(crate) fn new(primary: Box<dyn ResultReplier>, redis_connector: RedisConnector, progress_channel: Option<String>) -> Self {
+        Self {
+            primary,
+            redis_connector,
+            progress_channel,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ResultReplier for EventReplier {
+    async fn send(&self, reply: &RouteResult) -> BasicResult<()> {
+        publish(&self.redis_connector, self.progress_channel.as_deref(), reply.request_id, true, &QueryEvent::Result(reply.clone())).await;
+        self.primary.send(reply).await
+    }
+
+    async fn flush(&self) -> BasicResult<()> {
+        self.primary.flush().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::domain::{NodeInfo, RouteResult, RouteStatus};
+    use crate::events::QueryEvent;
+
+    #[test]
+    fn events_are_tagged() {
+        let entered = serde_json::to_string(&QueryEvent::RegionEntered { request_id: 7, region: 3, cost: 120, server: 2 }).unwrap();
+        assert_eq!(entered, r#"{"event":"region_entered","request_id":7,"region":3,"cost":120,"server":2}"#);
+        let result = RouteResult { request_id: 7, source: NodeInfo(1, 1), target: NodeInfo(2, 3), path: vec![], cost: 120, status: RouteStatus::Found, weight_scale: Default::default(), reason: None, failure: None };
+        let published = serde_json::to_string(&QueryEvent::Result(result)).unwrap();
+        assert!(published.starts_with(r#"{"event":"result","request_id":7,"#));
+        match serde_json::from_str(&published).unwrap() {
+            QueryEvent::Result(result) => { assert_eq!(result.cost, 120) }
+            event => { panic!("Unexpected event {:?}", event) }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::env;
+use rand::Rng;
+use serde::Deserialize;
+use crate::graph::RegionIdx;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// How often forwarding a request into a neighbouring region has been tried and how often the
+/// resulting route was the one delivered to the client.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct FanoutStats {
+    pub(crate) tries: u64,
+    pub(crate) wins: u64,
+}
+
+impl FanoutStats {
+    /// Laplace smoothed success rate, untried neighbours start at 1/2.
+    fn score(&self) -> f64 {
+        (self.wins as f64 + 1.) / (self.tries as f64 + 2.)
+    }
+}
+
+/// What continuations into neighbouring regions are ranked by.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FanoutRanking {
+    /// How often the neighbour led to the winning route before.
+    Wins,
+    /// Cost so far and the least cost of the boundary crossings left to the target region, in the
+    /// meta-graph of regions. Continuations which can't beat the best known route are dropped.
+    Distance,
+}
+
+impl FanoutRanking {
+    pub(crate) fn named(name: &str) -> Result<Self> {
+        match name {
+            "wins" => { Ok(FanoutRanking::Wins) }
+            "distance" => { Ok(FanoutRanking::Distance) }
+            _ => { Err(format!("Unknown fan-out ranking {}", name))? }
+        }
+    }
+}
+
+/// Orders the continuations of a request, by default by how often the neighbouring region led to
+/// the winning route before, and optionally keeps only the continuations into the best `limit`
+/// neighbours. With probability `epsilon` the last kept slot goes to a random other neighbour, so
+/// the statistics keep being refreshed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FanoutPolicy {
+    pub(crate) limit: Option<usize>,
+    pub(crate) epsilon: f64,
+    pub(crate) ranking: FanoutRanking,
+}
+
+impl Default for FanoutPolicy {
+    fn default() -> Self {
+        Self {
+            limit: None,
+            epsilon: 0.1,
+            ranking: FanoutRanking::Wins,
+        }
+    }
+}
+
+impl FanoutPolicy {
+    pub(crate) fn from_env() -> Result<Self> {
+        let mut policy = FanoutPolicy::default();
+        if let Ok(limit) = env::var("FANOUT_LIMIT") {
+            policy.limit = Some(limit.parse()?);
+        }
+        if let Ok(epsilon) = env::var("FANOUT_EPSILON") {
+            policy.epsilon = epsilon.parse()?;
+        }
+        if let Ok(ranking) = env::var("FANOUT_RANKING") {
+            policy.ranking = FanoutRanking::named(&ranking)?;
+        }
+        Ok(policy)
+    }
+
+    /// `candidates` are expected cheapest first, which breaks ties between equally successful neighbours.
+    /// Ranked by distance they are expected in that order already. The continuations into a
+    /// neighbour are kept together, after those into better ranked ones.
+    pub(crate) fn select<T>(&self,
+                            mut candidates: Vec<(RegionIdx, T)>,
+                            stats: &HashMap<RegionIdx, FanoutStats>,
+                            rng: &mut impl Rng) -> Vec<(RegionIdx, T)> {
+        if self.ranking == FanoutRanking::Wins {
+            let score = |region: &RegionIdx| stats.get(region).copied().unwrap_or_default().score();
+            candidates.sort_by(|(a, _), (b, _)| score(b).total_cmp(&score(a)));
+        }
+        let mut regions: Vec<RegionIdx> = vec![];
+        for (region, _) in candidates.iter() {
+            if !regions.contains(region) {
+                regions.push(*region);
+            }
+        }
+        let limit = match self.limit {
+            Some(limit) if limit > 0 && limit < regions.len() => { limit }
+            _ => { return candidates }
+        };
+        if rng.gen_bool(self.epsilon.clamp(0., 1.)) {
+            let explored = rng.gen_range(limit..regions.len());
+            regions.swap(limit - 1, explored);
+        }
+        let mut selected = Vec::with_capacity(candidates.len());
+        for region in regions.into_iter().take(limit) {
+            let (into_region, rest): (Vec<_>, Vec<_>) = candidates.into_iter().partition(|(candidate, _)| *candidate == region);
+            selected.extend(into_region);
+            candidates = rest;
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::fanout::{FanoutPolicy, FanoutRanking, FanoutStats};
+
+    #[test]
+    fn successful_neighbours_first() {
+        let stats = HashMap::from([
+            (2, FanoutStats { tries: 10, wins: 1 }),
+            (3, FanoutStats { tries: 10, wins: 9 }),
+        ]);
+        let mut rng = StdRng::seed_from_u64(1);
+        let policy = FanoutPolicy { limit: None, epsilon: 0., ranking: FanoutRanking::Wins };
+        let order: Vec<_> = policy.select(vec![(2, ()), (4, ()), (3, ())], &stats, &mut rng)
+            .into_iter().map(|(region, _)| region).collect();
+        assert_eq!(order, vec![3, 4, 2]);
+
+        let policy = FanoutPolicy { limit: Some(1), epsilon: 0., ranking: FanoutRanking::Wins };
+        assert_eq!(policy.select(vec![(2, ()), (4, ()), (3, ())], &stats, &mut rng)[0].0, 3);
+
+        let policy = FanoutPolicy { limit: Some(2), epsilon: 0., ranking: FanoutRanking::Distance };
+        let order: Vec<_> = policy.select(vec![(2, ()), (4, ()), (3, ())], &stats, &mut rng)
+            .into_iter().map(|(region, _)| region).collect();
+        assert_eq!(order, vec![2, 4]);
+    }
+
+    #[test]
+    fn the_limit_counts_regions() {
+        let stats = HashMap::from([(3, FanoutStats { tries: 10, wins: 9 })]);
+        let mut rng = StdRng::seed_from_u64(1);
+        let policy = FanoutPolicy { limit: Some(2), epsilon: 0., ranking: FanoutRanking::Wins };
+        let selected = policy.select(vec![(2, 'a'), (4, 'b'), (3, 'c'), (3, 'd'), (2, 'e')], &stats, &mut rng);
+        assert_eq!(selected, vec![(3, 'c'), (3, 'd'), (2, 'a'), (2, 'e')]);
+    }
+
+    #[test]
+    fn exploration() {
+        let stats = HashMap::from([(3, FanoutStats { tries: 10, wins: 9 })]);
+        let mut rng = StdRng::seed_from_u64(1);
+        let policy = FanoutPolicy { limit: Some(2), epsilon: 1., ranking: FanoutRanking::Wins };
+        let selected = policy.select(vec![(2, ()), (3, ()), (4, ())], &stats, &mut rng);
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[0].0, 3);
+        assert_eq!(selected[1].0, 4);
+    }
+}
@@ -0,0 +1,313 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use crate::graph::{NodeIdx, Profile, RegionIdx, VertexIdx, WeightScale};
+use crate::graph_provider::{GroupInfo, RawNode, RawVertex};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Small graph described by hand, e.g. in a TOML file:
+///
+/// ```toml
+/// [[nodes]]
+/// id = 1
+/// x = 0
+/// y = 0
+/// region = 1
+///
+/// [[edges]]
+/// a = 1
+/// b = 2
+/// weight = 3
+///
+/// [[groups]]
+/// id = 1
+/// regions = [1]
+///
+/// # Optional, regions grouped into super-regions
+/// [[super_regions]]
+/// id = 10
+/// regions = [1]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FixtureDefinition {
+    pub nodes: Vec<FixtureNode>,
+    pub edges: Vec<FixtureEdge>,
+    pub groups: Vec<FixtureGroup>,
+    #[serde(default)]
+    pub super_regions: Vec<FixtureSuperRegion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FixtureNode {
+    pub id: NodeIdx,
+    pub x: u64,
+    pub y: u64,
+    pub region: RegionIdx,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FixtureEdge {
+    /// Defaults to the position of the edge in the definition.
+    pub id: Option<VertexIdx>,
+    pub a: NodeIdx,
+    pub b: NodeIdx,
+    pub weight: f64,
+    /// Transport modes allowed on the edge, all of them if absent.
+    pub access: Option<Vec<Profile>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FixtureGroup {
+    pub id: usize,
+    pub regions: Vec<RegionIdx>,
+}
+
+/// Regions routed to as one from afar. Ids index region bits like region ids, so they must differ
+/// from every region id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FixtureSuperRegion {
+    pub id: RegionIdx,
+    pub regions: Vec<RegionIdx>,
+}
+
+/// Rows of the node and vertex files of a region.
+pub(crate) struct RegionRecords {
+    pub(crate) nodes: Vec<RawNode>,
+    pub(crate) vertices: Vec<RawVertex>,
+}
+
+/// Summary of a generated data set.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FixtureManifest {
+    pub regions: Vec<RegionIdx>,
+    pub groups: Vec<usize>,
+    pub nodes: usize,
+    pub vertices: usize,
+    #[serde(default)]
+    pub super_regions: BTreeMap<RegionIdx, Vec<RegionIdx>>,
+}
+
+impl FixtureDefinition {
+    pub fn from_toml(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    fn regions(&self) -> BTreeSet<RegionIdx> {
+        self.nodes.iter().map(|node| node.region).collect()
+    }
+
+    fn super_regions(&self) -> BTreeMap<RegionIdx, Vec<RegionIdx>> {
+        self.super_regions.iter().map(|super_region| (super_region.id, super_region.regions.clone())).collect()
+    }
+
+    /// Regions each region bit leads into: every region on its own, and the regions of every super-region.
+    fn bit_targets(&self) -> Result<BTreeMap<RegionIdx, BTreeSet<RegionIdx>>> {
+        let mut targets: BTreeMap<RegionIdx, BTreeSet<RegionIdx>> = self.regions().into_iter().map(|region| (region, BTreeSet::from([region]))).collect();
+        for super_region in self.super_regions.iter() {
+            if targets.insert(super_region.id, super_region.regions.iter().copied().collect()).is_some() {
+                Err(format!("Super-region {} shares its id with a region or another super-region", super_region.id))?
+            }
+        }
+        Ok(targets)
+    }
+
+    /// Cheapest cost from every node to the closest node of `region`.
+    /// Edge weights are compared in fixed point, fractional ones with this precision.
+    fn fixed_weight(edge: &FixtureEdge) -> u64 {
+        WeightScale(1_000_000).to_fixed(edge.weight).expect("Edge weights must be non-negative")
+    }
+
+    fn distances_to_regions(&self, regions: &BTreeSet<RegionIdx>) -> HashMap<NodeIdx, u64> {
+        let mut neighbours: HashMap<NodeIdx, Vec<(NodeIdx, u64)>> = HashMap::new();
+        for edge in self.edges.iter() {
+            neighbours.entry(edge.a).or_default().push((edge.b, Self::fixed_weight(edge)));
+            neighbours.entry(edge.b).or_default().push((edge.a, Self::fixed_weight(edge)));
+        }
+        let mut distances = HashMap::new();
+        let mut queue: BinaryHeap<Reverse<(u64, NodeIdx)>> = self.nodes.iter()
+            .filter(|node| regions.contains(&node.region))
+            .map(|node| Reverse((0, node.id)))
+            .collect();
+        while let Some(Reverse((cost, node))) = queue.pop() {
+            if distances.contains_key(&node) {
+                continue;
+            }
+            distances.insert(node, cost);
+            for (next, weight) in neighbours.get(&node).into_iter().flatten() {
+                if !distances.contains_key(next) {
+                    queue.push(Reverse((cost + weight, *next)));
+                }
+            }
+        }
+        distances
+    }
+
+    /// Bit `r` of an edge is set when the edge lies inside region `r` or on a shortest path into it,
+    /// likewise for the regions of super-region `r`.
+    fn region_bits(&self) -> Result<Vec<String>> {
+        let node_regions: HashMap<NodeIdx, RegionIdx> = self.nodes.iter().map(|node| (node.id, node.region)).collect();
+        let bit_targets = self.bit_targets()?;
+        let region_count = bit_targets.keys().max().map_or(0, |max| *max as usize + 1);
+        let mut bits = vec![vec!['0'; region_count]; self.edges.len()];
+        for (bit, regions) in bit_targets.iter() {
+            let distances = self.distances_to_regions(regions);
+            let inside = |node: &NodeIdx| node_regions.get(node).is_some_and(|region| regions.contains(region));
+            for (edge, edge_bits) in self.edges.iter().zip(bits.iter_mut()) {
+                if inside(&edge.a) && inside(&edge.b) {
+                    edge_bits[*bit as usize] = '1';
+                } else if let (Some(a), Some(b)) = (distances.get(&edge.a), distances.get(&edge.b)) {
+                    let weight = Self::fixed_weight(edge);
+                    if *a == b + weight || *b == a + weight {
+                        edge_bits[*bit as usize] = '1';
+                    }
+                }
+            }
+        }
+        Ok(bits.into_iter().map(|edge_bits| edge_bits.into_iter().collect()).collect())
+    }
+
+    /// Records of every region file: every edge touching the region, and both ends of those edges.
+    pub(crate) fn region_records(&self) -> Result<BTreeMap<RegionIdx, RegionRecords>> {
+        let nodes: BTreeMap<NodeIdx, &FixtureNode> = self.nodes.iter().map(|node| (node.id, node)).collect();
+        let region_bits = self.region_bits()?;
+        let mut records = BTreeMap::new();
+        for region in self.regions() {
+            let mut region_nodes = BTreeSet::new();
+            let mut vertices = vec![];
+            for (idx, (edge, bits)) in self.edges.iter().zip(region_bits.iter()).enumerate() {
+                let (a, b) = (nodes.get(&edge.a).ok_or(format!("Unknown node {}", edge.a))?, nodes.get(&edge.b).ok_or(format!("Unknown node {}", edge.b))?);
+                if a.region != region && b.region != region {
+                    continue;
+                }
+                region_nodes.insert(a.id);
+                region_nodes.insert(b.id);
+                vertices.push(RawVertex {
+                    id: edge.id.unwrap_or(idx),
+                    a: edge.a,
+                    b: edge.b,
+                    weight: edge.weight,
+                    region_bits: bits.clone(),
+                    access: edge.access.as_ref().map(|profiles| {
+                        profiles.iter().map(|profile| profile.to_string()).collect::<Vec<_>>().join("|")
+                    }),
+                });
+            }
+
+            region_nodes.extend(self.nodes.iter().filter(|node| node.region == region).map(|node| node.id));
+            let nodes = region_nodes.into_iter().map(|node_idx| {
+                let node = nodes[&node_idx];
+                RawNode {
+                    id: node.id,
+                    cord_x: node.x,
+                    cord_y: node.y,
+                    region: node.region,
+                }
+            }).collect();
+            records.insert(region, RegionRecords { nodes, vertices });
+        }
+        Ok(records)
+    }
+
+    pub(crate) fn group_infos(&self) -> Vec<GroupInfo> {
+        self.groups.iter().map(|group| GroupInfo {
+            group_id: group.id,
+            regions: group.regions.clone(),
+            super_regions: self.super_regions(),
+        }).collect()
+    }
+
+    /// Writes the layout read by the mock provider: `nodes/nodes_{region}.csv`,
+    /// `vertices/vertices_{region}.csv`, `groups/group_{id}.json` and `manifest.json`.
+    pub fn generate(&self, dir: &Path) -> Result<FixtureManifest> {
+        std::fs::create_dir_all(dir.join("nodes"))?;
+        std::fs::create_dir_all(dir.join("vertices"))?;
+        std::fs::create_dir_all(dir.join("groups"))?;
+
+        for (region, records) in self.region_records()? {
+            let mut vertices_writer = csv::WriterBuilder::new().has_headers(false).from_path(dir.join(format!("vertices/vertices_{}.csv", region)))?;
+            for vertex in records.vertices {
+                vertices_writer.serialize(vertex)?;
+            }
+            vertices_writer.flush()?;
+            let mut nodes_writer = csv::WriterBuilder::new().has_headers(false).from_path(dir.join(format!("nodes/nodes_{}.csv", region)))?;
+            for node in records.nodes {
+                nodes_writer.serialize(node)?;
+            }
+            nodes_writer.flush()?;
+        }
+
+        for info in self.group_infos() {
+            std::fs::write(dir.join(format!("groups/group_{}.json", info.group_id)), serde_json::to_vec_pretty(&info)?)?;
+        }
+
+        let manifest = FixtureManifest {
+            regions: self.regions().into_iter().collect(),
+            groups: self.groups.iter().map(|group| group.id).collect(),
+            nodes: self.nodes.len(),
+            vertices: self.edges.len(),
+            super_regions: self.super_regions(),
+        };
+        std::fs::write(dir.join("manifest.json"), serde_json::to_vec_pretty(&manifest)?)?;
+        Ok(manifest)
+    }
+}
+
+/// The data set described in `res/fixtures/{name}.toml`.
+#[cfg(test)]
+pub(crate) fn sample(name: &str) -> FixtureDefinition {
+    let definition_path = Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("res/fixtures/{}.toml", name));
+    FixtureDefinition::from_toml(&std::fs::read_to_string(definition_path).unwrap()).unwrap()
+}
+
+/// Generates the data set described in `res/fixtures/{name}.toml` into a fresh temporary directory.
+#[cfg(test)]
+pub(crate) fn generate_sample(name: &str) -> TempDir {
+    let dir = TempDir::new(name);
+    sample(name).generate(dir.path()).unwrap();
+    dir
+}
+
+/// Temporary directory of a test, removed with everything in it when dropped.
+#[cfg(test)]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// A fresh directory path named after `name`, created by whoever writes into it first.
+    pub(crate) fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("pathfinder-{}-{}", name, uuid::Uuid::new_v4())))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fixtures::{generate_sample, FixtureManifest};
+
+    #[test]
+    fn generates_layout() {
+        let dir = generate_sample("two_regions");
+        let manifest: FixtureManifest = serde_json::from_slice(&std::fs::read(dir.path().join("manifest.json")).unwrap()).unwrap();
+        assert_eq!(manifest.regions, vec![1, 2]);
+        let vertices = std::fs::read_to_string(dir.path().join("vertices/vertices_1.csv")).unwrap();
+        // The edge between the regions leads into both of them, the costly shortcut into neither.
+        assert!(vertices.contains("2,2,3,4.0,011"));
+        assert!(vertices.contains("4,1,4,20.0,000,car"));
+        let vertices = std::fs::read_to_string(dir.path().join("vertices/vertices_2.csv")).unwrap();
+        assert!(vertices.contains("3,3,4,1.5,011"));
+        assert!(!vertices.contains("1,1,2,1"));
+        assert!(dir.path().join("groups/group_2.json").exists());
+    }
+}
@@ -0,0 +1,240 @@
+//! Credentials of the Google Cloud Storage provider. Buckets are read through the S3 compatible API
+//! of GCS, either with HMAC keys signing every request like S3 does, or with OAuth access tokens of
+//! Application Default Credentials: the key file GOOGLE_APPLICATION_CREDENTIALS points to, the one
+//! written by `gcloud auth application-default login`, or the metadata server, which hands out the
+//! tokens of the service account of a VM or of a GKE workload (workload identity).
+
+use std::env;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::Deserialize;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Read and written, snapshots and uploaded regions are written to the bucket.
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+const DEFAULT_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+/// Tokens are renewed this long before they expire, so none expires during a download.
+const RENEW_BEFORE: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GcsCredentials {
+    /// HMAC keys of the interoperability API.
+    Hmac {
+        access_key: String,
+        secret_key: String,
+    },
+    /// Key file of a user or a service account.
+    KeyFile(PathBuf),
+    /// Tokens of the service account the server runs as, from the metadata server at `host`.
+    Metadata {
+        host: String,
+    },
+}
+
+impl GcsCredentials {
+    /// GOOGLE_APPLICATION_CREDENTIALS if set, else the HMAC keys GOOGLE_ACCESS_KEY and
+    /// GOOGLE_SECRET_KEY if set, else the key file of `gcloud auth application-default login` if
+    /// there is one, else the metadata server (GCE_METADATA_HOST, default `metadata.google.internal`).
+    pub fn from_env() -> Result<Self> {
+        if let Ok(path) = env::var("GOOGLE_APPLICATION_CREDENTIALS") {
+            return Ok(GcsCredentials::KeyFile(PathBuf::from(path)));
+        }
+        match (env::var("GOOGLE_ACCESS_KEY"), env::var("GOOGLE_SECRET_KEY")) {
+            (Ok(access_key), Ok(secret_key)) => { return Ok(GcsCredentials::Hmac { access_key, secret_key }) }
+            (Err(_), Err(_)) => {}
+            _ => { Err("GOOGLE_ACCESS_KEY and GOOGLE_SECRET_KEY have to be set together")? }
+        }
+        if let Some(path) = env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/gcloud/application_default_credentials.json")) {
+            if path.exists() {
+                return Ok(GcsCredentials::KeyFile(path));
+            }
+        }
+        Ok(GcsCredentials::Metadata {
+            host: env::var("GCE_METADATA_HOST").unwrap_or_else(|_| "metadata.google.internal".to_string()),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum KeyFile {
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+    },
+    ServiceAccount {
+        client_email: String,
+        private_key: String,
+        #[serde(default = "default_token_uri")]
+        token_uri: String,
+    },
+}
+
+fn default_token_uri() -> String {
+    DEFAULT_TOKEN_URI.to_string()
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+struct AccessToken {
+    token: String,
+    renew_at: Instant,
+}
+
+/// Access tokens of key file or metadata server credentials, fetched when first needed and kept
+/// until shortly before they expire.
+pub(crate) struct AccessTokens {
+    credentials: GcsCredentials,
+    client: reqwest::Client,
+    current: tokio::sync::Mutex<Option<AccessToken>>,
+}
+
+impl AccessTokens {
+    pub(crate) fn new(credentials: GcsCredentials) -> Self {
+        Self {
+            credentials,
+            client: reqwest::Client::new(),
+            current: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    pub(crate) async fn token(&self) -> Result<String> {
+        let mut current = self.current.lock().await;
+        if let Some(token) = current.as_ref().filter(|token| token.renew_at > Instant::now()) {
+            return Ok(token.token.clone());
+        }
+        let response = self.fetch().await?;
+        log::debug!("Fetched an access token valid for {}s", response.expires_in);
+        *current = Some(AccessToken {
+            token: response.access_token.clone(),
+            renew_at: Instant::now() + Duration::from_secs(response.expires_in).saturating_sub(RENEW_BEFORE),
+        });
+        Ok(response.access_token)
+    }
+
+    async fn fetch(&self) -> Result<TokenResponse> {
+        let request = match &self.credentials {
+            GcsCredentials::Hmac { .. } => { Err("HMAC keys sign requests, they have no access tokens")? }
+            GcsCredentials::Metadata { host } => {
+                self.client.get(format!("http://{}/computeMetadata/v1/instance/service-accounts/default/token", host))
+                    .header("Metadata-Flavor", "Google")
+            }
+            GcsCredentials::KeyFile(path) => {
+                let content = tokio::fs::read(path).await.map_err(|err| format!("Unable to read {}, details: {}", path.display(), err))?;
+                let key_file = serde_json::from_slice(&content)
+                    .map_err(|err| format!("{} is neither a user nor a service account key file, details: {}", path.display(), err))?;
+                match key_file {
+                    KeyFile::AuthorizedUser { client_id, client_secret, refresh_token } => {
+                        self.client.post(DEFAULT_TOKEN_URI).form(&[
+                            ("grant_type", "refresh_token"),
+                            ("client_id", &client_id),
+                            ("client_secret", &client_secret),
+                            ("refresh_token", &refresh_token),
+                        ])
+                    }
+                    KeyFile::ServiceAccount { client_email, private_key, token_uri } => {
+                        let assertion = assertion(&client_email, &private_key, &token_uri)?;
+                        self.client.post(&token_uri).form(&[
+                            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                            ("assertion", &assertion),
+                        ])
+                    }
+                }
+            }
+        };
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("Fetching an access token failed with status {}: {}", status, response.text().await.unwrap_or_default()).into());
+        }
+        Ok(response.json().await?)
+    }
+}
+
+fn base64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// JWT signed with the key of a service account, exchanged for an access token at `token_uri`.
+fn assertion(client_email: &str, private_key: &str, token_uri: &str) -> Result<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let header = base64url(br#"{"alg":"RS256","typ":"JWT"}"#);
+    let claims = base64url(&serde_json::to_vec(&serde_json::json!({
+        "iss": client_email,
+        "scope": SCOPE,
+        "aud": token_uri,
+        "iat": now,
+        "exp": now + 3600,
+    }))?);
+    let key = PKey::private_key_from_pem(private_key.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(format!("{}.{}", header, claims).as_bytes())?;
+    Ok(format!("{}.{}.{}", header, claims, base64url(&signer.sign_to_vec()?)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Verifier;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::gcs_auth::{assertion, AccessTokens, GcsCredentials};
+
+    #[test]
+    fn assertions_are_signed_with_the_service_account_key() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let pem = String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let jwt = assertion("loader@project.iam.gserviceaccount.com", &pem, "https://oauth2.googleapis.com/token").unwrap();
+        let parts: Vec<_> = jwt.split('.').collect();
+        let claims: serde_json::Value = serde_json::from_slice(&base64::decode_config(parts[1], base64::URL_SAFE_NO_PAD).unwrap()).unwrap();
+        assert_eq!(claims["iss"], "loader@project.iam.gserviceaccount.com");
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key).unwrap();
+        verifier.update(format!("{}.{}", parts[0], parts[1]).as_bytes()).unwrap();
+        assert!(verifier.verify(&base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD).unwrap()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn metadata_tokens_are_kept_until_they_expire() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = requests.clone();
+        tokio::task::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut raw = vec![];
+                let mut buf = [0; 1024];
+                while !raw.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let read = socket.read(&mut buf).await.unwrap();
+                    raw.extend_from_slice(&buf[..read]);
+                }
+                assert!(String::from_utf8(raw).unwrap().to_lowercase().contains("metadata-flavor: google"));
+                // Expiring within the renewal margin the second time, so the third request fetches again.
+                let expires_in = if served.fetch_add(1, Ordering::SeqCst) == 0 { 3600 } else { 60 };
+                let body = format!(r#"{{"access_token":"token-{}","expires_in":{},"token_type":"Bearer"}}"#, served.load(Ordering::SeqCst), expires_in);
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let tokens = AccessTokens::new(GcsCredentials::Metadata { host });
+        assert_eq!(tokens.token().await.unwrap(), "token-1");
+        assert_eq!(tokens.token().await.unwrap(), "token-1");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        *tokens.current.lock().await = None;
+        assert_eq!(tokens.token().await.unwrap(), "token-2");
+        assert_eq!(tokens.token().await.unwrap(), "token-3");
+    }
+}
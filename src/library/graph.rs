@@ -1,34 +1,135 @@
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Formatter;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use bitvec::vec::BitVec;
 use priority_queue::PriorityQueue;
 use serde::{Serialize, Deserialize};
+#[cfg(feature = "bucket-queue")]
+use crate::bucket_queue::BucketQueue;
 use crate::domain::{NodeInfo, PathPoint};
-use crate::PathResult::Continue;
+use crate::heuristic::Heuristic;
+use crate::mapped::{self, MappedGraph};
 
 pub type RegionIdx = u32;
 pub type VertexIdx = usize;
 pub type NodeIdx = usize;
 
+/// Searches hand control back to the runtime after this many node expansions, so a long search
+/// doesn't hold up the other tasks of its thread and can be cancelled or timed out midway.
+const YIELD_INTERVAL: usize = 256;
+
+/// Graphs whose edges are at most this heavy are searched with a bucket queue.
+#[cfg(feature = "bucket-queue")]
+const BUCKET_QUEUE_MAX_WEIGHT: u64 = 4096;
+
 #[derive(Debug, Clone)]
-pub(crate) enum GraphError {
+pub enum GraphError {
     StartNodeNotFound(NodeIdx, RegionIdx),
     VertexNotFound(VertexIdx, RegionIdx),
     Unreachable(NodeIdx, RegionIdx),
+    BudgetExceeded(RegionIdx),
+    TimedOut(RegionIdx),
+    NodeNotFound(NodeIdx, RegionIdx),
+    NodeExists(NodeIdx, RegionIdx),
+    VertexExists(VertexIdx, RegionIdx),
 }
 
 impl std::fmt::Display for GraphError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        return match self {
+        match self {
             GraphError::StartNodeNotFound(node_id, region_id) => { write!(f, "Starting node {} cannot be found in region {}", node_id, region_id) }
             GraphError::VertexNotFound(vertex_id, region_id) => { write!(f, "Vertex {} cannot be found in region {}", vertex_id, region_id) }
             GraphError::Unreachable(vertex_id, region_id) => { write!(f, "Vertex {} cannot reached in region {}", vertex_id, region_id) }
-        };
+            GraphError::BudgetExceeded(region_id) => { write!(f, "Search in region {} exceeded its memory limits", region_id) }
+            GraphError::TimedOut(region_id) => { write!(f, "Search in region {} ran out of time", region_id) }
+            GraphError::NodeNotFound(node_id, region_id) => { write!(f, "Node {} cannot be found in region {}", node_id, region_id) }
+            GraphError::NodeExists(node_id, region_id) => { write!(f, "Node {} already exists in region {}", node_id, region_id) }
+            GraphError::VertexExists(vertex_id, region_id) => { write!(f, "Vertex {} already exists or existed in region {}", vertex_id, region_id) }
+        }
     }
 }
 
 impl std::error::Error for GraphError {}
 
+/// Transport mode a route is searched for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Car,
+    Bike,
+    Foot,
+    Truck,
+}
+
+impl Profile {
+    fn bit(&self) -> u8 {
+        match self {
+            Profile::Car => { 1 }
+            Profile::Bike => { 2 }
+            Profile::Foot => { 4 }
+            Profile::Truck => { 8 }
+        }
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Profile::Car => { write!(f, "car") }
+            Profile::Bike => { write!(f, "bike") }
+            Profile::Foot => { write!(f, "foot") }
+            Profile::Truck => { write!(f, "truck") }
+        }
+    }
+}
+
+impl std::str::FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "car" => { Ok(Profile::Car) }
+            "bike" => { Ok(Profile::Bike) }
+            "foot" => { Ok(Profile::Foot) }
+            "truck" => { Ok(Profile::Truck) }
+            other => { Err(format!("Unknown transport mode {}", other)) }
+        }
+    }
+}
+
+/// Set of transport modes allowed to use an edge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Access(u8);
+
+impl Access {
+    pub const ALL: Access = Access(0b1111);
+
+    pub fn new(profiles: impl IntoIterator<Item=Profile>) -> Self {
+        Access(profiles.into_iter().fold(0, |mask, profile| mask | profile.bit()))
+    }
+
+    pub fn permits(&self, profile: Profile) -> bool {
+        self.0 & profile.bit() != 0
+    }
+
+    pub(crate) fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub(crate) fn from_bits(bits: u8) -> Self {
+        Access(bits & Access::ALL.0)
+    }
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access::ALL
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Vertex {
     pub(crate) a: NodeIdx,
@@ -36,6 +137,152 @@ pub struct Vertex {
     pub(crate) weight: u64,
     pub(crate) id: VertexIdx,
     pub(crate) region_bits: BitVec, // todo implement! (or check)
+    #[serde(default)]
+    pub(crate) access: Access,
+}
+
+/// Region bits of an edge, owned by an in-memory vertex or packed into 64 bit words in a mapped region.
+/// Copies are only made for edges handed out of a graph's patches.
+#[derive(Debug, Clone)]
+pub enum RegionBits<'a> {
+    Memory(&'a BitVec),
+    Packed { len: usize, words: &'a [u8] },
+    Owned(BitVec),
+}
+
+impl RegionBits<'_> {
+    pub fn len(&self) -> usize {
+        match self {
+            RegionBits::Memory(bits) => { bits.len() }
+            RegionBits::Packed { len, .. } => { *len }
+            RegionBits::Owned(bits) => { bits.len() }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, idx: usize) -> Option<bool> {
+        match self {
+            RegionBits::Memory(bits) => { bits.get(idx).map(|bit| *bit) }
+            RegionBits::Owned(bits) => { bits.get(idx).map(|bit| *bit) }
+            RegionBits::Packed { len, words } => {
+                if idx >= *len {
+                    return None;
+                }
+                let word = u64::from_le_bytes(words[idx / 64 * 8..idx / 64 * 8 + 8].try_into().unwrap());
+                Some(word & (1 << (idx % 64)) != 0)
+            }
+        }
+    }
+}
+
+/// Vertex as seen by searches, read from either kind of region storage without copying its region bits.
+#[derive(Debug, Clone)]
+pub struct Edge<'a> {
+    pub(crate) id: VertexIdx,
+    pub(crate) a: NodeIdx,
+    pub(crate) b: NodeIdx,
+    pub(crate) weight: u64,
+    pub(crate) access: Access,
+    pub(crate) region_bits: RegionBits<'a>,
+}
+
+impl<'a> From<&'a Vertex> for Edge<'a> {
+    fn from(vertex: &'a Vertex) -> Self {
+        Self {
+            id: vertex.id,
+            a: vertex.a,
+            b: vertex.b,
+            weight: vertex.weight,
+            access: vertex.access,
+            region_bits: RegionBits::Memory(&vertex.region_bits),
+        }
+    }
+}
+
+impl From<Edge<'_>> for Vertex {
+    fn from(edge: Edge<'_>) -> Self {
+        Self {
+            a: edge.a,
+            b: edge.b,
+            weight: edge.weight,
+            id: edge.id,
+            region_bits: (0..edge.region_bits.len()).map(|idx| edge.region_bits.get(idx) == Some(true)).collect(),
+            access: edge.access,
+        }
+    }
+}
+
+impl Edge<'_> {
+    fn into_owned(self) -> Edge<'static> {
+        let region_bits = match self.region_bits {
+            RegionBits::Owned(bits) => { bits }
+            borrowed => { (0..borrowed.len()).map(|idx| borrowed.get(idx) == Some(true)).collect() }
+        };
+        Edge {
+            id: self.id,
+            a: self.a,
+            b: self.b,
+            weight: self.weight,
+            access: self.access,
+            region_bits: RegionBits::Owned(region_bits),
+        }
+    }
+
+    fn get_neighbour(&self, a: NodeIdx) -> NodeIdx {
+        if a == self.a {
+            self.b
+        } else if a == self.b {
+            self.a
+        } else {
+            panic!("Invalid vertex chosen"); //todo
+        }
+    }
+
+    /// Whether the edge is on a cheapest way towards `region`.
+    fn leads_to(&self, region: RegionIdx) -> bool {
+        self.region_bits.get(region as usize).unwrap_or(false)
+    }
+}
+
+/// Second partition level, super-regions grouping regions. Their ids index region bits like region
+/// ids, so a request heading into another super-region follows the bit of that super-region until
+/// it arrives there, and the bit of its target region from then on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SuperRegions {
+    of_region: HashMap<RegionIdx, RegionIdx>,
+}
+
+impl SuperRegions {
+    /// From the regions of every super-region, which must not overlap nor share ids with regions.
+    pub fn new(super_regions: &BTreeMap<RegionIdx, Vec<RegionIdx>>) -> Result<Self, String> {
+        let mut of_region = HashMap::new();
+        for (super_region, regions) in super_regions.iter() {
+            for region in regions {
+                if let Some(other) = of_region.insert(*region, *super_region) {
+                    return Err(format!("Region {} belongs to super-regions {} and {}", region, other, super_region));
+                }
+            }
+        }
+        if let Some(super_region) = super_regions.keys().find(|super_region| of_region.contains_key(super_region)) {
+            return Err(format!("Super-region {} shares its id with a region", super_region));
+        }
+        Ok(Self { of_region })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.of_region.is_empty()
+    }
+
+    /// Region bit leading from `region` towards `target`.
+    pub fn towards(&self, region: RegionIdx, target: RegionIdx) -> RegionIdx {
+        match (self.of_region.get(&region), self.of_region.get(&target)) {
+            (Some(from), Some(to)) if from != to => { *to }
+            _ => { target }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -48,20 +295,140 @@ pub struct Node {
 }
 
 #[derive(Debug, Clone)]
-pub struct Graph {
-    pub(crate) nodes: HashMap<NodeIdx, Node>,
+enum Storage {
+    Memory {
+        nodes: HashMap<NodeIdx, Node>,
+        vertices: HashMap<VertexIdx, Vertex>,
+    },
+    Mapped(Arc<MappedGraph>),
+}
+
+/// Changes made since the region was loaded: live weights and topology patches. Replaced as a
+/// whole on every change, so a search keeps using the snapshot it started with.
+#[derive(Debug, Clone, Default)]
+struct Patches {
+    weights: HashMap<VertexIdx, u64>,
+    /// Heaviest edge of the region with these patches applied.
+    max_weight: u64,
+    /// Inserted nodes, their connections are kept in `connections`.
+    nodes: HashMap<NodeIdx, Node>,
     vertices: HashMap<VertexIdx, Vertex>,
+    /// Loaded vertices which were removed. Their ids are not reused.
+    removed: HashSet<VertexIdx>,
+    /// Inserted vertices by the nodes they connect.
+    connections: HashMap<NodeIdx, Vec<VertexIdx>>,
+}
+
+impl Patches {
+    fn weight(&self, vertex: &Edge) -> u64 {
+        if self.weights.is_empty() {
+            return vertex.weight;
+        }
+        self.weights.get(&vertex.id).copied().unwrap_or(vertex.weight)
+    }
+
+    fn changes_topology(&self) -> bool {
+        !self.nodes.is_empty() || !self.vertices.is_empty() || !self.removed.is_empty()
+    }
+}
+
+/// Graph as seen through one snapshot of its patches.
+struct View<'a> {
+    graph: &'a Graph,
+    patches: &'a Patches,
+}
+
+impl<'a> View<'a> {
+    fn get_node(&self, idx: NodeIdx) -> Option<Cow<'a, Node>> {
+        match self.patches.nodes.get(&idx) {
+            Some(node) => { Some(Cow::Borrowed(node)) }
+            None => { self.graph.loaded_node(idx) }
+        }
+    }
+
+    fn contains_node(&self, idx: NodeIdx) -> bool {
+        self.get_node(idx).is_some()
+    }
+
+    fn connections(&self, idx: NodeIdx) -> impl Iterator<Item=VertexIdx> + 'a {
+        let patches = self.patches;
+        self.graph.loaded_connections(idx)
+            .filter(move |vertex_id| !patches.removed.contains(vertex_id))
+            .chain(patches.connections.get(&idx).into_iter().flatten().copied())
+    }
+
+    fn get_vertex(&self, idx: VertexIdx) -> Option<Edge<'a>> {
+        if let Some(vertex) = self.patches.vertices.get(&idx) {
+            return Some(Edge::from(vertex));
+        }
+        if self.patches.removed.contains(&idx) {
+            return None;
+        }
+        self.graph.loaded_vertex(idx)
+    }
+
+    /// Current weight of an edge, including live updates.
+    fn weight(&self, vertex: &Edge) -> u64 {
+        self.patches.weight(vertex)
+    }
+
+    fn reconstruct_path(&self, parents: &HashMap<NodeIdx, NodeIdx>, end: NodeIdx) -> Vec<PathPoint> {
+        let mut path = vec![];
+        let mut current = Some(end);
+        while let Some(node_idx) = current {
+            path.push(PathPoint::from(&*self.get_node(node_idx).expect("Reached nodes are part of the graph")));
+            current = parents.get(&node_idx).copied();
+        }
+        path.reverse();
+        path
+    }
+}
+
+/// Cheapest way from a boundary node to one edge leaving the region.
+#[derive(Debug, Clone)]
+struct Shortcut {
+    vertex: VertexIdx,
+    /// Node on the other side of `vertex`.
+    next: NodeIdx,
+    /// From the boundary node to the last node inside the region.
+    path: Box<[NodeIdx]>,
+    /// Including `vertex`.
+    cost: u64,
+}
+
+/// Cheapest ways between all boundary nodes of a region and the edges leaving it, computed when the
+/// region is loaded. They stand in for searching a region that requests merely cross.
+#[derive(Debug, Default)]
+struct Shortcuts {
+    exits: HashMap<NodeIdx, Vec<Shortcut>>,
+}
+
+/// Clones share their patches.
+#[derive(Debug, Clone)]
+pub struct Graph {
+    storage: Storage,
     pub(crate) region_idx: RegionIdx,
+    max_weight: u64,
+    patches: Arc<RwLock<Arc<Patches>>>,
+    shortcuts: Option<Arc<Shortcuts>>,
+    super_regions: Option<Arc<SuperRegions>>,
 }
 
-impl Vertex {
-    fn get_neighbour(&self, a: NodeIdx) -> NodeIdx {
-        if a == self.a {
-            self.b
-        } else if a == self.b {
-            self.a
-        } else {
-            panic!("Invalid vertex chosen"); //todo
+/// Vertices connected to a node.
+pub(crate) enum Connections<'a> {
+    Memory(std::slice::Iter<'a, VertexIdx>),
+    Mapped(mapped::Connections<'a>),
+    Patched(std::vec::IntoIter<VertexIdx>),
+}
+
+impl Iterator for Connections<'_> {
+    type Item = VertexIdx;
+
+    fn next(&mut self) -> Option<VertexIdx> {
+        match self {
+            Connections::Memory(iter) => { iter.next().copied() }
+            Connections::Mapped(iter) => { iter.next() }
+            Connections::Patched(iter) => { iter.next() }
         }
     }
 }
@@ -80,15 +447,148 @@ impl Node {
             cord_y,
         }
     }
+
+    pub fn id(&self) -> NodeIdx {
+        self.id
+    }
+
+    pub fn region(&self) -> RegionIdx {
+        self.region
+    }
+
+    pub fn coordinates(&self) -> (u64, u64) {
+        (self.cord_x, self.cord_y)
+    }
 }
 
-pub(crate) enum Continuation {
+/// Fixed-point representation of fractional costs: every cost unit of the input data is stored as
+/// this many integer units, so searches keep working on exact `u64` arithmetic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WeightScale(pub u64);
+
+impl WeightScale {
+    pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        match std::env::var("WEIGHT_SCALE") {
+            Ok(scale) => { Self::parse(&scale) }
+            Err(_) => { Ok(WeightScale::default()) }
+        }
+    }
+
+    /// Units per cost unit, at least one.
+    fn parse(scale: &str) -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        match scale.parse::<u64>()? {
+            0 => { Err("WEIGHT_SCALE must be at least 1")? }
+            scale => { Ok(WeightScale(scale)) }
+        }
+    }
+
+    /// Rounds `cost` to the nearest representable value, `None` if it is negative or not finite.
+    pub fn to_fixed(&self, cost: f64) -> Option<u64> {
+        if !cost.is_finite() || cost < 0. {
+            return None;
+        }
+        Some((cost * self.0 as f64).round() as u64)
+    }
+
+    pub fn to_float(&self, cost: u64) -> f64 {
+        cost as f64 / self.0 as f64
+    }
+}
+
+impl Default for WeightScale {
+    fn default() -> Self {
+        WeightScale(1)
+    }
+}
+
+/// Area of a region to load, in node coordinates, bounds included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub min_x: u64,
+    pub min_y: u64,
+    pub max_x: u64,
+    pub max_y: u64,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, cord_x: u64, cord_y: u64) -> bool {
+        (self.min_x..=self.max_x).contains(&cord_x) && (self.min_y..=self.max_y).contains(&cord_y)
+    }
+}
+
+impl std::str::FromStr for BoundingBox {
+    type Err = String;
+
+    /// Reads `min_x,min_y,max_x,max_y`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bounds = s.split(',').map(|bound| bound.trim().parse::<u64>()).collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("Invalid bounding box {}, details: {}", s, err))?;
+        match bounds[..] {
+            [min_x, min_y, max_x, max_y] if min_x <= max_x && min_y <= max_y => { Ok(BoundingBox { min_x, min_y, max_x, max_y }) }
+            _ => { Err(format!("Invalid bounding box {}, expected min_x,min_y,max_x,max_y", s)) }
+        }
+    }
+}
+
+/// Parts of the graph a search must not pass through, e.g. closed roads.
+#[derive(Debug, Clone, Default)]
+pub struct Avoid {
+    pub nodes: HashSet<NodeIdx>,
+    pub vertices: HashSet<VertexIdx>,
+    pub regions: HashSet<RegionIdx>,
+    /// Edges closed to this transport mode are avoided as well.
+    pub profile: Option<Profile>,
+}
+
+impl Avoid {
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.vertices.is_empty() && self.regions.is_empty() && self.profile.is_none()
+    }
+
+    /// Whether a search may not start or end at `node`. Searches only enter nodes they may follow
+    /// an edge to, so this only rejects the ends of queries.
+    fn excludes(&self, node: &Node) -> bool {
+        self.nodes.contains(&node.id) || self.regions.contains(&node.region)
+    }
+
+    /// Whether `vertex` may be followed to `next`. Regions of nodes outside the graph are unknown here.
+    fn allows(&self, vertex: &Edge, next: NodeIdx, next_node: Option<&Node>) -> bool {
+        !self.vertices.contains(&vertex.id)
+            && self.profile.is_none_or(|profile| vertex.access.permits(profile))
+            && !self.nodes.contains(&next)
+            && next_node.is_none_or(|node| !self.regions.contains(&node.region))
+    }
+}
+
+/// Bounds on the memory a single search may use, so one query cannot exhaust a worker.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SearchLimits {
+    /// Nodes waiting in the frontier at once.
+    pub max_frontier: Option<usize>,
+    /// Nodes with a known cost and predecessor, which is what paths are rebuilt from.
+    pub max_reached: Option<usize>,
+    /// Time a single search may take.
+    pub max_duration: Option<Duration>,
+}
+
+impl SearchLimits {
+    pub fn from_env() -> std::result::Result<Self, std::num::ParseIntError> {
+        let parse = |var| std::env::var(var).ok().map(|limit| limit.parse()).transpose();
+        Ok(Self {
+            max_frontier: parse("SEARCH_MAX_FRONTIER")?,
+            max_reached: parse("SEARCH_MAX_REACHED")?,
+            max_duration: parse("SEARCH_TIMEOUT_MS")?.map(|millis| Duration::from_millis(millis as u64)),
+        })
+    }
+}
+
+pub enum Continuation {
     CRegionKnown(NodeIdx, RegionIdx),
     CRegionUnknown(NodeIdx)
 }
 
 impl Continuation {
-    pub(crate) fn get_node_idx(&self) -> NodeIdx {
+    pub fn get_node_idx(&self) -> NodeIdx {
         match self {
             Continuation::CRegionKnown(idx, _) => {*idx}
             Continuation::CRegionUnknown(idx) => {*idx}
@@ -97,7 +597,7 @@ impl Continuation {
 }
 
 
-pub(crate) enum PathResult {
+pub enum PathResult {
     TargetReached(Vec<PathPoint>, u64),
     Continue(Vec<PathPoint>, u64, Continuation),
 }
@@ -106,89 +606,846 @@ impl Graph {
     pub(crate) fn new(nodes: HashMap<NodeIdx, Node>,
                       vertices: HashMap<VertexIdx, Vertex>,
                       region_idx: RegionIdx) -> Self {
+        let max_weight = vertices.values().map(|vertex| vertex.weight).max().unwrap_or(0);
         Self {
-            nodes,
-            vertices,
+            storage: Storage::Memory { nodes, vertices },
             region_idx,
+            max_weight,
+            patches: Graph::initial_patches(max_weight),
+            shortcuts: None,
+            super_regions: None,
         }
     }
 
-    pub(crate) fn get_node(&self, idx: NodeIdx) -> Option<&Node> {
-        self.nodes.get(&idx)
+    /// Region searched in place in a memory mapped file, see [`crate::graph_provider::mapped`].
+    pub(crate) fn mapped(graph: MappedGraph) -> Self {
+        Self {
+            region_idx: graph.region(),
+            max_weight: graph.max_weight(),
+            patches: Graph::initial_patches(graph.max_weight()),
+            shortcuts: None,
+            super_regions: None,
+            storage: Storage::Mapped(Arc::new(graph)),
+        }
     }
 
-    pub(crate) fn find_way_local(&self, source: NodeInfo,
-                                 target: NodeInfo) -> Result<PathResult, GraphError> {
-        let mut queue: PriorityQueue<(NodeIdx, Vec<PathPoint>), i64> = PriorityQueue::new();
-        let mut visited = HashSet::new();
-        let start_node = self.nodes.get(&source.0).ok_or(GraphError::StartNodeNotFound( source.0, self.region_idx))?;
-        queue.push((start_node.id, vec![PathPoint::from(start_node.clone())]), 0);
+    fn initial_patches(max_weight: u64) -> Arc<RwLock<Arc<Patches>>> {
+        Arc::new(RwLock::new(Arc::new(Patches { max_weight, ..Patches::default() })))
+    }
 
-        while queue.len() > 0 {
-            let ((node_idx, path), mut cost): ((NodeIdx, Vec<PathPoint>), i64) = queue.pop().unwrap();
-            cost *= -1;
-            let node = self.nodes.get(&node_idx).unwrap();
-            if node.id == target.0 {
-                return Ok(PathResult::TargetReached(path, cost as u64));
+    fn patches(&self) -> Arc<Patches> {
+        self.patches.read().unwrap().clone()
+    }
+
+    fn view<'a>(&'a self, patches: &'a Patches) -> View<'a> {
+        View { graph: self, patches }
+    }
+
+    fn loaded_node(&self, idx: NodeIdx) -> Option<Cow<'_, Node>> {
+        match &self.storage {
+            Storage::Memory { nodes, .. } => { nodes.get(&idx).map(Cow::Borrowed) }
+            Storage::Mapped(graph) => { graph.node(idx).map(Cow::Owned) }
+        }
+    }
+
+    fn loaded_connections(&self, idx: NodeIdx) -> Connections<'_> {
+        match &self.storage {
+            Storage::Memory { nodes, .. } => { Connections::Memory(nodes.get(&idx).map_or([].iter(), |node| node.connections.iter())) }
+            Storage::Mapped(graph) => { Connections::Mapped(graph.connections(idx)) }
+        }
+    }
+
+    fn loaded_vertex(&self, idx: VertexIdx) -> Option<Edge<'_>> {
+        match &self.storage {
+            Storage::Memory { vertices, .. } => { vertices.get(&idx).map(Edge::from) }
+            Storage::Mapped(graph) => { graph.vertex(idx) }
+        }
+    }
+
+    /// Nodes of mapped regions are read on access, without their connections.
+    pub fn get_node(&self, idx: NodeIdx) -> Option<Cow<'_, Node>> {
+        match self.patches().nodes.get(&idx) {
+            Some(node) => { Some(Cow::Owned(node.clone())) }
+            None => { self.loaded_node(idx) }
+        }
+    }
+
+    pub fn contains_node(&self, idx: NodeIdx) -> bool {
+        self.get_node(idx).is_some()
+    }
+
+    pub fn nodes(&self) -> Box<dyn Iterator<Item=Cow<'_, Node>> + '_> {
+        let inserted: Vec<Node> = self.patches().nodes.values().cloned().collect();
+        let loaded: Box<dyn Iterator<Item=Cow<'_, Node>> + '_> = match &self.storage {
+            Storage::Memory { nodes, .. } => { Box::new(nodes.values().map(Cow::Borrowed)) }
+            Storage::Mapped(graph) => { Box::new(graph.nodes().map(Cow::Owned)) }
+        };
+        Box::new(loaded.chain(inserted.into_iter().map(Cow::Owned)))
+    }
+
+    pub fn node_count(&self) -> usize {
+        let loaded = match &self.storage {
+            Storage::Memory { nodes, .. } => { nodes.len() }
+            Storage::Mapped(graph) => { graph.node_count() }
+        };
+        loaded + self.patches().nodes.len()
+    }
+
+    pub(crate) fn connections(&self, idx: NodeIdx) -> Connections<'_> {
+        let patches = self.patches();
+        if !patches.changes_topology() {
+            return self.loaded_connections(idx);
+        }
+        let connections: Vec<VertexIdx> = self.view(&patches).connections(idx).collect();
+        Connections::Patched(connections.into_iter())
+    }
+
+    /// Vertices carry the weight they were loaded or inserted with, see [`Graph::current_weight`].
+    pub fn get_vertex(&self, idx: VertexIdx) -> Option<Edge<'_>> {
+        let patches = self.patches();
+        if let Some(vertex) = patches.vertices.get(&idx) {
+            return Some(Edge::from(vertex).into_owned());
+        }
+        if patches.removed.contains(&idx) {
+            return None;
+        }
+        self.loaded_vertex(idx)
+    }
+
+    pub fn vertices(&self) -> Box<dyn Iterator<Item=Edge<'_>> + '_> {
+        let patches = self.patches();
+        let inserted: Vec<Edge<'static>> = patches.vertices.values().map(|vertex| Edge::from(vertex).into_owned()).collect();
+        let loaded: Box<dyn Iterator<Item=Edge<'_>> + '_> = match &self.storage {
+            Storage::Memory { vertices, .. } => { Box::new(vertices.values().map(Edge::from)) }
+            Storage::Mapped(graph) => { Box::new(graph.vertices()) }
+        };
+        Box::new(loaded.filter(move |vertex| !patches.removed.contains(&vertex.id)).chain(inserted))
+    }
+
+    /// Copy of the region holding only its nodes within `bounds` and the edges between them, in
+    /// memory whatever the region is stored in. Patches are applied to the copy, which shares none.
+    pub fn within(&self, bounds: &BoundingBox) -> Graph {
+        let mut nodes: HashMap<NodeIdx, Node> = self.nodes()
+            .filter(|node| bounds.contains(node.cord_x, node.cord_y))
+            .map(|node| (node.id, Node::new(vec![], node.id, node.region, node.cord_x, node.cord_y)))
+            .collect();
+        let vertices: HashMap<VertexIdx, Vertex> = self.vertices()
+            .filter(|vertex| nodes.contains_key(&vertex.a) && nodes.contains_key(&vertex.b))
+            .map(|vertex| (vertex.id, Vertex::from(vertex)))
+            .collect();
+        for vertex in vertices.values() {
+            nodes.get_mut(&vertex.a).unwrap().connections.push(vertex.id);
+            nodes.get_mut(&vertex.b).unwrap().connections.push(vertex.id);
+        }
+        let mut graph = Graph::new(nodes, vertices, self.region_idx);
+        graph.super_regions = self.super_regions.clone();
+        graph
+    }
+
+    /// Routes requests heading into other super-regions by the super-region bits.
+    pub fn set_super_regions(&mut self, super_regions: Arc<SuperRegions>) {
+        self.super_regions = Some(super_regions);
+    }
+
+    /// Region bit leading towards `target`.
+    fn towards(&self, target: RegionIdx) -> RegionIdx {
+        self.super_regions.as_ref().map_or(target, |super_regions| super_regions.towards(self.region_idx, target))
+    }
+
+    /// Heaviest edge as loaded, without patches.
+    pub(crate) fn max_weight(&self) -> u64 {
+        self.max_weight
+    }
+
+    /// Applies a change to a copy of the current patches and publishes it if `change` succeeds.
+    /// Searches already running keep the snapshot they started with.
+    fn patch(&self, change: impl FnOnce(&View, &mut Patches) -> Result<(), GraphError>) -> Result<(), GraphError> {
+        let mut patches = self.patches.write().unwrap();
+        let mut next = Patches::clone(&patches);
+        change(&self.view(&patches), &mut next)?;
+        next.max_weight = next.weights.values().copied()
+            .chain(next.vertices.values().map(|vertex| vertex.weight))
+            .fold(self.max_weight, u64::max);
+        *patches = Arc::new(next);
+        Ok(())
+    }
+
+    /// Sets the weight of a vertex for every search started afterwards. [`Graph::get_vertex`] and
+    /// [`Graph::vertices`] keep returning the weights vertices were loaded or inserted with.
+    pub fn update_weight(&self, vertex_id: VertexIdx, new_weight: u64) -> Result<(), GraphError> {
+        self.update_weights([(vertex_id, new_weight)])
+    }
+
+    /// Applies all updates at once, or none of them if any vertex is unknown.
+    pub fn update_weights(&self, updates: impl IntoIterator<Item=(VertexIdx, u64)>) -> Result<(), GraphError> {
+        self.patch(|view, next| {
+            for (vertex_id, weight) in updates {
+                view.get_vertex(vertex_id).ok_or(GraphError::VertexNotFound(vertex_id, self.region_idx))?;
+                next.weights.insert(vertex_id, weight);
             }
-            for vertex_id in node.connections.iter() {
-                let vertex = self.vertices.get(&vertex_id).ok_or(GraphError::VertexNotFound(*vertex_id, self.region_idx))?;
-                let next = vertex.get_neighbour(node.id);
-                if !visited.contains(&next) {
-                    if let Some(next_node) = self.nodes.get(&next) {
-                        visited.insert(next);
-                        let mut new_path = path.clone();
-                        new_path.push(PathPoint::from(next_node.clone()));
-                        queue.push((next_node.id, new_path), -(cost + vertex.weight as i64));
+            Ok(())
+        })
+    }
+
+    /// Current weight of a vertex, including live updates.
+    pub fn current_weight(&self, vertex_id: VertexIdx) -> Option<u64> {
+        let patches = self.patches();
+        let view = self.view(&patches);
+        let vertex = view.get_vertex(vertex_id)?;
+        Some(view.weight(&vertex))
+    }
+
+    /// Current weight of the cheapest edge between `node` and `neighbour` that `avoid` allows, which
+    /// is how a search crossed it. `neighbour` may be outside the graph, across a boundary.
+    pub(crate) fn crossing_weight(&self, node: NodeIdx, neighbour: NodeIdx, avoid: &Avoid) -> Option<u64> {
+        let patches = self.patches();
+        let view = self.view(&patches);
+        view.connections(node)
+            .filter_map(|vertex_id| view.get_vertex(vertex_id))
+            .filter(|vertex| vertex.get_neighbour(node) == neighbour && avoid.allows(vertex, neighbour, None))
+            .map(|vertex| view.weight(&vertex))
+            .min()
+    }
+
+    /// Adds a node without connections, which [`Graph::insert_vertex`] adds afterwards.
+    pub fn insert_node(&self, node: Node) -> Result<(), GraphError> {
+        self.patch(|view, next| {
+            if view.contains_node(node.id) {
+                return Err(GraphError::NodeExists(node.id, self.region_idx));
+            }
+            next.nodes.insert(node.id, Node { connections: vec![], ..node });
+            Ok(())
+        })
+    }
+
+    /// Adds a vertex connecting at least one node of the graph.
+    pub fn insert_vertex(&self, vertex: Vertex) -> Result<(), GraphError> {
+        self.patch(|view, next| {
+            if view.get_vertex(vertex.id).is_some() || next.removed.contains(&vertex.id) {
+                return Err(GraphError::VertexExists(vertex.id, self.region_idx));
+            }
+            let endpoints: Vec<NodeIdx> = [vertex.a, vertex.b].into_iter().filter(|idx| view.contains_node(*idx)).collect();
+            if endpoints.is_empty() {
+                return Err(GraphError::NodeNotFound(vertex.a, self.region_idx));
+            }
+            for node_idx in endpoints {
+                next.connections.entry(node_idx).or_default().push(vertex.id);
+            }
+            next.vertices.insert(vertex.id, vertex);
+            Ok(())
+        })
+    }
+
+    pub fn remove_vertex(&self, vertex_id: VertexIdx) -> Result<(), GraphError> {
+        self.patch(|view, next| {
+            view.get_vertex(vertex_id).ok_or(GraphError::VertexNotFound(vertex_id, self.region_idx))?;
+            match next.vertices.remove(&vertex_id) {
+                Some(vertex) => {
+                    for node_idx in [vertex.a, vertex.b] {
+                        if let Some(connections) = next.connections.get_mut(&node_idx) {
+                            connections.retain(|idx| *idx != vertex_id);
+                        }
                     }
                 }
+                None => { next.removed.insert(vertex_id); }
             }
+            next.weights.remove(&vertex_id);
+            Ok(())
+        })
+    }
+
+    /// Applies the live updates made to `previous`, an older version of the region, as far as they
+    /// still fit this one: inserted nodes and vertices it doesn't have, removals and weights of
+    /// vertices it has. Returns how many updates didn't fit and were dropped.
+    pub(crate) fn carry_patches(&self, previous: &Graph) -> usize {
+        let patches = previous.patches();
+        let nodes = patches.nodes.values().map(|node| self.insert_node(node.clone()));
+        let vertices = patches.vertices.values().map(|vertex| self.insert_vertex(vertex.clone()));
+        let removed = patches.removed.iter().map(|vertex_id| self.remove_vertex(*vertex_id));
+        let weights = patches.weights.iter().map(|(vertex_id, weight)| self.update_weight(*vertex_id, *weight));
+        nodes.chain(vertices).chain(removed).chain(weights).filter(Result::is_err).count()
+    }
+
+    /// Searches for the cheapest path to a target within this graph, guided by `heuristic` (A*).
+    pub async fn find_way_local(&self, source: NodeInfo,
+                          target: NodeInfo,
+                          heuristic: &dyn Heuristic,
+                          avoid: &Avoid,
+                          limits: &SearchLimits) -> Result<PathResult, GraphError> {
+        let patches = self.patches();
+        let view = self.view(&patches);
+        let start_node = view.get_node(source.0).ok_or(GraphError::StartNodeNotFound( source.0, self.region_idx))?;
+        let target_node = view.get_node(target.0).ok_or(GraphError::Unreachable(target.0, target.1))?;
+        if avoid.excludes(&start_node) {
+            return Err(GraphError::Unreachable(target.0, target.1));
+        }
+        let mut search = Search::new(start_node.id, patches.max_weight);
+
+        while let Some((node_idx, cost)) = search.pop() {
+            if node_idx == target.0 {
+                return Ok(PathResult::TargetReached(view.reconstruct_path(&search.parents, node_idx), cost));
+            }
+            for vertex_id in view.connections(node_idx) {
+                let vertex = view.get_vertex(vertex_id).ok_or(GraphError::VertexNotFound(vertex_id, self.region_idx))?;
+                let next = vertex.get_neighbour(node_idx);
+                if let Some(next_node) = view.get_node(next).filter(|next_node| avoid.allows(&vertex, next, Some(next_node))) {
+                    search.relax(next, node_idx, cost + view.weight(&vertex), heuristic.estimate(&next_node, &target_node));
+                }
+            }
+            search.check(limits, self.region_idx)?;
+            search.pause().await;
         }
         Err(GraphError::Unreachable(target.0, target.1))
     }
 
-    pub(crate) fn find_way(&self, source: NodeInfo, target: NodeInfo) -> Result<Vec<PathResult>, GraphError> {
-        let mut queue: PriorityQueue<(NodeIdx, Vec<PathPoint>), u64> = PriorityQueue::new();
-        let mut possibilities = vec![];
-        let mut visited = HashSet::new();
-        let start_node = self.nodes.get(&source.0).ok_or(GraphError::StartNodeNotFound(source.0, self.region_idx))?;
-        queue.push((start_node.id, vec![PathPoint::from(start_node.clone())]), 0);
+    /// Bidirectional Dijkstra: searches from both ends at once, always expanding the side with the
+    /// smaller frontier, until no way through the unsettled nodes can beat the cheapest meeting found.
+    pub async fn find_way_bidirectional(&self, source: NodeInfo,
+                                        target: NodeInfo,
+                                        avoid: &Avoid,
+                                        limits: &SearchLimits) -> Result<PathResult, GraphError> {
+        let patches = self.patches();
+        let view = self.view(&patches);
+        let start_node = view.get_node(source.0).ok_or(GraphError::StartNodeNotFound(source.0, self.region_idx))?;
+        let target_node = view.get_node(target.0).ok_or(GraphError::Unreachable(target.0, target.1))?;
+        // The backward search starts at the target without entering it, so it is checked here.
+        if avoid.excludes(&start_node) || avoid.excludes(&target_node) {
+            return Err(GraphError::Unreachable(target.0, target.1));
+        }
+        let mut searches = [Search::new(source.0, patches.max_weight), Search::new(target.0, patches.max_weight)];
+        // Cost of the node settled last on each side, a lower bound of everything left in its frontier.
+        let mut settled_cost = [0, 0];
+        let mut best: Option<(NodeIdx, u64)> = if source.0 == target.0 { Some((source.0, 0)) } else { None };
 
-        while queue.len() > 0 {
-            let ((node_idx, path), cost): ((NodeIdx, Vec<PathPoint>), u64) = queue.pop().unwrap();
-            let node = self.nodes.get(&node_idx).unwrap();
-            if self.region_idx != node.region {
-                possibilities.push(Continue(path, cost, Continuation::CRegionKnown(node.id, node.region)));
-                continue;
+        loop {
+            let side = if searches[0].frontier.len() <= searches[1].frontier.len() { 0 } else { 1 };
+            let (node_idx, cost) = match searches[side].pop() {
+                Some(popped) => { popped }
+                None => { break }
+            };
+            settled_cost[side] = cost;
+            if best.is_some_and(|(_, best_cost)| settled_cost[0] + settled_cost[1] >= best_cost) {
+                break;
+            }
+            for vertex_id in view.connections(node_idx) {
+                let vertex = view.get_vertex(vertex_id).ok_or(GraphError::VertexNotFound(vertex_id, self.region_idx))?;
+                let next = vertex.get_neighbour(node_idx);
+                if view.get_node(next).filter(|next_node| avoid.allows(&vertex, next, Some(next_node))).is_none() {
+                    continue;
+                }
+                let next_cost = cost + view.weight(&vertex);
+                searches[side].relax(next, node_idx, next_cost, 0);
+                if let Some(other_cost) = searches[1 - side].costs.get(&next) {
+                    if best.is_none_or(|(_, best_cost)| next_cost + other_cost < best_cost) {
+                        best = Some((next, next_cost + other_cost));
+                    }
+                }
             }
+            searches[side].check(limits, self.region_idx)?;
+            searches[side].pause().await;
+        }
+
+        let (meeting, cost) = best.ok_or(GraphError::Unreachable(target.0, target.1))?;
+        let mut path = view.reconstruct_path(&searches[0].parents, meeting);
+        let mut current = searches[1].parents.get(&meeting).copied();
+        while let Some(node_idx) = current {
+            path.push(PathPoint::from(&*view.get_node(node_idx).expect("Reached nodes are part of the graph")));
+            current = searches[1].parents.get(&node_idx).copied();
+        }
+        Ok(PathResult::TargetReached(path, cost))
+    }
+
+    /// Costs of the cheapest paths from `source` to every node reachable from it.
+    pub fn distances_from(&self, source: NodeIdx) -> HashMap<NodeIdx, u64> {
+        let patches = self.patches();
+        let view = self.view(&patches);
+        let mut search = Search::new(source, patches.max_weight);
+        while let Some((node_idx, cost)) = search.pop() {
+            for vertex_id in view.connections(node_idx) {
+                if let Some(vertex) = view.get_vertex(vertex_id) {
+                    let next = vertex.get_neighbour(node_idx);
+                    if view.contains_node(next) {
+                        search.relax(next, node_idx, cost + view.weight(&vertex), 0);
+                    }
+                }
+            }
+        }
+        search.costs
+    }
+
+    /// Searches this region for the cheapest ways into the neighbouring regions, which lead towards the target region.
+    pub async fn find_way(&self, source: NodeInfo,
+                    target: NodeInfo,
+                    avoid: &Avoid,
+                    limits: &SearchLimits) -> Result<Vec<PathResult>, GraphError> {
+        let patches = self.patches();
+        let view = self.view(&patches);
+        let start_node = view.get_node(source.0).ok_or(GraphError::StartNodeNotFound(source.0, self.region_idx))?;
+        if avoid.excludes(&start_node) {
+            return Err(GraphError::Unreachable(target.0, target.1));
+        }
+        let mut search = Search::new(start_node.id, patches.max_weight);
+        let towards = self.towards(target.1);
+        // Cheapest known way out of the region through each boundary node: (last node inside, cost, continuation)
+        let mut exits: HashMap<NodeIdx, (NodeIdx, u64, Continuation)> = HashMap::new();
 
-            for vertex_id in node.connections.iter() {
-                let vertex = self.vertices.get(&vertex_id).ok_or(GraphError::VertexNotFound(*vertex_id, self.region_idx))?;
-                if vertex.region_bits[target.1 as usize] {
+        while let Some((node_idx, cost)) = search.pop() {
+            for vertex_id in view.connections(node_idx) {
+                let vertex = view.get_vertex(vertex_id).ok_or(GraphError::VertexNotFound(vertex_id, self.region_idx))?;
+                if !vertex.leads_to(towards) {
+                    continue;
+                }
+                let next = vertex.get_neighbour(node_idx);
+                let next_node = view.get_node(next);
+                if !avoid.allows(&vertex, next, next_node.as_deref()) {
+                    continue;
+                }
+                let next_cost = cost + view.weight(&vertex);
+                let continuation = match next_node {
+                    Some(next_node) if next_node.region == self.region_idx => {
+                        search.relax(next, node_idx, next_cost, 0);
+                        continue;
+                    }
+                    Some(next_node) => { Continuation::CRegionKnown(next, next_node.region) }
+                    None => { Continuation::CRegionUnknown(next) }
+                };
+                if exits.get(&next).is_none_or(|(_, known_cost, _)| next_cost < *known_cost) {
+                    exits.insert(next, (node_idx, next_cost, continuation));
+                }
+            }
+            search.check(limits, self.region_idx)?;
+            search.pause().await;
+        }
+
+        let mut exits: Vec<(NodeIdx, u64, Continuation)> = exits.into_values().collect();
+        exits.sort_by_key(|(_, cost, _)| *cost);
+        Ok(exits.into_iter()
+            .map(|(from, cost, continuation)| PathResult::Continue(view.reconstruct_path(&search.parents, from), cost, continuation))
+            .collect())
+    }
+
+    /// Computes the cheapest ways from every boundary node to every edge leaving the region, used
+    /// by [`Graph::find_way_shortcut`]. Takes a search per boundary node and memory for a path per
+    /// pair, which pays off for regions that many requests cross. Returns the number of boundary nodes.
+    pub fn build_shortcuts(&mut self) -> usize {
+        let patches = self.patches();
+        let view = self.view(&patches);
+        // Edges leaving the region: (vertex, node inside, node outside)
+        let mut crossings = vec![];
+        for node in self.nodes().filter(|node| node.region == self.region_idx) {
+            for vertex_id in view.connections(node.id) {
+                if let Some(vertex) = view.get_vertex(vertex_id) {
                     let next = vertex.get_neighbour(node.id);
-                    if !visited.contains(&next) {
-                        visited.insert(next);
-                        let next_node = match self.nodes.get(&next) {
-                            Some(node) => {
-                                if self.region_idx != node.region {
-                                    possibilities.push(Continue(path.clone(), cost, Continuation::CRegionKnown(node.id , node.region)));
-                                    continue;
-                                } else {
-                                    node
-                                }
-                            }
-                            None => {
-                                possibilities.push(Continue(path.clone(), cost + vertex.weight, Continuation::CRegionUnknown(node.id)));
-                                continue;
-                            }
-                        };
-                        let mut new_path = path.clone();
-                        new_path.push(PathPoint::from(next_node.clone()));
-                        queue.push((next_node.id, new_path), cost + vertex.weight);
+                    if view.get_node(next).is_none_or(|next_node| next_node.region != self.region_idx) {
+                        crossings.push((vertex_id, node.id, next, view.weight(&vertex)));
+                    }
+                }
+            }
+        }
+
+        let mut shortcuts = Shortcuts::default();
+        let boundary: HashSet<NodeIdx> = crossings.iter().map(|(_, inside, _, _)| *inside).collect();
+        for source in boundary.iter() {
+            let mut search = Search::new(*source, patches.max_weight);
+            while let Some((node_idx, cost)) = search.pop() {
+                for vertex_id in view.connections(node_idx) {
+                    if let Some(vertex) = view.get_vertex(vertex_id) {
+                        let next = vertex.get_neighbour(node_idx);
+                        if view.get_node(next).is_some_and(|next_node| next_node.region == self.region_idx) {
+                            search.relax(next, node_idx, cost + view.weight(&vertex), 0);
+                        }
                     }
                 }
             }
+            let exits = crossings.iter()
+                .filter_map(|(vertex, inside, next, weight)| {
+                    let cost = search.costs.get(inside)? + weight;
+                    let mut path = vec![*inside];
+                    let mut current = search.parents.get(inside);
+                    while let Some(node_idx) = current {
+                        path.push(*node_idx);
+                        current = search.parents.get(node_idx);
+                    }
+                    path.reverse();
+                    Some(Shortcut { vertex: *vertex, next: *next, path: path.into_boxed_slice(), cost })
+                })
+                .collect();
+            shortcuts.exits.insert(*source, exits);
+        }
+        self.shortcuts = Some(Arc::new(shortcuts));
+        boundary.len()
+    }
+
+    /// Same as [`Graph::find_way`] for a request entering the region at a boundary node, answered
+    /// from the precomputed shortcuts. `None` if there are none for the request: they are not
+    /// built, the region was patched since, or the request avoids anything.
+    pub fn find_way_shortcut(&self, source: NodeInfo,
+                             target: NodeInfo,
+                             avoid: &Avoid) -> Option<Vec<PathResult>> {
+        let shortcuts = self.shortcuts.as_ref()?;
+        let patches = self.patches();
+        if !patches.weights.is_empty() || patches.changes_topology() || !avoid.is_empty() {
+            return None;
+        }
+        let view = self.view(&patches);
+        let towards = self.towards(target.1);
+        let mut best: HashMap<NodeIdx, &Shortcut> = HashMap::new();
+        for shortcut in shortcuts.exits.get(&source.0)? {
+            if view.get_vertex(shortcut.vertex).is_none_or(|vertex| !vertex.leads_to(towards)) {
+                continue;
+            }
+            if best.get(&shortcut.next).is_none_or(|known| shortcut.cost < known.cost) {
+                best.insert(shortcut.next, shortcut);
+            }
+        }
+        let mut exits: Vec<&Shortcut> = best.into_values().collect();
+        exits.sort_by_key(|shortcut| shortcut.cost);
+        Some(exits.into_iter()
+            .map(|shortcut| {
+                let path = shortcut.path.iter()
+                    .map(|node_idx| PathPoint::from(&*view.get_node(*node_idx).expect("Shortcuts lead through the graph")))
+                    .collect();
+                let continuation = match view.get_node(shortcut.next) {
+                    Some(next_node) => { Continuation::CRegionKnown(shortcut.next, next_node.region) }
+                    None => { Continuation::CRegionUnknown(shortcut.next) }
+                };
+                PathResult::Continue(path, shortcut.cost, continuation)
+            })
+            .collect())
+    }
+}
+
+/// Queue of nodes waiting to be expanded, ordered by ascending priority.
+enum Frontier {
+    Heap(PriorityQueue<NodeIdx, Reverse<u64>>),
+    #[cfg(feature = "bucket-queue")]
+    Buckets(BucketQueue),
+}
+
+impl Frontier {
+    #[cfg(feature = "bucket-queue")]
+    fn new(max_weight: u64) -> Self {
+        if max_weight <= BUCKET_QUEUE_MAX_WEIGHT {
+            Frontier::Buckets(BucketQueue::new(max_weight))
+        } else {
+            Frontier::Heap(PriorityQueue::new())
+        }
+    }
+
+    #[cfg(not(feature = "bucket-queue"))]
+    fn new(_max_weight: u64) -> Self {
+        Frontier::Heap(PriorityQueue::new())
+    }
+
+    fn push_decrease(&mut self, node: NodeIdx, priority: u64) {
+        match self {
+            Frontier::Heap(queue) => { queue.push_increase(node, Reverse(priority)); }
+            #[cfg(feature = "bucket-queue")]
+            Frontier::Buckets(queue) => { queue.push_decrease(node, priority); }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Frontier::Heap(queue) => { queue.len() }
+            #[cfg(feature = "bucket-queue")]
+            Frontier::Buckets(queue) => { queue.len() }
+        }
+    }
+
+    fn pop(&mut self) -> Option<NodeIdx> {
+        match self {
+            Frontier::Heap(queue) => { queue.pop().map(|(node, _)| node) }
+            #[cfg(feature = "bucket-queue")]
+            Frontier::Buckets(queue) => { queue.pop().map(|(node, _)| node) }
+        }
+    }
+}
+
+/// Dijkstra/A* bookkeeping. Only the predecessor of every reached node is stored,
+/// paths are rebuilt from it once an end of the search is known.
+struct Search {
+    /// Prioritized by the cost so far plus the heuristic estimate of the remaining cost.
+    frontier: Frontier,
+    costs: HashMap<NodeIdx, u64>,
+    parents: HashMap<NodeIdx, NodeIdx>,
+    settled: HashSet<NodeIdx>,
+    started: Instant,
+}
+
+impl Search {
+    fn new(start: NodeIdx, max_weight: u64) -> Self {
+        let mut frontier = Frontier::new(max_weight);
+        frontier.push_decrease(start, 0);
+        Self {
+            frontier,
+            costs: HashMap::from([(start, 0)]),
+            parents: HashMap::new(),
+            settled: HashSet::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Takes the most promising node, whose cost is final.
+    fn pop(&mut self) -> Option<(NodeIdx, u64)> {
+        let node_idx = self.frontier.pop()?;
+        self.settled.insert(node_idx);
+        Some((node_idx, self.costs[&node_idx]))
+    }
+
+    /// Records `next` as reachable through `from` if that is cheaper than any way found so far.
+    fn relax(&mut self, next: NodeIdx, from: NodeIdx, cost: u64, estimate: u64) {
+        if self.settled.contains(&next) {
+            return;
+        }
+        if self.costs.get(&next).is_none_or(|known| cost < *known) {
+            self.costs.insert(next, cost);
+            self.parents.insert(next, from);
+            self.frontier.push_decrease(next, cost + estimate);
+        }
+    }
+
+    /// Yields to the runtime every [`YIELD_INTERVAL`] expanded nodes.
+    async fn pause(&self) {
+        if self.settled.len().is_multiple_of(YIELD_INTERVAL) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Fails once the search holds more state or has run longer than `limits` allow.
+    fn check(&self, limits: &SearchLimits, region_idx: RegionIdx) -> Result<(), GraphError> {
+        let frontier_exceeded = limits.max_frontier.is_some_and(|max| self.frontier.len() > max);
+        let reached_exceeded = limits.max_reached.is_some_and(|max| self.costs.len() > max);
+        if frontier_exceeded || reached_exceeded {
+            return Err(GraphError::BudgetExceeded(region_idx));
+        }
+        // The clock is read as rarely as the search yields.
+        if self.settled.len().is_multiple_of(YIELD_INTERVAL) && limits.max_duration.is_some_and(|max| self.started.elapsed() >= max) {
+            return Err(GraphError::TimedOut(region_idx));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+    use std::time::Duration;
+    use bitvec::vec::BitVec;
+    use crate::domain::{NodeInfo, PathPoint};
+    use crate::graph::{Access, Avoid, Continuation, Graph, GraphError, Node, PathResult, Profile, SearchLimits, SuperRegions, Vertex, WeightScale};
+    use crate::heuristic::{Euclidean, Heuristic, Landmarks, Zero};
+
+    /// Region 1 with nodes 1..=4 and a boundary node 5 from region 2:
+    /// 1 -(1)- 2 -(1)- 3 -(1)- 4 -(1)- 5, plus a costly shortcut 1 -(10)- 3 open to cars only.
+    fn sample_graph() -> Graph {
+        let edges = [(0, 1, 2, 1), (1, 2, 3, 1), (2, 3, 4, 1), (3, 4, 5, 1), (4, 1, 3, 10)];
+        let mut nodes: HashMap<_, _> = (1..=5)
+            .map(|id| (id, Node::new(vec![], id, if id == 5 { 2 } else { 1 }, id as u64, 0)))
+            .collect();
+        let mut vertices = HashMap::new();
+        for (id, a, b, weight) in edges {
+            nodes.get_mut(&a).unwrap().connections.push(id);
+            nodes.get_mut(&b).unwrap().connections.push(id);
+            let access = if id == 4 { Access::new([Profile::Car]) } else { Access::ALL };
+            vertices.insert(id, Vertex { a, b, weight, id, region_bits: BitVec::repeat(true, 4), access });
+        }
+        Graph::new(nodes, vertices, 1)
+    }
+
+    #[tokio::test]
+    async fn local_search_finds_cheapest_path() {
+        let graph = sample_graph();
+        let landmarks = Landmarks::new([&graph], 2);
+        let heuristics: [&dyn Heuristic; 3] = [&Zero, &Euclidean { scale: 1. }, &landmarks];
+        for heuristic in heuristics {
+            match graph.find_way_local(NodeInfo(1, 1), NodeInfo(4, 1), heuristic, &Avoid::default(), &SearchLimits::default()).await.unwrap() {
+                PathResult::TargetReached(path, cost) => {
+                    assert_eq!(cost, 3);
+                    let expected: Vec<_> = (1..=4).map(|id| PathPoint::from(&*graph.get_node(id).unwrap())).collect();
+                    assert_eq!(path, expected);
+                }
+                PathResult::Continue(..) => { panic!("Target should be reached") }
+            }
+        }
+    }
+
+    #[test]
+    fn landmark_bounds() {
+        let graph = sample_graph();
+        let landmarks = Landmarks::new([&graph], 2);
+        for (from, to) in [(1, 4), (2, 5), (4, 1), (3, 3)] {
+            let exact = graph.distances_from(from)[&to];
+            let estimate = landmarks.estimate(&graph.get_node(from).unwrap(), &graph.get_node(to).unwrap());
+            assert!(estimate <= exact);
+        }
+        assert_eq!(landmarks.estimate(&graph.get_node(1).unwrap(), &graph.get_node(4).unwrap()), 3);
+    }
+
+    #[tokio::test]
+    async fn search_stops_at_region_boundary() {
+        let graph = sample_graph();
+        let results = graph.find_way(NodeInfo(1, 1), NodeInfo(9, 2), &Avoid::default(), &SearchLimits::default()).await.unwrap();
+        assert_eq!(results.len(), 1);
+        match &results[0] {
+            PathResult::Continue(path, cost, Continuation::CRegionKnown(next, region)) => {
+                assert_eq!((*next, *region), (5, 2));
+                assert_eq!(*cost, 4);
+                assert_eq!(path.len(), 4);
+            }
+            _ => { panic!("Expected a continuation into region 2") }
+        }
+    }
+
+    #[tokio::test]
+    async fn avoided_parts_are_detoured() {
+        let graph = sample_graph();
+        let avoid = Avoid { nodes: [2].into(), ..Avoid::default() };
+        match graph.find_way_local(NodeInfo(1, 1), NodeInfo(4, 1), &Zero, &avoid, &SearchLimits::default()).await.unwrap() {
+            PathResult::TargetReached(path, cost) => {
+                assert_eq!(cost, 11);
+                assert_eq!(path.len(), 3);
+            }
+            PathResult::Continue(..) => { panic!("Target should be reached") }
+        }
+
+        let avoid = Avoid { vertices: [1, 4].into(), ..Avoid::default() };
+        assert!(graph.find_way_local(NodeInfo(1, 1), NodeInfo(4, 1), &Zero, &avoid, &SearchLimits::default()).await.is_err());
+
+        // The shortcut around node 2 is open to cars only.
+        let avoid = Avoid { nodes: [2].into(), profile: Some(Profile::Car), ..Avoid::default() };
+        assert!(graph.find_way_local(NodeInfo(1, 1), NodeInfo(4, 1), &Zero, &avoid, &SearchLimits::default()).await.is_ok());
+        let avoid = Avoid { nodes: [2].into(), profile: Some(Profile::Foot), ..Avoid::default() };
+        assert!(graph.find_way_local(NodeInfo(1, 1), NodeInfo(4, 1), &Zero, &avoid, &SearchLimits::default()).await.is_err());
+
+        let avoid = Avoid { regions: [2].into(), ..Avoid::default() };
+        assert!(graph.find_way(NodeInfo(1, 1), NodeInfo(9, 2), &avoid, &SearchLimits::default()).await.unwrap().is_empty());
+
+        // Queries starting where they avoid are refused, as are those ending there.
+        for avoid in [Avoid { nodes: [1].into(), ..Avoid::default() }, Avoid { regions: [1].into(), ..Avoid::default() }] {
+            assert!(matches!(graph.find_way_local(NodeInfo(1, 1), NodeInfo(4, 1), &Zero, &avoid, &SearchLimits::default()).await, Err(GraphError::Unreachable(4, 1))));
+            assert!(matches!(graph.find_way_bidirectional(NodeInfo(1, 1), NodeInfo(4, 1), &avoid, &SearchLimits::default()).await, Err(GraphError::Unreachable(4, 1))));
+            assert!(matches!(graph.find_way(NodeInfo(1, 1), NodeInfo(9, 2), &avoid, &SearchLimits::default()).await, Err(GraphError::Unreachable(9, 2))));
+        }
+    }
+
+    #[tokio::test]
+    async fn regions_past_the_region_bits_are_not_connected() {
+        let graph = sample_graph();
+        // The region bits of its edges cover regions 0 to 3.
+        assert!(graph.find_way(NodeInfo(1, 1), NodeInfo(9, 40), &Avoid::default(), &SearchLimits::default()).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn live_updates_are_carried_over() {
+        let vertex = |id, a, b| Vertex { a, b, weight: 2, id, region_bits: BitVec::repeat(true, 4), access: Access::ALL };
+        let previous = sample_graph();
+        previous.update_weight(0, 7).unwrap();
+        previous.remove_vertex(4).unwrap();
+        previous.insert_node(Node::new(vec![], 6, 1, 6, 0)).unwrap();
+        previous.insert_vertex(vertex(9, 3, 6)).unwrap();
+        previous.insert_vertex(vertex(10, 4, 6)).unwrap();
+
+        // The new version already has an edge 10 of its own.
+        let graph = sample_graph();
+        graph.insert_vertex(vertex(10, 1, 2)).unwrap();
+        assert_eq!(graph.carry_patches(&previous), 1);
+        assert_eq!((graph.current_weight(0), graph.current_weight(9)), (Some(7), Some(2)));
+        assert!(graph.get_vertex(4).is_none() && graph.contains_node(6));
+        assert_eq!(graph.get_vertex(10).unwrap().a, 1);
+    }
+
+    #[test]
+    fn weight_scale() {
+        let scale = WeightScale(1000);
+        assert_eq!(scale.to_fixed(12.3456), Some(12346));
+        assert_eq!(scale.to_fixed(-1.), None);
+        assert_eq!(scale.to_fixed(f64::NAN), None);
+        assert_eq!(scale.to_float(12346), 12.346);
+        assert_eq!(WeightScale::default().to_fixed(4.), Some(4));
+        assert_eq!(WeightScale::parse("1000").unwrap(), scale);
+        assert!(WeightScale::parse("0").is_err());
+    }
+
+    #[tokio::test]
+    async fn search_respects_memory_limits() {
+        let graph = sample_graph();
+        let limits = SearchLimits { max_reached: Some(3), ..SearchLimits::default() };
+        let res = graph.find_way_local(NodeInfo(1, 1), NodeInfo(4, 1), &Zero, &Avoid::default(), &limits).await;
+        assert!(matches!(res, Err(GraphError::BudgetExceeded(1))));
+        let res = graph.find_way(NodeInfo(1, 1), NodeInfo(9, 2), &Avoid::default(), &limits).await;
+        assert!(matches!(res, Err(GraphError::BudgetExceeded(1))));
+
+        let limits = SearchLimits { max_frontier: Some(2), max_reached: Some(5), ..SearchLimits::default() };
+        assert!(graph.find_way_local(NodeInfo(1, 1), NodeInfo(4, 1), &Zero, &Avoid::default(), &limits).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn long_searches_yield() {
+        let mut nodes: HashMap<_, _> = (0..2000).map(|id| (id, Node::new(vec![], id, 1, id as u64, 0))).collect();
+        let mut vertices = HashMap::new();
+        for id in 0..1999 {
+            nodes.get_mut(&id).unwrap().connections.push(id);
+            nodes.get_mut(&(id + 1)).unwrap().connections.push(id);
+            vertices.insert(id, Vertex { a: id, b: id + 1, weight: 1, id, region_bits: BitVec::repeat(true, 2), access: Access::ALL });
+        }
+        let graph = Graph::new(nodes, vertices, 1);
+        let (avoid, limits) = (Avoid::default(), SearchLimits::default());
+        let mut search = Box::pin(graph.find_way_local(NodeInfo(0, 1), NodeInfo(1999, 1), &Zero, &avoid, &limits));
+        assert!(futures_util::poll!(&mut search).is_pending());
+        assert!(matches!(search.await, Ok(PathResult::TargetReached(_, 1999))));
+
+        let limits = SearchLimits { max_duration: Some(Duration::ZERO), ..SearchLimits::default() };
+        let res = graph.find_way_local(NodeInfo(0, 1), NodeInfo(1999, 1), &Zero, &avoid, &limits).await;
+        assert!(matches!(res, Err(GraphError::TimedOut(1))));
+        let res = graph.find_way_bidirectional(NodeInfo(0, 1), NodeInfo(1999, 1), &avoid, &limits).await;
+        assert!(matches!(res, Err(GraphError::TimedOut(1))));
+    }
+
+    #[test]
+    fn super_region_bits() {
+        let super_regions = SuperRegions::new(&BTreeMap::from([(10, vec![1, 2]), (11, vec![3])])).unwrap();
+        assert_eq!(super_regions.towards(1, 2), 2);
+        assert_eq!(super_regions.towards(1, 3), 11);
+        assert_eq!(super_regions.towards(3, 1), 10);
+        // Regions outside of super-regions are routed to directly.
+        assert_eq!(super_regions.towards(1, 4), 4);
+        assert_eq!(super_regions.towards(4, 1), 1);
+        assert!(SuperRegions::new(&BTreeMap::from([(10, vec![1, 2]), (11, vec![2])])).is_err());
+        assert!(SuperRegions::new(&BTreeMap::from([(10, vec![1, 2]), (2, vec![3])])).is_err());
+    }
+
+    #[tokio::test]
+    async fn shortcuts_match_searches() {
+        // The sample region with a second way out, 1 -(2)- 6 into region 3.
+        let mut nodes: HashMap<_, _> = (1..=6)
+            .map(|id| (id, Node::new(vec![], id, [1, 1, 1, 1, 2, 3][id - 1], id as u64, 0)))
+            .collect();
+        let mut vertices = HashMap::new();
+        for (id, a, b, weight) in [(0, 1, 2, 1), (1, 2, 3, 1), (2, 3, 4, 1), (3, 4, 5, 1), (4, 1, 3, 10), (5, 1, 6, 2)] {
+            nodes.get_mut(&a).unwrap().connections.push(id);
+            nodes.get_mut(&b).unwrap().connections.push(id);
+            vertices.insert(id, Vertex { a, b, weight, id, region_bits: BitVec::repeat(true, 4), access: Access::ALL });
+        }
+        let mut graph = Graph::new(nodes, vertices, 1);
+        assert!(graph.find_way_shortcut(NodeInfo(1, 1), NodeInfo(9, 2), &Avoid::default()).is_none());
+        assert_eq!(graph.build_shortcuts(), 2);
+
+        let exits = |results: Vec<PathResult>| -> Vec<(Vec<PathPoint>, u64, usize)> {
+            results.into_iter().map(|result| match result {
+                PathResult::Continue(path, cost, continuation) => { (path, cost, continuation.get_node_idx()) }
+                PathResult::TargetReached(..) => { panic!("Region should be crossed") }
+            }).collect()
+        };
+        for source in [1, 4] {
+            let searched = graph.find_way(NodeInfo(source, 1), NodeInfo(9, 2), &Avoid::default(), &SearchLimits::default()).await.unwrap();
+            let shortcut = graph.find_way_shortcut(NodeInfo(source, 1), NodeInfo(9, 2), &Avoid::default()).unwrap();
+            assert_eq!(exits(shortcut), exits(searched));
         }
-        Ok(possibilities)
+        assert!(graph.find_way_shortcut(NodeInfo(2, 1), NodeInfo(9, 2), &Avoid::default()).is_none());
+        let avoid = Avoid { profile: Some(Profile::Truck), ..Avoid::default() };
+        assert!(graph.find_way_shortcut(NodeInfo(1, 1), NodeInfo(9, 2), &avoid).is_none());
+        graph.update_weight(0, 5).unwrap();
+        assert!(graph.find_way_shortcut(NodeInfo(1, 1), NodeInfo(9, 2), &Avoid::default()).is_none());
     }
 }
@@ -1,279 +1,2919 @@
-use bitvec::vec::BitVec;
+use std::collections::BTreeMap;
+use std::env;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use rand::Rng;
 use serde::{Serialize, Deserialize};
-use crate::graph::{Graph, Node, NodeIdx, RegionIdx, Vertex, VertexIdx};
+use crate::data_quality::{DataPolicy, QualityStats};
+use crate::gcs_auth::GcsCredentials;
+use crate::graph::{BoundingBox, Graph, Node, NodeIdx, RegionIdx, VertexIdx, WeightScale};
+use crate::region_cache::RegionCacheConfig;
+use crate::snapshot::{DirSnapshotStore, SnapshotStore};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct RawNode {
-    id: NodeIdx,
-    cord_x: u64,
-    cord_y: u64,
-    region: RegionIdx,
+pub(crate) struct RawNode {
+    pub(crate) id: NodeIdx,
+    pub(crate) cord_x: u64,
+    pub(crate) cord_y: u64,
+    pub(crate) region: RegionIdx,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct RawVertex {
+pub(crate) struct RawVertex {
     pub(crate) id: VertexIdx,
     pub(crate) a: NodeIdx,
     pub(crate) b: NodeIdx,
-    pub(crate) weight: u64,
-    region_bits: String,
+    /// In cost units of the data set, may be fractional.
+    pub(crate) weight: f64,
+    pub(crate) region_bits: String,
+    /// Transport modes allowed on the edge, separated by `|` (e.g. `car|truck`). All of them if absent.
+    #[serde(default)]
+    pub(crate) access: Option<String>,
 }
 
 impl From<RawNode> for Node {
     fn from(raw_node: RawNode) -> Self {
-        return Node::new(
+        Node::new(
             vec![],
             raw_node.id,
             raw_node.region,
             raw_node.cord_x,
             raw_node.cord_y,
-        );
+        )
     }
 }
 
-impl From<RawVertex> for Vertex {
-    fn from(raw_vertex: RawVertex) -> Self {
-        let char_vec = raw_vertex.region_bits.chars().collect::<Vec<_>>();
-        let bool_vec = char_vec.into_iter().map(|c| match c {
-            '0' => { false }
-            '1' => { true }
-            x => panic!("Bitvec has unknown character: {}", x)
-        });
-        Self {
-            a: raw_vertex.a,
-            b: raw_vertex.b,
-            weight: raw_vertex.weight,
-            id: raw_vertex.id,
-            region_bits: BitVec::from_iter(bool_vec),
-        }
-    }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupInfo {
+    pub group_id: usize,
+    pub regions: Vec<RegionIdx>,
+    /// Regions of every super-region in the data set, see [`crate::graph::SuperRegions`].
+    #[serde(default)]
+    pub super_regions: BTreeMap<RegionIdx, Vec<RegionIdx>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub(crate) struct GroupInfo {
-    pub(crate) group_id: usize,
-    pub(crate) regions: Vec<RegionIdx>,
+/// The region as read, or the part of it within `bounds`.
+fn bounded(graph: Graph, bounds: Option<&BoundingBox>) -> Graph {
+    match bounds {
+        Some(bounds) => { graph.within(bounds) }
+        None => { graph }
+    }
 }
 
 #[async_trait::async_trait]
 pub trait GraphProvider {
     async fn get_region(&self, id: RegionIdx) -> Result<Graph>;
+
+    /// Only the nodes of the region within `bounds` and the edges between them. Providers able to
+    /// skip the rest while reading do, the others read the whole region and keep the part within.
+    async fn get_region_within(&self, id: RegionIdx, bounds: &BoundingBox) -> Result<Graph> {
+        Ok(self.get_region(id).await?.within(bounds))
+    }
+
+    /// Stores the region in the packed format, which `get_region` reads from then on. Refused while
+    /// the group loaded or written last has a manifest, which would have to list the new file, and
+    /// before any was.
+    async fn put_region(&self, graph: &Graph) -> Result<()> {
+        Err(format!("Region {} can't be written, the provider is read only", graph.region_idx).into())
+    }
 }
 
 #[async_trait::async_trait]
-pub(crate) trait GroupInfoProvider {
+pub trait GroupInfoProvider {
     async fn get_info(&self, group_id: usize) -> Result<GroupInfo>;
+
+    /// Stores the group, once the provider made sure it has no manifest. Regions of the group are
+    /// written after it.
+    async fn put_info(&self, group_info: &GroupInfo) -> Result<()> {
+        Err(format!("Group {} can't be written, the provider is read only", group_info.group_id).into())
+    }
+}
+
+/// Provider a server loads its group from, keeping count of the rows it skipped or repaired.
+pub trait StorageProvider: GraphProvider + GroupInfoProvider + Send + Sync {
+    fn quality_stats(&self) -> Arc<QualityStats>;
+
+    /// Version of the data set in the manifest of the group loaded last, if it has one.
+    fn dataset_version(&self) -> Option<String>;
+}
+
+/// Retries of requests to a bucket which failed with a server error, timed out or couldn't connect,
+/// waiting exponentially longer between attempts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one.
+    pub retries: u32,
+    /// Wait before the first retry, doubled for every further one.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Time a single request may take, including downloading the object.
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Reads STORAGE_RETRIES, STORAGE_BACKOFF_MS, STORAGE_MAX_BACKOFF_MS and STORAGE_TIMEOUT_MS.
+    pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let defaults = RetryPolicy::default();
+        let millis = |name, default: Duration| -> std::result::Result<Duration, Box<dyn std::error::Error + Send + Sync>> {
+            match env::var(name) {
+                Ok(millis) => { Ok(Duration::from_millis(millis.parse()?)) }
+                Err(_) => { Ok(default) }
+            }
+        };
+        Ok(Self {
+            retries: match env::var("STORAGE_RETRIES") {
+                Ok(retries) => { retries.parse()? }
+                Err(_) => { defaults.retries }
+            },
+            backoff: millis("STORAGE_BACKOFF_MS", defaults.backoff)?,
+            max_backoff: millis("STORAGE_MAX_BACKOFF_MS", defaults.max_backoff)?,
+            timeout: millis("STORAGE_TIMEOUT_MS", defaults.timeout)?,
+        })
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self.backoff.saturating_mul(2u32.saturating_pow(retry)).min(self.max_backoff);
+        // Spreads the retries of servers started at the same time.
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    /// Runs `request`, answering with the content and status of an object, until it neither fails
+    /// nor answers with a server error.
+    pub(crate) async fn run<F, Fut, E>(&self, object: &str, request: F) -> std::io::Result<(Vec<u8>, u16)>
+        where F: Fn() -> Fut,
+              Fut: Future<Output=std::result::Result<(Vec<u8>, u16), E>>,
+              E: std::fmt::Display {
+        let mut retry = 0;
+        loop {
+            let failure = match tokio::time::timeout(self.timeout, request()).await {
+                Ok(Ok((_, status))) if status >= 500 || status == 429 => { format!("status {}", status) }
+                Ok(Ok(response)) => { return Ok(response) }
+                Ok(Err(err)) => { err.to_string() }
+                Err(_) => { format!("no response within {:?}", self.timeout) }
+            };
+            if retry == self.retries {
+                return Err(std::io::Error::other(format!("Request for {} failed {} times, last with {}", object, retry + 1, failure)));
+            }
+            let delay = self.delay(retry);
+            log::warn!("Request for {} failed with {}, retrying in {:?}", object, failure, delay);
+            tokio::time::sleep(delay).await;
+            retry += 1;
+        }
+    }
+}
+
+/// Where the regions are kept, picked with GRAPH_PROVIDER: `gcs` (the default), `s3`, `http`, `fs`,
+/// `sqlite` or `synthetic`, or several of them comma separated, tried in order. STORAGE_PROVIDER is read instead if it
+/// isn't set, `gcloud` and `mock` name the same providers as `gcs` and `fs`. With REGION_CACHE_DIR
+/// they are read through a local cache.
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    GoogleCloud {
+        region: String,
+        bucket: String,
+        credentials: GcsCredentials,
+        retry: RetryPolicy,
+    },
+    S3(s3::S3Config),
+    Http(http::HttpConfig),
+    /// A local data directory, laid out as written by the fixtures generator.
+    Directory(PathBuf),
+    /// A single SQLite file, see [`sqlite`].
+    #[cfg(feature = "sqlite")]
+    Sqlite(PathBuf),
+    /// Connection string of a PostgreSQL database, see [`postgres`].
+    #[cfg(feature = "postgres")]
+    Postgres(String),
+    /// Generated from a seed, see [`synthetic`].
+    Synthetic(synthetic::SyntheticConfig),
+    /// Providers tried in order, see [`fallback::FallbackProvider`].
+    Chain(Vec<StorageConfig>),
+    /// A provider read through a local cache, see [`cached::CachedProvider`].
+    Cached(Box<StorageConfig>, RegionCacheConfig),
+}
+
+impl StorageConfig {
+    pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let provider = env::var("GRAPH_PROVIDER").or_else(|_| env::var("STORAGE_PROVIDER")).unwrap_or_else(|_| "gcs".to_string());
+        let mut configs = provider.split(',').map(|name| Self::named(name.trim())).collect::<std::result::Result<Vec<_>, _>>()?;
+        let config = match configs.len() {
+            1 => { configs.remove(0) }
+            _ => { StorageConfig::Chain(configs) }
+        };
+        match RegionCacheConfig::from_env()? {
+            Some(cache) => { Ok(StorageConfig::Cached(Box::new(config), cache)) }
+            None => { Ok(config) }
+        }
+    }
+
+    fn named(name: &str) -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        match name {
+            "gcs" | "gcloud" => {
+                Ok(StorageConfig::GoogleCloud {
+                    region: env::var("GOOGLE_CLOUD_REGION")?,
+                    bucket: env::var("GOOGLE_CLOUD_BUCKET")?,
+                    credentials: GcsCredentials::from_env()?,
+                    retry: RetryPolicy::from_env()?,
+                })
+            }
+            "s3" => { Ok(StorageConfig::S3(s3::S3Config::from_env()?)) }
+            "http" => { Ok(StorageConfig::Http(http::HttpConfig::from_env()?)) }
+            "fs" | "mock" => { Ok(StorageConfig::Directory(PathBuf::from(env::var("DATA_DIR")?))) }
+            "synthetic" => { Ok(StorageConfig::Synthetic(synthetic::SyntheticConfig::from_env()?)) }
+            #[cfg(feature = "sqlite")]
+            "sqlite" => { Ok(StorageConfig::Sqlite(PathBuf::from(env::var("SQLITE_PATH")?))) }
+            #[cfg(not(feature = "sqlite"))]
+            "sqlite" => { Err("The sqlite graph provider needs a build with the sqlite feature")? }
+            #[cfg(feature = "postgres")]
+            "postgres" => { Ok(StorageConfig::Postgres(env::var("POSTGRES_URL")?)) }
+            #[cfg(not(feature = "postgres"))]
+            "postgres" => { Err("The postgres graph provider needs a build with the postgres feature")? }
+            other => { Err(format!("Unknown graph provider {}, expected gcs, s3, http, fs, sqlite, postgres or synthetic", other))? }
+        }
+    }
+
+    /// Name the provider is configured with, as recorded for the regions it serves in a chain.
+    pub fn name(&self) -> String {
+        match self {
+            StorageConfig::GoogleCloud { .. } => { "gcs".to_string() }
+            StorageConfig::S3(_) => { "s3".to_string() }
+            StorageConfig::Http(_) => { "http".to_string() }
+            StorageConfig::Directory(_) => { "fs".to_string() }
+            #[cfg(feature = "sqlite")]
+            StorageConfig::Sqlite(_) => { "sqlite".to_string() }
+            #[cfg(feature = "postgres")]
+            StorageConfig::Postgres(_) => { "postgres".to_string() }
+            StorageConfig::Synthetic(_) => { "synthetic".to_string() }
+            StorageConfig::Chain(configs) => { configs.iter().map(StorageConfig::name).collect::<Vec<_>>().join(",") }
+            StorageConfig::Cached(config, _) => { config.name() }
+        }
+    }
+
+    pub fn provider(&self, weight_scale: WeightScale, data_policy: DataPolicy) -> std::result::Result<Box<dyn StorageProvider>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(match self {
+            StorageConfig::GoogleCloud { region, bucket, credentials, retry } => {
+                Box::new(gcloud::CloudStorageProvider::with_credentials(region, bucket, credentials)
+                    .with_retry_policy(*retry)
+                    .with_weight_scale(weight_scale)
+                    .with_data_policy(data_policy))
+            }
+            StorageConfig::S3(config) => {
+                Box::new(s3::S3Provider::new(config)?
+                    .with_weight_scale(weight_scale)
+                    .with_data_policy(data_policy))
+            }
+            StorageConfig::Http(config) => {
+                Box::new(http::HttpGraphProvider::new(config.clone())
+                    .with_weight_scale(weight_scale)
+                    .with_data_policy(data_policy))
+            }
+            StorageConfig::Directory(dir) => {
+                Box::new(mock::MockGraphProvider::new(dir.clone())
+                    .with_weight_scale(weight_scale)
+                    .with_data_policy(data_policy))
+            }
+            #[cfg(feature = "sqlite")]
+            StorageConfig::Sqlite(path) => {
+                Box::new(sqlite::SqliteGraphProvider::new(path.clone())
+                    .with_weight_scale(weight_scale)
+                    .with_data_policy(data_policy))
+            }
+            #[cfg(feature = "postgres")]
+            StorageConfig::Postgres(url) => {
+                Box::new(postgres::PostgresGraphProvider::new(url.clone())
+                    .with_weight_scale(weight_scale)
+                    .with_data_policy(data_policy))
+            }
+            StorageConfig::Synthetic(config) => {
+                Box::new(synthetic::SyntheticGraphProvider::new(config.clone())
+                    .with_weight_scale(weight_scale)
+                    .with_data_policy(data_policy))
+            }
+            StorageConfig::Chain(configs) => { Box::new(Self::chain(configs, weight_scale, data_policy)?) }
+            StorageConfig::Cached(config, cache) => {
+                Box::new(cached::CachedProvider::new(config.provider(weight_scale, data_policy)?, cache.clone(), weight_scale))
+            }
+        })
+    }
+
+    fn chain(configs: &[StorageConfig], weight_scale: WeightScale, data_policy: DataPolicy) -> std::result::Result<fallback::FallbackProvider, Box<dyn std::error::Error + Send + Sync>> {
+        let mut providers = vec![];
+        for config in configs.iter() {
+            providers.push((config.name(), config.provider(weight_scale, data_policy)?));
+        }
+        Ok(fallback::FallbackProvider::new(providers))
+    }
+
+    /// Reads regions from the configured bucket, e.g. for tools working with the data set.
+    pub fn graph_provider(&self, weight_scale: WeightScale, data_policy: DataPolicy) -> std::result::Result<Box<dyn GraphProvider>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.provider(weight_scale, data_policy)?)
+    }
+
+    /// Snapshots are kept by the first provider of a chain able to.
+    pub fn snapshot_store(&self) -> std::result::Result<Box<dyn SnapshotStore>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(match self {
+            StorageConfig::GoogleCloud { region, bucket, credentials, retry } => {
+                Box::new(gcloud::CloudStorageProvider::with_credentials(region, bucket, credentials).with_retry_policy(*retry))
+            }
+            StorageConfig::S3(config) => { Box::new(s3::S3Provider::new(config)?) }
+            StorageConfig::Http(_) => { Err("Snapshots can't be kept with the http storage provider, which is read only")? }
+            StorageConfig::Synthetic(_) => { Err("Snapshots can't be kept with the synthetic storage provider, which has no storage")? }
+            StorageConfig::Directory(dir) => { Box::new(DirSnapshotStore::new(dir.join("snapshots"))) }
+            #[cfg(feature = "sqlite")]
+            StorageConfig::Sqlite(path) => { Box::new(DirSnapshotStore::new(path.with_extension("snapshots"))) }
+            #[cfg(feature = "postgres")]
+            StorageConfig::Postgres(_) => { Err("Snapshots can't be kept with the postgres storage provider, chain a provider which can")? }
+            StorageConfig::Chain(configs) => {
+                match configs.iter().find_map(|config| config.snapshot_store().ok()) {
+                    Some(store) => { store }
+                    None => { Err("None of the chained storage providers can keep snapshots")? }
+                }
+            }
+            StorageConfig::Cached(config, _) => { config.snapshot_store()? }
+        })
+    }
 }
 
 pub mod mock {
-    use std::collections::HashMap;
-    use std::path::{PathBuf};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
     use futures_util::StreamExt;
-    use tokio::io::AsyncReadExt;
-    use crate::graph_provider::{Graph, GraphProvider, GroupInfo, Node, RawNode, RawVertex, Result, Vertex};
-    use crate::graph::RegionIdx;
+    use tokio::io::{AsyncRead, AsyncReadExt};
+    use crate::compression;
+    use crate::data_quality::{DataPolicy, GraphBuilder, QualityStats};
+    use crate::graph_provider::{bounded, Graph, GraphProvider, GroupInfo, RawNode, RawVertex, Result, StorageProvider};
+    use crate::graph::{BoundingBox, RegionIdx, WeightScale};
+    use crate::manifest::{self, ManifestSlot, RegionManifest};
+    use crate::packed;
     use crate::GroupInfoProvider;
 
-    pub(crate) struct MockGraphProvider {
+    /// Reads regions from a local directory, laid out as written by the fixtures generator. The
+    /// manifest of a group is read from `groups/manifest_<group id>.json`, if there is one.
+    pub struct MockGraphProvider {
         dir_path: PathBuf,
+        manifest: ManifestSlot,
+        weight_scale: WeightScale,
+        data_policy: DataPolicy,
+        quality_stats: Arc<QualityStats>,
     }
 
     impl MockGraphProvider {
-        pub(crate) fn new(dir_path: PathBuf) -> Self {
+        pub fn new(dir_path: PathBuf) -> Self {
             Self {
-                dir_path
+                dir_path,
+                manifest: ManifestSlot::default(),
+                weight_scale: WeightScale::default(),
+                data_policy: DataPolicy::default(),
+                quality_stats: Arc::default(),
             }
         }
+
+        pub fn with_weight_scale(mut self, weight_scale: WeightScale) -> Self {
+            self.weight_scale = weight_scale;
+            self
+        }
+
+        pub fn with_data_policy(mut self, data_policy: DataPolicy) -> Self {
+            self.data_policy = data_policy;
+            self
+        }
+
+        /// Records skipped or repaired in all regions loaded so far.
+        pub fn quality_stats(&self) -> Arc<QualityStats> {
+            self.quality_stats.clone()
+        }
+
+        /// Version of the data set in the manifest of the group loaded last, if it has one.
+        pub fn dataset_version(&self) -> Option<String> {
+            self.manifest.version()
+        }
     }
 
-    #[async_trait::async_trait]
-    impl GraphProvider for MockGraphProvider {
-        async fn get_region(&self, id: RegionIdx) -> Result<Graph> {
-            let vertex_filepath = self.dir_path.clone().join(format!("vertices/vertices_{}.csv", id));
-            let nodes_filepath = self.dir_path.clone().join(format!("nodes/nodes_{}.csv", id));
-            assert!(vertex_filepath.exists());
-            assert!(nodes_filepath.exists());
+    /// Reads `<name>.zst` or `<name>.gz` from `dir` instead of `name` if there is one.
+    async fn open_csv(dir: &Path, name: &str, manifest: &ManifestSlot) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        for extension in compression::extensions() {
+            let compressed_name = format!("{}.{}", name, extension);
+            let path = dir.join(&compressed_name);
+            if path.exists() {
+                let content = tokio::fs::read(path).await?;
+                manifest.verify(&compressed_name, &content)?;
+                return Ok(Box::new(std::io::Cursor::new(compression::decompress(content)?)));
+            }
+        }
+        if manifest.get().is_some() {
+            let content = tokio::fs::read(dir.join(name)).await?;
+            manifest.verify(name, &content)?;
+            return Ok(Box::new(std::io::Cursor::new(content)));
+        }
+        Ok(Box::new(tokio::fs::File::open(dir.join(name)).await?))
+    }
 
-            let nodes_file = tokio::fs::File::open(nodes_filepath).await?;
+    impl MockGraphProvider {
+        /// CSV rows outside `bounds` are skipped while reading, packed regions are cut once read.
+        async fn read_region(&self, id: RegionIdx, bounds: Option<&BoundingBox>) -> Result<Graph> {
+            let packed_path = crate::graph_provider::packed::region_path(&self.dir_path, id);
+            if packed_path.exists() {
+                let content = tokio::fs::read(packed_path).await?;
+                self.manifest.verify(&packed::file_name(id), &content)?;
+                return Ok(bounded(packed::read(&content, id, self.weight_scale)?, bounds));
+            }
+            let mut builder = GraphBuilder::new(id, self.weight_scale, self.data_policy, &self.quality_stats).with_bounds(bounds);
+            let nodes_file = open_csv(&self.dir_path.join("nodes"), &format!("nodes_{}.csv", id), &self.manifest).await?;
             let mut nodes_reader = csv_async::AsyncReaderBuilder::new().has_headers(false).create_deserializer(nodes_file);
-            let mut nodes = HashMap::new();
             let mut nodes_read = nodes_reader.deserialize::<RawNode>();
             while let Some(record) = nodes_read.next().await {
-                let raw_node = record?;
-                let node = Node::from(raw_node);
-                nodes.insert(node.id, node);
+                builder.add_node(record)?;
             }
 
-            let vertex_file = tokio::fs::File::open(vertex_filepath).await?;
-            let mut vertices_reader = csv_async::AsyncReaderBuilder::new().has_headers(false).create_deserializer(vertex_file);
-            let mut vertices = HashMap::new();
+            let vertex_file = open_csv(&self.dir_path.join("vertices"), &format!("vertices_{}.csv", id), &self.manifest).await?;
+            let mut vertices_reader = csv_async::AsyncReaderBuilder::new().has_headers(false).flexible(true).create_deserializer(vertex_file);
             let mut vertices_read = vertices_reader.deserialize::<RawVertex>();
             while let Some(record) = vertices_read.next().await {
-                let record = record?;
-                let vertex = Vertex::from(record);
-                nodes.get_mut(&vertex.a).map(|node| node.connections.push(vertex.id));
-                nodes.get_mut(&vertex.b).map(|node| node.connections.push(vertex.id));
-                vertices.insert(vertex.id, vertex);
+                builder.add_vertex(record)?;
             }
 
-            return Ok(Graph::new(
-                nodes,
-                vertices,
-                id,
-            ));
+            Ok(builder.build()?)
+        }
+    }
+
+    impl MockGraphProvider {
+        async fn load_manifest(&self, group_id: usize) -> Result<()> {
+            let manifest_path = self.dir_path.join("groups").join(manifest::file_name(group_id));
+            let manifest = match manifest_path.exists() {
+                true => { Some(serde_json::from_slice::<RegionManifest>(&tokio::fs::read(manifest_path).await?)?) }
+                false => { None }
+            };
+            self.manifest.set(manifest);
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GraphProvider for MockGraphProvider {
+        async fn get_region(&self, id: RegionIdx) -> Result<Graph> {
+            self.read_region(id, None).await
+        }
+
+        async fn get_region_within(&self, id: RegionIdx, bounds: &BoundingBox) -> Result<Graph> {
+            self.read_region(id, Some(bounds)).await
+        }
+
+        async fn put_region(&self, graph: &Graph) -> Result<()> {
+            self.manifest.check_writable(&packed::file_name(graph.region_idx))?;
+            let mut content = vec![];
+            packed::write(graph, self.weight_scale, &mut content)?;
+            tokio::fs::create_dir_all(&self.dir_path).await?;
+            tokio::fs::write(crate::graph_provider::packed::region_path(&self.dir_path, graph.region_idx), content).await?;
+            Ok(())
         }
     }
 
     #[async_trait::async_trait]
     impl GroupInfoProvider for MockGraphProvider {
         async fn get_info(&self, group_id: usize) -> Result<GroupInfo> {
-            let nodes_filepath = self.dir_path.clone().join(format!("group_{}.json", group_id));
-            assert!(nodes_filepath.exists());
+            let nodes_filepath = self.dir_path.clone().join(format!("groups/group_{}.json", group_id));
+            if !nodes_filepath.exists() {
+                return Err(format!("Group {} not found in {}", group_id, self.dir_path.display()).into());
+            }
             let mut nodes_file = tokio::fs::File::open(nodes_filepath).await?;
             let mut content = vec![];
-            nodes_file.read_buf(&mut content).await?;
-            Ok(serde_json::from_slice::<GroupInfo>(&*content)?)
+            nodes_file.read_to_end(&mut content).await?;
+            self.load_manifest(group_id).await?;
+            Ok(serde_json::from_slice::<GroupInfo>(&content)?)
+        }
+
+        async fn put_info(&self, group_info: &GroupInfo) -> Result<()> {
+            self.load_manifest(group_info.group_id).await?;
+            self.manifest.check_writable(&format!("group_{}.json", group_info.group_id))?;
+            let groups_dir = self.dir_path.join("groups");
+            tokio::fs::create_dir_all(&groups_dir).await?;
+            tokio::fs::write(groups_dir.join(format!("group_{}.json", group_info.group_id)), serde_json::to_vec_pretty(group_info)?).await?;
+            Ok(())
+        }
+    }
+
+    impl StorageProvider for MockGraphProvider {
+        fn quality_stats(&self) -> Arc<QualityStats> {
+            self.quality_stats.clone()
+        }
+
+        fn dataset_version(&self) -> Option<String> {
+            self.manifest.version()
         }
     }
 
     #[cfg(test)]
     mod test {
-        use std::path::PathBuf;
+        use std::sync::Arc;
+        use crate::domain::NodeInfo;
+        use crate::fixtures::{generate_sample, TempDir};
+        use crate::graph::{Access, Avoid, BoundingBox, Continuation, Graph, PathResult, Profile, SearchLimits, SuperRegions, WeightScale};
+        use crate::data_quality::DataPolicy;
+        use crate::graph_provider::StorageConfig;
         use crate::graph_provider::mock::MockGraphProvider;
+        use crate::manifest::RegionManifest;
         use crate::{GraphProvider, GroupInfoProvider};
 
         #[tokio::test]
         async fn test_group_info() {
-            let provider = MockGraphProvider::new(PathBuf::from("res/groups/"));
+            let dir = generate_sample("two_regions");
+            let provider = MockGraphProvider::new(dir.path().to_path_buf());
             let group_info = provider.get_info(2).await.unwrap();
             assert_eq!(group_info.group_id, 2);
-            assert!(group_info.regions.len() > 0);
+            assert!(!group_info.regions.is_empty());
+        }
+
+        #[tokio::test]
+        async fn written_regions_are_read_back() {
+            let scale = WeightScale(100);
+            let sample = generate_sample("two_regions");
+            let source = MockGraphProvider::new(sample.path().to_path_buf()).with_weight_scale(scale);
+            let group_info = source.get_info(2).await.unwrap();
+            let graph = source.get_region(2).await.unwrap();
+            let dir = TempDir::new("written");
+            let target = MockGraphProvider::new(dir.path().to_path_buf()).with_weight_scale(scale);
+            // Not before the provider looked for a manifest of the group.
+            assert!(target.put_region(&graph).await.is_err());
+            target.put_info(&group_info).await.unwrap();
+            target.put_region(&graph).await.unwrap();
+            assert_eq!(target.get_info(2).await.unwrap().regions, group_info.regions);
+            let written = target.get_region(2).await.unwrap();
+            assert_eq!((written.node_count(), written.vertices().find(|vertex| vertex.id == 3).unwrap().weight), (graph.node_count(), 150));
+
+            // Not while a manifest pins the files of the group.
+            std::fs::write(dir.path().join("groups").join(crate::manifest::file_name(2)), serde_json::to_vec(&RegionManifest::new("v1")).unwrap()).unwrap();
+            target.get_info(2).await.unwrap();
+            assert!(target.put_region(&graph).await.is_err());
+            let fresh = MockGraphProvider::new(dir.path().to_path_buf()).with_weight_scale(scale);
+            assert!(fresh.put_info(&group_info).await.is_err());
+            assert!(fresh.put_region(&graph).await.is_err());
+        }
+
+        #[tokio::test]
+        async fn regions_are_cut_to_bounds() {
+            let dir = generate_sample("two_regions");
+            let provider = MockGraphProvider::new(dir.path().to_path_buf());
+            let bounds: BoundingBox = "0,0,5,0".parse().unwrap();
+            let vertex_ids = |graph: &Graph| {
+                let mut ids: Vec<_> = graph.vertices().map(|vertex| vertex.id).collect();
+                ids.sort();
+                ids
+            };
+            // The long edge to node 4 leaves the bounds.
+            let graph = provider.get_region_within(1, &bounds).await.unwrap();
+            assert_eq!((graph.node_count(), vertex_ids(&graph)), (3, vec![1, 2]));
+            assert_eq!(graph.connections(1).count(), 1);
+            let cut = provider.get_region(1).await.unwrap().within(&bounds);
+            assert_eq!((cut.node_count(), vertex_ids(&cut)), (3, vec![1, 2]));
+            assert_eq!(cut.connections(1).count(), 1);
+            assert!(!cut.contains_node(4));
+
+            assert!("0,0,5".parse::<BoundingBox>().is_err());
+            assert!("5,0,0,0".parse::<BoundingBox>().is_err());
+        }
+
+        #[tokio::test]
+        async fn data_directories_are_a_storage_provider() {
+            let dir = generate_sample("two_regions");
+            let config = StorageConfig::Directory(dir.path().to_path_buf());
+            let provider = config.provider(WeightScale::default(), DataPolicy::default()).unwrap();
+            assert_eq!(provider.get_info(2).await.unwrap().group_id, 2);
+            assert_eq!(provider.get_region(1).await.unwrap().node_count(), 4);
+            assert!(provider.get_info(99).await.is_err());
+            assert!(config.snapshot_store().is_ok());
         }
 
         #[tokio::test]
         async fn test_graph() {
-            let provider = MockGraphProvider::new(PathBuf::from("res/"));
+            let dir = generate_sample("two_regions");
+            let provider = MockGraphProvider::new(dir.path().to_path_buf());
             let graph = provider.get_region(1).await.unwrap();
             assert_eq!(graph.region_idx, 1);
+            assert_eq!(graph.node_count(), 4);
+            let access: Vec<_> = [1, 4].iter().map(|id| graph.vertices().find(|vertex| vertex.id == *id).unwrap().access).collect();
+            assert_eq!(access, vec![Access::ALL, Access::new([Profile::Car])]);
+        }
+
+        #[tokio::test]
+        async fn test_super_regions() {
+            let dir = generate_sample("super_regions");
+            let vertices = std::fs::read_to_string(dir.path().join("vertices/vertices_2.csv")).unwrap();
+            assert!(vertices.contains("3,3,4,2.0,011110000011"));
+            let provider = MockGraphProvider::new(dir.path().to_path_buf());
+            let group_info = provider.get_info(1).await.unwrap();
+            assert_eq!(group_info.super_regions.get(&11), Some(&vec![3, 4]));
+            let mut graph = provider.get_region(2).await.unwrap();
+            graph.set_super_regions(Arc::new(SuperRegions::new(&group_info.super_regions).unwrap()));
+            let exits = graph.find_way(NodeInfo(3, 2), NodeInfo(5, 4), &Avoid::default(), &SearchLimits::default()).await.unwrap();
+            assert!(exits.iter().any(|exit| matches!(exit, PathResult::Continue(_, 2, Continuation::CRegionKnown(4, 3)))));
+        }
+
+        #[tokio::test]
+        async fn test_scaled_weights() {
+            let dir = generate_sample("two_regions");
+            let provider = MockGraphProvider::new(dir.path().to_path_buf()).with_weight_scale(WeightScale(100));
+            let graph = provider.get_region(2).await.unwrap();
+            let weight = graph.vertices().find(|vertex| vertex.id == 3).unwrap().weight;
+            assert_eq!(weight, 150);
+        }
+
+        #[tokio::test]
+        async fn regions_are_checked_against_the_manifest() {
+            let dir = generate_sample("two_regions");
+            let mut manifest = RegionManifest::new("2024-03-01");
+            for name in ["nodes/nodes_1.csv", "vertices/vertices_1.csv"] {
+                manifest.add(name.split('/').next_back().unwrap(), &std::fs::read(dir.path().join(name)).unwrap());
+            }
+            std::fs::write(dir.path().join("groups/manifest_1.json"), serde_json::to_vec(&manifest).unwrap()).unwrap();
+            let provider = MockGraphProvider::new(dir.path().to_path_buf());
+            assert_eq!(provider.dataset_version(), None);
+            provider.get_info(1).await.unwrap();
+            assert_eq!(provider.dataset_version().as_deref(), Some("2024-03-01"));
+            assert!(provider.get_region(1).await.is_ok());
+            // Region 2 isn't listed.
+            assert!(provider.get_region(2).await.unwrap_err().to_string().contains("not part of version 2024-03-01"));
+
+            std::fs::write(dir.path().join("nodes/nodes_1.csv"), "1,0,0,1\n").unwrap();
+            assert!(provider.get_region(1).await.unwrap_err().to_string().contains("doesn't match"));
         }
     }
 }
 
 
-pub mod gcloud {
-    use std::collections::HashMap;
-    use std::env;
-    use std::io::Error;
-    use std::io::ErrorKind::{NotFound};
-    use s3::{Bucket, Region};
-    use s3::creds::Credentials;
-    use crate::graph_provider::{Graph, GraphProvider, GroupInfo, GroupInfoProvider, Node, RawNode, RawVertex, Result, Vertex};
+/// Regions converted to the packed binary format, loaded into RAM much faster than from CSV. Every
+/// provider reads `region_<region>.pfr` instead of the CSV files of a region where it finds one.
+pub mod packed {
+    use std::io::{BufWriter, Write};
+    use std::path::{Path, PathBuf};
+    use crate::graph_provider::{Graph, Result};
+    use crate::graph::{RegionIdx, WeightScale};
+    use crate::packed;
+
+    /// File the region is kept in within a directory of regions.
+    pub fn region_path(dir_path: &Path, id: RegionIdx) -> PathBuf {
+        dir_path.join(packed::file_name(id))
+    }
+
+    /// Writes `graph`, loaded with `weight_scale`, to `path` in the packed format. Servers refuse
+    /// it unless they run with the same scale.
+    pub fn convert(graph: &Graph, weight_scale: WeightScale, path: &Path) -> Result<()> {
+        let mut out = BufWriter::new(std::fs::File::create(path)?);
+        packed::write(graph, weight_scale, &mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Regions converted to the memory mapped format, which are searched in place instead of being loaded into RAM.
+pub mod mapped {
+    use std::io::{BufWriter, Read, Write};
+    use std::path::{Path, PathBuf};
+    use crate::graph_provider::{Graph, GraphProvider, Result};
     use crate::graph::RegionIdx;
+    use crate::manifest::{EntryBuilder, FileEntry, RegionManifest};
+    use crate::mapped::{self, MappedGraph};
 
-    pub struct CloudStorageProvider {
-        bucket: Bucket,
+    /// File the region is kept in within a directory of mapped regions.
+    pub fn region_path(dir_path: &Path, id: RegionIdx) -> PathBuf {
+        dir_path.join(format!("region_{}.graph", id))
     }
 
-    impl CloudStorageProvider {
-        pub fn new(region: &str,
-                   bucket: &str,
-                   access_key: &str,
-                   secret_key: &str) -> Self {
-            let region = Region::Custom {
-                region: region.to_owned(),
-                endpoint: "http://storage.googleapis.com".to_owned(),
-            };
-            let bucket = Bucket::new(bucket,
-                                     region,
-                                     Credentials::new(
-                                         Some(access_key),
-                                         Some(secret_key),
-                                         None,
-                                         None,
-                                         None,
-                                     ).unwrap()).unwrap();
-            return Self {
-                bucket
-            };
+    /// Manifest of the files of a version within its directory of mapped regions.
+    pub fn manifest_path(dir_path: &Path) -> PathBuf {
+        dir_path.join("manifest.json")
+    }
+
+    fn file_entry(path: &Path) -> Result<FileEntry> {
+        let mut file = std::fs::File::open(path)?;
+        let mut builder = EntryBuilder::default();
+        let mut chunk = vec![0; 64 * 1024];
+        loop {
+            match file.read(&mut chunk)? {
+                0 => { return Ok(builder.finish()) }
+                read => { builder.update(&chunk[..read]) }
+            }
         }
+    }
 
-        pub fn from_env() -> Self {
-            Self::new(
-                &*env::var("GOOGLE_CLOUD_REGION").unwrap(),
-                &*env::var("GOOGLE_CLOUD_BUCKET").unwrap(),
-                &*env::var("GOOGLE_ACCESS_KEY").unwrap(),
-                &*env::var("GOOGLE_SECRET_KEY").unwrap(),
-            )
+    /// Lists the file of region `id` in the manifest of `version` within `dir_path`, the
+    /// directory of the version.
+    pub fn record(dir_path: &Path, version: &str, id: RegionIdx) -> Result<()> {
+        let manifest_path = manifest_path(dir_path);
+        let mut manifest = match std::fs::read(&manifest_path) {
+            Ok(raw) => { serde_json::from_slice(&raw)? }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => { RegionManifest::new(version) }
+            Err(err) => { return Err(err.into()) }
+        };
+        if manifest.version != version {
+            return Err(format!("{} lists version {} of the data set, not {}", manifest_path.display(), manifest.version, version).into());
         }
+        let path = region_path(dir_path, id);
+        manifest.files.insert(file_name(&path), file_entry(&path)?);
+        std::fs::write(manifest_path, serde_json::to_vec_pretty(&manifest)?)?;
+        Ok(())
     }
 
-    #[async_trait::async_trait]
-    impl GraphProvider for CloudStorageProvider {
-        async fn get_region(&self, id: RegionIdx) -> Result<Graph> {
-            log::info!("Retrieving region data {}", id);
-            let (nodes_data, return_code) = self.bucket.get_object(format!("nodes_{}.csv", id)).await?;
-            if !(200 <= return_code && return_code < 300) {
-                return Err(Box::new(Error::from(NotFound)));
-            }
+    fn file_name(path: &Path) -> String {
+        path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default()
+    }
 
-            let mut nodes_reader = csv::ReaderBuilder::new().has_headers(false).from_reader(&*nodes_data);
-            let mut nodes = HashMap::new();
-            let mut nodes_read = nodes_reader.deserialize::<RawNode>();
-            while let Some(record) = nodes_read.next() {
-                let raw_node = record?;
-                let node = Node::from(raw_node);
-                nodes.insert(node.id, node);
+    /// Writes `graph` to `path` in the memory mapped format. Weights are stored already scaled.
+    pub fn convert(graph: &Graph, path: &Path) -> Result<()> {
+        let mut out = BufWriter::new(std::fs::File::create(path)?);
+        mapped::write(graph, &mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Maps regions written by [`convert`] from a local directory. Regions of data sets with a
+    /// manifest are kept in a subdirectory per version, see [`MappedGraphProvider::for_version`].
+    pub struct MappedGraphProvider {
+        dir_path: PathBuf,
+        /// Version the files are checked against the manifest of, see [`record`].
+        version: Option<String>,
+    }
+
+    impl MappedGraphProvider {
+        pub fn new(dir_path: PathBuf) -> Self {
+            Self {
+                dir_path,
+                version: None,
             }
+        }
 
-            let (vertices_data, return_code) = self.bucket.get_object(format!("vertices_{}.csv", id)).await?;
-            if !(200 <= return_code && return_code < 300) {
-                return Err(Box::new(Error::from(NotFound)));
+        /// Provider of the regions of `version` of the data set, in the subdirectory named after it,
+        /// so a server reloading a new version never maps the files of the previous one again.
+        /// Files are only mapped as listed in the manifest of the subdirectory.
+        pub fn for_version(&self, version: Option<&str>) -> Self {
+            match version {
+                Some(version) => { Self { dir_path: self.dir_path.join(version), version: Some(version.to_string()) } }
+                None => { Self::new(self.dir_path.clone()) }
             }
+        }
 
-            let mut vertices_reader = csv::ReaderBuilder::new().has_headers(false).from_reader(&*vertices_data);
-            let mut vertices = HashMap::new();
-            let mut vertices_read = vertices_reader.deserialize::<RawVertex>();
-            while let Some(record) = vertices_read.next() {
-                let record = record?;
-                let vertex = Vertex::from(record);
-                nodes.get_mut(&vertex.a).map(|node| node.connections.push(vertex.id));
-                nodes.get_mut(&vertex.b).map(|node| node.connections.push(vertex.id));
-                vertices.insert(vertex.id, vertex);
+        fn verify(&self, version: &str, path: &Path) -> Result<()> {
+            let manifest_path = manifest_path(&self.dir_path);
+            let raw = std::fs::read(&manifest_path)
+                .map_err(|err| format!("Unable to read {}, mapped regions of version {} are listed there, details: {}", manifest_path.display(), version, err))?;
+            let manifest: RegionManifest = serde_json::from_slice(&raw)?;
+            if manifest.version != version {
+                return Err(format!("{} lists version {} of the data set, not {}", manifest_path.display(), manifest.version, version).into());
             }
+            manifest.check(&file_name(path), &file_entry(path)?)?;
+            Ok(())
+        }
 
-            return Ok(Graph::new(
-                nodes,
-                vertices,
-                id,
-            ));
+        pub fn has_region(&self, id: RegionIdx) -> bool {
+            region_path(&self.dir_path, id).exists()
         }
     }
 
     #[async_trait::async_trait]
-    impl GroupInfoProvider for CloudStorageProvider {
-        async fn get_info(&self, group_id: usize) -> Result<GroupInfo> {
-            let (group_raw, return_code) = self.bucket.get_object(format!("group_{}.json", group_id)).await?;
-            if !(200 <= return_code && return_code < 300) {
-                let body: String = String::from_utf8(group_raw).unwrap_or(String::from("???"));
-                log::error!("Cloud storage returned {}: {}", return_code, body);
-                return Err(Box::new(Error::from(NotFound)));
+    impl GraphProvider for MappedGraphProvider {
+        async fn get_region(&self, id: RegionIdx) -> Result<Graph> {
+            let path = region_path(&self.dir_path, id);
+            if let Some(version) = &self.version {
+                self.verify(version, &path)?;
+            }
+            let graph = Graph::mapped(MappedGraph::open(&path)?);
+            if graph.region_idx != id {
+                return Err(format!("File of region {} holds region {}", id, graph.region_idx).into());
             }
-            Ok(serde_json::from_slice::<GroupInfo>(&*group_raw)?)
+            Ok(graph)
         }
     }
 
     #[cfg(test)]
     mod test {
-        use crate::graph_provider::gcloud::CloudStorageProvider;
-        use crate::{GraphProvider, GroupInfoProvider};
+        use crate::domain::NodeInfo;
+        use crate::fixtures::generate_sample;
+        use crate::graph::{Avoid, PathResult, SearchLimits};
+        use crate::graph_provider::mapped::{convert, record, region_path, MappedGraphProvider};
+        use crate::mapped::MappedGraph;
+        use crate::graph_provider::mock::MockGraphProvider;
+        use crate::heuristic::Zero;
+        use crate::GraphProvider;
 
         #[tokio::test]
-        async fn test_get_group() {
-            let cloud = CloudStorageProvider::from_env();
-            cloud.get_info(2).await.unwrap();
-            cloud.get_region(1).await.unwrap();
+        async fn mapped_regions_match_loaded_ones() {
+            let dir = generate_sample("two_regions");
+            let loaded = MockGraphProvider::new(dir.path().to_path_buf());
+            for region in [1, 2] {
+                convert(&loaded.get_region(region).await.unwrap(), &region_path(dir.path(), region)).unwrap();
+            }
+            let provider = MappedGraphProvider::new(dir.path().to_path_buf());
+            assert!(provider.has_region(1) && !provider.has_region(3));
+            for region in [1, 2] {
+                let expected = loaded.get_region(region).await.unwrap();
+                let graph = provider.get_region(region).await.unwrap();
+                assert_eq!(graph.node_count(), expected.node_count());
+                for node in expected.nodes() {
+                    let mapped = graph.get_node(node.id).unwrap();
+                    assert_eq!((mapped.region, mapped.coordinates()), (node.region, node.coordinates()));
+                    let mut connections: Vec<_> = graph.connections(node.id).collect();
+                    connections.sort();
+                    let mut expected_connections = node.connections.clone();
+                    expected_connections.sort();
+                    assert_eq!(connections, expected_connections);
+                }
+                for vertex in expected.vertices() {
+                    let mapped = graph.get_vertex(vertex.id).unwrap();
+                    assert_eq!((mapped.a, mapped.b, mapped.weight, mapped.access), (vertex.a, vertex.b, vertex.weight, vertex.access));
+                    let bits: Vec<_> = (0..vertex.region_bits.len()).map(|idx| mapped.region_bits.get(idx)).collect();
+                    let expected_bits: Vec<_> = (0..vertex.region_bits.len()).map(|idx| vertex.region_bits.get(idx)).collect();
+                    assert_eq!(bits, expected_bits);
+                }
+            }
+
+            let graph = provider.get_region(1).await.unwrap();
+            let expected = loaded.get_region(1).await.unwrap();
+            let mut found = Vec::new();
+            for graph in [graph, expected] {
+                match graph.find_way_local(NodeInfo(1, 1), NodeInfo(2, 1), &Zero, &Avoid::default(), &SearchLimits::default()).await.unwrap() {
+                    PathResult::TargetReached(path, cost) => { found.push((path, cost)) }
+                    PathResult::Continue(..) => { panic!("Target should be reached") }
+                }
+            }
+            assert_eq!(found[0], found[1]);
+
+            std::fs::write(region_path(dir.path(), 3), b"PFGRAPH1").unwrap();
+            assert!(provider.get_region(3).await.is_err());
+
+            // Damaged records are refused when the file is opened, not read out of bounds later.
+            let raw = std::fs::read(region_path(dir.path(), 1)).unwrap();
+            let node_count = u64::from_le_bytes(raw[16..24].try_into().unwrap()) as usize;
+            let damaged = |word: usize, value: u64| {
+                let mut raw = raw.clone();
+                raw[word * 8..word * 8 + 8].copy_from_slice(&value.to_le_bytes());
+                std::fs::write(region_path(dir.path(), 3), raw).unwrap();
+                MappedGraph::open(&region_path(dir.path(), 3)).unwrap_err().to_string()
+            };
+            assert!(damaged(3, u64::MAX).contains("truncated"));
+            assert!(damaged(8, u64::MAX).contains("out of order"));
+            assert!(damaged(8 + 5, u64::MAX).contains("connections of node"));
+            assert!(damaged(8 + 6 * node_count + 5, u64::MAX).contains("region bits"));
+
+            // Versions of the data set are mapped from their own subdirectories, as their manifests list them.
+            let versioned = provider.for_version(Some("v2"));
+            assert!(!versioned.has_region(1));
+            std::fs::create_dir(dir.path().join("v2")).unwrap();
+            convert(&loaded.get_region(1).await.unwrap(), &region_path(&dir.path().join("v2"), 1)).unwrap();
+            assert!(versioned.has_region(1) && !versioned.has_region(2));
+            assert!(provider.for_version(None).has_region(2));
+            assert!(versioned.get_region(1).await.unwrap_err().to_string().contains("manifest.json"));
+            record(&dir.path().join("v2"), "v2", 1).unwrap();
+            assert_eq!(versioned.get_region(1).await.unwrap().node_count(), loaded.get_region(1).await.unwrap().node_count());
+            assert!(record(&dir.path().join("v2"), "v3", 1).is_err());
+            let mut changed = std::fs::read(region_path(&dir.path().join("v2"), 1)).unwrap();
+            changed.push(0);
+            std::fs::write(region_path(&dir.path().join("v2"), 1), changed).unwrap();
+            assert!(versioned.get_region(1).await.unwrap_err().to_string().contains("doesn't match version v2"));
+        }
+    }
+}
+
+/// Regions, groups and snapshots kept in an S3 compatible bucket, as `nodes_<region>.csv`,
+/// `vertices_<region>.csv`, `group_<group id>.json` and `snapshots/<name>`.
+mod bucket {
+    use std::io::Error;
+    use std::io::ErrorKind::NotFound;
+    use s3::Bucket;
+    use crate::data_quality::{DataPolicy, GraphBuilder, QualityStats};
+    use crate::graph_provider::{bounded, Graph, GroupInfo, RawNode, RawVertex, Result, RetryPolicy};
+    use crate::compression;
+    use crate::gcs_auth::AccessTokens;
+    use crate::graph::{BoundingBox, RegionIdx, WeightScale};
+    use crate::manifest::{self, ManifestSlot, RegionManifest};
+    use crate::packed;
+    use crate::snapshot::NetworkSnapshot;
+
+    /// Bucket whose requests are retried according to `retry`. With `tokens` requests carry an
+    /// access token instead of being signed.
+    pub(super) struct RetryingBucket {
+        bucket: Bucket,
+        pub(super) retry: RetryPolicy,
+        pub(super) tokens: Option<AccessTokens>,
+    }
+
+    impl RetryingBucket {
+        pub(super) fn new(bucket: Bucket, retry: RetryPolicy) -> Self {
+            Self {
+                bucket,
+                retry,
+                tokens: None,
+            }
+        }
+
+        /// The bucket with the current access token, if requests carry one.
+        async fn authorized(&self) -> std::result::Result<Bucket, String> {
+            let mut bucket = self.bucket.clone();
+            if let Some(tokens) = self.tokens.as_ref() {
+                let token = tokens.token().await.map_err(|err| format!("no access token ({})", err))?;
+                bucket.add_header("Authorization", &format!("Bearer {}", token));
+            }
+            Ok(bucket)
+        }
+
+        pub(super) async fn get_object(&self, path: impl AsRef<str>) -> std::io::Result<(Vec<u8>, u16)> {
+            let path = path.as_ref();
+            self.retry.run(path, || async move {
+                self.authorized().await?.get_object(path).await.map_err(|err| err.to_string())
+            }).await
+        }
+
+        pub(super) async fn put_object(&self, path: impl AsRef<str>, content: &[u8]) -> std::io::Result<(Vec<u8>, u16)> {
+            let path = path.as_ref();
+            self.retry.run(path, || async move {
+                self.authorized().await?.put_object(path, content).await.map_err(|err| err.to_string())
+            }).await
+        }
+
+        /// Region file `name` checked against the manifest, `None` if the bucket doesn't hold it.
+        /// With a manifest files it doesn't list aren't asked for.
+        async fn get_checked(&self, name: &str, manifest: &ManifestSlot) -> Result<Option<Vec<u8>>> {
+            let manifest = manifest.get();
+            if manifest.as_deref().is_some_and(|manifest| !manifest.files.contains_key(name)) {
+                return Ok(None);
+            }
+            let (data, return_code) = self.get_object(name).await?;
+            match return_code {
+                200..=299 => {}
+                404 => { return Ok(None) }
+                _ => { return Err(format!("Fetching {} failed with status {}", name, return_code).into()) }
+            }
+            if let Some(manifest) = manifest.as_deref() {
+                manifest.verify(name, &data)?;
+            }
+            Ok(Some(data))
+        }
+    }
+
+    /// Content of the CSV file `name`, from the compressed objects `<name>.zst` or `<name>.gz` if
+    /// the bucket holds one of them. Objects are decompressed by their content, whatever their name.
+    async fn get_csv(bucket: &RetryingBucket, manifest: &ManifestSlot, name: &str) -> Result<Vec<u8>> {
+        let compressed = compression::extensions().iter().map(|extension| format!("{}.{}", name, extension));
+        for candidate in compressed.chain([name.to_string()]) {
+            if let Some(data) = bucket.get_checked(&candidate, manifest).await? {
+                return Ok(compression::decompress(data)?);
+            }
+        }
+        Err(Box::new(Error::from(NotFound)))
+    }
+
+    /// Reads the packed file of the region if there is one, its CSV files otherwise, downloading both
+    /// at once and checking them against the manifest of the group. Only the part within `bounds` is kept.
+    pub(super) async fn get_region(bucket: &RetryingBucket,
+                                   manifest: &ManifestSlot,
+                                   id: RegionIdx,
+                                   weight_scale: WeightScale,
+                                   data_policy: DataPolicy,
+                                   quality_stats: &QualityStats,
+                                   bounds: Option<&BoundingBox>) -> Result<Graph> {
+        log::info!("Retrieving region data {}", id);
+        if let Some(packed_data) = bucket.get_checked(&packed::file_name(id), manifest).await? {
+            return Ok(bounded(packed::read(&compression::decompress(packed_data)?, id, weight_scale)?, bounds));
+        }
+
+        let (nodes_name, vertices_name) = (format!("nodes_{}.csv", id), format!("vertices_{}.csv", id));
+        // Failures are kept as messages, a boxed error held while the other download runs isn't Send.
+        let (nodes_data, vertices_data) = tokio::try_join!(
+            async { get_csv(bucket, manifest, &nodes_name).await.map_err(|err| err.to_string()) },
+            async { get_csv(bucket, manifest, &vertices_name).await.map_err(|err| err.to_string()) },
+        )?;
+        let mut builder = GraphBuilder::new(id, weight_scale, data_policy, quality_stats).with_bounds(bounds);
+        let mut nodes_reader = csv::ReaderBuilder::new().has_headers(false).from_reader(&*nodes_data);
+        for record in nodes_reader.deserialize::<RawNode>() {
+            builder.add_node(record)?;
+        }
+
+        let mut vertices_reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(&*vertices_data);
+        for record in vertices_reader.deserialize::<RawVertex>() {
+            builder.add_vertex(record)?;
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// Reads the group and its manifest, which the regions loaded afterwards are checked against.
+    pub(super) async fn get_info(bucket: &RetryingBucket, manifest: &ManifestSlot, group_id: usize) -> Result<GroupInfo> {
+        let (group_raw, return_code) = bucket.get_object(format!("group_{}.json", group_id)).await?;
+        if !(200..300).contains(&return_code) {
+            let body: String = String::from_utf8(group_raw).unwrap_or(String::from("???"));
+            log::error!("Cloud storage returned {}: {}", return_code, body);
+            return Err(Box::new(Error::from(NotFound)));
+        }
+        load_manifest(bucket, manifest, group_id).await?;
+        Ok(serde_json::from_slice::<GroupInfo>(&group_raw)?)
+    }
+
+    async fn load_manifest(bucket: &RetryingBucket, manifest: &ManifestSlot, group_id: usize) -> Result<()> {
+        let (manifest_raw, return_code) = bucket.get_object(manifest::file_name(group_id)).await?;
+        match return_code {
+            200..=299 => { manifest.set(Some(serde_json::from_slice::<RegionManifest>(&manifest_raw)?)) }
+            404 => { manifest.set(None) }
+            _ => { return Err(format!("Fetching the manifest of group {} failed with status {}", group_id, return_code).into()) }
         }
+        Ok(())
     }
-}
\ No newline at end of file
+
+    pub(super) async fn put_region(bucket: &RetryingBucket, manifest: &ManifestSlot, graph: &Graph, weight_scale: WeightScale) -> Result<()> {
+        let name = packed::file_name(graph.region_idx);
+        manifest.check_writable(&name)?;
+        let mut content = vec![];
+        packed::write(graph, weight_scale, &mut content)?;
+        put(bucket, &name, &content).await
+    }
+
+    pub(super) async fn put_info(bucket: &RetryingBucket, manifest: &ManifestSlot, group_info: &GroupInfo) -> Result<()> {
+        let name = format!("group_{}.json", group_info.group_id);
+        load_manifest(bucket, manifest, group_info.group_id).await?;
+        manifest.check_writable(&name)?;
+        put(bucket, &name, &serde_json::to_vec_pretty(group_info)?).await
+    }
+
+    async fn put(bucket: &RetryingBucket, name: &str, content: &[u8]) -> Result<()> {
+        let (_, return_code) = bucket.put_object(name, content).await?;
+        if !(200..300).contains(&return_code) {
+            return Err(format!("Storing {} failed with status {}", name, return_code).into());
+        }
+        log::info!("Stored {}", name);
+        Ok(())
+    }
+
+    pub(super) async fn save_snapshot(bucket: &RetryingBucket, snapshot: &NetworkSnapshot) -> std::result::Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let (_, return_code) = bucket.put_object(format!("snapshots/{}", snapshot.name()), &serde_json::to_vec(snapshot)?).await?;
+        if !(200..300).contains(&return_code) {
+            return Err(format!("Storing snapshot {} failed with status {}", snapshot.name(), return_code).into());
+        }
+        Ok(snapshot.name())
+    }
+
+    pub(super) async fn load_snapshot(bucket: &RetryingBucket, name: &str) -> std::result::Result<NetworkSnapshot, Box<dyn std::error::Error + Send + Sync>> {
+        let (raw, return_code) = bucket.get_object(format!("snapshots/{}", name)).await?;
+        if !(200..300).contains(&return_code) {
+            return Err(Box::new(Error::from(NotFound)));
+        }
+        Ok(serde_json::from_slice(&raw)?)
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+        use crate::graph_provider::RetryPolicy;
+
+        #[tokio::test]
+        async fn failed_requests_are_retried() {
+            let retry = RetryPolicy {
+                retries: 2,
+                backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(2),
+                timeout: Duration::from_millis(50),
+            };
+            let attempts = AtomicUsize::new(0);
+            // Times out, then fails with a server error, then succeeds.
+            let response = retry.run("nodes_1.csv", || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => { tokio::time::sleep(Duration::from_secs(1)).await }
+                    1 => { return Ok((vec![], 503)) }
+                    _ => {}
+                }
+                Ok::<_, std::io::Error>((b"id".to_vec(), 200))
+            }).await.unwrap();
+            assert_eq!((response, attempts.load(Ordering::SeqCst)), ((b"id".to_vec(), 200), 3));
+
+            // Missing objects aren't asked for again.
+            attempts.store(0, Ordering::SeqCst);
+            let response = retry.run("nodes_2.csv", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, std::io::Error>((vec![], 404))
+            }).await.unwrap();
+            assert_eq!((response.1, attempts.load(Ordering::SeqCst)), (404, 1));
+
+            attempts.store(0, Ordering::SeqCst);
+            let failed = retry.run("nodes_3.csv", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err::<(Vec<u8>, u16), _>(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset"))
+            }).await.unwrap_err();
+            assert_eq!(attempts.load(Ordering::SeqCst), 3);
+            assert!(failed.to_string().contains("failed 3 times"));
+        }
+    }
+}
+
+pub mod gcloud {
+    use std::env;
+    use std::sync::Arc;
+    use s3::{Bucket, Region};
+    use s3::creds::Credentials;
+    use crate::data_quality::{DataPolicy, QualityStats};
+    use crate::graph_provider::{bucket, Graph, GraphProvider, GroupInfo, GroupInfoProvider, Result, RetryPolicy, StorageProvider};
+    use crate::graph_provider::bucket::RetryingBucket;
+    use crate::gcs_auth::{AccessTokens, GcsCredentials};
+    use crate::manifest::ManifestSlot;
+    use crate::graph::{BoundingBox, RegionIdx, WeightScale};
+    use crate::snapshot::{NetworkSnapshot, SnapshotStore};
+
+    pub struct CloudStorageProvider {
+        bucket: RetryingBucket,
+        manifest: ManifestSlot,
+        weight_scale: WeightScale,
+        data_policy: DataPolicy,
+        quality_stats: Arc<QualityStats>,
+    }
+
+    impl CloudStorageProvider {
+        pub fn new(region: &str,
+                   bucket: &str,
+                   access_key: &str,
+                   secret_key: &str) -> Self {
+            Self::with_credentials(region, bucket, &GcsCredentials::Hmac {
+                access_key: access_key.to_owned(),
+                secret_key: secret_key.to_owned(),
+            })
+        }
+
+        /// Reads the bucket with HMAC keys, or with access tokens of the other credentials.
+        pub fn with_credentials(region: &str, bucket: &str, credentials: &GcsCredentials) -> Self {
+            let region = Region::Custom {
+                region: region.to_owned(),
+                endpoint: "http://storage.googleapis.com".to_owned(),
+            };
+            let (keys, tokens) = match credentials {
+                GcsCredentials::Hmac { access_key, secret_key } => {
+                    (Credentials::new(Some(access_key), Some(secret_key), None, None, None).unwrap(), None)
+                }
+                other => { (Credentials::anonymous().unwrap(), Some(AccessTokens::new(other.clone()))) }
+            };
+            let mut bucket = RetryingBucket::new(Bucket::new(bucket, region, keys).unwrap(), RetryPolicy::default());
+            bucket.tokens = tokens;
+            Self {
+                bucket,
+                manifest: ManifestSlot::default(),
+                weight_scale: WeightScale::default(),
+                data_policy: DataPolicy::default(),
+                quality_stats: Arc::default(),
+            }
+        }
+
+        pub fn with_weight_scale(mut self, weight_scale: WeightScale) -> Self {
+            self.weight_scale = weight_scale;
+            self
+        }
+
+        pub fn with_data_policy(mut self, data_policy: DataPolicy) -> Self {
+            self.data_policy = data_policy;
+            self
+        }
+
+        pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+            self.bucket.retry = retry;
+            self
+        }
+
+        /// Records skipped or repaired in all regions loaded so far.
+        pub fn quality_stats(&self) -> Arc<QualityStats> {
+            self.quality_stats.clone()
+        }
+
+        pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(Self::with_credentials(
+                &env::var("GOOGLE_CLOUD_REGION")?,
+                &env::var("GOOGLE_CLOUD_BUCKET")?,
+                &GcsCredentials::from_env()?,
+            ).with_retry_policy(RetryPolicy::from_env()?))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GraphProvider for CloudStorageProvider {
+        async fn get_region(&self, id: RegionIdx) -> Result<Graph> {
+            bucket::get_region(&self.bucket, &self.manifest, id, self.weight_scale, self.data_policy, &self.quality_stats, None).await
+        }
+
+        async fn get_region_within(&self, id: RegionIdx, bounds: &BoundingBox) -> Result<Graph> {
+            bucket::get_region(&self.bucket, &self.manifest, id, self.weight_scale, self.data_policy, &self.quality_stats, Some(bounds)).await
+        }
+
+        async fn put_region(&self, graph: &Graph) -> Result<()> {
+            bucket::put_region(&self.bucket, &self.manifest, graph, self.weight_scale).await
+        }
+    }
+
+    /// Snapshots are kept in the bucket holding the regions, under `snapshots/`.
+    #[async_trait::async_trait]
+    impl SnapshotStore for CloudStorageProvider {
+        async fn save(&self, snapshot: &NetworkSnapshot) -> std::result::Result<String, Box<dyn std::error::Error + Send + Sync>> {
+            bucket::save_snapshot(&self.bucket, snapshot).await
+        }
+
+        async fn load(&self, name: &str) -> std::result::Result<NetworkSnapshot, Box<dyn std::error::Error + Send + Sync>> {
+            bucket::load_snapshot(&self.bucket, name).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GroupInfoProvider for CloudStorageProvider {
+        async fn get_info(&self, group_id: usize) -> Result<GroupInfo> {
+            bucket::get_info(&self.bucket, &self.manifest, group_id).await
+        }
+
+        async fn put_info(&self, group_info: &GroupInfo) -> Result<()> {
+            bucket::put_info(&self.bucket, &self.manifest, group_info).await
+        }
+    }
+
+    impl StorageProvider for CloudStorageProvider {
+        fn quality_stats(&self) -> Arc<QualityStats> {
+            self.quality_stats.clone()
+        }
+
+        fn dataset_version(&self) -> Option<String> {
+            self.manifest.version()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::graph_provider::gcloud::CloudStorageProvider;
+        use crate::{GraphProvider, GroupInfoProvider};
+
+        #[tokio::test]
+        async fn test_get_group() {
+            let cloud = CloudStorageProvider::from_env().unwrap();
+            cloud.get_info(2).await.unwrap();
+            cloud.get_region(1).await.unwrap();
+        }
+    }
+}
+/// Regions kept in Amazon S3, or any other S3 compatible store such as MinIO.
+pub mod s3 {
+    use std::env;
+    use std::sync::Arc;
+    use s3::{Bucket, Region};
+    use s3::creds::Credentials;
+    use crate::data_quality::{DataPolicy, QualityStats};
+    use crate::graph_provider::{bucket, Graph, GraphProvider, GroupInfo, GroupInfoProvider, Result, RetryPolicy, StorageProvider};
+    use crate::graph_provider::bucket::RetryingBucket;
+    use crate::manifest::ManifestSlot;
+    use crate::graph::{BoundingBox, RegionIdx, WeightScale};
+    use crate::snapshot::{NetworkSnapshot, SnapshotStore};
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct S3Config {
+        pub bucket: String,
+        pub region: String,
+        /// The regional AWS endpoint if not set.
+        pub endpoint: Option<String>,
+        /// Addresses the bucket as `<endpoint>/<bucket>` instead of `<bucket>.<endpoint>`, as most
+        /// self-hosted stores expect.
+        pub path_style: bool,
+        /// Keys of the bucket owner. Without them credentials are looked up like other AWS tools do:
+        /// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, the default profile, and finally the IAM role
+        /// of the instance.
+        pub access_key: Option<String>,
+        pub secret_key: Option<String>,
+        pub retry: RetryPolicy,
+    }
+
+    impl S3Config {
+        pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+            let access_key = env::var("S3_ACCESS_KEY").ok();
+            let secret_key = env::var("S3_SECRET_KEY").ok();
+            if access_key.is_some() != secret_key.is_some() {
+                Err("S3_ACCESS_KEY and S3_SECRET_KEY have to be set together")?
+            }
+            Ok(Self {
+                bucket: env::var("S3_BUCKET")?,
+                region: env::var("S3_REGION")?,
+                endpoint: env::var("S3_ENDPOINT").ok(),
+                path_style: match env::var("S3_PATH_STYLE") {
+                    Ok(enabled) => { enabled.parse()? }
+                    Err(_) => { false }
+                },
+                access_key,
+                secret_key,
+                retry: RetryPolicy::from_env()?,
+            })
+        }
+
+        pub fn endpoint(&self) -> String {
+            match &self.endpoint {
+                Some(endpoint) => { endpoint.clone() }
+                None => { format!("https://s3.{}.amazonaws.com", self.region) }
+            }
+        }
+    }
+
+    pub struct S3Provider {
+        bucket: RetryingBucket,
+        manifest: ManifestSlot,
+        weight_scale: WeightScale,
+        data_policy: DataPolicy,
+        quality_stats: Arc<QualityStats>,
+    }
+
+    impl S3Provider {
+        pub fn new(config: &S3Config) -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+            let region = Region::Custom {
+                region: config.region.clone(),
+                endpoint: config.endpoint(),
+            };
+            let credentials = match (&config.access_key, &config.secret_key) {
+                (Some(access_key), Some(secret_key)) => {
+                    Credentials::new(Some(access_key), Some(secret_key), None, None, None)?
+                }
+                _ => { Credentials::default()? }
+            };
+            let bucket = if config.path_style {
+                Bucket::new_with_path_style(&config.bucket, region, credentials)?
+            } else {
+                Bucket::new(&config.bucket, region, credentials)?
+            };
+            Ok(Self {
+                bucket: RetryingBucket::new(bucket, config.retry),
+                manifest: ManifestSlot::default(),
+                weight_scale: WeightScale::default(),
+                data_policy: DataPolicy::default(),
+                quality_stats: Arc::default(),
+            })
+        }
+
+        pub fn with_weight_scale(mut self, weight_scale: WeightScale) -> Self {
+            self.weight_scale = weight_scale;
+            self
+        }
+
+        pub fn with_data_policy(mut self, data_policy: DataPolicy) -> Self {
+            self.data_policy = data_policy;
+            self
+        }
+
+        /// Records skipped or repaired in all regions loaded so far.
+        pub fn quality_stats(&self) -> Arc<QualityStats> {
+            self.quality_stats.clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GraphProvider for S3Provider {
+        async fn get_region(&self, id: RegionIdx) -> Result<Graph> {
+            bucket::get_region(&self.bucket, &self.manifest, id, self.weight_scale, self.data_policy, &self.quality_stats, None).await
+        }
+
+        async fn get_region_within(&self, id: RegionIdx, bounds: &BoundingBox) -> Result<Graph> {
+            bucket::get_region(&self.bucket, &self.manifest, id, self.weight_scale, self.data_policy, &self.quality_stats, Some(bounds)).await
+        }
+
+        async fn put_region(&self, graph: &Graph) -> Result<()> {
+            bucket::put_region(&self.bucket, &self.manifest, graph, self.weight_scale).await
+        }
+    }
+
+    /// Snapshots are kept in the bucket holding the regions, under `snapshots/`.
+    #[async_trait::async_trait]
+    impl SnapshotStore for S3Provider {
+        async fn save(&self, snapshot: &NetworkSnapshot) -> std::result::Result<String, Box<dyn std::error::Error + Send + Sync>> {
+            bucket::save_snapshot(&self.bucket, snapshot).await
+        }
+
+        async fn load(&self, name: &str) -> std::result::Result<NetworkSnapshot, Box<dyn std::error::Error + Send + Sync>> {
+            bucket::load_snapshot(&self.bucket, name).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GroupInfoProvider for S3Provider {
+        async fn get_info(&self, group_id: usize) -> Result<GroupInfo> {
+            bucket::get_info(&self.bucket, &self.manifest, group_id).await
+        }
+
+        async fn put_info(&self, group_info: &GroupInfo) -> Result<()> {
+            bucket::put_info(&self.bucket, &self.manifest, group_info).await
+        }
+    }
+
+    impl StorageProvider for S3Provider {
+        fn quality_stats(&self) -> Arc<QualityStats> {
+            self.quality_stats.clone()
+        }
+
+        fn dataset_version(&self) -> Option<String> {
+            self.manifest.version()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::graph_provider::RetryPolicy;
+        use crate::graph_provider::s3::S3Config;
+
+        #[test]
+        fn endpoints() {
+            let mut config = S3Config {
+                bucket: "regions".to_string(),
+                region: "eu-central-1".to_string(),
+                endpoint: None,
+                path_style: false,
+                access_key: None,
+                secret_key: None,
+                retry: RetryPolicy::default(),
+            };
+            assert_eq!(config.endpoint(), "https://s3.eu-central-1.amazonaws.com");
+            config.endpoint = Some("http://minio:9000".to_string());
+            assert_eq!(config.endpoint(), "http://minio:9000");
+        }
+    }
+}
+
+/// Regions served by any HTTP(S) server, e.g. a CDN in front of the data set, as
+/// `<base url>/nodes_<region>.csv`, `<base url>/vertices_<region>.csv` and `<base url>/group_<group id>.json`,
+/// or packed as `<base url>/region_<region>.pfr`.
+///
+/// Downloads are kept in a local cache directory with their `ETag` and `Last-Modified` headers.
+/// Files are only downloaded again if the server says they changed, so restarting a server doesn't
+/// download its regions again.
+pub mod http {
+    use std::env;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use futures_util::StreamExt;
+    use reqwest::StatusCode;
+    use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+    use serde::{Serialize, Deserialize};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::compression::{self, Compression};
+    use crate::data_quality::{DataPolicy, GraphBuilder, QualityStats};
+    use crate::graph_provider::{bounded, Graph, GraphProvider, GroupInfo, GroupInfoProvider, RawNode, RawVertex, Result, StorageProvider};
+    use crate::graph::{BoundingBox, RegionIdx, WeightScale};
+    use crate::manifest::{self, EntryBuilder, FileEntry, ManifestSlot, RegionManifest};
+    use crate::packed;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct HttpConfig {
+        pub base_url: String,
+        pub cache_dir: PathBuf,
+    }
+
+    impl HttpConfig {
+        pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(Self {
+                base_url: env::var("HTTP_BASE_URL")?,
+                cache_dir: PathBuf::from(env::var("HTTP_CACHE_DIR").unwrap_or_else(|_| "region_cache".to_string())),
+            })
+        }
+    }
+
+    /// Validators of a cached download, sent back to the server to learn whether it changed.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+    struct CacheEntry {
+        etag: Option<String>,
+        last_modified: Option<String>,
+        /// Of the file as downloaded, before decompressing it.
+        #[serde(default)]
+        file: Option<FileEntry>,
+    }
+
+    pub struct HttpGraphProvider {
+        client: reqwest::Client,
+        config: HttpConfig,
+        manifest: ManifestSlot,
+        weight_scale: WeightScale,
+        data_policy: DataPolicy,
+        quality_stats: Arc<QualityStats>,
+    }
+
+    impl HttpGraphProvider {
+        pub fn new(config: HttpConfig) -> Self {
+            Self {
+                client: reqwest::Client::new(),
+                config,
+                manifest: ManifestSlot::default(),
+                weight_scale: WeightScale::default(),
+                data_policy: DataPolicy::default(),
+                quality_stats: Arc::default(),
+            }
+        }
+
+        pub fn with_weight_scale(mut self, weight_scale: WeightScale) -> Self {
+            self.weight_scale = weight_scale;
+            self
+        }
+
+        pub fn with_data_policy(mut self, data_policy: DataPolicy) -> Self {
+            self.data_policy = data_policy;
+            self
+        }
+
+        /// Records skipped or repaired in all regions loaded so far.
+        pub fn quality_stats(&self) -> Arc<QualityStats> {
+            self.quality_stats.clone()
+        }
+
+        /// Path of the current version of `name` in the cache, downloaded only if it changed. `None`
+        /// if the server has no such file. With a `manifest` the file has to match its listing.
+        async fn fetch(&self, name: &str, manifest: Option<&RegionManifest>) -> Result<Option<PathBuf>> {
+            tokio::fs::create_dir_all(&self.config.cache_dir).await?;
+            let path = self.config.cache_dir.join(name);
+            let meta_path = self.config.cache_dir.join(format!("{}.meta.json", name));
+            let cached = match tokio::fs::read(&meta_path).await {
+                Ok(raw) if path.exists() => { serde_json::from_slice::<CacheEntry>(&raw).ok() }
+                _ => { None }
+            };
+            // Files cached before manifests were checked are downloaded again to check them.
+            let cached = cached.filter(|cached| manifest.is_none() || cached.file.is_some());
+
+            let url = format!("{}/{}", self.config.base_url.trim_end_matches('/'), name);
+            let mut request = self.client.get(&url);
+            if let Some(cached) = cached.as_ref() {
+                if let Some(etag) = cached.etag.as_ref() {
+                    request = request.header(IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = cached.last_modified.as_ref() {
+                    request = request.header(IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+            let mut response = request.send().await?;
+            if let (StatusCode::NOT_MODIFIED, Some(cached)) = (response.status(), cached.as_ref()) {
+                if let (Some(manifest), Some(file)) = (manifest, cached.file.as_ref()) {
+                    manifest.check(name, file)?;
+                }
+                log::info!("Using cached {}, unchanged since it was downloaded", name);
+                return Ok(Some(path));
+            }
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            if !response.status().is_success() {
+                return Err(format!("Fetching {} failed with status {}", url, response.status()).into());
+            }
+
+            let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+            let mut entry = CacheEntry {
+                etag: header(ETAG),
+                last_modified: header(LAST_MODIFIED),
+                file: None,
+            };
+            log::info!("Downloading {}", url);
+            // Written aside first, so an interrupted download never passes for a complete one.
+            let partial_path = self.config.cache_dir.join(format!("{}.part", name));
+            let mut file = tokio::fs::File::create(&partial_path).await?;
+            let mut downloaded = EntryBuilder::default();
+            while let Some(chunk) = response.chunk().await? {
+                downloaded.update(&chunk);
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            drop(file);
+            let downloaded = downloaded.finish();
+            if let Some(manifest) = manifest {
+                manifest.check(name, &downloaded)?;
+            }
+            entry.file = Some(downloaded);
+            // Cached decompressed, so regions are parsed straight from the file on every start.
+            if is_compressed(&partial_path).await? {
+                let content = compression::decompress(tokio::fs::read(&partial_path).await?)?;
+                tokio::fs::write(&partial_path, content).await?;
+            }
+            tokio::fs::rename(&partial_path, &path).await?;
+            tokio::fs::write(&meta_path, serde_json::to_vec(&entry)?).await?;
+            Ok(Some(path))
+        }
+
+        /// Like [`HttpGraphProvider::fetch`], but the file has to exist.
+        async fn fetch_existing(&self, name: &str, manifest: Option<&RegionManifest>) -> Result<PathBuf> {
+            match self.fetch(name, manifest).await? {
+                Some(path) => { Ok(path) }
+                None => { Err(format!("{} not found at {}", name, self.config.base_url).into()) }
+            }
+        }
+
+        /// Fetches the CSV file `name`, or `<name>.zst` or `<name>.gz` instead if the server has one.
+        async fn fetch_csv(&self, name: &str, manifest: Option<&RegionManifest>) -> Result<PathBuf> {
+            for extension in compression::extensions() {
+                let path = self.fetch(&format!("{}.{}", name, extension), manifest).await?;
+                if let Some(path) = path {
+                    return Ok(path);
+                }
+            }
+            self.fetch_existing(name, manifest).await
+        }
+    }
+
+    async fn is_compressed(path: &Path) -> Result<bool> {
+        let mut header = vec![];
+        tokio::fs::File::open(path).await?.take(4).read_to_end(&mut header).await?;
+        Ok(Compression::detect(&header).is_some())
+    }
+
+    impl HttpGraphProvider {
+        /// CSV rows outside `bounds` are skipped while reading, packed regions are cut once read.
+        async fn read_region(&self, id: RegionIdx, bounds: Option<&BoundingBox>) -> Result<Graph> {
+            let manifest = self.manifest.get();
+            let packed_path = self.fetch(&packed::file_name(id), manifest.as_deref()).await?;
+            if let Some(packed_path) = packed_path {
+                return Ok(bounded(packed::read(&tokio::fs::read(packed_path).await?, id, self.weight_scale)?, bounds));
+            }
+            let (nodes_name, vertices_name) = (format!("nodes_{}.csv", id), format!("vertices_{}.csv", id));
+            // Failures are kept as messages, a boxed error held while the other download runs isn't Send.
+            let (nodes_path, vertices_path) = tokio::try_join!(
+                async { self.fetch_csv(&nodes_name, manifest.as_deref()).await.map_err(|err| err.to_string()) },
+                async { self.fetch_csv(&vertices_name, manifest.as_deref()).await.map_err(|err| err.to_string()) },
+            )?;
+
+            let mut builder = GraphBuilder::new(id, self.weight_scale, self.data_policy, &self.quality_stats).with_bounds(bounds);
+            let nodes_file = tokio::fs::File::open(nodes_path).await?;
+            let mut nodes_reader = csv_async::AsyncReaderBuilder::new().has_headers(false).create_deserializer(nodes_file);
+            let mut nodes_read = nodes_reader.deserialize::<RawNode>();
+            while let Some(record) = nodes_read.next().await {
+                builder.add_node(record)?;
+            }
+
+            let vertex_file = tokio::fs::File::open(vertices_path).await?;
+            let mut vertices_reader = csv_async::AsyncReaderBuilder::new().has_headers(false).flexible(true).create_deserializer(vertex_file);
+            let mut vertices_read = vertices_reader.deserialize::<RawVertex>();
+            while let Some(record) = vertices_read.next().await {
+                builder.add_vertex(record)?;
+            }
+
+            Ok(builder.build()?)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GraphProvider for HttpGraphProvider {
+        async fn get_region(&self, id: RegionIdx) -> Result<Graph> {
+            self.read_region(id, None).await
+        }
+
+        async fn get_region_within(&self, id: RegionIdx, bounds: &BoundingBox) -> Result<Graph> {
+            self.read_region(id, Some(bounds)).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GroupInfoProvider for HttpGraphProvider {
+        async fn get_info(&self, group_id: usize) -> Result<GroupInfo> {
+            let path = self.fetch_existing(&format!("group_{}.json", group_id), None).await?;
+            let manifest_path = self.fetch(&manifest::file_name(group_id), None).await?;
+            let manifest = match manifest_path {
+                Some(manifest_path) => { Some(serde_json::from_slice::<RegionManifest>(&tokio::fs::read(manifest_path).await?)?) }
+                None => { None }
+            };
+            self.manifest.set(manifest);
+            Ok(serde_json::from_slice::<GroupInfo>(&tokio::fs::read(path).await?)?)
+        }
+    }
+
+    impl StorageProvider for HttpGraphProvider {
+        fn quality_stats(&self) -> Arc<QualityStats> {
+            self.quality_stats.clone()
+        }
+
+        fn dataset_version(&self) -> Option<String> {
+            self.manifest.version()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::path::PathBuf;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+        use crate::fixtures::{generate_sample, TempDir};
+        use crate::graph_provider::http::{HttpConfig, HttpGraphProvider};
+        use crate::{GraphProvider, GroupInfoProvider};
+
+        /// Serves the fixtures in `dir` with their length as ETag, one request per connection, and
+        /// counts the files sent in full.
+        async fn serve(dir: PathBuf, downloads: Arc<AtomicUsize>) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::task::spawn(async move {
+                loop {
+                    let (mut socket, _) = listener.accept().await.unwrap();
+                    let mut raw = vec![];
+                    let mut buf = [0; 1024];
+                    while !raw.windows(4).any(|window| window == b"\r\n\r\n") {
+                        let read = socket.read(&mut buf).await.unwrap();
+                        raw.extend_from_slice(&buf[..read]);
+                    }
+                    let request = String::from_utf8(raw).unwrap();
+                    let name = request.split_whitespace().nth(1).unwrap().trim_start_matches('/');
+                    let subdir = match name.split('_').next().unwrap() {
+                        "group" => { "groups" }
+                        "nodes" => { "nodes" }
+                        _ => { "vertices" }
+                    };
+                    let body = match std::fs::read(dir.join(subdir).join(name)) {
+                        Ok(body) => { body }
+                        Err(_) => {
+                            socket.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await.unwrap();
+                            continue;
+                        }
+                    };
+                    let etag = format!("\"{}\"", body.len());
+                    let response = if request.lines().any(|line| line.eq_ignore_ascii_case(&format!("if-none-match: {}", etag))) {
+                        format!("HTTP/1.1 304 Not Modified\r\nETag: {}\r\nConnection: close\r\n\r\n", etag).into_bytes()
+                    } else {
+                        downloads.fetch_add(1, Ordering::SeqCst);
+                        let mut response = format!("HTTP/1.1 200 OK\r\nETag: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", etag, body.len()).into_bytes();
+                        response.extend(body);
+                        response
+                    };
+                    socket.write_all(&response).await.unwrap();
+                }
+            });
+            format!("http://{}/", addr)
+        }
+
+        #[tokio::test]
+        async fn unchanged_files_are_not_downloaded_again() {
+            let downloads = Arc::new(AtomicUsize::new(0));
+            let (dir, cache_dir) = (generate_sample("two_regions"), TempDir::new("http"));
+            let config = HttpConfig {
+                base_url: serve(dir.path().to_path_buf(), downloads.clone()).await,
+                cache_dir: cache_dir.path().to_path_buf(),
+            };
+            let provider = HttpGraphProvider::new(config.clone());
+            assert_eq!(provider.get_info(2).await.unwrap().group_id, 2);
+            assert_eq!(provider.get_region(1).await.unwrap().node_count(), 4);
+            assert_eq!(downloads.load(Ordering::SeqCst), 3);
+
+            // As after a restart, with the cache left in place.
+            let provider = HttpGraphProvider::new(config);
+            assert_eq!(provider.get_region(1).await.unwrap().node_count(), 4);
+            assert_eq!(provider.get_region(2).await.unwrap().region_idx, 2);
+            assert_eq!(downloads.load(Ordering::SeqCst), 5);
+        }
+    }
+}
+
+/// Providers tried in order for every file, e.g. a local data directory, then a bucket, then an
+/// HTTP mirror, so regions still load while one of them is down.
+pub mod fallback {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::sync::atomic::Ordering;
+    use crate::data_quality::QualityStats;
+    use crate::graph_provider::{Graph, GraphProvider, GroupInfo, GroupInfoProvider, Result, StorageProvider};
+    use crate::graph::{BoundingBox, RegionIdx};
+
+    /// The group is read from the first provider holding it, whose manifest the data set version is
+    /// taken from. Regions are read from the first provider holding them that serves the same
+    /// version, and the provider serving each region is recorded.
+    pub struct FallbackProvider {
+        providers: Vec<(String, Box<dyn StorageProvider>)>,
+        /// Provider the group was read from last.
+        primary: RwLock<Option<usize>>,
+        sources: Mutex<BTreeMap<RegionIdx, String>>,
+    }
+
+    impl FallbackProvider {
+        pub(crate) fn new(providers: Vec<(String, Box<dyn StorageProvider>)>) -> Self {
+            Self {
+                providers,
+                primary: RwLock::new(None),
+                sources: Mutex::new(BTreeMap::new()),
+            }
+        }
+
+        /// Name of the provider each region was loaded from last.
+        pub fn sources(&self) -> BTreeMap<RegionIdx, String> {
+            self.sources.lock().unwrap().clone()
+        }
+
+        /// Whether regions may be read from the provider, which they can't from one whose manifest
+        /// names another version of the data set than the group's.
+        fn serves_version(&self, index: usize) -> bool {
+            match *self.primary.read().unwrap() {
+                Some(primary) => { self.providers[index].1.dataset_version() == self.providers[primary].1.dataset_version() }
+                None => { true }
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GroupInfoProvider for FallbackProvider {
+        /// Reads the group from every provider, so each one knows its manifest.
+        async fn get_info(&self, group_id: usize) -> Result<GroupInfo> {
+            let mut group_info = None;
+            let mut failures = vec![];
+            for (index, (name, provider)) in self.providers.iter().enumerate() {
+                match provider.get_info(group_id).await {
+                    Ok(info) if group_info.is_none() => { group_info = Some((index, info)) }
+                    Ok(_) => {}
+                    Err(err) => {
+                        log::warn!("Unable to read group {} from {}, details: {}", group_id, name, err);
+                        failures.push(format!("{}: {}", name, err));
+                    }
+                }
+            }
+            match group_info {
+                Some((index, info)) => {
+                    *self.primary.write().unwrap() = Some(index);
+                    Ok(info)
+                }
+                None => { Err(format!("No provider holds group {} ({})", group_id, failures.join("; ")).into()) }
+            }
+        }
+
+        async fn put_info(&self, group_info: &GroupInfo) -> Result<()> {
+            let mut failures = vec![];
+            for (name, provider) in self.providers.iter() {
+                if let Err(err) = provider.put_info(group_info).await {
+                    log::warn!("Unable to write group {} to {}, details: {}", group_info.group_id, name, err);
+                    failures.push(format!("{}: {}", name, err));
+                }
+            }
+            match failures.len() == self.providers.len() {
+                true => { Err(format!("No provider took group {} ({})", group_info.group_id, failures.join("; ")).into()) }
+                false => { Ok(()) }
+            }
+        }
+    }
+
+    impl FallbackProvider {
+        async fn read_region(&self, id: RegionIdx, bounds: Option<&BoundingBox>) -> Result<Graph> {
+            let mut failures = vec![];
+            for (index, (name, provider)) in self.providers.iter().enumerate() {
+                if !self.serves_version(index) {
+                    log::debug!("Skipping {} for region {}, it holds another version of the data set", name, id);
+                    continue;
+                }
+                let read = match bounds {
+                    Some(bounds) => { provider.get_region_within(id, bounds).await }
+                    None => { provider.get_region(id).await }
+                };
+                match read {
+                    Ok(graph) => {
+                        log::info!("Region {} served by {}", id, name);
+                        self.sources.lock().unwrap().insert(id, name.clone());
+                        return Ok(graph);
+                    }
+                    Err(err) => {
+                        log::warn!("Unable to read region {} from {}, details: {}", id, name, err);
+                        failures.push(format!("{}: {}", name, err));
+                    }
+                }
+            }
+            Err(format!("No provider holds region {} ({})", id, failures.join("; ")).into())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GraphProvider for FallbackProvider {
+        async fn get_region(&self, id: RegionIdx) -> Result<Graph> {
+            self.read_region(id, None).await
+        }
+
+        async fn get_region_within(&self, id: RegionIdx, bounds: &BoundingBox) -> Result<Graph> {
+            self.read_region(id, Some(bounds)).await
+        }
+
+        /// Written to every provider able to, so they stay alike.
+        async fn put_region(&self, graph: &Graph) -> Result<()> {
+            let mut failures = vec![];
+            for (name, provider) in self.providers.iter() {
+                if let Err(err) = provider.put_region(graph).await {
+                    log::warn!("Unable to write region {} to {}, details: {}", graph.region_idx, name, err);
+                    failures.push(format!("{}: {}", name, err));
+                }
+            }
+            match failures.len() == self.providers.len() {
+                true => { Err(format!("No provider took region {} ({})", graph.region_idx, failures.join("; ")).into()) }
+                false => { Ok(()) }
+            }
+        }
+    }
+
+    impl StorageProvider for FallbackProvider {
+        /// Summed over the providers.
+        fn quality_stats(&self) -> Arc<QualityStats> {
+            let total = QualityStats::default();
+            for (_, provider) in self.providers.iter() {
+                let stats = provider.quality_stats();
+                total.skipped_nodes.fetch_add(stats.skipped_nodes.load(Ordering::Relaxed), Ordering::Relaxed);
+                total.skipped_vertices.fetch_add(stats.skipped_vertices.load(Ordering::Relaxed), Ordering::Relaxed);
+                total.repaired_vertices.fetch_add(stats.repaired_vertices.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+            Arc::new(total)
+        }
+
+        fn dataset_version(&self) -> Option<String> {
+            let primary = (*self.primary.read().unwrap())?;
+            self.providers[primary].1.dataset_version()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::fixtures::{generate_sample, TempDir};
+        use crate::graph_provider::fallback::FallbackProvider;
+        use crate::graph_provider::mock::MockGraphProvider;
+        use crate::manifest::RegionManifest;
+        use crate::{GraphProvider, GroupInfoProvider};
+
+        #[tokio::test]
+        async fn regions_are_read_from_the_first_provider_holding_them() {
+            let local = generate_sample("two_regions");
+            std::fs::remove_file(local.path().join("nodes").join("nodes_2.csv")).unwrap();
+            let mirror = generate_sample("two_regions");
+            let missing = TempDir::new("missing");
+            let provider = FallbackProvider::new(vec![
+                ("missing".to_string(), Box::new(MockGraphProvider::new(missing.path().to_path_buf()))),
+                ("local".to_string(), Box::new(MockGraphProvider::new(local.path().to_path_buf()))),
+                ("mirror".to_string(), Box::new(MockGraphProvider::new(mirror.path().to_path_buf()))),
+            ]);
+            assert_eq!(provider.get_info(2).await.unwrap().group_id, 2);
+            provider.get_region(1).await.unwrap();
+            provider.get_region(2).await.unwrap();
+            let sources: Vec<_> = provider.sources().into_iter().collect();
+            assert_eq!(sources, vec![(1, "local".to_string()), (2, "mirror".to_string())]);
+            assert!(provider.get_region(3).await.is_err());
+
+            // A mirror holding another version of the data set isn't read from.
+            let manifest = RegionManifest::new("v2");
+            std::fs::write(mirror.path().join("groups").join(crate::manifest::file_name(2)), serde_json::to_vec(&manifest).unwrap()).unwrap();
+            provider.get_info(2).await.unwrap();
+            assert!(provider.get_region(2).await.is_err());
+        }
+    }
+}
+
+/// Regions of any provider kept in a local cache, see [`crate::region_cache`].
+pub mod cached {
+    use std::sync::{Arc, RwLock};
+    use serde::{Deserialize, Serialize};
+    use crate::data_quality::QualityStats;
+    use crate::graph_provider::{bounded, Graph, GraphProvider, GroupInfo, GroupInfoProvider, Result, StorageProvider};
+    use crate::graph::{BoundingBox, RegionIdx, WeightScale};
+    use crate::packed;
+    use crate::region_cache::{RegionCache, RegionCacheConfig};
+
+    /// Group read last, with the version of the data set the regions cached for it belong to.
+    #[derive(Serialize, Deserialize)]
+    struct KeptGroup {
+        version: String,
+        group_info: GroupInfo,
+    }
+
+    /// Reads regions from the cache, and from `provider` the first time, caching them while the group
+    /// loaded last has a version. The group is read from the cache when the provider can't be reached,
+    /// then regions not cached aren't read at all, as the provider doesn't know their version.
+    pub struct CachedProvider {
+        provider: Box<dyn StorageProvider>,
+        cache: RegionCache,
+        weight_scale: WeightScale,
+        /// Version of the group read from the cache.
+        offline_version: RwLock<Option<String>>,
+    }
+
+    impl CachedProvider {
+        pub fn new(provider: Box<dyn StorageProvider>, cache: RegionCacheConfig, weight_scale: WeightScale) -> Self {
+            Self {
+                provider,
+                cache: RegionCache::new(cache),
+                weight_scale,
+                offline_version: RwLock::new(None),
+            }
+        }
+
+        fn region_key(version: &str, id: RegionIdx) -> String {
+            format!("{}/{}", version, packed::file_name(id))
+        }
+
+        fn group_file_name(group_id: usize) -> String {
+            format!("group_{}.json", group_id)
+        }
+
+        async fn cached_region(&self, version: &str, id: RegionIdx) -> Option<Graph> {
+            let key = Self::region_key(version, id);
+            let content = self.cache.get(&key).await?;
+            match packed::read(&content, id, self.weight_scale) {
+                Ok(graph) => {
+                    log::info!("Using cached region {} of version {}", id, version);
+                    Some(graph)
+                }
+                Err(err) => {
+                    log::warn!("Dropping cached region {} of version {}, details: {}", id, version, err);
+                    if let Err(err) = self.cache.remove(&key).await {
+                        log::warn!("Unable to drop cached region {}, details: {}", id, err);
+                    }
+                    None
+                }
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GraphProvider for CachedProvider {
+        async fn get_region(&self, id: RegionIdx) -> Result<Graph> {
+            let version = self.dataset_version();
+            if let Some(version) = version.as_deref() {
+                if let Some(graph) = self.cached_region(version, id).await {
+                    return Ok(graph);
+                }
+            }
+            if self.offline_version.read().unwrap().is_some() {
+                return Err(format!("Region {} isn't cached, and its group was read from the cache", id).into());
+            }
+            let graph = self.provider.get_region(id).await?;
+            // Not cached if another version was loaded meanwhile, the region may be of either.
+            if let Some(version) = version.filter(|version| self.provider.dataset_version().as_ref() == Some(version)) {
+                let mut content = vec![];
+                packed::write(&graph, self.weight_scale, &mut content)?;
+                if let Err(err) = self.cache.put(&Self::region_key(&version, id), &content).await {
+                    log::warn!("Unable to cache region {}, details: {}", id, err);
+                }
+            }
+            Ok(graph)
+        }
+
+        /// The part within `bounds` of the region if it is cached, otherwise read from the provider
+        /// and not cached, as it isn't the whole region.
+        async fn get_region_within(&self, id: RegionIdx, bounds: &BoundingBox) -> Result<Graph> {
+            if let Some(version) = self.dataset_version() {
+                if let Some(graph) = self.cached_region(&version, id).await {
+                    return Ok(bounded(graph, Some(bounds)));
+                }
+            }
+            if self.offline_version.read().unwrap().is_some() {
+                return Err(format!("Region {} isn't cached, and its group was read from the cache", id).into());
+            }
+            self.provider.get_region_within(id, bounds).await
+        }
+
+        async fn put_region(&self, graph: &Graph) -> Result<()> {
+            self.provider.put_region(graph).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GroupInfoProvider for CachedProvider {
+        async fn get_info(&self, group_id: usize) -> Result<GroupInfo> {
+            let name = Self::group_file_name(group_id);
+            // Kept as a message, the error of the provider isn't Send.
+            let read = self.provider.get_info(group_id).await.map_err(|err| err.to_string());
+            let err = match read {
+                Ok(group_info) => {
+                    *self.offline_version.write().unwrap() = None;
+                    let kept = match self.provider.dataset_version() {
+                        Some(version) => {
+                            let kept = KeptGroup { version, group_info: group_info.clone() };
+                            match serde_json::to_vec(&kept) {
+                                Ok(content) => { self.cache.keep(&name, &content).await }
+                                Err(err) => { Err(err.into()) }
+                            }
+                        }
+                        None => { self.cache.forget(&name).await }
+                    };
+                    if let Err(err) = kept {
+                        log::warn!("Unable to cache group {}, details: {}", group_id, err);
+                    }
+                    return Ok(group_info);
+                }
+                Err(err) => { err }
+            };
+            let kept = self.cache.kept(&name).await
+                .and_then(|content| serde_json::from_slice::<KeptGroup>(&content).ok());
+            match kept {
+                Some(kept) => {
+                    log::warn!("Using the cached copy of group {} of version {}, the provider is unreachable: {}", group_id, kept.version, err);
+                    *self.offline_version.write().unwrap() = Some(kept.version);
+                    Ok(kept.group_info)
+                }
+                None => { Err(err.into()) }
+            }
+        }
+
+        async fn put_info(&self, group_info: &GroupInfo) -> Result<()> {
+            self.provider.put_info(group_info).await
+        }
+    }
+
+    impl StorageProvider for CachedProvider {
+        fn quality_stats(&self) -> Arc<QualityStats> {
+            self.provider.quality_stats()
+        }
+
+        fn dataset_version(&self) -> Option<String> {
+            match self.offline_version.read().unwrap().clone() {
+                Some(version) => { Some(version) }
+                None => { self.provider.dataset_version() }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::fixtures::{generate_sample, TempDir};
+        use crate::graph::WeightScale;
+        use crate::graph_provider::cached::CachedProvider;
+        use crate::graph_provider::mock::MockGraphProvider;
+        use crate::graph_provider::StorageProvider;
+        use crate::manifest::RegionManifest;
+        use crate::region_cache::RegionCacheConfig;
+        use crate::{GraphProvider, GroupInfoProvider};
+
+        #[tokio::test]
+        async fn regions_of_a_version_are_read_once() {
+            let data = generate_sample("two_regions");
+            let cache_dir = TempDir::new("cached");
+            let config = RegionCacheConfig { dir: cache_dir.path().to_path_buf(), max_bytes: 1 << 20 };
+            let cached = |dir| CachedProvider::new(Box::new(MockGraphProvider::new(dir)), config.clone(), WeightScale::default());
+
+            // Without a version nothing is cached.
+            let provider = cached(data.path().to_path_buf());
+            provider.get_info(2).await.unwrap();
+            let expected = provider.get_region(1).await.unwrap();
+            assert!(std::fs::read_dir(cache_dir.path()).is_err());
+
+            let mut manifest = RegionManifest::new("v1");
+            for name in ["nodes/nodes_1.csv", "vertices/vertices_1.csv"] {
+                let file_name = name.split('/').nth(1).unwrap();
+                manifest.add(file_name, &std::fs::read(data.path().join(name)).unwrap());
+            }
+            std::fs::write(data.path().join("groups").join(crate::manifest::file_name(2)), serde_json::to_vec(&manifest).unwrap()).unwrap();
+            provider.get_info(2).await.unwrap();
+            provider.get_region(1).await.unwrap();
+            std::fs::remove_file(data.path().join("nodes/nodes_1.csv")).unwrap();
+            let graph = provider.get_region(1).await.unwrap();
+            assert_eq!(graph.node_count(), expected.node_count());
+
+            // The group is read from the cache while the provider is unreachable.
+            let offline = cached(data.path().join("missing"));
+            assert_eq!(offline.get_info(2).await.unwrap().group_id, 2);
+            assert_eq!(offline.dataset_version().as_deref(), Some("v1"));
+            offline.get_region(1).await.unwrap();
+            assert!(offline.get_region(2).await.is_err());
+        }
+    }
+}
+
+/// Regions kept in a single SQLite file, for a laptop or a demo without a bucket or a data directory.
+/// [`import`](sqlite::import) writes one from a data directory:
+///
+/// ```sql
+/// CREATE TABLE groups (group_id INTEGER, region INTEGER);
+/// CREATE TABLE super_regions (super_region INTEGER, region INTEGER);
+/// CREATE TABLE nodes (file_region INTEGER, id INTEGER, cord_x INTEGER, cord_y INTEGER, region INTEGER);
+/// CREATE TABLE vertices (file_region INTEGER, id INTEGER, a INTEGER, b INTEGER, weight REAL, region_bits TEXT, access TEXT);
+/// ```
+///
+/// `file_region` is the region whose file holds the row, nodes across a boundary are held by both
+/// regions like in the CSV files.
+#[cfg(feature = "sqlite")]
+pub mod sqlite {
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use rusqlite::{params, Connection, OpenFlags};
+    use crate::data_quality::{DataPolicy, GraphBuilder, QualityStats};
+    use crate::graph_provider::{Graph, GraphProvider, GroupInfo, GroupInfoProvider, RawNode, RawVertex, Result, StorageProvider};
+    use crate::graph::{BoundingBox, RegionIdx, WeightScale};
+
+    const SCHEMA: &str = "
+        CREATE TABLE groups (group_id INTEGER NOT NULL, region INTEGER NOT NULL);
+        CREATE TABLE super_regions (super_region INTEGER NOT NULL, region INTEGER NOT NULL);
+        CREATE TABLE nodes (file_region INTEGER NOT NULL, id INTEGER NOT NULL, cord_x INTEGER NOT NULL, cord_y INTEGER NOT NULL, region INTEGER NOT NULL);
+        CREATE TABLE vertices (file_region INTEGER NOT NULL, id INTEGER NOT NULL, a INTEGER NOT NULL, b INTEGER NOT NULL, weight REAL NOT NULL, region_bits TEXT NOT NULL, access TEXT);
+        CREATE INDEX nodes_by_region ON nodes (file_region);
+        CREATE INDEX vertices_by_region ON vertices (file_region);
+    ";
+
+    type SendResult<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+    pub struct SqliteGraphProvider {
+        path: PathBuf,
+        weight_scale: WeightScale,
+        data_policy: DataPolicy,
+        quality_stats: Arc<QualityStats>,
+    }
+
+    impl SqliteGraphProvider {
+        pub fn new(path: PathBuf) -> Self {
+            Self {
+                path,
+                weight_scale: WeightScale::default(),
+                data_policy: DataPolicy::default(),
+                quality_stats: Arc::default(),
+            }
+        }
+
+        pub fn with_weight_scale(mut self, weight_scale: WeightScale) -> Self {
+            self.weight_scale = weight_scale;
+            self
+        }
+
+        pub fn with_data_policy(mut self, data_policy: DataPolicy) -> Self {
+            self.data_policy = data_policy;
+            self
+        }
+
+        /// Runs `query` on a connection of its own, off the async workers.
+        async fn query<T, F>(&self, query: F) -> Result<T>
+            where T: Send + 'static,
+                  F: FnOnce(&Connection) -> SendResult<T> + Send + 'static {
+            let path = self.path.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let connection = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                    .map_err(|err| format!("Unable to open {}, details: {}", path.display(), err))?;
+                query(&connection)
+            }).await?;
+            result.map_err(|err| err as Box<dyn std::error::Error>)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GraphProvider for SqliteGraphProvider {
+        async fn get_region(&self, id: RegionIdx) -> Result<Graph> {
+            self.read_region(id, None).await
+        }
+
+        /// Only the nodes within `bounds` are queried.
+        async fn get_region_within(&self, id: RegionIdx, bounds: &BoundingBox) -> Result<Graph> {
+            self.read_region(id, Some(*bounds)).await
+        }
+    }
+
+    impl SqliteGraphProvider {
+        async fn read_region(&self, id: RegionIdx, bounds: Option<BoundingBox>) -> Result<Graph> {
+            let (weight_scale, data_policy, quality_stats) = (self.weight_scale, self.data_policy, self.quality_stats.clone());
+            self.query(move |connection| {
+                let mut builder = GraphBuilder::new(id, weight_scale, data_policy, &quality_stats).with_bounds(bounds.as_ref());
+                // SQLite integers are signed.
+                let area = bounds.map_or([0, u64::MAX, 0, u64::MAX], |bounds| [bounds.min_x, bounds.max_x, bounds.min_y, bounds.max_y])
+                    .map(|bound| bound.min(i64::MAX as u64) as i64);
+                let mut nodes = connection.prepare("SELECT id, cord_x, cord_y, region FROM nodes WHERE file_region = ? AND cord_x BETWEEN ? AND ? AND cord_y BETWEEN ? AND ?")?;
+                let rows = nodes.query_map(params![id, area[0], area[1], area[2], area[3]], |row| Ok(RawNode {
+                    id: row.get(0)?,
+                    cord_x: row.get(1)?,
+                    cord_y: row.get(2)?,
+                    region: row.get(3)?,
+                }))?;
+                for record in rows {
+                    builder.add_node(record)?;
+                }
+                let mut vertices = connection.prepare("SELECT id, a, b, weight, region_bits, access FROM vertices WHERE file_region = ?")?;
+                let rows = vertices.query_map(params![id], |row| Ok(RawVertex {
+                    id: row.get(0)?,
+                    a: row.get(1)?,
+                    b: row.get(2)?,
+                    weight: row.get(3)?,
+                    region_bits: row.get(4)?,
+                    access: row.get(5)?,
+                }))?;
+                for record in rows {
+                    builder.add_vertex(record)?;
+                }
+                Ok(builder.build()?)
+            }).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GroupInfoProvider for SqliteGraphProvider {
+        async fn get_info(&self, group_id: usize) -> Result<GroupInfo> {
+            self.query(move |connection| {
+                let regions = connection.prepare("SELECT region FROM groups WHERE group_id = ? ORDER BY region")?
+                    .query_map(params![group_id], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<RegionIdx>>>()?;
+                if regions.is_empty() {
+                    Err(format!("Group {} not found", group_id))?
+                }
+                let mut super_regions: BTreeMap<RegionIdx, Vec<RegionIdx>> = BTreeMap::new();
+                let rows = connection.prepare("SELECT super_region, region FROM super_regions ORDER BY super_region, region")?
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<rusqlite::Result<Vec<(RegionIdx, RegionIdx)>>>()?;
+                for (super_region, region) in rows {
+                    super_regions.entry(super_region).or_default().push(region);
+                }
+                Ok(GroupInfo {
+                    group_id,
+                    regions,
+                    super_regions,
+                })
+            }).await
+        }
+    }
+
+    impl StorageProvider for SqliteGraphProvider {
+        fn quality_stats(&self) -> Arc<QualityStats> {
+            self.quality_stats.clone()
+        }
+
+        /// SQLite files have no manifest, the file is the version.
+        fn dataset_version(&self) -> Option<String> {
+            None
+        }
+    }
+
+    /// Writes the groups and plain CSV region files of a data directory into a new SQLite file at
+    /// `path`. Rows are imported as they are, malformed ones fail the import.
+    pub fn import(dir: &Path, path: &Path) -> SendResult<()> {
+        if path.exists() {
+            Err(format!("{} already exists", path.display()))?
+        }
+        let mut connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        let transaction = connection.transaction()?;
+        let mut groups = vec![];
+        for entry in std::fs::read_dir(dir.join("groups"))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with("group_") && name.ends_with(".json") {
+                groups.push(serde_json::from_slice::<GroupInfo>(&std::fs::read(entry.path())?)?);
+            }
+        }
+        let mut super_regions = BTreeMap::new();
+        let mut regions = vec![];
+        for group in groups.iter() {
+            for region in group.regions.iter() {
+                transaction.execute("INSERT INTO groups (group_id, region) VALUES (?, ?)", params![group.group_id, region])?;
+                regions.push(*region);
+            }
+            // Every group lists all of them.
+            super_regions.extend(group.super_regions.clone());
+        }
+        for (super_region, members) in super_regions.iter() {
+            for region in members.iter() {
+                transaction.execute("INSERT INTO super_regions (super_region, region) VALUES (?, ?)", params![super_region, region])?;
+            }
+        }
+        for region in regions.iter() {
+            let nodes_path = dir.join("nodes").join(format!("nodes_{}.csv", region));
+            for record in csv::ReaderBuilder::new().has_headers(false).from_path(&nodes_path)?.deserialize::<RawNode>() {
+                let node = record.map_err(|err| format!("Malformed node in {}, details: {}", nodes_path.display(), err))?;
+                transaction.execute("INSERT INTO nodes (file_region, id, cord_x, cord_y, region) VALUES (?, ?, ?, ?, ?)",
+                                    params![region, node.id, node.cord_x, node.cord_y, node.region])?;
+            }
+            let vertices_path = dir.join("vertices").join(format!("vertices_{}.csv", region));
+            for record in csv::ReaderBuilder::new().has_headers(false).flexible(true).from_path(&vertices_path)?.deserialize::<RawVertex>() {
+                let vertex = record.map_err(|err| format!("Malformed vertex in {}, details: {}", vertices_path.display(), err))?;
+                transaction.execute("INSERT INTO vertices (file_region, id, a, b, weight, region_bits, access) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                                    params![region, vertex.id, vertex.a, vertex.b, vertex.weight, vertex.region_bits, vertex.access])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::fixtures::{generate_sample, TempDir};
+        use crate::graph_provider::mock::MockGraphProvider;
+        use crate::graph_provider::sqlite::{import, SqliteGraphProvider};
+        use crate::{GraphProvider, GroupInfoProvider};
+
+        #[tokio::test]
+        async fn imported_regions_match_the_data_directory() {
+            let (dir, sqlite_dir) = (generate_sample("super_regions"), TempDir::new("sqlite"));
+            std::fs::create_dir(sqlite_dir.path()).unwrap();
+            let path = sqlite_dir.path().join("regions.sqlite");
+            import(dir.path(), &path).unwrap();
+            assert!(import(dir.path(), &path).is_err());
+
+            let sqlite = SqliteGraphProvider::new(path);
+            let csv = MockGraphProvider::new(dir.path().to_path_buf());
+            let (group_info, expected) = (sqlite.get_info(1).await.unwrap(), csv.get_info(1).await.unwrap());
+            assert_eq!((group_info.regions, group_info.super_regions), (expected.regions, expected.super_regions));
+            assert!(sqlite.get_info(99).await.is_err());
+            let (graph, expected) = (sqlite.get_region(2).await.unwrap(), csv.get_region(2).await.unwrap());
+            assert_eq!(graph.node_count(), expected.node_count());
+            let weights = |graph: &crate::graph::Graph| {
+                let mut weights: Vec<_> = graph.vertices().map(|vertex| (vertex.id, vertex.weight, vertex.access)).collect();
+                weights.sort_by_key(|(id, _, _)| *id);
+                weights
+            };
+            assert_eq!(weights(&graph), weights(&expected));
+        }
+    }
+}
+
+/// Regions read from PostgreSQL, in tables laid out like the ones of [`sqlite`]:
+///
+/// ```sql
+/// CREATE TABLE groups (group_id BIGINT NOT NULL, region BIGINT NOT NULL);
+/// CREATE TABLE super_regions (super_region BIGINT NOT NULL, region BIGINT NOT NULL);
+/// CREATE TABLE nodes (file_region BIGINT NOT NULL, id BIGINT NOT NULL, cord_x BIGINT NOT NULL, cord_y BIGINT NOT NULL, region BIGINT NOT NULL);
+/// CREATE TABLE vertices (file_region BIGINT NOT NULL, id BIGINT NOT NULL, a BIGINT NOT NULL, b BIGINT NOT NULL, weight DOUBLE PRECISION NOT NULL, region_bits TEXT NOT NULL, access TEXT);
+/// ```
+#[cfg(feature = "postgres")]
+pub mod postgres {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use tokio_postgres::{Client, NoTls, Row};
+    use crate::data_quality::{DataPolicy, GraphBuilder, QualityStats};
+    use crate::graph_provider::{Graph, GraphProvider, GroupInfo, GroupInfoProvider, RawNode, RawVertex, Result, StorageProvider};
+    use crate::graph::{BoundingBox, RegionIdx, WeightScale};
+
+    pub struct PostgresGraphProvider {
+        /// Connection string, e.g. `host=db user=pathfinder dbname=regions`.
+        url: String,
+        client: Mutex<Option<Arc<Client>>>,
+        weight_scale: WeightScale,
+        data_policy: DataPolicy,
+        quality_stats: Arc<QualityStats>,
+    }
+
+    impl PostgresGraphProvider {
+        pub fn new(url: String) -> Self {
+            Self {
+                url,
+                client: Mutex::new(None),
+                weight_scale: WeightScale::default(),
+                data_policy: DataPolicy::default(),
+                quality_stats: Arc::default(),
+            }
+        }
+
+        pub fn with_weight_scale(mut self, weight_scale: WeightScale) -> Self {
+            self.weight_scale = weight_scale;
+            self
+        }
+
+        pub fn with_data_policy(mut self, data_policy: DataPolicy) -> Self {
+            self.data_policy = data_policy;
+            self
+        }
+
+        /// The connection opened last, or a new one if it was closed.
+        async fn client(&self) -> Result<Arc<Client>> {
+            let mut client = self.client.lock().await;
+            if let Some(client) = client.as_ref().filter(|client| !client.is_closed()) {
+                return Ok(client.clone());
+            }
+            // The connection string may hold a password, so it isn't part of the error.
+            let (connected, connection) = tokio_postgres::connect(&self.url, NoTls).await
+                .map_err(|err| format!("Unable to connect to PostgreSQL, details: {}", err))?;
+            tokio::task::spawn(async move {
+                if let Err(err) = connection.await {
+                    log::warn!("Lost the PostgreSQL connection, details: {}", err);
+                }
+            });
+            let connected = Arc::new(connected);
+            *client = Some(connected.clone());
+            Ok(connected)
+        }
+    }
+
+    /// Column `idx` of `row`, refused if it doesn't fit `T`, e.g. a negative id.
+    fn unsigned<T: TryFrom<i64>>(row: &Row, idx: usize) -> Result<T> {
+        let value: i64 = row.try_get(idx)?;
+        T::try_from(value).map_err(|_| format!("Value {} of column {} is out of range", value, row.columns()[idx].name()).into())
+    }
+
+    fn raw_node(row: &Row) -> Result<RawNode> {
+        Ok(RawNode {
+            id: unsigned(row, 0)?,
+            cord_x: unsigned(row, 1)?,
+            cord_y: unsigned(row, 2)?,
+            region: unsigned(row, 3)?,
+        })
+    }
+
+    fn raw_vertex(row: &Row) -> Result<RawVertex> {
+        Ok(RawVertex {
+            id: unsigned(row, 0)?,
+            a: unsigned(row, 1)?,
+            b: unsigned(row, 2)?,
+            weight: row.try_get(3)?,
+            region_bits: row.try_get(4)?,
+            access: row.try_get(5)?,
+        })
+    }
+
+    #[async_trait::async_trait]
+    impl GraphProvider for PostgresGraphProvider {
+        async fn get_region(&self, id: RegionIdx) -> Result<Graph> {
+            self.read_region(id, None).await
+        }
+
+        /// Only the nodes within `bounds` are queried.
+        async fn get_region_within(&self, id: RegionIdx, bounds: &BoundingBox) -> Result<Graph> {
+            self.read_region(id, Some(*bounds)).await
+        }
+    }
+
+    impl PostgresGraphProvider {
+        async fn read_region(&self, id: RegionIdx, bounds: Option<BoundingBox>) -> Result<Graph> {
+            let client = self.client().await?;
+            // BIGINT is signed.
+            let area = bounds.map_or([0, u64::MAX, 0, u64::MAX], |bounds| [bounds.min_x, bounds.max_x, bounds.min_y, bounds.max_y])
+                .map(|bound| bound.min(i64::MAX as u64) as i64);
+            let region = id as i64;
+            let nodes = client.query("SELECT id, cord_x, cord_y, region FROM nodes WHERE file_region = $1 AND cord_x BETWEEN $2 AND $3 AND cord_y BETWEEN $4 AND $5",
+                                     &[&region, &area[0], &area[1], &area[2], &area[3]]).await?;
+            let vertices = client.query("SELECT id, a, b, weight, region_bits, access FROM vertices WHERE file_region = $1", &[&region]).await?;
+            let mut builder = GraphBuilder::new(id, self.weight_scale, self.data_policy, &self.quality_stats).with_bounds(bounds.as_ref());
+            // Rows out of range are malformed records, handled as the data policy says.
+            for row in nodes.iter() {
+                builder.add_node(raw_node(row))?;
+            }
+            for row in vertices.iter() {
+                builder.add_vertex(raw_vertex(row))?;
+            }
+            Ok(builder.build()?)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GroupInfoProvider for PostgresGraphProvider {
+        async fn get_info(&self, group_id: usize) -> Result<GroupInfo> {
+            let client = self.client().await?;
+            let regions = client.query("SELECT region FROM groups WHERE group_id = $1 ORDER BY region", &[&(group_id as i64)]).await?
+                .iter()
+                .map(|row| unsigned(row, 0))
+                .collect::<Result<Vec<RegionIdx>>>()?;
+            if regions.is_empty() {
+                Err(format!("Group {} not found", group_id))?
+            }
+            let mut super_regions: BTreeMap<RegionIdx, Vec<RegionIdx>> = BTreeMap::new();
+            for row in client.query("SELECT super_region, region FROM super_regions ORDER BY super_region, region", &[]).await?.iter() {
+                super_regions.entry(unsigned(row, 0)?).or_default().push(unsigned(row, 1)?);
+            }
+            Ok(GroupInfo {
+                group_id,
+                regions,
+                super_regions,
+            })
+        }
+    }
+
+    impl StorageProvider for PostgresGraphProvider {
+        fn quality_stats(&self) -> Arc<QualityStats> {
+            self.quality_stats.clone()
+        }
+
+        /// The tables have no manifest.
+        fn dataset_version(&self) -> Option<String> {
+            None
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::graph_provider::postgres::PostgresGraphProvider;
+        use crate::{GraphProvider, GroupInfoProvider};
+
+        #[tokio::test]
+        async fn unreachable_databases_fail_reads() {
+            let provider = PostgresGraphProvider::new("host=127.0.0.1 port=1 user=pathfinder connect_timeout=1".to_string());
+            assert!(provider.get_info(1).await.is_err());
+            assert!(provider.get_region(1).await.is_err());
+        }
+    }
+}
+
+/// Graphs generated from a seed instead of read, for integration tests and load benchmarks without
+/// real data. The same configuration always generates the same data set, with region bits computed
+/// like [`crate::fixtures`] does.
+pub mod synthetic {
+    use std::collections::HashMap;
+    use std::env;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use tokio::sync::OnceCell;
+    use crate::data_quality::{DataPolicy, GraphBuilder, QualityStats};
+    use crate::fixtures::{FixtureDefinition, FixtureEdge, FixtureGroup, FixtureNode, RegionRecords};
+    use crate::graph_provider::{Graph, GraphProvider, GroupInfo, GroupInfoProvider, Result, StorageProvider};
+    use crate::graph::{BoundingBox, NodeIdx, RegionIdx, WeightScale};
+
+    /// Distance between neighbouring nodes of a grid, in coordinate units.
+    const SPACING: u64 = 100;
+    /// Neighbours a node of a random geometric graph has on average.
+    const DEGREE: f64 = 6.0;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum SyntheticShape {
+        /// Square regions of nodes connected to their horizontal and vertical neighbours.
+        Grid,
+        /// Nodes placed at random within the square of their region, connected to the nodes close to them.
+        Geometric,
+    }
+
+    impl FromStr for SyntheticShape {
+        type Err = String;
+
+        fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+            match s {
+                "grid" => { Ok(SyntheticShape::Grid) }
+                "geometric" => { Ok(SyntheticShape::Geometric) }
+                other => { Err(format!("Unknown synthetic graph shape {}, expected grid or geometric", other)) }
+            }
+        }
+    }
+
+    /// Regions `1..=regions`, laid out as squares side by side, spread round robin over groups
+    /// `1..=groups`. Edge weights are the distance between their ends scaled by a random factor
+    /// between 1 and 1.5, so the euclidean heuristic stays admissible.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct SyntheticConfig {
+        pub shape: SyntheticShape,
+        pub seed: u64,
+        pub regions: u32,
+        /// Nodes of every region, rounded up to a square number for grids.
+        pub region_nodes: usize,
+        pub groups: usize,
+    }
+
+    impl SyntheticConfig {
+        /// Reads SYNTHETIC_SHAPE (default grid), SYNTHETIC_SEED (default 0), SYNTHETIC_REGIONS
+        /// (default 4), SYNTHETIC_REGION_NODES (default 100) and SYNTHETIC_GROUPS (default one
+        /// region per group).
+        pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+            let regions = match env::var("SYNTHETIC_REGIONS") {
+                Ok(regions) => { regions.parse()? }
+                Err(_) => { 4 }
+            };
+            Ok(Self {
+                shape: match env::var("SYNTHETIC_SHAPE") {
+                    Ok(shape) => { shape.parse()? }
+                    Err(_) => { SyntheticShape::Grid }
+                },
+                seed: match env::var("SYNTHETIC_SEED") {
+                    Ok(seed) => { seed.parse()? }
+                    Err(_) => { 0 }
+                },
+                regions,
+                region_nodes: match env::var("SYNTHETIC_REGION_NODES") {
+                    Ok(region_nodes) => { region_nodes.parse()? }
+                    Err(_) => { 100 }
+                },
+                groups: match env::var("SYNTHETIC_GROUPS") {
+                    Ok(groups) => { groups.parse()? }
+                    Err(_) => { regions as usize }
+                },
+            })
+        }
+
+        /// Names the data set, so servers generating different ones refuse to serve together.
+        pub fn version(&self) -> String {
+            format!("synthetic-{:?}-{}-{}x{}-{}", self.shape, self.seed, self.regions, self.region_nodes, self.groups).to_lowercase()
+        }
+
+        /// Nodes on a side of a grid region.
+        fn side(&self) -> u64 {
+            ((self.region_nodes.max(1) as f64).sqrt().ceil()) as u64
+        }
+
+        /// Column and row of the square of every region.
+        fn cell(&self, region: RegionIdx) -> (u64, u64) {
+            let columns = ((self.regions.max(1) as f64).sqrt().ceil()) as u64;
+            ((region as u64 - 1) % columns, (region as u64 - 1) / columns)
+        }
+
+        /// The generated data set, which can also be written to a data directory.
+        pub fn definition(&self) -> std::result::Result<FixtureDefinition, Box<dyn std::error::Error + Send + Sync>> {
+            if self.regions == 0 || self.groups == 0 {
+                Err("Synthetic graphs need at least one region and one group")?
+            }
+            let mut rng = StdRng::seed_from_u64(self.seed);
+            let (nodes, pairs) = match self.shape {
+                SyntheticShape::Grid => { self.grid() }
+                SyntheticShape::Geometric => { self.geometric(&mut rng) }
+            };
+            let edges = pairs.into_iter().map(|(a, b)| {
+                let (a_node, b_node) = (&nodes[a - 1], &nodes[b - 1]);
+                let (dx, dy) = (a_node.x.abs_diff(b_node.x) as f64, a_node.y.abs_diff(b_node.y) as f64);
+                let weight = (dx * dx + dy * dy).sqrt().max(1.0) * rng.gen_range(1.0..1.5);
+                FixtureEdge {
+                    id: None,
+                    a,
+                    b,
+                    weight: (weight * 100.0).ceil() / 100.0,
+                    access: None,
+                }
+            }).collect();
+            let groups = (1..=self.groups).map(|id| FixtureGroup {
+                id,
+                regions: (1..=self.regions).filter(|region| (*region as usize - 1) % self.groups == id - 1).collect(),
+            }).collect();
+            Ok(FixtureDefinition {
+                nodes,
+                edges,
+                groups,
+                super_regions: vec![],
+            })
+        }
+
+        /// Node ids are positions in the returned nodes, counted from 1.
+        fn grid(&self) -> (Vec<FixtureNode>, Vec<(NodeIdx, NodeIdx)>) {
+            let side = self.side();
+            let mut nodes = vec![];
+            let mut positions = HashMap::new();
+            for region in 1..=self.regions {
+                let (column, row) = self.cell(region);
+                for y in row * side..(row + 1) * side {
+                    for x in column * side..(column + 1) * side {
+                        positions.insert((x, y), nodes.len() + 1);
+                        nodes.push(FixtureNode { id: nodes.len() + 1, x: x * SPACING, y: y * SPACING, region });
+                    }
+                }
+            }
+            let mut pairs = vec![];
+            for node in nodes.iter() {
+                let (x, y) = (node.x / SPACING, node.y / SPACING);
+                for neighbour in [(x + 1, y), (x, y + 1)] {
+                    if let Some(next) = positions.get(&neighbour) {
+                        pairs.push((node.id, *next));
+                    }
+                }
+            }
+            (nodes, pairs)
+        }
+
+        /// Connects every pair of nodes closer than the radius giving nodes [`DEGREE`] neighbours on
+        /// average. Like real networks, the graph may fall apart into several components.
+        fn geometric(&self, rng: &mut StdRng) -> (Vec<FixtureNode>, Vec<(NodeIdx, NodeIdx)>) {
+            let cell = self.side() * SPACING;
+            let mut nodes = vec![];
+            for region in 1..=self.regions {
+                let (column, row) = self.cell(region);
+                for _ in 0..self.region_nodes {
+                    let (x, y) = (column * cell + rng.gen_range(0..cell), row * cell + rng.gen_range(0..cell));
+                    nodes.push(FixtureNode { id: nodes.len() + 1, x, y, region });
+                }
+            }
+            let radius = (cell as f64 * (DEGREE / (std::f64::consts::PI * self.region_nodes.max(1) as f64)).sqrt()).max(1.0);
+            let bucket = |node: &FixtureNode| ((node.x as f64 / radius) as i64, (node.y as f64 / radius) as i64);
+            let mut buckets: HashMap<(i64, i64), Vec<&FixtureNode>> = HashMap::new();
+            for node in nodes.iter() {
+                buckets.entry(bucket(node)).or_default().push(node);
+            }
+            let mut pairs = vec![];
+            for node in nodes.iter() {
+                let (bx, by) = bucket(node);
+                for (dx, dy) in [(-1, -1), (-1, 0), (-1, 1), (0, -1), (0, 0), (0, 1), (1, -1), (1, 0), (1, 1)] {
+                    for other in buckets.get(&(bx + dx, by + dy)).into_iter().flatten() {
+                        let (x, y) = (node.x.abs_diff(other.x) as f64, node.y.abs_diff(other.y) as f64);
+                        if other.id > node.id && (x * x + y * y).sqrt() <= radius {
+                            pairs.push((node.id, other.id));
+                        }
+                    }
+                }
+            }
+            // Buckets are visited in hash order, the edges shouldn't be.
+            pairs.sort_unstable();
+            (nodes, pairs)
+        }
+    }
+
+    struct SyntheticData {
+        regions: std::collections::BTreeMap<RegionIdx, RegionRecords>,
+        groups: Vec<GroupInfo>,
+    }
+
+    /// Generates the whole data set on first use and keeps its records, every region is built from them.
+    pub struct SyntheticGraphProvider {
+        config: SyntheticConfig,
+        data: OnceCell<Arc<SyntheticData>>,
+        weight_scale: WeightScale,
+        data_policy: DataPolicy,
+        quality_stats: Arc<QualityStats>,
+    }
+
+    impl SyntheticGraphProvider {
+        pub fn new(config: SyntheticConfig) -> Self {
+            Self {
+                config,
+                data: OnceCell::new(),
+                weight_scale: WeightScale::default(),
+                data_policy: DataPolicy::default(),
+                quality_stats: Arc::default(),
+            }
+        }
+
+        pub fn with_weight_scale(mut self, weight_scale: WeightScale) -> Self {
+            self.weight_scale = weight_scale;
+            self
+        }
+
+        pub fn with_data_policy(mut self, data_policy: DataPolicy) -> Self {
+            self.data_policy = data_policy;
+            self
+        }
+
+        async fn data(&self) -> Result<&SyntheticData> {
+            let data = self.data.get_or_try_init(|| async {
+                let config = self.config.clone();
+                log::info!("Generating synthetic data set {}", config.version());
+                let generated = tokio::task::spawn_blocking(move || -> std::result::Result<_, Box<dyn std::error::Error + Send + Sync>> {
+                    let definition = config.definition()?;
+                    Ok(SyntheticData {
+                        regions: definition.region_records()?,
+                        groups: definition.group_infos(),
+                    })
+                }).await?;
+                generated.map(Arc::new).map_err(|err| err as Box<dyn std::error::Error>)
+            }).await?;
+            Ok(data)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GraphProvider for SyntheticGraphProvider {
+        async fn get_region(&self, id: RegionIdx) -> Result<Graph> {
+            self.read_region(id, None).await
+        }
+
+        async fn get_region_within(&self, id: RegionIdx, bounds: &BoundingBox) -> Result<Graph> {
+            self.read_region(id, Some(bounds)).await
+        }
+    }
+
+    impl SyntheticGraphProvider {
+        async fn read_region(&self, id: RegionIdx, bounds: Option<&BoundingBox>) -> Result<Graph> {
+            let records = self.data().await?.regions.get(&id).ok_or(format!("Region {} isn't generated, regions are 1 to {}", id, self.config.regions))?;
+            let mut builder = GraphBuilder::new(id, self.weight_scale, self.data_policy, &self.quality_stats).with_bounds(bounds);
+            for node in records.nodes.iter() {
+                builder.add_node(Ok::<_, String>(node.clone()))?;
+            }
+            for vertex in records.vertices.iter() {
+                builder.add_vertex(Ok::<_, String>(vertex.clone()))?;
+            }
+            Ok(builder.build()?)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GroupInfoProvider for SyntheticGraphProvider {
+        async fn get_info(&self, group_id: usize) -> Result<GroupInfo> {
+            let group_info = self.data().await?.groups.iter().find(|group_info| group_info.group_id == group_id)
+                .ok_or(format!("Group {} isn't generated, groups are 1 to {}", group_id, self.config.groups))?;
+            Ok(group_info.clone())
+        }
+    }
+
+    impl StorageProvider for SyntheticGraphProvider {
+        fn quality_stats(&self) -> Arc<QualityStats> {
+            self.quality_stats.clone()
+        }
+
+        fn dataset_version(&self) -> Option<String> {
+            Some(self.config.version())
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::graph_provider::synthetic::{SyntheticConfig, SyntheticGraphProvider, SyntheticShape};
+        use crate::{GraphProvider, GroupInfoProvider};
+
+        fn config(shape: SyntheticShape, seed: u64) -> SyntheticConfig {
+            SyntheticConfig { shape, seed, regions: 3, region_nodes: 16, groups: 2 }
+        }
+
+        async fn weights(provider: &SyntheticGraphProvider, region: u32) -> Vec<(usize, u64)> {
+            let graph = provider.get_region(region).await.unwrap();
+            let mut weights: Vec<_> = graph.vertices().map(|vertex| (vertex.id, vertex.weight)).collect();
+            weights.sort();
+            weights
+        }
+
+        #[tokio::test]
+        async fn grids_are_generated_in_square_regions() {
+            let provider = SyntheticGraphProvider::new(config(SyntheticShape::Grid, 7));
+            assert_eq!(provider.get_info(1).await.unwrap().regions, vec![1, 3]);
+            assert_eq!(provider.get_info(2).await.unwrap().regions, vec![2]);
+            assert!(provider.get_info(3).await.is_err());
+            let graph = provider.get_region(1).await.unwrap();
+            // 4x4 nodes of its own, and the neighbours across the borders with regions 2 and 3.
+            assert_eq!(graph.nodes().filter(|node| node.region == 1).count(), 16);
+            assert_eq!(graph.node_count(), 24);
+            assert!(graph.vertices().all(|vertex| vertex.weight >= 100));
+            assert!(provider.get_region(4).await.is_err());
+        }
+
+        #[tokio::test]
+        async fn graphs_are_generated_from_the_seed() {
+            let provider = SyntheticGraphProvider::new(config(SyntheticShape::Geometric, 7));
+            let weights_1 = weights(&provider, 1).await;
+            assert!(!weights_1.is_empty());
+            assert_eq!(weights(&SyntheticGraphProvider::new(config(SyntheticShape::Geometric, 7)), 1).await, weights_1);
+            assert_ne!(weights(&SyntheticGraphProvider::new(config(SyntheticShape::Geometric, 8)), 1).await, weights_1);
+            assert_ne!(config(SyntheticShape::Geometric, 7).version(), config(SyntheticShape::Geometric, 8).version());
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use crate::graph::{Graph, Node, NodeIdx, RegionIdx, WeightScale};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Lower bound of the remaining cost between two nodes, used to direct the search towards the target.
+///
+/// Implementations must never overestimate the real cost and must be consistent
+/// (`h(a) <= weight(a, b) + h(b)`), otherwise the returned paths are not the cheapest ones.
+pub trait Heuristic: Send + Sync {
+    fn estimate(&self, node: &Node, target: &Node) -> u64;
+}
+
+/// No guidance at all, the search is plain Dijkstra.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Zero;
+
+impl Heuristic for Zero {
+    fn estimate(&self, _node: &Node, _target: &Node) -> u64 {
+        0
+    }
+}
+
+/// Straight line distance between the coordinates, multiplied by the lowest cost of a coordinate unit.
+#[derive(Debug, Clone, Copy)]
+pub struct Euclidean {
+    pub scale: f64,
+}
+
+impl Heuristic for Euclidean {
+    fn estimate(&self, node: &Node, target: &Node) -> u64 {
+        let dx = node.cord_x.abs_diff(target.cord_x) as f64;
+        let dy = node.cord_y.abs_diff(target.cord_y) as f64;
+        ((dx * dx + dy * dy).sqrt() * self.scale) as u64
+    }
+}
+
+/// Grid distance between the coordinates, multiplied by the lowest cost of a coordinate unit.
+/// Only a lower bound when edges follow the axes.
+#[derive(Debug, Clone, Copy)]
+pub struct Manhattan {
+    pub scale: f64,
+}
+
+impl Heuristic for Manhattan {
+    fn estimate(&self, node: &Node, target: &Node) -> u64 {
+        let dx = node.cord_x.abs_diff(target.cord_x) as f64;
+        let dy = node.cord_y.abs_diff(target.cord_y) as f64;
+        ((dx + dy) * self.scale) as u64
+    }
+}
+
+/// ALT heuristic: exact distances from a few landmarks in every region give the bound
+/// `|d(l, target) - d(l, node)|` (triangle inequality). Independent of coordinates.
+#[derive(Debug, Clone, Default)]
+pub struct Landmarks {
+    distances: HashMap<RegionIdx, Vec<HashMap<NodeIdx, u64>>>,
+}
+
+impl Landmarks {
+    /// Picks `count` landmarks per graph, each one as far as possible from those already chosen.
+    pub fn new<'a>(graphs: impl IntoIterator<Item=&'a Graph>, count: usize) -> Self {
+        let mut distances = HashMap::new();
+        for graph in graphs {
+            let mut tables: Vec<HashMap<NodeIdx, u64>> = vec![];
+            let mut next = graph.nodes().map(|node| node.id).min();
+            while let Some(landmark) = next {
+                if tables.len() >= count {
+                    break;
+                }
+                tables.push(graph.distances_from(landmark));
+                next = graph.nodes()
+                    .map(|node| node.id)
+                    .filter(|idx| tables.iter().all(|table| table.get(idx) != Some(&0)))
+                    .filter_map(|idx| tables.iter().filter_map(|table| table.get(&idx)).min().map(|dist| (*dist, idx)))
+                    .max()
+                    .map(|(_, idx)| idx);
+            }
+            log::debug!("Selected {} landmarks for region {}", tables.len(), graph.region_idx);
+            distances.insert(graph.region_idx, tables);
+        }
+        Self {
+            distances
+        }
+    }
+}
+
+impl Heuristic for Landmarks {
+    fn estimate(&self, node: &Node, target: &Node) -> u64 {
+        let tables = match self.distances.get(&target.region) {
+            Some(tables) => { tables }
+            None => { return 0; }
+        };
+        tables.iter()
+            .filter_map(|table| Some(table.get(&node.id)?.abs_diff(*table.get(&target.id)?)))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Heuristic choice read from the configuration.
+#[derive(Debug, Clone)]
+pub enum HeuristicKind {
+    Zero,
+    Euclidean(f64),
+    Manhattan(f64),
+    Landmarks(usize),
+}
+
+impl HeuristicKind {
+    /// Reads SEARCH_HEURISTIC (zero, euclidean, manhattan or landmarks), HEURISTIC_SCALE and LANDMARK_COUNT.
+    pub fn from_env() -> Result<Self> {
+        match env::var("SEARCH_HEURISTIC") {
+            Ok(name) => { Self::named(&name) }
+            Err(_) => { Ok(HeuristicKind::Zero) }
+        }
+    }
+
+    /// Heuristic called `name`, parametrized by HEURISTIC_SCALE and LANDMARK_COUNT.
+    pub fn named(name: &str) -> Result<Self> {
+        let scale: f64 = match env::var("HEURISTIC_SCALE") {
+            Ok(scale) => { scale.parse()? }
+            Err(_) => { 1. }
+        };
+        let kind = match name {
+            "zero" => { HeuristicKind::Zero }
+            "euclidean" => { HeuristicKind::Euclidean(scale) }
+            "manhattan" => { HeuristicKind::Manhattan(scale) }
+            "landmarks" => {
+                match env::var("LANDMARK_COUNT") {
+                    Ok(count) => { HeuristicKind::Landmarks(count.parse()?) }
+                    Err(_) => { HeuristicKind::Landmarks(8) }
+                }
+            }
+            other => { Err(format!("Unknown search heuristic {}", other))? }
+        };
+        Ok(kind)
+    }
+
+    /// Converts coordinate based bounds, given in cost units of the data set, to fixed-point costs.
+    pub fn scaled(self, weight_scale: WeightScale) -> Self {
+        match self {
+            HeuristicKind::Euclidean(scale) => { HeuristicKind::Euclidean(scale * weight_scale.0 as f64) }
+            HeuristicKind::Manhattan(scale) => { HeuristicKind::Manhattan(scale * weight_scale.0 as f64) }
+            other => { other }
+        }
+    }
+
+    pub fn build<'a>(&self, graphs: impl IntoIterator<Item=&'a Graph>) -> Arc<dyn Heuristic> {
+        match self {
+            HeuristicKind::Zero => { Arc::new(Zero) }
+            HeuristicKind::Euclidean(scale) => { Arc::new(Euclidean { scale: *scale }) }
+            HeuristicKind::Manhattan(scale) => { Arc::new(Manhattan { scale: *scale }) }
+            HeuristicKind::Landmarks(count) => { Arc::new(Landmarks::new(graphs, *count)) }
+        }
+    }
+}
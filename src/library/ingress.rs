@@ -0,0 +1,486 @@
+//! HTTP ingress, so web clients can route without a redis client. `POST /paths` takes a query
+//! as JSON, the fields of [`ClientQuery`] but the request id, which is handed out by the cluster.
+//! Queries starting in a region of this server are dispatched like the hops it receives, others
+//! are forwarded to the server of their source region. `GET /paths/<request id>` returns the
+//! result kept for a query with RESULT_RETENTION, and `POST /paths?wait=true` answers with the
+//! first result published for the query (redis mode only). `GET /paths/<request id>/events`
+//! opens a WebSocket streaming the [`QueryEvent`](crate::events::QueryEvent)s of the query as
+//! JSON text messages. The results and events clients wait for all arrive over one redis
+//! connection, see [`Subscriptions`].
+
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use futures_util::{SinkExt, StreamExt};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::body::HttpBody as _;
+use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use hyper::service::{make_service_fn, service_fn};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Message, Role};
+use crate::client;
+use crate::domain::{ClientQuery, HopMessage, RouteResult};
+use crate::graph::RegionIdx;
+use crate::node_connector::{ConnectionError, NodeListener, NodeSender};
+use crate::redis_connector::RedisConnector;
+use crate::replicas::ReplicaSelector;
+use crate::retry::ListenerBackoff;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Default time `POST /paths?wait=true` waits for the result, 30 seconds.
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+
+/// Default size of the largest query accepted, 64 KiB.
+const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Messages of a channel held for a client before it falls behind and further ones are dropped.
+const SUBSCRIPTION_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IngressConfig {
+    pub addr: SocketAddr,
+    pub wait_timeout: Duration,
+    /// Queries with larger bodies are refused with `413 Payload Too Large`.
+    pub max_body_bytes: usize,
+}
+
+impl IngressConfig {
+    /// Reads HTTP_INGRESS_ADDR, HTTP_INGRESS_WAIT_MS and HTTP_INGRESS_MAX_BODY_BYTES, no ingress
+    /// without an address.
+    pub fn from_env() -> Result<Option<Self>> {
+        let addr = match env::var("HTTP_INGRESS_ADDR") {
+            Ok(addr) => { addr.parse()? }
+            Err(_) => { return Ok(None) }
+        };
+        Ok(Some(Self {
+            addr,
+            wait_timeout: match env::var("HTTP_INGRESS_WAIT_MS") {
+                Ok(millis) => { Duration::from_millis(millis.parse()?) }
+                Err(_) => { DEFAULT_WAIT }
+            },
+            max_body_bytes: match env::var("HTTP_INGRESS_MAX_BODY_BYTES") {
+                Ok(bytes) => { bytes.parse()? }
+                Err(_) => { DEFAULT_MAX_BODY_BYTES }
+            },
+        }))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    Submit { wait: bool },
+    Result(usize),
+    Events(usize),
+    NotFound,
+}
+
+fn route(method: &Method, path: &str, query: Option<&str>) -> Route {
+    match (method, path.strip_prefix("/paths")) {
+        (&Method::POST, Some("")) => {
+            Route::Submit { wait: query.is_some_and(|query| query.split('&').any(|param| param == "wait=true")) }
+        }
+        (&Method::GET, Some(id)) => {
+            let id = id.strip_prefix('/').unwrap_or("-");
+            match id.strip_suffix("/events").unwrap_or(id).parse() {
+                Ok(request_id) if id.ends_with("/events") => { Route::Events(request_id) }
+                Ok(request_id) => { Route::Result(request_id) }
+                Err(_) => { Route::NotFound }
+            }
+        }
+        _ => { Route::NotFound }
+    }
+}
+
+/// Query submitted as `body`, under `request_id` whatever the body says.
+fn parse_query(body: &[u8], request_id: usize) -> Result<ClientQuery> {
+    let mut query: serde_json::Value = serde_json::from_slice(body)?;
+    query.as_object_mut().ok_or("The query has to be a JSON object")?.insert("request_id".to_string(), request_id.into());
+    Ok(serde_json::from_value(query)?)
+}
+
+/// The whole of `body`, unless it is larger than `limit` bytes.
+async fn read_body(mut body: Body, limit: usize) -> std::result::Result<Vec<u8>, Response<Body>> {
+    let mut read = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| error(StatusCode::BAD_REQUEST, err))?;
+        if read.len() + chunk.len() > limit {
+            return Err(error(StatusCode::PAYLOAD_TOO_LARGE, format!("Queries are limited to {} bytes", limit)));
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Ok(read)
+}
+
+fn respond(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: impl std::fmt::Display) -> Response<Body> {
+    respond(status, json!({ "error": message.to_string() }))
+}
+
+/// Senders of the clients waiting on a channel, with the ids of their subscriptions.
+type ChannelClients = Vec<(u64, mpsc::Sender<redis::Value>)>;
+
+/// Clients waiting for the messages of a channel, by channel. A single connection is subscribed to
+/// the result and event channels of every query, since a redis connection can't subscribe to more
+/// channels while it is read, and hands each message to the clients of its channel.
+#[derive(Default)]
+struct Subscriptions {
+    clients: Mutex<HashMap<String, ChannelClients>>,
+    next_id: AtomicU64,
+    connected: AtomicBool,
+}
+
+/// Messages of a channel for one client, which stops taking them when dropped.
+struct Subscription {
+    subscriptions: Arc<Subscriptions>,
+    channel: String,
+    id: u64,
+    messages: mpsc::Receiver<redis::Value>,
+}
+
+impl Subscriptions {
+    /// Takes the messages of `channel` from now on. Refused while the connection is down, the client
+    /// would wait for messages that are lost.
+    fn subscribe(self: &Arc<Self>, channel: String) -> Result<Subscription> {
+        if !self.connected.load(Ordering::Acquire) {
+            return Err("Not subscribed to results and events, redis can't be reached".into());
+        }
+        let (sender, messages) = mpsc::channel(SUBSCRIPTION_CAPACITY);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.clients.lock().unwrap().entry(channel.clone()).or_default().push((id, sender));
+        Ok(Subscription { subscriptions: self.clone(), channel, id, messages })
+    }
+
+    fn deliver(&self, message: redis::Msg) {
+        let clients = self.clients.lock().unwrap();
+        for (_, client) in clients.get(message.get_channel_name()).into_iter().flatten() {
+            if client.try_send(message.get_payload().unwrap_or(redis::Value::Nil)).is_err() {
+                log::debug!("Dropping a message on {} for a client falling behind", message.get_channel_name());
+            }
+        }
+    }
+
+    /// Subscribes to the results and events of all queries and delivers them until the connection
+    /// drops, then connects again.
+    async fn run(self: Arc<Self>, redis_connector: RedisConnector) {
+        let mut backoff = ListenerBackoff::default();
+        loop {
+            match self.listen(&redis_connector).await {
+                Ok(()) => { log::warn!("Subscription to results and events closed, subscribing again") }
+                Err(err) => { log::warn!("Unable to subscribe to results and events, details: {}", err) }
+            }
+            self.connected.store(false, Ordering::Release);
+            tokio::time::sleep(backoff.failed()).await;
+            if self.connected.load(Ordering::Acquire) {
+                backoff.reset();
+            }
+        }
+    }
+
+    async fn listen(&self, redis_connector: &RedisConnector) -> Result<()> {
+        let mut pubsub = redis_connector.spawn_connection().await?.into_pubsub();
+        pubsub.psubscribe(client::results_channel_pattern()).await?;
+        pubsub.psubscribe(client::events_channel_pattern()).await?;
+        self.connected.store(true, Ordering::Release);
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            self.deliver(message);
+        }
+        Ok(())
+    }
+}
+
+impl Subscription {
+    async fn next(&mut self) -> Option<redis::Value> {
+        self.messages.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut clients = self.subscriptions.clients.lock().unwrap();
+        if let Some(channel_clients) = clients.get_mut(&self.channel) {
+            channel_clients.retain(|(id, _)| *id != self.id);
+            if channel_clients.is_empty() {
+                clients.remove(&self.channel);
+            }
+        }
+    }
+}
+
+/// Receives the hops of the server's listener and the queries submitted over HTTP in turn.
+pub(crate) struct IngressListener {
+    requests: mpsc::Receiver<std::result::Result<HopMessage, ConnectionError>>,
+}
+
+#[async_trait::async_trait]
+impl NodeListener for IngressListener {
+    async fn get_new_request(&mut self) -> std::result::Result<HopMessage, ConnectionError> {
+        self.requests.recv().await.unwrap_or(Err(ConnectionError::NoRequest))
+    }
+}
+
+struct Ingress {
+    config: IngressConfig,
+    requests: mpsc::Sender<std::result::Result<HopMessage, ConnectionError>>,
+    node_sender_mgr: Box<dyn NodeSender>,
+    replicas: ReplicaSelector,
+    redis_connector: RedisConnector,
+    regions: HashSet<RegionIdx>,
+    subscriptions: Arc<Subscriptions>,
+}
+
+impl Ingress {
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        match route(request.method(), request.uri().path(), request.uri().query()) {
+            Route::Submit { wait } => {
+                let body = match read_body(request.into_body(), self.config.max_body_bytes).await {
+                    Ok(body) => { body }
+                    Err(response) => { return response }
+                };
+                self.submit(&body, wait).await
+            }
+            Route::Result(request_id) => {
+                match self.redis_connector.get_result(request_id).await {
+                    Ok(Some(result)) => { respond(StatusCode::OK, json!(result)) }
+                    Ok(None) => { error(StatusCode::NOT_FOUND, format!("No result of request {} is kept (yet)", request_id)) }
+                    Err(err) => { error(StatusCode::SERVICE_UNAVAILABLE, err) }
+                }
+            }
+            Route::Events(request_id) => { self.stream_events(request, request_id).await }
+            Route::NotFound => { error(StatusCode::NOT_FOUND, "Use POST /paths, GET /paths/<request id> or GET /paths/<request id>/events") }
+        }
+    }
+
+    async fn submit(&self, body: &[u8], wait: bool) -> Response<Body> {
+        let request_id = match self.redis_connector.next_request_id().await {
+            Ok(request_id) => { request_id }
+            Err(err) => { return error(StatusCode::SERVICE_UNAVAILABLE, err) }
+        };
+        let query = match parse_query(body, request_id) {
+            Ok(query) => { query }
+            Err(err) => { return error(StatusCode::BAD_REQUEST, err) }
+        };
+        // Results aren't kept by redis, so the client waiting is subscribed before the query is sent.
+        let mut results = None;
+        if wait {
+            match self.subscriptions.subscribe(client::results_channel(request_id)) {
+                Ok(subscription) => { results = Some(subscription) }
+                Err(err) => { return error(StatusCode::SERVICE_UNAVAILABLE, err) }
+            }
+        }
+        if let Err(err) = self.dispatch(HopMessage::from(query)).await {
+            return error(StatusCode::SERVICE_UNAVAILABLE, format!("Unable to dispatch request {}, details: {}", request_id, err));
+        }
+        let accepted = respond(StatusCode::ACCEPTED, json!({ "request_id": request_id }));
+        let mut results = match results {
+            Some(results) => { results }
+            None => { return accepted }
+        };
+        match tokio::time::timeout(self.config.wait_timeout, results.next()).await {
+            Ok(Some(message)) => {
+                match redis::from_redis_value::<RouteResult>(&message) {
+                    Ok(result) => { respond(StatusCode::OK, json!(result)) }
+                    Err(err) => { error(StatusCode::BAD_GATEWAY, format!("Undecodable result of request {}, details: {}", request_id, err)) }
+                }
+            }
+            _ => { accepted }
+        }
+    }
+
+    /// Upgrades `request` to a WebSocket relaying the events of the query until the client closes it.
+    /// A query may be answered again when a cheaper route turns up, unless results are arbitrated.
+    async fn stream_events(&self, mut request: Request<Body>, request_id: usize) -> Response<Body> {
+        let key = match request.headers().get(SEC_WEBSOCKET_KEY) {
+            Some(key) if request.headers().get(UPGRADE).is_some_and(|upgrade| upgrade.as_bytes().eq_ignore_ascii_case(b"websocket")) => { key }
+            _ => { return error(StatusCode::UPGRADE_REQUIRED, "Events are streamed over a WebSocket") }
+        };
+        let accept = derive_accept_key(key.as_bytes());
+        // Subscribed before the upgrade is confirmed, so the client misses no event published after it.
+        let events = match self.subscriptions.subscribe(client::events_channel(request_id)) {
+            Ok(events) => { events }
+            Err(err) => { return error(StatusCode::SERVICE_UNAVAILABLE, err) }
+        };
+        let upgrade = hyper::upgrade::on(&mut request);
+        tokio::task::spawn(async move {
+            let socket = match upgrade.await {
+                Ok(upgraded) => { WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await }
+                Err(err) => {
+                    log::debug!("Unable to upgrade the event stream of request {}, details: {}", request_id, err);
+                    return;
+                }
+            };
+            relay_events(socket, events, request_id).await;
+        });
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, "Upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn dispatch(&self, request: HopMessage) -> Result<()> {
+        let region = request.source.1;
+        if self.regions.contains(&region) {
+            log::debug!("Dispatching request {} submitted over HTTP", request.request_id);
+            self.requests.send(Ok(request)).await.map_err(|_| "Server is shutting down")?;
+            return Ok(());
+        }
+        let server_id = self.replicas.pick(region).await?;
+        log::debug!("Forwarding request {} submitted over HTTP to server {}", request.request_id, server_id);
+        self.node_sender_mgr.send_request(server_id, request).await
+    }
+}
+
+async fn relay_events<S>(mut socket: WebSocketStream<S>, mut events: Subscription, request_id: usize)
+    where S: AsyncRead + AsyncWrite + Unpin {
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let sent = match event.map(|event| redis::from_redis_value::<String>(&event)) {
+                    Some(Ok(event)) => { socket.send(Message::Text(event)).await }
+                    Some(Err(err)) => {
+                        log::warn!("Skipping an undecodable event of request {}, details: {}", request_id, err);
+                        continue;
+                    }
+                    None => { break }
+                };
+                if sent.is_err() {
+                    return;
+                }
+            }
+            message = socket.next() => {
+                match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => { return }
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+    let _ = socket.close(None).await;
+}
+
+/// Serves the HTTP ingress of a server serving `regions`, returning the listener the server
+/// reads both `primary`'s hops and the submitted queries from.
+pub(crate) fn spawn(config: IngressConfig,
+                    mut primary: Box<dyn NodeListener>,
+                    node_sender_mgr: Box<dyn NodeSender>,
+                    replicas: ReplicaSelector,
+                    redis_connector: RedisConnector,
+                    regions: HashSet<RegionIdx>) -> Result<IngressListener> {
+    // A single slot, so hops are still only taken from the primary listener when the server asks.
+    let (requests, receiver) = mpsc::channel(1);
+    let primary_requests = requests.clone();
+    tokio::task::spawn(async move {
+        loop {
+            if primary_requests.send(primary.get_new_request().await).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let subscriptions = Arc::new(Subscriptions::default());
+    tokio::task::spawn(subscriptions.clone().run(redis_connector.clone()));
+
+    let addr = config.addr;
+    let ingress = Arc::new(Ingress {
+        config,
+        requests,
+        node_sender_mgr,
+        replicas,
+        redis_connector,
+        regions,
+        subscriptions,
+    });
+    let server = hyper::Server::try_bind(&addr)?.serve(make_service_fn(move |_| {
+        let ingress = ingress.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let ingress = ingress.clone();
+                async move { Ok::<_, Infallible>(ingress.handle(request).await) }
+            }))
+        }
+    }));
+    log::info!("Accepting queries over HTTP on {}", addr);
+    tokio::task::spawn(async move {
+        if let Err(err) = server.await {
+            log::error!("HTTP ingress stopped, details: {}", err);
+        }
+    });
+    Ok(IngressListener { requests: receiver })
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use hyper::{Body, Method, StatusCode};
+    use redis::Value;
+    use crate::ingress::{parse_query, read_body, route, Route, Subscriptions};
+
+    fn message(channel: &str, payload: &str) -> redis::Msg {
+        let parts = ["message", channel, payload].iter().map(|part| Value::Data(part.as_bytes().to_vec())).collect();
+        redis::Msg::from_value(&Value::Bulk(parts)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_are_handed_to_the_clients_of_their_channel() {
+        let subscriptions = Arc::new(Subscriptions::default());
+        assert!(subscriptions.subscribe("events_1".to_string()).is_err());
+        subscriptions.connected.store(true, Ordering::Release);
+        let mut first = subscriptions.subscribe("events_1".to_string()).unwrap();
+        let mut second = subscriptions.subscribe("events_1".to_string()).unwrap();
+        let mut other = subscriptions.subscribe("events_2".to_string()).unwrap();
+        subscriptions.deliver(message("events_1", "entered"));
+        subscriptions.deliver(message("events_3", "unwatched"));
+        assert_eq!(first.next().await, Some(Value::Data(b"entered".to_vec())));
+        assert_eq!(second.next().await, Some(Value::Data(b"entered".to_vec())));
+        assert!(other.messages.try_recv().is_err());
+        drop(first);
+        drop(second);
+        assert_eq!(subscriptions.clients.lock().unwrap().keys().collect::<Vec<_>>(), vec!["events_2"]);
+    }
+
+    #[test]
+    fn requests_are_routed_by_method_and_path() {
+        assert_eq!(route(&Method::POST, "/paths", None), Route::Submit { wait: false });
+        assert_eq!(route(&Method::POST, "/paths", Some("wait=true")), Route::Submit { wait: true });
+        assert_eq!(route(&Method::GET, "/paths/42", None), Route::Result(42));
+        assert_eq!(route(&Method::GET, "/paths/42/events", None), Route::Events(42));
+        assert_eq!(route(&Method::GET, "/paths/latest", None), Route::NotFound);
+        assert_eq!(route(&Method::GET, "/paths/42/stats", None), Route::NotFound);
+        assert_eq!(route(&Method::DELETE, "/paths/42", None), Route::NotFound);
+    }
+
+    #[test]
+    fn submitted_queries_get_the_request_id_handed_out() {
+        let query = parse_query(br#"{"request_id": 1, "source": [1, 1], "target": [7, 2], "max_cost": 100}"#, 12).unwrap();
+        assert_eq!((query.request_id, query.source.0, query.target.1, query.max_cost), (12, 1, 2, Some(100)));
+        assert!(parse_query(b"[1, 2]", 12).is_err());
+        assert!(parse_query(br#"{"source": [1, 1]}"#, 12).is_err());
+    }
+
+    #[tokio::test]
+    async fn large_bodies_are_refused() {
+        assert_eq!(read_body(Body::from("0123456789"), 10).await.unwrap(), b"0123456789");
+        let refused = read_body(Body::from("0123456789!"), 10).await.unwrap_err();
+        assert_eq!(refused.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
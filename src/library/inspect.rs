@@ -0,0 +1,171 @@
+use std::collections::{BTreeMap, HashMap};
+use futures_util::StreamExt as _;
+use redis::{AsyncCommands, RedisResult};
+use serde::{Serialize, Deserialize};
+use tokio::task::JoinHandle;
+use crate::graph::{Graph, RegionIdx, WeightScale};
+use crate::redis_connector::RedisConnector;
+use crate::reload::DatasetHandle;
+
+/// Most buckets of equal width between the lightest and the heaviest edge.
+const HISTOGRAM_BUCKETS: usize = 10;
+
+/// Edges of a region with a weight below `upper`, in the units of the data set. The last bucket
+/// includes the heaviest edges.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WeightBucket {
+    pub upper: f64,
+    pub count: usize,
+}
+
+/// Statistics of a region as loaded by a running server, with the weights currently in effect.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegionReport {
+    pub region: RegionIdx,
+    pub nodes: usize,
+    pub vertices: usize,
+    /// Edges that cost nothing to traverse. A region of mostly zero weights degenerates searches.
+    pub zero_weights: usize,
+    pub min_weight: f64,
+    pub max_weight: f64,
+    pub weights: Vec<WeightBucket>,
+    /// Number of nodes by the number of edges they are connected to.
+    pub degrees: BTreeMap<usize, usize>,
+}
+
+impl RegionReport {
+    pub(crate) fn new(graph: &Graph, scale: WeightScale) -> Self {
+        let weights: Vec<u64> = graph.vertices()
+            .map(|vertex| graph.current_weight(vertex.id).unwrap_or(vertex.weight))
+            .collect();
+        let min = weights.iter().copied().min().unwrap_or(0);
+        let max = weights.iter().copied().max().unwrap_or(0);
+        let bucket_count = HISTOGRAM_BUCKETS.min((max - min) as usize + 1);
+        let width = ((max - min) / bucket_count as u64).max(1);
+        let mut counts = vec![0; bucket_count];
+        for weight in weights.iter() {
+            counts[(((weight - min) / width) as usize).min(bucket_count - 1)] += 1;
+        }
+        let buckets = counts.into_iter().enumerate()
+            .map(|(bucket, count)| {
+                let upper = if bucket + 1 == bucket_count { max } else { min + width * (bucket as u64 + 1) };
+                WeightBucket { upper: scale.to_float(upper), count }
+            })
+            .collect();
+
+        let mut degrees = BTreeMap::new();
+        for node in graph.nodes() {
+            *degrees.entry(graph.connections(node.id()).count()).or_insert(0) += 1;
+        }
+        Self {
+            region: graph.region_idx,
+            nodes: graph.node_count(),
+            vertices: weights.len(),
+            zero_weights: weights.iter().filter(|weight| **weight == 0).count(),
+            min_weight: scale.to_float(min),
+            max_weight: scale.to_float(max),
+            weights: buckets,
+            degrees,
+        }
+    }
+}
+
+/// Redis channel on which a [`RegionReport`] of a region is requested. The payload names the channel
+/// to publish the report on, the report is sent as JSON.
+pub fn channel(region: RegionIdx) -> String {
+    format!("region_stats_{}", region)
+}
+
+/// Subscribes to the statistics channels of the regions served and answers every request with a report.
+pub(crate) async fn spawn_stats_queries(redis_connector: &RedisConnector,
+                                        dataset: DatasetHandle,
+                                        scale: WeightScale) -> RedisResult<JoinHandle<()>> {
+    let mut pubsub = redis_connector.spawn_connection().await?.into_pubsub();
+    let mut channels = HashMap::new();
+    for region in dataset.current().graphs.keys() {
+        pubsub.subscribe(channel(*region)).await?;
+        channels.insert(channel(*region), *region);
+    }
+    let redis_connector = redis_connector.clone();
+    Ok(tokio::task::spawn(async move {
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let region = match channels.get(message.get_channel_name()) {
+                Some(region) => { *region }
+                None => { continue }
+            };
+            let reply_channel = match message.get_payload::<String>() {
+                Ok(reply_channel) => { reply_channel }
+                Err(err) => {
+                    log::warn!("Ignoring malformed statistics request for region {}, details: {}", region, err);
+                    continue;
+                }
+            };
+            let current = dataset.current();
+            let graph = match current.graphs.get(&region).await {
+                Ok(Some(graph)) => { graph }
+                Ok(None) => { continue }
+                Err(err) => {
+                    log::warn!("Unable to report statistics of region {}, details: {}", region, err);
+                    continue
+                }
+            };
+            let report = serde_json::to_string(&RegionReport::new(graph, scale)).unwrap();
+            let published: RedisResult<()> = match redis_connector.claim_connection().await {
+                Ok(mut conn) => {
+                    let published = conn.publish(&reply_channel, report).await;
+                    conn.release();
+                    published
+                }
+                Err(err) => { Err(err) }
+            };
+            if let Err(err) = published {
+                log::warn!("Unable to send statistics of region {}, details: {}", region, err);
+            }
+        }
+        log::warn!("Statistics requests subscription closed");
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+    use bitvec::vec::BitVec;
+    use crate::graph::{Access, Graph, Node, Vertex, WeightScale};
+    use crate::inspect::{RegionReport, WeightBucket};
+
+    /// Star around node 1 with edges of weight 0, 0, 10 and 20, and an edge 2 - 3 of weight 5.
+    fn star() -> Graph {
+        let mut nodes: HashMap<_, _> = (1..=5).map(|id| (id, Node::new(vec![], id, 1, 0, 0))).collect();
+        let mut vertices = HashMap::new();
+        for (id, a, b, weight) in [(1, 1, 2, 0), (2, 1, 3, 0), (3, 1, 4, 10), (4, 1, 5, 20), (5, 2, 3, 5)] {
+            nodes.get_mut(&a).unwrap().connections.push(id);
+            nodes.get_mut(&b).unwrap().connections.push(id);
+            vertices.insert(id, Vertex { a, b, weight, id, region_bits: BitVec::repeat(true, 2), access: Access::ALL });
+        }
+        Graph::new(nodes, vertices, 1)
+    }
+
+    #[test]
+    fn weights_and_degrees() {
+        let graph = star();
+        let report = RegionReport::new(&graph, WeightScale(2));
+        assert_eq!((report.nodes, report.vertices, report.zero_weights), (5, 5, 2));
+        assert_eq!((report.min_weight, report.max_weight), (0., 10.));
+        assert_eq!(report.weights.len(), 10);
+        assert_eq!(report.weights[0], WeightBucket { upper: 1., count: 2 });
+        assert_eq!(report.weights[2], WeightBucket { upper: 3., count: 1 });
+        assert_eq!(report.weights[9], WeightBucket { upper: 10., count: 1 });
+        assert_eq!(report.weights.iter().map(|bucket| bucket.count).sum::<usize>(), 5);
+        assert_eq!(report.degrees, BTreeMap::from([(1, 2), (2, 2), (4, 1)]));
+
+        // Reports show the weights in effect, e.g. after traffic updates.
+        graph.update_weights([(1, 20), (2, 20)]).unwrap();
+        let report = RegionReport::new(&graph, WeightScale(2));
+        assert_eq!(report.zero_weights, 0);
+        assert_eq!(report.min_weight, 2.5);
+
+        let empty = RegionReport::new(&Graph::new(HashMap::new(), HashMap::new(), 2), WeightScale(1));
+        assert_eq!((empty.vertices, empty.max_weight, empty.weights.len()), (0, 0., 1));
+    }
+}
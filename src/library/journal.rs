@@ -0,0 +1,79 @@
+//! Journal of the hops a server took and didn't finish yet, kept in redis so the hops in flight when
+//! a server crashes are served again once it restarts, or by its standby, instead of being lost. A
+//! hop is journaled when it is queued and completed once served, its continuations sent on and its
+//! results settled, the servers it was sent on to journaling the continuations themselves.
+
+use std::env;
+use crate::dedup;
+use crate::domain::HopMessage;
+use crate::redis_connector::RedisConnector;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Redis hash of the unfinished hops of a server as JSON, by [`entry`].
+pub fn key(server_id: usize) -> String {
+    format!("journal_{}", server_id)
+}
+
+/// Reads REQUEST_JOURNAL, whether hops are journaled.
+pub(crate) fn enabled_from_env() -> Result<bool> {
+    match env::var("REQUEST_JOURNAL") {
+        Ok(enabled) => { Ok(enabled.parse()?) }
+        Err(_) => { Ok(false) }
+    }
+}
+
+/// Field of `request` in the journal, shared by its copies, which stand for the same work.
+fn entry(request: &HopMessage) -> String {
+    dedup::fingerprint(request)
+}
+
+#[derive(Clone)]
+pub(crate) struct Journal {
+    redis_connector: RedisConnector,
+    server_id: usize,
+}
+
+impl Journal {
+    pub(crate) fn new(redis_connector: RedisConnector, server_id: usize) -> Self {
+        Self { redis_connector, server_id }
+    }
+
+    /// Journals `request`, which is served without being journaled if redis fails.
+    pub(crate) async fn record(&self, request: &HopMessage) {
+        if let Err(err) = self.redis_connector.journal_hop(self.server_id, &entry(request), request).await {
+            log::warn!("Unable to journal request {}, details: {}", request.request_id, err);
+        }
+    }
+
+    /// Drops `request` from the journal, it is done with.
+    pub(crate) async fn complete(&self, request: &HopMessage) {
+        if let Err(err) = self.redis_connector.complete_hop(self.server_id, &entry(request)).await {
+            log::warn!("Unable to complete request {} in the journal, details: {}", request.request_id, err);
+        }
+    }
+
+    /// Hops journaled by a previous process of the server and never completed. They stay journaled
+    /// until served again.
+    pub(crate) async fn unfinished(&self) -> Result<Vec<HopMessage>> {
+        let hops = self.redis_connector.journaled_hops(self.server_id).await?;
+        Ok(hops.into_iter().map(|(_, request)| request).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::domain::{ClientQuery, HopMessage, NodeInfo};
+    use crate::journal::entry;
+
+    #[test]
+    fn copies_of_a_hop_share_their_entry() {
+        let request = HopMessage::from(ClientQuery::new(7, NodeInfo::new(1, 1), NodeInfo::new(9, 4))).update(vec![], 5, 10, 2);
+        let mut traced = request.clone();
+        traced.trace_context = Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_string());
+        assert_eq!(entry(&request), entry(&traced));
+        // A hop read back from the journal completes the entry it was journaled under.
+        let replayed: HopMessage = serde_json::from_str(&serde_json::to_string(&request).unwrap()).unwrap();
+        assert_eq!(entry(&request), entry(&replayed));
+    }
+}
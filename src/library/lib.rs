@@ -1,32 +1,161 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::sync::Arc;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 use async_channel::{Receiver, Sender, unbounded};
+use futures_util::StreamExt;
+use tracing::{Instrument, Span};
 use tokio::task::JoinHandle;
-use crate::domain::{NodeInfo, PathRequest};
-use crate::graph::{Continuation, Graph, GraphError, PathResult, RegionIdx};
-use crate::graph_provider::{GraphProvider, GroupInfoProvider};
-use crate::redis_connector::{RedisConnector};
+use crate::codec::{WireConfig, WireFormat};
+use crate::data_quality::DataPolicy;
+use crate::dedup::{DedupConfig, Deduplicator};
+use crate::dead_letter::{DeadLetter, Stage};
+use crate::dispatcher::{Slot, WorkQueue, WorkReceiver};
+use crate::events::{EventReplier, ProgressEvents};
+use crate::adjacency::{RegionAdjacency, RegionBorders};
+use crate::arbiter::{HopFailure, QueryStore, ResultArbiter};
+use crate::fanout::{FanoutPolicy, FanoutRanking};
+use crate::domain::{FailureKind, HalfRoute, HopMessage, NodeInfo, PathPoint, RouteResult, SearchDirection};
+use crate::graph::{Avoid, BoundingBox, Continuation, Graph, GraphError, PathResult, RegionIdx, SearchLimits, SuperRegions, WeightScale};
+use crate::graph_provider::{GraphProvider, GroupInfo, GroupInfoProvider, StorageConfig, StorageProvider};
+use crate::heuristic::HeuristicKind;
+use crate::ingress::IngressConfig;
+use crate::journal::Journal;
+use crate::policy::{ExecutionParams, PolicyConfig, PolicyEngine};
+use crate::queues::QueueStats;
+use crate::ratelimit::{DelayedQueries, RateLimitConfig, RateLimiter};
+use crate::registration::RegistrationConfig;
+use crate::replicas::{ReplicaSelection, ReplicaSelector};
+use crate::redis_connector::{NetworkInfo, RedisConnector};
+use crate::regions::{Regions, RegionSource};
+use crate::reload::{Dataset, DatasetHandle};
+use crate::topology::RegionWriter;
+use crate::liveness::{FailoverConfig, LivenessSender};
+use crate::middleware::{HopLogging, MiddlewareChain};
+use crate::mirror::{MeasuringListener, MirroringSender, Transport};
 use crate::node_connector::{NodeSender, ResultReplier, ConnectionError, NodeListener};
+use crate::retention::RetentionConfig;
+use crate::retry::{FailurePolicy, ListenerBackoff, Recovery, ServeRetryPolicy};
+use crate::signing::ClusterSecret;
+use crate::slo::{SloConfig, SloMonitor};
+use crate::standby::{HeartbeatConfig, Lease, LeaseLost, LeaseStore};
+use crate::strategy::{AutoStrategyConfig, Strategy, StrategySelector};
+use crate::transport::{TransportKind, TransportRegistry, TransportRoles, TransportSetup};
+use crate::usage::UsageCounters;
 
-mod node_connector;
-mod graph;
+pub mod node_connector;
+mod adjacency;
+mod arbiter;
+pub mod audit;
+#[cfg(feature = "bucket-queue")]
+mod bucket_queue;
+pub mod client;
+mod codec;
+mod compression;
+pub mod data_quality;
+mod dedup;
+pub mod dead_letter;
+mod dispatcher;
+pub mod events;
+mod fanout;
+pub mod fixtures;
+pub mod gcs_auth;
+pub mod graph;
+pub mod heuristic;
+pub mod inspect;
+mod ingress;
+mod journal;
+mod liveness;
+mod mapped;
+pub mod manifest;
+mod migration;
+mod middleware;
+mod mirror;
+mod packed;
+pub mod partition;
+mod policy;
+#[cfg(feature = "protobuf")]
+pub mod protocol;
+pub mod queues;
+mod ratelimit;
 mod redis_connector;
+pub mod region_cache;
+mod registration;
+mod replicas;
+mod regions;
+mod reload;
+mod retention;
+mod retry;
+mod segments;
+mod shutdown;
+mod signing;
 pub mod graph_provider;
-mod domain;
+pub mod domain;
+pub mod slo;
+pub mod snapshot;
+mod standby;
+mod strategy;
+pub mod telemetry;
+mod topology;
+pub mod traffic;
+pub mod transport;
+pub mod usage;
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Region boundaries a hop may cross unless HOP_LIMIT says otherwise, more than routes through
+/// regions with consistent region bits cross.
+const DEFAULT_HOP_LIMIT: usize = 128;
 
 #[derive(Debug, Clone)]
 pub struct Configuration {
-    google_region: String,
-    google_bucket: String,
-    google_access_key: String,
-    google_secret_key: String,
+    storage: StorageConfig,
     id: usize,
     redis_url: String,
     redis_connection_count: usize,
     worker_count: usize,
+    heuristic: HeuristicKind,
+    strategy: Option<AutoStrategyConfig>,
+    weight_scale: WeightScale,
+    data_policy: DataPolicy,
+    mapped_regions_dir: Option<PathBuf>,
+    search_limits: SearchLimits,
+    fanout: FanoutPolicy,
+    policies: PolicyConfig,
+    slo: SloConfig,
+    retention: RetentionConfig,
+    arbitration_timeout: Option<Duration>,
+    transport_mirror: Option<f64>,
+    ingress: Option<IngressConfig>,
+    wire: WireConfig,
+    progress_channel: Option<String>,
+    bidirectional: bool,
+    boundary_shortcuts: bool,
+    lazy_regions: bool,
+    topology_write_back: bool,
+    region_load_concurrency: usize,
+    region_bounds: Option<BoundingBox>,
+    standby: bool,
+    heartbeat: HeartbeatConfig,
+    snapshot_interval: Option<Duration>,
+    reload_interval: Option<Duration>,
+    shutdown_timeout: Duration,
+    worker_queue_capacity: usize,
+    request_timeout: Option<Duration>,
+    dedup: DedupConfig,
+    serve_retries: ServeRetryPolicy,
+    rate_limit: RateLimitConfig,
+    failover: FailoverConfig,
+    hop_limit: usize,
+    registration: RegistrationConfig,
+    replica_selection: ReplicaSelection,
+    journal: bool,
+    usage_interval: Duration,
+    failure_policy: FailurePolicy,
+    compact_paths: bool,
 }
 
 impl Configuration {
@@ -51,6 +180,7 @@ impl Configuration {
                 }
             }
         };
+        let heartbeat = HeartbeatConfig::from_env()?;
         let redis_url = match env::var("REDIS_URL") {
             Ok(url) => { url }
             Err(_) => {
@@ -65,15 +195,88 @@ impl Configuration {
         };
 
 
+        let weight_scale = WeightScale::from_env()?;
+        let topology_write_back = match env::var("TOPOLOGY_WRITE_BACK") {
+            Ok(enabled) => { enabled.parse()? }
+            Err(_) => { false }
+        };
+        let region_bounds = match env::var("REGION_BBOX") {
+            Ok(bounds) => { Some(bounds.parse()?) }
+            Err(_) => { None }
+        };
+        if topology_write_back && region_bounds.is_some() {
+            return Err("TOPOLOGY_WRITE_BACK can't be used with REGION_BBOX, it would store only the part of each region loaded".into());
+        }
+
         Ok(Configuration {
-            google_region: env::var("GOOGLE_CLOUD_REGION")?,
-            google_bucket: env::var("GOOGLE_CLOUD_BUCKET")?,
-            google_access_key: env::var("GOOGLE_ACCESS_KEY")?,
-            google_secret_key: env::var("GOOGLE_SECRET_KEY")?,
+            storage: StorageConfig::from_env()?,
             id,
             redis_url,
             redis_connection_count: env::var("REDIS_CONNECTION_COUNT")?.parse()?,
             worker_count: env::var("WORKER_COUNT")?.parse()?,
+            heuristic: HeuristicKind::from_env()?.scaled(weight_scale),
+            strategy: AutoStrategyConfig::from_env()?,
+            weight_scale,
+            data_policy: DataPolicy::from_env()?,
+            mapped_regions_dir: env::var("MAPPED_REGIONS_DIR").ok().map(PathBuf::from),
+            search_limits: SearchLimits::from_env()?,
+            fanout: FanoutPolicy::from_env()?,
+            policies: PolicyConfig::from_env()?,
+            slo: SloConfig::from_env()?,
+            retention: RetentionConfig::from_env()?,
+            arbitration_timeout: arbiter::timeout_from_env()?,
+            transport_mirror: mirror::fraction_from_env()?,
+            ingress: IngressConfig::from_env()?,
+            wire: WireConfig::new(WireFormat::from_env()?, codec::compression_threshold_from_env()?, ClusterSecret::from_env()?),
+            progress_channel: events::progress_channel_from_env(),
+            bidirectional: match env::var("BIDIRECTIONAL_SEARCH") {
+                Ok(enabled) => { enabled.parse()? }
+                Err(_) => { false }
+            },
+            boundary_shortcuts: match env::var("BOUNDARY_SHORTCUTS") {
+                Ok(enabled) => { enabled.parse()? }
+                Err(_) => { false }
+            },
+            lazy_regions: match env::var("LAZY_REGIONS") {
+                Ok(enabled) => { enabled.parse()? }
+                Err(_) => { false }
+            },
+            topology_write_back,
+            region_load_concurrency: match env::var("REGION_LOAD_CONCURRENCY") {
+                Ok(count) => { count.parse::<usize>()?.max(1) }
+                Err(_) => { 4 }
+            },
+            region_bounds,
+            standby: match env::var("STANDBY") {
+                Ok(enabled) => { enabled.parse()? }
+                Err(_) => { false }
+            },
+            heartbeat,
+            snapshot_interval: match env::var("NETWORK_SNAPSHOT_INTERVAL_SECS") {
+                Ok(secs) => { Some(Duration::from_secs(secs.parse()?)) }
+                Err(_) => { None }
+            },
+            reload_interval: reload::interval_from_env()?,
+            shutdown_timeout: shutdown::timeout_from_env()?,
+            worker_queue_capacity: queues::capacity_from_env()?,
+            request_timeout: match env::var("REQUEST_TIMEOUT_MS") {
+                Ok(millis) => { Some(Duration::from_millis(millis.parse()?)) }
+                Err(_) => { None }
+            },
+            dedup: DedupConfig::from_env()?,
+            serve_retries: ServeRetryPolicy::from_env()?,
+            rate_limit: RateLimitConfig::from_env()?,
+            failover: FailoverConfig::from_env(&heartbeat)?,
+            hop_limit: match env::var("HOP_LIMIT") {
+                Ok(limit) => { limit.parse()? }
+                Err(_) => { DEFAULT_HOP_LIMIT }
+            },
+            registration: RegistrationConfig::from_env()?,
+            replica_selection: ReplicaSelection::from_env()?,
+            journal: journal::enabled_from_env()?,
+            usage_interval: usage::interval_from_env()?,
+            failure_policy: FailurePolicy::from_env()?,
+            compact_paths: segments::enabled_from_env()?,
         })
     }
 }
@@ -85,32 +288,260 @@ pub struct Context {
     redis_connector: RedisConnector,
 }
 
+/// Serves the `Node` gRPC service on GRPC_LISTEN_ADDR.
+#[cfg(feature = "grpc")]
+async fn grpc_service(spool_size: usize, wire: WireConfig) -> Result<(Box<dyn NodeListener>, Box<dyn ResultReplier>)> {
+    let listener = tokio::net::TcpListener::bind(&*env::var("GRPC_LISTEN_ADDR")?).await?;
+    let (node_listener, result_reply) = node_connector::grpc_connector::serve(listener, spool_size, wire);
+    Ok((Box::new(node_listener), Box::new(result_reply)))
+}
+
+#[cfg(not(feature = "grpc"))]
+async fn grpc_service(_spool_size: usize, _wire: WireConfig) -> Result<(Box<dyn NodeListener>, Box<dyn ResultReplier>)> {
+    Err("The gRPC transport needs a build with the grpc feature")?
+}
+
+#[cfg(feature = "grpc")]
+async fn grpc_sender(network_info: NetworkInfo, wire: WireConfig) -> Result<Box<dyn NodeSender>> {
+    Ok(Box::new(node_connector::grpc_connector::GrpcConnectionsManager::new(network_info, wire).await?))
+}
+
+#[cfg(not(feature = "grpc"))]
+async fn grpc_sender(_network_info: NetworkInfo, _wire: WireConfig) -> Result<Box<dyn NodeSender>> {
+    Err("The gRPC transport needs a build with the grpc feature")?
+}
+
+/// Reads REDIS_STREAMS, whether requests are exchanged over redis streams instead of pub/sub.
+fn redis_streams_from_env() -> Result<bool> {
+    match env::var("REDIS_STREAMS") {
+        Ok(enabled) => { Ok(enabled.parse()?) }
+        Err(_) => { Ok(false) }
+    }
+}
+
 impl Context {
+    /// Context of the transports picked for each role, one of the single transport contexts if
+    /// they all share a built-in one. Roles may be given to the transports of `registry`.
+    pub async fn from_roles(config: &Configuration, roles: TransportRoles, registry: &TransportRegistry) -> Result<Context> {
+        match roles.single() {
+            Some(TransportKind::Redis) => {
+                log::info!("Launching in Redis mode");
+                Self::redis_ctx(config).await
+            }
+            Some(TransportKind::Zmq) => {
+                log::info!("Launching in ZMQ mode");
+                Self::zmq_ctx(config).await
+            }
+            Some(TransportKind::Tcp) => {
+                log::info!("Launching in TCP mode");
+                Self::tcp_ctx(config).await
+            }
+            #[cfg(feature = "grpc")]
+            Some(TransportKind::Grpc) => {
+                log::info!("Launching in gRPC mode");
+                Self::grpc_ctx(config).await
+            }
+            #[cfg(not(feature = "grpc"))]
+            Some(TransportKind::Grpc) => { Err("The gRPC transport needs a build with the grpc feature")? }
+            Some(TransportKind::Custom(_)) | None => {
+                log::info!("Launching with a {}", roles);
+                Self::mixed_ctx(config, roles, registry).await
+            }
+        }
+    }
+
     pub async fn redis_ctx(config: &Configuration) -> Result<Context> {
-        let redis_connector = redis_connector::RedisConnector::new(&*config.redis_url, config.redis_connection_count).await?;
-        let node_listener = Box::new(node_connector::redis_connector::RedisNodeListener::new(&redis_connector, config.id).await?);
-        let result_reply = Box::new(node_connector::redis_connector::RedisReplier::new(redis_connector.clone()).await?);
+        let redis_connector = redis_connector::RedisConnector::new(&config.redis_url, config.redis_connection_count).await?;
+        let streams = redis_streams_from_env()?;
+        let (node_listener, node_sender_mgr): (Box<dyn NodeListener>, Box<dyn NodeSender>) = if streams {
+            log::info!("Exchanging requests over redis streams");
+            let max_len = node_connector::stream_connector::max_len_from_env()?;
+            // A standby reads once it takes over, starting with what its predecessor left unacknowledged.
+            let node_listener = if config.standby {
+                node_connector::stream_connector::RedisStreamListener::deferred(&redis_connector, config.id, config.wire.clone())
+            } else {
+                node_connector::stream_connector::RedisStreamListener::new(&redis_connector, config.id, config.wire.clone()).await?
+            };
+            (Box::new(node_listener), Box::new(node_connector::stream_connector::RedisStreamSender::new(redis_connector.clone(), max_len, config.wire.clone())))
+        } else {
+            // A standby subscribes once it takes over, or it would replay everything sent to the group meanwhile.
+            let node_listener = if config.standby {
+                node_connector::redis_connector::RedisNodeListener::deferred(&redis_connector, config.id, config.wire.clone())
+            } else {
+                node_connector::redis_connector::RedisNodeListener::new(&redis_connector, config.id, config.wire.clone()).await?
+            };
+            (Box::new(node_listener), Box::new(node_connector::redis_connector::RedisConnectionsManager::new(redis_connector.clone(), config.wire.clone()).await?))
+        };
+        let result_reply = Box::new(node_connector::redis_connector::RedisReplier::new(redis_connector.clone(), config.wire.clone()).await?);
 
-        let node_sender_mgr = Box::new(node_connector::redis_connector::RedisConnectionsManager::new(redis_connector.clone()).await?);
-        Ok(Context {
+        let context = Context {
             redis_connector,
             result_reply,
             node_listener,
             node_sender_mgr,
-        })
+        };
+        match config.transport_mirror {
+            Some(fraction) => { context.mirrored(Transport::Redis, fraction, config).await }
+            None => { Ok(context) }
+        }
     }
 
     pub async fn zmq_ctx(config: &Configuration) -> Result<Context> {
+        if config.standby {
+            Err("Standby mode is only supported in redis mode")?
+        }
+        let listen_addr = env::var("LISTEN_ADDR")?;
+        let reply_addr = env::var("REPLY_ADDR")?;
+        let spool_size = match env::var("REPLY_SPOOL_SIZE") {
+            Ok(size) => { size.parse()? }
+            Err(_) => { node_connector::zmq_connector::DEFAULT_SPOOL_SIZE }
+        };
+
+        let redis_connector = redis_connector::RedisConnector::new(&config.redis_url, config.redis_connection_count).await?;
+        let node_listener = Box::new(node_connector::zmq_connector::ZMQNodeListener::new(&listen_addr, config.wire.clone()).await?);
+        let result_reply = Box::new(node_connector::zmq_connector::ZMQReplier::new(&reply_addr, spool_size, config.wire.clone()).await?);
+
+        let network_mgr = redis_connector.get_servers_info().await?;
+
+        let node_sender_mgr = Box::new(node_connector::zmq_connector::ZMQConnectionsManager::new(network_mgr.network_info, node_connector::zmq_connector::pool_size_from_env()?, config.wire.clone()).await?);
+        let context = Context {
+            redis_connector,
+            result_reply,
+            node_listener,
+            node_sender_mgr,
+        };
+        match config.transport_mirror {
+            Some(fraction) => { context.mirrored(Transport::Zmq, fraction, config).await }
+            None => { Ok(context) }
+        }
+    }
+
+    /// Exchanges length-prefixed frames over plain TCP connections: listens on LISTEN_ADDR, sends
+    /// results to REPLY_ADDR and hops to the addresses in `server_info`.
+    pub async fn tcp_ctx(config: &Configuration) -> Result<Context> {
+        if config.standby {
+            Err("Standby mode is only supported in redis mode")?
+        }
+        if config.transport_mirror.is_some() {
+            Err("Transport mirroring is only supported in redis and ZMQ mode")?
+        }
         let listen_addr = env::var("LISTEN_ADDR")?;
         let reply_addr = env::var("REPLY_ADDR")?;
 
-        let redis_connector = redis_connector::RedisConnector::new(&*config.redis_url, config.redis_connection_count).await?;
-        let node_listener = Box::new(node_connector::zmq_connector::ZMQNodeListener::new(&*listen_addr).await?);
-        let result_reply = Box::new(node_connector::zmq_connector::ZMQReplier::new(&*reply_addr).await?);
+        let redis_connector = redis_connector::RedisConnector::new(&config.redis_url, config.redis_connection_count).await?;
+        let node_listener = Box::new(node_connector::tcp_connector::TcpNodeListener::new(&listen_addr, config.wire.clone()).await?);
+        let result_reply = Box::new(node_connector::tcp_connector::TcpReplier::new(&reply_addr, config.wire.clone()));
+
+        let network_mgr = redis_connector.get_servers_info().await?;
+
+        let node_sender_mgr = Box::new(node_connector::tcp_connector::TcpConnectionsManager::new(network_mgr.network_info, config.wire.clone()).await?);
+        Ok(Context {
+            redis_connector,
+            result_reply,
+            node_listener,
+            node_sender_mgr,
+        })
+    }
+
+    /// Serves the `Node` gRPC service on GRPC_LISTEN_ADDR, forwarding hops to the addresses in
+    /// `server_info` and streaming results to the collectors subscribed to it.
+    #[cfg(feature = "grpc")]
+    pub async fn grpc_ctx(config: &Configuration) -> Result<Context> {
+        if config.standby {
+            Err("Standby mode is only supported in redis mode")?
+        }
+        if config.transport_mirror.is_some() {
+            Err("Transport mirroring is only supported in redis and ZMQ mode")?
+        }
+        let listen_addr = env::var("GRPC_LISTEN_ADDR")?;
+        let spool_size = match env::var("REPLY_SPOOL_SIZE") {
+            Ok(size) => { size.parse()? }
+            Err(_) => { node_connector::zmq_connector::DEFAULT_SPOOL_SIZE }
+        };
+
+        let redis_connector = redis_connector::RedisConnector::new(&config.redis_url, config.redis_connection_count).await?;
+        let listener = tokio::net::TcpListener::bind(&*listen_addr).await?;
+        let (node_listener, result_reply) = node_connector::grpc_connector::serve(listener, spool_size, config.wire.clone());
 
         let network_mgr = redis_connector.get_servers_info().await?;
 
-        let node_sender_mgr = Box::new(node_connector::zmq_connector::ZMQConnectionsManager::new(network_mgr.network_info).await?);
+        let node_sender_mgr = Box::new(node_connector::grpc_connector::GrpcConnectionsManager::new(network_mgr.network_info, config.wire.clone()).await?);
+        Ok(Context {
+            redis_connector,
+            result_reply: Box::new(result_reply),
+            node_listener: Box::new(node_listener),
+            node_sender_mgr,
+        })
+    }
+
+    /// Picks the transport of every role on its own. Other servers forward hops over the sender's
+    /// transport, so the server listens on it besides the listener's. ZMQ and TCP listen on
+    /// LISTEN_ADDR and send results to REPLY_ADDR, so a server listens on one of them at most.
+    pub async fn mixed_ctx(config: &Configuration, roles: TransportRoles, registry: &TransportRegistry) -> Result<Context> {
+        if config.standby {
+            Err("Standby mode is only supported in redis mode")?
+        }
+        if config.transport_mirror.is_some() {
+            Err("Transport mirroring is only supported in redis and ZMQ mode")?
+        }
+        let listened = roles.listened();
+        if listened.contains(&TransportKind::Zmq) && listened.contains(&TransportKind::Tcp) {
+            Err("ZMQ and TCP listeners can't share LISTEN_ADDR")?
+        }
+        let redis_connector = redis_connector::RedisConnector::new(&config.redis_url, config.redis_connection_count).await?;
+        let streams = redis_streams_from_env()?;
+        let spool_size = match env::var("REPLY_SPOOL_SIZE") {
+            Ok(size) => { size.parse()? }
+            Err(_) => { node_connector::zmq_connector::DEFAULT_SPOOL_SIZE }
+        };
+        let setup = TransportSetup::new(config.id, redis_connector.clone(), config.wire.clone());
+        // The gRPC service takes requests and streams results, whichever of the two it is used for.
+        let (mut grpc_listener, mut grpc_replier) = if listened.contains(&TransportKind::Grpc) || roles.replier == TransportKind::Grpc {
+            let (node_listener, result_reply) = grpc_service(spool_size, config.wire.clone()).await?;
+            (Some(node_listener), Some(result_reply))
+        } else {
+            (None, None)
+        };
+
+        let mut node_listeners: Vec<Box<dyn NodeListener>> = vec![];
+        for kind in listened {
+            node_listeners.push(match kind {
+                TransportKind::Redis if streams => { Box::new(node_connector::stream_connector::RedisStreamListener::new(&redis_connector, config.id, config.wire.clone()).await?) }
+                TransportKind::Redis => { Box::new(node_connector::redis_connector::RedisNodeListener::new(&redis_connector, config.id, config.wire.clone()).await?) }
+                TransportKind::Zmq => { Box::new(node_connector::zmq_connector::ZMQNodeListener::new(&env::var("LISTEN_ADDR")?, config.wire.clone()).await?) }
+                TransportKind::Tcp => { Box::new(node_connector::tcp_connector::TcpNodeListener::new(&env::var("LISTEN_ADDR")?, config.wire.clone()).await?) }
+                TransportKind::Grpc => { grpc_listener.take().expect("The gRPC service is served above") }
+                TransportKind::Custom(name) => { registry.plugin(&name)?.listener(&setup).await? }
+            });
+        }
+        let node_listener: Box<dyn NodeListener> = match node_listeners.len() {
+            1 => { node_listeners.pop().unwrap() }
+            _ => { Box::new(transport::MergedListener::new(node_listeners)) }
+        };
+
+        let node_sender_mgr: Box<dyn NodeSender> = match &roles.sender {
+            TransportKind::Redis if streams => {
+                Box::new(node_connector::stream_connector::RedisStreamSender::new(redis_connector.clone(), node_connector::stream_connector::max_len_from_env()?, config.wire.clone()))
+            }
+            TransportKind::Redis => { Box::new(node_connector::redis_connector::RedisConnectionsManager::new(redis_connector.clone(), config.wire.clone()).await?) }
+            TransportKind::Custom(name) => { registry.plugin(name)?.sender(&setup).await? }
+            kind => {
+                let network_info = redis_connector.get_servers_info().await?.network_info;
+                match kind {
+                    TransportKind::Zmq => { Box::new(node_connector::zmq_connector::ZMQConnectionsManager::new(network_info, node_connector::zmq_connector::pool_size_from_env()?, config.wire.clone()).await?) }
+                    TransportKind::Tcp => { Box::new(node_connector::tcp_connector::TcpConnectionsManager::new(network_info, config.wire.clone()).await?) }
+                    _ => { grpc_sender(network_info, config.wire.clone()).await? }
+                }
+            }
+        };
+
+        let result_reply: Box<dyn ResultReplier> = match &roles.replier {
+            TransportKind::Redis => { Box::new(node_connector::redis_connector::RedisReplier::new(redis_connector.clone(), config.wire.clone()).await?) }
+            TransportKind::Zmq => { Box::new(node_connector::zmq_connector::ZMQReplier::new(&env::var("REPLY_ADDR")?, spool_size, config.wire.clone()).await?) }
+            TransportKind::Tcp => { Box::new(node_connector::tcp_connector::TcpReplier::new(&env::var("REPLY_ADDR")?, config.wire.clone())) }
+            TransportKind::Grpc => { grpc_replier.take().expect("The gRPC service is served above") }
+            TransportKind::Custom(name) => { registry.plugin(name)?.replier(&setup).await? }
+        };
         Ok(Context {
             redis_connector,
             result_reply,
@@ -118,196 +549,1612 @@ impl Context {
             node_sender_mgr,
         })
     }
+
+    /// Mirrors a `fraction` of the hops sent over `transport` over the other one, measuring both
+    /// on arrival. Mirroring over ZMQ listens on LISTEN_ADDR and sends to the addresses in `server_info`.
+    async fn mirrored(self, transport: Transport, fraction: f64, config: &Configuration) -> Result<Context> {
+        log::info!("Mirroring {} of the hops over {}", fraction, transport.other().name());
+        let mirror_sender: Box<dyn NodeSender> = match transport.other() {
+            Transport::Redis => {
+                let listener = node_connector::redis_connector::RedisNodeListener::new(&self.redis_connector, config.id, config.wire.clone()).await?;
+                mirror::spawn_mirror_listener(listener, Transport::Redis, self.redis_connector.clone());
+                Box::new(node_connector::redis_connector::RedisConnectionsManager::new(self.redis_connector.clone(), config.wire.clone()).await?)
+            }
+            Transport::Zmq => {
+                let listener = node_connector::zmq_connector::ZMQNodeListener::new(&env::var("LISTEN_ADDR")?, config.wire.clone()).await?;
+                mirror::spawn_mirror_listener(listener, Transport::Zmq, self.redis_connector.clone());
+                let network_mgr = self.redis_connector.get_servers_info().await?;
+                Box::new(node_connector::zmq_connector::ZMQConnectionsManager::new(network_mgr.network_info, node_connector::zmq_connector::pool_size_from_env()?, config.wire.clone()).await?)
+            }
+        };
+        Ok(Context {
+            node_listener: Box::new(MeasuringListener::new(self.node_listener, transport, self.redis_connector.clone())),
+            node_sender_mgr: Box::new(MirroringSender::new(self.node_sender_mgr, mirror_sender, transport, fraction, self.redis_connector.clone())),
+            result_reply: self.result_reply,
+            redis_connector: self.redis_connector,
+        })
+    }
+}
+
+
+/// Loads the regions of a group and builds what searching them takes, at startup and for every
+/// new version of the data set.
+struct DatasetLoader {
+    config: Configuration,
+    provider: Box<dyn StorageProvider>,
+    mapped_provider: Option<graph_provider::mapped::MappedGraphProvider>,
+}
+
+impl DatasetLoader {
+    /// Also reads the manifest of the group, see [`DatasetLoader::dataset_version`].
+    async fn group_info(&self, group_id: usize) -> Result<GroupInfo> {
+        let group_info = self.provider.get_info(group_id).await
+            .map_err(|err| format!("Unable to load group {}, details: {}", group_id, err))?;
+        Ok(group_info)
+    }
+
+    fn dataset_version(&self) -> Option<String> {
+        self.provider.dataset_version()
+    }
+
+    async fn load_region(&self, region_id: RegionIdx, super_regions: &Arc<SuperRegions>) -> Result<Graph> {
+        log::info!("Loading region {}", region_id);
+        let bounds = self.config.region_bounds.as_ref();
+        let mapped_provider = self.mapped_provider.as_ref().map(|provider| provider.for_version(self.dataset_version().as_deref()));
+        let mut graph = match mapped_provider.filter(|provider| provider.has_region(region_id)) {
+            Some(provider) => {
+                log::info!("Mapping region {} from disk", region_id);
+                match bounds {
+                    Some(bounds) => { provider.get_region_within(region_id, bounds).await }
+                    None => { provider.get_region(region_id).await }
+                }
+            }
+            None => {
+                match bounds {
+                    Some(bounds) => { self.provider.get_region_within(region_id, bounds).await }
+                    None => { self.provider.get_region(region_id).await }
+                }
+            }
+        }.map_err(|err| format!("Unable to load region {}, details: {}", region_id, err))?;
+        if !super_regions.is_empty() {
+            graph.set_super_regions(super_regions.clone());
+        }
+        if self.config.boundary_shortcuts {
+            let boundary_nodes = graph.build_shortcuts();
+            log::info!("Precomputed shortcuts between {} boundary nodes of region {}", boundary_nodes, region_id);
+        }
+        log::debug!("Region {} successfully loaded", region_id);
+        Ok(graph)
+    }
+
+    /// Loads every region of the group, REGION_LOAD_CONCURRENCY at a time, or none with LAZY_REGIONS,
+    /// leaving each one to be loaded and registered by [`LazySource`] when first searched.
+    async fn load_regions(self: &Arc<Self>,
+                          group_info: &GroupInfo,
+                          redis_connector: &RedisConnector,
+                          lease_holder: &str) -> Result<Regions> {
+        let super_regions = Arc::new(SuperRegions::new(&group_info.super_regions)?);
+        if self.config.lazy_regions {
+            let source = LazySource {
+                loader: self.clone(),
+                super_regions,
+                redis_connector: redis_connector.clone(),
+                group_id: group_info.group_id,
+                lease_holder: lease_holder.to_string(),
+            };
+            return Ok(Regions::lazy(group_info.regions.iter().copied(), Box::new(source)))
+        }
+        // Each region is loaded on a task of its own, parsing them in parallel too.
+        let mut loads = futures_util::stream::iter(group_info.regions.iter().copied())
+            .map(|region_id| {
+                let (loader, super_regions) = (self.clone(), super_regions.clone());
+                tokio::task::spawn(async move { (region_id, loader.load_region(region_id, &super_regions).await) })
+            })
+            .buffer_unordered(self.config.region_load_concurrency);
+        let mut graphs = HashMap::new();
+        while let Some(load) = loads.next().await {
+            let (region_id, graph) = load?;
+            graphs.insert(region_id, graph?);
+        }
+        Ok(Regions::loaded(graphs))
+    }
+
+    /// Landmarks and the automatic strategy are computed from the regions loaded, none when they are
+    /// loaded lazily, which leaves searches to fall back to the zero heuristic.
+    fn build(&self, version: Option<String>, regions: Regions) -> Result<Dataset> {
+        let config = &self.config;
+        let graphs: Vec<&Graph> = regions.iter().map(|(_, graph)| graph).collect();
+        let heuristic = config.heuristic.build(graphs.iter().copied());
+        let strategy = config.strategy.map(|strategy| {
+            let tables = matches!(config.heuristic, HeuristicKind::Landmarks(_)).then(|| heuristic.clone());
+            Arc::new(StrategySelector::new(strategy, graphs.iter().copied(), tables))
+        });
+        let defaults = ExecutionParams {
+            heuristic,
+            strategy,
+            search_limits: config.search_limits,
+            fanout: config.fanout,
+            max_cost: None,
+            max_region_hops: None,
+            use_cost_cache: true,
+            bidirectional: config.bidirectional,
+        };
+        Ok(Dataset {
+            version,
+            policies: PolicyEngine::new(&config.policies, defaults, config.weight_scale, graphs.iter().copied())?,
+            graphs: Arc::new(regions),
+        })
+    }
+}
+
+/// Loads the regions of a group started with LAZY_REGIONS, registering each one in redis once loaded
+/// like a primary registers all of them at startup.
+struct LazySource {
+    loader: Arc<DatasetLoader>,
+    super_regions: Arc<SuperRegions>,
+    redis_connector: RedisConnector,
+    group_id: usize,
+    lease_holder: String,
 }
 
+#[async_trait::async_trait]
+impl RegionSource for LazySource {
+    async fn load(&self, region: RegionIdx) -> Result<Graph> {
+        let graph = self.loader.load_region(region, &self.super_regions).await?;
+        if self.redis_connector.get_lease_holder(self.group_id).await?.as_deref() == Some(self.lease_holder.as_str()) {
+            self.redis_connector.set_region(&graph, region).await?;
+            self.redis_connector.set_region_borders(&RegionBorders::new(&graph)).await?;
+        }
+        Ok(graph)
+    }
+}
+
+/// Writes regions patched by topology updates back to the storage they were loaded from, see
+/// TOPOLOGY_WRITE_BACK. Only the process holding the lease on the group writes, so a standby never
+/// overwrites the regions of the primary.
+struct LeaseWriter {
+    loader: Arc<DatasetLoader>,
+    redis_connector: RedisConnector,
+    group_id: usize,
+    lease_holder: String,
+}
+
+#[async_trait::async_trait]
+impl RegionWriter for LeaseWriter {
+    async fn write(&self, graph: &Graph) -> Result<()> {
+        if self.redis_connector.get_lease_holder(self.group_id).await?.as_deref() != Some(self.lease_holder.as_str()) {
+            log::debug!("Not writing region {} back, the lease on group {} is held elsewhere", graph.region_idx, self.group_id);
+            return Ok(());
+        }
+        self.loader.provider.put_region(graph).await
+            .map_err(|err| format!("Unable to write region {}, details: {}", graph.region_idx, err).into())
+    }
+}
 
 pub struct Server {
     node_listener: Box<dyn NodeListener>,
     workers: Vec<JoinHandle<()>>,
-    task_senders: Vec<Sender<PathRequest>>,
-    free_receiver: Receiver<usize>,
-    free_sender: Sender<usize>,
+    work_queue: WorkQueue<Task>,
+    dataset: DatasetHandle,
+    redis_connector: RedisConnector,
+    /// Where the lease on the group is released and the server deregistered as it shuts down.
+    leases: Arc<dyn LeaseStore>,
+    result_reply: Box<dyn ResultReplier>,
+    heartbeat: Option<JoinHandle<()>>,
+    /// Completes once another process took the group over, which stops the server.
+    lease_lost: Option<LeaseLost>,
+    registration: Option<JoinHandle<()>>,
+    /// Tasks following the weight, topology and border updates published for the regions.
+    updates: Vec<JoinHandle<()>>,
+    lease_holder: String,
+    group_id: usize,
+    server_id: usize,
+    shutdown_timeout: Duration,
+    queue_stats: Arc<QueueStats>,
+    request_timeout: Option<Duration>,
+    results: ResultArbiter,
+    weight_scale: WeightScale,
+    rate_limiter: RateLimiter,
+    /// Queries waiting for their turn under the rate limits.
+    delayed: DelayedQueries<HopMessage>,
+    listener_backoff: ListenerBackoff,
+    failure_policy: FailurePolicy,
+    /// Restarts of the listener since it last read a request.
+    listener_restarts: u32,
+    /// Whether queries entering the cluster here keep their paths in redis.
+    compact_paths: bool,
+    journal: Option<Journal>,
+    /// Hops a previous process of the server journaled and didn't finish, queued first.
+    unfinished: Vec<HopMessage>,
 }
 
-struct Worker {
+/// Hop dispatched to a worker, with its parameters, the regions it is served on and the span it is
+/// served in, opened when the hop was queued.
+type Task = (HopMessage, Arc<ExecutionParams>, Arc<Regions>, Span);
+
+/// What the workers of a server share, each of them holds a clone.
+#[derive(Clone)]
+struct WorkerContext {
     redis_connector: RedisConnector,
-    graphs: Arc<HashMap<RegionIdx, Graph>>,
-    result_reply: Box<dyn ResultReplier>,
+    queries: Arc<dyn QueryStore>,
+    weight_scale: WeightScale,
+    slo: Arc<SloMonitor>,
+    retention: Arc<RetentionConfig>,
+    adjacency: Arc<RwLock<RegionAdjacency>>,
+    results: ResultArbiter,
+    middleware: MiddlewareChain,
     node_sender_mgr: Box<dyn NodeSender>,
-    task_receiver: Receiver<PathRequest>,
-    free_sender: Sender<usize>,
+    replicas: ReplicaSelector,
+    work_receiver: WorkReceiver<Task>,
+    queue_stats: Arc<QueueStats>,
+    retries: ServeRetryPolicy,
+    /// Region boundaries a hop may cross before it is taken for bouncing between regions.
+    hop_limit: usize,
+    journal: Option<Journal>,
+    usage: Arc<UsageCounters>,
+    dataset: DatasetHandle,
+    server_id: usize,
+}
+
+struct Worker {
+    context: WorkerContext,
+    /// Hops this worker sent on to its own server, served before the queued ones. Unbounded,
+    /// as workers waiting for room in the work queue they empty could wait forever.
+    requeued: (Sender<Task>, Receiver<Task>),
     id: usize,
 }
 
 impl Worker {
-    async fn new(redis_connector: RedisConnector,
-                 graphs: Arc<HashMap<RegionIdx, Graph>>,
-                 zmq_reply: Box<dyn ResultReplier>,
-                 zmq_conn_mgr: Box<dyn NodeSender>,
-                 task_receiver: Receiver<PathRequest>,
-                 free_sender: Sender<usize>,
-                 id: usize) -> Result<Worker> {
-        free_sender.send(id).await?;
-        Ok(Worker {
-            redis_connector,
-            graphs,
-            result_reply: zmq_reply,
-            node_sender_mgr: zmq_conn_mgr,
-            task_receiver,
-            free_sender,
+    fn new(context: WorkerContext, id: usize) -> Worker {
+        Worker {
+            context,
+            requeued: unbounded(),
             id,
-        })
+        }
     }
 
-    async fn serve_request(&self, request: &PathRequest) -> Result<()> {
-        let mut start_region = None;
-        for (region_idx, graph) in self.graphs.iter() {
-            if graph.get_node(request.last).is_some() {
-                start_region = Some(region_idx);
+    /// Lowest of the cost carried by the request and the one shared through redis, if the policy allows it.
+    async fn best_known_cost(&self, request: &HopMessage, params: &ExecutionParams) -> Option<u64> {
+        if !params.use_cost_cache {
+            return request.best_known_cost;
+        }
+        let shared = match self.context.queries.get_best_cost(request.request_id).await {
+            Ok(cost) => { cost }
+            Err(err) => {
+                log::warn!("Unable to fetch best known cost of request {}, details: {}", request.request_id, err);
+                None
             }
+        };
+        match (request.best_known_cost, shared) {
+            (Some(a), Some(b)) => { Some(a.min(b)) }
+            (a, b) => { a.or(b) }
         }
-        let start_region = match start_region {
-            Some(r) => {r}
-            None => {
-                log::warn!("Received request to node {}, however this worker does not serve it's region. Request: {:?}", request.last, request);
-                Err("Not served region")?
+    }
+
+    /// Sends the result of `request`, or holds it back until the query ends if results are arbitrated.
+    async fn reply(&self, request: &HopMessage, mut result: RouteResult) -> Result<()> {
+        result.weight_scale = self.context.weight_scale;
+        self.context.results.offer(request.priority_class.as_deref(), result).await
+    }
+
+    /// Keeps the route of a forward hop which reached the target for later queries to reuse, for the
+    /// retention of its class.
+    async fn keep_route(&self, request: &HopMessage, path: &[PathPoint]) {
+        let ttl = match self.context.retention.ttl(request.priority_class.as_deref()) {
+            Some(ttl) if request.direction == SearchDirection::Forward => { ttl }
+            _ => { return }
+        };
+        if let Err(err) = self.context.redis_connector.store_route(request.request_id, &request.stored_route(path), ttl).await {
+            log::warn!("Unable to store the route of request {}, details: {}", request.request_id, err);
+        }
+    }
+
+    /// Hop continuing `request` along the stored route of `previous` from where it entered the target
+    /// region, after searching the way from the source to the route. `None` if the route isn't kept
+    /// or doesn't fit the request.
+    async fn reuse_route(&self, request: &HopMessage, previous: usize, params: &ExecutionParams, graphs: &Regions) -> Result<Option<HopMessage>> {
+        if !request.avoid().is_empty() {
+            return Ok(None)
+        }
+        let graph = match graphs.get(&request.source.1).await? {
+            Some(graph) => { graph }
+            None => { return Ok(None) }
+        };
+        let route = match self.context.redis_connector.get_route(previous).await? {
+            Some(route) if route.reusable_for(request) => { route }
+            _ => { return Ok(None) }
+        };
+        let entry = route.entries[0];
+        match graph.find_way_local(request.source, NodeInfo(entry.node, entry.region), &*params.heuristic, &Avoid::default(), &params.search_limits).await {
+            Ok(PathResult::TargetReached(path, cost)) => {
+                let reusing = request.reusing(&route, path, cost);
+                Ok((!reusing.exceeds_budget(0)).then_some(reusing))
+            }
+            _ => { Ok(None) }
+        }
+    }
+
+    /// Orders continuations by their cost and the least cost of the boundary crossings left to the
+    /// target region, dropping those which can't beat `best_known_cost`. Regions no server reported
+    /// borders of come last.
+    fn rank_by_distance(&self, target_region: RegionIdx,
+                        candidates: Vec<(RegionIdx, HopMessage)>,
+                        best_known_cost: Option<u64>) -> Vec<(RegionIdx, HopMessage)> {
+        let distances = self.context.adjacency.read().unwrap().distances_to(target_region);
+        let mut ranked: Vec<(u64, (RegionIdx, HopMessage))> = vec![];
+        for (region, new_request) in candidates.into_iter() {
+            let estimate = match distances.get(&region) {
+                Some(distance) if new_request.is_pruned(*distance, best_known_cost) => {
+                    log::debug!("Skipping request to {} (crossing to the target region costs at least {})", region, distance);
+                    continue;
+                }
+                Some(distance) => { new_request.cost() + distance }
+                None => { u64::MAX }
+            };
+            ranked.push((estimate, (region, new_request)));
+        }
+        ranked.sort_by_key(|(estimate, _)| *estimate);
+        ranked.into_iter().map(|(_, candidate)| candidate).collect()
+    }
+
+    /// Tells the client the query was given up on. The backward end of a bidirectional query stays
+    /// silent, the forward one answers for the query.
+    async fn give_up(&self, request: &HopMessage) -> Result<()> {
+        match request.direction {
+            SearchDirection::Forward => {
+                let expanded = segments::expand(&self.context.redis_connector, request).await?;
+                self.reply(request, expanded.budget_exceeded()).await
             }
+            SearchDirection::Backward => { Ok(()) }
+        }
+    }
+
+    /// Records where a hop of a bidirectional query crossed into the region and completes the route
+    /// if a hop from the other end of the query already got to either end of the crossed edge.
+    async fn meet(&self, request: &HopMessage, graph: &Graph) -> Result<()> {
+        let entered = PathPoint::from(&*graph.get_node(request.last).ok_or(GraphError::StartNodeNotFound(request.last, graph.region_idx))?);
+        let crossing = request.exit().and_then(|exit| graph.crossing_weight(request.last, exit, &request.avoid()));
+        let halves = request.half_routes(entered, crossing);
+        let recorded: Vec<_> = halves.iter().map(|(point, half)| (point.id(), half.clone())).collect();
+        let met = self.context.redis_connector.meet(request.request_id, request.direction, &recorded).await?;
+        let joined = halves.into_iter().zip(met)
+            .filter_map(|((point, half), other)| other.map(|other| (point, half, other)))
+            .map(|(point, half, other)| {
+                let (forward, backward) = match request.direction {
+                    SearchDirection::Forward => { (half, other) }
+                    SearchDirection::Backward => { (other, half) }
+                };
+                let (path, cost) = HalfRoute::join(&forward, point, &backward);
+                (point.id(), path, cost, forward.region_hops + backward.region_hops)
+            })
+            .min_by_key(|(_, _, cost, _)| *cost);
+        let (node, path, cost, region_hops) = match joined {
+            Some(joined) => { joined }
+            None => { return Ok(()) }
         };
+        if request.max_cost.is_some_and(|max| cost > max)
+            || request.max_region_hops.is_some_and(|max| region_hops > max) {
+            log::debug!("Both ends of request {} met at node {} over budget", request.request_id, node);
+            return Ok(())
+        }
+        let best = self.context.queries.offer_cost(request.request_id, cost).await?;
+        if cost > best {
+            log::debug!("Both ends of request {} met at node {}, but a cheaper route is already known", request.request_id, node);
+            return Ok(())
+        }
+        log::debug!("Both ends of request {} met at node {}! Sending over the result, total cost: {}", request.request_id, node, cost);
+        self.reply(request, request.joined(path, cost)).await?;
+        if let Some(latency) = request.elapsed() {
+            self.context.slo.record(request.priority_class.as_deref(), latency);
+        }
+        Ok(())
+    }
 
-        let graph = self.graphs.get(&start_region).ok_or(GraphError::StartNodeNotFound(request.last, *start_region))?;
-        let path_results: Vec<PathResult> = if request.target.1 == *start_region {
-            vec![graph.find_way_local(NodeInfo(request.last, *start_region), request.target)?]
-        } else {
-            graph.find_way(NodeInfo(request.last, *start_region), request.target)? // todo
+    /// Serves a request and, in turn, its continuations into other regions loaded by this server.
+    /// Only continuations into regions served elsewhere are forwarded. Every hop is served inside
+    /// the middleware chain, retries included as they serve the same hop again. The journal stays
+    /// out of it, a task is complete once all of its hops are.
+    async fn serve_request(&self, request: &HopMessage, params: &ExecutionParams, graphs: &Regions) -> Result<()> {
+        let mut local = vec![];
+        let served = self.context.middleware.around(request, params, self.serve_hop_retrying(request, params, graphs, &mut local)).await;
+        if let Err(err) = &served {
+            self.dead_letter(request.clone(), Stage::Serve, None, err.to_string()).await;
+        }
+        self.finish_hop(request, &served).await;
+        while let Some(hop) = local.pop() {
+            let continued = self.context.middleware.around(&hop, params, self.serve_hop_retrying(&hop, params, graphs, &mut local))
+                .instrument(telemetry::hop_span(&hop, self.context.server_id))
+                .await;
+            if let Err(err) = &continued {
+                log::warn!("Worker {} couldn't continue request {} in region {}, details: {:?}", self.id, hop.request_id, hop.visited_regions.last().copied().unwrap_or_default(), err);
+                self.dead_letter(hop.clone(), Stage::Serve, None, err.to_string()).await;
+            }
+            self.finish_hop(&hop, &continued).await;
+        }
+        served
+    }
+
+    /// Keeps a hop this server gave up on as a dead letter, to be inspected or replayed later.
+    async fn dead_letter(&self, request: HopMessage, stage: Stage, target_server: Option<usize>, error: String) {
+        dead_letter::push(&self.context.redis_connector, self.context.server_id, &DeadLetter::new(request, stage, target_server, error)).await;
+    }
+
+    /// Gives up on hops that couldn't be sent to `server_id`: they are kept as dead letters and
+    /// counted as finished, so their queries still end.
+    async fn dead_letter_unsent(&self, server_id: usize, requests: Vec<HopMessage>, error: Box<dyn std::error::Error + Send + Sync>) {
+        let down = liveness::is_server_down(&*error);
+        let error = error.to_string();
+        for request in requests {
+            log::warn!("Unable to send request {} to server {}, keeping it as a dead letter. Details: {}", request.request_id, server_id, error);
+            let unsent: Result<()> = if down {
+                Err(ConnectionError::ServerDown(server_id).into())
+            } else {
+                Err(HopFailure::new(FailureKind::Send, error.clone()).into())
+            };
+            self.dead_letter(request.clone(), Stage::Forward, Some(server_id), error.clone()).await;
+            self.finish_hop(&request, &unsent).await;
+        }
+    }
+
+    /// Counts `request` as finished, see [`ResultArbiter::finish_hop`].
+    async fn finish_hop(&self, request: &HopMessage, served: &Result<()>) {
+        self.context.results.finish_hop(request, self.context.weight_scale, served).await
+    }
+
+    /// Gives up on a hop that crossed more region boundaries than HOP_LIMIT allows, most likely
+    /// bouncing between regions whose border nodes disagree on their region bits. It is kept as a
+    /// dead letter telling the regions it went through, and counted as finished.
+    async fn break_loop(&self, request: HopMessage) {
+        let diagnostics = format!("Crossed {} region boundaries, more than the {} allowed, through {}",
+                                  request.region_hops(), self.context.hop_limit, request.region_trail());
+        log::warn!("Giving up on request {}, details: {}", request.request_id, diagnostics);
+        let unserved: Result<()> = Err(HopFailure::new(FailureKind::HopLimit, diagnostics.clone()).into());
+        self.dead_letter(request.clone(), Stage::HopLimit, None, diagnostics).await;
+        self.finish_hop(&request, &unserved).await;
+    }
+
+    /// Drops a hop whose query's deadline passed. The first one dropped answers the query, with the
+    /// result held for it if any, so the client isn't left waiting for the remaining hops.
+    async fn time_out(&self, request: &HopMessage) {
+        log::debug!("Dropping request {}, its deadline passed", request.request_id);
+        match self.context.queries.claim_answer(request.request_id).await {
+            Ok(true) => {
+                let mut timed_out = request.timed_out();
+                timed_out.weight_scale = self.context.weight_scale;
+                if let Err(err) = self.context.results.settle(request.priority_class.as_deref(), timed_out).await {
+                    log::warn!("Unable to send the result of request {}, details: {}", request.request_id, err);
+                }
+            }
+            Ok(false) => {}
+            Err(err) => { log::warn!("Unable to claim the answer to request {}, details: {}", request.request_id, err) }
+        }
+    }
+
+    /// Serves `request` with [`Worker::serve_hop`], again after a backoff while it fails with
+    /// transient errors. Continuations of a failed attempt queued here are dropped, those sent to
+    /// other servers may arrive twice.
+    async fn serve_hop_retrying(&self, request: &HopMessage, params: &ExecutionParams, graphs: &Regions, local: &mut Vec<HopMessage>) -> Result<()> {
+        let mut retry = 0;
+        loop {
+            let queued = local.len();
+            match self.serve_hop(request, params, graphs, local).await {
+                Err(err) if retry < self.context.retries.retries && retry::is_transient(&*err) => {
+                    local.truncate(queued);
+                    let delay = self.context.retries.delay(retry);
+                    log::info!("Worker {} retries request {} in {:?}, details: {}", self.id, request.request_id, delay, err);
+                    tokio::time::sleep(delay).await;
+                    retry += 1;
+                }
+                served => { return served }
+            }
+        }
+    }
+
+    /// Searches the region of `request.last`, continuations into regions loaded here are pushed to `local`.
+    async fn serve_hop(&self, request: &HopMessage, params: &ExecutionParams, graphs: &Regions, local: &mut Vec<HopMessage>) -> Result<()> {
+        if request.is_expired() {
+            self.time_out(request).await;
+            return Ok(());
+        }
+        let best_known_cost = self.best_known_cost(request, params).await;
+        if request.is_pruned(0, best_known_cost) {
+            log::debug!("Dropping request {}, cost {} is not below the best known cost {:?}", request.request_id, request.cost(), best_known_cost);
+            return Ok(());
+        }
+        if self.continue_stored_route(request, params, graphs, local).await? {
+            return Ok(());
+        }
+        if request.bidirectional && request.direction == SearchDirection::Forward && request.is_fresh() {
+            self.start_backward_search(request, graphs, local).await?;
+        }
+        let start_region = match self.start_region(request, graphs).await? {
+            Some(start_region) => { start_region }
+            None => { return Ok(()) }
+        };
+        let graph = graphs.get_loaded(&start_region).ok_or(GraphError::StartNodeNotFound(request.last, start_region))?;
+        if request.bidirectional && !request.is_fresh() {
+            if let Err(err) = self.meet(request, graph).await {
+                log::warn!("Unable to meet the other end of request {}, details: {}", request.request_id, err);
+            }
+        }
+        let path_results = match self.search(request, params, graph, start_region).await? {
+            Some(path_results) => { path_results }
+            None => { return Ok(()) }
         };
-        let mut to_send: Vec<(usize, PathRequest)> = vec![];
+        let avoid = request.avoid();
+        let mut candidates: Vec<(RegionIdx, HopMessage)> = vec![];
+        // Segments of compacted queries, stored before their continuations are sent.
+        let mut searched_segments: Vec<(String, Vec<PathPoint>)> = vec![];
+        let mut over_budget = false;
         for path_result in path_results.into_iter() {
             match path_result {
-                PathResult::TargetReached(path, cost) => {
-                    let reply = request.update_without_region(path, request.target.0, cost);
-                    log::debug!("Target reached! Sending over the result. Request id: {}, total cost: {}", request.request_id, cost);
-                    self.result_reply.send(&reply).await?;
-                    return Ok(())
-                }
+                PathResult::TargetReached(path, cost) => { return self.reach_target(request, path, cost).await }
                 PathResult::Continue(path, cost, continuation) => {
+                    if request.is_pruned(cost, best_known_cost) {
+                        log::debug!("Skipping continuation to {} (cheaper route already known)", continuation.get_node_idx());
+                        continue;
+                    }
                     let next_region = match continuation {
                         Continuation::CRegionKnown(_, region) => {region}
-                        Continuation::CRegionUnknown(node_idx) => {self.redis_connector.get_region(node_idx).await?}
+                        Continuation::CRegionUnknown(node_idx) => {self.context.redis_connector.get_region(node_idx).await?}
                     };
-                    if !request.visited_regions.contains(&next_region) {
-                        let new_request = request.update(path, continuation.get_node_idx(), cost, next_region);
-                        let server_id = self.redis_connector.get_server_id(next_region).await?;
-                        log::debug!("Reached region boundary. Sending over the request to server {}. Request id: {}, total cost: {}", server_id, request.request_id, cost);
-                        to_send.push((server_id, new_request));
+                    if avoid.regions.contains(&next_region) {
+                        log::debug!("Skipping request to {} (region is avoided)", next_region);
+                    } else if !request.visited_regions.contains(&next_region) {
+                        let segment = request.compact_path.then(|| (segments::field(request.last, continuation.get_node_idx()), path.clone()));
+                        let mut new_request = request.update(path, continuation.get_node_idx(), cost, next_region);
+                        new_request.best_known_cost = best_known_cost;
+                        if new_request.exceeds_budget(0) {
+                            log::debug!("Skipping request to {} (budget exceeded)", next_region);
+                            over_budget = true;
+                            continue;
+                        }
+                        searched_segments.extend(segment);
+                        candidates.push((next_region, new_request));
                     } else {
                         log::debug!("Skipping request to {} (region has been already visited)", next_region);
                     }
                 }
             }
         }
-        for (server_id, new_request) in to_send.into_iter() {
-            self.node_sender_mgr.send_request(server_id, new_request).await?;
+        if candidates.is_empty() && over_budget {
+            log::debug!("Every continuation is over budget. Request id: {}", request.request_id);
+            self.give_up(request).await?;
+            return Ok(())
+        }
+        let candidates = self.fan_out(request, params, start_region, candidates, best_known_cost).await;
+        if !candidates.is_empty() {
+            if !searched_segments.is_empty() {
+                self.context.redis_connector.store_path_segments(request.request_id, &searched_segments).await?;
+            }
+            self.context.queries.spawn_hops(request.request_id, candidates.len()).await?;
+        }
+        self.send_continuations(request, start_region, candidates, graphs, local).await
+    }
+
+    /// Continues a fresh query along the stored route it reuses, see [`Worker::reuse_route`].
+    /// Returns whether it did, else the query is searched in full.
+    async fn continue_stored_route(&self, request: &HopMessage, params: &ExecutionParams, graphs: &Regions, local: &mut Vec<HopMessage>) -> Result<bool> {
+        let previous = match request.reuse_route_of.filter(|_| request.direction == SearchDirection::Forward && request.is_fresh()) {
+            Some(previous) => { previous }
+            None => { return Ok(false) }
+        };
+        match self.reuse_route(request, previous, params, graphs).await {
+            Ok(Some(reusing)) => {
+                let region = reusing.target.1;
+                log::debug!("Request {} reuses the route of request {} up to region {}", request.request_id, previous, region);
+                self.context.queries.spawn_hops(request.request_id, 1).await?;
+                if graphs.contains_key(&region) {
+                    local.push(reusing);
+                } else {
+                    let server_id = self.context.replicas.pick(region).await?;
+                    self.forward(server_id, reusing).await?;
+                }
+                return Ok(true);
+            }
+            Ok(None) => { log::debug!("Request {} can't reuse the route of request {}, searching it in full", request.request_id, previous) }
+            Err(err) => { log::warn!("Unable to reuse the route of request {} for request {}, details: {}", previous, request.request_id, err) }
+        }
+        Ok(false)
+    }
+
+    /// Starts the search from the target of a bidirectional query, here or on the server of its region.
+    async fn start_backward_search(&self, request: &HopMessage, graphs: &Regions, local: &mut Vec<HopMessage>) -> Result<()> {
+        self.context.queries.spawn_hops(request.request_id, 1).await?;
+        if graphs.contains_key(&request.target.1) {
+            log::debug!("Searching request {} from both ends, starting the backward search here", request.request_id);
+            local.push(request.reversed());
+        } else {
+            let server_id = self.context.replicas.pick(request.target.1).await?;
+            log::debug!("Searching request {} from both ends, sending the backward search to server {}", request.request_id, server_id);
+            self.forward(server_id, request.reversed()).await?;
+        }
+        Ok(())
+    }
+
+    /// Region of `graphs` holding `request.last`. `None` if the hop was forwarded to the server its
+    /// region was handed over to.
+    async fn start_region(&self, request: &HopMessage, graphs: &Regions) -> Result<Option<RegionIdx>> {
+        let mut start_region = None;
+        // Region files hold the nodes across their boundary too, the node belongs to one region only.
+        for (region_idx, graph) in graphs.iter() {
+            if graph.get_node(request.last).is_some_and(|node| node.region == *region_idx) {
+                start_region = Some(*region_idx);
+            }
+        }
+        // Regions not loaded yet aren't searched for the node, only the one the hop was sent to is loaded.
+        let sent_to = request.region();
+        if start_region.is_none() && graphs.get_loaded(&sent_to).is_none() {
+            if let Some(graph) = graphs.get(&sent_to).await? {
+                if graph.get_node(request.last).is_some_and(|node| node.region == sent_to) {
+                    start_region = Some(sent_to);
+                }
+            }
+        }
+        match start_region {
+            Some(r) => { Ok(Some(r)) }
+            None if !graphs.contains_key(&sent_to) => {
+                // The region was handed over to another server since the hop was sent here.
+                self.context.replicas.forget(sent_to);
+                let server_id = self.context.replicas.pick(sent_to).await?;
+                if server_id == self.context.server_id {
+                    Err(HopFailure::new(FailureKind::NotServedRegion, format!("Region {} isn't served here", sent_to)))?
+                }
+                log::debug!("Forwarding request {} to server {}, which region {} was handed over to", request.request_id, server_id, sent_to);
+                self.context.queries.spawn_hops(request.request_id, 1).await?;
+                self.forward(server_id, request.clone()).await?;
+                Ok(None)
+            }
+            None => {
+                log::warn!("Received request to node {}, however this worker does not serve it's region. Request: {:?}", request.last, request);
+                Err(HopFailure::new(FailureKind::NotServedRegion, format!("Node {} isn't in a region served here", request.last)))?
+            }
+        }
+    }
+
+    /// Searches `graph` from `request.last` towards the target, within the limits of `params`.
+    /// `None` if the search was given up on, the query is answered then.
+    async fn search(&self, request: &HopMessage, params: &ExecutionParams, graph: &Graph, start_region: RegionIdx) -> Result<Option<Vec<PathResult>>> {
+        let avoid = request.avoid();
+        let search = async {
+            if request.target.1 == start_region {
+                let source = NodeInfo(request.last, start_region);
+                let strategy = match (&params.strategy, graph.get_node(request.last), graph.get_node(request.target.0)) {
+                    (Some(selector), Some(source_node), Some(target_node)) => { selector.select(&source_node, &target_node) }
+                    _ => { Strategy::AStar(params.heuristic.clone()) }
+                };
+                log::debug!("Searching request {} with {:?}", request.request_id, strategy);
+                match strategy {
+                    Strategy::AStar(heuristic) => { graph.find_way_local(source, request.target, &*heuristic, &avoid, &params.search_limits).await }
+                    Strategy::Bidirectional => { graph.find_way_bidirectional(source, request.target, &avoid, &params.search_limits).await }
+                }.map(|path_result| vec![path_result])
+            } else if let Some(path_results) = graph.find_way_shortcut(NodeInfo(request.last, start_region), request.target, &avoid) {
+                log::debug!("Request {} crosses region {} through shortcuts", request.request_id, start_region);
+                Ok(path_results)
+            } else {
+                graph.find_way(NodeInfo(request.last, start_region), request.target, &avoid, &params.search_limits).await // todo
+            }
+        };
+        let searching = Instant::now();
+        let search_result = search.await;
+        self.context.usage.searched(start_region, searching.elapsed());
+        match search_result {
+            Err(err @ (GraphError::BudgetExceeded(_) | GraphError::TimedOut(_))) => {
+                log::warn!("Giving up on request {}: {}", request.request_id, err);
+                self.give_up(request).await?;
+                Ok(None)
+            }
+            result => { Ok(Some(result?)) }
+        }
+    }
+
+    /// Answers the query of `request`, which got to its target over `path` at `cost` more, unless
+    /// it is over budget or a cheaper route is already known.
+    async fn reach_target(&self, request: &HopMessage, path: Vec<PathPoint>, cost: u64) -> Result<()> {
+        if request.exceeds_budget(cost) {
+            log::debug!("Target reached over budget. Request id: {}, total cost: {}", request.request_id, request.cost() + cost);
+            return self.give_up(request).await;
+        }
+        let best = self.context.queries.offer_cost(request.request_id, request.cost() + cost).await?;
+        if request.cost() + cost > best {
+            log::debug!("Target reached, but a cheaper route is already known. Request id: {}, best cost: {}", request.request_id, best);
+            return Ok(())
+        }
+        let expanded = segments::expand(&self.context.redis_connector, request).await?;
+        self.keep_route(&expanded, &path).await;
+        let reply = expanded.finish(path, cost);
+        log::debug!("Target reached! Sending over the result. Request id: {}, total cost: {}", request.request_id, cost);
+        self.reply(request, reply).await?;
+        if let Err(err) = self.context.redis_connector.record_fanout_wins(&request.visited_regions, request.target.1).await {
+            log::warn!("Unable to record the route of request {}, details: {}", request.request_id, err);
+        }
+        if let Some(latency) = request.elapsed() {
+            self.context.slo.record(request.priority_class.as_deref(), latency);
+        }
+        Ok(())
+    }
+
+    /// Continuations out of `start_region` taken by the fan-out policy of `params`, in the order
+    /// they are sent. The regions tried are recorded for [`FanoutRanking::Wins`].
+    async fn fan_out(&self, request: &HopMessage, params: &ExecutionParams, start_region: RegionIdx,
+                     candidates: Vec<(RegionIdx, HopMessage)>, best_known_cost: Option<u64>) -> Vec<(RegionIdx, HopMessage)> {
+        let candidates = match params.fanout.ranking {
+            FanoutRanking::Distance => { self.rank_by_distance(request.target.1, candidates, best_known_cost) }
+            FanoutRanking::Wins => { candidates }
+        };
+        let candidates = if candidates.len() > 1 {
+            let stats = match self.context.redis_connector.get_fanout_stats(start_region, request.target.1).await {
+                Ok(stats) => { stats }
+                Err(err) => {
+                    log::warn!("Unable to fetch fan-out statistics of region {}, details: {}", start_region, err);
+                    HashMap::new()
+                }
+            };
+            params.fanout.select(candidates, &stats, &mut rand::thread_rng())
+        } else {
+            candidates
+        };
+        let mut next_regions: Vec<RegionIdx> = candidates.iter().map(|(region, _)| *region).collect();
+        next_regions.sort_unstable();
+        next_regions.dedup();
+        if let Err(err) = self.context.redis_connector.record_fanout_tries(start_region, request.target.1, &next_regions).await {
+            log::warn!("Unable to record fan-out of region {}, details: {}", start_region, err);
+        }
+        candidates
+    }
+
+    /// Hands every continuation of `request` out of `start_region` to whoever serves its region:
+    /// `local` if loaded here, this worker if the region is served by this server in a newer data
+    /// set, else the server picked for it.
+    async fn send_continuations(&self, request: &HopMessage, start_region: RegionIdx, candidates: Vec<(RegionIdx, HopMessage)>,
+                                graphs: &Regions, local: &mut Vec<HopMessage>) -> Result<()> {
+        // Continuations are grouped by server, the ones for the same server are sent as one batch.
+        let mut to_send: BTreeMap<usize, Vec<HopMessage>> = BTreeMap::new();
+        for (next_region, mut new_request) in candidates.into_iter() {
+            telemetry::propagate(&mut new_request);
+            if new_request.region_hops() > self.context.hop_limit {
+                self.break_loop(new_request).await;
+                continue;
+            }
+            if graphs.contains_key(&next_region) {
+                log::debug!("Reached region boundary. Continuing in region {}. Request id: {}, total cost: {}", next_region, request.request_id, new_request.cost());
+                self.context.usage.crossed(start_region, false);
+                local.push(new_request);
+                continue;
+            }
+            // The search may have outlasted the deadline, continuations elsewhere aren't sent then.
+            if new_request.is_expired() {
+                self.time_out(&new_request).await;
+                self.finish_hop(&new_request, &Ok(())).await;
+                continue;
+            }
+            let server_id = self.context.replicas.pick(next_region).await?;
+            if server_id == self.context.server_id {
+                self.context.usage.crossed(start_region, false);
+                self.requeue(new_request).await?;
+                continue;
+            }
+            self.context.usage.crossed(start_region, true);
+            log::debug!("Reached region boundary. Sending over the request to server {}. Request id: {}, total cost: {}", server_id, request.request_id, new_request.cost());
+            to_send.entry(server_id).or_default().push(new_request);
+        }
+        // Forwarding happens in a detached task, so dropping this future cannot leave the
+        // continuations half sent. A server failing doesn't keep the others from getting theirs.
+        let node_sender_mgr = self.context.node_sender_mgr.clone();
+        let unsent = tokio::task::spawn(async move {
+            let mut unsent = vec![];
+            for (server_id, new_requests) in to_send.into_iter() {
+                let sending = tracing::info_span!("send_hops", server = server_id, hops = new_requests.len());
+                let sent = if new_requests.len() == 1 {
+                    node_sender_mgr.send_request(server_id, new_requests[0].clone()).instrument(sending).await
+                } else {
+                    node_sender_mgr.send_batch(server_id, new_requests.clone()).instrument(sending).await
+                };
+                if let Err(err) = sent {
+                    unsent.push((server_id, new_requests, err));
+                }
+            }
+            unsent
+        }.in_current_span()).await?;
+        for (server_id, new_requests, err) in unsent {
+            self.dead_letter_unsent(server_id, new_requests, err).await;
+        }
+        Ok(())
+    }
+
+    /// Sends `request` to server `server_id`, or requeues it if that's this server.
+    /// Hops whose deadline passed are dropped instead.
+    async fn forward(&self, server_id: usize, mut request: HopMessage) -> Result<()> {
+        if server_id == self.context.server_id {
+            return self.requeue(request).await;
+        }
+        if request.is_expired() {
+            self.time_out(&request).await;
+            self.finish_hop(&request, &Ok(())).await;
+            return Ok(());
+        }
+        telemetry::propagate(&mut request);
+        let unsent = request.clone();
+        let sending = tracing::info_span!("send_hops", server = server_id, hops = 1);
+        let sent = self.context.node_sender_mgr.send_request(server_id, request).instrument(sending).await;
+        if let Err(err) = sent {
+            self.dead_letter_unsent(server_id, vec![unsent], err).await;
         }
         Ok(())
     }
 
+    /// Queues a hop for this server to this worker instead of sending it over the network. Hops into
+    /// regions of `graphs` never get here, they are pushed to the `local` stack of
+    /// [`Worker::serve_request`] and served within the task, with its data set. Regions are only
+    /// served elsewhere than `graphs` if a newer data set took them over, so a requeued hop is a task
+    /// of its own, served with the current data set as if it had arrived on the listener, before the
+    /// tasks of the work queue.
+    async fn requeue(&self, mut request: HopMessage) -> Result<()> {
+        log::debug!("Requeueing request {} into region {} of this server", request.request_id, request.region());
+        let dataset = self.context.dataset.current();
+        let params = dataset.policies.evaluate(&mut request);
+        telemetry::propagate(&mut request);
+        let span = telemetry::hop_span(&request, self.context.server_id);
+        if let Some(journal) = &self.context.journal {
+            journal.record(&request).await;
+        }
+        self.requeued.0.send((request, params, dataset.graphs.clone(), span)).await
+            .map_err(|_| "Requeued hops are no longer served")?;
+        self.context.queue_stats.requeued.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
     async fn work(&self) {
-        self.free_sender.send(self.id).await.unwrap();
         loop {
-            match self.task_receiver.recv().await {
-                Ok(request) => {
-                    if let Err(err) = self.serve_request(&request).await {
+            // Requeued hops take no room in the work queue, so serving them frees none.
+            let (task, slot): (_, Option<Slot>) = tokio::select! {
+                biased;
+                Ok(task) = self.requeued.1.recv() => { (Ok(task), None) }
+                work = self.context.work_receiver.recv() => {
+                    match work {
+                        Ok((task, slot)) => { (Ok(task), Some(slot)) }
+                        Err(err) => { (Err(err), None) }
+                    }
+                }
+            };
+            if slot.is_none() && task.is_ok() {
+                self.context.queue_stats.requeued.fetch_sub(1, Ordering::Relaxed);
+            }
+            match task {
+                Ok((request, params, graphs, span)) => {
+                    if let Err(err) = self.serve_request(&request, &params, &graphs).instrument(span).await {
                         log::warn!("Worker {} couldn't handle request {:?}, details: {:?}", self.id, request, err)
                     }
+                    // Failed hops are done with too, they were dead-lettered.
+                    if let Some(journal) = &self.context.journal {
+                        journal.complete(&request).await;
+                    }
                 }
-                Err(err) => {
-                    log::warn!("Worker {} is shutting down, details: {:?}", self.id, err)
+                Err(_) => {
+                    // Closed once the server shuts down and every queued hop is taken.
+                    log::debug!("Worker {} is done", self.id);
+                    return;
                 }
             }
-            self.free_sender.send(self.id).await.unwrap();
+            if slot.is_some() {
+                self.context.queue_stats.dispatched.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Lease of a server still starting, given up if the server fails to start so the group can be
+/// taken by another process right away rather than once the lease lapses.
+struct HeldLease {
+    group_id: usize,
+    holder: String,
+    heartbeat: JoinHandle<()>,
+}
+
+impl HeldLease {
+    async fn release(self, store: &impl LeaseStore) {
+        self.heartbeat.abort();
+        if let Err(err) = store.release(self.group_id, &self.holder).await {
+            log::error!("Unable to release the lease on group {}, details: {}", self.group_id, err);
         }
     }
 }
 
 impl Server {
     pub async fn new(config: Configuration, context: Context) -> Result<Server> {
-        let graph_provider = graph_provider::gcloud::CloudStorageProvider::new(
-            &*config.google_region,
-            &*config.google_bucket,
-            &*config.google_access_key,
-            &*config.google_secret_key);
+        let redis_connector = context.redis_connector.clone();
+        let mut held = None;
+        match (Self::start(config, context, &mut held).await, held) {
+            (Ok(mut server), held) => {
+                server.heartbeat = held.map(|held| held.heartbeat);
+                Ok(server)
+            }
+            (Err(err), Some(held)) => {
+                held.release(&redis_connector).await;
+                Err(err)
+            }
+            (Err(err), None) => { Err(err) }
+        }
+    }
 
-        let group_info = graph_provider.get_info(config.id).await.unwrap();
+    /// Starts the server, the lease once acquired is left in `held` for [`Server::new`] to hand
+    /// over to the server, or to release if starting fails.
+    async fn start(config: Configuration, context: Context, held: &mut Option<HeldLease>) -> Result<Server> {
+        let loader = Arc::new(DatasetLoader {
+            provider: config.storage.provider(config.weight_scale, config.data_policy)?,
+            mapped_provider: config.mapped_regions_dir.clone().map(graph_provider::mapped::MappedGraphProvider::new),
+            config: config.clone(),
+        });
 
-        let mut graphs = HashMap::new();
-        for region_id in group_info.regions.iter() {
-            log::info!("Loading region {}", region_id);
-            let graph = graph_provider.get_region(*region_id).await.unwrap();
-            context.redis_connector.set_group(*region_id, group_info.group_id).await?;
-            context.redis_connector.set_region(&graph, *region_id).await?;
-            graphs.insert(*region_id, graph);
-            log::debug!("Region {} successfully loaded", region_id);
+        let group_info = loader.group_info(config.id).await?;
+        let version = loader.dataset_version();
+        if let Some(version) = version.as_ref() {
+            manifest::join_cluster(&context.redis_connector, group_info.group_id, version).await?;
+            log::info!("Serving version {} of the data set", version);
+        }
+        if !group_info.super_regions.is_empty() {
+            log::info!("Routing by {} super-regions towards regions outside of their own", group_info.super_regions.len());
+        }
+        // The lease is held from before registering the group, so processes started at the same
+        // time for a group never both register it.
+        let lease = Lease::new(group_info.group_id, config.heartbeat);
+        let lease_holder = lease.holder().to_string();
+        let mut lease_lost = None;
+        let standby_lease = if config.standby {
+            Some(lease)
+        } else {
+            lease.acquire(&context.redis_connector).await?;
+            log::info!("Serving group {}", group_info.group_id);
+            let (renewals, lost) = lease.spawn_heartbeat(context.redis_connector.clone());
+            *held = Some(HeldLease { group_id: group_info.group_id, holder: lease_holder.clone(), heartbeat: renewals });
+            lease_lost = Some(lost);
+            None
+        };
+        let regions = loader.load_regions(&group_info, &context.redis_connector, &lease_holder).await?;
+        if !config.standby {
+            for region_id in group_info.regions.iter() {
+                context.redis_connector.set_group(*region_id, group_info.group_id).await?;
+            }
+            for (region_id, graph) in regions.iter() {
+                context.redis_connector.set_region(graph, *region_id).await?;
+                context.redis_connector.set_region_borders(&RegionBorders::new(graph)).await?;
+            }
+        }
+        if config.lazy_regions {
+            log::info!("Loading the {} regions of group {} on first use", group_info.regions.len(), group_info.group_id);
+        } else {
+            let quality_stats = loader.provider.quality_stats();
+            log::info!("Loaded regions with {} nodes and {} vertices skipped, {} vertices repaired",
+                quality_stats.skipped_nodes.load(Ordering::Relaxed),
+                quality_stats.skipped_vertices.load(Ordering::Relaxed),
+                quality_stats.repaired_vertices.load(Ordering::Relaxed));
         }
 
+        let dataset = DatasetHandle::new(loader.build(version, regions)?);
+        let mut updates = vec![traffic::spawn_weight_updates(&context.redis_connector, dataset.clone(), config.weight_scale).await?];
+        let region_writer = config.topology_write_back.then(|| Arc::new(LeaseWriter {
+            loader: loader.clone(),
+            redis_connector: context.redis_connector.clone(),
+            group_id: group_info.group_id,
+            lease_holder: lease_holder.clone(),
+        }) as Arc<dyn RegionWriter>);
+        updates.push(topology::spawn_topology_updates(&context.redis_connector, dataset.clone(), config.weight_scale, region_writer).await?);
+        inspect::spawn_stats_queries(&context.redis_connector, dataset.clone(), config.weight_scale).await?;
+        let (adjacency, tracker) = adjacency::spawn_tracker(&context.redis_connector).await?;
+        updates.push(tracker);
 
-        let graphs = Arc::new(graphs);
+        if let Some(interval) = config.reload_interval {
+            log::info!("Checking for new versions of the data set every {:?}", interval);
+            reload::spawn_reloads(loader.clone(), dataset.clone(), context.redis_connector.clone(), group_info.group_id, lease_holder.clone(), interval);
+        }
+
+        // Live updates and reloads keep applying while standing by, so a standby takes over with current state.
+        if let Some(lease) = standby_lease {
+            log::info!("Regions loaded, standing by");
+            lease.wait_for_takeover(&context.redis_connector).await;
+            let (renewals, lost) = lease.spawn_heartbeat(context.redis_connector.clone());
+            *held = Some(HeldLease { group_id: group_info.group_id, holder: lease_holder.clone(), heartbeat: renewals });
+            lease_lost = Some(lost);
+            // Node regions are already registered by the primary, which loaded the same regions.
+            for region_id in group_info.regions.iter() {
+                context.redis_connector.set_group(*region_id, group_info.group_id).await?;
+            }
+        }
+
+        if let Some(interval) = config.snapshot_interval {
+            log::info!("Saving network snapshots every {:?}", interval);
+            snapshot::spawn_snapshots(context.redis_connector.clone(), config.storage.snapshot_store()?, interval);
+        }
+        migration::spawn_migrations(loader.clone(), dataset.clone(), &context.redis_connector, config.id, config.shutdown_timeout).await?;
+        let node_sender_mgr: Box<dyn NodeSender> = Box::new(LivenessSender::new(context.node_sender_mgr, context.redis_connector.clone(), config.failover));
+        let network_info = context.redis_connector.get_servers_info().await?.network_info;
+        let replicas = ReplicaSelector::new(context.redis_connector.clone(), &network_info, config.replica_selection);
+        dead_letter::spawn_replays(&context.redis_connector, node_sender_mgr.clone(), replicas.clone(), config.id).await?;
+        if config.retention.is_enabled() {
+            retention::spawn_cleaner(context.redis_connector.clone(), config.retention.cleanup_interval);
+        }
+        let slo = Arc::new(SloMonitor::from_config(config.slo.clone()));
+        let retention = Arc::new(config.retention.clone());
+        let queries: Arc<dyn QueryStore> = Arc::new(context.redis_connector.clone());
+        // Results are published as events for the WebSockets of the ingress and for the progress channel,
+        // a publish per result nobody reads otherwise.
+        let result_reply: Box<dyn ResultReplier> = match config.ingress.is_some() || config.progress_channel.is_some() {
+            true => { Box::new(EventReplier::new(context.result_reply, context.redis_connector.clone(), config.progress_channel.clone())) }
+            false => { context.result_reply }
+        };
+        let journal = config.journal.then(|| Journal::new(context.redis_connector.clone(), config.id));
+        let unfinished = match &journal {
+            Some(journal) => { journal.unfinished().await? }
+            None => { vec![] }
+        };
+        if !unfinished.is_empty() {
+            log::info!("Serving {} hops left unfinished by the previous process of the server again", unfinished.len());
+        }
+        // Copies are skipped by the workers. Unfinished hops were taken once already, they aren't.
+        let dedup = Deduplicator::new(&config.dedup, context.redis_connector.clone());
+        for request in unfinished.iter() {
+            dedup.forget(request).await;
+        }
+        let middleware = MiddlewareChain::default()
+            .with(HopLogging)
+            .with(dedup)
+            .with(ProgressEvents::new(context.redis_connector.clone(), config.id, config.progress_channel.clone()));
         let mut workers = vec![];
-        let mut task_senders = vec![];
-        let (free_sender, free_receiver) = unbounded();
+        let (work_queue, work_receiver) = WorkQueue::new(config.worker_count * config.worker_queue_capacity);
+        let queue_stats = Arc::new(QueueStats::default());
+        let usage = Arc::new(UsageCounters::default());
+        let worker_context = WorkerContext {
+            redis_connector: context.redis_connector.clone(),
+            queries: queries.clone(),
+            weight_scale: config.weight_scale,
+            slo,
+            retention: retention.clone(),
+            adjacency,
+            results: ResultArbiter::new(queries.clone(), result_reply.clone(), retention.clone(), config.arbitration_timeout, config.id),
+            middleware,
+            node_sender_mgr: node_sender_mgr.clone(),
+            replicas: replicas.clone(),
+            work_receiver,
+            queue_stats: queue_stats.clone(),
+            retries: config.serve_retries,
+            hop_limit: config.hop_limit,
+            journal: journal.clone(),
+            usage: usage.clone(),
+            dataset: dataset.clone(),
+            server_id: config.id,
+        };
         for i in 0..config.worker_count {
-            let (task_sender, task_receiver) = unbounded();
-            let worker = Worker::new(
-                context.redis_connector.clone(),
-                graphs.clone(),
-                context.result_reply.clone(),
-                context.node_sender_mgr.clone(),
-                task_receiver,
-                free_sender.clone(),
-                i,
-            ).await?;
-            task_senders.push(task_sender);
+            let worker = Worker::new(worker_context.clone(), i);
             workers.push(tokio::task::spawn(async move { worker.work().await }));
             log::debug!("Worker spawned {}", i);
         }
+        let node_listener: Box<dyn NodeListener> = match config.ingress.clone() {
+            Some(ingress) => {
+                let regions = group_info.regions.iter().copied().collect();
+                Box::new(ingress::spawn(ingress, context.node_listener, node_sender_mgr.clone(), replicas, context.redis_connector.clone(), regions)?)
+            }
+            None => { context.node_listener }
+        };
+        let results = ResultArbiter::new(queries, result_reply.clone(), retention, config.arbitration_timeout, config.id);
+        queues::spawn_reporter(context.redis_connector.clone(), config.id, config.worker_count * config.worker_queue_capacity, queue_stats.clone());
+        usage::spawn_flusher(context.redis_connector.clone(), usage, config.usage_interval);
+        let registration = registration::spawn_registration(context.redis_connector.clone(), config.id, config.registration.clone(), dataset.clone());
         log::info!("Ready to work!");
         Ok(Server {
-            node_listener: context.node_listener,
+            node_listener,
             workers,
-            task_senders,
-            free_receiver,
-            free_sender
+            work_queue,
+            dataset,
+            leases: Arc::new(context.redis_connector.clone()),
+            redis_connector: context.redis_connector,
+            result_reply,
+            heartbeat: None,
+            lease_lost,
+            registration,
+            updates,
+            lease_holder,
+            group_id: group_info.group_id,
+            server_id: config.id,
+            shutdown_timeout: config.shutdown_timeout,
+            queue_stats,
+            request_timeout: config.request_timeout,
+            results,
+            weight_scale: config.weight_scale,
+            rate_limiter: RateLimiter::new(config.rate_limit),
+            delayed: DelayedQueries::new(),
+            listener_backoff: ListenerBackoff::default(),
+            failure_policy: config.failure_policy,
+            listener_restarts: 0,
+            compact_paths: config.compact_paths,
+            journal,
+            unfinished,
         })
     }
 
+    /// Serves requests until SIGTERM or SIGINT, then shuts down, see [`Server::serve_until`].
     pub async fn serve(&mut self) {
-        loop {
-            let worker_id = match self.free_receiver.recv().await {
-                Ok(id) => { id }
-                Err(err) => {
-                    log::info!("Server is shutting down, details: {:?}", err);
-                    continue;
+        self.serve_until(shutdown::signal()).await
+    }
+
+    /// Serves requests until `shutdown` completes or another process takes the group over, then
+    /// stops taking requests, lets the workers finish the hops dispatched to them, delivers their
+    /// results and leaves the cluster.
+    pub async fn serve_until(&mut self, shutdown: impl Future<Output = ()>) {
+        let lease_lost = self.lease_lost.take();
+        let shutdown = async move {
+            match lease_lost {
+                Some(lease_lost) => {
+                    tokio::select! {
+                        _ = shutdown => {}
+                        _ = lease_lost.wait() => {}
+                    }
+                }
+                None => { shutdown.await }
+            }
+        };
+        tokio::pin!(shutdown);
+        'serve: loop {
+            // The listener isn't read while the work queue is full, so requests wait in the
+            // transport, which holds back their senders.
+            let slot = match self.work_queue.try_reserve() {
+                Some(slot) => { slot }
+                None => {
+                    self.queue_stats.saturations.fetch_add(1, Ordering::Relaxed);
+                    let saturated_since = Instant::now();
+                    let slot = tokio::select! {
+                        _ = &mut shutdown => { break 'serve }
+                        slot = self.work_queue.reserve() => { slot }
+                    };
+                    self.queue_stats.saturated_micros.fetch_add(saturated_since.elapsed().as_micros() as u64, Ordering::Relaxed);
+                    slot
                 }
             };
-            log::debug!("Got free worker {}", worker_id);
-            match self.node_listener.get_new_request().await {
-                Ok(request) => {
-                    log::info!("Dispatching request with id {} to worker {}", request.request_id, worker_id);
-                    if let Err(err) = self.task_senders[worker_id].send(request).await {
-                        panic!("Unable to delegate job  to worker {}, error details: {}", worker_id, err)
+            // Unfinished hops were taken once already, they aren't rate limited.
+            if let Some(request) = self.unfinished.pop() {
+                if !self.dispatch(slot, request).await {
+                    break 'serve;
+                }
+                continue;
+            }
+            if let Some(request) = self.delayed.pop_due(Instant::now()) {
+                if !self.dispatch(slot, request).await {
+                    break 'serve;
+                }
+                continue;
+            }
+            let next_due = self.delayed.next_due();
+            let request = tokio::select! {
+                _ = &mut shutdown => { break 'serve }
+                // The slot is taken again for the query due.
+                _ = tokio::time::sleep_until(next_due.unwrap_or_else(Instant::now).into()), if next_due.is_some() => { continue }
+                request = self.node_listener.get_new_request() => { request }
+            };
+            match request {
+                Ok(mut request) => {
+                    self.listener_backoff.reset();
+                    self.listener_restarts = 0;
+                    if let Some(timeout) = self.request_timeout {
+                        request.default_deadline(timeout);
+                    }
+                    if self.compact_paths {
+                        request.compact();
+                    }
+                    if request.is_fresh() && request.direction == SearchDirection::Forward {
+                        let now = Instant::now();
+                        match self.rate_limiter.admit(request.client.as_deref(), now) {
+                            Ok(delay) if delay.is_zero() => {}
+                            Ok(delay) => {
+                                self.delayed.delay(request, now + delay);
+                                continue;
+                            }
+                            Err(reason) => {
+                                self.reject(&request, reason).await;
+                                continue;
+                            }
+                        }
+                    }
+                    if !self.dispatch(slot, request).await {
+                        break 'serve;
                     }
                 }
                 Err(err) => {
                     match err {
-                        ConnectionError::ProtocolError(_) => {
-                            panic!("{}", err)
+                        // A malformed message tells nothing about the listener.
+                        ConnectionError::DeserializationError(_) | ConnectionError::RedisDeserializationError(_) => {
+                            log::warn!("{}", err)
                         }
                         _ => {
-                            self.free_sender.send(worker_id).await.unwrap();
-                            log::warn!("{}", err)
+                            // The slot goes back to the work queue while backing off, it is taken again before the next read.
+                            drop(slot);
+                            if let ConnectionError::ProtocolError(_) = err {
+                                if !self.recover_listener(&err).await {
+                                    break 'serve;
+                                }
+                            }
+                            let delay = self.listener_backoff.failed();
+                            log::warn!("Unable to read a request, {} failures in a row, reading again in {:?}. Details: {}", self.listener_backoff.failures(), delay, err);
+                            tokio::select! {
+                                _ = &mut shutdown => { break 'serve }
+                                _ = tokio::time::sleep(delay) => {}
+                            }
                         }
                     }
                 }
             }
         }
+        self.shutdown().await;
+    }
+
+    /// Queues `request` for the workers. Returns whether to keep serving, see
+    /// [`Server::dispatch_failed`].
+    async fn dispatch(&self, slot: Slot, mut request: HopMessage) -> bool {
+        let span = telemetry::hop_span(&request, self.server_id);
+        let dataset = self.dataset.current();
+        let params = dataset.policies.evaluate(&mut request);
+        if let Some(journal) = &self.journal {
+            journal.record(&request).await;
+        }
+        log::info!("Queueing request with id {}, {} hops queued or served", request.request_id, self.work_queue.queued());
+        self.queue_stats.dispatched.fetch_add(1, Ordering::Relaxed);
+        let pair = (request.region(), request.target.1);
+        match self.work_queue.push(slot, request.priority, pair, (request, params, dataset.graphs.clone(), span)) {
+            Ok(()) => { true }
+            Err((request, ..)) => { self.dispatch_failed(request).await }
+        }
+    }
+
+    /// Gives up on a hop no worker is left to take, keeping it as a dead letter and counting it as
+    /// finished like the hops workers give up on, then recovers by DISPATCH_FAILURE_POLICY. Returns whether to keep serving.
+    async fn dispatch_failed(&self, request: HopMessage) -> bool {
+        let error = "No worker is left to serve the request".to_string();
+        log::error!("Unable to queue request {}, details: {}", request.request_id, error);
+        dead_letter::push(&self.redis_connector, self.server_id, &DeadLetter::new(request.clone(), Stage::Serve, None, error.clone())).await;
+        let unserved: Result<()> = Err(HopFailure::new(FailureKind::Internal, error.clone()).into());
+        self.results.finish_hop(&request, self.weight_scale, &unserved).await;
+        if let Some(journal) = &self.journal {
+            journal.complete(&request).await;
+        }
+        match self.failure_policy.dispatch_failed() {
+            Recovery::Skip => { true }
+            Recovery::Panic => { panic!("Unable to queue request {}, details: {}", request.request_id, error) }
+            Recovery::Restart | Recovery::Shutdown => {
+                log::error!("Shutting down, no worker is left");
+                false
+            }
+        }
+    }
+
+    /// Recovers from the listener failing with `err` by DISPATCH_FAILURE_POLICY. Returns whether
+    /// to keep serving.
+    async fn recover_listener(&mut self, err: &ConnectionError) -> bool {
+        match self.failure_policy.listener_failed(self.listener_restarts) {
+            Recovery::Restart => {
+                self.listener_restarts += 1;
+                log::warn!("Listener failed, restarting it, {} restarts in a row. Details: {}", self.listener_restarts, err);
+                match self.node_listener.restart().await {
+                    Ok(()) => { true }
+                    Err(restart_err) => {
+                        log::error!("Unable to restart the listener, shutting down. Details: {}", restart_err);
+                        false
+                    }
+                }
+            }
+            Recovery::Skip => { true }
+            Recovery::Shutdown => {
+                log::error!("Listener failed, shutting down. Details: {}", err);
+                false
+            }
+            Recovery::Panic => { panic!("{}", err) }
+        }
+    }
+
+    /// Answers the query of `request` with `RATE_LIMITED` instead of searching it.
+    async fn reject(&self, request: &HopMessage, reason: String) {
+        log::warn!("Rejecting request {}, details: {}", request.request_id, reason);
+        match self.redis_connector.claim_answer(request.request_id).await {
+            Ok(true) => {
+                let mut rejected = request.rate_limited(reason);
+                rejected.weight_scale = self.weight_scale;
+                if let Err(err) = self.results.settle(request.priority_class.as_deref(), rejected).await {
+                    log::warn!("Unable to send the result of request {}, details: {}", request.request_id, err);
+                }
+            }
+            Ok(false) => {}
+            Err(err) => { log::warn!("Unable to claim the answer to request {}, details: {}", request.request_id, err) }
+        }
+    }
+
+    /// Drains the workers within SHUTDOWN_TIMEOUT_MS, then releases the lease on the group and
+    /// deregisters the server, unless another process took the group over meanwhile.
+    async fn shutdown(&mut self) {
+        log::info!("Shutting down, no longer taking requests");
+        for request in self.delayed.drain() {
+            self.reject(&request, "The server shut down before the turn of the query came".to_string()).await;
+        }
+        // Workers stop once the queued hops are served, forwards included.
+        self.work_queue.close();
+        let workers = std::mem::take(&mut self.workers);
+        let worker_count = workers.len();
+        let drained = tokio::time::timeout(self.shutdown_timeout, async {
+            futures_util::future::join_all(workers).await;
+            if let Err(err) = self.result_reply.flush().await {
+                log::warn!("Unable to deliver every result, details: {}", err);
+            }
+        }).await;
+        match drained {
+            Ok(()) => { log::info!("{} workers finished their hops", worker_count) }
+            Err(_) => { log::warn!("Workers didn't finish within {:?}, giving up on their hops", self.shutdown_timeout) }
+        }
+        // A renewal would take the released lease again.
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+        // Nor may the server register itself again once it left.
+        if let Some(registration) = self.registration.take() {
+            registration.abort();
+        }
+        for updates in self.updates.drain(..) {
+            updates.abort();
+        }
+        // Regions handed over since startup are left as registered by the servers serving them now.
+        let regions: Vec<RegionIdx> = self.dataset.current().graphs.keys().copied().collect();
+        match self.leases.leave(self.group_id, &self.lease_holder, self.server_id, &regions).await {
+            Ok(true) => { log::info!("Released group {} and deregistered server {}", self.group_id, self.server_id) }
+            Ok(false) => { log::warn!("Group {} was taken over by another process, leaving its registration", self.group_id) }
+            Err(err) => { log::warn!("Unable to leave the cluster, the lease lapses on its own. Details: {}", err) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use futures_util::FutureExt as _;
+    use tokio::sync::{mpsc, oneshot, Semaphore};
+    use tokio::time::timeout;
+    use crate::{Server, Worker, WorkerContext, DEFAULT_HOP_LIMIT};
+    use crate::arbiter::ResultArbiter;
+    use crate::arbiter::memory::{MemoryQueries, SentResults};
+    use crate::dispatcher::WorkQueue;
+    use crate::domain::{HopMessage, NodeInfo, RouteStatus};
+    use crate::fanout::FanoutPolicy;
+    use crate::fixtures::{sample, FixtureDefinition, FixtureGroup, TempDir};
+    use crate::graph::{Avoid, Graph, PathResult, RegionIdx, SearchLimits, WeightScale};
+    use crate::graph_provider::GraphProvider;
+    use crate::graph_provider::mock::MockGraphProvider;
+    use crate::heuristic::HeuristicKind;
+    use crate::middleware::{HopMiddleware, MiddlewareChain};
+    use crate::node_connector::{BasicResult, ConnectionError, NodeListener, NodeSender};
+    use crate::policy::{ExecutionParams, PolicyConfig, PolicyEngine};
+    use crate::ratelimit::{DelayedQueries, RateLimitConfig, RateLimiter};
+    use crate::redis_connector::{NetworkInfo, RedisConnector};
+    use crate::regions::Regions;
+    use crate::reload::{Dataset, DatasetHandle};
+    use crate::replicas::{ReplicaSelection, ReplicaSelector};
+    use crate::retention::RetentionConfig;
+    use crate::retry::{FailurePolicy, ListenerBackoff, ServeRetryPolicy};
+    use crate::slo::{SloConfig, SloMonitor};
+    use crate::standby::LeaseStore;
+    use crate::standby::memory::MemoryLeases;
+
+    const SERVER_ID: usize = 1;
+
+    /// Hops sent to other servers, with the server each was sent to.
+    #[derive(Clone, Default)]
+    struct SentHops(Arc<Mutex<Vec<(usize, HopMessage)>>>);
+
+    #[async_trait::async_trait]
+    impl NodeSender for SentHops {
+        async fn send_request(&self, target_id: usize, request: HopMessage) -> BasicResult<()> {
+            self.0.lock().unwrap().push((target_id, request));
+            Ok(())
+        }
     }
-}
\ No newline at end of file
+
+    /// Hands over `hops`, then waits for more for ever.
+    struct Listener(Vec<HopMessage>);
+
+    #[async_trait::async_trait]
+    impl NodeListener for Listener {
+        async fn get_new_request(&mut self) -> Result<HopMessage, ConnectionError> {
+            match self.0.pop() {
+                Some(hop) => { Ok(hop) }
+                None => { std::future::pending().await }
+            }
+        }
+    }
+
+    /// Holds every hop back until `open` has permits, telling `entered` about it first.
+    struct Gate {
+        entered: mpsc::UnboundedSender<usize>,
+        open: Arc<Semaphore>,
+    }
+
+    #[async_trait::async_trait]
+    impl HopMiddleware for Gate {
+        async fn before(&self, request: &HopMessage, _params: &ExecutionParams) -> crate::Result<()> {
+            self.entered.send(request.request_id).unwrap();
+            drop(self.open.acquire().await.unwrap());
+            Ok(())
+        }
+    }
+
+    /// The regions of `two_regions`, both served by group 1.
+    fn co_hosted() -> FixtureDefinition {
+        let mut definition = sample("two_regions");
+        definition.groups = vec![FixtureGroup { id: 1, regions: vec![1, 2] }];
+        definition
+    }
+
+    /// `regions` of the data set `definition` describes, read back from the files generated for it.
+    async fn load(definition: &FixtureDefinition, regions: &[RegionIdx]) -> HashMap<RegionIdx, Graph> {
+        let dir = TempDir::new("worker");
+        definition.generate(dir.path()).unwrap();
+        let provider = MockGraphProvider::new(dir.path().to_path_buf());
+        let mut graphs = HashMap::new();
+        for region in regions {
+            graphs.insert(*region, provider.get_region(*region).await.unwrap());
+        }
+        graphs
+    }
+
+    fn params() -> ExecutionParams {
+        ExecutionParams {
+            heuristic: HeuristicKind::Zero.build([]),
+            strategy: None,
+            search_limits: SearchLimits::default(),
+            fanout: FanoutPolicy::default(),
+            max_cost: None,
+            max_region_hops: None,
+            use_cost_cache: true,
+            bidirectional: false,
+        }
+    }
+
+    /// Workers of server [`SERVER_ID`] serving `graphs`, with redis out of reach but for `queries`.
+    fn context(graphs: HashMap<RegionIdx, Graph>, queries: &MemoryQueries, results: &SentResults, hops: &SentHops) -> WorkerContext {
+        let redis_connector = RedisConnector::unreachable();
+        let retention = Arc::new(RetentionConfig::default());
+        let network_info = NetworkInfo::new(Arc::new(tokio::sync::RwLock::new(BTreeMap::new())));
+        let dataset = Dataset {
+            version: None,
+            policies: PolicyEngine::new(&PolicyConfig::default(), params(), WeightScale::default(), graphs.values()).unwrap(),
+            graphs: Arc::new(Regions::loaded(graphs)),
+        };
+        WorkerContext {
+            redis_connector: redis_connector.clone(),
+            queries: Arc::new(queries.clone()),
+            weight_scale: WeightScale::default(),
+            slo: Arc::new(SloMonitor::new(SloConfig::default(), vec![])),
+            retention: retention.clone(),
+            adjacency: Arc::default(),
+            results: ResultArbiter::new(Arc::new(queries.clone()), Box::new(results.clone()), retention, Some(Duration::from_secs(60)), SERVER_ID),
+            middleware: MiddlewareChain::default(),
+            node_sender_mgr: Box::new(hops.clone()),
+            replicas: ReplicaSelector::new(redis_connector, &network_info, ReplicaSelection::RoundRobin),
+            work_receiver: WorkQueue::new(1).1,
+            queue_stats: Arc::default(),
+            retries: ServeRetryPolicy::default(),
+            hop_limit: DEFAULT_HOP_LIMIT,
+            journal: None,
+            usage: Arc::default(),
+            dataset: DatasetHandle::new(dataset),
+            server_id: SERVER_ID,
+        }
+    }
+
+    #[tokio::test]
+    async fn co_hosted_regions_are_crossed_in_process() {
+        let definition = co_hosted();
+        let graphs = load(&definition, &[1, 2]).await;
+        // The same roads as a single region.
+        let mut single = definition.clone();
+        single.nodes.iter_mut().for_each(|node| node.region = 1);
+        single.groups = vec![FixtureGroup { id: 1, regions: vec![1] }];
+        let single = load(&single, &[1]).await.remove(&1).unwrap();
+        let optimum = match single.find_way_local(NodeInfo(1, 1), NodeInfo(4, 1), &*params().heuristic, &Avoid::default(), &SearchLimits::default()).await.unwrap() {
+            PathResult::TargetReached(_, cost) => { cost }
+            PathResult::Continue(..) => { panic!("The single region holds the target") }
+        };
+
+        let (queries, results, hops) = (MemoryQueries::default(), SentResults::default(), SentHops::default());
+        let worker = Worker::new(context(graphs, &queries, &results, &hops), 0);
+        let request = HopMessage::new(1, NodeInfo(1, 1), NodeInfo(4, 2), 1, vec![], 0, vec![1]);
+        let dataset = worker.context.dataset.current();
+        worker.serve_request(&request, &params(), &dataset.graphs).await.unwrap();
+
+        assert!(hops.0.lock().unwrap().is_empty());
+        assert!(worker.requeued.1.is_empty());
+        let answers = results.of(1);
+        assert_eq!(answers.len(), 1);
+        assert_eq!((answers[0].status, answers[0].cost), (RouteStatus::Found, optimum));
+        assert_eq!(answers[0].path.iter().map(|point| point.id()).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn hops_for_this_server_are_requeued() {
+        let graphs = load(&sample("two_regions"), &[1, 2]).await;
+        let (queries, results, hops) = (MemoryQueries::default(), SentResults::default(), SentHops::default());
+        let worker = Worker::new(context(graphs, &queries, &results, &hops), 0);
+        let hop = HopMessage::new(2, NodeInfo(1, 1), NodeInfo(4, 2), 3, vec![], 5, vec![1, 2]);
+
+        worker.forward(SERVER_ID, hop.clone()).await.unwrap();
+        assert!(hops.0.lock().unwrap().is_empty());
+        assert_eq!(worker.context.queue_stats.requeued.load(Ordering::Relaxed), 1);
+        let (requeued, _, graphs, _) = worker.requeued.1.try_recv().unwrap();
+        assert_eq!((requeued.request_id, requeued.last), (2, 3));
+        assert!(graphs.contains_key(&2));
+
+        // Hops for other servers are sent.
+        worker.forward(SERVER_ID + 1, hop).await.unwrap();
+        assert!(worker.requeued.1.is_empty());
+        assert_eq!(hops.0.lock().unwrap().iter().map(|(server, hop)| (*server, hop.request_id)).collect::<Vec<_>>(), vec![(SERVER_ID + 1, 2)]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_hops_in_flight() {
+        let graphs = load(&co_hosted(), &[1, 2]).await;
+        let (queries, results, hops) = (MemoryQueries::default(), SentResults::default(), SentHops::default());
+        let (entered, mut hop_entered) = mpsc::unbounded_channel();
+        let open = Arc::new(Semaphore::new(0));
+        let (work_queue, work_receiver) = WorkQueue::new(4);
+        let context = WorkerContext {
+            work_receiver,
+            middleware: MiddlewareChain::default().with(Gate { entered, open: open.clone() }),
+            ..context(graphs, &queries, &results, &hops)
+        };
+        let leases = MemoryLeases::default();
+        leases.claim(1, "primary", Duration::from_secs(60)).await.unwrap();
+        leases.register(SERVER_ID, &[1, 2]);
+        let worker = Worker::new(context.clone(), 0);
+        let mut server = Server {
+            node_listener: Box::new(Listener(vec![HopMessage::new(1, NodeInfo(1, 1), NodeInfo(4, 2), 1, vec![], 0, vec![1])])),
+            workers: vec![tokio::task::spawn(async move { worker.work().await })],
+            work_queue,
+            dataset: context.dataset,
+            redis_connector: context.redis_connector,
+            leases: Arc::new(leases.clone()),
+            result_reply: Box::new(results.clone()),
+            heartbeat: None,
+            lease_lost: None,
+            registration: None,
+            updates: vec![],
+            lease_holder: "primary".to_string(),
+            group_id: 1,
+            server_id: SERVER_ID,
+            shutdown_timeout: Duration::from_secs(5),
+            queue_stats: context.queue_stats,
+            request_timeout: None,
+            results: context.results,
+            weight_scale: WeightScale::default(),
+            rate_limiter: RateLimiter::new(RateLimitConfig::default()),
+            delayed: DelayedQueries::new(),
+            listener_backoff: ListenerBackoff::default(),
+            failure_policy: FailurePolicy::default(),
+            listener_restarts: 0,
+            compact_paths: false,
+            journal: None,
+            unfinished: vec![],
+        };
+        let (stop, stopped) = oneshot::channel::<()>();
+        let mut serving = tokio::task::spawn(async move {
+            server.serve_until(async { stopped.await.unwrap_or_default() }).await
+        });
+
+        assert_eq!(hop_entered.recv().await, Some(1));
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!((&mut serving).now_or_never().is_none());
+        assert!(leases.is_registered(SERVER_ID));
+        assert!(results.of(1).is_empty());
+
+        open.add_permits(1);
+        timeout(Duration::from_secs(5), serving).await.unwrap().unwrap();
+        assert_eq!(results.of(1).iter().map(|result| result.status).collect::<Vec<_>>(), vec![RouteStatus::Found]);
+        assert!(!leases.is_registered(SERVER_ID));
+        assert_eq!(leases.held(1), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! Notices servers that went down before hops are sent to them. Every serving process renews the
+//! lease `lease_<group id>` in redis, which lapses once it misses heartbeats, so a server without
+//! a lease is down, whichever transport would have lost or refused its hops. Hops for a server
+//! found down wait for a standby to take its group over and are sent to it then.
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::domain::HopMessage;
+use crate::node_connector::{BasicResult, ConnectionError, NodeSender};
+use crate::redis_connector::RedisConnector;
+use crate::standby::HeartbeatConfig;
+
+/// How long hops for a server found down wait for a standby, and how often its lease is checked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct FailoverConfig {
+    wait: Duration,
+    interval: Duration,
+}
+
+impl FailoverConfig {
+    /// Reads FAILOVER_WAIT_MS, by default as long as a standby takes to notice a failure and
+    /// register itself.
+    pub(crate) fn from_env(heartbeat: &HeartbeatConfig) -> BasicResult<Self> {
+        Ok(Self {
+            wait: match env::var("FAILOVER_WAIT_MS") {
+                Ok(millis) => { Duration::from_millis(millis.parse()?) }
+                Err(_) => { heartbeat.timeout + heartbeat.interval * 2 }
+            },
+            interval: heartbeat.interval,
+        })
+    }
+}
+
+/// Whether `err` tells the hops were given up on as their server is down.
+pub(crate) fn is_server_down(err: &(dyn Error + 'static)) -> bool {
+    matches!(err.downcast_ref::<ConnectionError>(), Some(ConnectionError::ServerDown(_)))
+}
+
+/// Servers seen holding their lease, checked again at most every heartbeat interval.
+struct PeerLiveness {
+    redis_connector: RedisConnector,
+    config: FailoverConfig,
+    seen: Mutex<HashMap<usize, Instant>>,
+}
+
+impl PeerLiveness {
+    /// Whether server `server_id` holds its lease. Taken as alive if redis can't tell.
+    async fn is_alive(&self, server_id: usize) -> bool {
+        if let Some(seen) = self.seen.lock().unwrap().get(&server_id) {
+            if seen.elapsed() < self.config.interval {
+                return true;
+            }
+        }
+        match self.redis_connector.get_lease_holder(server_id).await {
+            Ok(Some(_)) => {
+                self.seen.lock().unwrap().insert(server_id, Instant::now());
+                true
+            }
+            Ok(None) => {
+                self.suspect(server_id);
+                false
+            }
+            Err(err) => {
+                log::warn!("Unable to check whether server {} is alive, details: {}", server_id, err);
+                true
+            }
+        }
+    }
+
+    /// Checks the lease of server `server_id` on its next use, e.g. after a send to it failed.
+    fn suspect(&self, server_id: usize) {
+        self.seen.lock().unwrap().remove(&server_id);
+    }
+
+    /// Waits for a process to serve the group of server `server_id` again, returns whether one
+    /// did within the failover wait.
+    async fn await_takeover(&self, server_id: usize) -> bool {
+        let deadline = Instant::now() + self.config.wait;
+        log::warn!("Server {} is down, waiting up to {:?} for its standby", server_id, self.config.wait);
+        loop {
+            if self.is_alive(server_id).await {
+                log::info!("Server {} is served again", server_id);
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(self.config.interval.min(deadline.saturating_duration_since(Instant::now()))).await;
+        }
+    }
+}
+
+/// Sends hops only to servers which are alive, waiting for a standby to take over those found
+/// down. Hops no process takes over within the failover wait fail with
+/// [`ConnectionError::ServerDown`].
+#[derive(Clone)]
+pub(crate) struct LivenessSender {
+    inner: Box<dyn NodeSender>,
+    liveness: Arc<PeerLiveness>,
+}
+
+impl LivenessSender {
+    pub(crate) fn new(inner: Box<dyn NodeSender>, redis_connector: RedisConnector, config: FailoverConfig) -> Self {
+        Self {
+            inner,
+            liveness: Arc::new(PeerLiveness { redis_connector, config, seen: Mutex::new(HashMap::new()) }),
+        }
+    }
+
+    async fn send(&self, target_id: usize, requests: &[HopMessage]) -> BasicResult<()> {
+        if requests.len() == 1 {
+            self.inner.send_request(target_id, requests[0].clone()).await
+        } else {
+            self.inner.send_batch(target_id, requests.to_vec()).await
+        }
+    }
+
+    async fn deliver(&self, target_id: usize, requests: Vec<HopMessage>) -> BasicResult<()> {
+        if !self.liveness.is_alive(target_id).await {
+            if !self.liveness.await_takeover(target_id).await {
+                return Err(ConnectionError::ServerDown(target_id).into());
+            }
+            return self.send(target_id, &requests).await;
+        }
+        match self.send(target_id, &requests).await {
+            Ok(()) => { Ok(()) }
+            Err(err) => {
+                // A server still holding its lease failed for another reason.
+                self.liveness.suspect(target_id);
+                if self.liveness.is_alive(target_id).await {
+                    return Err(err);
+                }
+                log::warn!("Unable to send to server {}, details: {}", target_id, err);
+                if !self.liveness.await_takeover(target_id).await {
+                    return Err(ConnectionError::ServerDown(target_id).into());
+                }
+                self.send(target_id, &requests).await
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl NodeSender for LivenessSender {
+    async fn send_request(&self, target_id: usize, request: HopMessage) -> BasicResult<()> {
+        self.deliver(target_id, vec![request]).await
+    }
+
+    async fn send_batch(&self, target_id: usize, requests: Vec<HopMessage>) -> BasicResult<()> {
+        self.deliver(target_id, requests).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+    use std::time::Duration;
+    use crate::liveness::{is_server_down, FailoverConfig};
+    use crate::node_connector::ConnectionError;
+    use crate::standby::HeartbeatConfig;
+
+    #[test]
+    fn standbys_are_waited_for_until_they_took_over() {
+        let heartbeat = HeartbeatConfig { interval: Duration::from_millis(500), timeout: Duration::from_millis(3000) };
+        let config = FailoverConfig::from_env(&heartbeat).unwrap();
+        assert_eq!(config, FailoverConfig { wait: Duration::from_millis(4000), interval: Duration::from_millis(500) });
+    }
+
+    #[test]
+    fn only_servers_down_are_told_apart() {
+        let down: Box<dyn Error + Send + Sync> = ConnectionError::ServerDown(3).into();
+        assert!(is_server_down(&*down));
+        let unknown: Box<dyn Error + Send + Sync> = ConnectionError::TargetDoesNotExist(3).into();
+        assert!(!is_server_down(&*unknown));
+    }
+}
@@ -0,0 +1,213 @@
+//! Manifests of the region files of a group, kept next to the group file as
+//! `manifest_<group id>.json`:
+//!
+//! ```json
+//! {
+//!   "version": "2024-03-01",
+//!   "files": {
+//!     "nodes_1.csv.zst": { "size": 18231, "sha256": "9f86d08..." },
+//!     "region_2.pfr": { "size": 90112, "sha256": "60303ae..." }
+//!   }
+//! }
+//! ```
+//!
+//! Files are named as stored, compressed ones with their extension, and checked as downloaded.
+//! With a manifest every region file loaded has to be listed in it, so files of several versions
+//! of the data set are never mixed up in one server.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use crate::redis_connector::RedisConnector;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Name of the manifest of a group, next to its group file.
+pub fn file_name(group_id: usize) -> String {
+    format!("manifest_{}.json", group_id)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub size: u64,
+    /// Lowercase hex.
+    pub sha256: String,
+}
+
+impl FileEntry {
+    pub fn of(content: &[u8]) -> Self {
+        let mut builder = EntryBuilder::default();
+        builder.update(content);
+        builder.finish()
+    }
+}
+
+/// Builds the entry of a file read in chunks, e.g. while downloading it.
+#[derive(Default)]
+pub(crate) struct EntryBuilder {
+    size: u64,
+    hasher: Sha256,
+}
+
+impl EntryBuilder {
+    pub(crate) fn update(&mut self, chunk: &[u8]) {
+        self.size += chunk.len() as u64;
+        self.hasher.update(chunk);
+    }
+
+    pub(crate) fn finish(self) -> FileEntry {
+        FileEntry {
+            size: self.size,
+            sha256: format!("{:x}", self.hasher.finalize()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RegionManifest {
+    /// Version of the data set, the same in the manifests of all its groups.
+    pub version: String,
+    pub files: BTreeMap<String, FileEntry>,
+}
+
+impl RegionManifest {
+    pub fn new(version: &str) -> Self {
+        Self {
+            version: version.to_string(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, content: &[u8]) {
+        self.files.insert(name.to_string(), FileEntry::of(content));
+    }
+
+    /// Checks a downloaded file, described by `entry`, against its listing.
+    pub fn check(&self, name: &str, entry: &FileEntry) -> std::io::Result<()> {
+        match self.files.get(name) {
+            Some(listed) if listed == entry => { Ok(()) }
+            Some(listed) => {
+                Err(Error::new(ErrorKind::InvalidData,
+                               format!("{} doesn't match version {} of the data set: {} bytes with SHA-256 {}, expected {} bytes with SHA-256 {}",
+                                       name, self.version, entry.size, entry.sha256, listed.size, listed.sha256)))
+            }
+            None => {
+                Err(Error::new(ErrorKind::InvalidData,
+                               format!("{} is not part of version {} of the data set", name, self.version)))
+            }
+        }
+    }
+
+    pub fn verify(&self, name: &str, content: &[u8]) -> std::io::Result<()> {
+        self.check(name, &FileEntry::of(content))
+    }
+}
+
+/// Manifest of the group a provider loaded or wrote last, which the region files it loads are
+/// checked against. Without one files are loaded unchecked.
+#[derive(Default)]
+pub(crate) struct ManifestSlot(RwLock<Option<Option<Arc<RegionManifest>>>>);
+
+impl ManifestSlot {
+    pub(crate) fn set(&self, manifest: Option<RegionManifest>) {
+        *self.0.write().unwrap() = Some(manifest.map(Arc::new));
+    }
+
+    pub(crate) fn get(&self) -> Option<Arc<RegionManifest>> {
+        self.0.read().unwrap().clone().flatten()
+    }
+
+    pub(crate) fn version(&self) -> Option<String> {
+        self.get().map(|manifest| manifest.version.clone())
+    }
+
+    pub(crate) fn verify(&self, name: &str, content: &[u8]) -> std::io::Result<()> {
+        match self.get() {
+            Some(manifest) => { manifest.verify(name, content) }
+            None => { Ok(()) }
+        }
+    }
+
+    /// Refuses to write `name` while there is a manifest, which doesn't list the new file, or
+    /// before the provider looked for one, so files are never written past a manifest unseen.
+    pub(crate) fn check_writable(&self, name: &str) -> std::io::Result<()> {
+        match &*self.0.read().unwrap() {
+            Some(Some(manifest)) => {
+                Err(Error::new(ErrorKind::PermissionDenied,
+                               format!("{} can't be written into version {} of the data set, write a new version with build_manifest instead", name, manifest.version)))
+            }
+            Some(None) => { Ok(()) }
+            None => {
+                Err(Error::new(ErrorKind::PermissionDenied,
+                               format!("{} can't be written before the group it belongs to is loaded or written, whose manifest may list it", name)))
+            }
+        }
+    }
+}
+
+/// Groups announcing another version than `version` or `previous`, among those still served.
+fn conflicts<'a>(group_id: usize, version: &str, previous: Option<&str>, versions: &'a HashMap<usize, String>, live: &[usize]) -> Vec<(usize, &'a str)> {
+    let mut conflicts: Vec<_> = versions.iter()
+        .filter(|(group, other)| **group != group_id && *other != version && Some(other.as_str()) != previous && live.contains(group))
+        .map(|(group, other)| (*group, other.as_str()))
+        .collect();
+    conflicts.sort();
+    conflicts
+}
+
+/// Refuses to join a cluster whose groups serve another version of the data set, then announces
+/// `version` as the one served for `group_id`. Versions announced for groups nobody serves any more
+/// are left over from earlier runs and ignored.
+pub(crate) async fn join_cluster(redis_connector: &RedisConnector, group_id: usize, version: &str) -> Result<()> {
+    announce_version(redis_connector, group_id, version, None, true).await
+}
+
+/// Refuses `version` if a group still served announces another one, other than `previous`, the
+/// version a reloading server moves away from like the groups still serving it are about to.
+/// Announces `version` for `group_id` if `announce`, in the same transaction, so servers moving
+/// to different versions at once never both succeed.
+pub(crate) async fn announce_version(redis_connector: &RedisConnector, group_id: usize, version: &str, previous: Option<&str>, announce: bool) -> Result<()> {
+    let refused = redis_connector.announce_dataset_version(group_id, announce.then_some(version), |versions, live| {
+        conflicts(group_id, version, previous, versions, live).first()
+            .map(|(group, other)| format!("Group {} serves version {} of the data set, this server loaded version {}", group, other, version))
+    }).await?;
+    match refused {
+        Some(refused) => { Err(refused.into()) }
+        None => { Ok(()) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::manifest::{conflicts, FileEntry, RegionManifest};
+
+    #[test]
+    fn files_are_checked() {
+        let mut manifest = RegionManifest::new("v2");
+        manifest.add("nodes_1.csv", b"1,0,0,1\n");
+        assert_eq!(manifest.files["nodes_1.csv"], FileEntry {
+            size: 8,
+            sha256: "6faacdea73a8d6a559a7ddce784447dede0644a3909ee96672c589b778c44fd6".to_string(),
+        });
+        assert!(manifest.verify("nodes_1.csv", b"1,0,0,1\n").is_ok());
+        let changed = manifest.verify("nodes_1.csv", b"1,0,0,2\n").unwrap_err();
+        assert!(changed.to_string().contains("doesn't match version v2"));
+        assert!(manifest.verify("vertices_1.csv", b"").unwrap_err().to_string().contains("not part of"));
+    }
+
+    #[test]
+    fn mixed_versions_are_refused() {
+        let versions = HashMap::from([(1, "v1".to_string()), (2, "v2".to_string()), (3, "v1".to_string())]);
+        assert_eq!(conflicts(1, "v1", None, &versions, &[1, 2, 3]), [(2, "v2")]);
+        // Group 2 isn't served any more.
+        assert!(conflicts(1, "v1", None, &versions, &[1, 3]).is_empty());
+        // The group's own version is replaced.
+        assert!(conflicts(2, "v1", None, &versions, &[1, 2, 3]).is_empty());
+        // Reloading, groups still serving the version left behind are about to reload too.
+        assert!(conflicts(1, "v2", Some("v1"), &versions, &[1, 2, 3]).is_empty());
+        assert_eq!(conflicts(1, "v3", Some("v1"), &versions, &[1, 2, 3]), [(2, "v2")]);
+    }
+}
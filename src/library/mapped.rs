@@ -0,0 +1,261 @@
+//! Binary region format which is memory mapped and searched in place, for regions too large to
+//! comfortably keep as hash maps in RAM.
+//!
+//! All fields are little endian `u64`s:
+//! - header: magic, region, node count, vertex count, connection count, region bit words per
+//!   vertex, max weight, reserved
+//! - nodes sorted by id: id, region, x, y, first connection, connection count
+//! - vertices sorted by id: id, a, b, weight, access, region bit count, region bit words
+//! - connections: vertex ids, grouped by node
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Write};
+use std::ops::Range;
+use std::path::Path;
+use memmap2::Mmap;
+use crate::graph::{Access, Edge, Graph, Node, NodeIdx, RegionBits, RegionIdx, VertexIdx};
+
+const MAGIC: &[u8; 8] = b"PFGRAPH1";
+const HEADER_FIELDS: usize = 8;
+const NODE_FIELDS: usize = 6;
+const VERTEX_FIELDS: usize = 6;
+
+fn word(raw: &[u8], idx: usize) -> u64 {
+    u64::from_le_bytes(raw[idx * 8..idx * 8 + 8].try_into().unwrap())
+}
+
+pub(crate) struct Connections<'a> {
+    raw: &'a [u8],
+    next: Range<usize>,
+}
+
+impl Iterator for Connections<'_> {
+    type Item = VertexIdx;
+
+    fn next(&mut self) -> Option<VertexIdx> {
+        self.next.next().map(|idx| word(self.raw, idx) as VertexIdx)
+    }
+}
+
+/// Region in the binary format, read straight from the mapping on every access.
+pub(crate) struct MappedGraph {
+    map: Mmap,
+    region: RegionIdx,
+    node_count: usize,
+    vertex_count: usize,
+    bit_words: usize,
+    max_weight: u64,
+}
+
+impl std::fmt::Debug for MappedGraph {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MappedGraph {{ region: {}, nodes: {}, vertices: {} }}", self.region, self.node_count, self.vertex_count)
+    }
+}
+
+impl MappedGraph {
+    /// Maps the file at `path` and checks its records, so that reading them later can't go out of
+    /// bounds.
+    pub(crate) fn open(path: &Path) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is only sound as long as nobody changes or truncates the file while it
+        // is mapped. Files of mapped regions are written once by `convert_regions` and never
+        // touched afterwards, new versions of the data set go into directories of their own.
+        let map = unsafe { Mmap::map(&file)? };
+        let invalid = |reason: String| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), reason));
+        if map.len() < HEADER_FIELDS * 8 || &map[..8] != MAGIC {
+            return Err(invalid("not a mapped graph file".to_string()));
+        }
+        let header = |idx| usize::try_from(word(&map, idx)).map_err(|_| invalid(format!("header field {} out of range", idx)));
+        let (node_count, vertex_count, connection_count, bit_words) = (header(2)?, header(3)?, header(4)?, header(5)?);
+        let expected = VERTEX_FIELDS.checked_add(bit_words)
+            .and_then(|vertex_fields| vertex_count.checked_mul(vertex_fields))
+            .zip(node_count.checked_mul(NODE_FIELDS))
+            .and_then(|(vertices, nodes)| vertices.checked_add(nodes))
+            .and_then(|records| records.checked_add(HEADER_FIELDS))
+            .and_then(|fields| fields.checked_add(connection_count))
+            .and_then(|fields| fields.checked_mul(8));
+        if expected != Some(map.len()) {
+            return Err(invalid("truncated or corrupted".to_string()));
+        }
+        let graph = Self {
+            region: word(&map, 1) as RegionIdx,
+            max_weight: word(&map, 6),
+            map,
+            node_count,
+            vertex_count,
+            bit_words,
+        };
+        graph.validate(connection_count).map_err(invalid)?;
+        Ok(graph)
+    }
+
+    /// Checks that records are sorted by id, connections of nodes lie within the connections and
+    /// region bits of vertices within their words.
+    fn validate(&self, connection_count: usize) -> Result<(), String> {
+        let mut previous = None;
+        for pos in 0..self.node_count {
+            let raw = self.node_record(pos);
+            let id = word(raw, 0);
+            if previous.is_some_and(|previous| previous >= id) {
+                return Err(format!("node {} out of order", id));
+            }
+            previous = Some(id);
+            let end = word(raw, 4).checked_add(word(raw, 5));
+            if end.is_none_or(|end| end > connection_count as u64) {
+                return Err(format!("connections of node {} out of range", id));
+            }
+        }
+        let mut previous = None;
+        for pos in 0..self.vertex_count {
+            let raw = self.vertex_record(pos);
+            let id = word(raw, 0);
+            if previous.is_some_and(|previous| previous >= id) {
+                return Err(format!("vertex {} out of order", id));
+            }
+            previous = Some(id);
+            if word(raw, 5) > 64 * self.bit_words as u64 {
+                return Err(format!("{} region bits of vertex {} don't fit {} words", word(raw, 5), id, self.bit_words));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn region(&self) -> RegionIdx {
+        self.region
+    }
+
+    pub(crate) fn max_weight(&self) -> u64 {
+        self.max_weight
+    }
+
+    pub(crate) fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    fn vertex_size(&self) -> usize {
+        8 * (VERTEX_FIELDS + self.bit_words)
+    }
+
+    fn node_record(&self, pos: usize) -> &[u8] {
+        let start = 8 * (HEADER_FIELDS + pos * NODE_FIELDS);
+        &self.map[start..start + 8 * NODE_FIELDS]
+    }
+
+    fn vertex_record(&self, pos: usize) -> &[u8] {
+        let start = 8 * (HEADER_FIELDS + self.node_count * NODE_FIELDS) + pos * self.vertex_size();
+        &self.map[start..start + self.vertex_size()]
+    }
+
+    /// Position of the record with `id` among `count` records sorted by id.
+    fn find(&self, count: usize, id: usize, record: impl Fn(usize) -> usize) -> Option<usize> {
+        let (mut low, mut high) = (0, count);
+        while low < high {
+            let mid = (low + high) / 2;
+            match record(mid).cmp(&id) {
+                std::cmp::Ordering::Less => { low = mid + 1 }
+                std::cmp::Ordering::Greater => { high = mid }
+                std::cmp::Ordering::Equal => { return Some(mid) }
+            }
+        }
+        None
+    }
+
+    /// Node without its connections, which are read through [`MappedGraph::connections`].
+    fn node_at(&self, pos: usize) -> Node {
+        let raw = self.node_record(pos);
+        Node::new(vec![], word(raw, 0) as NodeIdx, word(raw, 1) as RegionIdx, word(raw, 2), word(raw, 3))
+    }
+
+    pub(crate) fn node(&self, id: NodeIdx) -> Option<Node> {
+        let pos = self.find(self.node_count, id, |pos| word(self.node_record(pos), 0) as usize)?;
+        Some(self.node_at(pos))
+    }
+
+    pub(crate) fn nodes(&self) -> impl Iterator<Item=Node> + '_ {
+        (0..self.node_count).map(|pos| self.node_at(pos))
+    }
+
+    pub(crate) fn connections(&self, id: NodeIdx) -> Connections<'_> {
+        let (start, len) = match self.find(self.node_count, id, |pos| word(self.node_record(pos), 0) as usize) {
+            Some(pos) => { (word(self.node_record(pos), 4) as usize, word(self.node_record(pos), 5) as usize) }
+            None => { (0, 0) }
+        };
+        let base = 8 * (HEADER_FIELDS + self.node_count * NODE_FIELDS) + self.vertex_count * self.vertex_size();
+        Connections {
+            raw: &self.map[base..],
+            next: start..start + len,
+        }
+    }
+
+    fn vertex_at(&self, pos: usize) -> Edge<'_> {
+        let raw = self.vertex_record(pos);
+        Edge {
+            id: word(raw, 0) as VertexIdx,
+            a: word(raw, 1) as NodeIdx,
+            b: word(raw, 2) as NodeIdx,
+            weight: word(raw, 3),
+            access: Access::from_bits(word(raw, 4) as u8),
+            region_bits: RegionBits::Packed { len: word(raw, 5) as usize, words: &raw[8 * VERTEX_FIELDS..] },
+        }
+    }
+
+    pub(crate) fn vertex(&self, id: VertexIdx) -> Option<Edge<'_>> {
+        let pos = self.find(self.vertex_count, id, |pos| word(self.vertex_record(pos), 0) as usize)?;
+        Some(self.vertex_at(pos))
+    }
+
+    pub(crate) fn vertices(&self) -> impl Iterator<Item=Edge<'_>> + '_ {
+        (0..self.vertex_count).map(|pos| self.vertex_at(pos))
+    }
+}
+
+/// Writes `graph` in the binary format.
+pub(crate) fn write(graph: &Graph, out: &mut impl Write) -> std::io::Result<()> {
+    let mut nodes: Vec<_> = graph.nodes().collect();
+    nodes.sort_by_key(|node| node.id);
+    let mut vertices: Vec<_> = graph.vertices().collect();
+    vertices.sort_by_key(|vertex| vertex.id);
+    let bit_words = vertices.iter().map(|vertex| vertex.region_bits.len().div_ceil(64)).max().unwrap_or(0);
+    let connections: Vec<Vec<VertexIdx>> = nodes.iter().map(|node| graph.connections(node.id).collect()).collect();
+
+    let mut put = |value: u64| out.write_all(&value.to_le_bytes());
+    let header = [
+        u64::from_le_bytes(*MAGIC),
+        graph.region_idx as u64,
+        nodes.len() as u64,
+        vertices.len() as u64,
+        connections.iter().map(Vec::len).sum::<usize>() as u64,
+        bit_words as u64,
+        graph.max_weight(),
+        0,
+    ];
+    for value in header {
+        put(value)?;
+    }
+    let mut first_connection = 0;
+    for (node, connections) in nodes.iter().zip(connections.iter()) {
+        for value in [node.id as u64, node.region as u64, node.cord_x, node.cord_y, first_connection, connections.len() as u64] {
+            put(value)?;
+        }
+        first_connection += connections.len() as u64;
+    }
+    for vertex in vertices.iter() {
+        for value in [vertex.id as u64, vertex.a as u64, vertex.b as u64, vertex.weight, vertex.access.bits() as u64, vertex.region_bits.len() as u64] {
+            put(value)?;
+        }
+        let mut words = vec![0u64; bit_words];
+        for idx in 0..vertex.region_bits.len() {
+            if vertex.region_bits.get(idx) == Some(true) {
+                words[idx / 64] |= 1 << (idx % 64);
+            }
+        }
+        for value in words {
+            put(value)?;
+        }
+    }
+    for vertex_id in connections.into_iter().flatten() {
+        put(vertex_id as u64)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::sync::Arc;
+use crate::domain::HopMessage;
+use crate::policy::ExecutionParams;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Layer wrapped around serving every hop, for concerns such as deduplication, authorisation,
+/// tracing, budgets or metrics which don't belong to the search itself.
+#[async_trait::async_trait]
+pub(crate) trait HopMiddleware: Send + Sync {
+    /// Runs before the hop is served. An error rejects the hop: it isn't served, and the error
+    /// becomes its outcome, unless it is a [`Skip`].
+    async fn before(&self, _request: &HopMessage, _params: &ExecutionParams) -> Result<()> {
+        Ok(())
+    }
+
+    /// Runs once the hop was served or rejected, with its outcome.
+    async fn after(&self, _request: &HopMessage, _outcome: &Result<()>) {}
+}
+
+/// Rejects a hop there is nothing to do for, e.g. a copy of one served before. It isn't served
+/// and counts as served.
+#[derive(Debug)]
+pub(crate) struct Skip(pub(crate) String);
+
+impl Display for Skip {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for Skip {}
+
+/// Layers run around a hop in order: `before` from the first layer to the last, `after` the other
+/// way round and only for the layers whose `before` ran.
+#[derive(Clone, Default)]
+pub(crate) struct MiddlewareChain {
+    layers: Vec<Arc<dyn HopMiddleware>>,
+}
+
+impl MiddlewareChain {
+    pub(crate) fn with(mut self, layer: impl HopMiddleware + 'static) -> Self {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+
+    /// Serves `request` with `serve` inside the layers of the chain.
+    pub(crate) async fn around(&self,
+                               request: &HopMessage,
+                               params: &ExecutionParams,
+                               serve: impl Future<Output=Result<()>>) -> Result<()> {
+        let mut entered = 0;
+        let mut rejected = None;
+        for layer in self.layers.iter() {
+            if let Err(err) = layer.before(request, params).await {
+                rejected = Some(err);
+                break;
+            }
+            entered += 1;
+        }
+        let outcome = match rejected {
+            Some(err) if err.is::<Skip>() => {
+                log::debug!("Skipping request {} at node {}, details: {}", request.request_id, request.last, err);
+                Ok(())
+            }
+            Some(err) => { Err(err) }
+            None => { serve.await }
+        };
+        for layer in self.layers[..entered].iter().rev() {
+            layer.after(request, &outcome).await;
+        }
+        outcome
+    }
+}
+
+/// Logs every hop served, with the region it was served in.
+pub(crate) struct HopLogging;
+
+#[async_trait::async_trait]
+impl HopMiddleware for HopLogging {
+    async fn before(&self, request: &HopMessage, _params: &ExecutionParams) -> Result<()> {
+        log::debug!("Serving request {} at node {} in region {}", request.request_id, request.last, request.region());
+        Ok(())
+    }
+
+    async fn after(&self, request: &HopMessage, outcome: &Result<()>) {
+        match outcome {
+            Ok(()) => { log::debug!("Served request {} in region {}", request.request_id, request.region()) }
+            Err(err) => { log::debug!("Request {} failed in region {}, details: {}", request.request_id, request.region(), err) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use crate::domain::{ClientQuery, HopMessage, NodeInfo};
+    use crate::fanout::FanoutPolicy;
+    use crate::graph::SearchLimits;
+    use crate::heuristic::HeuristicKind;
+    use crate::middleware::{HopMiddleware, MiddlewareChain, Result, Skip};
+    use crate::policy::ExecutionParams;
+
+    struct Recorder {
+        name: &'static str,
+        reject: bool,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl HopMiddleware for Recorder {
+        async fn before(&self, _request: &HopMessage, _params: &ExecutionParams) -> Result<()> {
+            self.calls.lock().unwrap().push(format!("before {}", self.name));
+            if self.reject {
+                Err(format!("rejected by {}", self.name))?
+            }
+            Ok(())
+        }
+
+        async fn after(&self, _request: &HopMessage, outcome: &Result<()>) {
+            self.calls.lock().unwrap().push(format!("after {} {}", self.name, outcome.is_ok()));
+        }
+    }
+
+    struct Skipping;
+
+    #[async_trait::async_trait]
+    impl HopMiddleware for Skipping {
+        async fn before(&self, _request: &HopMessage, _params: &ExecutionParams) -> Result<()> {
+            Err(Skip("copy".to_string()))?
+        }
+    }
+
+    #[tokio::test]
+    async fn layers_wrap_hops() {
+        let params = ExecutionParams {
+            heuristic: HeuristicKind::Zero.build([]),
+            strategy: None,
+            search_limits: SearchLimits::default(),
+            fanout: FanoutPolicy::default(),
+            max_cost: None,
+            max_region_hops: None,
+            use_cost_cache: true,
+            bidirectional: false,
+        };
+        let request = HopMessage::from(ClientQuery::new(1, NodeInfo::new(1, 1), NodeInfo::new(2, 2)));
+        let calls = Arc::new(Mutex::new(vec![]));
+        let recorder = |name, reject| Recorder { name, reject, calls: calls.clone() };
+
+        let chain = MiddlewareChain::default().with(recorder("outer", false)).with(recorder("inner", false));
+        let served = chain.around(&request, &params, async {
+            calls.lock().unwrap().push("serve".to_string());
+            Ok(())
+        }).await;
+        assert!(served.is_ok());
+        assert_eq!(calls.lock().unwrap().drain(..).collect::<Vec<_>>(),
+                   ["before outer", "before inner", "serve", "after inner true", "after outer true"]);
+
+        // A rejected hop isn't served, and only the layers it passed see its outcome.
+        let chain = MiddlewareChain::default()
+            .with(recorder("outer", false))
+            .with(recorder("auth", true))
+            .with(recorder("inner", false));
+        let served = chain.around(&request, &params, async {
+            calls.lock().unwrap().push("serve".to_string());
+            Ok(())
+        }).await;
+        assert_eq!(served.unwrap_err().to_string(), "rejected by auth");
+        assert_eq!(calls.lock().unwrap().drain(..).collect::<Vec<_>>(),
+                   ["before outer", "before auth", "after outer false"]);
+
+        // A skipped hop isn't served either, yet counts as served.
+        let chain = MiddlewareChain::default()
+            .with(recorder("outer", false))
+            .with(Skipping);
+        let served = chain.around(&request, &params, async {
+            calls.lock().unwrap().push("serve".to_string());
+            Ok(())
+        }).await;
+        assert!(served.is_ok());
+        assert_eq!(calls.lock().unwrap().drain(..).collect::<Vec<_>>(), ["before outer", "after outer true"]);
+    }
+}
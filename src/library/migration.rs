@@ -0,0 +1,165 @@
+//! Hands regions over between running servers, e.g. to move a hot region off a busy server. The
+//! server told to shed a region asks the receiving server to take it, which loads the region and
+//! only then flips `region_servers_<region>` over to itself, so hops keep being served throughout.
+//! The shedding server drops the region once told it flipped and finishes the hops queued for it
+//! on the regions they were dispatched with. Hops still sent to it afterwards are forwarded on.
+//!
+//! Handovers last until the servers restart, which serve the regions of their groups again. New
+//! versions of the data set reloaded meanwhile keep them, see [`crate::reload::Handovers`].
+
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use futures_util::StreamExt as _;
+use redis::RedisResult;
+use serde::{Serialize, Deserialize};
+use tokio::task::JoinHandle;
+use crate::DatasetLoader;
+use crate::graph::{RegionIdx, SuperRegions};
+use crate::redis_connector::RedisConnector;
+use crate::regions::Regions;
+use crate::reload::DatasetHandle;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Interval at which a shedding server checks whether the hops queued for a region finished.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Redis channel on which server `server_id` takes part in handovers.
+pub fn channel(server_id: usize) -> String {
+    format!("region_migrations_{}", server_id)
+}
+
+/// Step of a handover, published on the channel of the server taking it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub(crate) enum Migration {
+    /// Hand `region` over to server `to`, sent by an operator.
+    Shed { region: RegionIdx, to: usize },
+    /// Load `region` and flip it over from server `from`.
+    Take { region: RegionIdx, from: usize },
+    /// Server `to` serves `region` now, the shedding server drops it.
+    Flipped { region: RegionIdx, to: usize },
+}
+
+/// Publishes `migration` to server `server_id`, returns whether it got it.
+async fn send(redis_connector: &RedisConnector, server_id: usize, migration: Migration) -> Result<bool> {
+    let mut conn = redis_connector.spawn_connection().await?;
+    let receivers: usize = redis::cmd("PUBLISH").arg(channel(server_id)).arg(serde_json::to_string(&migration)?).query_async(&mut conn).await?;
+    Ok(receivers > 0)
+}
+
+fn parse(message: &redis::Msg) -> Result<Migration> {
+    Ok(serde_json::from_str(&message.get_payload::<String>()?)?)
+}
+
+struct Migrations {
+    loader: Arc<DatasetLoader>,
+    dataset: DatasetHandle,
+    redis_connector: RedisConnector,
+    server_id: usize,
+    /// How long the hops queued for a region shed are waited for.
+    drain_timeout: Duration,
+}
+
+impl Migrations {
+    async fn shed(&self, region: RegionIdx, to: usize) -> Result<()> {
+        if to == self.server_id {
+            Err("The region would be handed over to the server itself")?
+        }
+        if !self.dataset.current().graphs.contains_key(&region) {
+            Err(format!("Region {} isn't served here", region))?
+        }
+        if !send(&self.redis_connector, to, Migration::Take { region, from: self.server_id }).await? {
+            Err(format!("Server {} isn't running", to))?
+        }
+        log::info!("Handing region {} over to server {}", region, to);
+        Ok(())
+    }
+
+    /// Loads the region and swaps in the regions served with it before traffic flips.
+    async fn take(&self, region: RegionIdx, from: usize) -> Result<()> {
+        if !self.dataset.current().graphs.contains_key(&region) {
+            let group_info = self.loader.group_info(self.server_id).await?;
+            let super_regions = Arc::new(SuperRegions::new(&group_info.super_regions)?);
+            let graph = self.loader.load_region(region, &super_regions).await?;
+            self.dataset.hand_over(region, true, |current| self.loader.build(current.version.clone(), current.graphs.with(region, graph)))?;
+        }
+        self.redis_connector.hand_over_region(region, from, self.server_id).await?;
+        log::info!("Took region {} over from server {}", region, from);
+        if !send(&self.redis_connector, from, Migration::Flipped { region, to: self.server_id }).await? {
+            log::warn!("Server {} left before dropping region {}", from, region);
+        }
+        Ok(())
+    }
+
+    /// Drops the region, then waits for the hops queued for it.
+    async fn drop_region(&self, region: RegionIdx, to: usize) -> Result<()> {
+        if !self.dataset.current().graphs.contains_key(&region) {
+            return Ok(());
+        }
+        let current = self.dataset.hand_over(region, false, |current| self.loader.build(current.version.clone(), current.graphs.without(region)))?;
+        let previous: Weak<Regions> = Arc::downgrade(&current.graphs);
+        drop(current);
+        log::info!("Handed region {} over to server {}, finishing the hops queued for it", region, to);
+        let deadline = Instant::now() + self.drain_timeout;
+        while previous.strong_count() > 0 {
+            if Instant::now() >= deadline {
+                log::warn!("Hops queued for region {} didn't finish within {:?}", region, self.drain_timeout);
+                return Ok(());
+            }
+            tokio::time::sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+        log::info!("Finished the hops queued for region {}", region);
+        Ok(())
+    }
+
+    async fn apply(&self, migration: Migration) -> Result<()> {
+        match migration {
+            Migration::Shed { region, to } => { self.shed(region, to).await }
+            Migration::Take { region, from } => { self.take(region, from).await }
+            Migration::Flipped { region, to } => { self.drop_region(region, to).await }
+        }
+    }
+}
+
+/// Subscribes to the handover channel of `server_id`. Handovers are applied one at a time, a
+/// failed one leaves the region with the server that had it.
+pub(crate) async fn spawn_migrations(loader: Arc<DatasetLoader>,
+                                     dataset: DatasetHandle,
+                                     redis_connector: &RedisConnector,
+                                     server_id: usize,
+                                     drain_timeout: Duration) -> RedisResult<JoinHandle<()>> {
+    let mut pubsub = redis_connector.spawn_connection().await?.into_pubsub();
+    pubsub.subscribe(channel(server_id)).await?;
+    let migrations = Migrations { loader, dataset, redis_connector: redis_connector.clone(), server_id, drain_timeout };
+    Ok(tokio::task::spawn(async move {
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let migration = match parse(&message) {
+                Ok(migration) => { migration }
+                Err(err) => {
+                    log::warn!("Ignoring malformed region migration, details: {}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = migrations.apply(migration).await {
+                log::warn!("Unable to apply {:?}, details: {}", migration, err);
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::migration::Migration;
+
+    #[test]
+    fn migrations_are_tagged_with_their_command() {
+        let shed = Migration::Shed { region: 7, to: 2 };
+        let json = serde_json::to_string(&shed).unwrap();
+        assert_eq!(json, r#"{"command":"shed","region":7,"to":2}"#);
+        assert_eq!(serde_json::from_str::<Migration>(&json).unwrap(), shed);
+        let flipped: Migration = serde_json::from_str(r#"{"command":"flipped","region":7,"to":2}"#).unwrap();
+        assert_eq!(flipped, Migration::Flipped { region: 7, to: 2 });
+    }
+}
@@ -0,0 +1,258 @@
+use std::env;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use tokio::task::JoinHandle;
+use crate::domain::{now_millis, HopMessage};
+use crate::node_connector::{BasicResult, ConnectionError, NodeListener, NodeSender};
+use crate::redis_connector::RedisConnector;
+use crate::retry::ListenerBackoff;
+
+/// Upper bounds of the latency buckets probes are counted in, in milliseconds.
+const LATENCY_BUCKETS: [u64; 5] = [1, 5, 25, 100, 500];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Transport {
+    Redis,
+    Zmq,
+}
+
+impl Transport {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Transport::Redis => { "redis" }
+            Transport::Zmq => { "zmq" }
+        }
+    }
+
+    pub(crate) fn other(&self) -> Transport {
+        match self {
+            Transport::Redis => { Transport::Zmq }
+            Transport::Zmq => { Transport::Redis }
+        }
+    }
+}
+
+/// Marks a hop sent over both transports at the same time, in milliseconds since the unix epoch
+/// on the sender's clock.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Probe {
+    pub(crate) sent_at: u64,
+}
+
+/// Reads TRANSPORT_MIRROR_FRACTION, the share of hops mirrored over the other transport.
+pub(crate) fn fraction_from_env() -> BasicResult<Option<f64>> {
+    match env::var("TRANSPORT_MIRROR_FRACTION") {
+        Ok(fraction) => {
+            let fraction: f64 = fraction.parse()?;
+            if !(fraction > 0. && fraction <= 1.) {
+                Err(format!("Transport mirror fraction {} has to be in (0, 1]", fraction))?
+            }
+            Ok(Some(fraction))
+        }
+        Err(_) => { Ok(None) }
+    }
+}
+
+/// Field of the redis hash `transport_stats` counting probes received with `latency`.
+pub(crate) fn latency_bucket(transport: Transport, latency: u64) -> String {
+    match LATENCY_BUCKETS.iter().find(|bound| latency <= **bound) {
+        Some(bound) => { format!("{}_latency_le_{}", transport.name(), bound) }
+        None => { format!("{}_latency_le_inf", transport.name()) }
+    }
+}
+
+async fn record_received(redis_connector: &RedisConnector, transport: Transport, probe: Probe) {
+    let latency = now_millis().saturating_sub(probe.sent_at);
+    if let Err(err) = redis_connector.record_probe_received(transport, latency).await {
+        log::warn!("Unable to record a probe received over {}, details: {}", transport.name(), err);
+    }
+}
+
+/// Sends hops over the primary transport, and a `fraction` of them over the mirror transport as
+/// well. Both copies carry the same [`Probe`], the mirrored one is only measured by the receiver.
+#[derive(Clone)]
+pub(crate) struct MirroringSender {
+    primary: Box<dyn NodeSender>,
+    mirror: Box<dyn NodeSender>,
+    transport: Transport,
+    fraction: f64,
+    redis_connector: RedisConnector,
+}
+
+impl MirroringSender {
+    pub(crate) fn new(primary: Box<dyn NodeSender>,
+                      mirror: Box<dyn NodeSender>,
+                      transport: Transport,
+                      fraction: f64,
+                      redis_connector: RedisConnector) -> Self {
+        Self {
+            primary,
+            mirror,
+            transport,
+            fraction,
+            redis_connector,
+        }
+    }
+
+    /// Picks `request` as a probe with the mirrored fraction and sends its copy over the mirror transport.
+    async fn probe(&self, target_id: usize, request: &mut HopMessage) {
+        if rand::thread_rng().gen_bool(self.fraction) {
+            request.probe = Some(Probe { sent_at: now_millis() });
+            if let Err(err) = self.redis_connector.record_probe_sent(&[self.transport, self.transport.other()]).await {
+                log::warn!("Unable to record a mirrored hop, details: {}", err);
+            }
+            // A copy that fails to send counts as lost, like one that never arrives.
+            if let Err(err) = self.mirror.send_request(target_id, request.clone()).await {
+                log::debug!("Unable to mirror a hop over {}, details: {}", self.transport.other().name(), err);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl NodeSender for MirroringSender {
+    async fn send_request(&self, target_id: usize, mut request: HopMessage) -> BasicResult<()> {
+        self.probe(target_id, &mut request).await;
+        self.primary.send_request(target_id, request).await
+    }
+
+    /// Copies are mirrored one at a time, the batch itself goes over the primary transport.
+    async fn send_batch(&self, target_id: usize, mut requests: Vec<HopMessage>) -> BasicResult<()> {
+        for request in requests.iter_mut() {
+            self.probe(target_id, request).await;
+        }
+        self.primary.send_batch(target_id, requests).await
+    }
+}
+
+/// Measures the probes among the hops received over the primary transport.
+pub(crate) struct MeasuringListener {
+    primary: Box<dyn NodeListener>,
+    transport: Transport,
+    redis_connector: RedisConnector,
+}
+
+impl MeasuringListener {
+    pub(crate) fn new(primary: Box<dyn NodeListener>, transport: Transport, redis_connector: RedisConnector) -> Self {
+        Self {
+            primary,
+            transport,
+            redis_connector,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl NodeListener for MeasuringListener {
+    async fn get_new_request(&mut self) -> Result<HopMessage, ConnectionError> {
+        let mut request = self.primary.get_new_request().await?;
+        if let Some(probe) = request.probe.take() {
+            record_received(&self.redis_connector, self.transport, probe).await;
+        }
+        Ok(request)
+    }
+
+    async fn restart(&mut self) -> Result<(), ConnectionError> {
+        self.primary.restart().await
+    }
+}
+
+/// Next copy received by `listener`, none once it is closed. A listener failing is read again
+/// less and less often, and reopened if it failed at the protocol level.
+async fn next_mirrored(listener: &mut impl NodeListener, transport: Transport, backoff: &mut ListenerBackoff) -> Option<HopMessage> {
+    loop {
+        let err = match listener.get_new_request().await {
+            Ok(request) => {
+                backoff.reset();
+                return Some(request);
+            }
+            Err(ConnectionError::NoRequest) => { return None }
+            // A malformed message tells nothing about the listener.
+            Err(err @ (ConnectionError::DeserializationError(_) | ConnectionError::RedisDeserializationError(_))) => {
+                log::warn!("Dropping a malformed mirrored hop over {}, details: {}", transport.name(), err);
+                continue;
+            }
+            Err(err) => { err }
+        };
+        if let ConnectionError::ProtocolError(_) = err {
+            if let Err(restart_err) = listener.restart().await {
+                log::warn!("Unable to reopen the mirror listener over {}, details: {}", transport.name(), restart_err);
+            }
+        }
+        let delay = backoff.failed();
+        log::warn!("Unable to receive a mirrored hop over {}, {} failures in a row, reading again in {:?}. Details: {}", transport.name(), backoff.failures(), delay, err);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Measures and drops the copies received over the mirror transport.
+pub(crate) fn spawn_mirror_listener(mut listener: impl NodeListener + 'static,
+                                    transport: Transport,
+                                    redis_connector: RedisConnector) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let mut backoff = ListenerBackoff::default();
+        while let Some(request) = next_mirrored(&mut listener, transport, &mut backoff).await {
+            if let Some(probe) = request.probe {
+                record_received(&redis_connector, transport, probe).await;
+            }
+        }
+        log::warn!("Mirror listener over {} closed", transport.name());
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use crate::domain::{HopMessage, NodeInfo};
+    use crate::mirror::{latency_bucket, next_mirrored, Transport};
+    use crate::node_connector::{ConnectionError, NodeListener};
+    use crate::retry::ListenerBackoff;
+
+    /// Fails `failures` times, then hands over a hop and closes.
+    struct FailingListener {
+        failures: usize,
+        restarts: usize,
+        served: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl NodeListener for FailingListener {
+        async fn get_new_request(&mut self) -> Result<HopMessage, ConnectionError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(ConnectionError::ProtocolError(zeromq::ZmqError::NoMessage));
+            }
+            if self.served {
+                return Err(ConnectionError::NoRequest);
+            }
+            self.served = true;
+            Ok(HopMessage::new(1, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![]))
+        }
+
+        async fn restart(&mut self) -> Result<(), ConnectionError> {
+            self.restarts += 1;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_listeners_are_backed_off() {
+        let mut listener = FailingListener { failures: 3, restarts: 0, served: false };
+        let mut backoff = ListenerBackoff::default();
+        let started = Instant::now();
+        assert_eq!(next_mirrored(&mut listener, Transport::Zmq, &mut backoff).await.unwrap().request_id, 1);
+        assert!(started.elapsed() >= Duration::from_millis(70), "Read again after {:?}", started.elapsed());
+        assert_eq!(listener.restarts, 3);
+        assert_eq!(backoff.failures(), 0);
+        assert!(next_mirrored(&mut listener, Transport::Zmq, &mut backoff).await.is_none());
+    }
+
+    #[test]
+    fn latency_buckets() {
+        assert_eq!(latency_bucket(Transport::Redis, 0), "redis_latency_le_1");
+        assert_eq!(latency_bucket(Transport::Zmq, 5), "zmq_latency_le_5");
+        assert_eq!(latency_bucket(Transport::Zmq, 6), "zmq_latency_le_25");
+        assert_eq!(latency_bucket(Transport::Redis, 10_000), "redis_latency_le_inf");
+    }
+}
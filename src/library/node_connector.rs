@@ -1,62 +1,163 @@
+//! Transports between servers, and from servers to clients and result collectors. Besides the
+//! built-in ones, transports of other crates implement [`NodeListener`], [`NodeSender`] and
+//! [`ResultReplier`] and are registered with [`crate::transport::TransportRegistry`].
+
+use std::collections::VecDeque;
 use std::fmt::{Display, Formatter};
-use redis::{ErrorKind, FromRedisValue, RedisError, RedisResult, RedisWrite, ToRedisArgs, Value};
-use crate::domain::PathRequest;
+use redis::{FromRedisValue, RedisError, RedisResult, Value};
+use crate::codec;
+use crate::domain::{HopMessage, InboundPayload, Priority, RouteResult};
+
+pub use crate::codec::WireConfig;
 
-type BasicResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+pub type BasicResult<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 #[derive(Debug)]
-pub(crate) enum ConnectionError {
+pub enum ConnectionError {
     DeserializationError(zeromq::ZmqMessage),
     TargetDoesNotExist(usize),
+    /// The server lost its lease and no process took its group over.
+    ServerDown(usize),
     ProtocolError(zeromq::ZmqError),
     NoRequest,
-    RedisDeserializationError(RedisError)
+    RedisDeserializationError(RedisError),
+    SubscriptionError(RedisError),
+    /// Failure of a transport registered by another crate.
+    Custom(Box<dyn std::error::Error + Send + Sync>),
 }
 
 impl Display for ConnectionError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        return match self {
+        match self {
             ConnectionError::DeserializationError(msg) => { write!(f, "Cannot deserialize message {:?} to string", msg) }
             ConnectionError::TargetDoesNotExist(target_id) => { write!(f, "Cannot send message to non existing server with id {:?}", target_id) }
+            ConnectionError::ServerDown(target_id) => { write!(f, "Server {} is down and no standby took over", target_id) }
             ConnectionError::ProtocolError(err) => { err.fmt(f) }
             ConnectionError::NoRequest => { write!(f, "No request received!") }
             ConnectionError::RedisDeserializationError(err) => { err.fmt(f) }
-        };
+            ConnectionError::SubscriptionError(err) => { write!(f, "Cannot subscribe to requests, details: {}", err) }
+            ConnectionError::Custom(err) => { err.fmt(f) }
+        }
     }
 }
 
 impl std::error::Error for ConnectionError {}
 
+/// Encodes a hop as the built-in transports send it: in the format of WIRE_FORMAT, compressed
+/// above PAYLOAD_COMPRESSION_THRESHOLD and signed with CLUSTER_SECRET if set, as given in `wire`.
+pub fn encode_request(wire: &WireConfig, request: &HopMessage) -> BasicResult<Vec<u8>> {
+    Ok(wire.with_encoded_hop(request, <[u8]>::to_vec)?)
+}
 
-impl ToRedisArgs for PathRequest {
-    fn write_redis_args<W>(&self, out: &mut W) where W: ?Sized + RedisWrite {
-        let json_string = serde_json::to_string(self).unwrap();
-        String::write_redis_args(&json_string, out);
-    }
+/// Decodes the hops of a payload received by a listener, a hop, a batch of hops or a client
+/// query. Refuses unsigned payloads if `wire` has a cluster secret.
+pub fn decode_requests(wire: &WireConfig, raw: &[u8]) -> BasicResult<Vec<HopMessage>> {
+    Ok(wire.decode_inbound::<InboundPayload>(raw)?.into_hops())
+}
+
+/// Encodes a result as the built-in repliers send it to collectors.
+pub fn encode_reply(wire: &WireConfig, reply: &RouteResult) -> BasicResult<Vec<u8>> {
+    Ok(wire.with_encoded_reply(reply, <[u8]>::to_vec)?)
 }
 
-impl FromRedisValue for PathRequest {
+impl FromRedisValue for RouteResult {
     fn from_redis_value(v: &Value) -> RedisResult<Self> {
-        let json_string = String::from_redis_value(v)?;
-        match serde_json::from_str(&json_string) {
-            Ok(x) => Ok(x),
-            Err(e) => { Err(RedisError::from((ErrorKind::TypeError, "Failed to deserialize json: ", e.to_string()))) }
-        }
+        codec::decode_redis(v)
     }
 }
 
+/// Receives the requests of a server, from clients and other servers.
 #[async_trait::async_trait]
-pub(crate) trait NodeListener: Sync {
-    async fn get_new_request(&mut self) -> Result<PathRequest, ConnectionError>;
+pub trait NodeListener: Send + Sync {
+    /// Waits for the next request. [`ConnectionError::NoRequest`] tells the listener is closed.
+    async fn get_new_request(&mut self) -> Result<HopMessage, ConnectionError>;
+
+    /// Reopens the listener after it failed at the protocol level. Listeners recovering on their
+    /// own are read again as they are.
+    async fn restart(&mut self) -> Result<(), ConnectionError> {
+        Ok(())
+    }
+}
+
+/// Most hops a listener reads ahead of the server, so the high priority ones among them are
+/// handed over before the low priority ones received earlier.
+pub(crate) const READ_AHEAD: usize = 64;
+
+/// Hops received but not handed to the server yet, high priority ones first and each priority in
+/// the order of arrival. Every hop carries a `T` along, e.g. the entry it was read from.
+pub(crate) struct PendingHops<T = ()> {
+    high: VecDeque<(HopMessage, T)>,
+    low: VecDeque<(HopMessage, T)>,
+}
+
+impl<T> Default for PendingHops<T> {
+    fn default() -> Self {
+        Self {
+            high: VecDeque::new(),
+            low: VecDeque::new(),
+        }
+    }
+}
+
+impl<T> PendingHops<T> {
+    pub(crate) fn push(&mut self, hop: HopMessage, tag: T) {
+        match hop.priority {
+            Priority::High => { self.high.push_back((hop, tag)) }
+            Priority::Low => { self.low.push_back((hop, tag)) }
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<(HopMessage, T)> {
+        self.high.pop_front().or_else(|| self.low.pop_front())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.high.len() + self.low.len()
+    }
+
+    /// Whether the listener should stop reading ahead.
+    pub(crate) fn is_full(&self) -> bool {
+        self.len() >= READ_AHEAD
+    }
+}
+
+impl PendingHops {
+    /// Queues the hops `ready` returns without waiting, until it returns none or the queue is full.
+    pub(crate) fn read_ahead(&mut self, mut ready: impl FnMut() -> Option<HopMessage>) {
+        while !self.is_full() {
+            match ready() {
+                Some(hop) => { self.push(hop, ()) }
+                None => { break }
+            }
+        }
+    }
+
+    pub(crate) fn extend(&mut self, hops: Vec<HopMessage>) {
+        for hop in hops {
+            self.push(hop, ());
+        }
+    }
+
+    pub(crate) fn pop_hop(&mut self) -> Option<HopMessage> {
+        self.pop().map(|(hop, ())| hop)
+    }
 }
 
 
+/// Sends results to clients or result collectors.
 #[async_trait::async_trait]
-pub(crate) trait ResultReplier: Send + Sync + ResultReplierClone {
-    async fn send(&self, reply: &PathRequest) -> BasicResult<()>;
+pub trait ResultReplier: Send + Sync + ResultReplierClone {
+    async fn send(&self, reply: &RouteResult) -> BasicResult<()>;
+
+    /// Waits until the results sent so far are delivered, before the server shuts down. Repliers
+    /// delivering every result before `send` returns have nothing to do.
+    async fn flush(&self) -> BasicResult<()> {
+        Ok(())
+    }
 }
 
-pub(crate) trait ResultReplierClone {
+/// Implemented for every [`ResultReplier`] which is `Clone`.
+pub trait ResultReplierClone {
     fn clone_box(&self) -> Box<dyn ResultReplier>;
 }
 
@@ -72,12 +173,23 @@ impl Clone for Box<dyn ResultReplier> {
     }
 }
 
+/// Sends hops to other servers, by their id.
 #[async_trait::async_trait]
-pub(crate) trait NodeSender: Send + Sync + NodeSenderClone {
-    async fn send_request(&self, target_id: usize, request: PathRequest) -> BasicResult<()>;
+pub trait NodeSender: Send + Sync + NodeSenderClone {
+    async fn send_request(&self, target_id: usize, request: HopMessage) -> BasicResult<()>;
+
+    /// Sends several requests to the same server. Transports able to carry them as one message
+    /// override this, the others send them one at a time.
+    async fn send_batch(&self, target_id: usize, requests: Vec<HopMessage>) -> BasicResult<()> {
+        for request in requests {
+            self.send_request(target_id, request).await?;
+        }
+        Ok(())
+    }
 }
 
-pub(crate) trait NodeSenderClone {
+/// Implemented for every [`NodeSender`] which is `Clone`.
+pub trait NodeSenderClone {
     fn clone_box(&self) -> Box<dyn NodeSender>;
 }
 
@@ -94,42 +206,219 @@ impl Clone for Box<dyn NodeSender> {
 }
 
 pub(crate) mod zmq_connector {
-    use std::collections::BTreeMap;
+    use std::collections::{BTreeMap, HashMap, VecDeque};
+    use std::convert::TryFrom;
     use std::fmt::{Display, Formatter};
     use std::sync::Arc;
-    use zeromq::{Socket, SocketRecv, SocketSend, ZmqMessage};
-    use crate::node_connector::BasicResult;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::task::Poll;
+    use std::time::{Duration, Instant};
+    use bytes::Bytes;
+    use futures_util::FutureExt as _;
+    use tokio::sync::{broadcast, mpsc, oneshot};
+    use zeromq::{Socket, SocketRecv, SocketSend, ZmqMessage, ZmqResult};
+    use crate::node_connector::{BasicResult, PendingHops, WireConfig};
     use crate::{ConnectionError, NodeListener, NodeSender, ResultReplier};
-    use crate::domain::PathRequest;
-    use crate::redis_connector::NetworkInfo;
+    use crate::domain::{HopMessage, InboundPayload, RouteResult};
+    use crate::redis_connector::{NetworkInfo, ServerUpdate};
 
+    /// Acknowledgement of an accepted request, anything else is the reason it was refused.
+    const ACCEPTED: &str = "OK";
+    /// Polls of the listening socket per wake up, see [`ZMQNodeListener::recv`].
+    const RECV_POLLS: usize = 64;
+
+    /// Receives requests on a ROUTER socket. Each request is acknowledged with the frames it came
+    /// with before its payload, the correlation id of a forwarding server's DEALER socket or the
+    /// empty delimiter of a client's REQ socket, followed by [`ACCEPTED`] or the refusal reason.
+    /// Requests with an empty payload are heartbeats, acknowledged but not handed to the server.
+    /// A batch is acknowledged once and its hops handed over one at a time. Requests already
+    /// received are read ahead and acknowledged before they are handed over by priority.
     pub(crate) struct ZMQNodeListener {
-        listen_sck: zeromq::PullSocket,
+        listen_sck: zeromq::RouterSocket,
+        /// Address the socket is bound to again on a restart, unknown for sockets bound elsewhere.
+        addr: Option<String>,
+        pending: PendingHops,
+        wire: WireConfig,
     }
 
     impl ZMQNodeListener {
-        pub(crate) async fn new(addr: &str) -> BasicResult<Self> {
-            let mut listen_sck = zeromq::PullSocket::new();
+        pub(crate) async fn new(addr: &str, wire: WireConfig) -> BasicResult<Self> {
+            let mut listen_sck = zeromq::RouterSocket::new();
             listen_sck.bind(addr).await?;
-            Ok(ZMQNodeListener {
-                listen_sck
-            })
+            let mut listener = Self::with_socket(listen_sck, wire);
+            listener.addr = Some(addr.to_string());
+            Ok(listener)
+        }
+
+        fn with_socket(listen_sck: zeromq::RouterSocket, wire: WireConfig) -> Self {
+            ZMQNodeListener {
+                listen_sck,
+                addr: None,
+                pending: PendingHops::default(),
+                wire,
+            }
+        }
+
+        /// Receives the next message. The fair queue of the ROUTER socket gives up on the first
+        /// peer it finds with nothing to read, leaving the peers which woke it before unread until
+        /// another one wakes it. Once several connections close at once, e.g. the pool of a server
+        /// going away, that stalls the listener, so it is polled again to move on to them.
+        async fn recv(listen_sck: &mut zeromq::RouterSocket) -> ZmqResult<ZmqMessage> {
+            let mut recv = listen_sck.recv();
+            futures_util::future::poll_fn(|cx| {
+                for _ in 0..RECV_POLLS {
+                    if let Poll::Ready(zmq_msg) = std::future::Future::poll(recv.as_mut(), cx) {
+                        return Poll::Ready(zmq_msg);
+                    }
+                }
+                Poll::Pending
+            }).await
+        }
+
+        /// Acknowledges a received message and queues its hops, heartbeats carry none.
+        async fn take(&mut self, zmq_msg: ZmqMessage) -> Result<(), ConnectionError> {
+            let mut envelope = zmq_msg.into_vec();
+            let payload = envelope.pop().expect("Router messages start with the peer identity");
+            if payload.is_empty() {
+                self.acknowledge(envelope, ACCEPTED).await;
+                return Ok(());
+            }
+            match self.wire.decode_inbound::<InboundPayload>(&payload) {
+                Ok(decoded) => {
+                    // Queued first, reads given up while acknowledging keep the hops.
+                    self.pending.extend(decoded.into_hops());
+                    self.acknowledge(envelope, ACCEPTED).await;
+                    Ok(())
+                }
+                Err(err) => {
+                    self.acknowledge(envelope, &format!("Cannot deserialize request, details: {}", err)).await;
+                    Err(ConnectionError::DeserializationError(ZmqMessage::from(payload)))
+                }
+            }
+        }
+
+        async fn acknowledge(&mut self, mut envelope: Vec<Bytes>, status: &str) {
+            envelope.push(Bytes::copy_from_slice(status.as_bytes()));
+            let ack = ZmqMessage::try_from(envelope).expect("Envelope holds the peer identity");
+            if let Err(err) = self.listen_sck.send(ack).await {
+                log::debug!("Cannot acknowledge request, its sender is gone. Details: {}", err);
+            }
         }
     }
 
     #[async_trait::async_trait]
     impl NodeListener for ZMQNodeListener {
-        async fn get_new_request(&mut self) -> Result<PathRequest, ConnectionError> {
-            let zmq_msg: ZmqMessage = self.listen_sck.recv().await.map_err(|e| ConnectionError::ProtocolError(e))?;
-            let msg_str = String::from_utf8(zmq_msg.get(0).unwrap().to_vec()).map_err(|_| ConnectionError::DeserializationError(zmq_msg.clone()))?;
-            serde_json::from_str::<PathRequest>(&msg_str).map_err(|_| ConnectionError::DeserializationError(zmq_msg))
+        async fn get_new_request(&mut self) -> Result<HopMessage, ConnectionError> {
+            // Receiving is only polled once here, a message not there yet stays on the socket.
+            while !self.pending.is_full() {
+                match Self::recv(&mut self.listen_sck).now_or_never() {
+                    Some(zmq_msg) => { self.take(zmq_msg.map_err(ConnectionError::ProtocolError)?).await? }
+                    None => { break }
+                }
+            }
+            loop {
+                if let Some(hop) = self.pending.pop_hop() {
+                    return Ok(hop);
+                }
+                let zmq_msg: ZmqMessage = Self::recv(&mut self.listen_sck).await.map_err(ConnectionError::ProtocolError)?;
+                self.take(zmq_msg).await?;
+            }
+        }
+
+        /// Binds a new socket, hops read ahead are still handed over. Senders resend the requests
+        /// the old socket didn't acknowledge.
+        async fn restart(&mut self) -> Result<(), ConnectionError> {
+            let addr = match &self.addr {
+                Some(addr) => { addr.clone() }
+                None => { return Ok(()) }
+            };
+            let failed = std::mem::replace(&mut self.listen_sck, zeromq::RouterSocket::new());
+            for err in failed.close().await {
+                log::debug!("Error closing the failed listening socket, details: {}", err);
+            }
+            self.listen_sck.bind(&addr).await.map_err(ConnectionError::ProtocolError)?;
+            Ok(())
+        }
+    }
+
+    /// Default number of results kept while the result collector is unreachable.
+    pub(crate) const DEFAULT_SPOOL_SIZE: usize = 1024;
+    const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Delivery counters of a [`ZMQReplier`].
+    #[derive(Debug, Default)]
+    pub(crate) struct ReplierStats {
+        /// Results which couldn't be sent right away and were kept for later.
+        pub(crate) spooled: AtomicU64,
+        /// Results lost because the spool was full.
+        pub(crate) dropped: AtomicU64,
+        /// Spooled results delivered after the collector came back.
+        pub(crate) replayed: AtomicU64,
+    }
+
+    struct ReplierState {
+        socket: Option<zeromq::PushSocket>,
+        spool: VecDeque<Bytes>,
+        spool_size: usize,
+    }
+
+    impl ReplierState {
+        fn spool(&mut self, raw_reply: Bytes, stats: &ReplierStats) {
+            if self.spool.len() >= self.spool_size {
+                self.spool.pop_front();
+                let dropped = stats.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                log::warn!("Result spool is full, dropping the oldest result ({} dropped so far)", dropped);
+            }
+            self.spool.push_back(raw_reply);
+            stats.spooled.fetch_add(1, Ordering::Relaxed);
+        }
+
+        async fn try_send(&mut self, raw_reply: &Bytes) -> bool {
+            let socket = match self.socket.as_mut() {
+                Some(socket) => { socket }
+                None => { return false; }
+            };
+            if let Err(err) = socket.send(raw_reply.clone().into()).await {
+                log::warn!("Result collector is unreachable, spooling results. Details: {}", err);
+                self.socket = None;
+                return false;
+            }
+            true
+        }
+
+        /// Sends spooled results oldest first. Returns false if the collector went away meanwhile.
+        async fn replay(&mut self, stats: &ReplierStats) -> bool {
+            while let Some(raw_reply) = self.spool.pop_front() {
+                if !self.try_send(&raw_reply).await {
+                    self.spool.push_front(raw_reply);
+                    return false;
+                }
+                stats.replayed.fetch_add(1, Ordering::Relaxed);
+            }
+            true
         }
     }
 
+    /// Pushes results to the collector at REPLY_ADDR. While the collector is down results are
+    /// spooled locally (up to a bound) and replayed once the connection is re-established.
+    /// PUSH has no acknowledgements, so a result written just as the collector dies can still be lost.
     #[derive(Clone)]
     pub(crate) struct ZMQReplier {
-        socket: Arc<tokio::sync::Mutex<zeromq::PushSocket>>,
+        state: Arc<tokio::sync::Mutex<ReplierState>>,
+        stats: Arc<ReplierStats>,
         url: String,
+        wire: WireConfig,
+        _maintenance: Arc<Maintenance>,
+    }
+
+    /// Task of [`ZMQReplier::maintain`], stopped once the last copy of the replier is dropped.
+    struct Maintenance(tokio::task::JoinHandle<()>);
+
+    impl Drop for Maintenance {
+        fn drop(&mut self) {
+            self.0.abort();
+        }
     }
 
     impl Display for ZMQReplier {
@@ -139,64 +428,618 @@ pub(crate) mod zmq_connector {
     }
 
     impl ZMQReplier {
-        pub(crate) async fn new(url: &str) -> BasicResult<Self> {
-            let mut socket = zeromq::PushSocket::new();
-            socket.connect(url).await?;
+        pub(crate) async fn new(url: &str, spool_size: usize, wire: WireConfig) -> BasicResult<Self> {
+            let socket = Self::connect(url).await;
+            if socket.is_none() {
+                log::warn!("Result collector {} is not reachable yet, results will be spooled", url);
+            }
+            let state = Arc::new(tokio::sync::Mutex::new(ReplierState {
+                socket,
+                spool: VecDeque::new(),
+                spool_size,
+            }));
+            let stats = Arc::new(ReplierStats::default());
+            let maintenance = tokio::task::spawn(Self::maintain(state.clone(), stats.clone(), String::from(url)));
             Ok(ZMQReplier {
-                socket: Arc::new(tokio::sync::Mutex::new(socket)),
+                state,
+                stats,
                 url: String::from(url),
+                wire,
+                _maintenance: Arc::new(Maintenance(maintenance)),
             })
         }
+
+        async fn connect(url: &str) -> Option<zeromq::PushSocket> {
+            let mut socket = zeromq::PushSocket::new();
+            match tokio::time::timeout(CONNECT_TIMEOUT, socket.connect(url)).await {
+                Ok(Ok(())) => { Some(socket) }
+                Ok(Err(err)) => {
+                    log::debug!("Cannot connect to result collector {}: {}", url, err);
+                    None
+                }
+                Err(_) => {
+                    log::debug!("Connecting to result collector {} timed out", url);
+                    None
+                }
+            }
+        }
+
+        /// Health monitor: re-establishes the connection after failures and replays the spool.
+        async fn maintain(state: Arc<tokio::sync::Mutex<ReplierState>>, stats: Arc<ReplierStats>, url: String) {
+            loop {
+                tokio::time::sleep(RECONNECT_INTERVAL).await;
+                {
+                    let state_guard = state.lock().await;
+                    if state_guard.socket.is_some() {
+                        continue;
+                    }
+                    log::warn!("Result collector {} is down, {} results waiting (spooled: {}, dropped: {})",
+                        url, state_guard.spool.len(), stats.spooled.load(Ordering::Relaxed), stats.dropped.load(Ordering::Relaxed));
+                }
+                if let Some(socket) = Self::connect(&url).await {
+                    let mut state_guard = state.lock().await;
+                    state_guard.socket = Some(socket);
+                    let pending = state_guard.spool.len();
+                    if state_guard.replay(&stats).await {
+                        log::info!("Reconnected to result collector {}, replayed {} results (spooled: {}, dropped: {})",
+                            url, pending, stats.spooled.load(Ordering::Relaxed), stats.dropped.load(Ordering::Relaxed));
+                    }
+                }
+            }
+        }
     }
 
     #[async_trait::async_trait]
     impl ResultReplier for ZMQReplier {
-        async fn send(&self, reply: &PathRequest) -> BasicResult<()> {
-            let mut target_sck_guard = self.socket.lock().await;
-            let raw_request = serde_json::to_vec(&reply)?;
-            Ok(target_sck_guard.send(raw_request.into()).await?)
+        async fn send(&self, reply: &RouteResult) -> BasicResult<()> {
+            let raw_reply = self.wire.encode_reply(reply)?;
+            let mut state = self.state.lock().await;
+            if state.replay(&self.stats).await && state.try_send(&raw_reply).await {
+                return Ok(());
+            }
+            state.spool(raw_reply, &self.stats);
+            Ok(())
+        }
+
+        /// Replays the spool, results are still lost if the collector is down.
+        async fn flush(&self) -> BasicResult<()> {
+            let mut state = self.state.lock().await;
+            if !state.replay(&self.stats).await {
+                Err(format!("Result collector {} is unreachable, {} results are lost", self.url, state.spool.len()))?
+            }
+            Ok(())
+        }
+    }
+
+    /// Time a forwarded request waits for its acknowledgement before it is sent again.
+    const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Sends of a request before forwarding it fails. A request whose acknowledgement got lost
+    /// arrives twice.
+    const SEND_ATTEMPTS: usize = 3;
+    /// Requests queued for a server's socket, forwarding waits while it is full.
+    const PEER_QUEUE_SIZE: usize = 1024;
+    /// Interval of the heartbeats sent to servers nothing was heard from meanwhile. Requests
+    /// abandoned by their senders stop waiting for acknowledgements at the same interval.
+    const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+    /// Time after which a server nothing was heard from is taken for dead and its connection dropped.
+    const PEER_TIMEOUT: Duration = Duration::from_secs(6);
+    /// Correlation id of heartbeats, never given to requests.
+    const HEARTBEAT_ID: u64 = u64::MAX;
+    /// Default number of connections to each server.
+    pub(crate) const DEFAULT_POOL_SIZE: usize = 4;
+
+    /// Reads ZMQ_POOL_SIZE, the number of connections to each server.
+    pub(crate) fn pool_size_from_env() -> BasicResult<usize> {
+        match std::env::var("ZMQ_POOL_SIZE") {
+            Ok(size) => {
+                let size = size.parse()?;
+                if size == 0 {
+                    Err("ZMQ_POOL_SIZE must be at least 1")?
+                }
+                Ok(size)
+            }
+            Err(_) => { Ok(DEFAULT_POOL_SIZE) }
+        }
+    }
+
+    type PendingRequest = (u64, Bytes, oneshot::Sender<Result<(), String>>);
+
+    struct Connection {
+        addr: Box<str>,
+        requests: mpsc::Sender<PendingRequest>,
+    }
+
+    /// Pool of DEALER sockets to another server, each owned by a task which sends requests as
+    /// they are queued, each tagged with a correlation id, and hands acknowledgements to their
+    /// requests by it in whatever order they arrive. A slow or lost acknowledgement only holds up
+    /// its own request. Requests take the connections in turn, so the workers forwarding to the
+    /// same server don't queue up behind one socket.
+    ///
+    /// A connection is opened when first used and opened again once its task ended, after the
+    /// server stopped answering heartbeats, or when the server registered a new address.
+    struct Peer {
+        connections: Vec<tokio::sync::Mutex<Option<Connection>>>,
+        next: AtomicUsize,
+    }
+
+    impl Peer {
+        fn new(pool_size: usize) -> Self {
+            Peer {
+                connections: (0..pool_size.max(1)).map(|_| tokio::sync::Mutex::new(None)).collect(),
+                next: AtomicUsize::new(0),
+            }
+        }
+
+        /// Queue of the next connection of the pool to `addr`, the address the server is
+        /// registered with. A request sent again takes another one.
+        async fn requests(&self, addr: &str) -> BasicResult<mpsc::Sender<PendingRequest>> {
+            let slot = self.next.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+            Self::open(&self.connections[slot], addr).await
+        }
+
+        /// Opens every connection of the pool.
+        async fn connect(&self, addr: &str) -> BasicResult<()> {
+            for connection in &self.connections {
+                Self::open(connection, addr).await?;
+            }
+            Ok(())
+        }
+
+        async fn open(connection: &tokio::sync::Mutex<Option<Connection>>, addr: &str) -> BasicResult<mpsc::Sender<PendingRequest>> {
+            let mut connection = connection.lock().await;
+            match connection.as_ref() {
+                Some(open) if &*open.addr != addr => { log::info!("Server moved from {} to {}, reconnecting", open.addr, addr) }
+                Some(open) if open.requests.is_closed() => { log::warn!("Connection to {} was lost, reconnecting", addr) }
+                Some(open) => { return Ok(open.requests.clone()) }
+                None => {}
+            }
+            // Ends the task of the old connection once the requests waiting on it are answered.
+            *connection = None;
+            let mut socket = zeromq::DealerSocket::new();
+            tokio::time::timeout(CONNECT_TIMEOUT, socket.connect(addr)).await
+                .map_err(|_| format!("Connecting to {} timed out", addr))??;
+            let (requests, queued) = mpsc::channel(PEER_QUEUE_SIZE);
+            tokio::task::spawn(Self::drive(socket, queued, addr.to_string()));
+            *connection = Some(Connection {
+                addr: addr.into(),
+                requests: requests.clone(),
+            });
+            Ok(requests)
+        }
+
+        async fn drive(mut socket: zeromq::DealerSocket, mut queued: mpsc::Receiver<PendingRequest>, addr: String) {
+            let mut waiting: HashMap<u64, oneshot::Sender<Result<(), String>>> = HashMap::new();
+            let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+            let mut last_heard = Instant::now();
+            loop {
+                tokio::select! {
+                    request = queued.recv() => {
+                        let (id, raw_request, ack) = match request {
+                            Some(request) => { request }
+                            None => { return }
+                        };
+                        match socket.send(Self::frames(id, raw_request)).await {
+                            Ok(()) => { waiting.insert(id, ack); }
+                            Err(err) => { let _ = ack.send(Err(format!("Cannot send request, details: {}", err))); }
+                        }
+                    }
+                    reply = socket.recv() => {
+                        let frames = match reply {
+                            Ok(reply) => { reply.into_vec() }
+                            Err(err) => {
+                                log::warn!("Cannot receive acknowledgements from {}, details: {}", addr, err);
+                                return;
+                            }
+                        };
+                        last_heard = Instant::now();
+                        let id = frames.first().and_then(|id| <[u8; 8]>::try_from(&id[..]).ok()).map(u64::from_be_bytes);
+                        match (id.and_then(|id| waiting.remove(&id)), frames.get(1)) {
+                            (Some(ack), Some(status)) if status == ACCEPTED.as_bytes() => { let _ = ack.send(Ok(())); }
+                            (Some(ack), status) => { let _ = ack.send(Err(status.map_or_else(String::new, |status| String::from_utf8_lossy(status).to_string()))); }
+                            (None, _) if id == Some(HEARTBEAT_ID) => {}
+                            (None, _) => { log::debug!("Acknowledgement from {} of an abandoned or unknown request", addr) }
+                        }
+                    }
+                    _ = heartbeat.tick() => {
+                        waiting.retain(|_, ack| !ack.is_closed());
+                        if last_heard.elapsed() >= PEER_TIMEOUT {
+                            // Waiting requests are sent again over a new connection.
+                            log::warn!("Server {} didn't answer for {:?}, dropping the connection", addr, last_heard.elapsed());
+                            return;
+                        }
+                        if last_heard.elapsed() >= HEARTBEAT_INTERVAL {
+                            if let Err(err) = socket.send(Self::frames(HEARTBEAT_ID, Bytes::new())).await {
+                                log::debug!("Cannot send heartbeat to {}, details: {}", addr, err);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        fn frames(id: u64, payload: Bytes) -> ZmqMessage {
+            ZmqMessage::try_from(vec![Bytes::copy_from_slice(&id.to_be_bytes()), payload]).expect("Two frames")
         }
     }
 
+    /// Forwards requests to the other servers at the addresses they are registered with in
+    /// `server_info`, following them when `server_updates` moves them.
     #[derive(Clone)]
     pub struct ZMQConnectionsManager {
-        node_connections: Arc<BTreeMap<usize, tokio::sync::Mutex<zeromq::ReqSocket>>>,
+        node_connections: Arc<Peers>,
         network_info: NetworkInfo,
+        next_id: Arc<AtomicU64>,
+        ack_timeout: Duration,
+        wire: WireConfig,
     }
 
     impl ZMQConnectionsManager {
-        pub(crate) async fn new(network_info: NetworkInfo) -> BasicResult<Self> {
-            let mut node_connections = BTreeMap::new();
-            for (id, server_info) in network_info.get_servers().await {
-                let mut request_sck = zeromq::ReqSocket::new();
-                request_sck.connect(&server_info.addr).await?;
-                node_connections.insert(id, tokio::sync::Mutex::new(request_sck));
-            }
+        /// Opens `pool_size` connections to each of the servers registered so far, those not
+        /// reachable yet are connected to when first sent to.
+        pub(crate) async fn new(network_info: NetworkInfo, pool_size: usize, wire: WireConfig) -> BasicResult<Self> {
+            let updates = network_info.subscribe();
+            let node_connections = Arc::new(Peers {
+                pool_size,
+                peers: std::sync::Mutex::new(BTreeMap::new()),
+            });
+            resync(&node_connections, &network_info).await;
+            tokio::task::spawn(follow(Arc::downgrade(&node_connections), network_info.clone(), updates));
             Ok(ZMQConnectionsManager {
-                node_connections: Arc::new(node_connections),
+                node_connections,
                 network_info,
+                next_id: Arc::new(AtomicU64::new(0)),
+                ack_timeout: ACK_TIMEOUT,
+                wire,
             })
         }
+
+        fn peer(&self, id: usize) -> Arc<Peer> {
+            self.node_connections.peer(id)
+        }
+    }
+
+    struct Peers {
+        pool_size: usize,
+        peers: std::sync::Mutex<BTreeMap<usize, Arc<Peer>>>,
+    }
+
+    impl Peers {
+        fn peer(&self, id: usize) -> Arc<Peer> {
+            self.peers.lock().unwrap().entry(id).or_insert_with(|| Arc::new(Peer::new(self.pool_size))).clone()
+        }
+    }
+
+    async fn connect(peer: &Peer, id: usize, addr: &str) {
+        if let Err(err) = peer.connect(addr).await {
+            log::warn!("Cannot connect to server {} at {} yet, details: {}", id, addr, err);
+        }
+    }
+
+    /// Connects to every registered server and drops the connections to the others.
+    async fn resync(peers: &Peers, network_info: &NetworkInfo) {
+        let servers = network_info.get_servers().await;
+        peers.peers.lock().unwrap().retain(|id, _| servers.contains_key(id));
+        for (id, server_info) in servers {
+            connect(&peers.peer(id), id, &server_info.addr).await;
+        }
+    }
+
+    /// Applies server updates to the connections of a manager until it is dropped.
+    async fn follow(peers: std::sync::Weak<Peers>, network_info: NetworkInfo, mut updates: broadcast::Receiver<ServerUpdate>) {
+        loop {
+            let update = updates.recv().await;
+            let peers = match peers.upgrade() {
+                Some(peers) => { peers }
+                None => { return }
+            };
+            match update {
+                Ok(ServerUpdate::Registered(server_info)) => {
+                    connect(&peers.peer(server_info.id), server_info.id, &server_info.addr).await;
+                }
+                Ok(ServerUpdate::Removed { removed }) => {
+                    // Requests already waiting on the connection are still answered.
+                    if peers.peers.lock().unwrap().remove(&removed).is_some() {
+                        log::info!("Server {} left, disconnecting", removed);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log::warn!("Missed {} server updates, reconnecting to the registered servers", missed);
+                    resync(&peers, &network_info).await;
+                }
+                Err(broadcast::error::RecvError::Closed) => { return }
+            }
+        }
+    }
+
+    impl ZMQConnectionsManager {
+        /// Sends an encoded request or batch and waits for its acknowledgement.
+        async fn send_raw(&self, target_id: usize, raw_request: Bytes) -> BasicResult<()> {
+            for attempt in 1..=SEND_ATTEMPTS {
+                // Looked up on every attempt, so a server which moved is reached at its new address.
+                let server_info = self.network_info.get_server(target_id).await.ok_or(ConnectionError::TargetDoesNotExist(target_id))?;
+                let requests = match self.peer(target_id).requests(&server_info.addr).await {
+                    Ok(requests) => { requests }
+                    Err(err) => {
+                        log::warn!("Cannot connect to server {} at {} (attempt {}), details: {}", target_id, server_info.addr, attempt, err);
+                        tokio::time::sleep(RECONNECT_INTERVAL).await;
+                        continue;
+                    }
+                };
+                let (ack, acknowledged) = oneshot::channel();
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                if requests.send((id, raw_request.clone(), ack)).await.is_err() {
+                    continue;
+                }
+                match tokio::time::timeout(self.ack_timeout, acknowledged).await {
+                    Ok(Ok(Ok(()))) => { return Ok(()) }
+                    Ok(Ok(Err(reason))) => { return Err(format!("Server {} refused the request: {}", target_id, reason).into()) }
+                    Ok(Err(_)) => { log::warn!("Connection to server {} closed before it acknowledged the request (attempt {})", target_id, attempt) }
+                    Err(_) => { log::warn!("Server {} didn't acknowledge the request within {:?} (attempt {})", target_id, self.ack_timeout, attempt) }
+                }
+            }
+            Err(format!("Server {} didn't acknowledge the request after {} attempts", target_id, SEND_ATTEMPTS).into())
+        }
     }
 
     #[async_trait::async_trait]
     impl NodeSender for ZMQConnectionsManager {
-        async fn send_request(&self, target_id: usize, request: PathRequest) -> BasicResult<()> { // todo dont send to self
-            loop {
-                let mut target_sck_guard = self.node_connections.get(&target_id).ok_or(ConnectionError::TargetDoesNotExist(target_id))?.lock().await;
-                let raw_request = serde_json::to_vec(&request)?;
-                target_sck_guard.send(raw_request.into()).await?;
-                let zmq_msg = target_sck_guard.recv().await?;
-                if let Ok(response) = String::from_utf8(zmq_msg.get(0).unwrap().to_vec()) {
-                    if response == "OK" {
-                        return Ok(());
-                    } else {
-                        log::warn!("Node {} responded with message: {}", target_id, response);
+        async fn send_request(&self, target_id: usize, request: HopMessage) -> BasicResult<()> {
+            self.send_raw(target_id, self.wire.encode_hop(&request)?).await
+        }
+
+        async fn send_batch(&self, target_id: usize, requests: Vec<HopMessage>) -> BasicResult<()> {
+            self.send_raw(target_id, self.wire.encode_hop(&requests)?).await
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::collections::BTreeMap;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio::time::timeout;
+        use zeromq::{Socket, SocketRecv, SocketSend};
+        use std::sync::atomic::Ordering;
+        use crate::codec;
+        use crate::domain::{HopMessage, NodeInfo, Priority, RouteResult, RouteStatus};
+        use crate::node_connector::WireConfig;
+        use crate::node_connector::zmq_connector::{DEFAULT_POOL_SIZE, ZMQConnectionsManager, ZMQNodeListener, ZMQReplier};
+        use crate::{NodeListener, NodeSender, ResultReplier};
+        use crate::redis_connector::{NetworkInfo, ServerInfo, ServerUpdate};
+
+        async fn manager(endpoint: &str) -> ZMQConnectionsManager {
+            manager_with_pool(endpoint, DEFAULT_POOL_SIZE).await
+        }
+
+        async fn manager_with_pool(endpoint: &str, pool_size: usize) -> ZMQConnectionsManager {
+            let servers = BTreeMap::from([(0, ServerInfo::new(0, endpoint.to_string().into(), vec![]))]);
+            ZMQConnectionsManager::new(NetworkInfo::new(Arc::new(tokio::sync::RwLock::new(servers))), pool_size, WireConfig::default()).await.unwrap()
+        }
+
+        #[tokio::test]
+        async fn acknowledgements_are_matched_by_correlation_id() {
+            let mut router = zeromq::RouterSocket::new();
+            let endpoint = router.bind("tcp://127.0.0.1:0").await.unwrap().to_string();
+            tokio::task::spawn(async move {
+                // The first request is never acknowledged, as if its acknowledgement got lost.
+                let mut first = true;
+                loop {
+                    let mut frames = router.recv().await.unwrap().into_vec();
+                    frames.pop();
+                    if !std::mem::take(&mut first) {
+                        frames.push("OK".into());
+                        router.send(frames.try_into().unwrap()).await.unwrap();
                     }
-                } else {
-                    log::warn!("Node {} responded with illegible message: {:?}", target_id, zmq_msg);
+                }
+            });
+
+            let mut manager = manager(&endpoint).await;
+            manager.ack_timeout = Duration::from_millis(300);
+            let request = |request_id| HopMessage::new(request_id, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![]);
+            let mut lost = tokio::task::spawn({
+                let manager = manager.clone();
+                async move { manager.send_request(0, request(1)).await.unwrap() }
+            });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            timeout(Duration::from_millis(200), manager.send_request(0, request(2))).await.unwrap().unwrap();
+            assert!(timeout(Duration::ZERO, &mut lost).await.is_err());
+            // Sent again once its acknowledgement timed out.
+            timeout(Duration::from_secs(2), lost).await.unwrap().unwrap();
+        }
+
+        #[tokio::test]
+        async fn requests_take_the_connections_of_the_pool_in_turn() {
+            let mut router = zeromq::RouterSocket::new();
+            let endpoint = router.bind("tcp://127.0.0.1:0").await.unwrap().to_string();
+            let manager = manager_with_pool(&endpoint, 3).await;
+            let sent = tokio::task::spawn(async move {
+                for request_id in 0..6 {
+                    manager.send_request(0, HopMessage::new(request_id, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![])).await.unwrap();
+                }
+            });
+            let mut identities = vec![];
+            while identities.len() < 6 {
+                let mut frames = router.recv().await.unwrap().into_vec();
+                if frames.pop().unwrap().is_empty() {
+                    continue;
+                }
+                identities.push(frames[0].clone());
+                frames.push("OK".into());
+                router.send(frames.try_into().unwrap()).await.unwrap();
+            }
+            sent.await.unwrap();
+            assert_ne!(identities[0], identities[1]);
+            assert_ne!(identities[1], identities[2]);
+            assert_ne!(identities[0], identities[2]);
+            assert_eq!(identities[..3], identities[3..]);
+        }
+
+        #[tokio::test]
+        async fn listener_acknowledges_requests() {
+            let mut listen_sck = zeromq::RouterSocket::new();
+            let endpoint = listen_sck.bind("tcp://127.0.0.1:0").await.unwrap().to_string();
+            let mut listener = ZMQNodeListener::with_socket(listen_sck, WireConfig::default());
+            let manager = manager(&endpoint).await;
+            let sent = tokio::task::spawn(async move {
+                manager.send_request(0, HopMessage::new(3, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![])).await
+            });
+            assert_eq!(listener.get_new_request().await.unwrap().request_id, 3);
+            sent.await.unwrap().unwrap();
+
+            // Clients may use REQ sockets, refused requests are answered with the reason.
+            let mut client = zeromq::ReqSocket::new();
+            client.connect(&endpoint).await.unwrap();
+            client.send("not a request".into()).await.unwrap();
+            assert!(listener.get_new_request().await.is_err());
+            let refusal = String::from_utf8(client.recv().await.unwrap().get(0).unwrap().to_vec()).unwrap();
+            assert!(refusal.starts_with("Cannot deserialize request"), "{}", refusal);
+
+            // Heartbeats are acknowledged without being handed to the server.
+            let received = tokio::task::spawn(async move { listener.get_new_request().await.unwrap().request_id });
+            client.send(vec![].into()).await.unwrap();
+            assert_eq!(client.recv().await.unwrap().get(0).unwrap().as_ref(), b"OK");
+            client.send(codec::encode(&HopMessage::new(4, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![])).unwrap().into()).await.unwrap();
+            assert_eq!(received.await.unwrap(), 4);
+        }
+
+        #[tokio::test]
+        async fn batches_are_unpacked_by_the_listener() {
+            let mut listen_sck = zeromq::RouterSocket::new();
+            let endpoint = listen_sck.bind("tcp://127.0.0.1:0").await.unwrap().to_string();
+            let mut listener = ZMQNodeListener::with_socket(listen_sck, WireConfig::default());
+            let manager = manager(&endpoint).await;
+            let batch = (6..9).map(|request_id| HopMessage::new(request_id, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![])).collect();
+            let sent = tokio::task::spawn(async move { manager.send_batch(0, batch).await });
+            for expected in 6..9 {
+                assert_eq!(listener.get_new_request().await.unwrap().request_id, expected);
+            }
+            sent.await.unwrap().unwrap();
+        }
+
+        #[tokio::test]
+        async fn high_priority_hops_are_handed_over_first() {
+            let mut listen_sck = zeromq::RouterSocket::new();
+            let endpoint = listen_sck.bind("tcp://127.0.0.1:0").await.unwrap().to_string();
+            let mut listener = ZMQNodeListener::with_socket(listen_sck, WireConfig::default());
+            let manager = manager(&endpoint).await;
+            let batch = (1..5).map(|request_id| {
+                let mut hop = HopMessage::new(request_id, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![]);
+                hop.priority = if request_id % 2 == 1 { Priority::Low } else { Priority::High };
+                hop
+            }).collect();
+            let sent = tokio::task::spawn(async move { manager.send_batch(0, batch).await });
+            for expected in [2, 4, 1, 3] {
+                assert_eq!(listener.get_new_request().await.unwrap().request_id, expected);
+            }
+            sent.await.unwrap().unwrap();
+        }
+
+        #[tokio::test]
+        async fn requests_follow_servers_to_their_new_address() {
+            let mut listeners = vec![];
+            let mut endpoints = vec![];
+            for _ in 0..2 {
+                let mut listen_sck = zeromq::RouterSocket::new();
+                endpoints.push(listen_sck.bind("tcp://127.0.0.1:0").await.unwrap().to_string());
+                listeners.push(ZMQNodeListener::with_socket(listen_sck, WireConfig::default()));
+            }
+            let network_info = NetworkInfo::new(Arc::new(tokio::sync::RwLock::new(BTreeMap::from([(0, ServerInfo::new(0, endpoints[0].clone().into(), vec![]))]))));
+            let manager = ZMQConnectionsManager::new(network_info.clone(), DEFAULT_POOL_SIZE, WireConfig::default()).await.unwrap();
+            let mut listener = listeners.remove(0);
+            let request = |request_id| HopMessage::new(request_id, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![]);
+            let sent = tokio::task::spawn({
+                let manager = manager.clone();
+                async move { manager.send_request(0, request(1)).await }
+            });
+            assert_eq!(listener.get_new_request().await.unwrap().request_id, 1);
+            sent.await.unwrap().unwrap();
+
+            network_info.apply(ServerUpdate::Registered(ServerInfo::new(0, endpoints[1].clone().into(), vec![]))).await;
+            let mut moved = listeners.remove(0);
+            let sent = tokio::task::spawn(async move { manager.send_request(0, request(2)).await });
+            assert_eq!(timeout(Duration::from_secs(2), moved.get_new_request()).await.unwrap().unwrap().request_id, 2);
+            sent.await.unwrap().unwrap();
+        }
+
+        async fn wait_until<F: Fn() -> bool>(condition: F) {
+            timeout(Duration::from_secs(2), async {
+                while !condition() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }).await.unwrap();
+        }
+
+        #[tokio::test]
+        async fn servers_join_and_leave_at_runtime() {
+            let mut listen_sck = zeromq::RouterSocket::new();
+            let endpoint = listen_sck.bind("tcp://127.0.0.1:0").await.unwrap().to_string();
+            let mut listener = ZMQNodeListener::with_socket(listen_sck, WireConfig::default());
+            let network_info = NetworkInfo::new(Arc::new(tokio::sync::RwLock::new(BTreeMap::new())));
+            let manager = ZMQConnectionsManager::new(network_info.clone(), DEFAULT_POOL_SIZE, WireConfig::default()).await.unwrap();
+
+            network_info.apply(ServerUpdate::Registered(ServerInfo::new(1, endpoint.into(), vec![]))).await;
+            wait_until(|| manager.node_connections.peers.lock().unwrap().contains_key(&1)).await;
+            let sent = tokio::task::spawn({
+                let manager = manager.clone();
+                async move { manager.send_request(1, HopMessage::new(5, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![])).await }
+            });
+            assert_eq!(listener.get_new_request().await.unwrap().request_id, 5);
+            sent.await.unwrap().unwrap();
+
+            network_info.apply(ServerUpdate::Removed { removed: 1 }).await;
+            wait_until(|| manager.node_connections.peers.lock().unwrap().is_empty()).await;
+            assert!(manager.send_request(1, HopMessage::new(6, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![])).await.is_err());
+            assert!(manager.node_connections.peers.lock().unwrap().is_empty());
+        }
+
+        #[tokio::test]
+        async fn results_are_spooled_while_collector_is_down() {
+            let mut collector = zeromq::PullSocket::new();
+            let endpoint = collector.bind("tcp://127.0.0.1:0").await.unwrap().to_string();
+            let replier = ZMQReplier::new(&endpoint, 2, WireConfig::default()).await.unwrap();
+            let result = |request_id| RouteResult { request_id, source: NodeInfo(1, 1), target: NodeInfo(2, 1), path: vec![], cost: 0, status: RouteStatus::Found, weight_scale: Default::default(), reason: None, failure: None };
+
+            replier.send(&result(1)).await.unwrap();
+            let received: RouteResult = codec::decode(collector.recv().await.unwrap().get(0).unwrap()).unwrap();
+            assert_eq!(received.request_id, 1);
+            drop(collector);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            // Without acknowledgements the first write after the collector died can still look successful.
+            for request_id in 2..10 {
+                replier.send(&result(request_id)).await.unwrap();
+                if replier.stats.spooled.load(Ordering::Relaxed) > 0 {
+                    break;
                 }
             }
+            replier.send(&result(100)).await.unwrap();
+            replier.send(&result(101)).await.unwrap();
+            assert_eq!(replier.stats.spooled.load(Ordering::Relaxed), 3);
+            assert_eq!(replier.stats.dropped.load(Ordering::Relaxed), 1);
+            assert!(replier.flush().await.is_err());
+
+            let mut collector = zeromq::PullSocket::new();
+            collector.bind(&endpoint).await.unwrap();
+            for expected in [100, 101] {
+                let raw = timeout(Duration::from_secs(5), collector.recv()).await.unwrap().unwrap();
+                let received: RouteResult = codec::decode(raw.get(0).unwrap()).unwrap();
+                assert_eq!(received.request_id, expected);
+            }
+            assert_eq!(replier.stats.replayed.load(Ordering::Relaxed), 2);
+            replier.flush().await.unwrap();
+        }
+
+        #[tokio::test]
+        async fn maintenance_stops_with_the_last_replier() {
+            let replier = ZMQReplier::new("tcp://127.0.0.1:1", 2, WireConfig::default()).await.unwrap();
+            let state = Arc::downgrade(&replier.state);
+            let copy = replier.clone();
+            drop(replier);
+            // The copy and the running task hold the state.
+            assert_eq!(state.strong_count(), 2);
+            drop(copy);
+            // The aborted task drops its hold on the state.
+            wait_until(|| state.strong_count() == 0).await;
         }
     }
 }
@@ -204,39 +1047,88 @@ pub(crate) mod zmq_connector {
 pub(crate) mod redis_connector {
     use std::fmt::{Display, Formatter};
     use std::pin::Pin;
-    use futures_util::StreamExt;
-    use redis::{AsyncCommands, Msg};
-    use crate::node_connector::{BasicResult};
-    use crate::{ConnectionError, NodeListener, NodeSender, RedisConnector, ResultReplier};
-    use crate::domain::PathRequest;
+    use futures_util::{FutureExt as _, StreamExt};
+    use redis::{AsyncCommands, Msg, RedisResult};
+    use crate::node_connector::{BasicResult, PendingHops, WireConfig};
+    use crate::{codec, ConnectionError, NodeListener, NodeSender, RedisConnector, ResultReplier};
+    use crate::domain::{HopMessage, InboundPayload, RouteResult};
 
 
     pub(crate) struct RedisNodeListener {
-        stream: Pin<Box<dyn futures_util::Stream<Item=Msg> + Sync + Send>>,
+        stream: Option<Pin<Box<dyn futures_util::Stream<Item=Msg> + Sync + Send>>>,
+        redis_connector: RedisConnector,
+        id: usize,
+        /// Hops received but not handed to the server yet, messages already delivered are read ahead.
+        pending: PendingHops,
+        wire: WireConfig,
     }
 
     impl RedisNodeListener {
-        pub(crate) async fn new(redis_connector: &RedisConnector, id: usize) -> BasicResult<Self> {
-            let connection = redis_connector.spawn_connection().await?;
+        pub(crate) async fn new(redis_connector: &RedisConnector, id: usize, wire: WireConfig) -> BasicResult<Self> {
+            let mut listener = Self::deferred(redis_connector, id, wire);
+            listener.subscribe().await?;
+            Ok(listener)
+        }
+
+        /// Subscribes when the first request is awaited instead of right away.
+        pub(crate) fn deferred(redis_connector: &RedisConnector, id: usize, wire: WireConfig) -> Self {
+            Self {
+                stream: None,
+                redis_connector: redis_connector.clone(),
+                id,
+                pending: PendingHops::default(),
+                wire,
+            }
+        }
+
+        fn unpack(wire: &WireConfig, msg: Msg) -> Result<Vec<HopMessage>, ConnectionError> {
+            let payload: InboundPayload = wire.decode_inbound(msg.get_payload_bytes())
+                .map_err(|err| ConnectionError::RedisDeserializationError(codec::redis_error(err)))?;
+            Ok(payload.into_hops())
+        }
+
+        async fn subscribe(&mut self) -> RedisResult<()> {
+            let connection = self.redis_connector.spawn_connection().await?;
             let mut pubsub = connection.into_pubsub();
-            pubsub.subscribe(format!("node_{}", id)).await?;
-            let stream = Box::pin(pubsub.into_on_message());
-            Ok(Self {
-                stream,
-            })
+            pubsub.subscribe(format!("node_{}", self.id)).await?;
+            self.stream = Some(Box::pin(pubsub.into_on_message()));
+            Ok(())
         }
     }
 
     #[async_trait::async_trait]
     impl NodeListener for RedisNodeListener {
-        async fn get_new_request(&mut self) -> Result<PathRequest, ConnectionError> {
-            self.stream.next().await.ok_or(ConnectionError::NoRequest)?.get_payload().map_err(|err| ConnectionError::RedisDeserializationError(err))
+        async fn get_new_request(&mut self) -> Result<HopMessage, ConnectionError> {
+            if self.stream.is_none() {
+                self.subscribe().await.map_err(ConnectionError::SubscriptionError)?;
+            }
+            let stream = self.stream.as_mut().expect("Subscribed above");
+            while !self.pending.is_full() {
+                match stream.next().now_or_never().flatten() {
+                    Some(msg) => { self.pending.extend(Self::unpack(&self.wire, msg)?) }
+                    None => { break }
+                }
+            }
+            loop {
+                if let Some(hop) = self.pending.pop_hop() {
+                    return Ok(hop);
+                }
+                let msg = stream.next().await.ok_or(ConnectionError::NoRequest)?;
+                self.pending.extend(Self::unpack(&self.wire, msg)?);
+            }
+        }
+
+        /// Subscribes again on the next read.
+        async fn restart(&mut self) -> Result<(), ConnectionError> {
+            self.stream = None;
+            Ok(())
         }
     }
 
     #[derive(Clone)]
     pub(crate) struct RedisReplier {
-        redis_connector: RedisConnector
+        redis_connector: RedisConnector,
+        wire: WireConfig,
     }
 
     impl Display for RedisReplier {
@@ -246,19 +1138,21 @@ pub(crate) mod redis_connector {
     }
 
     impl RedisReplier {
-        pub(crate) async fn new(redis_connector: RedisConnector) -> BasicResult<Self> {
+        pub(crate) async fn new(redis_connector: RedisConnector, wire: WireConfig) -> BasicResult<Self> {
             Ok(Self {
                 redis_connector,
+                wire,
             })
         }
     }
 
     #[async_trait::async_trait]
     impl ResultReplier for RedisReplier {
-        async fn send(&self, reply: &PathRequest) -> BasicResult<()> {
-            let (_count_guard, mut conn) = self.redis_connector.claim_connection().await;
-            let res = conn.publish(format!("results_{}", reply.request_id), reply).await;
-            self.redis_connector.release_connection(conn).await;
+        async fn send(&self, reply: &RouteResult) -> BasicResult<()> {
+            let raw_reply = self.wire.encode_reply(reply)?;
+            let mut conn = self.redis_connector.claim_connection().await?;
+            let res: RedisResult<()> = conn.publish(crate::client::results_channel(reply.request_id), &*raw_reply).await;
+            conn.release();
             res?;
             Ok(())
         }
@@ -267,24 +1161,1058 @@ pub(crate) mod redis_connector {
     #[derive(Clone)]
     pub struct RedisConnectionsManager {
         redis_connector: RedisConnector,
+        wire: WireConfig,
     }
 
     impl RedisConnectionsManager {
-        pub(crate) async fn new(redis_connector: RedisConnector) -> BasicResult<Self> {
+        pub(crate) async fn new(redis_connector: RedisConnector, wire: WireConfig) -> BasicResult<Self> {
             Ok(Self {
                 redis_connector,
+                wire,
             })
         }
+
+        async fn publish(&self, target_id: usize, raw_requests: &[u8]) -> BasicResult<()> {
+            let mut conn = self.redis_connector.claim_connection().await?;
+            let res: RedisResult<()> = conn.publish(format!("node_{}", target_id), raw_requests).await;
+            conn.release();
+            res?;
+            Ok(())
+        }
     }
 
     #[async_trait::async_trait]
     impl NodeSender for RedisConnectionsManager {
-        async fn send_request(&self, target_id: usize, request: PathRequest) -> BasicResult<()> { // todo dont send to self
-            let (_count_guard, mut conn) = self.redis_connector.claim_connection().await;
-            let res = conn.publish(format!("node_{}", target_id), request).await;
-            self.redis_connector.release_connection(conn).await;
-            res?;
-            Ok(())
+        async fn send_request(&self, target_id: usize, request: HopMessage) -> BasicResult<()> {
+            self.publish(target_id, &self.wire.encode_hop(&request)?).await
+        }
+
+        async fn send_batch(&self, target_id: usize, requests: Vec<HopMessage>) -> BasicResult<()> {
+            self.publish(target_id, &self.wire.encode_hop(&requests)?).await
+        }
+    }
+}
+
+/// Transport over Redis Streams, a reliable alternative to pub/sub: requests for a server are
+/// appended to its stream `node_stream_<id>` and read by the server's consumer group, which
+/// acknowledges an entry once its hops are handed to the server. Entries sent while a server is
+/// down wait in its stream, and entries read but not acknowledged when it died are read again once
+/// it is back, or by its standby. Hops being served when a server dies are still lost.
+pub(crate) mod stream_connector {
+    use std::collections::HashMap;
+    use std::env;
+    use bytes::Bytes;
+    use redis::{AsyncCommands, RedisResult};
+    use redis::streams::{StreamId, StreamMaxlen, StreamReadOptions, StreamReadReply};
+    use crate::node_connector::{BasicResult, PendingHops, WireConfig};
+    use crate::{ConnectionError, NodeListener, NodeSender, RedisConnector};
+    use crate::domain::{HopMessage, InboundPayload};
+
+    /// Consumer group reading the stream of a server. The processes of a group consume under the
+    /// same name, so a standby taking over reads the entries its predecessor left unacknowledged.
+    const GROUP: &str = "servers";
+    /// Field of an entry holding the encoded request or batch.
+    const PAYLOAD: &str = "payload";
+    /// Entries read at once.
+    const READ_COUNT: usize = 64;
+    /// Default bound of the length of a stream, past which its oldest entries are trimmed.
+    pub(crate) const DEFAULT_MAX_LEN: usize = 100_000;
+
+    fn stream_key(id: usize) -> String {
+        format!("node_stream_{}", id)
+    }
+
+    fn consumer(id: usize) -> String {
+        format!("server_{}", id)
+    }
+
+    /// Reads STREAM_MAX_LEN, the approximate number of entries kept per stream.
+    pub(crate) fn max_len_from_env() -> BasicResult<usize> {
+        match env::var("STREAM_MAX_LEN") {
+            Ok(max_len) => { Ok(max_len.parse()?) }
+            Err(_) => { Ok(DEFAULT_MAX_LEN) }
+        }
+    }
+
+    /// Hops of an entry.
+    fn unpack(wire: &WireConfig, entry: &StreamId) -> Result<Vec<HopMessage>, ConnectionError> {
+        // Entries trimmed before they were acknowledged are read again without fields.
+        match entry.map.get(PAYLOAD) {
+            Some(payload) => {
+                let payload: InboundPayload = wire.decode_inbound_redis(payload).map_err(ConnectionError::RedisDeserializationError)?;
+                Ok(payload.into_hops())
+            }
+            None => { Ok(vec![]) }
+        }
+    }
+
+    /// Entries read from a stream until they are acknowledged.
+    #[derive(Default)]
+    struct ReadEntries {
+        /// Hops read but not handed to the server yet, with the id of the entry they were read from.
+        pending: PendingHops<String>,
+        /// Hops of every entry read that weren't handed over yet, an entry is acknowledged once
+        /// all of them were.
+        unacknowledged: HashMap<String, usize>,
+        /// Entries done with, acknowledged on the next read. Reads are given up whenever the
+        /// server has something else to do, so a hop is never held back while awaiting redis.
+        finished: Vec<String>,
+    }
+
+    impl ReadEntries {
+        /// Queues the hops of `entries`, those of the entries read at once by priority. Entries
+        /// still held are skipped: read again from the backlog after a reconnection, their hops
+        /// are queued or were handed over already.
+        fn take(&mut self, wire: &WireConfig, entries: Vec<StreamId>) {
+            for entry in entries {
+                if self.unacknowledged.contains_key(&entry.id) || self.finished.contains(&entry.id) {
+                    continue;
+                }
+                match unpack(wire, &entry) {
+                    Ok(hops) if !hops.is_empty() => {
+                        self.unacknowledged.insert(entry.id.clone(), hops.len());
+                        for hop in hops {
+                            self.pending.push(hop, entry.id.clone());
+                        }
+                    }
+                    Ok(_) => { self.finished.push(entry.id) }
+                    Err(err) => {
+                        log::warn!("Dropping undecodable request {}, details: {}", entry.id, err);
+                        self.finished.push(entry.id);
+                    }
+                }
+            }
+        }
+
+        /// Hands over the next hop, finishing its entry with its last hop.
+        fn pop(&mut self) -> Option<HopMessage> {
+            let (hop, entry) = self.pending.pop()?;
+            let left = self.unacknowledged.get_mut(&entry).expect("Read entries are counted");
+            *left -= 1;
+            if *left == 0 {
+                self.unacknowledged.remove(&entry);
+                self.finished.push(entry);
+            }
+            Some(hop)
+        }
+    }
+
+    pub(crate) struct RedisStreamListener {
+        connection: Option<redis::aio::Connection>,
+        redis_connector: RedisConnector,
+        id: usize,
+        /// Id after which the entries left unacknowledged by earlier connections are read again,
+        /// `None` once all of them were.
+        backlog: Option<String>,
+        entries: ReadEntries,
+        wire: WireConfig,
+    }
+
+    impl RedisStreamListener {
+        pub(crate) async fn new(redis_connector: &RedisConnector, id: usize, wire: WireConfig) -> BasicResult<Self> {
+            let mut listener = Self::deferred(redis_connector, id, wire);
+            listener.connect().await?;
+            Ok(listener)
+        }
+
+        /// Reads from the stream when the first request is awaited instead of right away.
+        pub(crate) fn deferred(redis_connector: &RedisConnector, id: usize, wire: WireConfig) -> Self {
+            Self {
+                connection: None,
+                redis_connector: redis_connector.clone(),
+                id,
+                backlog: None,
+                entries: ReadEntries::default(),
+                wire,
+            }
+        }
+
+        /// Opens the connection reading the stream and creates the consumer group of the server,
+        /// which reads the entries sent before it existed too.
+        async fn connect(&mut self) -> RedisResult<()> {
+            let mut connection = self.redis_connector.spawn_connection().await?;
+            let created: RedisResult<()> = connection.xgroup_create_mkstream(stream_key(self.id), GROUP, "0").await;
+            match created {
+                Err(err) if err.code() != Some("BUSYGROUP") => { return Err(err) }
+                _ => {}
+            }
+            self.connection = Some(connection);
+            self.backlog = Some("0".to_string());
+            Ok(())
+        }
+
+        async fn read(&mut self) -> RedisResult<Vec<StreamId>> {
+            if self.connection.is_none() {
+                self.connect().await?;
+            }
+            let connection = self.connection.as_mut().expect("Connected above");
+            let key = stream_key(self.id);
+            let options = StreamReadOptions::default().group(GROUP, consumer(self.id)).count(READ_COUNT);
+            if let Some(after) = self.backlog.take() {
+                let reply: StreamReadReply = connection.xread_options(&[&key], &[&after], &options).await?;
+                let entries: Vec<StreamId> = reply.keys.into_iter().flat_map(|key| key.ids).collect();
+                if let Some(last) = entries.last() {
+                    log::info!("Reading {} requests left unacknowledged on {} again", entries.len(), key);
+                    self.backlog = Some(last.id.clone());
+                    return Ok(entries);
+                }
+            }
+            let reply: StreamReadReply = connection.xread_options(&[&key], &[">"], &options.block(0)).await?;
+            Ok(reply.keys.into_iter().flat_map(|key| key.ids).collect())
+        }
+
+        async fn acknowledge(&self, entry: &str) {
+            let acknowledged: RedisResult<()> = async {
+                let mut conn = self.redis_connector.claim_connection().await?;
+                let res = conn.xack(stream_key(self.id), GROUP, &[entry]).await;
+                conn.release();
+                res
+            }.await;
+            if let Err(err) = acknowledged {
+                log::warn!("Cannot acknowledge request {}, it will be read again after a restart. Details: {}", entry, err);
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl NodeListener for RedisStreamListener {
+        async fn get_new_request(&mut self) -> Result<HopMessage, ConnectionError> {
+            while let Some(entry) = self.entries.finished.last().cloned() {
+                self.acknowledge(&entry).await;
+                self.entries.finished.pop();
+            }
+            loop {
+                if let Some(hop) = self.entries.pop() {
+                    return Ok(hop);
+                }
+                match self.read().await {
+                    Ok(entries) => { self.entries.take(&self.wire, entries) }
+                    Err(err) => {
+                        self.connection = None;
+                        return Err(ConnectionError::SubscriptionError(err));
+                    }
+                }
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    pub(crate) struct RedisStreamSender {
+        redis_connector: RedisConnector,
+        max_len: usize,
+        wire: WireConfig,
+    }
+
+    impl RedisStreamSender {
+        pub(crate) fn new(redis_connector: RedisConnector, max_len: usize, wire: WireConfig) -> Self {
+            Self {
+                redis_connector,
+                max_len,
+                wire,
+            }
+        }
+
+        async fn append(&self, target_id: usize, payload: Bytes) -> BasicResult<()> {
+            let mut conn = self.redis_connector.claim_connection().await?;
+            let res: RedisResult<String> = conn.xadd_maxlen(stream_key(target_id), StreamMaxlen::Approx(self.max_len), "*", &[(PAYLOAD, &*payload)]).await;
+            conn.release();
+            res?;
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl NodeSender for RedisStreamSender {
+        async fn send_request(&self, target_id: usize, request: HopMessage) -> BasicResult<()> {
+            self.append(target_id, self.wire.encode_hop(&request)?).await
+        }
+
+        async fn send_batch(&self, target_id: usize, requests: Vec<HopMessage>) -> BasicResult<()> {
+            self.append(target_id, self.wire.encode_hop(&requests)?).await
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::collections::HashMap;
+        use redis::Value;
+        use redis::streams::StreamId;
+        use crate::codec;
+        use crate::domain::{HopMessage, NodeInfo};
+        use crate::node_connector::WireConfig;
+        use crate::node_connector::stream_connector::{unpack, ReadEntries, PAYLOAD};
+
+        fn entry(id: &str, payload: Option<Vec<u8>>) -> StreamId {
+            StreamId {
+                id: id.to_string(),
+                map: payload.map(|payload| HashMap::from([(PAYLOAD.to_string(), Value::Data(payload))])).unwrap_or_default(),
+            }
+        }
+
+        #[test]
+        fn entries_are_unpacked_into_their_hops() {
+            let batch: Vec<HopMessage> = (1..4).map(|request_id| HopMessage::new(request_id, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![])).collect();
+            let hops = unpack(&WireConfig::default(), &entry("1-0", Some(codec::encode(&batch).unwrap().to_vec()))).unwrap();
+            assert_eq!(hops.iter().map(|hop| hop.request_id).collect::<Vec<_>>(), vec![1, 2, 3]);
+
+            let hops = unpack(&WireConfig::default(), &entry("2-0", Some(codec::encode(&batch[0]).unwrap().to_vec()))).unwrap();
+            assert_eq!(hops[0].request_id, 1);
+            assert!(unpack(&WireConfig::default(), &entry("3-0", None)).unwrap().is_empty());
+            assert!(unpack(&WireConfig::default(), &entry("4-0", Some(b"{".to_vec()))).is_err());
+        }
+
+        #[test]
+        fn entries_read_again_are_skipped_while_held() {
+            let batch: Vec<HopMessage> = (1..3).map(|request_id| HopMessage::new(request_id, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![])).collect();
+            let read = || vec![entry("1-0", Some(codec::encode(&batch).unwrap().to_vec())), entry("2-0", Some(codec::encode(&batch[0]).unwrap().to_vec()))];
+            let mut entries = ReadEntries::default();
+            entries.take(&WireConfig::default(), read());
+            assert_eq!(entries.pop().unwrap().request_id, 1);
+            // Read from the backlog again after reconnecting.
+            entries.take(&WireConfig::default(), read());
+            let handed: Vec<_> = std::iter::from_fn(|| entries.pop()).map(|hop| hop.request_id).collect();
+            assert_eq!(handed, vec![2, 1]);
+            assert_eq!(entries.finished, vec!["1-0", "2-0"]);
+            entries.take(&WireConfig::default(), read());
+            assert!(entries.pop().is_none());
+        }
+    }
+}
+
+/// Transport over plain TCP connections carrying length-prefixed frames: a big-endian `u32`
+/// length followed by the codec-encoded payload. Every frame is acknowledged with a single byte,
+/// so senders notice connections which broke and send the frame again over a new one.
+pub(crate) mod tcp_connector {
+    use std::collections::BTreeMap;
+    use std::fmt::{Display, Formatter};
+    use std::sync::{Arc, Weak};
+    use std::time::Duration;
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{broadcast, mpsc};
+    use crate::node_connector::{BasicResult, PendingHops, WireConfig};
+    use crate::{ConnectionError, NodeListener, NodeSender, ResultReplier};
+    use crate::domain::{HopMessage, InboundPayload, RouteResult};
+    use crate::redis_connector::{NetworkInfo, ServerUpdate};
+
+    /// Frames larger than this are refused, a corrupt length must not allocate gigabytes.
+    const MAX_FRAME_LEN: usize = 64 << 20;
+    const ACCEPTED: u8 = 0;
+    const REJECTED: u8 = 1;
+    /// Attempts to deliver a frame, reconnecting after each failure.
+    const SEND_ATTEMPTS: u32 = 5;
+    const RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Time a frame may wait for its acknowledgement. Busy servers acknowledge late on purpose, so
+    /// it is generous; a connection whose peer stays silent longer is dropped and opened again.
+    const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// `tcp://` addresses, as used in ZMQ mode, are accepted too.
+    pub(crate) fn socket_addr(addr: &str) -> &str {
+        addr.strip_prefix("tcp://").unwrap_or(addr)
+    }
+
+    async fn read_frame(stream: &mut TcpStream) -> std::io::Result<Option<Vec<u8>>> {
+        let len = match stream.read_u32().await {
+            Ok(len) => { len as usize }
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => { return Ok(None) }
+            Err(err) => { return Err(err) }
+        };
+        if len > MAX_FRAME_LEN {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Frame of {} bytes exceeds the limit", len)));
+        }
+        let mut frame = vec![0; len];
+        stream.read_exact(&mut frame).await?;
+        Ok(Some(frame))
+    }
+
+    /// Reads the frames of a connection until it closes, queueing the hops they carry. A frame
+    /// holding a batch is acknowledged once all its hops are queued.
+    async fn receive(mut stream: TcpStream, requests: mpsc::Sender<HopMessage>, wire: &WireConfig) -> std::io::Result<()> {
+        while let Some(frame) = read_frame(&mut stream).await? {
+            match wire.decode_inbound::<InboundPayload>(&frame) {
+                Ok(payload) => {
+                    for hop in payload.into_hops() {
+                        if requests.send(hop).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    stream.write_u8(ACCEPTED).await?;
+                }
+                Err(err) => {
+                    log::warn!("Rejecting an undecodable frame of {} bytes, details: {}", frame.len(), err);
+                    stream.write_u8(REJECTED).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) struct TcpNodeListener {
+        requests: mpsc::Receiver<HopMessage>,
+        pending: PendingHops,
+    }
+
+    impl TcpNodeListener {
+        pub(crate) async fn new(addr: &str, wire: WireConfig) -> BasicResult<Self> {
+            let listener = TcpListener::bind(socket_addr(addr)).await?;
+            // A frame is only acknowledged once the server took it or read it ahead, so senders
+            // wait while it is busy.
+            let (sender, requests) = mpsc::channel(1);
+            tokio::task::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, peer)) => {
+                            let sender = sender.clone();
+                            let wire = wire.clone();
+                            tokio::task::spawn(async move {
+                                if let Err(err) = receive(stream, sender, &wire).await {
+                                    log::debug!("Connection from {} closed, details: {}", peer, err);
+                                }
+                            });
+                        }
+                        Err(err) => { log::warn!("Unable to accept a connection, details: {}", err) }
+                    }
+                }
+            });
+            Ok(TcpNodeListener {
+                requests,
+                pending: PendingHops::default(),
+            })
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl NodeListener for TcpNodeListener {
+        async fn get_new_request(&mut self) -> Result<HopMessage, ConnectionError> {
+            self.pending.read_ahead(|| self.requests.try_recv().ok());
+            match self.pending.pop_hop() {
+                Some(hop) => { Ok(hop) }
+                None => { self.requests.recv().await.ok_or(ConnectionError::NoRequest) }
+            }
+        }
+    }
+
+    /// Connection to `addr`, opened when first needed and opened again whenever it breaks.
+    pub(crate) struct Connection {
+        addr: String,
+        stream: tokio::sync::Mutex<Option<TcpStream>>,
+        ack_timeout: Duration,
+    }
+
+    impl Connection {
+        pub(crate) fn new(addr: &str) -> Self {
+            Self {
+                addr: socket_addr(addr).to_string(),
+                stream: tokio::sync::Mutex::new(None),
+                ack_timeout: ACK_TIMEOUT,
+            }
+        }
+
+        async fn exchange(stream: &mut Option<TcpStream>, addr: &str, frame: &[u8], ack_timeout: Duration) -> BasicResult<u8> {
+            if stream.is_none() {
+                let connected = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await
+                    .map_err(|_| format!("Connecting to {} timed out", addr))??;
+                connected.set_nodelay(true)?;
+                *stream = Some(connected);
+            }
+            let connected = stream.as_mut().unwrap();
+            connected.write_u32(frame.len() as u32).await?;
+            connected.write_all(frame).await?;
+            let ack = tokio::time::timeout(ack_timeout, connected.read_u8()).await
+                .map_err(|_| format!("{} didn't acknowledge the frame within {:?}", addr, ack_timeout))??;
+            Ok(ack)
+        }
+
+        /// Sends `frame` and waits for it to be acknowledged. A frame whose acknowledgement got
+        /// lost is sent again, so it may arrive twice.
+        async fn send(&self, frame: &[u8]) -> BasicResult<()> {
+            let mut stream = self.stream.lock().await;
+            let mut attempt = 1;
+            loop {
+                match Self::exchange(&mut stream, &self.addr, frame, self.ack_timeout).await {
+                    Ok(ACCEPTED) => { return Ok(()) }
+                    Ok(_) => { return Err(format!("{} rejected the frame", self.addr).into()) }
+                    Err(err) if attempt < SEND_ATTEMPTS => {
+                        log::debug!("Sending to {} failed (attempt {}), reconnecting. Details: {}", self.addr, attempt, err);
+                        *stream = None;
+                        tokio::time::sleep(RECONNECT_BACKOFF * attempt).await;
+                        attempt += 1;
+                    }
+                    Err(err) => {
+                        *stream = None;
+                        return Err(err);
+                    }
+                }
+            }
+        }
+
+        /// Sends `frame` from its own task: cancelling the caller must not leave half a frame on the connection.
+        async fn send_detached(self: &Arc<Self>, frame: Bytes) -> BasicResult<()> {
+            let connection = self.clone();
+            tokio::task::spawn(async move { connection.send(&frame).await }).await?
+        }
+    }
+
+    /// Sends results to the collector at REPLY_ADDR, which acknowledges them like servers do hops.
+    #[derive(Clone)]
+    pub(crate) struct TcpReplier {
+        connection: Arc<Connection>,
+        wire: WireConfig,
+    }
+
+    impl TcpReplier {
+        pub(crate) fn new(addr: &str, wire: WireConfig) -> Self {
+            Self {
+                connection: Arc::new(Connection::new(addr)),
+                wire,
+            }
+        }
+    }
+
+    impl Display for TcpReplier {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.connection.addr)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ResultReplier for TcpReplier {
+        async fn send(&self, reply: &RouteResult) -> BasicResult<()> {
+            self.connection.send_detached(self.wire.encode_reply(reply)?).await
+        }
+    }
+
+    type Connections = std::sync::Mutex<BTreeMap<usize, Arc<Connection>>>;
+
+    /// Connections to the servers of `server_info`, following them when `server_updates` moves
+    /// them or removes them.
+    #[derive(Clone)]
+    pub(crate) struct TcpConnectionsManager {
+        node_connections: Arc<Connections>,
+        network_info: NetworkInfo,
+        wire: WireConfig,
+    }
+
+    impl TcpConnectionsManager {
+        /// Connects lazily, so servers started later are reached once they are up.
+        pub(crate) async fn new(network_info: NetworkInfo, wire: WireConfig) -> BasicResult<Self> {
+            let node_connections = Arc::new(Connections::default());
+            tokio::task::spawn(follow(Arc::downgrade(&node_connections), network_info.clone(), network_info.subscribe()));
+            Ok(TcpConnectionsManager {
+                node_connections,
+                network_info,
+                wire,
+            })
+        }
+
+        /// Connection to the server at the address it is registered with now.
+        async fn connection(&self, target_id: usize) -> BasicResult<Arc<Connection>> {
+            let server_info = self.network_info.get_server(target_id).await.ok_or(ConnectionError::TargetDoesNotExist(target_id))?;
+            let mut connections = self.node_connections.lock().unwrap();
+            let connection = connections.entry(target_id).or_insert_with(|| Arc::new(Connection::new(&server_info.addr)));
+            if connection.addr != socket_addr(&server_info.addr) {
+                *connection = Arc::new(Connection::new(&server_info.addr));
+            }
+            Ok(connection.clone())
+        }
+    }
+
+    /// Drops the connections to servers which left or moved until the manager is dropped. Those
+    /// which moved are connected to at their new address when next sent to.
+    async fn follow(connections: Weak<Connections>, network_info: NetworkInfo, mut updates: broadcast::Receiver<ServerUpdate>) {
+        loop {
+            let update = updates.recv().await;
+            let connections = match connections.upgrade() {
+                Some(connections) => { connections }
+                None => { return }
+            };
+            match update {
+                Ok(ServerUpdate::Registered(server_info)) => {
+                    let mut connections = connections.lock().unwrap();
+                    if connections.get(&server_info.id).is_some_and(|connection| connection.addr != socket_addr(&server_info.addr)) {
+                        log::info!("Server {} moved to {}, reconnecting", server_info.id, server_info.addr);
+                        connections.remove(&server_info.id);
+                    }
+                }
+                Ok(ServerUpdate::Removed { removed }) => {
+                    // Frames already being sent keep their connection until acknowledged.
+                    if connections.lock().unwrap().remove(&removed).is_some() {
+                        log::info!("Server {} left, disconnecting", removed);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log::warn!("Missed {} server updates, dropping the connections to unregistered servers", missed);
+                    let servers = network_info.get_servers().await;
+                    connections.lock().unwrap().retain(|id, connection| servers.get(id).is_some_and(|server_info| connection.addr == socket_addr(&server_info.addr)));
+                }
+                Err(broadcast::error::RecvError::Closed) => { return }
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl NodeSender for TcpConnectionsManager {
+        async fn send_request(&self, target_id: usize, request: HopMessage) -> BasicResult<()> {
+            self.connection(target_id).await?.send_detached(self.wire.encode_hop(&request)?).await
+        }
+
+        async fn send_batch(&self, target_id: usize, requests: Vec<HopMessage>) -> BasicResult<()> {
+            self.connection(target_id).await?.send_detached(self.wire.encode_hop(&requests)?).await
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::collections::BTreeMap;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::TcpListener;
+        use crate::codec;
+        use crate::domain::{HopMessage, NodeInfo};
+        use crate::node_connector::WireConfig;
+        use crate::node_connector::tcp_connector::{read_frame, socket_addr, Connection, TcpConnectionsManager, TcpNodeListener, ACCEPTED};
+        use crate::{NodeListener, NodeSender};
+        use crate::redis_connector::{NetworkInfo, ServerInfo, ServerUpdate};
+
+        async fn manager_for(addr: &str) -> TcpConnectionsManager {
+            let servers = BTreeMap::from([(0, ServerInfo::new(0, addr.into(), vec![]))]);
+            TcpConnectionsManager::new(NetworkInfo::new(Arc::new(tokio::sync::RwLock::new(servers))), WireConfig::default()).await.unwrap()
+        }
+
+        fn hop(request_id: usize) -> HopMessage {
+            HopMessage::new(request_id, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![])
+        }
+
+        #[tokio::test]
+        async fn hops_arrive_at_the_listener() {
+            let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            let addr = format!("tcp://127.0.0.1:{}", port);
+            let mut listener = TcpNodeListener::new(&addr, WireConfig::default()).await.unwrap();
+            let manager = manager_for(&addr).await;
+            for request_id in 1..=3 {
+                let sent = tokio::task::spawn({
+                    let manager = manager.clone();
+                    async move { manager.send_request(0, hop(request_id)).await }
+                });
+                assert_eq!(listener.get_new_request().await.unwrap().request_id, request_id);
+                sent.await.unwrap().unwrap();
+            }
+            assert_eq!(socket_addr("10.0.0.1:5555"), "10.0.0.1:5555");
+        }
+
+        #[tokio::test]
+        async fn broken_connections_are_reopened() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            // Takes one frame per connection, then hangs up.
+            let received = tokio::task::spawn(async move {
+                let mut received = vec![];
+                for _ in 0..2 {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let frame = read_frame(&mut stream).await.unwrap().unwrap();
+                    received.push(codec::decode::<HopMessage>(&frame).unwrap().request_id);
+                    stream.write_u8(ACCEPTED).await.unwrap();
+                }
+                received
+            });
+            let manager = manager_for(&addr).await;
+            manager.send_request(0, hop(1)).await.unwrap();
+            manager.send_request(0, hop(2)).await.unwrap();
+            assert_eq!(received.await.unwrap(), vec![1, 2]);
+        }
+
+        #[tokio::test]
+        async fn silent_peers_are_reconnected_to() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            // Never acknowledges on the first connection.
+            let received = tokio::task::spawn(async move {
+                let (mut silent, _) = listener.accept().await.unwrap();
+                read_frame(&mut silent).await.unwrap().unwrap();
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let frame = read_frame(&mut stream).await.unwrap().unwrap();
+                stream.write_u8(ACCEPTED).await.unwrap();
+                codec::decode::<HopMessage>(&frame).unwrap().request_id
+            });
+            let connection = Connection { ack_timeout: Duration::from_millis(200), ..Connection::new(&addr) };
+            connection.send(&WireConfig::default().encode_hop(&hop(3)).unwrap()).await.unwrap();
+            assert_eq!(received.await.unwrap(), 3);
+        }
+
+        #[tokio::test]
+        async fn servers_are_followed_when_they_move() {
+            let mut listeners = vec![];
+            for _ in 0..2 {
+                let addr = format!("tcp://{}", std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+                listeners.push((TcpNodeListener::new(&addr, WireConfig::default()).await.unwrap(), addr));
+            }
+            let network_info = NetworkInfo::new(Arc::new(tokio::sync::RwLock::new(BTreeMap::new())));
+            let manager = TcpConnectionsManager::new(network_info.clone(), WireConfig::default()).await.unwrap();
+            assert!(manager.send_request(0, hop(1)).await.is_err());
+
+            for (request_id, (listener, addr)) in listeners.iter_mut().enumerate() {
+                network_info.apply(ServerUpdate::Registered(ServerInfo::new(0, addr.clone().into(), vec![]))).await;
+                let sent = tokio::task::spawn({
+                    let manager = manager.clone();
+                    async move { manager.send_request(0, hop(request_id)).await }
+                });
+                assert_eq!(listener.get_new_request().await.unwrap().request_id, request_id);
+                sent.await.unwrap().unwrap();
+            }
+
+            network_info.apply(ServerUpdate::Removed { removed: 0 }).await;
+            assert!(manager.send_request(0, hop(3)).await.is_err());
+        }
+    }
+}
+
+/// Transport over gRPC, see `proto/pathfinder.proto`. Each server runs a `Node` service: other
+/// servers forward hops with unary calls, clients stream requests in, and result collectors
+/// subscribe to the stream of results. Requests and results are the typed messages of
+/// [`crate::protocol`], so services in other languages can take part.
+#[cfg(feature = "grpc")]
+#[allow(clippy::result_large_err)]
+pub(crate) mod grpc_connector {
+    use std::collections::BTreeMap;
+    use std::pin::Pin;
+    use std::sync::{Arc, Weak};
+    use std::time::Duration;
+    use futures_util::{Stream, StreamExt};
+    use prost::Message;
+    use tokio::net::TcpListener;
+    use tokio::sync::{broadcast, mpsc};
+    use tonic::{Request, Response, Status, Streaming};
+    use tonic::transport::{Channel, Endpoint};
+    use crate::node_connector::{BasicResult, PendingHops, WireConfig};
+    use crate::{ConnectionError, NodeListener, NodeSender, ResultReplier};
+    use crate::domain::{HopMessage, InboundMessage, RouteResult};
+    use crate::protocol::{Ack, PathReply, PathRequest, RepliesRequest};
+    use crate::protocol::node_client::NodeClient;
+    use crate::protocol::node_server::{Node, NodeServer};
+    use crate::redis_connector::{NetworkInfo, ServerUpdate};
+    use crate::signing::ClusterSecret;
+
+    /// Requests received but not taken by the server yet. Forwarding servers and ingesting
+    /// clients wait while it is full.
+    const REQUEST_QUEUE_SIZE: usize = 1024;
+
+    struct NodeService {
+        requests: mpsc::Sender<HopMessage>,
+        replies: async_channel::Receiver<PathReply>,
+        wire: WireConfig,
+    }
+
+    /// Signs `request` with `secret`: its signature is the tag of the request encoded without one.
+    fn sign(mut request: PathRequest, secret: Option<&ClusterSecret>) -> BasicResult<PathRequest> {
+        if let Some(secret) = secret {
+            request.signature = vec![];
+            request.signature = secret.tag(&request.encode_to_vec())?;
+        }
+        Ok(request)
+    }
+
+    /// Takes the signature off `request`, which has to be valid if there is a `secret`.
+    fn authenticate(request: &mut PathRequest, secret: Option<&ClusterSecret>) -> Result<(), Status> {
+        let signature = std::mem::take(&mut request.signature);
+        let secret = match secret {
+            Some(secret) => { secret }
+            None => { return Ok(()) }
+        };
+        match secret.verify(&request.encode_to_vec(), &signature) {
+            Ok(true) => { Ok(()) }
+            Ok(false) => { Err(Status::unauthenticated("Request isn't signed with the cluster secret")) }
+            Err(err) => { Err(Status::internal(format!("Cannot verify request, details: {}", err))) }
+        }
+    }
+
+    impl NodeService {
+        async fn queue(&self, mut request: PathRequest) -> Result<(), Status> {
+            authenticate(&mut request, self.wire.secret())?;
+            let hop = InboundMessage::from_protobuf(request)
+                .map_err(|err| Status::invalid_argument(format!("Cannot deserialize request, details: {}", err)))?;
+            self.requests.send(HopMessage::from(hop)).await.map_err(|_| Status::unavailable("Server is shutting down"))
+        }
+    }
+
+    #[tonic::async_trait]
+    impl Node for NodeService {
+        async fn ingest(&self, request: Request<Streaming<PathRequest>>) -> Result<Response<Ack>, Status> {
+            let mut requests = request.into_inner();
+            while let Some(request) = requests.message().await? {
+                self.queue(request).await?;
+            }
+            Ok(Response::new(Ack {}))
+        }
+
+        async fn forward(&self, request: Request<PathRequest>) -> Result<Response<Ack>, Status> {
+            self.queue(request.into_inner()).await?;
+            Ok(Response::new(Ack {}))
+        }
+
+        type RepliesStream = Pin<Box<dyn Stream<Item = Result<PathReply, Status>> + Send + Sync>>;
+
+        async fn replies(&self, _request: Request<RepliesRequest>) -> Result<Response<Self::RepliesStream>, Status> {
+            let replies = self.replies.clone().map(Ok);
+            Ok(Response::new(Box::pin(replies)))
+        }
+    }
+
+    /// Serves the `Node` service on `listener`, returning the requests it receives and the sink of
+    /// the results it streams to collectors.
+    pub(crate) fn serve(listener: TcpListener, spool_size: usize, wire: WireConfig) -> (GrpcNodeListener, GrpcReplier) {
+        let (request_sender, requests) = mpsc::channel(REQUEST_QUEUE_SIZE);
+        let (replies, spooled) = async_channel::bounded(spool_size);
+        let service = NodeService {
+            requests: request_sender,
+            replies: spooled.clone(),
+            wire,
+        };
+        let incoming = futures_util::stream::unfold(listener, |listener| async move {
+            let socket = listener.accept().await.map(|(socket, _)| socket);
+            Some((socket, listener))
+        });
+        tokio::task::spawn(async move {
+            if let Err(err) = tonic::transport::Server::builder().add_service(NodeServer::new(service)).serve_with_incoming(incoming).await {
+                log::error!("gRPC server stopped, details: {}", err);
+            }
+        });
+        (GrpcNodeListener { requests, pending: PendingHops::default() }, GrpcReplier { replies, spooled })
+    }
+
+    pub(crate) struct GrpcNodeListener {
+        requests: mpsc::Receiver<HopMessage>,
+        pending: PendingHops,
+    }
+
+    #[async_trait::async_trait]
+    impl NodeListener for GrpcNodeListener {
+        async fn get_new_request(&mut self) -> Result<HopMessage, ConnectionError> {
+            self.pending.read_ahead(|| self.requests.try_recv().ok());
+            match self.pending.pop_hop() {
+                Some(hop) => { Ok(hop) }
+                None => { self.requests.recv().await.ok_or(ConnectionError::NoRequest) }
+            }
+        }
+    }
+
+    /// Queues results for the collectors subscribed to the reply stream. Results are spooled while
+    /// no collector reads them, up to a bound past which the oldest are dropped. A result taken by
+    /// a collector which disconnects before receiving it is lost.
+    #[derive(Clone)]
+    pub(crate) struct GrpcReplier {
+        replies: async_channel::Sender<PathReply>,
+        spooled: async_channel::Receiver<PathReply>,
+    }
+
+    #[async_trait::async_trait]
+    impl ResultReplier for GrpcReplier {
+        async fn send(&self, reply: &RouteResult) -> BasicResult<()> {
+            let mut reply = PathReply::from(reply);
+            loop {
+                match self.replies.try_send(reply) {
+                    Ok(()) => { return Ok(()) }
+                    Err(async_channel::TrySendError::Full(back)) => {
+                        if self.spooled.try_recv().is_ok() {
+                            log::warn!("Result spool is full, dropping the oldest result");
+                        }
+                        reply = back;
+                    }
+                    Err(async_channel::TrySendError::Closed(_)) => { return Err("Reply stream is closed".into()) }
+                }
+            }
+        }
+
+        /// Waits for collectors to take the spooled results.
+        async fn flush(&self) -> BasicResult<()> {
+            while !self.replies.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok(())
+        }
+    }
+
+    /// Addresses in `server_info` are shared with ZMQ mode, a `tcp://` address is called over plain HTTP/2.
+    fn endpoint(addr: &str) -> String {
+        match addr.strip_prefix("tcp://") {
+            Some(host) => { format!("http://{}", host) }
+            None => { addr.to_string() }
+        }
+    }
+
+    /// Clients of the servers, with the address each was connected to.
+    type Clients = std::sync::Mutex<BTreeMap<usize, (Box<str>, NodeClient<Channel>)>>;
+
+    /// Clients of the servers of `network_info`, following them when they move or leave.
+    #[derive(Clone)]
+    pub(crate) struct GrpcConnectionsManager {
+        node_connections: Arc<Clients>,
+        network_info: NetworkInfo,
+        wire: WireConfig,
+    }
+
+    impl GrpcConnectionsManager {
+        /// Connects lazily, so servers started later are reached once they are up.
+        pub(crate) async fn new(network_info: NetworkInfo, wire: WireConfig) -> BasicResult<Self> {
+            let node_connections = Arc::new(Clients::default());
+            tokio::task::spawn(follow(Arc::downgrade(&node_connections), network_info.clone(), network_info.subscribe()));
+            Ok(GrpcConnectionsManager {
+                node_connections,
+                network_info,
+                wire,
+            })
+        }
+
+        /// Client of the server at the address it is registered with now.
+        async fn client(&self, target_id: usize) -> BasicResult<NodeClient<Channel>> {
+            let server_info = self.network_info.get_server(target_id).await.ok_or(ConnectionError::TargetDoesNotExist(target_id))?;
+            let mut clients = self.node_connections.lock().unwrap();
+            if let Some((_, client)) = clients.get(&target_id).filter(|(addr, _)| *addr == server_info.addr) {
+                return Ok(client.clone());
+            }
+            let client = NodeClient::new(Endpoint::from_shared(endpoint(&server_info.addr))?.connect_lazy());
+            clients.insert(target_id, (server_info.addr.clone(), client.clone()));
+            Ok(client)
+        }
+    }
+
+    /// Drops the clients of servers which left or moved until the manager is dropped. Those
+    /// which moved are connected to at their new address when next sent to.
+    async fn follow(clients: Weak<Clients>, network_info: NetworkInfo, mut updates: broadcast::Receiver<ServerUpdate>) {
+        loop {
+            let update = updates.recv().await;
+            let clients = match clients.upgrade() {
+                Some(clients) => { clients }
+                None => { return }
+            };
+            match update {
+                Ok(ServerUpdate::Registered(server_info)) => {
+                    let mut clients = clients.lock().unwrap();
+                    if clients.get(&server_info.id).is_some_and(|(addr, _)| *addr != server_info.addr) {
+                        log::info!("Server {} moved to {}, reconnecting", server_info.id, server_info.addr);
+                        clients.remove(&server_info.id);
+                    }
+                }
+                Ok(ServerUpdate::Removed { removed }) => {
+                    if clients.lock().unwrap().remove(&removed).is_some() {
+                        log::info!("Server {} left, disconnecting", removed);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    log::warn!("Missed {} server updates, dropping the clients of unregistered servers", missed);
+                    let servers = network_info.get_servers().await;
+                    clients.lock().unwrap().retain(|id, (addr, _)| servers.get(id).is_some_and(|server_info| *addr == server_info.addr));
+                }
+                Err(broadcast::error::RecvError::Closed) => { return }
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl NodeSender for GrpcConnectionsManager {
+        async fn send_request(&self, target_id: usize, request: HopMessage) -> BasicResult<()> {
+            let mut client = self.client(target_id).await?;
+            client.forward(sign(request.to_protobuf(), self.wire.secret())?).await?;
+            Ok(())
+        }
+
+        /// Streams the requests over a single `Ingest` call.
+        async fn send_batch(&self, target_id: usize, requests: Vec<HopMessage>) -> BasicResult<()> {
+            let mut client = self.client(target_id).await?;
+            let requests = requests.iter()
+                .map(|request| sign(request.to_protobuf(), self.wire.secret()))
+                .collect::<BasicResult<Vec<PathRequest>>>()?;
+            client.ingest(futures_util::stream::iter(requests)).await?;
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::collections::BTreeMap;
+        use std::sync::Arc;
+        use futures_util::StreamExt;
+        use tokio::net::TcpListener;
+        use crate::domain::{HopMessage, NodeInfo, RouteResult, RouteStatus};
+        use crate::node_connector::WireConfig;
+        use crate::node_connector::grpc_connector::{authenticate, endpoint, serve, sign, GrpcConnectionsManager};
+        use crate::protocol::node_client::NodeClient;
+        use crate::protocol::{path_request, RepliesRequest};
+        use crate::{NodeListener, NodeSender, ResultReplier};
+        use crate::redis_connector::{NetworkInfo, ServerInfo, ServerUpdate};
+        use crate::signing::ClusterSecret;
+
+        #[test]
+        fn zmq_addresses_are_called_over_http() {
+            assert_eq!(endpoint("tcp://10.0.0.1:5555"), "http://10.0.0.1:5555");
+            assert_eq!(endpoint("https://node-1:443"), "https://node-1:443");
+        }
+
+        #[test]
+        fn signatures_cover_the_request() {
+            let secret = ClusterSecret::new(b"cluster secret");
+            let request = HopMessage::new(1, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![]).to_protobuf();
+            let mut signed = sign(request.clone(), Some(&secret)).unwrap();
+            assert!(!signed.signature.is_empty());
+            authenticate(&mut signed.clone(), Some(&secret)).unwrap();
+            assert!(authenticate(&mut request.clone(), Some(&secret)).is_err());
+            if let Some(path_request::Body::Hop(hop)) = signed.body.as_mut() {
+                hop.cost = 0;
+                hop.request_id = 2;
+            }
+            assert!(authenticate(&mut signed, Some(&secret)).is_err());
+            // Servers without a secret accept anything.
+            authenticate(&mut request.clone(), None).unwrap();
+        }
+
+        #[tokio::test]
+        async fn requests_and_results_go_through_the_node_service() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = format!("tcp://{}", listener.local_addr().unwrap());
+            let (mut node_listener, replier) = serve(listener, 2, WireConfig::default());
+
+            let servers = BTreeMap::from([(0, ServerInfo::new(0, addr.clone().into(), vec![]))]);
+            let manager = GrpcConnectionsManager::new(NetworkInfo::new(Arc::new(tokio::sync::RwLock::new(servers))), WireConfig::default()).await.unwrap();
+            let hop = |request_id| HopMessage::new(request_id, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![]);
+            manager.send_request(0, hop(1)).await.unwrap();
+            assert_eq!(node_listener.get_new_request().await.unwrap().request_id, 1);
+
+            let mut client = NodeClient::connect(endpoint(&addr)).await.unwrap();
+            let requests = [hop(2), hop(3)].map(|hop| hop.to_protobuf());
+            client.ingest(futures_util::stream::iter(requests)).await.unwrap();
+            assert_eq!(node_listener.get_new_request().await.unwrap().request_id, 2);
+            assert_eq!(node_listener.get_new_request().await.unwrap().request_id, 3);
+
+            // Results sent before a collector subscribes are spooled, dropping the oldest past the bound.
+            let result = |request_id| RouteResult { request_id, source: NodeInfo(1, 1), target: NodeInfo(2, 1), path: vec![], cost: 0, status: RouteStatus::Found, weight_scale: Default::default(), reason: None, failure: None };
+            for request_id in 1..=3 {
+                replier.send(&result(request_id)).await.unwrap();
+            }
+            let mut replies = client.replies(RepliesRequest {}).await.unwrap().into_inner();
+            for expected in [2, 3] {
+                let reply = replies.next().await.unwrap().unwrap();
+                assert_eq!(reply.request_id, expected);
+            }
+        }
+
+        #[tokio::test]
+        async fn servers_registered_later_are_reached() {
+            let network_info = NetworkInfo::new(Arc::new(tokio::sync::RwLock::new(BTreeMap::new())));
+            let manager = GrpcConnectionsManager::new(network_info.clone(), WireConfig::default()).await.unwrap();
+            let hop = HopMessage::new(1, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![]);
+            assert!(manager.send_request(0, hop.clone()).await.is_err());
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = format!("tcp://{}", listener.local_addr().unwrap());
+            let (mut node_listener, _replier) = serve(listener, 2, WireConfig::default());
+            network_info.apply(ServerUpdate::Registered(ServerInfo::new(0, addr.into(), vec![]))).await;
+            manager.send_request(0, hop).await.unwrap();
+            assert_eq!(node_listener.get_new_request().await.unwrap().request_id, 1);
+
+            network_info.apply(ServerUpdate::Removed { removed: 0 }).await;
+            assert!(manager.send_batch(0, vec![]).await.is_err());
         }
     }
 }
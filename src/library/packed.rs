@@ -0,0 +1,250 @@
+//! Compact binary region format, loaded into memory like regions read from CSV but without parsing
+//! text or validating records again.
+//!
+//! After the magic `PFREGION`, all fields are unsigned LEB128 varints:
+//! - header: format version, region, weight scale, node count, vertex count
+//! - nodes sorted by id: id less the previous id, region, x, y
+//! - vertices sorted by id: id less the previous id, a, b, weight, access, region bit count and
+//!   the region bits, eight to a byte
+//!
+//! Connections aren't stored, they are rebuilt from the vertices.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Write};
+use bitvec::vec::BitVec;
+use crate::graph::{Access, Graph, Node, NodeIdx, RegionIdx, Vertex, WeightScale};
+
+const MAGIC: &[u8; 8] = b"PFREGION";
+/// Bumped on every change to the layout. Files of other versions are refused rather than guessed at.
+pub(crate) const VERSION: u64 = 1;
+/// Fields of a node record and of a vertex record, which are at least a byte each.
+const NODE_FIELDS: usize = 4;
+const VERTEX_FIELDS: usize = 6;
+
+/// Name of the file of a region, next to its CSV files.
+pub(crate) fn file_name(id: RegionIdx) -> String {
+    format!("region_{}.pfr", id)
+}
+
+fn invalid(reason: String) -> Error {
+    Error::new(ErrorKind::InvalidData, reason)
+}
+
+fn put(out: &mut impl Write, mut value: u64) -> std::io::Result<()> {
+    let mut bytes = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes[len] = byte;
+            len += 1;
+            break;
+        }
+        bytes[len] = byte | 0x80;
+        len += 1;
+    }
+    out.write_all(&bytes[..len])
+}
+
+struct Reader<'a> {
+    raw: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn next(&mut self) -> std::io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.raw.get(self.pos).ok_or_else(|| invalid("Truncated region file".to_string()))?;
+            self.pos += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid(format!("Malformed number at byte {}", self.pos)))
+    }
+
+    fn remaining(&self) -> usize {
+        self.raw.len() - self.pos
+    }
+
+    fn bytes(&mut self, len: usize) -> std::io::Result<&[u8]> {
+        let bytes = self.pos.checked_add(len)
+            .and_then(|end| self.raw.get(self.pos..end))
+            .ok_or_else(|| invalid("Truncated region file".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+/// Id of the record following the one of id `previous`, stored as the difference.
+fn next_id(previous: usize, delta: u64) -> std::io::Result<usize> {
+    usize::try_from(delta).ok()
+        .and_then(|delta| previous.checked_add(delta))
+        .ok_or_else(|| invalid(format!("Id {} + {} out of range", previous, delta)))
+}
+
+/// Writes `graph` in the packed format. Weights are stored as scaled with `scale`.
+pub(crate) fn write(graph: &Graph, scale: WeightScale, out: &mut impl Write) -> std::io::Result<()> {
+    let mut nodes: Vec<_> = graph.nodes().collect();
+    nodes.sort_by_key(|node| node.id);
+    let mut vertices: Vec<_> = graph.vertices().collect();
+    vertices.sort_by_key(|vertex| vertex.id);
+
+    out.write_all(MAGIC)?;
+    for value in [VERSION, graph.region_idx as u64, scale.0, nodes.len() as u64, vertices.len() as u64] {
+        put(out, value)?;
+    }
+    let mut previous = 0;
+    for node in nodes.iter() {
+        for value in [(node.id - previous) as u64, node.region as u64, node.cord_x, node.cord_y] {
+            put(out, value)?;
+        }
+        previous = node.id;
+    }
+    let mut previous = 0;
+    for vertex in vertices.iter() {
+        let bit_count = vertex.region_bits.len();
+        for value in [(vertex.id - previous) as u64, vertex.a as u64, vertex.b as u64, vertex.weight, vertex.access.bits() as u64, bit_count as u64] {
+            put(out, value)?;
+        }
+        let mut bits = vec![0u8; bit_count.div_ceil(8)];
+        for idx in 0..bit_count {
+            if vertex.region_bits.get(idx) == Some(true) {
+                bits[idx / 8] |= 1 << (idx % 8);
+            }
+        }
+        out.write_all(&bits)?;
+        previous = vertex.id;
+    }
+    Ok(())
+}
+
+/// Reads `expected`, written by [`write`], refusing files of other regions, format versions or weight scales.
+pub(crate) fn read(raw: &[u8], expected: RegionIdx, scale: WeightScale) -> std::io::Result<Graph> {
+    if raw.len() < MAGIC.len() || &raw[..MAGIC.len()] != MAGIC {
+        return Err(invalid("Not a packed region file".to_string()));
+    }
+    let mut reader = Reader { raw, pos: MAGIC.len() };
+    let version = reader.next()?;
+    if version != VERSION {
+        return Err(invalid(format!("Packed region format version {} is not supported, expected {}", version, VERSION)));
+    }
+    let region = reader.next()? as RegionIdx;
+    if region != expected {
+        return Err(invalid(format!("File of region {} holds region {}", expected, region)));
+    }
+    let file_scale = reader.next()?;
+    if file_scale != scale.0 {
+        return Err(invalid(format!("Region {} was packed with weight scale {}, the server runs with {}", region, file_scale, scale.0)));
+    }
+    let (node_count, vertex_count) = (reader.next()? as usize, reader.next()? as usize);
+    // Every record takes a byte per field at least, so counts the rest of the file can't hold are
+    // refused before room is reserved for them.
+    let least_bytes = node_count.checked_mul(NODE_FIELDS).zip(vertex_count.checked_mul(VERTEX_FIELDS))
+        .and_then(|(nodes, vertices)| nodes.checked_add(vertices));
+    if least_bytes.is_none_or(|least_bytes| least_bytes > reader.remaining()) {
+        return Err(invalid(format!("{} nodes and {} vertices don't fit the {} bytes left of region {}", node_count, vertex_count, reader.remaining(), region)));
+    }
+
+    let mut nodes = HashMap::with_capacity(node_count);
+    let mut id = 0;
+    for _ in 0..node_count {
+        id = next_id(id, reader.next()?)?;
+        let (node_region, x, y) = (reader.next()? as RegionIdx, reader.next()?, reader.next()?);
+        nodes.insert(id, Node::new(vec![], id, node_region, x, y));
+    }
+    let mut vertices = HashMap::with_capacity(vertex_count);
+    let mut id = 0;
+    for _ in 0..vertex_count {
+        id = next_id(id, reader.next()?)?;
+        let (a, b, weight) = (reader.next()? as NodeIdx, reader.next()? as NodeIdx, reader.next()?);
+        let access = Access::from_bits(reader.next()? as u8);
+        let bit_count = reader.next()? as usize;
+        let bits = reader.bytes(bit_count.div_ceil(8))?;
+        let region_bits: BitVec = (0..bit_count).map(|idx| bits[idx / 8] & (1 << (idx % 8)) != 0).collect();
+        for node in [a, b] {
+            if let Some(node) = nodes.get_mut(&node) {
+                node.connections.push(id);
+            }
+        }
+        vertices.insert(id, Vertex { a, b, weight, id, region_bits, access });
+    }
+    if reader.pos != raw.len() {
+        return Err(invalid(format!("{} unexpected bytes after region {}", raw.len() - reader.pos, region)));
+    }
+    Ok(Graph::new(nodes, vertices, region))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fixtures::generate_sample;
+    use crate::graph::WeightScale;
+    use crate::graph_provider::GraphProvider;
+    use crate::graph_provider::mock::MockGraphProvider;
+    use crate::packed::{put, read, write, VERSION};
+
+    #[tokio::test]
+    async fn packed_regions_match_loaded_ones() {
+        let scale = WeightScale(100);
+        let dir = generate_sample("two_regions");
+        let loaded = MockGraphProvider::new(dir.path().to_path_buf()).with_weight_scale(scale);
+        for region in [1, 2] {
+            let expected = loaded.get_region(region).await.unwrap();
+            let mut raw = vec![];
+            write(&expected, scale, &mut raw).unwrap();
+            let graph = read(&raw, region, scale).unwrap();
+            assert_eq!((graph.region_idx, graph.node_count()), (expected.region_idx, expected.node_count()));
+            for node in expected.nodes() {
+                let packed = graph.get_node(node.id).unwrap();
+                assert_eq!((packed.region, packed.coordinates()), (node.region, node.coordinates()));
+                let mut connections = packed.connections.clone();
+                connections.sort();
+                let mut expected_connections = node.connections.clone();
+                expected_connections.sort();
+                assert_eq!(connections, expected_connections);
+            }
+            for vertex in expected.vertices() {
+                let packed = graph.get_vertex(vertex.id).unwrap();
+                assert_eq!((packed.a, packed.b, packed.weight, packed.access), (vertex.a, vertex.b, vertex.weight, vertex.access));
+                let bits: Vec<_> = (0..vertex.region_bits.len()).map(|idx| packed.region_bits.get(idx)).collect();
+                let expected_bits: Vec<_> = (0..vertex.region_bits.len()).map(|idx| vertex.region_bits.get(idx)).collect();
+                assert_eq!(bits, expected_bits);
+            }
+
+            // Files of other scales or format versions, or damaged ones, are refused.
+            assert!(read(&raw, region, WeightScale(1)).is_err());
+            assert!(read(&raw, region + 1, scale).is_err());
+            assert!(read(&raw[..raw.len() - 1], region, scale).is_err());
+            let mut newer = raw.clone();
+            newer[8] = VERSION as u8 + 1;
+            assert!(read(&newer, region, scale).unwrap_err().to_string().contains("version"));
+        }
+    }
+
+    #[test]
+    fn damaged_headers_are_refused() {
+        let header = |counts: &[u64]| {
+            let mut raw = b"PFREGION".to_vec();
+            for value in [VERSION, 1, 1].iter().chain(counts) {
+                put(&mut raw, *value).unwrap();
+            }
+            raw
+        };
+        // Counts far beyond what the file holds.
+        let oversized = read(&header(&[u64::MAX, u64::MAX]), 1, WeightScale(1)).unwrap_err();
+        assert!(oversized.to_string().contains("don't fit"));
+        assert!(read(&header(&[1 << 40, 0]), 1, WeightScale(1)).is_err());
+        // Truncated within the header.
+        let raw = header(&[2, 1]);
+        assert!(read(&raw[..raw.len() - 1], 1, WeightScale(1)).unwrap_err().to_string().contains("Truncated"));
+        // Ids overflowing.
+        let mut raw = header(&[2, 0]);
+        for value in [u64::MAX, 1, 0, 0, u64::MAX, 1, 0, 0] {
+            put(&mut raw, value).unwrap();
+        }
+        assert!(read(&raw, 1, WeightScale(1)).unwrap_err().to_string().contains("out of range"));
+    }
+}
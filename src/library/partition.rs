@@ -0,0 +1,361 @@
+//! Splits a flat road network into regions and writes the per-region files read by the graph
+//! providers, region bits included.
+//!
+//! Regions are found by multilevel k-way partitioning: the graph is coarsened by merging nodes
+//! along heavy edges, the coarsest graph is split by growing regions from distant seeds, and the
+//! split is projected back level by level, moving boundary nodes wherever that cuts fewer edges.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use crate::fixtures::{FixtureDefinition, FixtureEdge, FixtureGroup, FixtureManifest, FixtureNode};
+use crate::graph::{NodeIdx, Profile, RegionIdx, VertexIdx};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Coarsening stops once a graph has at most this many nodes per region.
+const COARSEST_NODES_PER_REGION: usize = 20;
+/// Refinement passes over the boundary at every level.
+const REFINEMENT_PASSES: usize = 8;
+
+/// Node of the unpartitioned network, `id,cord_x,cord_y` in CSV.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlatNode {
+    pub id: NodeIdx,
+    pub cord_x: u64,
+    pub cord_y: u64,
+}
+
+/// Edge of the unpartitioned network, `id,a,b,weight[,access]` in CSV.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlatEdge {
+    pub id: VertexIdx,
+    pub a: NodeIdx,
+    pub b: NodeIdx,
+    pub weight: f64,
+    /// Transport modes separated by `|`, all of them if absent.
+    #[serde(default)]
+    pub access: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PartitionConfig {
+    pub regions: usize,
+    /// Regions may hold this share more nodes than an even split would give them.
+    pub imbalance: f64,
+}
+
+impl Default for PartitionConfig {
+    fn default() -> Self {
+        Self {
+            regions: 2,
+            imbalance: 0.05,
+        }
+    }
+}
+
+/// Graph at one level of coarsening. Node weights count the original nodes merged into a node,
+/// edge weights the original edges between them.
+struct Level {
+    node_weights: Vec<u64>,
+    neighbours: Vec<Vec<(usize, u64)>>,
+}
+
+impl Level {
+    fn len(&self) -> usize {
+        self.node_weights.len()
+    }
+
+    /// Merges every node with its unmatched neighbour along the heaviest edge. Returns the coarser
+    /// graph and the coarse node of every node of this one.
+    fn coarsen(&self) -> (Level, Vec<usize>) {
+        let mut order: Vec<usize> = (0..self.len()).collect();
+        // Matching nodes with few neighbours first leaves fewer of them unmatched.
+        order.sort_by_key(|node| self.neighbours[*node].len());
+        let mut coarse: Vec<Option<usize>> = vec![None; self.len()];
+        let mut node_weights = vec![];
+        for node in order {
+            if coarse[node].is_some() {
+                continue;
+            }
+            let mate = self.neighbours[node].iter()
+                .filter(|(next, _)| coarse[*next].is_none() && *next != node)
+                .max_by_key(|(next, weight)| (*weight, std::cmp::Reverse(self.node_weights[*next])))
+                .map(|(next, _)| *next);
+            coarse[node] = Some(node_weights.len());
+            let mut weight = self.node_weights[node];
+            if let Some(mate) = mate {
+                coarse[mate] = Some(node_weights.len());
+                weight += self.node_weights[mate];
+            }
+            node_weights.push(weight);
+        }
+        let coarse: Vec<usize> = coarse.into_iter().map(|node| node.expect("Every node is matched")).collect();
+
+        let mut merged: Vec<HashMap<usize, u64>> = vec![HashMap::new(); node_weights.len()];
+        for (node, neighbours) in self.neighbours.iter().enumerate() {
+            for (next, weight) in neighbours {
+                if coarse[node] != coarse[*next] {
+                    *merged[coarse[node]].entry(coarse[*next]).or_default() += weight;
+                }
+            }
+        }
+        let neighbours = merged.into_iter()
+            .map(|neighbours| {
+                let mut neighbours: Vec<(usize, u64)> = neighbours.into_iter().collect();
+                neighbours.sort();
+                neighbours
+            })
+            .collect();
+        (Level { node_weights, neighbours }, coarse)
+    }
+
+    /// Grows the regions one after another from the unassigned node furthest from those assigned so
+    /// far, always taking the frontier node most connected to the growing region.
+    fn initial_partition(&self, regions: usize) -> Vec<usize> {
+        let total: u64 = self.node_weights.iter().sum();
+        let mut parts = vec![usize::MAX; self.len()];
+        for part in 0..regions - 1 {
+            let target = total * (part as u64 + 1) / regions as u64;
+            let mut assigned: u64 = self.node_weights.iter().zip(parts.iter()).filter(|(_, p)| **p != usize::MAX).map(|(w, _)| *w).sum();
+            let mut connections: HashMap<usize, u64> = HashMap::new();
+            while assigned < target {
+                let next = match connections.iter().max_by_key(|(node, weight)| (**weight, std::cmp::Reverse(**node))) {
+                    Some((node, _)) => { *node }
+                    None => {
+                        match self.furthest_unassigned(&parts) {
+                            Some(seed) => { seed }
+                            None => { break }
+                        }
+                    }
+                };
+                connections.remove(&next);
+                parts[next] = part;
+                assigned += self.node_weights[next];
+                for (neighbour, weight) in self.neighbours[next].iter() {
+                    if parts[*neighbour] == usize::MAX {
+                        *connections.entry(*neighbour).or_default() += weight;
+                    }
+                }
+            }
+        }
+        for part in parts.iter_mut().filter(|part| **part == usize::MAX) {
+            *part = regions - 1;
+        }
+        parts
+    }
+
+    /// Unassigned node with the most hops to any assigned node, the first unassigned one if nothing
+    /// is assigned yet or no unassigned node is connected to the assigned ones.
+    fn furthest_unassigned(&self, parts: &[usize]) -> Option<usize> {
+        let mut hops = vec![usize::MAX; self.len()];
+        let mut queue: VecDeque<usize> = (0..self.len()).filter(|node| parts[*node] != usize::MAX).collect();
+        for node in queue.iter() {
+            hops[*node] = 0;
+        }
+        while let Some(node) = queue.pop_front() {
+            for (next, _) in self.neighbours[node].iter() {
+                if hops[*next] == usize::MAX {
+                    hops[*next] = hops[node] + 1;
+                    queue.push_back(*next);
+                }
+            }
+        }
+        let unassigned = (0..self.len()).filter(|node| parts[*node] == usize::MAX);
+        match unassigned.clone().find(|node| hops[*node] == usize::MAX) {
+            Some(unreached) => { Some(unreached) }
+            None => { unassigned.max_by_key(|node| (hops[*node], std::cmp::Reverse(*node))) }
+        }
+    }
+
+    /// Moves boundary nodes to the neighbouring region they are most connected to, as long as that
+    /// cuts fewer edges (or as many, bringing the regions closer to balance) and keeps regions
+    /// within `max_weight`. Overweight regions first give away their least connected nodes.
+    fn refine(&self, parts: &mut [usize], regions: usize, max_weight: u64) {
+        let mut weights = vec![0; regions];
+        for (node, part) in parts.iter().enumerate() {
+            weights[*part] += self.node_weights[node];
+        }
+        for _ in 0..REFINEMENT_PASSES {
+            let mut moved = false;
+            for node in 0..self.len() {
+                let own = parts[node];
+                let mut connections = vec![0u64; regions];
+                for (next, weight) in self.neighbours[node].iter() {
+                    connections[parts[*next]] += weight;
+                }
+                let node_weight = self.node_weights[node];
+                let overweight = weights[own] > max_weight;
+                let best = (0..regions)
+                    .filter(|part| *part != own && connections[*part] > 0 && weights[*part] + node_weight <= max_weight)
+                    .max_by_key(|part| (connections[*part], std::cmp::Reverse(weights[*part])));
+                let best = match best {
+                    Some(best) => { best }
+                    None => { continue }
+                };
+                let gain = connections[best] as i64 - connections[own] as i64;
+                let balances = weights[best] + node_weight < weights[own];
+                if gain > 0 || (gain == 0 && balances) || overweight {
+                    // Regions are never emptied.
+                    if weights[own] == node_weight {
+                        continue;
+                    }
+                    parts[node] = best;
+                    weights[own] -= node_weight;
+                    weights[best] += node_weight;
+                    moved = true;
+                }
+            }
+            if !moved {
+                break;
+            }
+        }
+    }
+}
+
+/// Region (`1..=regions`) of every node.
+pub fn partition(nodes: &[FlatNode], edges: &[FlatEdge], config: &PartitionConfig) -> Result<HashMap<NodeIdx, RegionIdx>> {
+    if config.regions == 0 || config.regions > nodes.len() {
+        Err(format!("Cannot split {} nodes into {} regions", nodes.len(), config.regions))?;
+    }
+    let positions: HashMap<NodeIdx, usize> = nodes.iter().enumerate().map(|(pos, node)| (node.id, pos)).collect();
+    let mut neighbours: Vec<HashMap<usize, u64>> = vec![HashMap::new(); nodes.len()];
+    for edge in edges {
+        let a = *positions.get(&edge.a).ok_or(format!("Edge {} connects unknown node {}", edge.id, edge.a))?;
+        let b = *positions.get(&edge.b).ok_or(format!("Edge {} connects unknown node {}", edge.id, edge.b))?;
+        if a != b {
+            *neighbours[a].entry(b).or_default() += 1;
+            *neighbours[b].entry(a).or_default() += 1;
+        }
+    }
+    let finest = Level {
+        node_weights: vec![1; nodes.len()],
+        neighbours: neighbours.into_iter().map(|neighbours| neighbours.into_iter().collect()).collect(),
+    };
+
+    let mut levels = vec![finest];
+    let mut projections = vec![];
+    while levels.last().unwrap().len() > COARSEST_NODES_PER_REGION * config.regions {
+        let (coarser, projection) = levels.last().unwrap().coarsen();
+        // Nothing left to merge, e.g. only isolated nodes.
+        if coarser.len() * 10 > levels.last().unwrap().len() * 9 {
+            break;
+        }
+        levels.push(coarser);
+        projections.push(projection);
+    }
+    log::debug!("Coarsened {} nodes in {} levels down to {}", nodes.len(), levels.len(), levels.last().unwrap().len());
+
+    let max_weight = ((nodes.len() as f64 / config.regions as f64) * (1. + config.imbalance)).ceil() as u64;
+    let coarsest = levels.pop().unwrap();
+    let mut parts = coarsest.initial_partition(config.regions);
+    coarsest.refine(&mut parts, config.regions, max_weight);
+    while let (Some(level), Some(projection)) = (levels.pop(), projections.pop()) {
+        parts = projection.iter().map(|coarse| parts[*coarse]).collect();
+        level.refine(&mut parts, config.regions, max_weight);
+    }
+
+    Ok(nodes.iter().zip(parts).map(|(node, part)| (node.id, part as RegionIdx + 1)).collect())
+}
+
+/// Partitions the network and writes the layout of [`FixtureDefinition::generate`], with regions
+/// spread round robin over `groups` groups.
+pub fn generate(nodes: &[FlatNode], edges: &[FlatEdge], config: &PartitionConfig, groups: usize, dir: &Path) -> Result<FixtureManifest> {
+    let regions = partition(nodes, edges, config)?;
+    let mut fixture_edges = vec![];
+    for edge in edges {
+        let access = match edge.access.as_deref() {
+            None | Some("") => { None }
+            Some(modes) => { Some(modes.split('|').map(|mode| mode.parse::<Profile>()).collect::<std::result::Result<Vec<_>, _>>()?) }
+        };
+        fixture_edges.push(FixtureEdge {
+            id: Some(edge.id),
+            a: edge.a,
+            b: edge.b,
+            weight: edge.weight,
+            access,
+        });
+    }
+    let groups = groups.max(1);
+    let definition = FixtureDefinition {
+        nodes: nodes.iter().map(|node| FixtureNode { id: node.id, x: node.cord_x, y: node.cord_y, region: regions[&node.id] }).collect(),
+        edges: fixture_edges,
+        groups: (1..=groups)
+            .map(|group| FixtureGroup {
+                id: group,
+                regions: (1..=config.regions as RegionIdx).filter(|region| (*region as usize - 1) % groups == group - 1).collect(),
+            })
+            .collect(),
+        super_regions: vec![],
+    };
+    definition.generate(dir)
+}
+
+/// Reads headerless `nodes` and `edges` CSV files.
+pub fn read_csv(nodes: &Path, edges: &Path) -> Result<(Vec<FlatNode>, Vec<FlatEdge>)> {
+    let nodes = csv::ReaderBuilder::new().has_headers(false).from_path(nodes)?
+        .deserialize()
+        .collect::<std::result::Result<Vec<FlatNode>, _>>()?;
+    let edges = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_path(edges)?
+        .deserialize()
+        .collect::<std::result::Result<Vec<FlatEdge>, _>>()?;
+    Ok((nodes, edges))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::fixtures::TempDir;
+    use crate::graph_provider::GraphProvider;
+    use crate::graph_provider::mock::MockGraphProvider;
+    use crate::partition::{generate, partition, FlatEdge, FlatNode, PartitionConfig};
+
+    fn grid(size: usize) -> (Vec<FlatNode>, Vec<FlatEdge>) {
+        let nodes: Vec<FlatNode> = (0..size * size)
+            .map(|idx| FlatNode { id: idx + 1, cord_x: (idx % size) as u64, cord_y: (idx / size) as u64 })
+            .collect();
+        let mut edges = vec![];
+        for idx in 0..size * size {
+            let (x, y) = (idx % size, idx / size);
+            if x + 1 < size {
+                edges.push(FlatEdge { id: edges.len(), a: idx + 1, b: idx + 2, weight: 1., access: None });
+            }
+            if y + 1 < size {
+                edges.push(FlatEdge { id: edges.len(), a: idx + 1, b: idx + size + 1, weight: 1., access: None });
+            }
+        }
+        (nodes, edges)
+    }
+
+    #[test]
+    fn balanced_with_short_borders() {
+        let (nodes, edges) = grid(30);
+        let config = PartitionConfig { regions: 4, imbalance: 0.05 };
+        let regions = partition(&nodes, &edges, &config).unwrap();
+        let mut sizes: HashMap<u32, usize> = HashMap::new();
+        for region in regions.values() {
+            *sizes.entry(*region).or_default() += 1;
+        }
+        assert_eq!(sizes.len(), 4);
+        assert!(sizes.values().all(|size| *size <= 237), "{:?}", sizes);
+        let cut = edges.iter().filter(|edge| regions[&edge.a] != regions[&edge.b]).count();
+        // Quartering the grid cuts 60 edges, a random split about 1300.
+        assert!(cut <= 120, "{} edges cut", cut);
+    }
+
+    #[tokio::test]
+    async fn written_regions_load() {
+        let (nodes, edges) = grid(12);
+        let dir = TempDir::new("partition");
+        let manifest = generate(&nodes, &edges, &PartitionConfig { regions: 3, imbalance: 0.1 }, 2, dir.path()).unwrap();
+        assert_eq!(manifest.regions, vec![1, 2, 3]);
+        assert_eq!(manifest.groups, vec![1, 2]);
+        let provider = MockGraphProvider::new(dir.path().to_path_buf());
+        let mut own_nodes = 0;
+        for region in 1..=3 {
+            let graph = provider.get_region(region).await.unwrap();
+            own_nodes += graph.nodes().filter(|node| node.region() == region).count();
+        }
+        assert_eq!(own_nodes, 144);
+    }
+}
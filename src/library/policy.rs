@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::domain::{HopMessage, SearchDirection};
+use crate::fanout::{FanoutPolicy, FanoutRanking};
+use crate::graph::{Graph, Profile, SearchLimits, WeightScale};
+use crate::heuristic::{Heuristic, HeuristicKind};
+use crate::strategy::StrategySelector;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Execution parameters for one class of requests. Attributes left out match any request,
+/// parameters left out keep the server defaults.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PolicyRule {
+    #[serde(default)]
+    pub(crate) name: Option<String>,
+    #[serde(default)]
+    pub(crate) client: Option<String>,
+    #[serde(default)]
+    pub(crate) priority_class: Option<String>,
+    #[serde(default)]
+    pub(crate) profile: Option<Profile>,
+    /// Search heuristic, as in SEARCH_HEURISTIC.
+    #[serde(default)]
+    pub(crate) heuristic: Option<String>,
+    #[serde(default)]
+    pub(crate) max_cost: Option<u64>,
+    #[serde(default)]
+    pub(crate) max_region_hops: Option<usize>,
+    #[serde(default)]
+    pub(crate) max_frontier: Option<usize>,
+    #[serde(default)]
+    pub(crate) max_reached: Option<usize>,
+    #[serde(default)]
+    pub(crate) search_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub(crate) fanout_limit: Option<usize>,
+    #[serde(default)]
+    pub(crate) fanout_epsilon: Option<f64>,
+    /// As in FANOUT_RANKING.
+    #[serde(default)]
+    pub(crate) fanout_ranking: Option<FanoutRanking>,
+    /// Whether the best cost shared by all servers through redis may be used for pruning.
+    #[serde(default)]
+    pub(crate) use_cost_cache: Option<bool>,
+    /// Whether queries crossing regions are searched from both ends, as in BIDIRECTIONAL_SEARCH.
+    #[serde(default)]
+    pub(crate) bidirectional: Option<bool>,
+}
+
+impl PolicyRule {
+    fn matches(&self, request: &HopMessage) -> bool {
+        fn attribute<T: PartialEq>(expected: &Option<T>, actual: &Option<T>) -> bool {
+            expected.is_none() || expected == actual
+        }
+        attribute(&self.client, &request.client)
+            && attribute(&self.priority_class, &request.priority_class)
+            && attribute(&self.profile, &request.profile)
+    }
+}
+
+/// Routing policies in the order they are evaluated, as read from the file at ROUTING_POLICIES.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PolicyConfig {
+    #[serde(default, rename = "rule")]
+    pub(crate) rules: Vec<PolicyRule>,
+}
+
+impl PolicyConfig {
+    pub(crate) fn from_toml(raw: &str) -> Result<Self> {
+        let config: PolicyConfig = toml::from_str(raw)?;
+        for rule in config.rules.iter() {
+            if let Some(heuristic) = rule.heuristic.as_deref() {
+                HeuristicKind::named(heuristic)?;
+            }
+        }
+        Ok(config)
+    }
+
+    pub(crate) fn from_env() -> Result<Self> {
+        match env::var("ROUTING_POLICIES") {
+            Ok(path) => { Self::from_toml(&std::fs::read_to_string(path)?) }
+            Err(_) => { Ok(PolicyConfig::default()) }
+        }
+    }
+}
+
+/// How a worker serves a request.
+#[derive(Clone)]
+pub(crate) struct ExecutionParams {
+    pub(crate) heuristic: Arc<dyn Heuristic>,
+    /// Picks the search per query instead of always using `heuristic`, with SEARCH_STRATEGY=auto.
+    pub(crate) strategy: Option<Arc<StrategySelector>>,
+    pub(crate) search_limits: SearchLimits,
+    pub(crate) fanout: FanoutPolicy,
+    pub(crate) max_cost: Option<u64>,
+    pub(crate) max_region_hops: Option<usize>,
+    pub(crate) use_cost_cache: bool,
+    pub(crate) bidirectional: bool,
+}
+
+/// Picks the execution parameters of the first rule matching a request, the server defaults if none does.
+pub(crate) struct PolicyEngine {
+    rules: Vec<(PolicyRule, Arc<ExecutionParams>)>,
+    defaults: Arc<ExecutionParams>,
+}
+
+impl PolicyEngine {
+    /// Heuristics are built once per distinct name, landmarks being expensive to compute.
+    pub(crate) fn new<'a>(config: &PolicyConfig,
+                          defaults: ExecutionParams,
+                          weight_scale: WeightScale,
+                          graphs: impl IntoIterator<Item=&'a Graph> + Clone) -> Result<Self> {
+        let mut heuristics: HashMap<&str, Arc<dyn Heuristic>> = HashMap::new();
+        let mut rules = vec![];
+        for rule in config.rules.iter() {
+            // A heuristic chosen by the rule is used as is, instead of the automatic strategy.
+            let strategy = match rule.heuristic {
+                Some(_) => { None }
+                None => { defaults.strategy.clone() }
+            };
+            let heuristic = match rule.heuristic.as_deref() {
+                Some(name) => {
+                    match heuristics.get(name) {
+                        Some(heuristic) => { heuristic.clone() }
+                        None => {
+                            let heuristic = HeuristicKind::named(name)?.scaled(weight_scale).build(graphs.clone());
+                            heuristics.insert(name, heuristic.clone());
+                            heuristic
+                        }
+                    }
+                }
+                None => { defaults.heuristic.clone() }
+            };
+            let params = ExecutionParams {
+                heuristic,
+                strategy,
+                search_limits: SearchLimits {
+                    max_frontier: rule.max_frontier.or(defaults.search_limits.max_frontier),
+                    max_reached: rule.max_reached.or(defaults.search_limits.max_reached),
+                    max_duration: rule.search_timeout_ms.map(Duration::from_millis).or(defaults.search_limits.max_duration),
+                },
+                fanout: FanoutPolicy {
+                    limit: rule.fanout_limit.or(defaults.fanout.limit),
+                    epsilon: rule.fanout_epsilon.unwrap_or(defaults.fanout.epsilon),
+                    ranking: rule.fanout_ranking.unwrap_or(defaults.fanout.ranking),
+                },
+                max_cost: rule.max_cost.or(defaults.max_cost),
+                max_region_hops: rule.max_region_hops.or(defaults.max_region_hops),
+                use_cost_cache: rule.use_cost_cache.unwrap_or(defaults.use_cost_cache),
+                bidirectional: rule.bidirectional.unwrap_or(defaults.bidirectional),
+            };
+            rules.push((rule.clone(), Arc::new(params)));
+        }
+        Ok(Self {
+            rules,
+            defaults: Arc::new(defaults),
+        })
+    }
+
+    /// Also tightens the budget carried by the request to the one of its class, so it holds on every
+    /// following hop, and decides on fresh queries whether they are searched from both ends.
+    pub(crate) fn evaluate(&self, request: &mut HopMessage) -> Arc<ExecutionParams> {
+        let params = match self.rules.iter().find(|(rule, _)| rule.matches(request)) {
+            Some((rule, params)) => {
+                log::debug!("Request {} matches routing policy {}", request.request_id, rule.name.as_deref().unwrap_or("<unnamed>"));
+                params.clone()
+            }
+            None => { self.defaults.clone() }
+        };
+        fn tighten<T: Ord + Copy>(requested: Option<T>, allowed: Option<T>) -> Option<T> {
+            match (requested, allowed) {
+                (Some(a), Some(b)) => { Some(a.min(b)) }
+                (a, b) => { a.or(b) }
+            }
+        }
+        request.max_cost = tighten(request.max_cost, params.max_cost);
+        request.max_region_hops = tighten(request.max_region_hops, params.max_region_hops);
+        if request.is_fresh() && request.direction == SearchDirection::Forward {
+            request.bidirectional = params.bidirectional && request.source.1 != request.target.1;
+        }
+        params
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use crate::domain::{HopMessage, NodeInfo};
+    use crate::fanout::{FanoutPolicy, FanoutRanking};
+    use crate::graph::{Profile, SearchLimits, WeightScale};
+    use crate::heuristic::HeuristicKind;
+    use crate::policy::{ExecutionParams, PolicyConfig, PolicyEngine, PolicyRule};
+
+    const POLICIES: &str = r#"
+        [[rule]]
+        name = "fleet trucks"
+        client = "fleet"
+        profile = "truck"
+        heuristic = "euclidean"
+        max_frontier = 1000
+        fanout_limit = 1
+        fanout_ranking = "distance"
+
+        [[rule]]
+        name = "batch"
+        priority_class = "batch"
+        max_cost = 500
+        use_cost_cache = false
+        bidirectional = true
+    "#;
+
+    fn request(client: Option<&str>, priority_class: Option<&str>, profile: Option<Profile>) -> HopMessage {
+        let mut request = HopMessage::new(1, NodeInfo(1, 1), NodeInfo(2, 2), 1, vec![], 0, vec![1]);
+        request.client = client.map(str::to_string);
+        request.priority_class = priority_class.map(str::to_string);
+        request.profile = profile;
+        request
+    }
+
+    #[test]
+    fn parse() {
+        let config = PolicyConfig::from_toml(POLICIES).unwrap();
+        assert_eq!(config.rules.len(), 2);
+        assert_eq!(config.rules[0], PolicyRule {
+            name: Some("fleet trucks".to_string()),
+            client: Some("fleet".to_string()),
+            profile: Some(Profile::Truck),
+            heuristic: Some("euclidean".to_string()),
+            max_frontier: Some(1000),
+            fanout_limit: Some(1),
+            fanout_ranking: Some(FanoutRanking::Distance),
+            ..PolicyRule::default()
+        });
+        assert!(PolicyConfig::from_toml("[[rule]]\nheuristic = \"dijkstra\"").is_err());
+        assert!(PolicyConfig::from_toml("[[rule]]\nmax_frontir = 5").is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let config = PolicyConfig::from_toml(POLICIES).unwrap();
+        let defaults = ExecutionParams {
+            heuristic: HeuristicKind::Zero.build([]),
+            strategy: None,
+            search_limits: SearchLimits { max_reached: Some(10_000), ..SearchLimits::default() },
+            fanout: FanoutPolicy::default(),
+            max_cost: None,
+            max_region_hops: None,
+            use_cost_cache: true,
+            bidirectional: false,
+        };
+        let engine = PolicyEngine::new(&config, defaults, WeightScale::default(), []).unwrap();
+
+        let mut fleet = request(Some("fleet"), Some("batch"), Some(Profile::Truck));
+        let params = engine.evaluate(&mut fleet);
+        assert_eq!(params.search_limits.max_frontier, Some(1000));
+        assert_eq!(params.search_limits.max_reached, Some(10_000));
+        assert_eq!(params.fanout.limit, Some(1));
+        assert_eq!(params.fanout.ranking, FanoutRanking::Distance);
+        assert!(params.use_cost_cache);
+        assert_eq!(fleet.max_cost, None);
+
+        let mut batch = request(Some("fleet"), Some("batch"), Some(Profile::Car));
+        batch.max_cost = Some(800);
+        let params = engine.evaluate(&mut batch);
+        assert!(!params.use_cost_cache);
+        assert_eq!(batch.max_cost, Some(500));
+        assert!(batch.bidirectional);
+        assert!(!fleet.bidirectional);
+
+        let mut other = request(None, None, Some(Profile::Truck));
+        let params = engine.evaluate(&mut other);
+        assert!(Arc::ptr_eq(&params, &engine.defaults));
+    }
+}
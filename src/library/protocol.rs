@@ -0,0 +1,36 @@
+//! Rust types of `proto/messages.proto`, the protobuf schema of the messages exchanged with
+//! servers, clients and result collectors written in other languages. The conversions from and to
+//! the types of [`crate::domain`] refuse messages of a schema version newer than [`VERSION`].
+
+use std::fmt::{Display, Formatter};
+
+include!(concat!(env!("OUT_DIR"), "/pathfinder.rs"));
+
+/// Version of the schema messages are written with, and the newest one read.
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    UnsupportedVersion(u32),
+    MissingField(&'static str),
+    UnknownValue(&'static str, i32),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::UnsupportedVersion(version) => { write!(f, "Message of schema version {} is newer than the supported {}", version, VERSION) }
+            ProtocolError::MissingField(field) => { write!(f, "Message has no {}", field) }
+            ProtocolError::UnknownValue(field, value) => { write!(f, "Unknown {} {}", field, value) }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+pub(crate) fn check_version(version: u32) -> Result<(), ProtocolError> {
+    if version > VERSION {
+        return Err(ProtocolError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
@@ -0,0 +1,107 @@
+//! Bounds of the queues between the listener and the workers, and their depth for monitoring.
+//! Once the work queue is full the server stops reading its listener, so requests wait in
+//! the transport instead of piling up in memory.
+
+use std::env;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use tokio::task::JoinHandle;
+use crate::redis_connector::RedisConnector;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Default number of hops queued per worker, including the one being served.
+pub(crate) const DEFAULT_CAPACITY: usize = 2;
+/// Interval at which a server reports the depth of its queues.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Reports of a server which stopped reporting expire after this many intervals.
+const REPORT_TTL_INTERVALS: u32 = 10;
+
+/// Reads WORKER_QUEUE_CAPACITY.
+pub(crate) fn capacity_from_env() -> Result<usize> {
+    match env::var("WORKER_QUEUE_CAPACITY") {
+        Ok(capacity) => {
+            let capacity = capacity.parse()?;
+            if capacity == 0 {
+                Err("WORKER_QUEUE_CAPACITY must be at least 1")?
+            }
+            Ok(capacity)
+        }
+        Err(_) => { Ok(DEFAULT_CAPACITY) }
+    }
+}
+
+/// Redis key under which server `server_id` reports the depth of its queues as a [`QueueReport`].
+pub fn report_key(server_id: usize) -> String {
+    format!("queue_stats_{}", server_id)
+}
+
+/// Depth of the queues of a server, updated as hops come and go.
+#[derive(Debug, Default)]
+pub(crate) struct QueueStats {
+    /// Hops dispatched to the workers and not served yet.
+    pub(crate) dispatched: AtomicUsize,
+    /// Hops the workers queued for their own server and didn't serve yet.
+    pub(crate) requeued: AtomicUsize,
+    /// Times the listener wasn't read as every worker queue was full.
+    pub(crate) saturations: AtomicU64,
+    pub(crate) saturated_micros: AtomicU64,
+}
+
+/// Depth of the queues of a server, as last reported.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueueReport {
+    /// Hops dispatched to the workers and not served yet.
+    pub dispatched: usize,
+    /// Hops the workers may hold at most, `WORKER_QUEUE_CAPACITY` per worker.
+    pub capacity: usize,
+    /// Hops the workers queued for their own server, not bounded by the capacity.
+    pub requeued: usize,
+    /// Times the server stopped reading requests since it started.
+    pub saturations: u64,
+    /// Milliseconds the server didn't read requests since it started.
+    pub saturated_ms: u64,
+}
+
+impl QueueStats {
+    fn report(&self, capacity: usize) -> QueueReport {
+        QueueReport {
+            dispatched: self.dispatched.load(Ordering::Relaxed),
+            capacity,
+            requeued: self.requeued.load(Ordering::Relaxed),
+            saturations: self.saturations.load(Ordering::Relaxed),
+            saturated_ms: self.saturated_micros.load(Ordering::Relaxed) / 1000,
+        }
+    }
+}
+
+/// Reports the depth of the queues of server `server_id` every interval.
+pub(crate) fn spawn_reporter(redis_connector: RedisConnector, server_id: usize, capacity: usize, stats: Arc<QueueStats>) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let mut ticker = tokio::time::interval(REPORT_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(err) = redis_connector.put_queue_report(server_id, &stats.report(capacity), REPORT_INTERVAL * REPORT_TTL_INTERVALS).await {
+                log::debug!("Unable to report the queues of server {}, details: {}", server_id, err);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+    use crate::queues::{QueueReport, QueueStats};
+
+    #[test]
+    fn reports_take_the_current_depth() {
+        let stats = QueueStats::default();
+        stats.dispatched.store(3, Ordering::Relaxed);
+        stats.requeued.store(1, Ordering::Relaxed);
+        stats.saturations.store(2, Ordering::Relaxed);
+        stats.saturated_micros.store(4_500, Ordering::Relaxed);
+        assert_eq!(stats.report(8), QueueReport { dispatched: 3, capacity: 8, requeued: 1, saturations: 2, saturated_ms: 4 });
+    }
+}
@@ -0,0 +1,266 @@
+//! Token buckets limiting the queries a server takes, in total and per client, so a dispatcher
+//! flooding a small cluster doesn't starve everyone else. Only queries entering the cluster are
+//! limited, hops forwarded by other servers belong to queries already taken.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::time::{Duration, Instant};
+use priority_queue::PriorityQueue;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Clients whose buckets are kept before the least recently seen ones are dropped.
+const MAX_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BucketConfig {
+    /// Tokens added per second.
+    rate: f64,
+    /// Tokens a bucket holds at most, the queries taken at once after a quiet period.
+    burst: f64,
+}
+
+impl BucketConfig {
+    /// Reads `rate_var`, and `burst_var` defaulting to one second's worth of queries.
+    fn from_env(rate_var: &str, burst_var: &str) -> Result<Option<Self>> {
+        let rate: f64 = match env::var(rate_var) {
+            Ok(rate) => { rate.parse()? }
+            Err(_) => { return Ok(None) }
+        };
+        if rate <= 0.0 {
+            Err(format!("{} must be positive", rate_var))?
+        }
+        let burst = match env::var(burst_var) {
+            Ok(burst) => { burst.parse()? }
+            Err(_) => { rate.max(1.0) }
+        };
+        if burst < 1.0 {
+            Err(format!("{} must be at least 1", burst_var))?
+        }
+        Ok(Some(Self { rate, burst }))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct RateLimitConfig {
+    global: Option<BucketConfig>,
+    per_client: Option<BucketConfig>,
+    /// How long a query may wait for a token before it is rejected.
+    max_delay: Duration,
+}
+
+impl RateLimitConfig {
+    /// Reads RATE_LIMIT_PER_SEC, RATE_LIMIT_BURST, CLIENT_RATE_LIMIT_PER_SEC,
+    /// CLIENT_RATE_LIMIT_BURST and RATE_LIMIT_MAX_DELAY_MS.
+    pub(crate) fn from_env() -> Result<Self> {
+        Ok(Self {
+            global: BucketConfig::from_env("RATE_LIMIT_PER_SEC", "RATE_LIMIT_BURST")?,
+            per_client: BucketConfig::from_env("CLIENT_RATE_LIMIT_PER_SEC", "CLIENT_RATE_LIMIT_BURST")?,
+            max_delay: match env::var("RATE_LIMIT_MAX_DELAY_MS") {
+                Ok(millis) => { Duration::from_millis(millis.parse()?) }
+                Err(_) => { Duration::ZERO }
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    /// Negative while queries wait for tokens taken in advance.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(config: &BucketConfig, now: Instant) -> Self {
+        Self { tokens: config.burst, updated: now }
+    }
+
+    fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.rate).min(config.burst);
+        self.updated = now;
+    }
+
+    /// Wait until the bucket holds a token.
+    fn wait(&self, config: &BucketConfig) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / config.rate)
+        }
+    }
+}
+
+/// Limits of a server, shared by all clients and of each client.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    global: Option<TokenBucket>,
+    /// Queries without a client share the bucket of the empty name.
+    clients: HashMap<String, TokenBucket>,
+    /// Clients by the last query they sent, the least recent first.
+    last_seen: PriorityQueue<String, Reverse<Instant>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            global: config.global.as_ref().map(|global| TokenBucket::full(global, now)),
+            config,
+            clients: HashMap::new(),
+            last_seen: PriorityQueue::new(),
+        }
+    }
+
+    /// Takes a token for a query of `client`, returns how long the query has to wait for it, or
+    /// why it is rejected if that is longer than the maximum delay. Rejected queries take none.
+    pub(crate) fn admit(&mut self, client: Option<&str>, now: Instant) -> std::result::Result<Duration, String> {
+        let mut delay = Duration::ZERO;
+        if let (Some(config), Some(global)) = (&self.config.global, &mut self.global) {
+            global.refill(config, now);
+            let wait = global.wait(config);
+            if wait > self.config.max_delay {
+                return Err(format!("Rate limit of {} queries per second exceeded", config.rate));
+            }
+            delay = wait;
+        }
+        if let Some(config) = &self.config.per_client {
+            let name = client.unwrap_or_default();
+            self.last_seen.push(name.to_string(), Reverse(now));
+            while self.last_seen.len() > MAX_CLIENTS {
+                if let Some((evicted, _)) = self.last_seen.pop() {
+                    self.clients.remove(&evicted);
+                }
+            }
+            let bucket = self.clients.entry(name.to_string()).or_insert_with(|| TokenBucket::full(config, now));
+            bucket.refill(config, now);
+            let wait = bucket.wait(config);
+            if wait > self.config.max_delay {
+                return Err(format!("Rate limit of {} queries per second exceeded by client {}", config.rate, client.unwrap_or("without a name")));
+            }
+            bucket.tokens -= 1.0;
+            delay = delay.max(wait);
+        }
+        if let Some(global) = &mut self.global {
+            global.tokens -= 1.0;
+        }
+        Ok(delay)
+    }
+}
+
+/// Queries admitted with a delay, put aside until their tokens are due so the listener is read
+/// meanwhile. Queries due at once are taken in the order they were put aside.
+#[derive(Debug)]
+pub(crate) struct DelayedQueries<T> {
+    queries: BTreeMap<(Instant, u64), T>,
+    /// Queries put aside so far.
+    added: u64,
+}
+
+impl<T> DelayedQueries<T> {
+    pub(crate) fn new() -> Self {
+        Self { queries: BTreeMap::new(), added: 0 }
+    }
+
+    pub(crate) fn delay(&mut self, query: T, until: Instant) {
+        self.queries.insert((until, self.added), query);
+        self.added += 1;
+    }
+
+    /// When the next query is due.
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.queries.keys().next().map(|(due, _)| *due)
+    }
+
+    pub(crate) fn pop_due(&mut self, now: Instant) -> Option<T> {
+        match self.next_due() {
+            Some(due) if due <= now => { self.queries.pop_first().map(|(_, query)| query) }
+            _ => { None }
+        }
+    }
+
+    /// Takes all queries, due or not, in the order they are due.
+    pub(crate) fn drain(&mut self) -> Vec<T> {
+        std::mem::take(&mut self.queries).into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use crate::ratelimit::{BucketConfig, DelayedQueries, MAX_CLIENTS, RateLimitConfig, RateLimiter};
+
+    #[test]
+    fn clients_are_limited_on_their_own() {
+        let config = RateLimitConfig {
+            global: Some(BucketConfig { rate: 10.0, burst: 3.0 }),
+            per_client: Some(BucketConfig { rate: 1.0, burst: 2.0 }),
+            max_delay: Duration::ZERO,
+        };
+        let mut limiter = RateLimiter::new(config);
+        let now = Instant::now();
+        assert_eq!(limiter.admit(Some("batch"), now), Ok(Duration::ZERO));
+        assert_eq!(limiter.admit(Some("batch"), now), Ok(Duration::ZERO));
+        assert!(limiter.admit(Some("batch"), now).unwrap_err().contains("client batch"));
+        // The rejected query took no token from the server.
+        assert_eq!(limiter.admit(Some("web"), now), Ok(Duration::ZERO));
+        assert!(limiter.admit(None, now).unwrap_err().contains("10 queries per second"));
+        assert_eq!(limiter.admit(Some("batch"), now + Duration::from_secs(1)), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn excess_queries_wait_up_to_the_maximum_delay() {
+        let config = RateLimitConfig {
+            global: Some(BucketConfig { rate: 10.0, burst: 1.0 }),
+            per_client: None,
+            max_delay: Duration::from_millis(250),
+        };
+        let mut limiter = RateLimiter::new(config);
+        let now = Instant::now();
+        assert_eq!(limiter.admit(None, now), Ok(Duration::ZERO));
+        let delays: Vec<_> = (0..2).map(|_| limiter.admit(None, now).unwrap().as_millis()).collect();
+        assert_eq!(delays, vec![100, 200]);
+        assert!(limiter.admit(None, now).is_err());
+        assert_eq!(limiter.admit(None, now + Duration::from_millis(300)).unwrap().as_millis(), 0);
+    }
+
+    #[test]
+    fn least_recently_seen_clients_are_dropped() {
+        let config = RateLimitConfig {
+            global: None,
+            per_client: Some(BucketConfig { rate: 1.0, burst: 1.0 }),
+            max_delay: Duration::ZERO,
+        };
+        let mut limiter = RateLimiter::new(config);
+        let now = Instant::now();
+        assert_eq!(limiter.admit(Some("batch"), now), Ok(Duration::ZERO));
+        for client in 0..MAX_CLIENTS {
+            let seen = now + Duration::from_micros(client as u64 + 1);
+            assert_eq!(limiter.admit(Some(&client.to_string()), seen), Ok(Duration::ZERO));
+        }
+        assert_eq!(limiter.clients.len(), MAX_CLIENTS);
+        // Client 0 was seen after batch, whose bucket was dropped.
+        assert!(limiter.clients.contains_key("0") && !limiter.clients.contains_key("batch"));
+        assert!(limiter.admit(Some("0"), now + Duration::from_millis(20)).is_err());
+        assert_eq!(limiter.admit(Some("batch"), now + Duration::from_millis(20)), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn delayed_queries_are_taken_when_due() {
+        let mut delayed = DelayedQueries::new();
+        let now = Instant::now();
+        delayed.delay("second", now + Duration::from_millis(200));
+        delayed.delay("first", now + Duration::from_millis(100));
+        delayed.delay("third", now + Duration::from_millis(200));
+        assert_eq!(delayed.next_due(), Some(now + Duration::from_millis(100)));
+        assert_eq!(delayed.pop_due(now), None);
+        let due: Vec<_> = std::iter::from_fn(|| delayed.pop_due(now + Duration::from_millis(200))).collect();
+        assert_eq!(due, vec!["first", "second", "third"]);
+        assert_eq!(delayed.next_due(), None);
+        delayed.delay("later", now + Duration::from_secs(60));
+        assert_eq!(delayed.drain(), vec!["later"]);
+    }
+}
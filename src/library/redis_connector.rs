@@ -1,15 +1,160 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use futures_util::StreamExt as _;
 use redis::{AsyncCommands, FromRedisValue, RedisResult, Value, ErrorKind, RedisError, ToRedisArgs, RedisWrite};
+use redis::Msg;
 use redis::aio::{Connection};
 use serde::{Serialize, Deserialize};
 use tokio::sync::SemaphorePermit;
 use tokio::task::JoinHandle;
-use crate::Graph;
+use crate::{codec, Graph};
+use crate::adjacency::{self, RegionBorders};
+use crate::dead_letter::{self, DeadLetter};
+use crate::events::QueryEvent;
+use crate::fanout::FanoutStats;
+use crate::domain::{HalfRoute, HopMessage, PathPoint, RouteFailure, RouteResult, RouteStatus, SearchDirection, StoredRoute};
 use crate::graph::{NodeIdx, RegionIdx};
+use crate::journal;
+use crate::mirror::{self, Transport};
+use crate::queues::{self, QueueReport};
+use crate::retention::StoredResults;
+use crate::retry::ListenerBackoff;
+use crate::segments;
+use crate::usage::{self, RegionUsage};
 
 
+/// Seconds a request's best known cost is kept after its last improvement.
+const BEST_COST_TTL: usize = 600;
+/// Seconds the path segments of a request are kept after the last one was stored.
+const PATH_SEGMENTS_TTL: usize = 600;
+
+/// Lowers the stored cost to ARGV[1] unless it is already lower, returns the resulting best cost.
+/// Costs are compared as the zero padded strings of [`fixed_width`], Lua numbers being doubles.
+const OFFER_COST_SCRIPT: &str = r#"
+local current = redis.call('GET', KEYS[1])
+if (not current) or ARGV[1] < current then
+    redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+    return ARGV[1]
+end
+return current
+"#;
+
+/// `cost` padded to the width of the largest u64, so costs compare as strings as they do as numbers.
+fn fixed_width(cost: u64) -> String {
+    format!("{:020}", cost)
+}
+
+/// Records half routes in the hash KEYS[1], a node, the half route reaching it and its cost per three
+/// ARGV after the TTL in ARGV[1], unless cheaper ones are already there. Returns what the other end
+/// of the query recorded in KEYS[2] for each of the nodes, false where nothing.
+const MEET_SCRIPT: &str = r#"
+local met = {}
+for i = 2, #ARGV, 3 do
+    local current = redis.call('HGET', KEYS[1], ARGV[i])
+    if (not current) or tonumber(ARGV[i + 2]) < cjson.decode(current)['cost'] then
+        redis.call('HSET', KEYS[1], ARGV[i], ARGV[i + 1])
+    end
+    met[#met + 1] = redis.call('HGET', KEYS[2], ARGV[i])
+end
+redis.call('EXPIRE', KEYS[1], ARGV[1])
+return met
+"#;
+
+/// Counts a finished hop of a request in KEYS[1], which holds the hops in flight besides the first.
+/// Once none is left, returns 1 if nobody answered the request yet and marks it answered in KEYS[2].
+const FINISH_HOP_SCRIPT: &str = r#"
+local left = redis.call('DECR', KEYS[1])
+redis.call('EXPIRE', KEYS[1], ARGV[1])
+if left < 0 and redis.call('SET', KEYS[2], '1', 'NX', 'EX', ARGV[1]) then
+    return 1
+end
+return 0
+"#;
+
+/// Holds the result ARGV[1] of cost ARGV[2] in KEYS[1] unless a found result at most as costly is
+/// held, or any result is held and ARGV[3] says this one wasn't found. Returns whether it is held.
+const HOLD_RESULT_SCRIPT: &str = r#"
+local current = redis.call('GET', KEYS[1])
+if current then
+    local held = cjson.decode(current)
+    if ARGV[3] == '0' or (held['status'] == 'FOUND' and tonumber(ARGV[2]) >= held['cost']) then
+        return 0
+    end
+end
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[4])
+return 1
+"#;
+
+/// Extends the lease KEYS[1] by ARGV[2] milliseconds if it is held by ARGV[1] or lapsed, returns
+/// whether ARGV[1] holds it afterwards.
+const RENEW_LEASE_SCRIPT: &str = r#"
+local holder = redis.call('GET', KEYS[1])
+if (not holder) or holder == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+/// Deletes the lease KEYS[1] if ARGV[1] holds it, returns whether it did.
+const RELEASE_LEASE_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call('DEL', KEYS[1])
+return 1
+"#;
+
+/// Releases the lease KEYS[1] if ARGV[1] holds it, removes server ARGV[2] from the hash KEYS[2] and
+/// from the servers of its regions KEYS[3..], and announces it with ARGV[3] on `server_updates`.
+/// Returns whether ARGV[1] held the lease, nothing is touched otherwise as the group is served by
+/// another process.
+const LEAVE_CLUSTER_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call('DEL', KEYS[1])
+redis.call('HDEL', KEYS[2], ARGV[2])
+for i = 3, #KEYS do
+    redis.call('SREM', KEYS[i], ARGV[2])
+end
+redis.call('PUBLISH', 'server_updates', ARGV[3])
+return 1
+"#;
+
+/// Stores the result ARGV[2] of request ARGV[1] as KEYS[1] for ARGV[3] seconds, indexed by its expiry
+/// ARGV[4] in KEYS[2] and its size in KEYS[3], KEYS[4] counting the bytes of all. Results given
+/// up on (ARGV[5] = 0) don't replace stored ones.
+const STORE_RESULT_SCRIPT: &str = r#"
+if ARGV[5] == '0' and redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+local previous = tonumber(redis.call('HGET', KEYS[3], ARGV[1])) or 0
+redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+redis.call('ZADD', KEYS[2], ARGV[4], ARGV[1])
+redis.call('HSET', KEYS[3], ARGV[1], string.len(ARGV[2]))
+redis.call('INCRBY', KEYS[4], string.len(ARGV[2]) - previous)
+return 1
+"#;
+
+/// Drops the results expired by ARGV[1] from the indexes of [`STORE_RESULT_SCRIPT`], returns how
+/// many were dropped, how many are left and their bytes.
+const CLEAN_RESULTS_SCRIPT: &str = r#"
+local expired = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1])
+local freed = 0
+for _, id in ipairs(expired) do
+    freed = freed + (tonumber(redis.call('HGET', KEYS[2], id)) or 0)
+    redis.call('HDEL', KEYS[2], id)
+    redis.call('ZREM', KEYS[1], id)
+end
+local bytes = redis.call('DECRBY', KEYS[3], freed)
+return {#expired, redis.call('ZCARD', KEYS[1]), bytes}
+"#;
+
 macro_rules! invalid_type_error {
     ($v:expr, $det:expr) => {{
         return Err(::std::convert::From::from(
@@ -23,9 +168,9 @@ macro_rules! invalid_type_error {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct ServerInfo {
-    id: usize,
+    pub(crate) id: usize,
     pub(crate) addr: Box<str>,
-    regions: Vec<RegionIdx>,
+    pub(crate) regions: Vec<RegionIdx>,
 }
 
 impl ServerInfo {
@@ -42,18 +187,19 @@ impl ServerInfo {
 
 impl ToRedisArgs for ServerInfo {
     fn write_redis_args<W>(&self, out: &mut W) where W: ?Sized + RedisWrite {
-        let json_string = serde_json::to_string(self).unwrap();
-        String::write_redis_args(&json_string, out);
+        codec::with_encoded(self, |raw| out.write_arg(raw)).unwrap();
     }
 }
 
 impl FromRedisValue for ServerInfo {
     fn from_redis_value(v: &Value) -> RedisResult<Self> {
-        let json_string = String::from_redis_value(v)?;
-        match serde_json::from_str(&json_string) {
-            Ok(x) => Ok(x),
-            Err(e) => { Err(RedisError::from((ErrorKind::TypeError, "Failed to deserialize json: ", e.to_string()))) }
-        }
+        codec::decode_redis(v)
+    }
+}
+
+impl FromRedisValue for ServerUpdate {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        codec::decode_redis(v)
     }
 }
 
@@ -71,7 +217,7 @@ impl FromRedisValue for BulkServerInfo {
                     invalid_type_error!(v, "Response type has odd number of fields.")
                 } else {
                     let mut servers = BTreeMap::new();
-                    for pair in items.chunks(2).into_iter() {
+                    for pair in items.chunks(2) {
                         let server_id = usize::from_redis_value(&pair[0])?;
                         let server_info = ServerInfo::from_redis_value(&pair[1])?;
                         servers.insert(server_id, server_info);
@@ -88,15 +234,29 @@ impl FromRedisValue for BulkServerInfo {
     }
 }
 
+/// Message of the `server_updates` channel: the registration of a server which joined or moved,
+/// or `{"removed": <id>}` for a server which left the cluster.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub(crate) enum ServerUpdate {
+    Registered(ServerInfo),
+    Removed { removed: usize },
+}
+
+/// Updates kept for subscribers of [`NetworkInfo`] which fell behind.
+const SERVER_UPDATES_CAPACITY: usize = 256;
+
 #[derive(Debug, Clone)]
 pub struct NetworkInfo {
     servers: Arc<tokio::sync::RwLock<BTreeMap<usize, ServerInfo>>>,
+    updates: tokio::sync::broadcast::Sender<ServerUpdate>,
 }
 
 impl NetworkInfo {
-    fn new(servers: Arc<tokio::sync::RwLock<BTreeMap<usize, ServerInfo>>>) -> Self {
+    pub(crate) fn new(servers: Arc<tokio::sync::RwLock<BTreeMap<usize, ServerInfo>>>) -> Self {
         NetworkInfo {
-            servers
+            servers,
+            updates: tokio::sync::broadcast::channel(SERVER_UPDATES_CAPACITY).0,
         }
     }
 
@@ -107,13 +267,31 @@ impl NetworkInfo {
 
     pub(crate) async fn get_server(&self, id: usize) -> Option<ServerInfo> {
         let servers_reader = self.servers.read().await;
-        servers_reader.get(&id).map(|x| x.clone())
+        servers_reader.get(&id).cloned()
+    }
+
+    /// Updates applied from now on. A subscriber which lagged behind should read the whole
+    /// [`NetworkInfo::get_servers`] again.
+    pub(crate) fn subscribe(&self) -> tokio::sync::broadcast::Receiver<ServerUpdate> {
+        self.updates.subscribe()
+    }
+
+    pub(crate) async fn apply(&self, update: ServerUpdate) {
+        {
+            let mut servers_guard = self.servers.write().await;
+            match &update {
+                ServerUpdate::Registered(server_info) => { servers_guard.insert(server_info.id, server_info.clone()); }
+                ServerUpdate::Removed { removed } => { servers_guard.remove(removed); }
+            }
+        }
+        // No subscribers is fine.
+        let _ = self.updates.send(update);
     }
 }
 
 pub(crate) struct NetworkManager {
     pub(crate) network_info: NetworkInfo,
-    update_task: JoinHandle<()>,
+    _update_task: JoinHandle<()>,
 }
 
 impl NetworkManager {
@@ -124,30 +302,166 @@ impl NetworkManager {
 
         let res: BulkServerInfo = hget_conn.hgetall("server_info").await?;
 
-        let servers = Arc::new(tokio::sync::RwLock::new(res.servers));
-        let servers_for_task = servers.clone();
+        let network_info = NetworkInfo::new(Arc::new(tokio::sync::RwLock::new(res.servers)));
+        let network_info_for_task = network_info.clone();
         let update_task = tokio::task::spawn(async move {
             let mut pubsub_stream = pubsub.on_message();
-            loop {
-                let server_update: ServerInfo = pubsub_stream.next().await.unwrap().get_payload().unwrap(); // todo unsafe unwrap
-                let mut servers_guard = servers_for_task.write().await;
-                servers_guard.insert(server_update.id, server_update);
+            while let Some(message) = pubsub_stream.next().await {
+                match message.get_payload::<ServerUpdate>() {
+                    Ok(server_update) => { network_info_for_task.apply(server_update).await }
+                    Err(err) => { log::warn!("Ignoring malformed server update, details: {}", err) }
+                }
             }
+            log::error!("Subscription to server updates ended, the set of servers is no longer updated");
         });
 
         Ok(NetworkManager {
-            network_info: NetworkInfo::new(servers),
-            update_task,
+            network_info,
+            _update_task: update_task,
+        })
+    }
+}
+
+/// What [`Subscriber::next`] got.
+pub(crate) enum Delivery {
+    Message(Msg),
+    /// Subscribed again after the connection dropped, messages published meanwhile are lost.
+    Resubscribed,
+}
+
+/// Subscription to a set of channels outliving the connections it is made on: once one drops,
+/// the channels are subscribed to again, less and less often while redis stays unreachable.
+pub(crate) struct Subscriber {
+    redis_connector: RedisConnector,
+    channels: Vec<String>,
+    messages: Pin<Box<dyn futures_util::Stream<Item=Msg> + Send + Sync>>,
+    backoff: ListenerBackoff,
+}
+
+impl Subscriber {
+    /// Subscribes to `channels` right away, failing if redis can't be reached.
+    pub(crate) async fn new(redis_connector: &RedisConnector, channels: Vec<String>) -> RedisResult<Self> {
+        let messages = Self::subscribe(redis_connector, &channels).await?;
+        Ok(Self {
+            redis_connector: redis_connector.clone(),
+            channels,
+            messages,
+            backoff: ListenerBackoff::default(),
+        })
+    }
+
+    async fn subscribe(redis_connector: &RedisConnector, channels: &[String]) -> RedisResult<Pin<Box<dyn futures_util::Stream<Item=Msg> + Send + Sync>>> {
+        let mut pubsub = redis_connector.spawn_connection().await?.into_pubsub();
+        for channel in channels {
+            pubsub.subscribe(channel).await?;
+        }
+        Ok(Box::pin(pubsub.into_on_message()))
+    }
+
+    /// Waits for the next message, subscribing again first if the connection dropped.
+    pub(crate) async fn next(&mut self) -> Delivery {
+        if let Some(message) = self.messages.next().await {
+            self.backoff.reset();
+            return Delivery::Message(message);
+        }
+        log::warn!("Subscription to {} channels closed, subscribing again", self.channels.len());
+        loop {
+            tokio::time::sleep(self.backoff.failed()).await;
+            match Self::subscribe(&self.redis_connector, &self.channels).await {
+                Ok(messages) => {
+                    self.messages = messages;
+                    return Delivery::Resubscribed;
+                }
+                Err(err) => {
+                    log::warn!("Unable to subscribe to {} channels again, {} failures in a row. Details: {}", self.channels.len(), self.backoff.failures(), err);
+                }
+            }
+        }
+    }
+}
+
+/// Fixed size pool of reusable connections.
+///
+/// Items are handed out as [`Pooled`] guards which own both the item and its semaphore permit,
+/// so a caller that gets cancelled can never leak either of them.
+pub(crate) struct Pool<T> {
+    items: std::sync::Mutex<Vec<T>>,
+    permits: tokio::sync::Semaphore,
+}
+
+impl<T> Pool<T> {
+    pub(crate) fn new(items: Vec<T>) -> Self {
+        let permits = tokio::sync::Semaphore::new(items.len());
+        Self {
+            items: std::sync::Mutex::new(items),
+            permits,
+        }
+    }
+
+    /// Waits for a free slot and checks out an item. If a previous holder discarded its item,
+    /// `open` is used to create a replacement.
+    pub(crate) async fn claim<F, Fut, E>(&self, open: F) -> Result<Pooled<'_, T>, E>
+        where F: FnOnce() -> Fut,
+              Fut: Future<Output=Result<T, E>> {
+        let permit = self.permits.acquire().await.expect("Pool semaphore is never closed");
+        let pooled = self.items.lock().unwrap().pop();
+        let item = match pooled {
+            Some(item) => { item }
+            None => { open().await? }
+        };
+        Ok(Pooled {
+            pool: self,
+            item: Some(item),
+            _permit: permit,
         })
     }
 }
 
+/// Item checked out of a [`Pool`].
+///
+/// Call [`Pooled::release`] once the item is back in a consistent state. A guard dropped without
+/// being released (e.g. because the future using it was cancelled mid-command) discards its item,
+/// since a half-finished exchange would desynchronize the next user. The permit is returned either way.
+pub(crate) struct Pooled<'a, T> {
+    pool: &'a Pool<T>,
+    item: Option<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<'a, T> Pooled<'a, T> {
+    pub(crate) fn release(mut self) {
+        if let Some(item) = self.item.take() {
+            self.pool.items.lock().unwrap().push(item);
+        }
+    }
+}
+
+impl<'a, T> Deref for Pooled<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for Pooled<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.item.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for Pooled<'a, T> {
+    fn drop(&mut self) {
+        if self.item.is_some() {
+            log::debug!("Pooled connection dropped before release, discarding it");
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct RedisConnector {
     client: redis::Client,
-    conn_pool: Arc<tokio::sync::Mutex<Vec<redis::aio::Connection>>>,
-    conn_count: Arc<tokio::sync::Semaphore>,
+    conn_pool: Arc<Pool<Connection>>,
 }
 
 impl RedisConnector {
@@ -167,83 +481,743 @@ impl RedisConnector {
         }
         Ok(RedisConnector {
             client,
-            conn_pool: Arc::new(tokio::sync::Mutex::new(conn_pool)),
-            conn_count: Arc::new(tokio::sync::Semaphore::new(connection_count)),
+            conn_pool: Arc::new(Pool::new(conn_pool)),
         })
     }
 
-    pub(crate) async fn claim_connection(&self) -> (SemaphorePermit<'_>, redis::aio::Connection) {
-        let permit = self.conn_count.acquire().await.unwrap(); // todo unwrap
-        let conn = {
-            let mut pool_guard = self.conn_pool.lock().await;
-            pool_guard.pop().unwrap()
-        };
-        return (permit, conn);
+    /// Connector to a redis nobody listens on, every command fails right away. For tests of code
+    /// which gets by without redis.
+    #[cfg(test)]
+    pub(crate) fn unreachable() -> Self {
+        RedisConnector {
+            client: redis::Client::open("redis://127.0.0.1:1").unwrap(),
+            conn_pool: Arc::new(Pool { items: std::sync::Mutex::new(vec![]), permits: tokio::sync::Semaphore::new(4) }),
+        }
     }
 
-    pub(crate) async fn release_connection(&self, conn: Connection) { // todo may be replaced with drop trait on connection
-        let mut pool_guard = self.conn_pool.lock().await;
-        pool_guard.push(conn)
+    pub(crate) async fn claim_connection(&self) -> RedisResult<Pooled<'_, Connection>> {
+        self.conn_pool.claim(|| self.client.get_async_connection()).await
     }
 
-    pub(crate) async fn get_server_id(&self, region_id: RegionIdx) -> RedisResult<usize> {
-        let (_count_guard, mut conn) = self.claim_connection().await;
-        let res = conn.get(format!("region_server_{}", region_id)).await;
-        self.release_connection(conn).await;
+    /// Servers hosting the region, its replicas. Regions registered by servers predating replicas
+    /// only have the one of `region_server_<region>`.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn get_region_servers(&self, region_id: RegionIdx) -> RedisResult<Vec<usize>> {
+        let mut conn = self.claim_connection().await?;
+        let res: RedisResult<Vec<usize>> = conn.smembers(format!("region_servers_{}", region_id)).await;
+        let res = match res {
+            Ok(servers) if servers.is_empty() => {
+                conn.get::<_, Option<usize>>(format!("region_server_{}", region_id)).await.map(|server| server.into_iter().collect())
+            }
+            res => { res }
+        };
+        conn.release();
         res
     }
 
+    /// Those of `servers` whose group's lease is held, i.e. which are alive.
+    pub(crate) async fn get_live_servers(&self, servers: &[usize]) -> RedisResult<Vec<usize>> {
+        let keys: Vec<String> = servers.iter().map(|server| format!("lease_{}", server)).collect();
+        let mut conn = self.claim_connection().await?;
+        let holders: RedisResult<Vec<Option<String>>> = redis::cmd("MGET").arg(keys).query_async(&mut *conn).await;
+        conn.release();
+        Ok(servers.iter().zip(holders?).filter(|(_, holder)| holder.is_some()).map(|(server, _)| *server).collect())
+    }
+
+    /// Last queue reports of `servers`, none for those which stopped reporting or send undecodable ones.
+    pub(crate) async fn get_queue_reports(&self, servers: &[usize]) -> RedisResult<Vec<Option<QueueReport>>> {
+        let keys: Vec<String> = servers.iter().map(|server| queues::report_key(*server)).collect();
+        let mut conn = self.claim_connection().await?;
+        let raw: RedisResult<Vec<Option<String>>> = redis::cmd("MGET").arg(keys).query_async(&mut *conn).await;
+        conn.release();
+        Ok(raw?.into_iter().map(|raw| raw.and_then(|raw| serde_json::from_str(&raw).ok())).collect())
+    }
+
+    /// Servers currently registered, without subscribing to updates.
+    pub(crate) async fn get_servers(&self) -> RedisResult<BTreeMap<usize, ServerInfo>> {
+        let mut conn = self.claim_connection().await?;
+        let res: RedisResult<BulkServerInfo> = conn.hgetall("server_info").await;
+        conn.release();
+        Ok(res?.servers)
+    }
+
     pub(crate) async fn get_servers_info(&self) -> RedisResult<NetworkManager> {
         let pubsub_conn = self.client.get_async_connection().await?;
-        let (_count_guard, mut conn) = self.claim_connection().await;
+        let mut conn = self.claim_connection().await?;
         let res = NetworkManager::new(&mut conn, pubsub_conn).await;
-        self.release_connection(conn).await;
+        conn.release();
         res
     }
 
-    async fn register_server(&self, server_info: &ServerInfo) -> RedisResult<()> {
-        let (_count_guard, mut conn) = self.claim_connection().await;
-        let r1 = conn.publish("server_updates", server_info).await;
-        let r2 = conn.hset("server_info", server_info.id, server_info).await;
-        self.release_connection(conn).await;
+    /// Stores `server_info` in `server_info` and announces it on `server_updates`.
+    pub(crate) async fn register_server(&self, server_info: &ServerInfo) -> RedisResult<()> {
+        let mut conn = self.claim_connection().await?;
+        let r1: RedisResult<()> = conn.publish("server_updates", server_info).await;
+        let r2: RedisResult<()> = conn.hset("server_info", server_info.id, server_info).await;
+        conn.release();
         r1?;
         r2?;
         Ok(())
     }
 
+    /// Records the borders of a loaded region in the meta-graph of regions and announces them.
+    pub(crate) async fn set_region_borders(&self, borders: &RegionBorders) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let encoded = serde_json::to_string(borders)?;
+        let mut conn = self.claim_connection().await?;
+        let r1: RedisResult<()> = conn.hset("region_borders", borders.region, &encoded).await;
+        let r2: RedisResult<()> = conn.publish(adjacency::UPDATES_CHANNEL, &encoded).await;
+        conn.release();
+        r1?;
+        r2?;
+        Ok(())
+    }
+
+    /// Borders of every region reported so far, malformed ones are skipped.
+    pub(crate) async fn get_region_borders(&self) -> RedisResult<Vec<RegionBorders>> {
+        let mut conn = self.claim_connection().await?;
+        let raw: RedisResult<HashMap<RegionIdx, String>> = conn.hgetall("region_borders").await;
+        conn.release();
+        Ok(raw?.into_iter()
+            .filter_map(|(region, raw)| match serde_json::from_str(&raw) {
+                Ok(borders) => { Some(borders) }
+                Err(err) => {
+                    log::warn!("Ignoring malformed borders of region {}, details: {}", region, err);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Counts a hop mirrored over `transports`, see [`crate::mirror`].
+    pub(crate) async fn record_probe_sent(&self, transports: &[Transport]) -> RedisResult<()> {
+        let mut pipe = redis::pipe();
+        for transport in transports {
+            pipe.hincr("transport_stats", format!("{}_sent", transport.name()), 1).ignore();
+        }
+        let mut conn = self.claim_connection().await?;
+        let res = pipe.query_async(&mut *conn).await;
+        conn.release();
+        res
+    }
+
+    pub(crate) async fn record_probe_received(&self, transport: Transport, latency_ms: u64) -> RedisResult<()> {
+        let mut conn = self.claim_connection().await?;
+        let res = redis::pipe()
+            .hincr("transport_stats", format!("{}_received", transport.name()), 1).ignore()
+            .hincr("transport_stats", format!("{}_latency_ms_total", transport.name()), latency_ms).ignore()
+            .hincr("transport_stats", mirror::latency_bucket(transport, latency_ms), 1).ignore()
+            .query_async(&mut *conn).await;
+        conn.release();
+        res
+    }
+
     pub(crate) async fn get_region(&self, node_id: NodeIdx) -> RedisResult<RegionIdx> {
-        let (_count_guard, mut conn) = self.claim_connection().await;
+        let mut conn = self.claim_connection().await?;
         let region = conn.get(format!("node_region_{}", node_id)).await;
-        self.release_connection(conn).await;
+        conn.release();
         region
     }
 
+    /// Cheapest complete route found so far for the request by any server.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn get_best_cost(&self, request_id: usize) -> RedisResult<Option<u64>> {
+        let mut conn = self.claim_connection().await?;
+        let cost = conn.get(format!("best_cost_{}", request_id)).await;
+        conn.release();
+        cost
+    }
+
+    /// Result kept for the request, see [`RedisConnector::store_result`].
+    pub(crate) async fn get_result(&self, request_id: usize) -> RedisResult<Option<RouteResult>> {
+        let mut conn = self.claim_connection().await?;
+        let result = conn.get(crate::client::result_key(request_id)).await;
+        conn.release();
+        result
+    }
+
+    pub(crate) async fn publish_event(&self, channel: &str, event: &QueryEvent) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.claim_connection().await?;
+        let res = conn.publish(channel, &*codec::encode(event)?).await;
+        conn.release();
+        Ok(res?)
+    }
+
+    /// Unique id for a query submitted without one, counted up in `next_request_id` and marked with
+    /// [`crate::client::ISSUED_REQUEST_ID_BIT`].
+    pub(crate) async fn next_request_id(&self) -> RedisResult<usize> {
+        let mut conn = self.claim_connection().await?;
+        let count: RedisResult<usize> = conn.incr("next_request_id", 1).await;
+        conn.release();
+        Ok(crate::client::ISSUED_REQUEST_ID_BIT | (count? & (crate::client::ISSUED_REQUEST_ID_BIT - 1)))
+    }
+
+    /// Reports a complete route of the given cost, returns the best cost known afterwards.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn offer_cost(&self, request_id: usize, cost: u64) -> RedisResult<u64> {
+        let mut conn = self.claim_connection().await?;
+        let best = redis::Script::new(OFFER_COST_SCRIPT)
+            .key(format!("best_cost_{}", request_id))
+            .arg(fixed_width(cost))
+            .arg(BEST_COST_TTL)
+            .invoke_async(&mut *conn).await;
+        conn.release();
+        best
+    }
+
+    /// Keeps a dead letter of `server_id`, dropping the oldest past [`dead_letter::MAX_LEN`].
+    pub(crate) async fn push_dead_letter(&self, server_id: usize, letter: &DeadLetter) -> RedisResult<()> {
+        let raw = serde_json::to_string(letter).map_err(|err| RedisError::from((ErrorKind::TypeError, "Unable to encode dead letter", err.to_string())))?;
+        let key = dead_letter::list_key(server_id);
+        let mut conn = self.claim_connection().await?;
+        let res: RedisResult<()> = redis::pipe()
+            .lpush(&key, raw).ignore()
+            .ltrim(&key, 0, dead_letter::MAX_LEN as isize - 1).ignore()
+            .query_async(&mut *conn).await;
+        conn.release();
+        res
+    }
+
+    /// Journals `request`, taken by server `server_id`, under `entry`.
+    pub(crate) async fn journal_hop(&self, server_id: usize, entry: &str, request: &HopMessage) -> RedisResult<()> {
+        let raw = serde_json::to_string(request).map_err(|err| RedisError::from((ErrorKind::TypeError, "Unable to encode journal entry", err.to_string())))?;
+        let mut conn = self.claim_connection().await?;
+        let res = conn.hset(journal::key(server_id), entry, raw).await;
+        conn.release();
+        res
+    }
+
+    /// Drops journal entry `entry` of server `server_id`, its hop is done with.
+    pub(crate) async fn complete_hop(&self, server_id: usize, entry: &str) -> RedisResult<()> {
+        let mut conn = self.claim_connection().await?;
+        let res = conn.hdel(journal::key(server_id), entry).await;
+        conn.release();
+        res
+    }
+
+    /// Hops journaled by server `server_id` and not completed, by entry.
+    pub(crate) async fn journaled_hops(&self, server_id: usize) -> RedisResult<Vec<(String, HopMessage)>> {
+        let mut conn = self.claim_connection().await?;
+        let raw: RedisResult<HashMap<String, String>> = conn.hgetall(journal::key(server_id)).await;
+        conn.release();
+        Ok(raw?.into_iter().filter_map(|(entry, raw)| match serde_json::from_str(&raw) {
+            Ok(request) => { Some((entry, request)) }
+            Err(err) => {
+                log::warn!("Skipping undecodable journal entry {}, details: {}", entry, err);
+                None
+            }
+        }).collect())
+    }
+
+    /// Replaces the queue report of `server_id`, which expires after `ttl` unless renewed.
+    pub(crate) async fn put_queue_report(&self, server_id: usize, report: &QueueReport, ttl: Duration) -> RedisResult<()> {
+        let raw = serde_json::to_string(report).map_err(|err| RedisError::from((ErrorKind::TypeError, "Unable to encode queue report", err.to_string())))?;
+        let mut conn = self.claim_connection().await?;
+        let res = conn.pset_ex(queues::report_key(server_id), raw, ttl.as_millis() as usize).await;
+        conn.release();
+        res
+    }
+
+    /// Adds `usage` to the usage of its regions, shared by all servers.
+    pub(crate) async fn add_region_usage(&self, usage: &HashMap<RegionIdx, RegionUsage>) -> RedisResult<()> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (region_id, usage) in usage.iter() {
+            for (field, count) in RegionUsage::FIELDS.iter().zip(usage.counts()) {
+                pipe.hincr(usage::usage_key(*region_id), *field, count).ignore();
+            }
+        }
+        let mut conn = self.claim_connection().await?;
+        let res = pipe.query_async(&mut *conn).await;
+        conn.release();
+        res
+    }
+
+    /// Takes the oldest dead letter of `server_id`, undecodable ones are dropped.
+    pub(crate) async fn take_dead_letter(&self, server_id: usize) -> RedisResult<Option<DeadLetter>> {
+        let mut conn = self.claim_connection().await?;
+        let raw: RedisResult<Option<String>> = conn.rpop(dead_letter::list_key(server_id), None).await;
+        conn.release();
+        Ok(raw?.and_then(|raw| match serde_json::from_str(&raw) {
+            Ok(letter) => { Some(letter) }
+            Err(err) => {
+                log::warn!("Dropping undecodable dead letter {}, details: {}", raw, err);
+                None
+            }
+        }))
+    }
+
+    /// Counts hops of a request sent on, before sending them so they can't finish first.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn spawn_hops(&self, request_id: usize, count: usize) -> RedisResult<()> {
+        let mut conn = self.claim_connection().await?;
+        let key = format!("pending_hops_{}", request_id);
+        let res: RedisResult<()> = redis::pipe()
+            .incr(&key, count).ignore()
+            .expire(&key, BEST_COST_TTL).ignore()
+            .query_async(&mut *conn).await;
+        conn.release();
+        res
+    }
+
+    /// Counts a finished hop, returns whether it was the last one of a request nobody answered.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn finish_hop(&self, request_id: usize) -> RedisResult<bool> {
+        let mut conn = self.claim_connection().await?;
+        let unanswered: RedisResult<u8> = redis::Script::new(FINISH_HOP_SCRIPT)
+            .key(format!("pending_hops_{}", request_id))
+            .key(format!("answered_{}", request_id))
+            .arg(BEST_COST_TTL)
+            .invoke_async(&mut *conn).await;
+        conn.release();
+        Ok(unanswered? == 1)
+    }
+
+    /// Holds `result` back from the client if it beats the one held for its request so far, returns
+    /// whether it does.
+    #[tracing::instrument(level = "debug", skip(self, result), fields(request_id = result.request_id))]
+    pub(crate) async fn hold_result(&self, result: &RouteResult) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let encoded = codec::encode(result)?;
+        let mut conn = self.claim_connection().await?;
+        let held: RedisResult<u8> = redis::Script::new(HOLD_RESULT_SCRIPT)
+            .key(format!("held_result_{}", result.request_id))
+            .arg(&*encoded)
+            .arg(result.cost)
+            .arg(u8::from(result.status == RouteStatus::Found))
+            .arg(BEST_COST_TTL)
+            .invoke_async(&mut *conn).await;
+        conn.release();
+        Ok(held? == 1)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn get_held_result(&self, request_id: usize) -> RedisResult<Option<RouteResult>> {
+        let mut conn = self.claim_connection().await?;
+        let held = conn.get(format!("held_result_{}", request_id)).await;
+        conn.release();
+        held
+    }
+
+    /// Marks a request answered unless it already is, returns whether this call did.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn claim_answer(&self, request_id: usize) -> RedisResult<bool> {
+        let mut conn = self.claim_connection().await?;
+        let claimed: RedisResult<Option<String>> = redis::cmd("SET")
+            .arg(format!("answered_{}", request_id))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(BEST_COST_TTL)
+            .query_async(&mut *conn).await;
+        conn.release();
+        Ok(claimed?.is_some())
+    }
+
+    /// Claims the hop of fingerprint `fingerprint` for `ttl`, returns whether no process took it before.
+    pub(crate) async fn claim_hop(&self, fingerprint: &str, ttl: Duration) -> RedisResult<bool> {
+        let mut conn = self.claim_connection().await?;
+        let claimed: RedisResult<Option<String>> = redis::cmd("SET")
+            .arg(format!("seen_hop_{}", fingerprint))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl.as_secs().max(1))
+            .query_async(&mut *conn).await;
+        conn.release();
+        Ok(claimed?.is_some())
+    }
+
+    pub(crate) async fn release_hop(&self, fingerprint: &str) -> RedisResult<()> {
+        let mut conn = self.claim_connection().await?;
+        let res = conn.del(format!("seen_hop_{}", fingerprint)).await;
+        conn.release();
+        res
+    }
+
+    /// Records the failure a hop of a request was lost to, unless one was already.
+    pub(crate) async fn record_hop_failure(&self, request_id: usize, failure: &RouteFailure, reason: &str) -> RedisResult<()> {
+        let raw = serde_json::to_string(&(failure, reason)).map_err(|err| RedisError::from((ErrorKind::TypeError, "Unable to encode hop failure", err.to_string())))?;
+        let mut conn = self.claim_connection().await?;
+        let res: RedisResult<Value> = redis::cmd("SET").arg(format!("hop_failure_{}", request_id)).arg(raw).arg("NX").arg("EX").arg(BEST_COST_TTL)
+            .query_async(&mut *conn).await;
+        conn.release();
+        res.map(|_| ())
+    }
+
+    /// First failure recorded for a hop of a request, with the error it failed with.
+    pub(crate) async fn get_hop_failure(&self, request_id: usize) -> RedisResult<Option<(RouteFailure, String)>> {
+        let mut conn = self.claim_connection().await?;
+        let raw: RedisResult<Option<String>> = conn.get(format!("hop_failure_{}", request_id)).await;
+        conn.release();
+        match raw? {
+            Some(raw) => {
+                serde_json::from_str(&raw).map(Some)
+                    .map_err(|err| RedisError::from((ErrorKind::TypeError, "Unable to decode hop failure", err.to_string())))
+            }
+            None => { Ok(None) }
+        }
+    }
+
+    /// Marks a request answered, so its last hop doesn't report it as not found.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub(crate) async fn mark_answered(&self, request_id: usize) -> RedisResult<()> {
+        let mut conn = self.claim_connection().await?;
+        let res = conn.set_ex(format!("answered_{}", request_id), 1, BEST_COST_TTL).await;
+        conn.release();
+        res
+    }
+
+    /// Records the cheapest half routes of a bidirectional request reaching each of `halves` from one
+    /// end, returns the cheapest ones recorded from the other end so far, in the same order.
+    #[tracing::instrument(level = "debug", skip(self, halves))]
+    pub(crate) async fn meet(&self, request_id: usize, direction: SearchDirection, halves: &[(NodeIdx, HalfRoute)]) -> Result<Vec<Option<HalfRoute>>, Box<dyn std::error::Error + Send + Sync>> {
+        let key = |direction: SearchDirection| format!("meet_{}_{}", request_id, direction.name());
+        let script = redis::Script::new(MEET_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation.key(key(direction)).key(key(direction.opposite())).arg(BEST_COST_TTL);
+        for (node, route) in halves {
+            invocation.arg(*node).arg(serde_json::to_string(route)?).arg(route.cost);
+        }
+        let mut conn = self.claim_connection().await?;
+        let met: RedisResult<Vec<Option<String>>> = invocation.invoke_async(&mut *conn).await;
+        conn.release();
+        met?.into_iter()
+            .map(|raw| raw.map(|raw| serde_json::from_str(&raw)).transpose().map_err(Into::into))
+            .collect()
+    }
+
+    /// Keeps `result` for `ttl`, see [`crate::client::ResultsClient::get_result`]. A result found
+    /// later replaces it.
+    pub(crate) async fn store_result(&self, result: &RouteResult, ttl: Duration) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let expires_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + ttl.as_secs();
+        let mut conn = self.claim_connection().await?;
+        let stored: RedisResult<u8> = redis::Script::new(STORE_RESULT_SCRIPT)
+            .key(crate::client::result_key(result.request_id))
+            .key("results_expiry")
+            .key("results_size")
+            .key("results_bytes")
+            .arg(result.request_id)
+            .arg(&*codec::encode(result)?)
+            .arg(ttl.as_secs())
+            .arg(expires_at)
+            .arg(u8::from(result.status == RouteStatus::Found))
+            .invoke_async(&mut *conn).await;
+        conn.release();
+        stored?;
+        Ok(())
+    }
+
+    /// Keeps the complete route of a request for `ttl`, for later requests to reuse.
+    pub(crate) async fn store_route(&self, request_id: usize, route: &StoredRoute, ttl: Duration) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let encoded = codec::encode(route)?;
+        let mut conn = self.claim_connection().await?;
+        let stored: RedisResult<()> = conn.pset_ex(format!("route_{}", request_id), &*encoded, ttl.as_millis() as usize).await;
+        conn.release();
+        stored?;
+        Ok(())
+    }
+
+    /// Stores path segments of a request by their [`segments::field`].
+    pub(crate) async fn store_path_segments(&self, request_id: usize, stored: &[(String, Vec<PathPoint>)]) -> RedisResult<()> {
+        let mut encoded = vec![];
+        for (field, segment) in stored.iter() {
+            let raw = serde_json::to_string(segment).map_err(|err| RedisError::from((ErrorKind::TypeError, "Unable to encode path segment", err.to_string())))?;
+            encoded.push((field.as_str(), raw));
+        }
+        let mut conn = self.claim_connection().await?;
+        let res = redis::pipe()
+            .hset_multiple(segments::key(request_id), &encoded).ignore()
+            .expire(segments::key(request_id), PATH_SEGMENTS_TTL).ignore()
+            .query_async(&mut *conn).await;
+        conn.release();
+        res
+    }
+
+    /// Path segments of a request stored under `fields`, `None` for those not stored or expired.
+    pub(crate) async fn get_path_segments(&self, request_id: usize, fields: &[String]) -> RedisResult<Vec<Option<Vec<PathPoint>>>> {
+        let mut conn = self.claim_connection().await?;
+        let raw: RedisResult<Vec<Option<String>>> = redis::cmd("HMGET").arg(segments::key(request_id)).arg(fields).query_async(&mut *conn).await;
+        conn.release();
+        raw?.into_iter().map(|raw| raw.map(|raw| serde_json::from_str(&raw).map_err(|err| {
+            RedisError::from((ErrorKind::TypeError, "Undecodable path segment", err.to_string()))
+        })).transpose()).collect()
+    }
+
+    pub(crate) async fn get_route(&self, request_id: usize) -> Result<Option<StoredRoute>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.claim_connection().await?;
+        let raw: RedisResult<Option<String>> = conn.get(format!("route_{}", request_id)).await;
+        conn.release();
+        match raw? {
+            Some(raw) => { Ok(Some(serde_json::from_str(&raw)?)) }
+            None => { Ok(None) }
+        }
+    }
+
+    /// Forgets the results which expired, returns the volume of those still stored.
+    pub(crate) async fn clean_results(&self) -> Result<StoredResults, Box<dyn std::error::Error + Send + Sync>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut conn = self.claim_connection().await?;
+        let cleaned: RedisResult<(u64, u64, i64)> = redis::Script::new(CLEAN_RESULTS_SCRIPT)
+            .key("results_expiry")
+            .key("results_size")
+            .key("results_bytes")
+            .arg(now)
+            .invoke_async(&mut *conn).await;
+        conn.release();
+        let (removed, results, bytes) = cleaned?;
+        Ok(StoredResults { removed, results, bytes: bytes.max(0) as u64 })
+    }
+
+    /// Success of forwarding requests from `region` towards `target_region`, per neighbouring region.
+    pub(crate) async fn get_fanout_stats(&self, region: RegionIdx, target_region: RegionIdx) -> RedisResult<HashMap<RegionIdx, FanoutStats>> {
+        let mut conn = self.claim_connection().await?;
+        let counters: RedisResult<HashMap<String, u64>> = conn.hgetall(format!("fanout_{}_{}", region, target_region)).await;
+        conn.release();
+        let mut stats: HashMap<RegionIdx, FanoutStats> = HashMap::new();
+        for (field, count) in counters? {
+            match field.split_once('_') {
+                Some(("tries", next)) => { stats.entry(next.parse().unwrap_or_default()).or_default().tries = count }
+                Some(("wins", next)) => { stats.entry(next.parse().unwrap_or_default()).or_default().wins = count }
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+
+    pub(crate) async fn record_fanout_tries(&self, region: RegionIdx, target_region: RegionIdx, next_regions: &[RegionIdx]) -> RedisResult<()> {
+        if next_regions.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for next in next_regions {
+            pipe.hincr(format!("fanout_{}_{}", region, target_region), format!("tries_{}", next), 1).ignore();
+        }
+        let mut conn = self.claim_connection().await?;
+        let res = pipe.query_async(&mut *conn).await;
+        conn.release();
+        res
+    }
+
+    /// Credits every hop of a delivered route.
+    pub(crate) async fn record_fanout_wins(&self, visited_regions: &[RegionIdx], target_region: RegionIdx) -> RedisResult<()> {
+        if visited_regions.len() < 2 {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for hop in visited_regions.windows(2) {
+            pipe.hincr(format!("fanout_{}_{}", hop[0], target_region), format!("wins_{}", hop[1]), 1).ignore();
+        }
+        let mut conn = self.claim_connection().await?;
+        let res = pipe.query_async(&mut *conn).await;
+        conn.release();
+        res
+    }
+
     pub(crate) async fn spawn_connection(&self) -> RedisResult<redis::aio::Connection> {
         self.client.get_async_connection().await
     }
 
+    /// Registers the server of `group_id` as a replica of the region. `region_server_<region>`
+    /// names the last one registered, for servers predating replicas.
     pub(crate) async fn set_group(&self, region_id: RegionIdx, group_id: usize) -> RedisResult <()> {
-        let (_count_guard, mut conn) = self.claim_connection().await;
-        let res = conn.set(format!("region_server_{}", region_id), group_id).await;
-        self.release_connection(conn).await;
+        let mut conn = self.claim_connection().await?;
+        let res = redis::pipe()
+            .sadd(format!("region_servers_{}", region_id), group_id).ignore()
+            .set(format!("region_server_{}", region_id), group_id).ignore()
+            .query_async(&mut *conn).await;
+        conn.release();
         res
     }
 
+    /// Makes the server of `to_group` a replica of the region in place of the server of
+    /// `from_group` in one transaction, so hops are sent to either one all along.
+    pub(crate) async fn hand_over_region(&self, region_id: RegionIdx, from_group: usize, to_group: usize) -> RedisResult<()> {
+        let mut conn = self.claim_connection().await?;
+        let res = redis::pipe().atomic()
+            .sadd(format!("region_servers_{}", region_id), to_group).ignore()
+            .srem(format!("region_servers_{}", region_id), from_group).ignore()
+            .set(format!("region_server_{}", region_id), to_group).ignore()
+            .query_async(&mut *conn).await;
+        conn.release();
+        res
+    }
+
+    /// Registers a node inserted while running, see [`RedisConnector::set_region`].
+    pub(crate) async fn set_node_region(&self, node_id: NodeIdx, region_id: RegionIdx) -> RedisResult<()> {
+        let mut conn = self.claim_connection().await?;
+        let res = conn.set(format!("node_region_{}", node_id), region_id).await;
+        conn.release();
+        res
+    }
+
+    /// Process currently serving the group, as recorded by its last heartbeat.
+    pub(crate) async fn get_lease_holder(&self, group_id: usize) -> RedisResult<Option<String>> {
+        let mut conn = self.claim_connection().await?;
+        let holder = conn.get(format!("lease_{}", group_id)).await;
+        conn.release();
+        holder
+    }
+
+    /// Announces `version` as served for `group_id`, see [`crate::manifest::announce_version`],
+    /// unless `refuse` objects to the versions announced by the servers of every group, given with
+    /// the groups whose lease is held. Only checks them without a version to announce. Both happen
+    /// in one transaction, read again whenever another server changes the versions or the leases
+    /// meanwhile. Returns the objection.
+    pub(crate) async fn announce_dataset_version(&self,
+                                                 group_id: usize,
+                                                 version: Option<&str>,
+                                                 refuse: impl Fn(&HashMap<usize, String>, &[usize]) -> Option<String>) -> RedisResult<Option<String>> {
+        // A connection of its own, which forgets the keys it watches once dropped.
+        let mut conn = self.spawn_connection().await?;
+        loop {
+            redis::cmd("WATCH").arg("dataset_versions").query_async::<_, ()>(&mut conn).await?;
+            let versions: HashMap<usize, String> = conn.hgetall("dataset_versions").await?;
+            let groups: Vec<usize> = versions.keys().copied().collect();
+            let mut live = vec![];
+            if !groups.is_empty() {
+                let leases: Vec<String> = groups.iter().map(|group| format!("lease_{}", group)).collect();
+                redis::cmd("WATCH").arg(&leases).query_async::<_, ()>(&mut conn).await?;
+                let holders: Vec<Option<String>> = redis::cmd("MGET").arg(&leases).query_async(&mut conn).await?;
+                live = groups.into_iter().zip(holders).filter(|(_, holder)| holder.is_some()).map(|(group, _)| group).collect();
+            }
+            if let Some(objection) = refuse(&versions, &live) {
+                return Ok(Some(objection));
+            }
+            let version = match version {
+                Some(version) => { version }
+                None => { return Ok(None) }
+            };
+            let announced: Option<()> = redis::pipe().atomic()
+                .hset("dataset_versions", group_id, version).ignore()
+                .query_async(&mut conn).await?;
+            if announced.is_some() {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Takes the lease on serving the group if it lapsed, returns whether it was taken.
+    pub(crate) async fn claim_lease(&self, group_id: usize, holder: &str, ttl: Duration) -> RedisResult<bool> {
+        let mut conn = self.claim_connection().await?;
+        let claimed: RedisResult<Option<String>> = redis::cmd("SET")
+            .arg(format!("lease_{}", group_id))
+            .arg(holder)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut *conn).await;
+        conn.release();
+        Ok(claimed?.is_some())
+    }
+
+    /// Extends the lease on serving the group, returns false if another process holds it.
+    pub(crate) async fn renew_lease(&self, group_id: usize, holder: &str, ttl: Duration) -> RedisResult<bool> {
+        let mut conn = self.claim_connection().await?;
+        let renewed = redis::Script::new(RENEW_LEASE_SCRIPT)
+            .key(format!("lease_{}", group_id))
+            .arg(holder)
+            .arg(ttl.as_millis() as u64)
+            .invoke_async(&mut *conn).await;
+        conn.release();
+        renewed
+    }
+
+    /// Releases the lease on serving the group held by `holder`, returns false if another process holds it.
+    pub(crate) async fn release_lease(&self, group_id: usize, holder: &str) -> RedisResult<bool> {
+        let mut conn = self.claim_connection().await?;
+        let released = redis::Script::new(RELEASE_LEASE_SCRIPT)
+            .key(format!("lease_{}", group_id))
+            .arg(holder)
+            .invoke_async(&mut *conn).await;
+        conn.release();
+        released
+    }
+
+    /// Releases the lease on serving the group held by `holder` and deregisters server `server_id`
+    /// and its replicas of `regions`, returns false if another process holds the lease.
+    pub(crate) async fn leave_cluster(&self, group_id: usize, holder: &str, server_id: usize, regions: &[RegionIdx]) -> RedisResult<bool> {
+        let removed = serde_json::to_string(&ServerUpdate::Removed { removed: server_id })
+            .map_err(|err| RedisError::from((ErrorKind::TypeError, "Failed to serialize server update", err.to_string())))?;
+        let script = redis::Script::new(LEAVE_CLUSTER_SCRIPT);
+        let mut invocation = script.key(format!("lease_{}", group_id));
+        invocation.key("server_info");
+        for region_id in regions {
+            invocation.key(format!("region_servers_{}", region_id));
+        }
+        invocation.arg(holder).arg(server_id).arg(removed);
+        let mut conn = self.claim_connection().await?;
+        let left = invocation.invoke_async(&mut *conn).await;
+        conn.release();
+        left
+    }
+
     pub(crate) async fn set_region(&self, graph: &Graph, region_id: RegionIdx) -> RedisResult<()> {
-        let (_count_guard, mut conn) = self.claim_connection().await;
+        let mut conn = self.claim_connection().await?;
         let mut nodes_ids = vec![];
         let mut nodes_vals = vec![];
-        for (id, node) in graph.nodes.iter() {
+        for node in graph.nodes() {
+            let id = node.id;
             if node.region == region_id {
                 nodes_vals.push((format!("node_region_{}", id), region_id));
                 nodes_ids.push(format!("node_region_{}", id));
             }
         }
-        let res1 = conn.del(&*nodes_ids).await;
-        let res2 = conn.mset_nx(&*nodes_vals).await;
-        self.release_connection(conn).await;
+        let res1: RedisResult<()> = conn.del(&*nodes_ids).await;
+        let res2 = conn.mset_nx(&nodes_vals).await;
+        conn.release();
         res1?;
         res2
 
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use tokio::time::timeout;
+    use crate::redis_connector::{fixed_width, Pool};
+
+    async fn open() -> Result<usize, ()> {
+        Ok(7)
+    }
+
+    #[tokio::test]
+    async fn cancelled_holder_returns_permit() {
+        let pool = Pool::new(vec![1, 2]);
+        let res = timeout(Duration::from_millis(10), async {
+            let _item = pool.claim(open).await.unwrap();
+            futures_util::future::pending::<()>().await;
+        }).await;
+        assert!(res.is_err());
+        assert_eq!(pool.permits.available_permits(), 2);
+        assert_eq!(pool.items.lock().unwrap().len(), 1);
+
+        let first = pool.claim(open).await.unwrap();
+        let second = pool.claim(open).await.unwrap();
+        assert_eq!(*first, 1);
+        assert_eq!(*second, 7);
+        first.release();
+        second.release();
+        assert_eq!(pool.permits.available_permits(), 2);
+        assert_eq!(pool.items.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiter_does_not_take_permit() {
+        let pool = Pool::new(vec![1]);
+        let held = pool.claim(open).await.unwrap();
+        assert!(timeout(Duration::from_millis(10), pool.claim(open)).await.is_err());
+        held.release();
+        assert_eq!(pool.permits.available_permits(), 1);
+        let item = timeout(Duration::from_millis(10), pool.claim(open)).await.unwrap().unwrap();
+        assert_eq!(*item, 1);
+    }
+
+    #[test]
+    fn costs_compare_as_fixed_width_strings() {
+        let costs = [0, 9, 10, 1 << 53, (1 << 53) + 1, u64::MAX - 1, u64::MAX];
+        for pair in costs.windows(2) {
+            assert!(fixed_width(pair[0]) < fixed_width(pair[1]));
+        }
+        assert_eq!(fixed_width(u64::MAX).parse::<u64>().unwrap(), u64::MAX);
+    }
+}
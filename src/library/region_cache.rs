@@ -0,0 +1,244 @@
+//! Local copies of the regions read from a graph provider, see
+//! [`CachedProvider`](crate::graph_provider::cached::CachedProvider). Regions are kept in the
+//! packed format under the version of the data set they belong to, so a region already cached is
+//! never read again from the provider, and the least recently used files are dropped once the
+//! cache grows beyond its size limit. Regions of groups without a manifest aren't cached.
+//!
+//! The group read last is kept too, so a server restarted while the provider is unreachable still
+//! finds its regions.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use priority_queue::PriorityQueue;
+use tokio::sync::OnceCell;
+use crate::manifest::FileEntry;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Default size limit of the cache, 10 GiB.
+pub const DEFAULT_MAX_BYTES: u64 = 10 << 30;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionCacheConfig {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+}
+
+impl RegionCacheConfig {
+    /// Reads REGION_CACHE_DIR and REGION_CACHE_MAX_BYTES, no cache without a directory.
+    pub fn from_env() -> Result<Option<Self>> {
+        let dir = match env::var("REGION_CACHE_DIR") {
+            Ok(dir) => { PathBuf::from(dir) }
+            Err(_) => { return Ok(None) }
+        };
+        Ok(Some(Self {
+            dir,
+            max_bytes: match env::var("REGION_CACHE_MAX_BYTES") {
+                Ok(max_bytes) => { max_bytes.parse()? }
+                Err(_) => { DEFAULT_MAX_BYTES }
+            },
+        }))
+    }
+}
+
+/// Cached files by when they were last used, with the sizes they add up to.
+#[derive(Default)]
+struct CacheIndex {
+    used: PriorityQueue<String, Reverse<SystemTime>>,
+    sizes: HashMap<String, u64>,
+    total: u64,
+}
+
+impl CacheIndex {
+    fn insert(&mut self, name: String, size: u64, used: SystemTime) {
+        if let Some(previous) = self.sizes.insert(name.clone(), size) {
+            self.total -= previous;
+        }
+        self.total += size;
+        self.used.push(name, Reverse(used));
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.used.remove(name);
+        if let Some(size) = self.sizes.remove(name) {
+            self.total -= size;
+        }
+    }
+
+    /// Drops the least recently used files from the index until they fit into `max_bytes`,
+    /// returning them.
+    fn evict(&mut self, max_bytes: u64) -> Vec<String> {
+        let mut evicted = vec![];
+        while self.total > max_bytes {
+            let (name, _) = match self.used.pop() {
+                Some(oldest) => { oldest }
+                None => { break }
+            };
+            self.total -= self.sizes.remove(&name).unwrap_or(0);
+            evicted.push(name);
+        }
+        evicted
+    }
+}
+
+pub(crate) struct RegionCache {
+    config: RegionCacheConfig,
+    /// Read from the directory once, when the cache is first used, and kept up to date since.
+    index: OnceCell<Mutex<CacheIndex>>,
+}
+
+impl RegionCache {
+    pub(crate) fn new(config: RegionCacheConfig) -> Self {
+        Self {
+            config,
+            index: OnceCell::new(),
+        }
+    }
+
+    /// Files are named by the checksum of their key, which may hold any character.
+    fn file_name(key: &str) -> String {
+        FileEntry::of(key.as_bytes()).sha256
+    }
+
+    fn kept_path(&self, name: &str) -> PathBuf {
+        self.config.dir.join("latest").join(name)
+    }
+
+    async fn index(&self) -> Result<&Mutex<CacheIndex>> {
+        self.index.get_or_try_init(|| async {
+            let mut index = CacheIndex::default();
+            let mut entries = match tokio::fs::read_dir(&self.config.dir).await {
+                Ok(entries) => { entries }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => { return Ok(Mutex::new(index)) }
+                Err(err) => { Err(err)? }
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                // Only cached files are named by a checksum, partial ones and kept copies aren't.
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !metadata.is_file() || name.len() != 64 {
+                    continue;
+                }
+                index.insert(name, metadata.len(), metadata.modified()?);
+            }
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(Mutex::new(index))
+        }).await
+    }
+
+    /// Content cached under `key`, which marks it as recently used.
+    pub(crate) async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let name = Self::file_name(key);
+        let path = self.config.dir.join(&name);
+        let content = tokio::fs::read(&path).await.ok()?;
+        if let Ok(index) = self.index().await {
+            index.lock().unwrap().insert(name, content.len() as u64, SystemTime::now());
+        }
+        // Marked on disk too, for the index read after a restart.
+        if let Err(err) = touch(&path).await {
+            log::debug!("Unable to mark {} as used, details: {}", path.display(), err);
+        }
+        Some(content)
+    }
+
+    /// Caches `content` under `key`, then drops the least recently used files over the limit.
+    pub(crate) async fn put(&self, key: &str, content: &[u8]) -> Result<()> {
+        let index = self.index().await?;
+        let name = Self::file_name(key);
+        tokio::fs::create_dir_all(&self.config.dir).await?;
+        // Written aside first, so an interrupted write never passes for a cached file.
+        let partial_path = self.config.dir.join(format!("{}.{}.part", name, uuid::Uuid::new_v4()));
+        tokio::fs::write(&partial_path, content).await?;
+        tokio::fs::rename(&partial_path, self.config.dir.join(&name)).await?;
+        let evicted = {
+            let mut index = index.lock().unwrap();
+            index.insert(name, content.len() as u64, SystemTime::now());
+            index.evict(self.config.max_bytes)
+        };
+        for name in evicted {
+            let path = self.config.dir.join(name);
+            log::info!("Dropping {} from the region cache", path.display());
+            tokio::fs::remove_file(&path).await?;
+        }
+        Ok(())
+    }
+
+    /// Drops what is cached under `key`, e.g. a file found corrupt.
+    pub(crate) async fn remove(&self, key: &str) -> Result<()> {
+        let name = Self::file_name(key);
+        if let Ok(index) = self.index().await {
+            index.lock().unwrap().remove(&name);
+        }
+        match tokio::fs::remove_file(self.config.dir.join(&name)).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => { Err(err)? }
+            _ => { Ok(()) }
+        }
+    }
+
+    /// Keeps the last copy of a file, e.g. a group file, under its name.
+    pub(crate) async fn keep(&self, name: &str, content: &[u8]) -> Result<()> {
+        let path = self.kept_path(name);
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        let partial_path = path.with_extension("part");
+        tokio::fs::write(&partial_path, content).await?;
+        tokio::fs::rename(&partial_path, &path).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn kept(&self, name: &str) -> Option<Vec<u8>> {
+        tokio::fs::read(self.kept_path(name)).await.ok()
+    }
+
+    pub(crate) async fn forget(&self, name: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.kept_path(name)).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => { Err(err)? }
+            _ => { Ok(()) }
+        }
+    }
+}
+
+async fn touch(path: &Path) -> std::io::Result<()> {
+    let file = tokio::fs::OpenOptions::new().append(true).open(path).await?.into_std().await;
+    tokio::task::spawn_blocking(move || file.set_modified(SystemTime::now())).await?
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+    use crate::fixtures::TempDir;
+    use crate::region_cache::{RegionCache, RegionCacheConfig};
+
+    fn age(cache: &RegionCache, key: &str, secs: u64) {
+        let file = std::fs::File::options().append(true).open(cache.config.dir.join(RegionCache::file_name(key))).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(secs)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_files_are_dropped() {
+        let dir = TempDir::new("cache");
+        let config = RegionCacheConfig { dir: dir.path().to_path_buf(), max_bytes: 16 };
+        let cache = RegionCache::new(config.clone());
+        cache.put("v1/1", b"1,0,0,1\n").await.unwrap();
+        cache.put("v1/2", b"2,0,0,1\n").await.unwrap();
+        age(&cache, "v1/1", 120);
+        age(&cache, "v1/2", 60);
+
+        // The files are indexed by when they were last used on disk after a restart.
+        let cache = RegionCache::new(config);
+        assert_eq!(cache.get("v1/1").await.unwrap(), b"1,0,0,1\n");
+        cache.put("v2/3", b"3,0,0,1\n").await.unwrap();
+        assert!(cache.get("v1/2").await.is_none());
+        assert_eq!(cache.get("v2/3").await.unwrap(), b"3,0,0,1\n");
+        cache.remove("v1/1").await.unwrap();
+        assert!(cache.get("v1/1").await.is_none());
+        assert_eq!(cache.index().await.unwrap().lock().unwrap().total, 8);
+
+        cache.keep("group_2.json", b"{}").await.unwrap();
+        assert_eq!(cache.kept("group_2.json").await.unwrap(), b"{}");
+        cache.forget("group_2.json").await.unwrap();
+        assert!(cache.kept("group_2.json").await.is_none());
+    }
+}
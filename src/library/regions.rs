@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use async_trait::async_trait;
+use tokio::sync::OnceCell;
+use crate::graph::{Graph, RegionIdx};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Loads a region of a lazily loaded group, see [`Regions::lazy`].
+#[async_trait]
+pub(crate) trait RegionSource: Send + Sync {
+    async fn load(&self, region: RegionIdx) -> Result<Graph>;
+}
+
+/// Regions served by a server, either all loaded up front or each one on first use. Regions are
+/// shared with those derived by [`Regions::with`] and [`Regions::without`].
+pub(crate) struct Regions {
+    graphs: HashMap<RegionIdx, Arc<OnceCell<Graph>>>,
+    source: Option<Arc<dyn RegionSource>>,
+}
+
+impl Regions {
+    pub(crate) fn loaded(graphs: HashMap<RegionIdx, Graph>) -> Self {
+        Self {
+            graphs: graphs.into_iter().map(|(region, graph)| (region, Arc::new(OnceCell::new_with(Some(graph))))).collect(),
+            source: None,
+        }
+    }
+
+    /// Regions loaded from `source` the first time they are searched. Concurrent searches of a region
+    /// being loaded wait for it to be loaded once, a failed load is tried again by the next one.
+    pub(crate) fn lazy(regions: impl IntoIterator<Item=RegionIdx>, source: Box<dyn RegionSource>) -> Self {
+        Self {
+            graphs: regions.into_iter().map(|region| (region, Arc::new(OnceCell::new()))).collect(),
+            source: Some(Arc::from(source)),
+        }
+    }
+
+    /// These regions and `graph` as region `region`, e.g. one handed over by another server.
+    pub(crate) fn with(&self, region: RegionIdx, graph: Graph) -> Self {
+        let mut graphs = self.graphs.clone();
+        graphs.insert(region, Arc::new(OnceCell::new_with(Some(graph))));
+        Self { graphs, source: self.source.clone() }
+    }
+
+    /// These regions but `region`, e.g. one handed over to another server.
+    pub(crate) fn without(&self, region: RegionIdx) -> Self {
+        let mut graphs = self.graphs.clone();
+        graphs.remove(&region);
+        Self { graphs, source: self.source.clone() }
+    }
+
+    /// Whether the region is served here, loaded or not.
+    pub(crate) fn contains_key(&self, region: &RegionIdx) -> bool {
+        self.graphs.contains_key(region)
+    }
+
+    /// Every region served here, loaded or not.
+    pub(crate) fn keys(&self) -> impl Iterator<Item=&RegionIdx> {
+        self.graphs.keys()
+    }
+
+    /// Regions loaded so far.
+    pub(crate) fn iter(&self) -> impl Iterator<Item=(&RegionIdx, &Graph)> {
+        self.graphs.iter().filter_map(|(region, graph)| Some((region, graph.get()?)))
+    }
+
+    pub(crate) fn get_loaded(&self, region: &RegionIdx) -> Option<&Graph> {
+        self.graphs.get(region)?.get()
+    }
+
+    /// The region, loaded first if it isn't yet. `None` if it isn't served here.
+    pub(crate) async fn get(&self, region: &RegionIdx) -> Result<Option<&Graph>> {
+        let graph = match self.graphs.get(region) {
+            Some(graph) => { graph }
+            None => { return Ok(None) }
+        };
+        match &self.source {
+            Some(source) => {
+                graph.get_or_try_init(|| async {
+                    log::info!("Loading region {} on first use", region);
+                    source.load(*region).await
+                }).await.map(Some)
+            }
+            None => { Ok(graph.get()) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use async_trait::async_trait;
+    use crate::graph::{Graph, RegionIdx};
+    use crate::regions::{RegionSource, Regions};
+
+    /// Fails the first load, counts the others.
+    struct Flaky(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl RegionSource for Flaky {
+        async fn load(&self, region: RegionIdx) -> super::Result<Graph> {
+            if self.0.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err("Bucket unavailable")?
+            }
+            tokio::task::yield_now().await;
+            Ok(Graph::new(HashMap::new(), HashMap::new(), region))
+        }
+    }
+
+    #[tokio::test]
+    async fn regions_are_loaded_once_on_first_use() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let regions = Regions::lazy([1, 2], Box::new(Flaky(loads.clone())));
+        assert!(regions.contains_key(&2));
+        assert_eq!(regions.iter().count(), 0);
+        assert!(regions.get(&1).await.is_err());
+        let (first, second) = tokio::join!(regions.get(&1), regions.get(&1));
+        assert_eq!(first.unwrap().unwrap().region_idx, 1);
+        assert_eq!(second.unwrap().unwrap().region_idx, 1);
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert!(regions.get(&3).await.unwrap().is_none());
+        assert_eq!(regions.iter().map(|(region, _)| *region).collect::<Vec<_>>(), vec![1]);
+        assert!(regions.get_loaded(&2).is_none());
+    }
+
+    #[tokio::test]
+    async fn derived_regions_share_the_loaded_ones() {
+        let loads = Arc::new(AtomicUsize::new(1));
+        let regions = Regions::lazy([1, 2], Box::new(Flaky(loads.clone())));
+        regions.get(&1).await.unwrap();
+        let moved = regions.without(2).with(3, Graph::new(HashMap::new(), HashMap::new(), 3));
+        let mut keys: Vec<_> = moved.keys().copied().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![1, 3]);
+        assert!(moved.get_loaded(&1).is_some());
+        assert_eq!(moved.get(&3).await.unwrap().unwrap().region_idx, 3);
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert!(regions.contains_key(&2));
+    }
+}
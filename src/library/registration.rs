@@ -0,0 +1,111 @@
+//! Registers a server in `server_info` with the address other servers reach it at and the regions
+//! it serves, so operators don't have to. The registration is published again whenever the
+//! regions change, e.g. after a reload, and every interval, which also restores one lost from
+//! redis.
+
+use std::env;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use crate::graph::RegionIdx;
+use crate::redis_connector::{RedisConnector, ServerInfo};
+use crate::reload::DatasetHandle;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Interval at which a server registers itself again unless REGISTRATION_INTERVAL_SECS is set.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+/// Interval at which the served regions are checked for changes.
+const CHANGE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RegistrationConfig {
+    /// Address registered for the server, none if it can't be told.
+    addr: Option<String>,
+    interval: Duration,
+}
+
+impl RegistrationConfig {
+    /// Reads ADVERTISE_ADDR, falling back to LISTEN_ADDR and GRPC_LISTEN_ADDR unless they listen
+    /// on every interface, and REGISTRATION_INTERVAL_SECS.
+    pub(crate) fn from_env() -> Result<Self> {
+        let addr = match env::var("ADVERTISE_ADDR") {
+            Ok(addr) => { Some(addr) }
+            Err(_) => {
+                ["LISTEN_ADDR", "GRPC_LISTEN_ADDR"].iter()
+                    .filter_map(|name| env::var(name).ok())
+                    .find(|addr| is_reachable(addr))
+            }
+        };
+        Ok(Self {
+            addr,
+            interval: match env::var("REGISTRATION_INTERVAL_SECS") {
+                Ok(secs) => { Duration::from_secs(secs.parse()?) }
+                Err(_) => { DEFAULT_INTERVAL }
+            },
+        })
+    }
+}
+
+/// Whether other servers can connect to `addr`, which isn't the case for wildcard addresses.
+fn is_reachable(addr: &str) -> bool {
+    let host_port = addr.split("://").last().unwrap_or(addr);
+    let host = match host_port.rfind(':') {
+        Some(colon) => { &host_port[..colon] }
+        None => { host_port }
+    };
+    !matches!(host, "0.0.0.0" | "[::]" | "::" | "*" | "")
+}
+
+fn served_regions(dataset: &DatasetHandle) -> Vec<RegionIdx> {
+    let mut regions: Vec<RegionIdx> = dataset.current().graphs.keys().copied().collect();
+    regions.sort_unstable();
+    regions
+}
+
+/// Registers server `server_id` until aborted, none is registered without an address.
+pub(crate) fn spawn_registration(redis_connector: RedisConnector, server_id: usize, config: RegistrationConfig, dataset: DatasetHandle) -> Option<JoinHandle<()>> {
+    let addr = match config.addr {
+        Some(addr) => { addr }
+        None => {
+            log::info!("Not registering in server_info, set ADVERTISE_ADDR to the address other servers reach this one at");
+            return None;
+        }
+    };
+    log::info!("Registering in server_info at {}", addr);
+    Some(tokio::task::spawn(async move {
+        let mut ticker = tokio::time::interval(CHANGE_CHECK_INTERVAL);
+        let mut registered: Option<(Vec<RegionIdx>, Instant)> = None;
+        loop {
+            ticker.tick().await;
+            let regions = served_regions(&dataset);
+            let due = match &registered {
+                Some((previous, at)) => { *previous != regions || at.elapsed() >= config.interval }
+                None => { true }
+            };
+            if !due {
+                continue;
+            }
+            let server_info = ServerInfo::new(server_id, addr.clone().into(), regions.clone());
+            match redis_connector.register_server(&server_info).await {
+                Ok(()) => { registered = Some((regions, Instant::now())) }
+                Err(err) => { log::warn!("Unable to register server {}, details: {}", server_id, err) }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::registration::is_reachable;
+
+    #[test]
+    fn wildcard_addresses_are_not_registered() {
+        assert!(is_reachable("tcp://pathfinder-3:5555"));
+        assert!(is_reachable("10.0.0.7:50051"));
+        assert!(is_reachable("[fd00::7]:5555"));
+        assert!(!is_reachable("0.0.0.0:5555"));
+        assert!(!is_reachable("tcp://0.0.0.0:5555"));
+        assert!(!is_reachable("[::]:50051"));
+        assert!(!is_reachable("tcp://*:5555"));
+    }
+}
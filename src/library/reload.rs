@@ -0,0 +1,234 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use crate::DatasetLoader;
+use crate::adjacency::RegionBorders;
+use crate::graph::RegionIdx;
+use crate::graph_provider::GroupInfo;
+use crate::manifest;
+use crate::policy::PolicyEngine;
+use crate::redis_connector::RedisConnector;
+use crate::regions::Regions;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Reads DATASET_RELOAD_INTERVAL_SECS, how often the manifest of the group is checked for a new
+/// version of the data set. Never without it.
+pub(crate) fn interval_from_env() -> Result<Option<Duration>> {
+    match env::var("DATASET_RELOAD_INTERVAL_SECS") {
+        Ok(secs) => { Ok(Some(Duration::from_secs(secs.parse()?))) }
+        Err(_) => { Ok(None) }
+    }
+}
+
+/// Regions a server searches, with the routing policies whose heuristics were built from them.
+pub(crate) struct Dataset {
+    pub(crate) version: Option<String>,
+    pub(crate) graphs: Arc<Regions>,
+    pub(crate) policies: PolicyEngine,
+}
+
+/// Regions handed over to this server or away from it since it started, see [`crate::migration`].
+/// Reloads keep them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Handovers {
+    taken: BTreeSet<RegionIdx>,
+    shed: BTreeSet<RegionIdx>,
+    /// Counts the handovers, so a reload prepared before one isn't swapped in after it.
+    generation: u64,
+}
+
+impl Handovers {
+    /// Regions served of `group_regions`, those the data set assigns to the group.
+    pub(crate) fn served(&self, group_regions: impl IntoIterator<Item=RegionIdx>) -> BTreeSet<RegionIdx> {
+        group_regions.into_iter()
+            .chain(self.taken.iter().copied())
+            .filter(|region| !self.shed.contains(region))
+            .collect()
+    }
+}
+
+/// Dataset currently served, swapped as a whole once a new version is loaded. Hops are served with
+/// the dataset current when they were dispatched, so hops in flight finish on the regions they
+/// started on, which are dropped with the last of them.
+#[derive(Clone)]
+pub(crate) struct DatasetHandle {
+    current: Arc<RwLock<Arc<Dataset>>>,
+    handovers: Arc<Mutex<Handovers>>,
+}
+
+impl DatasetHandle {
+    pub(crate) fn new(dataset: Dataset) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(dataset))),
+            handovers: Arc::new(Mutex::new(Handovers::default())),
+        }
+    }
+
+    pub(crate) fn current(&self) -> Arc<Dataset> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub(crate) fn handovers(&self) -> Handovers {
+        self.handovers.lock().unwrap().clone()
+    }
+
+    /// Swaps in the dataset `hand_over` derives from the current one, serving `region` from now on
+    /// if `taken`, else no more. Returns the dataset replaced.
+    pub(crate) fn hand_over(&self, region: RegionIdx, taken: bool, hand_over: impl FnOnce(&Dataset) -> Result<Dataset>) -> Result<Arc<Dataset>> {
+        let mut handovers = self.handovers.lock().unwrap();
+        let previous = self.current();
+        let dataset = hand_over(&previous)?;
+        let Handovers { taken: taken_regions, shed, .. } = &mut *handovers;
+        let (recorded, other) = if taken { (taken_regions, shed) } else { (shed, taken_regions) };
+        // Taking back a region shed earlier undoes the handover.
+        if !other.remove(&region) {
+            recorded.insert(region);
+        }
+        handovers.generation += 1;
+        *self.current.write().unwrap() = Arc::new(dataset);
+        Ok(previous)
+    }
+
+    /// Swaps in a new version of the data set loaded for `handovers`, unless regions were handed
+    /// over since.
+    pub(crate) fn swap(&self, dataset: Dataset, handovers: &Handovers) -> Result<()> {
+        let current = self.handovers.lock().unwrap();
+        if current.generation != handovers.generation {
+            Err("Regions were handed over while the data set was loaded")?
+        }
+        *self.current.write().unwrap() = Arc::new(dataset);
+        Ok(())
+    }
+}
+
+/// Loads and swaps in the regions of the group once its manifest announces a new version, unless
+/// a group still served announces a third one. Live weight and topology updates applied to the
+/// previous version are carried over as far as they fit the new one. With LAZY_REGIONS the new
+/// version is swapped in unloaded, its regions are loaded on first use as stored. Regions handed
+/// over are loaded in their new version by the server they were handed to.
+async fn reload(loader: &Arc<DatasetLoader>,
+                dataset: &DatasetHandle,
+                redis_connector: &RedisConnector,
+                group_id: usize,
+                lease_holder: &str) -> Result<()> {
+    let group_info = loader.group_info(group_id).await?;
+    let current = dataset.current();
+    let version = match loader.dataset_version() {
+        Some(version) if Some(&version) != current.version.as_ref() => { version }
+        _ => { return Ok(()) }
+    };
+    // Regions handed over since the server started stay where they were handed.
+    let handovers = dataset.handovers();
+    let regions = handovers.served(group_info.regions.iter().copied());
+    if regions != current.graphs.keys().copied().collect() {
+        Err(format!("Version {} of the data set assigns other regions to group {}, restart the server to serve them", version, group_id))?
+    }
+    log::info!("Loading version {} of the data set", version);
+    let served = GroupInfo { regions: regions.into_iter().collect(), ..group_info };
+    let graphs = loader.load_regions(&served, redis_connector, lease_holder).await?;
+    // Only the process serving the group registers it, a standby takes over what the primary registered.
+    let registering = redis_connector.get_lease_holder(group_id).await?.as_deref() == Some(lease_holder);
+    // Announced along with the check, a reload failing past it is tried again at the next interval.
+    manifest::announce_version(redis_connector, group_id, &version, current.version.as_deref(), registering).await?;
+    if registering {
+        for (region_id, graph) in graphs.iter() {
+            redis_connector.set_region(graph, *region_id).await?;
+            redis_connector.set_region_borders(&RegionBorders::new(graph)).await?;
+        }
+    }
+    // Last, so that few updates land on the previous version after they were carried over.
+    for (region_id, graph) in graphs.iter() {
+        let dropped = current.graphs.get_loaded(region_id).map_or(0, |previous| graph.carry_patches(previous));
+        if dropped > 0 {
+            log::warn!("{} live updates of region {} don't fit version {} of the data set, dropped them", dropped, region_id, version);
+        }
+    }
+    dataset.swap(loader.build(Some(version.clone()), graphs)?, &handovers)?;
+    log::info!("Serving version {} of the data set", version);
+    Ok(())
+}
+
+/// Checks the manifest of the group every `interval`, see [`reload`]. A failed reload keeps the
+/// current version and is tried again, e.g. once the upload of the new version is complete.
+pub(crate) fn spawn_reloads(loader: Arc<DatasetLoader>,
+                            dataset: DatasetHandle,
+                            redis_connector: RedisConnector,
+                            group_id: usize,
+                            lease_holder: String,
+                            interval: Duration) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(err) = reload(&loader, &dataset, &redis_connector, group_id, &lease_holder).await {
+                log::warn!("Unable to reload the regions of group {}, details: {}", group_id, err);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use crate::fanout::FanoutPolicy;
+    use crate::graph::{Graph, SearchLimits, WeightScale};
+    use crate::heuristic::HeuristicKind;
+    use crate::policy::{ExecutionParams, PolicyConfig, PolicyEngine};
+    use crate::regions::Regions;
+    use crate::reload::{Dataset, DatasetHandle};
+
+    fn dataset(version: &str, regions: &[u32]) -> Dataset {
+        let graphs: HashMap<_, _> = regions.iter().map(|region| (*region, Graph::new(HashMap::new(), HashMap::new(), *region))).collect();
+        let defaults = ExecutionParams {
+            heuristic: HeuristicKind::Zero.build([]),
+            strategy: None,
+            search_limits: SearchLimits::default(),
+            fanout: FanoutPolicy::default(),
+            max_cost: None,
+            max_region_hops: None,
+            use_cost_cache: true,
+            bidirectional: false,
+        };
+        Dataset {
+            version: Some(version.to_string()),
+            policies: PolicyEngine::new(&PolicyConfig::default(), defaults, WeightScale::default(), graphs.values()).unwrap(),
+            graphs: Arc::new(Regions::loaded(graphs)),
+        }
+    }
+
+    #[test]
+    fn swapping_keeps_datasets_in_use() {
+        let handle = DatasetHandle::new(dataset("v1", &[1]));
+        let in_flight = handle.current();
+        handle.swap(dataset("v2", &[1, 2]), &handle.handovers()).unwrap();
+        assert_eq!(in_flight.version.as_deref(), Some("v1"));
+        assert_eq!(in_flight.graphs.keys().count(), 1);
+        let current = handle.current();
+        assert_eq!((current.version.as_deref(), current.graphs.keys().count()), (Some("v2"), 2));
+    }
+
+    #[test]
+    fn reloads_keep_handovers() {
+        let handle = DatasetHandle::new(dataset("v1", &[1, 2]));
+        handle.hand_over(3, true, |current| Ok(Dataset { graphs: Arc::new(current.graphs.with(3, Graph::new(HashMap::new(), HashMap::new(), 3))), ..dataset("v1", &[]) })).unwrap();
+        let previous = handle.hand_over(1, false, |current| Ok(Dataset { graphs: Arc::new(current.graphs.without(1)), ..dataset("v1", &[]) })).unwrap();
+        assert_eq!(previous.graphs.keys().count(), 3);
+
+        // The group still has regions 1 and 2 in the next version, the server keeps serving 2 and 3.
+        let handovers = handle.handovers();
+        let served = handovers.served([1, 2]);
+        assert_eq!(served, handle.current().graphs.keys().copied().collect());
+        assert_eq!(served.into_iter().collect::<Vec<_>>(), vec![2, 3]);
+        // Region 1 handed back meanwhile, the version loaded without it isn't swapped in.
+        handle.hand_over(1, true, |current| Ok(Dataset { graphs: Arc::new(current.graphs.with(1, Graph::new(HashMap::new(), HashMap::new(), 1))), ..dataset("v1", &[]) })).unwrap();
+        assert!(handle.swap(dataset("v2", &[2, 3]), &handovers).is_err());
+        assert_eq!(handle.handovers().served([1, 2]).into_iter().collect::<Vec<_>>(), vec![1, 2, 3]);
+        handle.swap(dataset("v2", &[1, 2, 3]), &handle.handovers()).unwrap();
+        assert_eq!(handle.current().version.as_deref(), Some("v2"));
+    }
+}
@@ -0,0 +1,193 @@
+//! Picks the server a hop is sent to among the servers hosting its region. Groups may share
+//! regions, each of their servers registering itself as a replica of them, and hops are spread
+//! over the replicas which are alive.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use redis::{ErrorKind, RedisError, RedisResult};
+use tokio::sync::broadcast;
+use crate::graph::RegionIdx;
+use crate::queues::QueueReport;
+use crate::redis_connector::{NetworkInfo, RedisConnector, ServerUpdate};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReplicaSelection {
+    /// Each replica in turn.
+    RoundRobin,
+    /// The replica with the fullest work queue last, by the queue reports of the servers.
+    LeastLoaded,
+}
+
+impl ReplicaSelection {
+    /// Reads REPLICA_SELECTION.
+    pub(crate) fn from_env() -> Result<Self> {
+        match env::var("REPLICA_SELECTION").as_deref() {
+            Err(_) | Ok("round_robin") => { Ok(ReplicaSelection::RoundRobin) }
+            Ok("least_loaded") => { Ok(ReplicaSelection::LeastLoaded) }
+            Ok(name) => { Err(format!("Unknown replica selection {}", name))? }
+        }
+    }
+}
+
+/// Share of its work queue a server filled, servers which didn't report count as full.
+fn load(report: &Option<QueueReport>) -> f64 {
+    match report {
+        Some(report) => { (report.dispatched + report.requeued) as f64 / report.capacity.max(1) as f64 }
+        None => { f64::INFINITY }
+    }
+}
+
+/// Index of the least loaded of the servers `reports` belong to, ties going to the first one from
+/// `start` on, so equally loaded replicas take turns.
+fn least_loaded(reports: &[Option<QueueReport>], start: usize) -> usize {
+    (0..reports.len())
+        .map(|offset| (start + offset) % reports.len())
+        .fold(None, |best: Option<usize>, index| match best {
+            Some(best) if load(&reports[best]) <= load(&reports[index]) => { Some(best) }
+            _ => { Some(index) }
+        })
+        .unwrap_or_default()
+}
+
+/// How long the replicas of a region are picked from without reading them again, unless a server
+/// joins or leaves meanwhile. Replicas dying or taking over a region are noticed this late.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Replicas of a region hops are spread over, as read at `read_at`.
+struct Replicas {
+    /// The live servers hosting the region, or all of them if none is alive.
+    candidates: Vec<usize>,
+    /// Queue reports of the candidates, only read with [`ReplicaSelection::LeastLoaded`].
+    reports: Vec<Option<QueueReport>>,
+    read_at: Instant,
+}
+
+type Cache = Mutex<HashMap<RegionIdx, Arc<Replicas>>>;
+
+#[derive(Clone)]
+pub(crate) struct ReplicaSelector {
+    redis_connector: RedisConnector,
+    selection: ReplicaSelection,
+    next: Arc<AtomicUsize>,
+    replicas: Arc<Cache>,
+}
+
+impl ReplicaSelector {
+    /// Forgets the replicas read whenever `network_info` has a server join or leave.
+    pub(crate) fn new(redis_connector: RedisConnector, network_info: &NetworkInfo, selection: ReplicaSelection) -> Self {
+        let replicas = Arc::new(Cache::default());
+        tokio::task::spawn(follow(Arc::downgrade(&replicas), network_info.subscribe()));
+        Self {
+            redis_connector,
+            selection,
+            next: Arc::new(AtomicUsize::new(0)),
+            replicas,
+        }
+    }
+
+    /// Server to send a hop into `region_id` to. A region with a single server is sent to it
+    /// whether alive or not, as are regions none of whose replicas is alive, so their hops wait
+    /// for a standby as usual.
+    pub(crate) async fn pick(&self, region_id: RegionIdx) -> RedisResult<usize> {
+        let replicas = self.replicas(region_id).await?;
+        let turn = self.next.fetch_add(1, Ordering::Relaxed) % replicas.candidates.len();
+        match self.selection {
+            ReplicaSelection::RoundRobin => { Ok(replicas.candidates[turn]) }
+            ReplicaSelection::LeastLoaded => { Ok(replicas.candidates[least_loaded(&replicas.reports, turn)]) }
+        }
+    }
+
+    /// Reads the replicas of `region_id` again when next picked from, e.g. once it was handed over.
+    pub(crate) fn forget(&self, region_id: RegionIdx) {
+        self.replicas.lock().unwrap().remove(&region_id);
+    }
+
+    async fn replicas(&self, region_id: RegionIdx) -> RedisResult<Arc<Replicas>> {
+        let cached = self.replicas.lock().unwrap().get(&region_id).filter(|replicas| replicas.read_at.elapsed() < REFRESH_INTERVAL).cloned();
+        if let Some(replicas) = cached {
+            return Ok(replicas);
+        }
+        let replicas = Arc::new(self.read(region_id).await?);
+        self.replicas.lock().unwrap().insert(region_id, replicas.clone());
+        Ok(replicas)
+    }
+
+    async fn read(&self, region_id: RegionIdx) -> RedisResult<Replicas> {
+        let read_at = Instant::now();
+        let servers = self.redis_connector.get_region_servers(region_id).await?;
+        let candidates = match servers.len() {
+            0 => { return Err(RedisError::from((ErrorKind::TypeError, "No server hosts the region", region_id.to_string()))) }
+            1 => { servers }
+            _ => {
+                let live = self.redis_connector.get_live_servers(&servers).await?;
+                if live.is_empty() { servers } else { live }
+            }
+        };
+        let reports = match self.selection {
+            ReplicaSelection::LeastLoaded if candidates.len() > 1 => { self.redis_connector.get_queue_reports(&candidates).await? }
+            _ => { vec![None; candidates.len()] }
+        };
+        Ok(Replicas { candidates, reports, read_at })
+    }
+}
+
+/// Forgets the replicas read whenever a server joins, moves or leaves, until the selector is dropped.
+async fn follow(replicas: Weak<Cache>, mut updates: broadcast::Receiver<ServerUpdate>) {
+    loop {
+        let update = updates.recv().await;
+        let replicas = match replicas.upgrade() {
+            Some(replicas) => { replicas }
+            None => { return }
+        };
+        if let Err(broadcast::error::RecvError::Closed) = update {
+            return;
+        }
+        replicas.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use crate::queues::QueueReport;
+    use crate::redis_connector::{NetworkInfo, ServerInfo, ServerUpdate};
+    use crate::replicas::{follow, least_loaded, Cache, Replicas};
+
+    fn report(dispatched: usize, capacity: usize) -> Option<QueueReport> {
+        Some(QueueReport { dispatched, capacity, requeued: 0, saturations: 0, saturated_ms: 0 })
+    }
+
+    #[test]
+    fn the_least_loaded_replica_is_picked() {
+        let reports = vec![report(6, 8), None, report(3, 8), report(4, 16)];
+        assert_eq!(least_loaded(&reports, 0), 3);
+        // Equally loaded replicas take turns.
+        let reports = vec![report(2, 8), report(1, 4), None];
+        assert_eq!(least_loaded(&reports, 0), 0);
+        assert_eq!(least_loaded(&reports, 1), 1);
+        assert_eq!(least_loaded(&reports, 2), 0);
+    }
+
+    #[tokio::test]
+    async fn replicas_are_read_again_once_servers_change() {
+        let network_info = NetworkInfo::new(Arc::new(tokio::sync::RwLock::new(BTreeMap::new())));
+        let replicas = Arc::new(Cache::default());
+        tokio::task::spawn(follow(Arc::downgrade(&replicas), network_info.subscribe()));
+        let cache = |region| { replicas.lock().unwrap().insert(region, Arc::new(Replicas { candidates: vec![1], reports: vec![None], read_at: Instant::now() })); };
+        cache(1);
+        cache(2);
+        network_info.apply(ServerUpdate::Registered(ServerInfo::new(3, "tcp://127.0.0.1:5555".into(), vec![1]))).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !replicas.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+    }
+}
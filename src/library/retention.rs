@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use crate::redis_connector::RedisConnector;
+use crate::slo::DEFAULT_CLASS;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// How long results are kept in redis after they are sent, per priority class.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RetentionConfig {
+    /// Classes not listed fall back to `default`, results are not kept if it isn't listed either.
+    pub(crate) ttls: HashMap<String, Duration>,
+    /// How often the index of stored results is cleaned of expired ones.
+    pub(crate) cleanup_interval: Duration,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            ttls: HashMap::new(),
+            cleanup_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetentionConfig {
+    /// Parses TTLs given as `class=secs`, separated by commas (e.g. `default=3600,batch=86400`).
+    pub(crate) fn parse_ttls(ttls: &str) -> Result<HashMap<String, Duration>> {
+        let mut parsed = HashMap::new();
+        for entry in ttls.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (class, secs) = entry.split_once('=').ok_or(format!("Invalid result retention {}", entry))?;
+            let ttl = Duration::from_secs(secs.parse()?);
+            if ttl.is_zero() {
+                Err(format!("Result retention of class {} has to be positive", class))?
+            }
+            parsed.insert(class.to_string(), ttl);
+        }
+        Ok(parsed)
+    }
+
+    /// Reads RESULT_RETENTION and RESULT_CLEANUP_INTERVAL_SECS.
+    pub(crate) fn from_env() -> Result<Self> {
+        let mut config = RetentionConfig::default();
+        if let Ok(ttls) = env::var("RESULT_RETENTION") {
+            config.ttls = Self::parse_ttls(&ttls)?;
+        }
+        if let Ok(interval) = env::var("RESULT_CLEANUP_INTERVAL_SECS") {
+            config.cleanup_interval = Duration::from_secs(interval.parse()?);
+        }
+        Ok(config)
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.ttls.is_empty()
+    }
+
+    /// How long results of `class` are kept, `None` if they aren't.
+    pub(crate) fn ttl(&self, class: Option<&str>) -> Option<Duration> {
+        class.and_then(|class| self.ttls.get(class))
+            .or_else(|| self.ttls.get(DEFAULT_CLASS))
+            .copied()
+    }
+}
+
+/// Volume of the results store after a cleanup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct StoredResults {
+    pub(crate) removed: u64,
+    pub(crate) results: u64,
+    pub(crate) bytes: u64,
+}
+
+/// Removes expired results from the index every `interval` and logs the volume stored. Redis expires
+/// the results themselves, every server may run a cleaner.
+pub(crate) fn spawn_cleaner(redis_connector: RedisConnector, interval: Duration) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match redis_connector.clean_results().await {
+                Ok(stored) => {
+                    log::info!("Storing {} results in {} bytes, {} expired since the last cleanup", stored.results, stored.bytes, stored.removed)
+                }
+                Err(err) => { log::warn!("Unable to clean up stored results, details: {}", err) }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use crate::retention::RetentionConfig;
+
+    #[test]
+    fn ttl_per_class() {
+        let config = RetentionConfig {
+            ttls: RetentionConfig::parse_ttls("default=3600, batch=86400").unwrap(),
+            ..RetentionConfig::default()
+        };
+        assert_eq!(config.ttl(Some("batch")), Some(Duration::from_secs(86400)));
+        assert_eq!(config.ttl(Some("interactive")), Some(Duration::from_secs(3600)));
+        assert_eq!(config.ttl(None), Some(Duration::from_secs(3600)));
+
+        let config = RetentionConfig { ttls: HashMap::from([("batch".to_string(), Duration::from_secs(60))]), ..RetentionConfig::default() };
+        assert_eq!(config.ttl(None), None);
+        assert!(!RetentionConfig::default().is_enabled());
+        assert!(RetentionConfig::parse_ttls("batch").is_err());
+        assert!(RetentionConfig::parse_ttls("batch=0").is_err());
+    }
+}
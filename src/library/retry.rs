@@ -0,0 +1,247 @@
+use std::env;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::time::Duration;
+use rand::Rng;
+use redis::RedisError;
+use crate::node_connector::ConnectionError;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Retries of hops whose serving failed with a transient error, such as a redis hiccup or a peer
+/// not registered yet, waiting exponentially longer between attempts. Hops failing for good are
+/// dead-lettered once the retries are exhausted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ServeRetryPolicy {
+    /// Attempts after the first one.
+    pub(crate) retries: u32,
+    /// Wait before the first retry, doubled for every further one.
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for ServeRetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+impl ServeRetryPolicy {
+    /// Reads SERVE_RETRIES, SERVE_BACKOFF_MS and SERVE_MAX_BACKOFF_MS.
+    pub(crate) fn from_env() -> Result<Self> {
+        let defaults = ServeRetryPolicy::default();
+        let millis = |name, default: Duration| -> Result<Duration> {
+            match env::var(name) {
+                Ok(millis) => { Ok(Duration::from_millis(millis.parse()?)) }
+                Err(_) => { Ok(default) }
+            }
+        };
+        Ok(Self {
+            retries: match env::var("SERVE_RETRIES") {
+                Ok(retries) => { retries.parse()? }
+                Err(_) => { defaults.retries }
+            },
+            backoff: millis("SERVE_BACKOFF_MS", defaults.backoff)?,
+            max_backoff: millis("SERVE_MAX_BACKOFF_MS", defaults.max_backoff)?,
+        })
+    }
+
+    pub(crate) fn delay(&self, retry: u32) -> Duration {
+        let delay = self.backoff.saturating_mul(2u32.saturating_pow(retry)).min(self.max_backoff);
+        // Spreads the retries of workers hit by the same outage.
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Wait after the first of consecutive listener failures, doubled for every further one.
+const LISTENER_BACKOFF: Duration = Duration::from_millis(10);
+/// Longest wait between reads of a listener that keeps failing.
+const LISTENER_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Backs a server off a listener failing over and over, e.g. while its redis or its socket is
+/// gone, instead of reading it again at once. A request read resets it.
+#[derive(Debug, Default)]
+pub(crate) struct ListenerBackoff {
+    failures: u32,
+}
+
+impl ListenerBackoff {
+    /// Counts a failure, returns how long to wait before reading the listener again.
+    pub(crate) fn failed(&mut self) -> Duration {
+        let delay = LISTENER_BACKOFF.saturating_mul(2u32.saturating_pow(self.failures)).min(LISTENER_MAX_BACKOFF);
+        self.failures = self.failures.saturating_add(1);
+        delay
+    }
+
+    /// Consecutive failures so far.
+    pub(crate) fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// Restarts of a failed listener in a row after which a server shuts down unless
+/// DISPATCH_MAX_RESTARTS says otherwise.
+const DEFAULT_MAX_RESTARTS: u32 = 5;
+
+/// How a server recovers from a failure it can't work around by reading its listener again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Recovery {
+    /// Reopens the listener, e.g. rebinding its socket.
+    Restart,
+    /// Gives up on what failed and goes on.
+    Skip,
+    /// Stops taking requests and shuts down as on SIGTERM.
+    Shutdown,
+    /// Panics the process, the last resort for deployments relying on being restarted.
+    Panic,
+}
+
+/// Recovery from listeners failing at the protocol level, e.g. a broken ZMQ socket, and from hops
+/// no worker is left to take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FailurePolicy {
+    recovery: Recovery,
+    /// Restarts of the listener in a row before shutting down instead.
+    max_restarts: u32,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> Self {
+        Self {
+            recovery: Recovery::Restart,
+            max_restarts: DEFAULT_MAX_RESTARTS,
+        }
+    }
+}
+
+impl FailurePolicy {
+    /// Reads DISPATCH_FAILURE_POLICY and DISPATCH_MAX_RESTARTS.
+    pub(crate) fn from_env() -> Result<Self> {
+        let defaults = FailurePolicy::default();
+        Ok(Self {
+            recovery: match env::var("DISPATCH_FAILURE_POLICY").as_deref() {
+                Err(_) | Ok("restart") => { Recovery::Restart }
+                Ok("skip") => { Recovery::Skip }
+                Ok("shutdown") => { Recovery::Shutdown }
+                Ok("panic") => { Recovery::Panic }
+                Ok(name) => { Err(format!("Unknown dispatch failure policy {}", name))? }
+            },
+            max_restarts: match env::var("DISPATCH_MAX_RESTARTS") {
+                Ok(restarts) => { restarts.parse()? }
+                Err(_) => { defaults.max_restarts }
+            },
+        })
+    }
+
+    /// Recovery from a listener failure after `restarts` restarts in a row.
+    pub(crate) fn listener_failed(&self, restarts: u32) -> Recovery {
+        match self.recovery {
+            Recovery::Restart if restarts >= self.max_restarts => { Recovery::Shutdown }
+            recovery => { recovery }
+        }
+    }
+
+    /// Recovery from a hop no worker is left to take. Workers aren't restarted, so the server
+    /// shuts down instead.
+    pub(crate) fn dispatch_failed(&self) -> Recovery {
+        match self.recovery {
+            Recovery::Restart => { Recovery::Shutdown }
+            recovery => { recovery }
+        }
+    }
+}
+
+/// Whether `err`, or an error it was caused by, may go away when trying again: lost or refused
+/// connections, timeouts, redis loading its data set and servers which aren't registered yet.
+pub(crate) fn is_transient(err: &(dyn Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if let Some(err) = err.downcast_ref::<RedisError>() {
+            if err.is_io_error() || err.is_timeout() || err.is_connection_refusal() || err.is_connection_dropped()
+                || matches!(err.kind(), redis::ErrorKind::BusyLoadingError | redis::ErrorKind::TryAgain | redis::ErrorKind::MasterDown) {
+                return true;
+            }
+        }
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            if matches!(err.kind(), ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected | ErrorKind::BrokenPipe | ErrorKind::TimedOut | ErrorKind::Interrupted | ErrorKind::UnexpectedEof) {
+                return true;
+            }
+        }
+        if let Some(ConnectionError::TargetDoesNotExist(_)) = err.downcast_ref::<ConnectionError>() {
+            return true;
+        }
+        if err.is::<tokio::time::error::Elapsed>() {
+            return true;
+        }
+        current = err.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+    use redis::RedisError;
+    use crate::node_connector::ConnectionError;
+    use crate::retry::{is_transient, FailurePolicy, ListenerBackoff, Recovery, ServeRetryPolicy};
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        let dropped: BoxedError = RedisError::from(io::Error::new(io::ErrorKind::ConnectionReset, "reset")).into();
+        assert!(is_transient(&*dropped));
+        let unregistered: BoxedError = ConnectionError::TargetDoesNotExist(4).into();
+        assert!(is_transient(&*unregistered));
+        let refused: BoxedError = io::Error::new(io::ErrorKind::ConnectionRefused, "refused").into();
+        assert!(is_transient(&*refused));
+
+        let malformed: BoxedError = RedisError::from((redis::ErrorKind::TypeError, "Response was of incompatible type")).into();
+        assert!(!is_transient(&*malformed));
+        let unreachable: BoxedError = "No route leads from the source to the target".into();
+        assert!(!is_transient(&*unreachable));
+    }
+
+    #[test]
+    fn delays_grow_up_to_the_maximum() {
+        let policy = ServeRetryPolicy { retries: 5, backoff: Duration::from_millis(100), max_backoff: Duration::from_millis(500) };
+        let first = policy.delay(0);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+        let third = policy.delay(2);
+        assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+        assert!(policy.delay(30) <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn failing_listeners_are_read_less_and_less_often() {
+        let mut backoff = ListenerBackoff::default();
+        let delays: Vec<_> = (0..4).map(|_| backoff.failed().as_millis()).collect();
+        assert_eq!(delays, vec![10, 20, 40, 80]);
+        assert_eq!((0..20).map(|_| backoff.failed()).last(), Some(Duration::from_secs(5)));
+        backoff.reset();
+        assert_eq!(backoff.failed(), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn restarts_give_way_to_a_shutdown() {
+        let policy = FailurePolicy { recovery: Recovery::Restart, max_restarts: 2 };
+        assert_eq!(policy.listener_failed(0), Recovery::Restart);
+        assert_eq!(policy.listener_failed(1), Recovery::Restart);
+        assert_eq!(policy.listener_failed(2), Recovery::Shutdown);
+        // Workers can't be restarted.
+        assert_eq!(policy.dispatch_failed(), Recovery::Shutdown);
+        let policy = FailurePolicy { recovery: Recovery::Skip, max_restarts: 0 };
+        assert_eq!(policy.listener_failed(10), Recovery::Skip);
+        assert_eq!(policy.dispatch_failed(), Recovery::Skip);
+    }
+}
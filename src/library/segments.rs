@@ -0,0 +1,49 @@
+//! Keeps the path of a query in redis instead of on the wire. Hops of a compacted query carry no
+//! path, only the boundary nodes they crossed with the cost up to each (their `entries`), and every
+//! server stores the segment it searched through its region in the hash `path_segments_<request id>`,
+//! under the nodes it went from and to. The server reaching the target assembles the full path from
+//! them before replying.
+//!
+//! The same query searched from the same boundary node to the same next one yields the same
+//! segment, so hops fanned out to several regions share the segments they have in common.
+
+use std::env;
+use crate::domain::{HopMessage, PathPoint};
+use crate::graph::NodeIdx;
+use crate::redis_connector::RedisConnector;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Redis hash of the path segments of a query, by [`field`].
+pub fn key(request_id: usize) -> String {
+    format!("path_segments_{}", request_id)
+}
+
+/// Field of the segment from node `from` to the boundary node `to`, which isn't part of it.
+pub(crate) fn field(from: NodeIdx, to: NodeIdx) -> String {
+    format!("{}_{}", from, to)
+}
+
+/// Reads COMPACT_PATHS, whether queries entering the cluster here are compacted.
+pub(crate) fn enabled_from_env() -> Result<bool> {
+    match env::var("COMPACT_PATHS") {
+        Ok(enabled) => { Ok(enabled.parse()?) }
+        Err(_) => { Ok(false) }
+    }
+}
+
+/// `request` with the path it crossed so far, itself if it carries its path.
+pub(crate) async fn expand(redis_connector: &RedisConnector, request: &HopMessage) -> Result<HopMessage> {
+    if !request.compact_path {
+        return Ok(request.clone());
+    }
+    let fields: Vec<String> = request.crossed_segments().into_iter().map(|(from, to)| field(from, to)).collect();
+    if fields.is_empty() {
+        return Ok(request.with_segments(vec![]));
+    }
+    let stored = redis_connector.get_path_segments(request.request_id, &fields).await?;
+    let segments: Vec<Vec<PathPoint>> = stored.into_iter().zip(fields.iter())
+        .map(|(segment, field)| segment.ok_or_else(|| format!("Segment {} of request {} expired", field, request.request_id)))
+        .collect::<std::result::Result<_, _>>()?;
+    Ok(request.with_segments(segments))
+}
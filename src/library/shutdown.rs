@@ -0,0 +1,41 @@
+use std::env;
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Time a server takes at most to finish the hops dispatched to its workers and deliver their
+/// results once asked to stop, within the 30s Kubernetes grants by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Reads SHUTDOWN_TIMEOUT_MS.
+pub(crate) fn timeout_from_env() -> Result<Duration> {
+    match env::var("SHUTDOWN_TIMEOUT_MS") {
+        Ok(millis) => { Ok(Duration::from_millis(millis.parse()?)) }
+        Err(_) => { Ok(DEFAULT_TIMEOUT) }
+    }
+}
+
+/// Completes on SIGTERM or SIGINT.
+#[cfg(unix)]
+pub(crate) async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => { terminate }
+        Err(err) => {
+            log::warn!("Unable to listen for SIGTERM, only stopping on SIGINT. Details: {}", err);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => { log::info!("Received SIGTERM") }
+        _ = tokio::signal::ctrl_c() => { log::info!("Received SIGINT") }
+    }
+}
+
+/// Completes on Ctrl-C.
+#[cfg(not(unix))]
+pub(crate) async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+    log::info!("Received Ctrl-C");
+}
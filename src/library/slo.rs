@@ -0,0 +1,309 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Class of requests which don't name one.
+pub const DEFAULT_CLASS: &str = "default";
+
+/// Latency objective of a request class: `percentile` % of the requests finish within `target`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SloTarget {
+    pub percentile: f64,
+    pub target: Duration,
+}
+
+impl SloTarget {
+    /// Fraction of requests allowed to miss the target.
+    fn error_budget(&self) -> f64 {
+        1. - self.percentile / 100.
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SloConfig {
+    pub targets: HashMap<String, SloTarget>,
+    /// Span of the rolling measurement window.
+    pub window: Duration,
+    /// Alert once requests miss the target this many times faster than the budget allows.
+    pub burn_rate_alert: f64,
+    /// Fewer measurements in the window are not considered representative.
+    pub min_samples: usize,
+    pub webhook_url: Option<String>,
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        Self {
+            targets: HashMap::new(),
+            window: Duration::from_secs(300),
+            burn_rate_alert: 2.,
+            min_samples: 50,
+            webhook_url: None,
+        }
+    }
+}
+
+impl SloConfig {
+    /// Parses targets given as `class=percentile:millis`, separated by commas (e.g. `default=99:500,batch=95:10000`).
+    pub fn parse_targets(targets: &str) -> Result<HashMap<String, SloTarget>> {
+        let mut parsed = HashMap::new();
+        for entry in targets.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (class, objective) = entry.split_once('=').ok_or(format!("Invalid SLO target {}", entry))?;
+            let (percentile, millis) = objective.split_once(':').ok_or(format!("Invalid SLO target {}", entry))?;
+            let percentile: f64 = percentile.parse()?;
+            if !(0. ..100.).contains(&percentile) {
+                Err(format!("SLO percentile {} of class {} is not within [0, 100)", percentile, class))?
+            }
+            parsed.insert(class.to_string(), SloTarget {
+                percentile,
+                target: Duration::from_millis(millis.parse()?),
+            });
+        }
+        Ok(parsed)
+    }
+
+    pub fn from_env() -> Result<Self> {
+        let mut config = SloConfig::default();
+        if let Ok(targets) = env::var("SLO_TARGETS") {
+            config.targets = Self::parse_targets(&targets)?;
+        }
+        if let Ok(window) = env::var("SLO_WINDOW_SECS") {
+            config.window = Duration::from_secs(window.parse()?);
+        }
+        if let Ok(burn_rate) = env::var("SLO_BURN_RATE_ALERT") {
+            config.burn_rate_alert = burn_rate.parse()?;
+        }
+        if let Ok(min_samples) = env::var("SLO_MIN_SAMPLES") {
+            config.min_samples = min_samples.parse()?;
+        }
+        config.webhook_url = env::var("SLO_WEBHOOK_URL").ok();
+        Ok(config)
+    }
+}
+
+/// Raised when a class burns its error budget too fast.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SloAlert {
+    pub class: String,
+    pub percentile: f64,
+    pub target_ms: u64,
+    pub samples: usize,
+    pub violations: usize,
+    pub burn_rate: f64,
+}
+
+/// Destination of SLO alerts.
+#[async_trait]
+pub trait AlertHook: Send + Sync {
+    async fn alert(&self, alert: &SloAlert);
+}
+
+pub struct LogAlert;
+
+#[async_trait]
+impl AlertHook for LogAlert {
+    async fn alert(&self, alert: &SloAlert) {
+        log::warn!("Latency SLO of class {} is burning: {} of {} requests exceeded {} ms (p{} objective), burn rate {:.2}",
+            alert.class, alert.violations, alert.samples, alert.target_ms, alert.percentile, alert.burn_rate);
+    }
+}
+
+/// Posts alerts as JSON.
+pub struct WebhookAlert {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookAlert {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+}
+
+#[async_trait]
+impl AlertHook for WebhookAlert {
+    async fn alert(&self, alert: &SloAlert) {
+        let res = self.client.post(&self.url)
+            .timeout(Duration::from_secs(5))
+            .json(alert)
+            .send().await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = res {
+            log::warn!("Unable to deliver SLO alert to {}, details: {}", self.url, err);
+        }
+    }
+}
+
+/// Measurements of one class within the rolling window.
+#[derive(Default)]
+struct ClassWindow {
+    /// Completion time and whether the request missed the target.
+    samples: VecDeque<(Instant, bool)>,
+    violations: usize,
+    last_alert: Option<Instant>,
+}
+
+impl ClassWindow {
+    fn push(&mut self, now: Instant, violation: bool, window: Duration) {
+        self.samples.push_back((now, violation));
+        if violation {
+            self.violations += 1;
+        }
+        while let Some((time, violation)) = self.samples.front() {
+            if now.duration_since(*time) <= window {
+                break;
+            }
+            if *violation {
+                self.violations -= 1;
+            }
+            self.samples.pop_front();
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SloStats {
+    pub measured: AtomicU64,
+    pub violations: AtomicU64,
+    pub alerts: AtomicU64,
+}
+
+/// Measures end-to-end latencies against the configured targets and notifies the hooks,
+/// at most once per window and class, when the error budget burns too fast.
+pub struct SloMonitor {
+    config: SloConfig,
+    windows: std::sync::Mutex<HashMap<String, ClassWindow>>,
+    hooks: Arc<Vec<Box<dyn AlertHook>>>,
+    stats: SloStats,
+}
+
+impl SloMonitor {
+    pub fn new(config: SloConfig, hooks: Vec<Box<dyn AlertHook>>) -> Self {
+        Self {
+            config,
+            windows: std::sync::Mutex::new(HashMap::new()),
+            hooks: Arc::new(hooks),
+            stats: SloStats::default(),
+        }
+    }
+
+    /// Log alerts, plus webhook ones if a webhook is configured.
+    pub fn from_config(config: SloConfig) -> Self {
+        let mut hooks: Vec<Box<dyn AlertHook>> = vec![Box::new(LogAlert)];
+        if let Some(url) = config.webhook_url.clone() {
+            hooks.push(Box::new(WebhookAlert::new(url)));
+        }
+        Self::new(config, hooks)
+    }
+
+    pub fn stats(&self) -> &SloStats {
+        &self.stats
+    }
+
+    /// Notifies the hooks in the background, so a slow webhook doesn't hold up the hop measured.
+    pub fn record(&self, class: Option<&str>, latency: Duration) {
+        if let Some(alert) = self.measure(class.unwrap_or(DEFAULT_CLASS), latency, Instant::now()) {
+            self.stats.alerts.fetch_add(1, Ordering::Relaxed);
+            let hooks = self.hooks.clone();
+            tokio::task::spawn(async move {
+                for hook in hooks.iter() {
+                    hook.alert(&alert).await;
+                }
+            });
+        }
+    }
+
+    fn measure(&self, class: &str, latency: Duration, now: Instant) -> Option<SloAlert> {
+        let target = self.config.targets.get(class)?;
+        let violation = latency > target.target;
+        self.stats.measured.fetch_add(1, Ordering::Relaxed);
+        if violation {
+            self.stats.violations.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(class.to_string()).or_default();
+        window.push(now, violation, self.config.window);
+        let samples = window.samples.len();
+        if samples < self.config.min_samples {
+            return None;
+        }
+        let burn_rate = window.violations as f64 / samples as f64 / target.error_budget();
+        if burn_rate < self.config.burn_rate_alert {
+            return None;
+        }
+        if window.last_alert.is_some_and(|last| now.duration_since(last) < self.config.window) {
+            return None;
+        }
+        window.last_alert = Some(now);
+        Some(SloAlert {
+            class: class.to_string(),
+            percentile: target.percentile,
+            target_ms: target.target.as_millis() as u64,
+            samples,
+            violations: window.violations,
+            burn_rate,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use crate::slo::{SloConfig, SloMonitor};
+
+    fn monitor() -> SloMonitor {
+        let config = SloConfig {
+            targets: SloConfig::parse_targets("default=90:100, batch=50:1000").unwrap(),
+            window: Duration::from_secs(60),
+            burn_rate_alert: 2.,
+            min_samples: 10,
+            webhook_url: None,
+        };
+        SloMonitor::new(config, vec![])
+    }
+
+    #[test]
+    fn alerts_once_per_window() {
+        let monitor = monitor();
+        let start = Instant::now();
+        let fast = Duration::from_millis(10);
+        let slow = Duration::from_millis(200);
+        for i in 0..8 {
+            assert_eq!(monitor.measure("default", fast, start + Duration::from_secs(i)), None);
+        }
+        // 2 of 10 over the target is twice the 10% budget.
+        assert_eq!(monitor.measure("default", slow, start + Duration::from_secs(8)), None);
+        let alert = monitor.measure("default", slow, start + Duration::from_secs(9)).unwrap();
+        assert_eq!((alert.samples, alert.violations), (10, 2));
+        assert!(monitor.measure("default", slow, start + Duration::from_secs(10)).is_none());
+
+        // Later on, the old measurements left the window and the budget is burning again.
+        let later = start + Duration::from_secs(120);
+        for i in 0..10 {
+            let alert = monitor.measure("default", slow, later + Duration::from_secs(i));
+            assert_eq!(alert.is_some(), i == 9);
+        }
+    }
+
+    #[test]
+    fn classes_are_separate() {
+        let monitor = monitor();
+        let now = Instant::now();
+        for _ in 0..20 {
+            assert!(monitor.measure("batch", Duration::from_millis(500), now).is_none());
+            assert!(monitor.measure("interactive", Duration::from_secs(10), now).is_none());
+        }
+        assert_eq!(monitor.windows.lock().unwrap().len(), 1);
+        assert!(SloConfig::parse_targets("default=100:5").is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
+use tokio::task::JoinHandle;
+use crate::graph::RegionIdx;
+use crate::queues::QueueReport;
+use crate::redis_connector::RedisConnector;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerSnapshot {
+    pub addr: String,
+    pub regions: Vec<RegionIdx>,
+    /// Whether the lease of its group was held, see [`crate::standby`]. Snapshots taken before
+    /// health was recorded count every server registered as alive.
+    #[serde(default = "registered_is_alive")]
+    pub alive: bool,
+    /// Its last queue report, none if it stopped reporting.
+    #[serde(default)]
+    pub queues: Option<QueueReport>,
+}
+
+fn registered_is_alive() -> bool {
+    true
+}
+
+/// Cluster topology and the health of its servers at one point in time, as registered in Redis.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NetworkSnapshot {
+    /// Milliseconds since the unix epoch.
+    pub taken_at: u64,
+    pub servers: BTreeMap<usize, ServerSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotChange {
+    ServerAdded(usize, ServerSnapshot),
+    ServerRemoved(usize, ServerSnapshot),
+    AddrChanged { server: usize, from: String, to: String },
+    RegionsChanged { server: usize, added: Vec<RegionIdx>, removed: Vec<RegionIdx> },
+    AliveChanged { server: usize, alive: bool },
+}
+
+impl std::fmt::Display for SnapshotChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotChange::ServerAdded(id, server) => { write!(f, "+ server {} at {} serving {:?}", id, server.addr, server.regions) }
+            SnapshotChange::ServerRemoved(id, server) => { write!(f, "- server {} at {} serving {:?}", id, server.addr, server.regions) }
+            SnapshotChange::AddrChanged { server, from, to } => { write!(f, "~ server {} moved from {} to {}", server, from, to) }
+            SnapshotChange::RegionsChanged { server, added, removed } => { write!(f, "~ server {} gained regions {:?}, lost regions {:?}", server, added, removed) }
+            SnapshotChange::AliveChanged { server, alive: true } => { write!(f, "~ server {} is alive again", server) }
+            SnapshotChange::AliveChanged { server, alive: false } => { write!(f, "~ server {} lost its lease", server) }
+        }
+    }
+}
+
+impl NetworkSnapshot {
+    /// Changes turning `self` into `later`.
+    pub fn diff(&self, later: &NetworkSnapshot) -> Vec<SnapshotChange> {
+        let mut changes = vec![];
+        for (id, server) in self.servers.iter() {
+            match later.servers.get(id) {
+                None => { changes.push(SnapshotChange::ServerRemoved(*id, server.clone())) }
+                Some(other) => {
+                    if server.addr != other.addr {
+                        changes.push(SnapshotChange::AddrChanged { server: *id, from: server.addr.clone(), to: other.addr.clone() });
+                    }
+                    let added: Vec<_> = other.regions.iter().filter(|region| !server.regions.contains(region)).copied().collect();
+                    let removed: Vec<_> = server.regions.iter().filter(|region| !other.regions.contains(region)).copied().collect();
+                    if !added.is_empty() || !removed.is_empty() {
+                        changes.push(SnapshotChange::RegionsChanged { server: *id, added, removed });
+                    }
+                    if server.alive != other.alive {
+                        changes.push(SnapshotChange::AliveChanged { server: *id, alive: other.alive });
+                    }
+                }
+            }
+        }
+        for (id, server) in later.servers.iter() {
+            if !self.servers.contains_key(id) {
+                changes.push(SnapshotChange::ServerAdded(*id, server.clone()));
+            }
+        }
+        changes
+    }
+
+    pub(crate) fn name(&self) -> String {
+        format!("network_{}.json", self.taken_at)
+    }
+}
+
+/// Where network snapshots are kept.
+#[async_trait]
+pub trait SnapshotStore: Send + Sync {
+    /// Returns the name the snapshot can be loaded by.
+    async fn save(&self, snapshot: &NetworkSnapshot) -> Result<String>;
+    async fn load(&self, name: &str) -> Result<NetworkSnapshot>;
+}
+
+/// Keeps snapshots as files in a local directory.
+pub struct DirSnapshotStore {
+    dir: PathBuf,
+}
+
+impl DirSnapshotStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir
+        }
+    }
+}
+
+#[async_trait]
+impl SnapshotStore for DirSnapshotStore {
+    async fn save(&self, snapshot: &NetworkSnapshot) -> Result<String> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(self.dir.join(snapshot.name()), serde_json::to_vec_pretty(snapshot)?).await?;
+        Ok(snapshot.name())
+    }
+
+    async fn load(&self, name: &str) -> Result<NetworkSnapshot> {
+        Ok(serde_json::from_slice(&tokio::fs::read(self.dir.join(name)).await?)?)
+    }
+}
+
+pub(crate) async fn take_snapshot(redis_connector: &RedisConnector) -> Result<NetworkSnapshot> {
+    let servers = redis_connector.get_servers().await?;
+    let ids: Vec<usize> = servers.keys().copied().collect();
+    let (live, queues) = if ids.is_empty() {
+        (vec![], vec![])
+    } else {
+        (redis_connector.get_live_servers(&ids).await?, redis_connector.get_queue_reports(&ids).await?)
+    };
+    let mut queues = queues.into_iter();
+    Ok(NetworkSnapshot {
+        taken_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+        servers: servers.into_iter()
+            .map(|(id, info)| (id, ServerSnapshot { addr: info.addr.to_string(), regions: info.regions, alive: live.contains(&id), queues: queues.next().flatten() }))
+            .collect(),
+    })
+}
+
+/// Saves a snapshot every `interval`, as long as the returned task runs.
+pub(crate) fn spawn_snapshots(redis_connector: RedisConnector,
+                              store: Box<dyn SnapshotStore>,
+                              interval: Duration) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let saved = match take_snapshot(&redis_connector).await {
+                Ok(snapshot) => { store.save(&snapshot).await }
+                Err(err) => { Err(err) }
+            };
+            match saved {
+                Ok(name) => { log::debug!("Saved network snapshot {}", name) }
+                Err(err) => { log::warn!("Unable to save network snapshot, details: {}", err) }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use crate::fixtures::TempDir;
+    use crate::snapshot::{DirSnapshotStore, NetworkSnapshot, ServerSnapshot, SnapshotChange, SnapshotStore};
+
+    fn server(addr: &str, regions: Vec<u32>) -> ServerSnapshot {
+        ServerSnapshot { addr: addr.to_string(), regions, alive: true, queues: None }
+    }
+
+    #[tokio::test]
+    async fn diff_of_stored_snapshots() {
+        let before = NetworkSnapshot {
+            taken_at: 1,
+            servers: BTreeMap::from([(1, server("tcp://a:1", vec![1, 2])), (2, server("tcp://b:1", vec![3]))]),
+        };
+        let after = NetworkSnapshot {
+            taken_at: 2,
+            servers: BTreeMap::from([(1, ServerSnapshot { alive: false, ..server("tcp://a:2", vec![2, 4]) }), (3, server("tcp://c:1", vec![3]))]),
+        };
+        let dir = TempDir::new("snapshots");
+        let store = DirSnapshotStore::new(dir.path().to_path_buf());
+        let before_name = store.save(&before).await.unwrap();
+        let after_name = store.save(&after).await.unwrap();
+        let before = store.load(&before_name).await.unwrap();
+        let after = store.load(&after_name).await.unwrap();
+
+        assert_eq!(before.diff(&after), vec![
+            SnapshotChange::AddrChanged { server: 1, from: "tcp://a:1".to_string(), to: "tcp://a:2".to_string() },
+            SnapshotChange::RegionsChanged { server: 1, added: vec![4], removed: vec![1] },
+            SnapshotChange::AliveChanged { server: 1, alive: false },
+            SnapshotChange::ServerRemoved(2, server("tcp://b:1", vec![3])),
+            SnapshotChange::ServerAdded(3, server("tcp://c:1", vec![3])),
+        ]);
+        assert!(after.diff(&after).is_empty());
+    }
+}
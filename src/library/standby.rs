@@ -0,0 +1,345 @@
+use std::env;
+use std::time::Duration;
+use redis::RedisResult;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use crate::graph::RegionIdx;
+use crate::redis_connector::RedisConnector;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// How often the process serving a group proves it is alive, and how long a standby waits for
+/// that proof before taking over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct HeartbeatConfig {
+    pub(crate) interval: Duration,
+    pub(crate) timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(1000),
+            timeout: Duration::from_millis(5000),
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    /// Reads HEARTBEAT_INTERVAL_MS and HEARTBEAT_TIMEOUT_MS.
+    pub(crate) fn from_env() -> Result<Self> {
+        let mut config = HeartbeatConfig::default();
+        if let Ok(interval) = env::var("HEARTBEAT_INTERVAL_MS") {
+            config.interval = Duration::from_millis(interval.parse()?);
+        }
+        if let Ok(timeout) = env::var("HEARTBEAT_TIMEOUT_MS") {
+            config.timeout = Duration::from_millis(timeout.parse()?);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.interval.is_zero() || self.timeout <= self.interval {
+            Err(format!("Heartbeat timeout {:?} has to exceed the interval {:?}", self.timeout, self.interval))?
+        }
+        Ok(())
+    }
+}
+
+/// Where the leases of the groups are kept, redis in a cluster.
+#[async_trait::async_trait]
+pub(crate) trait LeaseStore: Send + Sync {
+    /// Takes the lease of the group for `holder` unless someone holds it.
+    async fn claim(&self, group_id: usize, holder: &str, ttl: Duration) -> RedisResult<bool>;
+
+    /// Extends the lease of `holder`, returns false if someone else holds it.
+    async fn renew(&self, group_id: usize, holder: &str, ttl: Duration) -> RedisResult<bool>;
+
+    async fn holder(&self, group_id: usize) -> RedisResult<Option<String>>;
+
+    /// Gives up the lease of `holder`, returns false if someone else holds it.
+    async fn release(&self, group_id: usize, holder: &str) -> RedisResult<bool>;
+
+    /// Gives up the lease of `holder` and deregisters server `server_id` with its replicas of
+    /// `regions`, returns false and leaves both if someone else holds the lease.
+    async fn leave(&self, group_id: usize, holder: &str, server_id: usize, regions: &[RegionIdx]) -> RedisResult<bool>;
+}
+
+#[async_trait::async_trait]
+impl LeaseStore for RedisConnector {
+    async fn claim(&self, group_id: usize, holder: &str, ttl: Duration) -> RedisResult<bool> {
+        self.claim_lease(group_id, holder, ttl).await
+    }
+
+    async fn renew(&self, group_id: usize, holder: &str, ttl: Duration) -> RedisResult<bool> {
+        self.renew_lease(group_id, holder, ttl).await
+    }
+
+    async fn holder(&self, group_id: usize) -> RedisResult<Option<String>> {
+        self.get_lease_holder(group_id).await
+    }
+
+    async fn release(&self, group_id: usize, holder: &str) -> RedisResult<bool> {
+        self.release_lease(group_id, holder).await
+    }
+
+    async fn leave(&self, group_id: usize, holder: &str, server_id: usize, regions: &[RegionIdx]) -> RedisResult<bool> {
+        self.leave_cluster(group_id, holder, server_id, regions).await
+    }
+}
+
+/// Completes once another process took the lease over, see [`Lease::spawn_heartbeat`].
+pub(crate) struct LeaseLost(oneshot::Receiver<()>);
+
+impl LeaseLost {
+    /// Never completes while the lease is held, nor once its heartbeat is stopped.
+    pub(crate) async fn wait(self) {
+        if self.0.await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Right to serve a group, held by one process at a time and kept by heartbeats. It lapses once
+/// the holder misses heartbeats for the timeout. Only its holder registers the group in redis.
+pub(crate) struct Lease {
+    group_id: usize,
+    holder: String,
+    config: HeartbeatConfig,
+}
+
+impl Lease {
+    pub(crate) fn new(group_id: usize, config: HeartbeatConfig) -> Self {
+        Self {
+            group_id,
+            holder: uuid::Uuid::new_v4().to_string(),
+            config,
+        }
+    }
+
+    /// Identifies this process as the holder of the lease in redis.
+    pub(crate) fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Claims the lease before a primary registers anything for the group. A lease left by a
+    /// crashed holder lapses within the timeout, one still renewed means another process serves
+    /// the group, which is an error rather than a second set of mappings in redis.
+    pub(crate) async fn acquire(&self, store: &impl LeaseStore) -> Result<()> {
+        let mut ticker = tokio::time::interval(self.config.interval);
+        let deadline = tokio::time::Instant::now() + self.config.timeout + self.config.interval;
+        while tokio::time::Instant::now() < deadline {
+            ticker.tick().await;
+            if store.claim(self.group_id, &self.holder, self.config.timeout).await? {
+                return Ok(());
+            }
+        }
+        let holder = store.holder(self.group_id).await?.unwrap_or_default();
+        Err(format!("Group {} is already served by process {}, start further processes for it with STANDBY=true", self.group_id, holder))?
+    }
+
+    /// Waits for a primary to serve the group and takes the lease once it lapses. Waiting for a
+    /// primary first keeps a standby started alongside it from taking over before it is loaded.
+    pub(crate) async fn wait_for_takeover(&self, store: &impl LeaseStore) {
+        let mut ticker = tokio::time::interval(self.config.interval);
+        let mut primary_seen = false;
+        loop {
+            ticker.tick().await;
+            let attempt = if primary_seen {
+                store.claim(self.group_id, &self.holder, self.config.timeout).await
+            } else {
+                store.holder(self.group_id).await.map(|holder| {
+                    if let Some(holder) = holder {
+                        log::info!("Standing by for group {}, served by {}", self.group_id, holder);
+                        primary_seen = true;
+                    }
+                    false
+                })
+            };
+            match attempt {
+                Ok(true) => {
+                    log::warn!("Group {} missed heartbeats for {:?}, taking over", self.group_id, self.config.timeout);
+                    return;
+                }
+                Ok(false) => {}
+                Err(err) => { log::warn!("Unable to check the lease of group {}, details: {}", self.group_id, err) }
+            }
+        }
+    }
+
+    /// Renews the lease every interval. Stops once it finds the lease taken over, completing the
+    /// returned [`LeaseLost`], so the process shuts down and a group is never served twice after
+    /// a failover.
+    pub(crate) fn spawn_heartbeat(self, store: impl LeaseStore + 'static) -> (JoinHandle<()>, LeaseLost) {
+        let (lost, lease_lost) = oneshot::channel();
+        let heartbeat = tokio::task::spawn(async move {
+            let mut ticker = tokio::time::interval(self.config.interval);
+            loop {
+                ticker.tick().await;
+                match store.renew(self.group_id, &self.holder, self.config.timeout).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        log::error!("Group {} was taken over by another process, shutting down", self.group_id);
+                        let _ = lost.send(());
+                        return;
+                    }
+                    Err(err) => { log::warn!("Unable to renew the lease of group {}, details: {}", self.group_id, err) }
+                }
+            }
+        });
+        (heartbeat, LeaseLost(lease_lost))
+    }
+}
+
+/// Leases and registered servers kept in memory, for tests of whatever holds leases.
+#[cfg(test)]
+pub(crate) mod memory {
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use redis::RedisResult;
+    use tokio::time::Instant;
+    use crate::graph::RegionIdx;
+    use crate::standby::LeaseStore;
+
+    /// Leases lapsing like the keys of redis, and the servers registered with the regions they serve.
+    #[derive(Clone, Default)]
+    pub(crate) struct MemoryLeases {
+        leases: Arc<Mutex<HashMap<usize, (String, Instant)>>>,
+        servers: Arc<Mutex<BTreeMap<usize, Vec<RegionIdx>>>>,
+    }
+
+    impl MemoryLeases {
+        pub(crate) fn held(&self, group_id: usize) -> Option<String> {
+            let mut leases = self.leases.lock().unwrap();
+            leases.retain(|_, (_, expiry)| *expiry > Instant::now());
+            leases.get(&group_id).map(|(holder, _)| holder.clone())
+        }
+
+        /// Takes the lease over for `holder`, whoever held it.
+        pub(crate) fn take_over(&self, group_id: usize, holder: &str, ttl: Duration) {
+            self.leases.lock().unwrap().insert(group_id, (holder.to_string(), Instant::now() + ttl));
+        }
+
+        pub(crate) fn register(&self, server_id: usize, regions: &[RegionIdx]) {
+            self.servers.lock().unwrap().insert(server_id, regions.to_vec());
+        }
+
+        pub(crate) fn is_registered(&self, server_id: usize) -> bool {
+            self.servers.lock().unwrap().contains_key(&server_id)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl LeaseStore for MemoryLeases {
+        async fn claim(&self, group_id: usize, holder: &str, ttl: Duration) -> RedisResult<bool> {
+            if self.held(group_id).is_some() {
+                return Ok(false);
+            }
+            self.take_over(group_id, holder, ttl);
+            Ok(true)
+        }
+
+        async fn renew(&self, group_id: usize, holder: &str, ttl: Duration) -> RedisResult<bool> {
+            if self.held(group_id).as_deref() != Some(holder) {
+                return Ok(false);
+            }
+            self.take_over(group_id, holder, ttl);
+            Ok(true)
+        }
+
+        async fn holder(&self, group_id: usize) -> RedisResult<Option<String>> {
+            Ok(self.held(group_id))
+        }
+
+        async fn release(&self, group_id: usize, holder: &str) -> RedisResult<bool> {
+            if self.held(group_id).as_deref() != Some(holder) {
+                return Ok(false);
+            }
+            self.leases.lock().unwrap().remove(&group_id);
+            Ok(true)
+        }
+
+        async fn leave(&self, group_id: usize, holder: &str, server_id: usize, _regions: &[RegionIdx]) -> RedisResult<bool> {
+            if !self.release(group_id, holder).await? {
+                return Ok(false);
+            }
+            self.servers.lock().unwrap().remove(&server_id);
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use futures_util::FutureExt as _;
+    use tokio::time::timeout;
+    use crate::standby::{HeartbeatConfig, Lease, LeaseStore};
+    use crate::standby::memory::MemoryLeases;
+
+    const CONFIG: HeartbeatConfig = HeartbeatConfig { interval: Duration::from_millis(20), timeout: Duration::from_millis(100) };
+
+    #[tokio::test]
+    async fn standby_takes_over_once_the_primary_stops() {
+        let leases = MemoryLeases::default();
+        let primary = Lease::new(3, CONFIG);
+        let primary_holder = primary.holder().to_string();
+        primary.acquire(&leases).await.unwrap();
+        let (heartbeat, _) = primary.spawn_heartbeat(leases.clone());
+        assert!(Lease::new(3, CONFIG).acquire(&leases).await.is_err());
+
+        let standby = Lease::new(3, CONFIG);
+        assert!(timeout(CONFIG.timeout * 3, standby.wait_for_takeover(&leases)).await.is_err());
+        assert_eq!(leases.held(3), Some(primary_holder));
+
+        heartbeat.abort();
+        timeout(CONFIG.timeout * 3, standby.wait_for_takeover(&leases)).await.unwrap();
+        assert_eq!(leases.held(3).as_deref(), Some(standby.holder()));
+    }
+
+    #[tokio::test]
+    async fn losing_the_lease_is_signalled() {
+        let leases = MemoryLeases::default();
+        let lease = Lease::new(3, CONFIG);
+        lease.acquire(&leases).await.unwrap();
+        let (mut heartbeat, lost) = lease.spawn_heartbeat(leases.clone());
+        tokio::time::sleep(CONFIG.timeout * 2).await;
+        assert!((&mut heartbeat).now_or_never().is_none());
+
+        leases.take_over(3, "standby", CONFIG.timeout);
+        timeout(CONFIG.timeout, lost.wait()).await.unwrap();
+        heartbeat.await.unwrap();
+
+        // A heartbeat stopped on shutdown loses nothing.
+        let lease = Lease::new(4, CONFIG);
+        lease.acquire(&leases).await.unwrap();
+        let (heartbeat, lost) = lease.spawn_heartbeat(leases.clone());
+        heartbeat.abort();
+        assert!(timeout(CONFIG.timeout, lost.wait()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn released_leases_are_free_at_once() {
+        let leases = MemoryLeases::default();
+        let primary = Lease::new(3, CONFIG);
+        let primary_holder = primary.holder().to_string();
+        primary.acquire(&leases).await.unwrap();
+        let (heartbeat, _) = primary.spawn_heartbeat(leases.clone());
+        assert!(!leases.release(3, "standby").await.unwrap());
+
+        heartbeat.abort();
+        assert!(leases.release(3, &primary_holder).await.unwrap());
+        Lease::new(3, CONFIG).acquire(&leases).await.unwrap();
+    }
+
+    #[test]
+    fn timeout_exceeds_interval() {
+        assert!(HeartbeatConfig::default().validate().is_ok());
+        let config = |interval, timeout| HeartbeatConfig { interval: Duration::from_millis(interval), timeout: Duration::from_millis(timeout) };
+        assert!(config(1000, 1000).validate().is_err());
+        assert!(config(0, 1000).validate().is_err());
+        assert!(config(200, 1000).validate().is_ok());
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use crate::graph::{Graph, Node, RegionIdx};
+use crate::heuristic::{Euclidean, Heuristic, Zero};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Coordinates guide a search well enough when the cheapest edge per coordinate unit costs at least
+/// this share of the typical edge.
+const TRUSTED_COORDINATES_RATIO: f64 = 0.25;
+
+/// Thresholds of the automatic search strategy, read from SEARCH_STRATEGY=auto.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct AutoStrategyConfig {
+    /// Regions with at most this many nodes are searched with plain Dijkstra.
+    pub(crate) small_region_nodes: usize,
+    /// Pairs further apart than this share of the region's extent are searched from both ends.
+    pub(crate) long_distance: f64,
+}
+
+impl Default for AutoStrategyConfig {
+    fn default() -> Self {
+        Self {
+            small_region_nodes: 2000,
+            long_distance: 0.5,
+        }
+    }
+}
+
+impl AutoStrategyConfig {
+    /// Reads SEARCH_STRATEGY (fixed or auto), AUTO_SMALL_REGION_NODES and AUTO_LONG_DISTANCE.
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        match env::var("SEARCH_STRATEGY").as_deref() {
+            Err(_) | Ok("fixed") => { return Ok(None) }
+            Ok("auto") => {}
+            Ok(other) => { Err(format!("Unknown search strategy {}", other))? }
+        }
+        let defaults = Self::default();
+        Ok(Some(Self {
+            small_region_nodes: match env::var("AUTO_SMALL_REGION_NODES") {
+                Ok(nodes) => { nodes.parse()? }
+                Err(_) => { defaults.small_region_nodes }
+            },
+            long_distance: match env::var("AUTO_LONG_DISTANCE") {
+                Ok(share) => { share.parse()? }
+                Err(_) => { defaults.long_distance }
+            },
+        }))
+    }
+}
+
+fn distance(a: &Node, b: &Node) -> f64 {
+    let dx = a.cord_x.abs_diff(b.cord_x) as f64;
+    let dy = a.cord_y.abs_diff(b.cord_y) as f64;
+    (dx * dx + dy * dy).sqrt()
+}
+
+/// Shape of a region, collected once it is loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RegionStats {
+    pub(crate) nodes: usize,
+    pub(crate) vertices: usize,
+    /// Diagonal of the box around all coordinates.
+    pub(crate) extent: f64,
+    /// Cost of a coordinate unit along the cheapest edge, which makes the straight line distance a
+    /// consistent bound. Only set if it is close enough to the typical edge to be worth using.
+    pub(crate) coordinate_scale: Option<f64>,
+}
+
+impl RegionStats {
+    pub(crate) fn collect(graph: &Graph) -> Self {
+        let (mut min, mut max) = ((u64::MAX, u64::MAX), (0, 0));
+        for node in graph.nodes() {
+            min = (min.0.min(node.cord_x), min.1.min(node.cord_y));
+            max = (max.0.max(node.cord_x), max.1.max(node.cord_y));
+        }
+        let extent = if graph.node_count() == 0 {
+            0.
+        } else {
+            let (dx, dy) = ((max.0 - min.0) as f64, (max.1 - min.1) as f64);
+            (dx * dx + dy * dy).sqrt()
+        };
+
+        let mut vertices = 0;
+        let mut ratios = vec![];
+        for vertex in graph.vertices() {
+            vertices += 1;
+            // Edges leaving the region don't take part in local searches.
+            if let (Some(a), Some(b)) = (graph.get_node(vertex.a), graph.get_node(vertex.b)) {
+                let length = distance(&a, &b);
+                if length > 0. {
+                    ratios.push(vertex.weight as f64 / length);
+                }
+            }
+        }
+        ratios.sort_by(f64::total_cmp);
+        let coordinate_scale = match (ratios.first(), ratios.get(ratios.len() / 2)) {
+            (Some(lowest), Some(median)) if *lowest > 0. && *lowest >= median * TRUSTED_COORDINATES_RATIO => { Some(*lowest) }
+            _ => { None }
+        };
+        Self {
+            nodes: graph.node_count(),
+            vertices,
+            extent,
+            coordinate_scale,
+        }
+    }
+}
+
+/// How a search within a single region is carried out.
+#[derive(Clone)]
+pub(crate) enum Strategy {
+    /// A*, which is plain Dijkstra with the [`Zero`] heuristic.
+    AStar(Arc<dyn Heuristic>),
+    Bidirectional,
+}
+
+impl std::fmt::Debug for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Strategy::AStar(_) => { write!(f, "AStar") }
+            Strategy::Bidirectional => { write!(f, "Bidirectional") }
+        }
+    }
+}
+
+/// Picks a strategy per query from the stats of its region, instead of one for all queries.
+pub(crate) struct StrategySelector {
+    config: AutoStrategyConfig,
+    regions: HashMap<RegionIdx, (RegionStats, Option<Arc<dyn Heuristic>>)>,
+    /// Precomputed distance tables (landmarks), preferred wherever they exist.
+    tables: Option<Arc<dyn Heuristic>>,
+}
+
+impl StrategySelector {
+    pub(crate) fn new<'a>(config: AutoStrategyConfig,
+                          graphs: impl IntoIterator<Item=&'a Graph>,
+                          tables: Option<Arc<dyn Heuristic>>) -> Self {
+        let mut regions = HashMap::new();
+        for graph in graphs {
+            let stats = RegionStats::collect(graph);
+            log::info!("Region {} has {} nodes, {} vertices, extent {:.0} and {} coordinates",
+                graph.region_idx, stats.nodes, stats.vertices, stats.extent,
+                if stats.coordinate_scale.is_some() { "trustworthy" } else { "untrustworthy" });
+            let euclidean = stats.coordinate_scale.map(|scale| Arc::new(Euclidean { scale }) as Arc<dyn Heuristic>);
+            regions.insert(graph.region_idx, (stats, euclidean));
+        }
+        Self {
+            config,
+            regions,
+            tables,
+        }
+    }
+
+    /// Strategy for a search from `source` to `target` within their region.
+    pub(crate) fn select(&self, source: &Node, target: &Node) -> Strategy {
+        let (stats, euclidean) = match self.regions.get(&source.region) {
+            Some(region) => { region }
+            None => { return Strategy::AStar(Arc::new(Zero)) }
+        };
+        if stats.nodes <= self.config.small_region_nodes {
+            return Strategy::AStar(Arc::new(Zero));
+        }
+        if let Some(tables) = &self.tables {
+            return Strategy::AStar(tables.clone());
+        }
+        if let Some(euclidean) = euclidean {
+            return Strategy::AStar(euclidean.clone());
+        }
+        if distance(source, target) >= stats.extent * self.config.long_distance {
+            return Strategy::Bidirectional;
+        }
+        Strategy::AStar(Arc::new(Zero))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use bitvec::vec::BitVec;
+    use crate::domain::NodeInfo;
+    use crate::graph::{Access, Avoid, Graph, Node, PathResult, SearchLimits, Vertex};
+    use crate::heuristic::Zero;
+    use crate::strategy::{AutoStrategyConfig, RegionStats, Strategy, StrategySelector};
+
+    /// `size` x `size` grid with unit spacing. Edges along x cost `x_weight`, along y 1.
+    fn grid(size: usize, x_weight: u64) -> Graph {
+        let id = |x: usize, y: usize| y * size + x + 1;
+        let mut nodes: HashMap<_, _> = (0..size * size)
+            .map(|idx| (idx + 1, Node::new(vec![], idx + 1, 1, (idx % size) as u64, (idx / size) as u64)))
+            .collect();
+        let mut vertices = HashMap::new();
+        for y in 0..size {
+            for x in 0..size {
+                for (nx, ny, weight) in [(x + 1, y, x_weight), (x, y + 1, 1)] {
+                    if nx < size && ny < size {
+                        let vertex_id = vertices.len();
+                        let (a, b) = (id(x, y), id(nx, ny));
+                        nodes.get_mut(&a).unwrap().connections.push(vertex_id);
+                        nodes.get_mut(&b).unwrap().connections.push(vertex_id);
+                        vertices.insert(vertex_id, Vertex { a, b, weight, id: vertex_id, region_bits: BitVec::repeat(true, 2), access: Access::ALL });
+                    }
+                }
+            }
+        }
+        Graph::new(nodes, vertices, 1)
+    }
+
+    #[test]
+    fn stats() {
+        let stats = RegionStats::collect(&grid(3, 1));
+        assert_eq!((stats.nodes, stats.vertices), (9, 12));
+        assert!((stats.extent - 8f64.sqrt()).abs() < 1e-9);
+        assert_eq!(stats.coordinate_scale, Some(1.));
+        assert_eq!(RegionStats::collect(&grid(3, 10)).coordinate_scale, None);
+    }
+
+    #[test]
+    fn selection() {
+        let config = AutoStrategyConfig { small_region_nodes: 10, long_distance: 0.5 };
+        let (trusted, untrusted) = (grid(4, 2), grid(4, 10));
+        let selector = StrategySelector::new(config, [&trusted], None);
+        let node = |graph: &Graph, id| graph.get_node(id).unwrap().into_owned();
+        assert!(matches!(selector.select(&node(&trusted, 1), &node(&trusted, 16)), Strategy::AStar(_)));
+
+        let selector = StrategySelector::new(config, [&untrusted], None);
+        assert!(matches!(selector.select(&node(&untrusted, 1), &node(&untrusted, 16)), Strategy::Bidirectional));
+        assert!(matches!(selector.select(&node(&untrusted, 1), &node(&untrusted, 2)), Strategy::AStar(_)));
+
+        let small = grid(3, 10);
+        let selector = StrategySelector::new(config, [&small], None);
+        assert!(matches!(selector.select(&node(&small, 1), &node(&small, 9)), Strategy::AStar(_)));
+    }
+
+    #[tokio::test]
+    async fn bidirectional_matches_dijkstra() {
+        let graph = grid(6, 3);
+        let (mut avoid, limits) = (Avoid::default(), SearchLimits::default());
+        avoid.nodes.insert(8);
+        for (source, target) in [(1, 36), (6, 31), (13, 13), (2, 9)] {
+            let one_way = graph.find_way_local(NodeInfo(source, 1), NodeInfo(target, 1), &Zero, &avoid, &limits).await.unwrap();
+            let both_ways = graph.find_way_bidirectional(NodeInfo(source, 1), NodeInfo(target, 1), &avoid, &limits).await.unwrap();
+            match (one_way, both_ways) {
+                (PathResult::TargetReached(_, expected), PathResult::TargetReached(path, cost)) => {
+                    assert_eq!(cost, expected);
+                    assert_eq!(path.first(), Some(&crate::domain::PathPoint::from(&*graph.get_node(source).unwrap())));
+                    assert_eq!(path.last(), Some(&crate::domain::PathPoint::from(&*graph.get_node(target).unwrap())));
+                }
+                _ => { panic!("Target should be reached") }
+            }
+        }
+        assert!(graph.find_way_bidirectional(NodeInfo(1, 1), NodeInfo(8, 1), &avoid, &limits).await.is_err());
+    }
+}
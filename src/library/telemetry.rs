@@ -0,0 +1,82 @@
+//! Tracing of queries across servers. Every hop is served within a span, and hops carry the W3C
+//! `traceparent` of the span that sent them on, so the spans of all servers a query visits form
+//! one trace. Built with `--features otlp`, spans are exported over OTLP.
+
+use tracing::Span;
+use crate::domain::HopMessage;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Exports spans to OTEL_EXPORTER_OTLP_ENDPOINT, e.g. `http://collector:4317`, as service
+/// OTEL_SERVICE_NAME (default `pathfinder`). Spans aren't exported if the endpoint isn't set.
+#[cfg(feature = "otlp")]
+pub fn init() -> Result<()> {
+    use opentelemetry::KeyValue;
+    use opentelemetry::sdk::{propagation::TraceContextPropagator, trace, Resource};
+    use opentelemetry_otlp::WithExportConfig;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let endpoint = match std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) => { endpoint }
+        Err(_) => { return Ok(()) }
+    };
+    let service_name = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "pathfinder".to_string());
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint.clone()))
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![KeyValue::new("service.name", service_name)])))
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber)?;
+    log::info!("Exporting spans to {}", endpoint);
+    Ok(())
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn init() -> Result<()> {
+    if std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_ok() {
+        log::warn!("OTEL_EXPORTER_OTLP_ENDPOINT is set, but spans are only exported when built with --features otlp");
+    }
+    Ok(())
+}
+
+/// Sends the spans not exported yet.
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Span of serving `request` on server `server_id`, within the trace of the span that sent it.
+pub(crate) fn hop_span(request: &HopMessage, server_id: usize) -> Span {
+    let span = tracing::info_span!("serve_hop",
+        request_id = request.request_id,
+        region = request.region(),
+        direction = request.direction.name(),
+        server = server_id);
+    #[cfg(feature = "otlp")]
+    if let Some(parent) = request.trace_context.as_deref() {
+        use opentelemetry::propagation::TextMapPropagator;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        let carrier = std::collections::HashMap::from([("traceparent".to_string(), parent.to_string())]);
+        let context = opentelemetry::sdk::propagation::TraceContextPropagator::new().extract(&carrier);
+        span.set_parent(context);
+    }
+    span
+}
+
+/// Makes the current span the parent of the spans serving `request`, which it sends on.
+pub(crate) fn propagate(request: &mut HopMessage) {
+    #[cfg(feature = "otlp")]
+    {
+        use opentelemetry::propagation::TextMapPropagator;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        let mut carrier = std::collections::HashMap::new();
+        opentelemetry::sdk::propagation::TraceContextPropagator::new().inject_context(&Span::current().context(), &mut carrier);
+        if let Some(parent) = carrier.remove("traceparent") {
+            request.trace_context = Some(parent);
+        }
+    }
+    #[cfg(not(feature = "otlp"))]
+    let _ = request;
+}
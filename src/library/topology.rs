@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use redis::RedisResult;
+use serde::{Serialize, Deserialize};
+use tokio::task::JoinHandle;
+use crate::data_quality::{parse_access, parse_region_bits};
+use crate::graph::{Graph, Node, RegionIdx, Vertex, VertexIdx, WeightScale};
+use crate::graph_provider::{RawNode, RawVertex};
+use crate::redis_connector::{Delivery, RedisConnector, Subscriber};
+use crate::reload::DatasetHandle;
+
+/// Change to the topology of a region, e.g. a road closure or a new connection. Nodes and vertices
+/// are given like the records of the region files.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum TopologyPatch {
+    InsertNode(RawNode),
+    InsertVertex(RawVertex),
+    RemoveVertex { id: VertexIdx },
+}
+
+/// Persists a region after topology patches were applied to it, see TOPOLOGY_WRITE_BACK.
+#[async_trait::async_trait]
+pub(crate) trait RegionWriter: Send + Sync {
+    async fn write(&self, graph: &Graph) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// Redis channel on which operators publish lists of [`TopologyPatch`]es for a region, as JSON.
+pub fn channel(region: RegionIdx) -> String {
+    format!("topology_updates_{}", region)
+}
+
+pub(crate) fn apply(graph: &Graph, patch: TopologyPatch, scale: WeightScale) -> Result<(), String> {
+    match patch {
+        TopologyPatch::InsertNode(raw_node) => {
+            graph.insert_node(Node::from(raw_node)).map_err(|err| err.to_string())
+        }
+        TopologyPatch::InsertVertex(raw_vertex) => {
+            let weight = scale.to_fixed(raw_vertex.weight).ok_or(format!("Vertex {} has invalid weight {}", raw_vertex.id, raw_vertex.weight))?;
+            let region_bits = parse_region_bits(&raw_vertex.region_bits).ok_or(format!("Vertex {} has malformed region bits {}", raw_vertex.id, raw_vertex.region_bits))?;
+            let access = parse_access(raw_vertex.access.as_deref()).map_err(|_| format!("Vertex {} has unknown transport modes {:?}", raw_vertex.id, raw_vertex.access))?;
+            graph.insert_vertex(Vertex {
+                a: raw_vertex.a,
+                b: raw_vertex.b,
+                weight,
+                id: raw_vertex.id,
+                region_bits,
+                access,
+            }).map_err(|err| err.to_string())
+        }
+        TopologyPatch::RemoveVertex { id } => {
+            graph.remove_vertex(id).map_err(|err| err.to_string())
+        }
+    }
+}
+
+/// Subscribes to the topology channels of the regions served and applies the patches published there in
+/// order to the regions loaded. A rejected patch is logged and skipped, the following ones are still applied.
+/// With a `writer` every region patched is written back after each list, before the next one is applied,
+/// so the writes of a region happen in the order of its patches. Patches published while the
+/// subscription is down are lost.
+pub(crate) async fn spawn_topology_updates(redis_connector: &RedisConnector,
+                                           dataset: DatasetHandle,
+                                           scale: WeightScale,
+                                           writer: Option<Arc<dyn RegionWriter>>) -> RedisResult<JoinHandle<()>> {
+    let channels: HashMap<String, RegionIdx> = dataset.current().graphs.keys().map(|region| (channel(*region), *region)).collect();
+    let mut subscriber = Subscriber::new(redis_connector, channels.keys().cloned().collect()).await?;
+    let redis_connector = redis_connector.clone();
+    Ok(tokio::task::spawn(async move {
+        loop {
+            let message = match subscriber.next().await {
+                Delivery::Message(message) => { message }
+                Delivery::Resubscribed => {
+                    log::warn!("Subscribed to topology updates again, patches published meanwhile are lost");
+                    continue
+                }
+            };
+            let region = match channels.get(message.get_channel_name()) {
+                Some(region) => { *region }
+                None => { continue }
+            };
+            let patches: Vec<TopologyPatch> = match message.get_payload::<String>().map_err(|err| err.to_string())
+                .and_then(|payload| serde_json::from_str(&payload).map_err(|err| err.to_string())) {
+                Ok(patches) => { patches }
+                Err(err) => {
+                    log::warn!("Ignoring malformed topology patches for region {}, details: {}", region, err);
+                    continue;
+                }
+            };
+            let current = dataset.current();
+            let graph = match current.graphs.get_loaded(&region) {
+                Some(graph) => { graph }
+                None => {
+                    log::debug!("Ignoring topology patches for region {}, it isn't loaded yet", region);
+                    continue
+                }
+            };
+            let mut patched = false;
+            for patch in patches {
+                // Other servers look up the region of nodes they route to in redis.
+                let registered = match &patch {
+                    TopologyPatch::InsertNode(raw_node) if raw_node.region == region => { Some(raw_node.id) }
+                    _ => { None }
+                };
+                log::info!("Patching region {}: {:?}", region, patch);
+                if let Err(err) = apply(graph, patch, scale) {
+                    log::warn!("Rejected topology patch for region {}, details: {}", region, err);
+                    continue;
+                }
+                patched = true;
+                if let Some(node_id) = registered {
+                    if let Err(err) = redis_connector.set_node_region(node_id, region).await {
+                        log::warn!("Unable to register node {} of region {}, details: {}", node_id, region, err);
+                    }
+                }
+            }
+            if let (true, Some(writer)) = (patched, writer.as_ref()) {
+                if let Err(err) = writer.write(graph).await {
+                    log::warn!("Unable to write patched region {} back, details: {}", region, err);
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use bitvec::vec::BitVec;
+    use crate::domain::NodeInfo;
+    use crate::graph::{Access, Avoid, Graph, Node, PathResult, SearchLimits, Vertex, WeightScale};
+    use crate::heuristic::Zero;
+    use crate::topology::{apply, TopologyPatch};
+
+    /// 1 -(1)- 2 -(1)- 3
+    fn line() -> Graph {
+        let mut nodes: HashMap<_, _> = (1..=3).map(|id| (id, Node::new(vec![], id, 1, id as u64, 0))).collect();
+        let mut vertices = HashMap::new();
+        for (id, a, b) in [(1, 1, 2), (2, 2, 3)] {
+            nodes.get_mut(&a).unwrap().connections.push(id);
+            nodes.get_mut(&b).unwrap().connections.push(id);
+            vertices.insert(id, Vertex { a, b, weight: 1, id, region_bits: BitVec::repeat(true, 2), access: Access::ALL });
+        }
+        Graph::new(nodes, vertices, 1)
+    }
+
+    async fn cost(graph: &Graph, target: usize) -> Option<u64> {
+        let (avoid, limits) = (Avoid::default(), SearchLimits::default());
+        match graph.find_way_local(NodeInfo(1, 1), NodeInfo(target, 1), &Zero, &avoid, &limits).await {
+            Ok(PathResult::TargetReached(_, cost)) => { Some(cost) }
+            _ => { None }
+        }
+    }
+
+    fn patches(raw: &str) -> Vec<TopologyPatch> {
+        serde_json::from_str(raw).unwrap()
+    }
+
+    #[tokio::test]
+    async fn closures_and_new_connections() {
+        let graph = line();
+        let patched = patches(r#"[
+            {"op": "remove_vertex", "id": 2},
+            {"op": "insert_node", "id": 4, "cord_x": 2, "cord_y": 1, "region": 1},
+            {"op": "insert_vertex", "id": 3, "a": 2, "b": 4, "weight": 1.0, "region_bits": "11"},
+            {"op": "insert_vertex", "id": 4, "a": 4, "b": 3, "weight": 2.5, "region_bits": "11", "access": "car"}
+        ]"#);
+        for patch in patched {
+            apply(&graph, patch, WeightScale(2)).unwrap();
+        }
+        assert_eq!(cost(&graph, 3).await, Some(1 + 2 + 5));
+        assert_eq!(graph.node_count(), 4);
+        assert_eq!(graph.vertices().count(), 3);
+        assert_eq!(graph.get_vertex(4).unwrap().access, Access::new([crate::graph::Profile::Car]));
+        let mut connections: Vec<_> = graph.connections(2).collect();
+        connections.sort();
+        assert_eq!(connections, vec![1, 3]);
+
+        graph.remove_vertex(4).unwrap();
+        assert_eq!(cost(&graph, 3).await, None);
+        assert_eq!(graph.connections(4).collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn invalid_patches_are_rejected() {
+        let graph = line();
+        let rejected = patches(r#"[
+            {"op": "insert_node", "id": 2, "cord_x": 0, "cord_y": 0, "region": 1},
+            {"op": "insert_vertex", "id": 1, "a": 1, "b": 3, "weight": 1.0, "region_bits": "11"},
+            {"op": "insert_vertex", "id": 5, "a": 8, "b": 9, "weight": 1.0, "region_bits": "11"},
+            {"op": "insert_vertex", "id": 6, "a": 1, "b": 3, "weight": 1.0, "region_bits": "1x"},
+            {"op": "remove_vertex", "id": 7}
+        ]"#);
+        for patch in rejected {
+            assert!(apply(&graph, patch, WeightScale::default()).is_err());
+        }
+        graph.remove_vertex(1).unwrap();
+        assert!(graph.remove_vertex(1).is_err());
+        let reused = Vertex { a: 1, b: 2, weight: 1, id: 1, region_bits: BitVec::repeat(true, 2), access: Access::ALL };
+        assert!(graph.insert_vertex(reused).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use redis::RedisResult;
+use serde::{Serialize, Deserialize};
+use tokio::task::JoinHandle;
+use crate::graph::{Graph, RegionIdx, VertexIdx, WeightScale};
+use crate::redis_connector::{Delivery, RedisConnector, Subscriber};
+use crate::reload::DatasetHandle;
+
+/// New weight of an edge, in the units of the data set like the weights in the region files.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WeightUpdate {
+    pub vertex: VertexIdx,
+    pub weight: f64,
+}
+
+/// Redis channel on which batches of [`WeightUpdate`]s for a region are published, as a JSON list.
+pub fn channel(region: RegionIdx) -> String {
+    format!("weight_updates_{}", region)
+}
+
+/// Applies a batch of updates, all of them or none if any is invalid.
+pub(crate) fn apply(graph: &Graph, payload: &str, scale: WeightScale) -> Result<usize, String> {
+    let updates: Vec<WeightUpdate> = serde_json::from_str(payload).map_err(|err| err.to_string())?;
+    let mut fixed = Vec::with_capacity(updates.len());
+    for update in updates {
+        let weight = scale.to_fixed(update.weight).ok_or(format!("Invalid weight {} for vertex {}", update.weight, update.vertex))?;
+        fixed.push((update.vertex, weight));
+    }
+    let count = fixed.len();
+    graph.update_weights(fixed).map_err(|err| err.to_string())?;
+    Ok(count)
+}
+
+/// Subscribes to the weight update channels of the regions served and applies whatever is published
+/// there to the current version of the regions, if loaded. Updates published while the
+/// subscription is down are lost.
+pub(crate) async fn spawn_weight_updates(redis_connector: &RedisConnector,
+                                         dataset: DatasetHandle,
+                                         scale: WeightScale) -> RedisResult<JoinHandle<()>> {
+    let channels: HashMap<String, RegionIdx> = dataset.current().graphs.keys().map(|region| (channel(*region), *region)).collect();
+    let mut subscriber = Subscriber::new(redis_connector, channels.keys().cloned().collect()).await?;
+    Ok(tokio::task::spawn(async move {
+        loop {
+            let message = match subscriber.next().await {
+                Delivery::Message(message) => { message }
+                Delivery::Resubscribed => {
+                    log::warn!("Subscribed to weight updates again, updates published meanwhile are lost");
+                    continue
+                }
+            };
+            let region = match channels.get(message.get_channel_name()) {
+                Some(region) => { *region }
+                None => { continue }
+            };
+            // A region not loaded yet is loaded as stored, updates published before aren't applied to it.
+            let current = dataset.current();
+            let graph = match current.graphs.get_loaded(&region) {
+                Some(graph) => { graph }
+                None => {
+                    log::debug!("Ignoring weight updates for region {}, it isn't loaded yet", region);
+                    continue
+                }
+            };
+            let applied = message.get_payload::<String>()
+                .map_err(|err| err.to_string())
+                .and_then(|payload| apply(graph, &payload, scale));
+            match applied {
+                Ok(count) => { log::debug!("Updated {} weights in region {}", count, region) }
+                Err(err) => { log::warn!("Ignoring weight updates for region {}, details: {}", region, err) }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use bitvec::vec::BitVec;
+    use crate::domain::NodeInfo;
+    use crate::graph::{Access, Avoid, Graph, Node, PathResult, SearchLimits, Vertex, WeightScale};
+    use crate::heuristic::Zero;
+    use crate::traffic::apply;
+
+    /// 1 -(1)- 2 -(1)- 3 and a direct 1 -(3)- 3.
+    fn triangle() -> Graph {
+        let mut nodes: HashMap<_, _> = (1..=3).map(|id| (id, Node::new(vec![], id, 1, 0, 0))).collect();
+        let mut vertices = HashMap::new();
+        for (id, a, b, weight) in [(1, 1, 2, 1), (2, 2, 3, 1), (3, 1, 3, 3)] {
+            nodes.get_mut(&a).unwrap().connections.push(id);
+            nodes.get_mut(&b).unwrap().connections.push(id);
+            vertices.insert(id, Vertex { a, b, weight, id, region_bits: BitVec::repeat(true, 2), access: Access::ALL });
+        }
+        Graph::new(nodes, vertices, 1)
+    }
+
+    async fn cost(graph: &Graph) -> u64 {
+        let (avoid, limits) = (Avoid::default(), SearchLimits::default());
+        match graph.find_way_local(NodeInfo(1, 1), NodeInfo(3, 1), &Zero, &avoid, &limits).await.unwrap() {
+            PathResult::TargetReached(_, cost) => { cost }
+            PathResult::Continue(..) => { panic!("Target should be reached") }
+        }
+    }
+
+    #[tokio::test]
+    async fn updates_change_routes() {
+        let graph = triangle();
+        let shared = graph.clone();
+        assert_eq!(cost(&graph).await, 2);
+        assert_eq!(apply(&shared, r#"[{"vertex": 2, "weight": 5.0}]"#, WeightScale::default()), Ok(1));
+        assert_eq!(cost(&graph).await, 3);
+        assert_eq!(graph.current_weight(2), Some(5));
+        assert_eq!(graph.get_vertex(2).unwrap().weight, 1);
+
+        assert!(apply(&graph, r#"[{"vertex": 2, "weight": 1.0}, {"vertex": 9, "weight": 1.0}]"#, WeightScale::default()).is_err());
+        assert!(apply(&graph, r#"[{"vertex": 2, "weight": -1.0}]"#, WeightScale::default()).is_err());
+        assert!(apply(&graph, "{", WeightScale::default()).is_err());
+        assert_eq!(graph.current_weight(2), Some(5));
+    }
+}
@@ -0,0 +1,294 @@
+//! Transports of the roles of a server, chosen one by one: where requests are received, how hops
+//! are forwarded to other servers and where results are sent. Without LISTENER_TRANSPORT,
+//! SENDER_TRANSPORT and REPLIER_TRANSPORT the mode switches (ZMQ_MODE, TCP_MODE, GRPC_MODE, redis
+//! otherwise) pick one transport for all of them. Transports of other crates are added to a
+//! [`TransportRegistry`] and picked by their name like the built-in ones.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use crate::domain::HopMessage;
+use crate::node_connector::{BasicResult, ConnectionError, NodeListener, NodeSender, ResultReplier, WireConfig};
+use crate::redis_connector::{NetworkInfo, RedisConnector};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportKind {
+    /// Redis pub/sub, or redis streams with REDIS_STREAMS.
+    Redis,
+    Zmq,
+    Tcp,
+    Grpc,
+    /// Transport registered by its name in a [`TransportRegistry`].
+    Custom(String),
+}
+
+impl TransportKind {
+    pub fn name(&self) -> &str {
+        match self {
+            TransportKind::Redis => { "redis" }
+            TransportKind::Zmq => { "zmq" }
+            TransportKind::Tcp => { "tcp" }
+            TransportKind::Grpc => { "grpc" }
+            TransportKind::Custom(name) => { name }
+        }
+    }
+
+    /// A built-in transport, or one of `registry`.
+    pub fn parse(name: &str, registry: &TransportRegistry) -> Result<Self> {
+        match TransportKind::from_str(name) {
+            Ok(kind) => { Ok(kind) }
+            Err(_) if registry.plugins.contains_key(name) => { Ok(TransportKind::Custom(name.to_string())) }
+            Err(err) => { Err(err.into()) }
+        }
+    }
+
+    /// Transport of the mode switched on by ZMQ_MODE, TCP_MODE or GRPC_MODE, redis otherwise.
+    pub fn mode_from_env() -> Self {
+        if env::var("GRPC_MODE").is_ok() {
+            TransportKind::Grpc
+        } else if env::var("TCP_MODE").is_ok() {
+            TransportKind::Tcp
+        } else if env::var("ZMQ_MODE").is_ok() {
+            TransportKind::Zmq
+        } else {
+            TransportKind::Redis
+        }
+    }
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "redis" => { Ok(TransportKind::Redis) }
+            "zmq" => { Ok(TransportKind::Zmq) }
+            "tcp" => { Ok(TransportKind::Tcp) }
+            "grpc" => { Ok(TransportKind::Grpc) }
+            other => { Err(format!("Unknown transport {}, expected redis, zmq, tcp or grpc", other)) }
+        }
+    }
+}
+
+/// Transport of every role of a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportRoles {
+    /// Where queries from clients are received.
+    pub listener: TransportKind,
+    /// How hops are sent to other servers, which receive them over the same transport.
+    pub sender: TransportKind,
+    /// Where results are sent.
+    pub replier: TransportKind,
+}
+
+impl TransportRoles {
+    /// Roles given in `listener`, `sender` and `replier`, the ones left out use `mode`.
+    pub fn parse(listener: Option<&str>, sender: Option<&str>, replier: Option<&str>, mode: TransportKind, registry: &TransportRegistry) -> Result<Self> {
+        let kind = |role: Option<&str>| role.map_or(Ok(mode.clone()), |name| TransportKind::parse(name, registry));
+        Ok(Self {
+            listener: kind(listener)?,
+            sender: kind(sender)?,
+            replier: kind(replier)?,
+        })
+    }
+
+    /// Reads LISTENER_TRANSPORT, SENDER_TRANSPORT and REPLIER_TRANSPORT, roles left out use the
+    /// transport of the mode switches. They may name the transports of `registry`.
+    pub fn from_env(registry: &TransportRegistry) -> Result<Self> {
+        let role = |var| env::var(var).ok();
+        Self::parse(role("LISTENER_TRANSPORT").as_deref(), role("SENDER_TRANSPORT").as_deref(), role("REPLIER_TRANSPORT").as_deref(), TransportKind::mode_from_env(), registry)
+    }
+
+    /// The transport of all roles, if they share one.
+    pub fn single(&self) -> Option<&TransportKind> {
+        Some(&self.listener).filter(|kind| **kind == self.sender && **kind == self.replier)
+    }
+
+    /// Transports the server receives over: the sender's, as other servers forward hops over it,
+    /// and the listener's.
+    pub fn listened(&self) -> Vec<TransportKind> {
+        let mut listened = vec![self.sender.clone(), self.listener.clone()];
+        listened.dedup();
+        listened
+    }
+
+    pub fn uses(&self, kind: &TransportKind) -> bool {
+        self.listener == *kind || self.sender == *kind || self.replier == *kind
+    }
+}
+
+impl Display for TransportRoles {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} listener, {} sender, {} replier", self.listener.name(), self.sender.name(), self.replier.name())
+    }
+}
+
+/// A transport of another crate, building the roles it is picked for. Roles it doesn't implement
+/// can't be given to it.
+#[async_trait::async_trait]
+pub trait TransportPlugin: Send + Sync {
+    async fn listener(&self, _setup: &TransportSetup) -> BasicResult<Box<dyn NodeListener>> {
+        Err("This transport can't receive requests")?
+    }
+
+    async fn sender(&self, _setup: &TransportSetup) -> BasicResult<Box<dyn NodeSender>> {
+        Err("This transport can't send hops")?
+    }
+
+    async fn replier(&self, _setup: &TransportSetup) -> BasicResult<Box<dyn ResultReplier>> {
+        Err("This transport can't send results")?
+    }
+}
+
+/// Transports of other crates by their name, for [`crate::Context::from_roles`].
+#[derive(Clone, Default)]
+pub struct TransportRegistry {
+    plugins: HashMap<String, Arc<dyn TransportPlugin>>,
+}
+
+impl TransportRegistry {
+    /// Adds `plugin` as `name`, which must not be taken by a built-in or registered transport.
+    pub fn register(&mut self, name: &str, plugin: impl TransportPlugin + 'static) -> Result<()> {
+        if TransportKind::from_str(name).is_ok() || self.plugins.contains_key(name) {
+            Err(format!("Transport {} is already registered", name))?
+        }
+        self.plugins.insert(name.to_string(), Arc::new(plugin));
+        Ok(())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.plugins.keys().map(|name| name.as_str())
+    }
+
+    pub(crate) fn plugin(&self, name: &str) -> Result<&dyn TransportPlugin> {
+        match self.plugins.get(name) {
+            Some(plugin) => { Ok(&**plugin) }
+            None => { Err(format!("Transport {} isn't registered", name))? }
+        }
+    }
+}
+
+/// What a [`TransportPlugin`] gets to build the roles of a server.
+pub struct TransportSetup {
+    /// GROUP_ID of the server.
+    pub server_id: usize,
+    redis_connector: RedisConnector,
+    wire: WireConfig,
+}
+
+impl TransportSetup {
+    pub(crate) fn new(server_id: usize, redis_connector: RedisConnector, wire: WireConfig) -> Self {
+        Self {
+            server_id,
+            redis_connector,
+            wire,
+        }
+    }
+
+    /// How the built-in transports of the server encode payloads, for
+    /// [`crate::node_connector::encode_request`] and the other helpers.
+    pub fn wire(&self) -> &WireConfig {
+        &self.wire
+    }
+
+    /// Addresses of the servers registered in `server_info`, following `server_updates`.
+    pub async fn server_addresses(&self) -> BasicResult<ServerAddresses> {
+        Ok(ServerAddresses {
+            network_info: self.redis_connector.get_servers_info().await?.network_info,
+        })
+    }
+}
+
+/// Addresses the servers of the cluster registered, kept up to date.
+#[derive(Clone)]
+pub struct ServerAddresses {
+    network_info: NetworkInfo,
+}
+
+impl ServerAddresses {
+    pub async fn get(&self, server_id: usize) -> Option<String> {
+        self.network_info.get_server(server_id).await.map(|server| server.addr.to_string())
+    }
+
+    pub async fn all(&self) -> BTreeMap<usize, String> {
+        self.network_info.get_servers().await.into_iter().map(|(id, server)| (id, server.addr.to_string())).collect()
+    }
+}
+
+/// Receives the requests of several listeners in turn.
+pub(crate) struct MergedListener {
+    requests: mpsc::Receiver<std::result::Result<HopMessage, ConnectionError>>,
+}
+
+impl MergedListener {
+    pub(crate) fn new(listeners: Vec<Box<dyn NodeListener>>) -> Self {
+        // A single slot, so hops are still only taken from a listener when the server asks.
+        let (sender, requests) = mpsc::channel(1);
+        for mut listener in listeners {
+            let sender = sender.clone();
+            tokio::task::spawn(async move {
+                loop {
+                    let request = listener.get_new_request().await;
+                    let closed = matches!(request, Err(ConnectionError::NoRequest));
+                    if sender.send(request).await.is_err() || closed {
+                        break;
+                    }
+                }
+            });
+        }
+        Self {
+            requests,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl NodeListener for MergedListener {
+    async fn get_new_request(&mut self) -> std::result::Result<HopMessage, ConnectionError> {
+        self.requests.recv().await.unwrap_or(Err(ConnectionError::NoRequest))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::transport::{TransportKind, TransportPlugin, TransportRegistry, TransportRoles};
+
+    struct Carrier;
+
+    impl TransportPlugin for Carrier {}
+
+    #[test]
+    fn roles_fall_back_to_the_mode() {
+        let registry = TransportRegistry::default();
+        let roles = TransportRoles::parse(None, Some("zmq"), Some("Redis"), TransportKind::Redis, &registry).unwrap();
+        assert_eq!(roles, TransportRoles { listener: TransportKind::Redis, sender: TransportKind::Zmq, replier: TransportKind::Redis });
+        assert_eq!(roles.single(), None);
+        assert_eq!(roles.listened(), vec![TransportKind::Zmq, TransportKind::Redis]);
+        assert!(roles.uses(&TransportKind::Zmq) && !roles.uses(&TransportKind::Tcp));
+
+        let roles = TransportRoles::parse(None, None, None, TransportKind::Tcp, &registry).unwrap();
+        assert_eq!(roles.single(), Some(&TransportKind::Tcp));
+        assert_eq!(roles.listened(), vec![TransportKind::Tcp]);
+        assert!(TransportRoles::parse(Some("http"), None, None, TransportKind::Redis, &registry).is_err());
+    }
+
+    #[test]
+    fn registered_transports_are_picked_by_name() {
+        let mut registry = TransportRegistry::default();
+        registry.register("carrier", Carrier).unwrap();
+        assert!(registry.register("carrier", Carrier).is_err());
+        assert!(registry.register("zmq", Carrier).is_err());
+        assert_eq!(registry.names().collect::<Vec<_>>(), vec!["carrier"]);
+
+        let roles = TransportRoles::parse(Some("carrier"), None, None, TransportKind::Redis, &registry).unwrap();
+        assert_eq!(roles.listener, TransportKind::Custom("carrier".to_string()));
+        assert_eq!(roles.listened(), vec![TransportKind::Redis, TransportKind::Custom("carrier".to_string())]);
+        assert!(registry.plugin("carrier").is_ok() && registry.plugin("pigeon").is_err());
+        assert!(TransportRoles::parse(Some("pigeon"), None, None, TransportKind::Redis, &registry).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Counters of the work done in every region, summed over all servers in redis, so operators can
+//! tell which regions are worth splitting or moving to a group of their own. Servers count in
+//! memory and add their counts to the hash `region_usage_<region>` every interval.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use tokio::task::JoinHandle;
+use crate::graph::RegionIdx;
+use crate::redis_connector::RedisConnector;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Interval at which a server adds its counts unless REGION_USAGE_INTERVAL_SECS is set.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Reads REGION_USAGE_INTERVAL_SECS.
+pub(crate) fn interval_from_env() -> Result<Duration> {
+    match env::var("REGION_USAGE_INTERVAL_SECS") {
+        Ok(secs) => { Ok(Duration::from_secs(secs.parse()?)) }
+        Err(_) => { Ok(DEFAULT_INTERVAL) }
+    }
+}
+
+/// Redis hash of the usage of a region, a field per counter of [`RegionUsage`].
+pub fn usage_key(region: RegionIdx) -> String {
+    format!("region_usage_{}", region)
+}
+
+/// Work done in a region since its counters were last reset.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegionUsage {
+    /// Hops searched in the region.
+    pub served: u64,
+    /// Continuations out of the region sent to another server.
+    pub forwarded: u64,
+    /// Continuations out of the region, into regions of any server.
+    pub boundary_crossings: u64,
+    /// Microseconds spent searching the region.
+    pub search_micros: u64,
+}
+
+impl RegionUsage {
+    pub(crate) const FIELDS: [&'static str; 4] = ["served", "forwarded", "boundary_crossings", "search_micros"];
+
+    /// Counters in the order of [`RegionUsage::FIELDS`].
+    pub(crate) fn counts(&self) -> [u64; 4] {
+        [self.served, self.forwarded, self.boundary_crossings, self.search_micros]
+    }
+
+    /// From the fields of its hash, missing ones count as zero.
+    pub(crate) fn from_fields(fields: &HashMap<String, u64>) -> Self {
+        let field = |name: &str| fields.get(name).copied().unwrap_or(0);
+        Self {
+            served: field("served"),
+            forwarded: field("forwarded"),
+            boundary_crossings: field("boundary_crossings"),
+            search_micros: field("search_micros"),
+        }
+    }
+
+    /// Milliseconds a search of the region took on average.
+    pub fn average_search_ms(&self) -> f64 {
+        if self.served == 0 { 0. } else { self.search_micros as f64 / self.served as f64 / 1000. }
+    }
+
+    fn add(&mut self, other: &RegionUsage) {
+        self.served += other.served;
+        self.forwarded += other.forwarded;
+        self.boundary_crossings += other.boundary_crossings;
+        self.search_micros += other.search_micros;
+    }
+}
+
+/// Usage counted by a server since it last added it to redis.
+#[derive(Debug, Default)]
+pub(crate) struct UsageCounters {
+    regions: Mutex<HashMap<RegionIdx, RegionUsage>>,
+}
+
+impl UsageCounters {
+    /// Counts a search of `region` which took `took`.
+    pub(crate) fn searched(&self, region: RegionIdx, took: Duration) {
+        let mut regions = self.regions.lock().unwrap();
+        let usage = regions.entry(region).or_default();
+        usage.served += 1;
+        usage.search_micros += took.as_micros() as u64;
+    }
+
+    /// Counts a continuation out of `region`, `forwarded` if to another server.
+    pub(crate) fn crossed(&self, region: RegionIdx, forwarded: bool) {
+        let mut regions = self.regions.lock().unwrap();
+        let usage = regions.entry(region).or_default();
+        usage.boundary_crossings += 1;
+        if forwarded {
+            usage.forwarded += 1;
+        }
+    }
+
+    fn take(&self) -> HashMap<RegionIdx, RegionUsage> {
+        std::mem::take(&mut *self.regions.lock().unwrap())
+    }
+
+    /// Counts `usage` again, e.g. after adding it to redis failed.
+    fn restore(&self, usage: HashMap<RegionIdx, RegionUsage>) {
+        let mut regions = self.regions.lock().unwrap();
+        for (region, usage) in usage.iter() {
+            regions.entry(*region).or_default().add(usage);
+        }
+    }
+}
+
+/// Adds the usage counted by the server to redis every `interval`. Counts failing to be added are
+/// kept for the next interval.
+pub(crate) fn spawn_flusher(redis_connector: RedisConnector, counters: Arc<UsageCounters>, interval: Duration) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let usage = counters.take();
+            if usage.is_empty() {
+                continue;
+            }
+            if let Err(err) = redis_connector.add_region_usage(&usage).await {
+                log::debug!("Unable to add the usage of {} regions, details: {}", usage.len(), err);
+                counters.restore(usage);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use crate::usage::{RegionUsage, UsageCounters};
+
+    #[test]
+    fn counts_are_kept_until_added() {
+        let counters = UsageCounters::default();
+        counters.searched(3, Duration::from_millis(4));
+        counters.searched(3, Duration::from_millis(2));
+        counters.crossed(3, true);
+        counters.crossed(3, false);
+        let usage = counters.take();
+        assert_eq!(usage[&3], RegionUsage { served: 2, forwarded: 1, boundary_crossings: 2, search_micros: 6000 });
+        assert_eq!(usage[&3].average_search_ms(), 3.);
+        assert!(counters.take().is_empty());
+        counters.crossed(3, false);
+        counters.restore(usage);
+        assert_eq!(counters.take()[&3].boundary_crossings, 3);
+    }
+
+    #[test]
+    fn missing_fields_count_as_zero() {
+        let fields = HashMap::from([("served".to_string(), 5), ("search_micros".to_string(), 2500)]);
+        let usage = RegionUsage::from_fields(&fields);
+        assert_eq!(usage.counts(), [5, 0, 0, 2500]);
+        assert_eq!(usage.average_search_ms(), 0.5);
+    }
+}
@@ -1,22 +1,17 @@
-use std::env;
 use pathfinder::{Configuration, Context, Server};
+use pathfinder::transport::{TransportRegistry, TransportRoles};
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
+    pathfinder::telemetry::init().unwrap();
     log::info!("Pathfinder launching!");
-    for (key, value) in env::vars() {
-        eprintln!("{}: {}", key, value);
-    }
     let config = Configuration::from_env().unwrap();
-    let context = if env::var("ZMQ_MODE").is_ok() {
-        log::info!("Launching in ZMQ mode");
-        Context::zmq_ctx(&config).await.unwrap()
-    } else {
-        log::info!("Launching in Redis mode");
-        Context::redis_ctx(&config).await.unwrap()
-    };
+    let registry = TransportRegistry::default();
+    let roles = TransportRoles::from_env(&registry).unwrap();
+    let context = Context::from_roles(&config, roles, &registry).await.unwrap();
 
     let mut server = Server::new(config, context).await.unwrap();
     server.serve().await;
+    pathfinder::telemetry::shutdown();
 }
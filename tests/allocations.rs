@@ -0,0 +1,71 @@
+//! Allocations made encoding hops. Counting them takes a global allocator, which is installed in
+//! this test binary only so the tests of the library run with the system one.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use pathfinder::domain::{ClientQuery, HopMessage, NodeInfo};
+use pathfinder::node_connector::{self, WireConfig};
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts allocations made by the current thread, so tests running in parallel don't disturb each other.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_allocations<F: FnMut()>(mut f: F) -> usize {
+    let before = ALLOCATIONS.with(|count| count.get());
+    f();
+    ALLOCATIONS.with(|count| count.get()) - before
+}
+
+/// Hop of a query which crossed 2000 nodes.
+fn long_request() -> HopMessage {
+    let query = serde_json::to_vec(&ClientQuery::new(1, NodeInfo::new(0, 1), NodeInfo::new(1999, 2))).unwrap();
+    let hops = node_connector::decode_requests(&WireConfig::default(), &query).unwrap();
+    let mut hop = serde_json::to_value(&hops[0]).unwrap();
+    hop["path"] = (0..2000u64).map(|i| serde_json::json!({"id": i, "region_id": 1, "cord_x": i * 10, "cord_y": i * 3})).collect();
+    serde_json::from_value(hop).unwrap()
+}
+
+/// Encoding into pooled buffers allocates only the copy handed out, not the buffer growing.
+#[test]
+fn pooled_encoding_allocates_less() {
+    let request = long_request();
+    let wire = WireConfig::default();
+    const HOPS: usize = 100;
+    let fresh = count_allocations(|| {
+        for _ in 0..HOPS {
+            let raw = serde_json::to_vec(&request).unwrap();
+            assert!(!raw.is_empty());
+        }
+    });
+    node_connector::encode_request(&wire, &request).unwrap();
+    let pooled = count_allocations(|| {
+        for _ in 0..HOPS {
+            let raw = node_connector::encode_request(&wire, &request).unwrap();
+            assert!(!raw.is_empty());
+        }
+    });
+    assert_eq!(pooled, HOPS);
+    assert!(fresh > 2 * HOPS);
+}